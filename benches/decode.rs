@@ -0,0 +1,80 @@
+//! Compares the dynamic decoder in `openpid::codec` with the compiled plans in `openpid::plan`,
+//! on a fixed-size IMU sample (the 1 kHz streaming case) and on a variable-length batch
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use openpid::plan::FieldView;
+use openpid::prelude::*;
+
+const SPEC: &str = r#"
+[device_info]
+name = "BenchIMU"
+description = "Synthetic IMU used for benchmarking"
+
+[structs.vec3]
+name = "vec3"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "LittleEndian" } },
+    { name = "y", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "LittleEndian" } },
+    { name = "z", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "LittleEndian" } },
+]
+
+[payloads.tx]
+
+[payloads.rx.sample]
+description = "One IMU sample"
+segments = [
+    { name = "timestamp", bits = 32, type = { type = "Integer", signing = "Unsigned", endianness = "LittleEndian" } },
+    { name = "accel", struct_name = "vec3" },
+    { name = "gyro", struct_name = "vec3" },
+    { name = "temperature", bits = 32, type = { type = "FloatIEEE", endianness = "LittleEndian" } },
+]
+
+[payloads.rx.batch]
+description = "Several gyro samples"
+segments = [
+    { name = "count", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "samples", type = { type = "Array", item_struct = "vec3" }, termination = { field_name = "count" } },
+]
+
+[transactions]
+"#;
+
+/// Reads every nested field, so the compiled path does as much work as the dynamic one
+fn visit(field: FieldView) {
+    match field {
+        FieldView::Struct(s) => s.fields().for_each(|f| visit(f.unwrap().1)),
+        FieldView::Array(a) => a.items().for_each(|i| i.unwrap().fields().for_each(|f| visit(f.unwrap().1))),
+        other => {
+            black_box(other);
+        }
+    }
+}
+
+fn decode(c: &mut Criterion) {
+    let pid: OpenPID = SPEC.parse().unwrap();
+    let codec = pid.compile().unwrap();
+
+    let sample: Vec<u8> = (0..20u8).collect();
+    let mut batch = vec![16u8];
+    batch.extend((0..16 * 6).map(|i| i as u8));
+
+    for (name, data) in [("sample", &sample), ("batch", &batch)] {
+        let plan = codec.payload(Direction::Rx, name).unwrap();
+        assert_eq!(plan.decode(data).unwrap().to_fields().unwrap(), pid.decode_payload(Direction::Rx, name, data).unwrap().fields);
+
+        let mut group = c.benchmark_group(name);
+        group.bench_function("dynamic", |b| b.iter(|| pid.decode_payload(Direction::Rx, name, black_box(data)).unwrap()));
+        group.bench_function("compiled", |b| {
+            b.iter(|| {
+                let view = plan.decode(black_box(data)).unwrap();
+                for field in view.fields() {
+                    visit(field.unwrap().1);
+                }
+            })
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -0,0 +1,254 @@
+//! Bit packing. Fields are laid out most significant bit first; little endian fields are whole
+//! bytes whose byte order is swapped
+
+use crate::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    Big,
+    #[default]
+    Little,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Signing {
+    OnesComplement,
+    TwosComplement,
+    #[default]
+    Unsigned,
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1u64 << bits) - 1
+    }
+}
+
+fn check_width(bits: u32) -> Result<()> {
+    if bits > 64 {
+        return Err(Error::TooWide { bits });
+    }
+    Ok(())
+}
+
+/// Reads `bits` (up to 64) bits starting at bit `pos`, most significant bit first
+pub fn read_bits(data: &[u8], pos: usize, bits: u32) -> Result<u64> {
+    check_width(bits)?;
+    let end = pos + bits as usize;
+    if end > data.len() * 8 {
+        return Err(Error::UnexpectedEnd { needed_bits: end - data.len() * 8 });
+    }
+    if pos.is_multiple_of(8) && bits.is_multiple_of(8) {
+        return Ok(data[pos / 8..end / 8].iter().fold(0u64, |acc, b| (acc << 8) | *b as u64));
+    }
+    Ok((pos..end).fold(0u64, |acc, p| (acc << 1) | ((data[p / 8] >> (7 - p % 8)) & 1) as u64))
+}
+
+/// Writes the low `bits` bits of `value` starting at bit `pos`, most significant bit first,
+/// leaving the surrounding bits alone
+pub fn write_bits(buf: &mut [u8], pos: usize, bits: u32, value: u64) -> Result<()> {
+    check_width(bits)?;
+    let end = pos + bits as usize;
+    if end > buf.len() * 8 {
+        return Err(Error::BufferTooSmall { needed_bits: end });
+    }
+    if pos.is_multiple_of(8) && bits.is_multiple_of(8) {
+        for (i, byte) in buf[pos / 8..end / 8].iter_mut().enumerate() {
+            *byte = (value >> (bits as usize - 8 * (i + 1))) as u8;
+        }
+        return Ok(());
+    }
+    for (i, p) in (pos..end).enumerate() {
+        let bit = ((value >> (bits as usize - 1 - i)) & 1) as u8;
+        let shift = 7 - p % 8;
+        buf[p / 8] = (buf[p / 8] & !(1 << shift)) | (bit << shift);
+    }
+    Ok(())
+}
+
+/// Converts between wire order and numeric order. A no-op for big endian
+pub fn reorder(raw: u64, bits: u32, endianness: Endianness) -> Result<u64> {
+    match endianness {
+        Endianness::Big => Ok(raw),
+        Endianness::Little => {
+            if !bits.is_multiple_of(8) {
+                return Err(Error::NotWholeBytes { bits });
+            }
+            Ok((0..bits / 8).fold(0u64, |acc, i| (acc << 8) | ((raw >> (8 * i)) & 0xFF)))
+        }
+    }
+}
+
+/// Interprets the low `bits` bits of `raw` as a signed number
+pub fn to_signed(raw: u64, bits: u32, signing: Signing) -> i64 {
+    if bits == 0 {
+        return 0;
+    }
+    let sign_bit = 1u64 << (bits - 1);
+    match signing {
+        Signing::TwosComplement if raw & sign_bit != 0 => (raw | !mask(bits)) as i64,
+        Signing::OnesComplement if raw & sign_bit != 0 => -((!raw & mask(bits)) as i64),
+        _ => raw as i64,
+    }
+}
+
+/// Encodes a number into `bits` bits, checking that it fits
+pub fn from_signed(value: i128, bits: u32, signing: Signing) -> Result<u64> {
+    check_width(bits)?;
+    if bits == 0 {
+        return if value == 0 { Ok(0) } else { Err(Error::OutOfRange { bits }) };
+    }
+    match signing {
+        Signing::Unsigned => {
+            if value < 0 || value > mask(bits) as i128 {
+                return Err(Error::OutOfRange { bits });
+            }
+            Ok(value as u64)
+        }
+        Signing::TwosComplement | Signing::OnesComplement => {
+            let max = (1i128 << (bits - 1)) - 1;
+            let min = if signing == Signing::TwosComplement { -max - 1 } else { -max };
+            if value < min || value > max {
+                return Err(Error::OutOfRange { bits });
+            }
+            if value < 0 && signing == Signing::OnesComplement {
+                Ok(!((-value) as u64) & mask(bits))
+            } else {
+                Ok(value as u64 & mask(bits))
+            }
+        }
+    }
+}
+
+/// Reads fields one after the other out of a buffer
+#[derive(Debug, Clone)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Position of the next field, in bits
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining_bits(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    pub fn read_bits(&mut self, bits: u32) -> Result<u64> {
+        let value = read_bits(self.data, self.pos, bits)?;
+        self.pos += bits as usize;
+        Ok(value)
+    }
+
+    pub fn read_uint(&mut self, bits: u32, endianness: Endianness) -> Result<u64> {
+        let raw = read_bits(self.data, self.pos, bits)?;
+        let value = reorder(raw, bits, endianness)?;
+        self.pos += bits as usize;
+        Ok(value)
+    }
+
+    pub fn read_int(&mut self, bits: u32, endianness: Endianness, signing: Signing) -> Result<i64> {
+        Ok(to_signed(self.read_uint(bits, endianness)?, bits, signing))
+    }
+
+    pub fn read_f32(&mut self, endianness: Endianness) -> Result<f32> {
+        Ok(f32::from_bits(self.read_uint(32, endianness)? as u32))
+    }
+
+    pub fn read_f64(&mut self, endianness: Endianness) -> Result<f64> {
+        Ok(f64::from_bits(self.read_uint(64, endianness)?))
+    }
+
+    /// Borrows the next `count` bytes. Must be byte aligned
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        if !self.pos.is_multiple_of(8) {
+            return Err(Error::Unaligned { bit_offset: self.pos });
+        }
+        let start = self.pos / 8;
+        let bytes = self
+            .data
+            .get(start..start + count)
+            .ok_or_else(|| Error::UnexpectedEnd { needed_bits: (start + count - self.data.len()) * 8 })?;
+        self.pos += count * 8;
+        Ok(bytes)
+    }
+}
+
+/// Writes fields one after the other into a fixed buffer
+#[derive(Debug)]
+pub struct BitWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> BitWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Bits written so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Bytes touched so far, including a partially written last byte
+    pub fn len_bytes(&self) -> usize {
+        self.pos.div_ceil(8)
+    }
+
+    pub fn written(&self) -> &[u8] {
+        &self.buf[..self.len_bytes()]
+    }
+
+    pub fn write_bits(&mut self, bits: u32, value: u64) -> Result<()> {
+        write_bits(self.buf, self.pos, bits, value)?;
+        self.pos += bits as usize;
+        Ok(())
+    }
+
+    pub fn write_uint(&mut self, bits: u32, endianness: Endianness, value: u64) -> Result<()> {
+        if bits < 64 && value >> bits != 0 {
+            return Err(Error::OutOfRange { bits });
+        }
+        self.write_bits(bits, reorder(value, bits, endianness)?)
+    }
+
+    pub fn write_int(&mut self, bits: u32, endianness: Endianness, signing: Signing, value: i64) -> Result<()> {
+        let raw = from_signed(value as i128, bits, signing)?;
+        self.write_bits(bits, reorder(raw, bits, endianness)?)
+    }
+
+    pub fn write_f32(&mut self, endianness: Endianness, value: f32) -> Result<()> {
+        self.write_bits(32, reorder(value.to_bits() as u64, 32, endianness)?)
+    }
+
+    pub fn write_f64(&mut self, endianness: Endianness, value: f64) -> Result<()> {
+        self.write_bits(64, reorder(value.to_bits(), 64, endianness)?)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.pos.is_multiple_of(8) {
+            let start = self.pos / 8;
+            let dest = self
+                .buf
+                .get_mut(start..start + bytes.len())
+                .ok_or_else(|| Error::BufferTooSmall { needed_bits: (start + bytes.len()) * 8 })?;
+            dest.copy_from_slice(bytes);
+            self.pos += bytes.len() * 8;
+            return Ok(());
+        }
+        for byte in bytes {
+            self.write_bits(8, *byte as u64)?;
+        }
+        Ok(())
+    }
+}
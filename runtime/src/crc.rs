@@ -0,0 +1,74 @@
+//! Table-driven CRCs. Tables are built at compile time, so they live in flash rather than RAM
+
+/// Checksums that can appear in a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crc {
+    /// CRC-32 (IEEE 802.3), as used by Ethernet and zlib
+    Crc32,
+
+    /// CRC-16/XMODEM: polynomial 0x1021, no reflection, initial value 0
+    Crc16XModem,
+}
+
+impl Crc {
+    /// Width of the checksum on the wire
+    pub const fn bits(self) -> usize {
+        match self {
+            Crc::Crc32 => 32,
+            Crc::Crc16XModem => 16,
+        }
+    }
+
+    pub fn compute(self, data: &[u8]) -> u32 {
+        match self {
+            Crc::Crc32 => crc32(data),
+            Crc::Crc16XModem => crc16_xmodem(data) as u32,
+        }
+    }
+}
+
+const fn crc16_xmodem_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC16_XMODEM_TABLE: [u16; 256] = crc16_xmodem_table();
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+pub fn crc16_xmodem(data: &[u8]) -> u16 {
+    data.iter()
+        .fold(0u16, |crc, byte| (crc << 8) ^ CRC16_XMODEM_TABLE[((crc >> 8) as u8 ^ byte) as usize])
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data
+        .iter()
+        .fold(0xFFFF_FFFFu32, |crc, byte| (crc >> 8) ^ CRC32_TABLE[((crc as u8) ^ byte) as usize])
+}
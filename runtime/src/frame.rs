@@ -0,0 +1,253 @@
+//! Frame envelopes: sizes, metadata, constants and CRCs around a payload
+
+use crate::bits::{read_bits, BitReader, BitWriter, Endianness};
+use crate::crc::Crc;
+use crate::{Error, Result};
+
+/// The most `Metadata` elements a frame format may have
+pub const MAX_METADATA: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeUnit {
+    Bits,
+    Bytes,
+}
+
+/// One piece of a frame format, in wire order. Mirrors `PacketFormatElement` in the `openpid`
+/// crate, flattened so that a format is a single borrowed slice
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Element<'a> {
+    /// Size of the whole frame
+    SizeTotal { bits: u32, unit: SizeUnit },
+
+    /// Size of the payload alone
+    SizeOfPayload { bits: u32, unit: SizeUnit },
+
+    /// Size of the `covers` elements that immediately follow this one
+    SizeOfElements { bits: u32, unit: SizeUnit, covers: usize },
+
+    Payload,
+
+    /// A per-payload value such as a frame ID, as an unsigned integer
+    Metadata { bits: u32, endianness: Endianness },
+
+    /// Checksum of every byte before it
+    Crc(Crc),
+
+    /// The last `bits` bits of `data`
+    Const { data: &'a [u8], bits: u32 },
+}
+
+impl Element<'_> {
+    /// Bits this element takes up. Zero for the payload
+    pub fn static_bits(&self) -> usize {
+        match self {
+            Element::SizeTotal { bits, .. } | Element::SizeOfPayload { bits, .. } | Element::SizeOfElements { bits, .. } => *bits as usize,
+            Element::Payload => 0,
+            Element::Metadata { bits, .. } => *bits as usize,
+            Element::Crc(crc) => crc.bits(),
+            Element::Const { bits, .. } => *bits as usize,
+        }
+    }
+}
+
+/// Size of everything in the format except the payload
+pub fn envelope_bits(format: &[Element]) -> usize {
+    format.iter().map(Element::static_bits).sum()
+}
+
+fn covered_bits(format: &[Element], index: usize, covers: usize, payload_bits: usize) -> usize {
+    format[index + 1..=index + covers]
+        .iter()
+        .map(|e| if *e == Element::Payload { payload_bits } else { e.static_bits() })
+        .sum()
+}
+
+fn size_value(bits: usize, unit: SizeUnit) -> u64 {
+    match unit {
+        SizeUnit::Bits => bits as u64,
+        SizeUnit::Bytes => bits.div_ceil(8) as u64,
+    }
+}
+
+fn size_bits(value: u64, unit: SizeUnit) -> usize {
+    match unit {
+        SizeUnit::Bits => value as usize,
+        SizeUnit::Bytes => value as usize * 8,
+    }
+}
+
+/// A frame found in a buffer. Borrows the payload from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    metadata: [u64; MAX_METADATA],
+    metadata_len: usize,
+
+    pub payload: &'a [u8],
+
+    /// Length of the whole frame in bytes
+    pub len: usize,
+}
+
+impl Frame<'_> {
+    /// Values of the format's `Metadata` elements, in order
+    pub fn metadata(&self) -> &[u64] {
+        &self.metadata[..self.metadata_len]
+    }
+}
+
+/// Wraps `payload` in a frame, writing into `out`. `metadata` holds a value for each `Metadata`
+/// element, in order. Returns the number of bytes written
+pub fn encode(format: &[Element], metadata: &[u64], payload: &[u8], out: &mut [u8]) -> Result<usize> {
+    let payload_bits = payload.len() * 8;
+    let total = envelope_bits(format) + payload_bits;
+    let needed = total.div_ceil(8);
+    let out = out.get_mut(..needed).ok_or(Error::BufferTooSmall { needed_bits: total })?;
+    out.fill(0);
+
+    let mut writer = BitWriter::new(out);
+    let mut metadata = metadata.iter();
+    for (i, element) in format.iter().enumerate() {
+        match *element {
+            Element::SizeTotal { bits, unit } => writer.write_uint(bits, Endianness::Big, size_value(total, unit))?,
+            Element::SizeOfPayload { bits, unit } => writer.write_uint(bits, Endianness::Big, size_value(payload_bits, unit))?,
+            Element::SizeOfElements { bits, unit, covers } => {
+                writer.write_uint(bits, Endianness::Big, size_value(covered_bits(format, i, covers, payload_bits), unit))?
+            }
+            Element::Payload => writer.write_bytes(payload)?,
+            Element::Metadata { bits, endianness } => {
+                let value = *metadata.next().ok_or(Error::TooManyMetadata)?;
+                writer.write_uint(bits, endianness, value)?;
+            }
+            Element::Crc(crc) => {
+                if !writer.position().is_multiple_of(8) {
+                    return Err(Error::Unaligned { bit_offset: writer.position() });
+                }
+                let value = crc.compute(writer.written());
+                writer.write_bits(crc.bits() as u32, value as u64)?;
+            }
+            Element::Const { data, bits } => write_const(&mut writer, data, bits)?,
+        }
+    }
+    Ok(writer.len_bytes())
+}
+
+#[cfg(feature = "alloc")]
+pub fn encode_to_vec(format: &[Element], metadata: &[u64], payload: &[u8]) -> Result<alloc::vec::Vec<u8>> {
+    let mut out = alloc::vec![0; (envelope_bits(format) + payload.len() * 8).div_ceil(8)];
+    let len = encode(format, metadata, payload, &mut out)?;
+    out.truncate(len);
+    Ok(out)
+}
+
+fn write_const(writer: &mut BitWriter, data: &[u8], bits: u32) -> Result<()> {
+    let mut pos = (data.len() * 8).checked_sub(bits as usize).ok_or(Error::BadSize)?;
+    let mut left = bits;
+    while left > 0 {
+        let n = left.min(64);
+        writer.write_bits(n, read_bits(data, pos, n)?)?;
+        pos += n as usize;
+        left -= n;
+    }
+    Ok(())
+}
+
+/// Decodes a buffer holding exactly one frame
+pub fn decode<'a>(format: &[Element], data: &'a [u8]) -> Result<Frame<'a>> {
+    parse(format, data, None, true)?.ok_or(Error::UnknownLength)
+}
+
+/// Looks up a payload's length in bytes from the frame's metadata, for formats that don't carry
+/// a size field
+pub type PayloadLen<'f> = &'f dyn Fn(&[u64]) -> Option<usize>;
+
+/// Tries to parse a frame from the start of `data`. Unless `complete` is set, running out of data
+/// is not an error and yields `Ok(None)`, so this can be called again as more bytes arrive
+pub fn parse<'a>(format: &[Element], data: &'a [u8], payload_len: Option<PayloadLen>, complete: bool) -> Result<Option<Frame<'a>>> {
+    match parse_inner(format, data, payload_len, complete) {
+        Err(Error::UnexpectedEnd { .. }) if !complete => Ok(None),
+        other => other.map(Some),
+    }
+}
+
+fn parse_inner<'a>(format: &[Element], data: &'a [u8], payload_len: Option<PayloadLen>, complete: bool) -> Result<Frame<'a>> {
+    let payload_index = format.iter().position(|e| *e == Element::Payload);
+    let mut reader = BitReader::new(data);
+    let mut frame = Frame { metadata: [0; MAX_METADATA], metadata_len: 0, payload: &[], len: 0 };
+    let mut total = None;
+    let mut payload_bits = None;
+
+    for (i, element) in format.iter().enumerate() {
+        match *element {
+            Element::SizeTotal { bits, unit } => {
+                let value = size_bits(reader.read_bits(bits)?, unit);
+                if value < envelope_bits(format) {
+                    return Err(Error::BadSize);
+                }
+                if value > data.len() * 8 {
+                    return Err(Error::UnexpectedEnd { needed_bits: value - data.len() * 8 });
+                }
+                total = Some(value);
+            }
+            Element::SizeOfPayload { bits, unit } => payload_bits = Some(size_bits(reader.read_bits(bits)?, unit)),
+            Element::SizeOfElements { bits, unit, covers } => {
+                let region = size_bits(reader.read_bits(bits)?, unit);
+                if payload_index.is_some_and(|p| (i + 1..=i + covers).contains(&p)) {
+                    let others = covered_bits(format, i, covers, 0);
+                    payload_bits = Some(region.checked_sub(others).ok_or(Error::BadSize)?);
+                }
+            }
+            Element::Payload => {
+                let bits = match (payload_bits, total) {
+                    (Some(bits), _) => bits,
+                    (None, Some(total)) => total - envelope_bits(format),
+                    (None, None) => match payload_len {
+                        Some(lookup) => lookup(frame.metadata()).ok_or(Error::UnknownLength)? * 8,
+                        None if complete => reader.remaining_bits().saturating_sub(envelope_bits(&format[i + 1..])),
+                        None => return Err(Error::UnknownLength),
+                    },
+                };
+                if !bits.is_multiple_of(8) {
+                    return Err(Error::NotWholeBytes { bits: bits as u32 });
+                }
+                frame.payload = reader.read_bytes(bits / 8)?;
+            }
+            Element::Metadata { bits, endianness } => {
+                let slot = frame.metadata.get_mut(frame.metadata_len).ok_or(Error::TooManyMetadata)?;
+                *slot = reader.read_uint(bits, endianness)?;
+                frame.metadata_len += 1;
+            }
+            Element::Crc(crc) => {
+                if !reader.position().is_multiple_of(8) {
+                    return Err(Error::Unaligned { bit_offset: reader.position() });
+                }
+                let expected = crc.compute(&data[..reader.position() / 8]);
+                let found = reader.read_bits(crc.bits() as u32)? as u32;
+                if expected != found {
+                    return Err(Error::CrcMismatch { expected, found });
+                }
+            }
+            Element::Const { data: expected, bits } => {
+                let mut pos = (expected.len() * 8).checked_sub(bits as usize).ok_or(Error::BadSize)?;
+                let mut left = bits;
+                while left > 0 {
+                    let n = left.min(64);
+                    if reader.read_bits(n)? != read_bits(expected, pos, n)? {
+                        return Err(Error::ConstMismatch);
+                    }
+                    pos += n as usize;
+                    left -= n;
+                }
+            }
+        }
+    }
+
+    if total.is_some_and(|total| total.div_ceil(8) != reader.position().div_ceil(8)) {
+        return Err(Error::BadSize);
+    }
+    if complete && reader.remaining_bits() >= 8 {
+        return Err(Error::TrailingData { bits: reader.remaining_bits() });
+    }
+    frame.len = reader.position().div_ceil(8);
+    Ok(frame)
+}
@@ -0,0 +1,81 @@
+//! The parts of OpenPID that run on the device side of the wire, or on anything without `std`:
+//! CRCs, bit packing, frame encoding into fixed buffers and a streaming frame parser.
+//!
+//! Nothing here knows about `openpid.toml`. Frame formats are described with borrowed
+//! [frame::Element] slices, which the `openpid` crate (or generated code) builds from a spec.
+
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod bits;
+pub mod crc;
+pub mod frame;
+pub mod parser;
+
+pub use bits::{BitReader, BitWriter, Endianness, Signing};
+pub use crc::Crc;
+pub use frame::{Element, Frame, SizeUnit};
+pub use parser::FrameParser;
+
+use core::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Needed this many more bits than were available
+    UnexpectedEnd { needed_bits: usize },
+
+    /// The output buffer can't hold what's being written
+    BufferTooSmall { needed_bits: usize },
+
+    /// Little endian values and byte fields must be a whole number of bytes
+    NotWholeBytes { bits: u32 },
+
+    /// Byte fields, payloads and CRCs must start on a byte boundary
+    Unaligned { bit_offset: usize },
+
+    /// Wider than the 64 bits this runtime handles
+    TooWide { bits: u32 },
+
+    /// A value doesn't fit in its field
+    OutOfRange { bits: u32 },
+
+    CrcMismatch { expected: u32, found: u32 },
+
+    /// A frame's constant bytes (e.g. a sync word) weren't where they should be
+    ConstMismatch,
+
+    /// A size field disagrees with the rest of the frame
+    BadSize,
+
+    /// The payload's length can't be worked out from the envelope
+    UnknownLength,
+
+    /// More `Metadata` elements than [frame::MAX_METADATA]
+    TooManyMetadata,
+
+    /// Data left over after a complete frame
+    TrailingData { bits: usize },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::UnexpectedEnd { needed_bits } => write!(f, "Ran out of data, needed {needed_bits} more bits"),
+            Error::BufferTooSmall { needed_bits } => write!(f, "Buffer too small, needed {needed_bits} bits"),
+            Error::NotWholeBytes { bits } => write!(f, "{bits} bits is not a whole number of bytes"),
+            Error::Unaligned { bit_offset } => write!(f, "Bit offset {bit_offset} is not on a byte boundary"),
+            Error::TooWide { bits } => write!(f, "{bits} bits is wider than 64 bits"),
+            Error::OutOfRange { bits } => write!(f, "Value doesn't fit in {bits} bits"),
+            Error::CrcMismatch { expected, found } => write!(f, "CRC mismatch: computed {expected:#x}, frame has {found:#x}"),
+            Error::ConstMismatch => write!(f, "Constant bytes in frame don't match"),
+            Error::BadSize => write!(f, "Size field is inconsistent with the frame"),
+            Error::UnknownLength => write!(f, "Can't determine the payload's length"),
+            Error::TooManyMetadata => write!(f, "Too many metadata elements"),
+            Error::TrailingData { bits } => write!(f, "{bits} bits left over after frame"),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
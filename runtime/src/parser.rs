@@ -0,0 +1,74 @@
+//! Finds frames in a byte stream, e.g. bytes arriving one at a time from a UART interrupt
+
+use crate::frame::{parse, Element, Frame, PayloadLen};
+use crate::{Error, Result};
+
+/// Buffers incoming bytes in a fixed `N` byte buffer until they make up a frame. When bytes don't
+/// parse (a bad CRC, a missing sync word) the oldest byte is dropped, so the parser resyncs on
+/// the next frame boundary by itself
+pub struct FrameParser<'f, const N: usize> {
+    format: &'f [Element<'f>],
+    payload_len: Option<PayloadLen<'f>>,
+    buf: [u8; N],
+    len: usize,
+
+    /// Bytes of the last returned frame, dropped on the next push
+    consumed: usize,
+}
+
+impl<'f, const N: usize> FrameParser<'f, N> {
+    pub fn new(format: &'f [Element<'f>]) -> Self {
+        Self { format, payload_len: None, buf: [0; N], len: 0, consumed: 0 }
+    }
+
+    /// For formats without a size field, looks up the payload length from the frame's metadata
+    pub fn with_payload_len(mut self, payload_len: PayloadLen<'f>) -> Self {
+        self.payload_len = Some(payload_len);
+        self
+    }
+
+    /// Bytes received but not yet part of a frame
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf[self.consumed..self.len]
+    }
+
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.consumed = 0;
+    }
+
+    fn discard(&mut self, count: usize) {
+        self.buf.copy_within(count..self.len, 0);
+        self.len -= count;
+    }
+
+    /// Feeds one byte in. Returns a frame once one is complete, or an error when buffered bytes
+    /// had to be dropped
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame<'_>>> {
+        if self.consumed > 0 {
+            self.discard(self.consumed);
+            self.consumed = 0;
+        }
+
+        let mut overflowed = false;
+        if self.len == N {
+            self.discard(1);
+            overflowed = true;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        match parse(self.format, &self.buf[..self.len], self.payload_len, false).map(|f| f.map(|f| f.len)) {
+            Ok(Some(len)) => {
+                self.consumed = len;
+                Some(parse(self.format, &self.buf[..len], self.payload_len, true).map(|f| f.expect("frame was just parsed")))
+            }
+            Ok(None) if overflowed => Some(Err(Error::BufferTooSmall { needed_bits: (N + 1) * 8 })),
+            Ok(None) => None,
+            Err(e) => {
+                self.discard(1);
+                Some(Err(e))
+            }
+        }
+    }
+}
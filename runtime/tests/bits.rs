@@ -0,0 +1,62 @@
+//! Bit packing and CRCs on their own, without a spec
+
+use openpid_runtime::bits::{from_signed, read_bits, to_signed, write_bits};
+use openpid_runtime::{BitReader, BitWriter, Crc, Endianness, Error, Signing};
+
+#[test]
+fn crcs_match_their_check_values() {
+    // the standard check input, "123456789"
+    assert_eq!(Crc::Crc32.compute(b"123456789"), 0xCBF4_3926);
+    assert_eq!(Crc::Crc16XModem.compute(b"123456789"), 0x31C3);
+    assert_eq!(Crc::Crc16XModem.compute(&[]), 0);
+}
+
+#[test]
+fn fields_pack_most_significant_bit_first() {
+    let mut buf = [0u8; 4];
+    write_bits(&mut buf, 0, 4, 0xA).unwrap();
+    write_bits(&mut buf, 4, 12, 0x123).unwrap();
+    write_bits(&mut buf, 16, 3, 0b101).unwrap();
+    assert_eq!(buf, [0xA1, 0x23, 0b1010_0000, 0]);
+    assert_eq!(read_bits(&buf, 4, 12), Ok(0x123));
+    assert_eq!(read_bits(&buf, 16, 3), Ok(0b101));
+    assert_eq!(read_bits(&buf, 30, 8), Err(Error::UnexpectedEnd { needed_bits: 6 }));
+    assert_eq!(write_bits(&mut buf, 0, 65, 0), Err(Error::TooWide { bits: 65 }));
+
+    // writing leaves the neighbouring bits alone
+    write_bits(&mut buf, 2, 4, 0).unwrap();
+    assert_eq!(buf[0], 0b1000_0001);
+}
+
+#[test]
+fn integers_round_trip_in_each_order_and_signing() {
+    let mut buf = [0u8; 16];
+    let mut writer = BitWriter::new(&mut buf);
+    writer.write_uint(16, Endianness::Little, 0x1234).unwrap();
+    writer.write_int(12, Endianness::Big, Signing::TwosComplement, -5).unwrap();
+    writer.write_int(4, Endianness::Big, Signing::OnesComplement, -3).unwrap();
+    writer.write_f32(Endianness::Little, -21.5).unwrap();
+    writer.write_f64(Endianness::Big, 1e300).unwrap();
+    assert_eq!(writer.len_bytes(), 16);
+    assert_eq!(writer.write_uint(12, Endianness::Little, 1), Err(Error::NotWholeBytes { bits: 12 }));
+    assert_eq!(buf[..4], [0x34, 0x12, 0xFF, 0xB0 | 0b1100]);
+
+    let mut reader = BitReader::new(&buf);
+    assert_eq!(reader.read_uint(16, Endianness::Little), Ok(0x1234));
+    assert_eq!(reader.read_int(12, Endianness::Big, Signing::TwosComplement), Ok(-5));
+    assert_eq!(reader.read_int(4, Endianness::Big, Signing::OnesComplement), Ok(-3));
+    assert_eq!(reader.read_f32(Endianness::Little), Ok(-21.5));
+    assert_eq!(reader.read_f64(Endianness::Big), Ok(1e300));
+    assert_eq!(reader.remaining_bits(), 0);
+}
+
+#[test]
+fn signed_ranges_are_checked() {
+    assert_eq!(from_signed(-128, 8, Signing::TwosComplement), Ok(0x80));
+    assert_eq!(from_signed(-128, 8, Signing::OnesComplement), Err(Error::OutOfRange { bits: 8 }));
+    assert_eq!(from_signed(256, 8, Signing::Unsigned), Err(Error::OutOfRange { bits: 8 }));
+    assert_eq!(from_signed(-1, 8, Signing::Unsigned), Err(Error::OutOfRange { bits: 8 }));
+    assert_eq!(to_signed(0x80, 8, Signing::TwosComplement), -128);
+    assert_eq!(to_signed(0xFE, 8, Signing::OnesComplement), -1);
+    assert_eq!(to_signed(0xFE, 8, Signing::Unsigned), 0xFE);
+}
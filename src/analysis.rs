@@ -0,0 +1,535 @@
+//! Where things are defined and used in an OpenPID document, for editor tooling. [Analysis] finds
+//! the payloads, structs, fields, transactions and metadata keys in a source file and every string
+//! or key that refers to them, places validation problems in the file, and answers what a language
+//! server asks: what's at this offset, where is it defined, what refers to it, what would renaming
+//! it change, and what could be typed here. Offsets are bytes into the source
+
+use std::ops::Range;
+use std::path::Path;
+
+use toml_edit::{ImDocument, Item, Key};
+
+use crate::codec::Direction;
+use crate::codegen::CodegenError;
+use crate::edit::rows;
+use crate::include::{self, Packages};
+use crate::ir::{byte_offset, Field, Ir, Name};
+use crate::migrate::{self, MigrateError};
+use crate::OpenPID;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+
+    /// Where the problem is, when it's tied to one place
+    pub span: Option<Range<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Payload(Direction),
+    Struct,
+
+    /// A segment of a payload or a field of a struct
+    Field,
+    Transaction,
+
+    /// A key named by a frame format's `Metadata` element, such as `FrameID`
+    Metadata(Direction),
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub name: String,
+
+    /// The payload or struct a field belongs to, as an index into [Analysis::symbols]
+    pub owner: Option<usize>,
+
+    /// The name where it's defined, without quotes
+    pub span: Range<usize>,
+
+    /// The struct a field holds, through `struct_name` or `item_struct`
+    holds: Option<String>,
+}
+
+/// A use of one of [Analysis::symbols]
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub symbol: usize,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub label: String,
+    pub kind: SymbolKind,
+    pub detail: Option<String>,
+}
+
+/// What a string or key in the document refers to, before it's looked up
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Payload(Direction, String),
+    Struct(String),
+    Metadata(Direction, String),
+
+    /// A field of the payload or struct at this index
+    Field(usize, String),
+
+    /// A transaction's `returns` entry, `Payload.field.field…`
+    Return(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Analysis {
+    pub diagnostics: Vec<Diagnostic>,
+    pub symbols: Vec<Symbol>,
+    pub references: Vec<Reference>,
+    targets: Vec<(Target, Range<usize>)>,
+    ir: Option<Ir>,
+    parsed: bool,
+}
+
+/// A table's keys with their items, in the order written
+fn entries(item: Option<&Item>) -> Vec<(&Key, &Item)> {
+    let Some(table) = item.and_then(Item::as_table_like) else { return Vec::new() };
+    table.iter().filter_map(|(k, _)| table.get_key_value(k)).collect()
+}
+
+/// A string and where it is, quotes included
+fn string(item: Option<&Item>) -> Option<(&str, Range<usize>)> {
+    let value = item?.as_value()?;
+    Some((value.as_str()?, value.span()?))
+}
+
+fn direction_key(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Tx => "tx",
+        Direction::Rx => "rx",
+    }
+}
+
+/// Collects definitions and the targets of references in one pass over the document
+struct Walker<'s> {
+    source: &'s str,
+    symbols: Vec<Symbol>,
+    targets: Vec<(Target, Range<usize>)>,
+}
+
+impl Walker<'_> {
+    /// A string's or key's span without its quotes
+    fn inner(&self, span: Range<usize>) -> Range<usize> {
+        let text = &self.source[span.clone()];
+        let quotes = ["\"\"\"", "'''", "\"", "'"].into_iter().find(|q| text.starts_with(q) && text.len() >= 2 * q.len());
+        match quotes {
+            Some(q) => span.start + q.len()..span.end - q.len(),
+            None => span,
+        }
+    }
+
+    fn define(&mut self, kind: SymbolKind, name: &str, span: Range<usize>, owner: Option<usize>) -> usize {
+        let span = self.inner(span);
+        self.symbols.push(Symbol { kind, name: name.to_owned(), owner, span, holds: None });
+        self.symbols.len() - 1
+    }
+
+    fn target(&mut self, target: Target, span: Range<usize>) {
+        let span = self.inner(span);
+        self.targets.push((target, span));
+    }
+
+    fn string(&mut self, item: Option<&Item>, target: impl FnOnce(String) -> Target) {
+        if let Some((text, span)) = string(item) {
+            self.target(target(text.to_owned()), span);
+        }
+    }
+
+    fn key(&mut self, kind: SymbolKind, key: &Key, owner: Option<usize>) -> Option<usize> {
+        Some(self.define(kind, key.get(), key.span()?, owner))
+    }
+
+    fn document(&mut self, root: &Item) {
+        for (key, direction) in [("tx_format", Direction::Tx), ("rx_format", Direction::Rx)] {
+            if let Some(format) = root.get("uart").and_then(|u| u.get(key)) {
+                self.frame(format, direction);
+            }
+        }
+        for (key, rs) in entries(root.get("structs")) {
+            let Some(owner) = self.key(SymbolKind::Struct, key, None) else { continue };
+            self.string(rs.get("name"), Target::Struct);
+            self.fields(owner, rs.get("fields"));
+        }
+        for direction in [Direction::Tx, Direction::Rx] {
+            for (key, payload) in entries(root.get("payloads").and_then(|p| p.get(direction_key(direction)))) {
+                let Some(owner) = self.key(SymbolKind::Payload(direction), key, None) else { continue };
+                for (key, value) in entries(Some(payload)) {
+                    match key.get() {
+                        "segments" => self.fields(owner, Some(value)),
+                        "description" => {}
+                        metadata => {
+                            if let Some(span) = key.span() {
+                                self.target(Target::Metadata(direction, metadata.to_owned()), span);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for (key, transaction) in entries(root.get("transactions")) {
+            self.key(SymbolKind::Transaction, key, None);
+            for action in transaction.get("actions").map(rows).unwrap_or_default() {
+                let direction = match action.get("type").and_then(Item::as_str) {
+                    Some("Tx") => Direction::Tx,
+                    Some("Rx") => Direction::Rx,
+                    _ => continue,
+                };
+                self.string(action.get("payload"), |name| Target::Payload(direction, name));
+            }
+            for value in transaction.get("returns").and_then(Item::as_array).into_iter().flatten() {
+                if let (Some(text), Some(span)) = (value.as_str(), value.span()) {
+                    self.target(Target::Return(text.to_owned()), span);
+                }
+            }
+        }
+    }
+
+    fn frame(&mut self, elements: &Item, direction: Direction) {
+        for element in rows(elements) {
+            if element.get("type").and_then(Item::as_str) == Some("Metadata") {
+                if let Some((text, span)) = string(element.get("segment").and_then(|s| s.get("name"))) {
+                    self.define(SymbolKind::Metadata(direction), text, span, None);
+                }
+            }
+            if let Some(nested) = element.get("elements") {
+                self.frame(nested, direction);
+            }
+        }
+    }
+
+    fn fields(&mut self, owner: usize, fields: Option<&Item>) {
+        for field in fields.map(rows).unwrap_or_default() {
+            let item_struct = field.get("type").and_then(|t| t.get("item_struct"));
+            if let Some((text, span)) = string(field.get("name")) {
+                let index = self.define(SymbolKind::Field, text, span, Some(owner));
+                let holds = field.get("struct_name").or(item_struct).and_then(Item::as_str);
+                self.symbols[index].holds = holds.map(str::to_owned);
+            }
+            self.string(field.get("struct_name"), Target::Struct);
+            self.string(item_struct, Target::Struct);
+            self.string(field.get("termination").and_then(|t| t.get("field_name")), |name| Target::Field(owner, name));
+        }
+    }
+}
+
+impl Analysis {
+    /// Analyses a document, collecting every problem in it. A document that isn't TOML has a
+    /// diagnostic and nothing else. Imports are found from the current directory
+    pub fn new(source: &str) -> Self {
+        Self::for_file(source, Path::new("openpid.toml"))
+    }
+
+    /// Analyses the document at `path`, whose imports are found from there
+    pub fn for_file(source: &str, path: &Path) -> Self {
+        let root = match ImDocument::parse(source) {
+            Ok(root) => root,
+            Err(e) => {
+                let diagnostic = Diagnostic { severity: Severity::Error, message: e.message().to_owned(), span: e.span() };
+                return Analysis { diagnostics: vec![diagnostic], ..Default::default() };
+            }
+        };
+        let mut walker = Walker { source, symbols: Vec::new(), targets: Vec::new() };
+        walker.document(root.as_item());
+        let mut analysis = Analysis { symbols: walker.symbols, targets: walker.targets, parsed: true, ..Default::default() };
+        analysis.resolve();
+
+        let doc: OpenPID = match migrate::parse(source) {
+            Ok(parsed) => parsed,
+            Err(MigrateError::Invalid(e)) => {
+                analysis.diagnostics.push(Diagnostic { severity: Severity::Error, message: e.message().to_owned(), span: e.span() });
+                return analysis;
+            }
+            Err(e) => {
+                let span = root.get("openpid_version").and_then(Item::span);
+                analysis.diagnostics.push(Diagnostic { severity: Severity::Error, message: e.to_string(), span });
+                return analysis;
+            }
+        };
+        let doc = match doc.imports.is_empty() {
+            true => doc,
+            false => match include::resolve(doc, path, &Packages::for_document(path)) {
+                Ok(doc) => doc,
+                Err(e) => {
+                    analysis.diagnostics.push(Diagnostic { severity: Severity::Error, message: e.to_string(), span: None });
+                    return analysis;
+                }
+            },
+        };
+        match doc.to_ir() {
+            Ok(ir) => analysis.ir = Some(ir),
+            Err(CodegenError::Invalid(errors)) => {
+                for e in errors {
+                    let span = analysis.locate(&e);
+                    analysis.diagnostics.push(Diagnostic { severity: Severity::Error, message: e.to_string(), span });
+                }
+            }
+            Err(e) => analysis.diagnostics.push(Diagnostic { severity: Severity::Error, message: e.to_string(), span: None }),
+        }
+
+        for symbol in &analysis.symbols {
+            let kind = match symbol.kind {
+                SymbolKind::Struct => "Struct",
+                SymbolKind::Payload(Direction::Tx) => "TX payload",
+                SymbolKind::Payload(Direction::Rx) => "RX payload",
+                SymbolKind::Transaction => "Transaction",
+                _ => continue,
+            };
+            // names in the standard are snake case, though codegen recases them anyway
+            if Name::snake_case().convert(&symbol.name) != symbol.name {
+                let message = format!("{kind} \"{}\" is not snake case", symbol.name);
+                analysis.diagnostics.push(Diagnostic { severity: Severity::Warning, message, span: Some(symbol.span.clone()) });
+            }
+        }
+        analysis
+    }
+
+    /// Whether the source was TOML, so the symbols and references are current
+    pub fn parsed(&self) -> bool {
+        self.parsed
+    }
+
+    fn find(&self, kind: SymbolKind, name: &str, owner: Option<usize>) -> Option<usize> {
+        self.symbols.iter().position(|s| s.kind == kind && s.name == name && s.owner == owner)
+    }
+
+    fn resolve(&mut self) {
+        let mut references = Vec::new();
+        for (target, span) in &self.targets {
+            let symbol = match target {
+                Target::Payload(direction, name) => self.find(SymbolKind::Payload(*direction), name, None),
+                Target::Struct(name) => self.find(SymbolKind::Struct, name, None),
+                Target::Metadata(direction, name) => self.find(SymbolKind::Metadata(*direction), name, None),
+                Target::Field(owner, name) => self.find(SymbolKind::Field, name, Some(*owner)),
+                Target::Return(path) => {
+                    let mut start = span.start;
+                    let mut owner = None;
+                    for (i, part) in path.split('.').enumerate() {
+                        let found = match (i, owner) {
+                            (0, _) => self.find(SymbolKind::Payload(Direction::Rx), part, None),
+                            (_, Some(owner)) => self.find(SymbolKind::Field, part, Some(owner)),
+                            _ => None,
+                        };
+                        let Some(found) = found else { break };
+                        references.push(Reference { symbol: found, span: start..start + part.len() });
+                        start += part.len() + 1;
+                        owner = match self.symbols[found].kind {
+                            SymbolKind::Field => self.symbols[found].holds.as_deref().and_then(|s| self.find(SymbolKind::Struct, s, None)),
+                            _ => Some(found),
+                        };
+                    }
+                    continue;
+                }
+            };
+            if let Some(symbol) = symbol {
+                references.push(Reference { symbol, span: span.clone() });
+            }
+        }
+        self.references = references;
+    }
+
+    /// Where in the file a validation error is about, when it can be placed
+    fn locate(&self, error: &CodegenError) -> Option<Range<usize>> {
+        let target = |wanted: &dyn Fn(&Target) -> bool| self.targets.iter().find(|(t, _)| wanted(t)).map(|(_, span)| span.clone());
+        let symbol = |wanted: &dyn Fn(&Symbol) -> bool| self.symbols.iter().find(|s| wanted(s)).map(|s| s.span.clone());
+        match error {
+            CodegenError::UnknownStruct { struct_name, .. } => target(&|t| *t == Target::Struct(struct_name.clone())),
+            CodegenError::UnknownPayload { direction, payload, .. } => target(&|t| *t == Target::Payload(*direction, payload.clone())),
+            CodegenError::BadReturn { value, .. } => target(&|t| *t == Target::Return(value.clone())),
+            CodegenError::BadCountField { wanted_by, field, .. } | CodegenError::UnterminatedRx { wanted_by, field } => {
+                // "Payload", "[Struct name]", or a path through structs ending in one
+                let owner = match wanted_by.rfind("[Struct ") {
+                    Some(i) => wanted_by[i + 8..].split(']').next().and_then(|name| self.find(SymbolKind::Struct, name, None)),
+                    None => self.symbols.iter().position(|s| matches!(s.kind, SymbolKind::Payload(_)) && s.name == *wanted_by),
+                };
+                symbol(&|s| s.kind == SymbolKind::Field && s.name == *field && s.owner == owner && owner.is_some())
+            }
+            CodegenError::MissingMetadata { payload, .. } => symbol(&|s| matches!(s.kind, SymbolKind::Payload(_)) && s.name == *payload),
+            CodegenError::NameCollision { second, .. } => symbol(&|s| s.owner.is_none() && s.name == *second),
+            _ => None,
+        }
+    }
+
+    /// The symbol defined or referred to at `offset`
+    pub fn symbol_at(&self, offset: usize) -> Option<usize> {
+        let contains = |span: &Range<usize>| span.start <= offset && offset <= span.end;
+        let defined = self.symbols.iter().position(|s| contains(&s.span));
+        defined.or_else(|| self.references.iter().find(|r| contains(&r.span)).map(|r| r.symbol))
+    }
+
+    /// Everywhere `symbol` is referred to, not counting its definition
+    pub fn references_to(&self, symbol: usize) -> Vec<Range<usize>> {
+        self.references.iter().filter(|r| r.symbol == symbol).map(|r| r.span.clone()).collect()
+    }
+
+    /// The replacements that rename `symbol` and everything referring to it
+    pub fn rename(&self, symbol: usize, new: &str) -> Result<Vec<Range<usize>>, String> {
+        // names are also keys and parts of `returns` paths, so keep them to bare key characters
+        if new.is_empty() || !new.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(format!("\"{new}\" can only use letters, digits, _ and -"));
+        }
+        let old = &self.symbols[symbol];
+        if self.symbols.iter().any(|s| s.kind == old.kind && s.owner == old.owner && s.name == new) {
+            return Err(format!("\"{new}\" is already used"));
+        }
+        let mut spans = vec![old.span.clone()];
+        spans.extend(self.references_to(symbol));
+        Ok(spans)
+    }
+
+    fn fields(&self, owner: &Symbol) -> Option<&[Field]> {
+        let ir = self.ir.as_ref()?;
+        let name = Name::new(&owner.name);
+        match owner.kind {
+            SymbolKind::Payload(direction) => ir.get_payload(direction, &name).map(|p| &p.fields[..]),
+            SymbolKind::Struct => ir.get_struct(&name).map(|s| &s.fields[..]),
+            _ => None,
+        }
+    }
+
+    /// A Markdown description of `symbol`, with its size, and offset for fields, when the
+    /// document is valid
+    pub fn hover(&self, symbol: usize) -> String {
+        let symbol = &self.symbols[symbol];
+        let name = Name::new(&symbol.name);
+        let ir = self.ir.as_ref();
+        let mut text = match symbol.kind {
+            SymbolKind::Payload(direction) => format!("**{direction} payload** `{}`", symbol.name),
+            SymbolKind::Struct => format!("**Struct** `{}`", symbol.name),
+            SymbolKind::Field => format!("**Field** `{}` of `{}`", symbol.name, symbol.owner.map(|o| &self.symbols[o].name[..]).unwrap_or_default()),
+            SymbolKind::Transaction => format!("**Transaction** `{}`", symbol.name),
+            SymbolKind::Metadata(direction) => format!("**{direction} metadata** `{}`", symbol.name),
+        };
+        match symbol.kind {
+            SymbolKind::Payload(direction) => {
+                if let Some(payload) = ir.and_then(|ir| ir.get_payload(direction, &name)) {
+                    text += &format!("\n\n{}\n\nSize: {}", payload.description, payload.size);
+                    if let Some(framing) = ir.and_then(|ir| ir.framing.as_ref()) {
+                        let envelope = framing.format(direction).iter().map(|e| e.envelope_bits()).sum();
+                        text += &format!(", framed: {}", payload.size + crate::size::Size::Fixed(envelope));
+                    }
+                }
+            }
+            SymbolKind::Struct => {
+                if let Some(rs) = ir.and_then(|ir| ir.get_struct(&name)) {
+                    if let Some(description) = &rs.description {
+                        text += &format!("\n\n{description}");
+                    }
+                    text += &format!("\n\nSize: {}", rs.size);
+                }
+            }
+            SymbolKind::Field => {
+                let fields = symbol.owner.and_then(|o| self.fields(&self.symbols[o]));
+                if let Some(field) = fields.and_then(|f| f.iter().find(|f| f.name.raw() == symbol.name)) {
+                    text += &format!("\n\n`{}`, {}", field.ty.summary(), field.size);
+                    if let Some(bits) = field.offset_bits {
+                        text += &format!(", at byte {}", byte_offset(bits));
+                    }
+                    if let Some(description) = &field.description {
+                        text += &format!("\n\n{description}");
+                    }
+                }
+            }
+            SymbolKind::Transaction => {
+                if let Some(transaction) = ir.and_then(|ir| ir.transactions.iter().find(|t| t.name == name)) {
+                    text += &format!("\n\n{}", transaction.description);
+                }
+            }
+            SymbolKind::Metadata(direction) => {
+                let framing = ir.and_then(|ir| ir.framing.as_ref());
+                if let Some((_, ty)) = framing.and_then(|f| f.metadata(direction).into_iter().find(|(n, _)| **n == name)) {
+                    text += &format!("\n\n`{}`", ty.summary());
+                }
+            }
+        }
+        text
+    }
+
+    fn completions(&self, wanted: impl Fn(&Symbol) -> bool) -> Vec<Completion> {
+        let detail = |s: &Symbol| match s.kind {
+            SymbolKind::Payload(direction) => Some(format!("{direction} payload")),
+            SymbolKind::Struct => Some("struct".to_owned()),
+            SymbolKind::Field => s.owner.map(|o| format!("field of {}", self.symbols[o].name)),
+            SymbolKind::Transaction => Some("transaction".to_owned()),
+            SymbolKind::Metadata(direction) => Some(format!("{direction} metadata")),
+        };
+        self.symbols.iter().filter(|s| wanted(s)).map(|s| Completion { label: s.name.clone(), kind: s.kind, detail: detail(s) }).collect()
+    }
+
+    /// The payload or struct whose `[section]` `offset` is in
+    fn section_at(&self, source: &str, offset: usize) -> Option<usize> {
+        let header = source[..offset].lines().rev().find(|l| l.trim_start().starts_with('['))?;
+        let path = header.trim().trim_start_matches('[').trim_end_matches(']').split('.').map(|p| p.trim().trim_matches('"')).collect::<Vec<_>>();
+        match path[..] {
+            ["payloads", "tx", name] => self.find(SymbolKind::Payload(Direction::Tx), name, None),
+            ["payloads", "rx", name] => self.find(SymbolKind::Payload(Direction::Rx), name, None),
+            ["structs", name] => self.find(SymbolKind::Struct, name, None),
+            _ => None,
+        }
+    }
+
+    /// What could be typed at `offset` in `source`. Works from the text around it rather than
+    /// the analysed document, so `source` can be a newer, half-typed version of it
+    pub fn complete(&self, source: &str, offset: usize) -> Vec<Completion> {
+        let line = &source[source[..offset].rfind('\n').map_or(0, |i| i + 1)..offset];
+        let section = self.section_at(source, offset);
+
+        // a key at the start of a line in a payload's section: its metadata
+        if line.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ' ') {
+            return match section.map(|s| self.symbols[s].kind) {
+                Some(SymbolKind::Payload(direction)) => self.completions(|s| s.kind == SymbolKind::Metadata(direction)),
+                _ => Vec::new(),
+            };
+        }
+
+        // otherwise only inside a string
+        if line.matches('"').count().is_multiple_of(2) {
+            return Vec::new();
+        }
+        let quote = line.rfind('"').unwrap();
+        let typed = &line[quote + 1..];
+        let key = line[..quote].trim_end().trim_end_matches('=').trim_end();
+        let key = &key[key.rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).map_or(0, |i| i + 1)..];
+        let in_returns = source[..offset].rfind("returns").is_some_and(|i| !source[i..offset].contains(']'));
+        match key {
+            "struct_name" | "item_struct" => self.completions(|s| s.kind == SymbolKind::Struct),
+            "payload" if line.contains("\"Tx\"") => self.completions(|s| s.kind == SymbolKind::Payload(Direction::Tx)),
+            "payload" if line.contains("\"Rx\"") => self.completions(|s| s.kind == SymbolKind::Payload(Direction::Rx)),
+            "payload" => self.completions(|s| matches!(s.kind, SymbolKind::Payload(_))),
+            "field_name" => self.completions(|s| s.kind == SymbolKind::Field && s.owner == section && section.is_some()),
+            _ if in_returns => {
+                let mut parts = typed.split('.').collect::<Vec<_>>();
+                parts.pop();
+                let Some((first, rest)) = parts.split_first() else {
+                    return self.completions(|s| s.kind == SymbolKind::Payload(Direction::Rx));
+                };
+                let mut owner = self.find(SymbolKind::Payload(Direction::Rx), first, None);
+                for part in rest {
+                    let field = owner.and_then(|o| self.find(SymbolKind::Field, part, Some(o)));
+                    owner = field.and_then(|f| self.symbols[f].holds.as_deref()).and_then(|s| self.find(SymbolKind::Struct, s, None));
+                }
+                match owner {
+                    Some(owner) => self.completions(|s| s.kind == SymbolKind::Field && s.owner == Some(owner)),
+                    None => Vec::new(),
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,918 @@
+//! Dynamic encoding and decoding of payloads and frames, driven directly by an [OpenPID] document.
+//! Favours clear errors over speed; it's meant for tests, tooling and sniffing traffic.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::prelude::*;
+use crate::value::*;
+use openpid_runtime as rt;
+
+/// Which way a payload travels, selecting between `[payloads.tx]` and `[payloads.rx]` and between
+/// the `tx_format` and `rx_format` frame formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Host to device
+    Tx,
+
+    /// Device to host
+    Rx,
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Tx => write!(f, "TX"),
+            Direction::Rx => write!(f, "RX"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecError {
+    UnknownPayload { direction: Direction, name: String },
+    NoFrameFormat,
+    NoStruct { field: String, struct_name: String },
+    RecursiveStruct { struct_name: String },
+    UnexpectedEnd { field: String, needed_bits: usize, available_bits: usize },
+    TrailingData { payload: String, bits: usize },
+    UnsupportedWidth { field: String, bits: u32, reason: &'static str },
+    ConstMismatch { field: String, expected: Vec<u8>, found: Vec<u8> },
+    InvalidUtf8 { field: String },
+    SequenceNotFound { field: String },
+    UnknownCountField { field: String, count_field: String },
+    BadCount { field: String, count_field: String },
+    MissingField { field: String },
+    TypeMismatch { field: String, expected: &'static str, found: &'static str },
+    OutOfRange { field: String, bits: u32 },
+    LengthMismatch { field: String, expected: usize, found: usize },
+    MissingMetadata { payload: String, key: String },
+    CrcMismatch { expected: u32, found: u32 },
+    TooManyCountFields { payload: String, max: usize },
+    NoMatchingPayload { metadata: Fields },
+
+    /// An error from the no_std runtime
+    Runtime { field: String, error: rt::Error },
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::UnknownPayload { direction, name } => write!(f, "No {direction} payload named \"{name}\""),
+            CodecError::NoFrameFormat => write!(f, "Document has no frame format (no [uart] section)"),
+            CodecError::NoStruct { field, struct_name } => write!(f, "Couldn't find struct named {struct_name} referenced by {field}"),
+            CodecError::RecursiveStruct { struct_name } => write!(f, "Struct {struct_name} contains itself"),
+            CodecError::UnexpectedEnd { field, needed_bits, available_bits } => {
+                write!(f, "Ran out of data reading {field}: needed {needed_bits} bits, {available_bits} left")
+            }
+            CodecError::TrailingData { payload, bits } => write!(f, "{bits} bits left over after decoding {payload}"),
+            CodecError::UnsupportedWidth { field, bits, reason } => write!(f, "{field} can't be {bits} bits wide: {reason}"),
+            CodecError::ConstMismatch { field, expected, found } => {
+                write!(f, "Constant {field} should be {expected:02x?}, found {found:02x?}")
+            }
+            CodecError::InvalidUtf8 { field } => write!(f, "{field} is not valid UTF8"),
+            CodecError::SequenceNotFound { field } => write!(f, "Terminating sequence for {field} not found"),
+            CodecError::UnknownCountField { field, count_field } => {
+                write!(f, "{field} is counted by \"{count_field}\", which doesn't precede it")
+            }
+            CodecError::BadCount { field, count_field } => write!(f, "\"{count_field}\" isn't a usable count for {field}"),
+            CodecError::MissingField { field } => write!(f, "No value given for {field}"),
+            CodecError::TypeMismatch { field, expected, found } => write!(f, "{field} expects a {expected}, got a {found}"),
+            CodecError::OutOfRange { field, bits } => write!(f, "Value for {field} doesn't fit in {bits} bits"),
+            CodecError::LengthMismatch { field, expected, found } => {
+                write!(f, "{field} should have {expected} elements, has {found}")
+            }
+            CodecError::MissingMetadata { payload, key } => write!(f, "Payload {payload} has no metadata \"{key}\" required by the frame format"),
+            CodecError::CrcMismatch { expected, found } => write!(f, "CRC mismatch: computed {expected:#x}, frame has {found:#x}"),
+            CodecError::TooManyCountFields { payload, max } => write!(f, "{payload} references more than {max} count fields"),
+            CodecError::NoMatchingPayload { metadata } => write!(f, "No payload matches frame metadata {}", Value::Struct(metadata.clone())),
+            CodecError::Runtime { field, error } => write!(f, "{field}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl CodecError {
+    pub(crate) fn runtime(field: &str, error: rt::Error) -> Self {
+        CodecError::Runtime { field: field.to_owned(), error }
+    }
+}
+
+type Result<T> = std::result::Result<T, CodecError>;
+
+impl AllPayloads {
+    /// Payloads travelling in the given direction
+    pub fn get(&self, direction: Direction) -> &BTreeMap<String, Payload> {
+        match direction {
+            Direction::Tx => &self.tx,
+            Direction::Rx => &self.rx,
+        }
+    }
+}
+
+impl UARTConfig {
+    /// Frame format used in the given direction
+    pub fn format(&self, direction: Direction) -> &[PacketFormatElement] {
+        match direction {
+            Direction::Tx => &self.tx_format,
+            Direction::Rx => &self.rx_format,
+        }
+    }
+}
+
+impl Crc {
+    /// Width of the checksum on the wire
+    pub fn bits(&self) -> usize {
+        rt::Crc::from(self).bits()
+    }
+
+    pub fn compute(&self, data: &[u8]) -> u32 {
+        rt::Crc::from(self).compute(data)
+    }
+}
+
+/// Reads fields most significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0, end: data.len() * 8 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.end - self.pos
+    }
+
+    fn ensure(&self, field: &str, bits: usize) -> Result<()> {
+        if self.remaining() < bits {
+            return Err(CodecError::UnexpectedEnd { field: field.to_owned(), needed_bits: bits, available_bits: self.remaining() });
+        }
+        Ok(())
+    }
+
+    fn read_bits(&mut self, field: &str, bits: usize) -> Result<u64> {
+        self.ensure(field, bits)?;
+        let value = rt::bits::read_bits(self.data, self.pos, bits as u32).map_err(|e| CodecError::runtime(field, e))?;
+        self.pos += bits;
+        Ok(value)
+    }
+
+    fn read_bytes(&mut self, field: &str, count: usize) -> Result<Vec<u8>> {
+        self.ensure(field, count * 8)?;
+        if self.pos.is_multiple_of(8) {
+            let start = self.pos / 8;
+            self.pos += count * 8;
+            return Ok(self.data[start..start + count].to_vec());
+        }
+        (0..count).map(|_| self.read_bits(field, 8).map(|b| b as u8)).collect()
+    }
+
+    /// Reads up to (and consumes) `sequence`, returning the bytes before it
+    fn read_until(&mut self, field: &str, sequence: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        loop {
+            if self.starts_with(sequence) {
+                self.pos += sequence.len() * 8;
+                return Ok(out);
+            }
+            if self.remaining() < 8 {
+                return Err(CodecError::SequenceNotFound { field: field.to_owned() });
+            }
+            out.push(self.read_bits(field, 8)? as u8);
+        }
+    }
+
+    fn starts_with(&self, sequence: &[u8]) -> bool {
+        if self.remaining() < sequence.len() * 8 {
+            return false;
+        }
+        let mut peek = BitReader { data: self.data, pos: self.pos, end: self.end };
+        sequence.iter().all(|b| peek.read_bits("", 8).ok() == Some(*b as u64))
+    }
+}
+
+/// Writes fields most significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, value: u64, bits: usize) {
+        self.bytes.resize((self.len + bits).div_ceil(8), 0);
+        rt::bits::write_bits(&mut self.bytes, self.len, bits as u32, value).expect("buffer was just grown to fit");
+        self.len += bits;
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.extend_from_slice(bytes);
+            self.len += bytes.len() * 8;
+        } else {
+            for b in bytes {
+                self.write_bits(*b as u64, 8);
+            }
+        }
+    }
+
+    fn append(&mut self, other: &BitWriter) {
+        let mut reader = BitReader { data: &other.bytes, pos: 0, end: other.len };
+        while reader.remaining() > 0 {
+            let bits = reader.remaining().min(64);
+            let value = reader.read_bits("", bits).unwrap();
+            self.write_bits(value, bits);
+        }
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{path}.{name}")
+    }
+}
+
+/// Byte-swaps a byte-multiple integer read most significant byte first
+pub(crate) fn reorder(field: &str, raw: u64, bits: u32, endianness: &Endianness) -> Result<u64> {
+    rt::bits::reorder(raw, bits, endianness.into()).map_err(|_| CodecError::UnsupportedWidth {
+        field: field.to_owned(),
+        bits,
+        reason: "little endian fields must be whole bytes",
+    })
+}
+
+pub(crate) fn check_width(field: &str, bits: u32, max: u32) -> Result<()> {
+    if bits == 0 || bits > max {
+        return Err(CodecError::UnsupportedWidth { field: field.to_owned(), bits, reason: "unsupported width" });
+    }
+    Ok(())
+}
+
+pub(crate) fn whole_bytes(field: &str, bits: u32) -> Result<usize> {
+    if !bits.is_multiple_of(8) {
+        return Err(CodecError::UnsupportedWidth { field: field.to_owned(), bits, reason: "must be a whole number of bytes" });
+    }
+    Ok(bits as usize / 8)
+}
+
+pub(crate) fn decode_integer(raw: u64, bits: u32, signing: &Signing) -> Value {
+    match signing {
+        Signing::Unsigned => Value::UInt(raw),
+        _ => Value::Int(rt::bits::to_signed(raw, bits, signing.into())),
+    }
+}
+
+fn encode_integer(field: &str, value: i128, bits: u32, signing: &Signing) -> Result<u64> {
+    rt::bits::from_signed(value, bits, signing.into()).map_err(|_| CodecError::OutOfRange { field: field.to_owned(), bits })
+}
+
+fn lookup_count(field: &str, count_field: &str, scopes: &[&Fields]) -> Result<usize> {
+    let value = scopes
+        .iter()
+        .rev()
+        .find_map(|scope| scope.get(count_field))
+        .ok_or_else(|| CodecError::UnknownCountField { field: field.to_owned(), count_field: count_field.to_owned() })?;
+    value
+        .as_i128()
+        .and_then(|c| usize::try_from(c).ok())
+        .ok_or_else(|| CodecError::BadCount { field: field.to_owned(), count_field: count_field.to_owned() })
+}
+
+fn bytes_of(field: &str, value: &Value) -> Result<Vec<u8>> {
+    match value {
+        Value::Bytes(b) => Ok(b.clone()),
+        Value::Array(items) => items
+            .iter()
+            .map(|i| i.as_i128().and_then(|b| u8::try_from(b).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| CodecError::TypeMismatch { field: field.to_owned(), expected: "bytes", found: "array" }),
+        other => Err(CodecError::TypeMismatch { field: field.to_owned(), expected: "bytes", found: other.kind() }),
+    }
+}
+
+fn string_of<'v>(field: &str, value: &'v Value) -> Result<&'v str> {
+    match value {
+        Value::String(s) => Ok(s),
+        other => Err(CodecError::TypeMismatch { field: field.to_owned(), expected: "string", found: other.kind() }),
+    }
+}
+
+fn items_of<'v>(field: &str, value: &'v Value) -> Result<&'v [Value]> {
+    match value {
+        Value::Array(items) => Ok(items),
+        Value::Bytes(b) if b.is_empty() => Ok(&[]),
+        other => Err(CodecError::TypeMismatch { field: field.to_owned(), expected: "array", found: other.kind() }),
+    }
+}
+
+fn struct_of<'v>(field: &str, value: &'v Value) -> Result<&'v Fields> {
+    match value {
+        Value::Struct(fields) => Ok(fields),
+        other => Err(CodecError::TypeMismatch { field: field.to_owned(), expected: "struct", found: other.kind() }),
+    }
+}
+
+impl OpenPID {
+    fn get_struct(&self, field: &str, struct_name: &str) -> Result<&ReusableStruct> {
+        self.structs
+            .get(struct_name)
+            .ok_or_else(|| CodecError::NoStruct { field: field.to_owned(), struct_name: struct_name.to_owned() })
+    }
+
+    /// Looks up a struct about to be entered, refusing one that's already being entered further up,
+    /// which would never end. Pop it off `visiting` when done with it
+    fn enter_struct<'a>(&'a self, field: &str, struct_name: &'a str, visiting: &mut Vec<&'a str>) -> Result<&'a ReusableStruct> {
+        if visiting.contains(&struct_name) {
+            return Err(CodecError::RecursiveStruct { struct_name: struct_name.to_owned() });
+        }
+        let rs = self.get_struct(field, struct_name)?;
+        visiting.push(struct_name);
+        Ok(rs)
+    }
+
+    /// Looks up a payload by direction and name
+    pub fn get_payload(&self, direction: Direction, name: &str) -> Result<&Payload> {
+        self.payloads
+            .get(direction)
+            .get(name)
+            .ok_or_else(|| CodecError::UnknownPayload { direction, name: name.to_owned() })
+    }
+
+    /// Decodes a bare payload, without any frame around it
+    pub fn decode_payload(&self, direction: Direction, name: &str, data: &[u8]) -> Result<DecodedPayload> {
+        let payload = self.get_payload(direction, name)?;
+        let mut reader = BitReader::new(data);
+        let mut spans = Vec::new();
+        let fields = self.decode_segments(&payload.segments, &mut reader, "", &[], &mut spans, &mut Vec::new())?;
+        if reader.remaining() >= 8 {
+            return Err(CodecError::TrailingData { payload: name.to_owned(), bits: reader.remaining() });
+        }
+        Ok(DecodedPayload { name: name.to_owned(), fields, spans })
+    }
+
+    fn decode_segments<'a>(
+        &'a self,
+        segments: &'a [PacketSegment],
+        reader: &mut BitReader,
+        path: &str,
+        outer: &[&Fields],
+        spans: &mut Vec<FieldSpan>,
+        visiting: &mut Vec<&'a str>,
+    ) -> Result<Fields> {
+        let mut fields = Fields::new();
+        for segment in segments {
+            let field = join(path, segment.get_name());
+            let start = reader.pos;
+            let value = match segment {
+                PacketSegment::Sized { bits, datatype, .. } => decode_sized(&field, *bits, datatype, reader)?,
+                PacketSegment::Unsized { datatype, termination, .. } => {
+                    let mut scopes = outer.to_vec();
+                    scopes.push(&fields);
+                    self.decode_unsized(&field, datatype, termination.as_ref(), reader, &scopes, spans, visiting)?
+                }
+                PacketSegment::Struct { struct_name, .. } => {
+                    let rs = self.enter_struct(&field, struct_name, visiting)?;
+                    let mut scopes = outer.to_vec();
+                    scopes.push(&fields);
+                    let value = Value::Struct(self.decode_segments(&rs.fields, reader, &field, &scopes, spans, visiting)?);
+                    visiting.pop();
+                    value
+                }
+            };
+            spans.push(FieldSpan { path: field, span: Span { offset_bits: start, len_bits: reader.pos - start } });
+            fields.0.push((segment.get_name().to_owned(), value));
+        }
+        Ok(fields)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn decode_unsized<'a>(
+        &'a self,
+        field: &str,
+        datatype: &'a UnsizedDataType,
+        termination: Option<&Terminator>,
+        reader: &mut BitReader,
+        scopes: &[&Fields],
+        spans: &mut Vec<FieldSpan>,
+        visiting: &mut Vec<&'a str>,
+    ) -> Result<Value> {
+        let count = match termination {
+            Some(Terminator::CountFixed { count }) => Some(*count as usize),
+            Some(Terminator::CountInPacket { field_name }) => Some(lookup_count(field, field_name, scopes)?),
+            Some(Terminator::Sequence { .. }) | None => None,
+        };
+
+        match datatype {
+            UnsizedDataType::Raw | UnsizedDataType::StringUTF8 => {
+                let bytes = match (count, termination) {
+                    (Some(count), _) => reader.read_bytes(field, count)?,
+                    (None, Some(Terminator::Sequence { sequence })) => reader.read_until(field, sequence)?,
+                    _ => reader.read_bytes(field, reader.remaining() / 8)?,
+                };
+                if matches!(datatype, UnsizedDataType::Raw) {
+                    Ok(Value::Bytes(bytes))
+                } else {
+                    String::from_utf8(bytes)
+                        .map(Value::String)
+                        .map_err(|_| CodecError::InvalidUtf8 { field: field.to_owned() })
+                }
+            }
+            UnsizedDataType::Array { item_struct } => {
+                let rs = self.enter_struct(field, item_struct, visiting)?;
+                let mut items = Vec::new();
+                loop {
+                    let done = match (count, termination) {
+                        (Some(count), _) => items.len() == count,
+                        (None, Some(Terminator::Sequence { sequence })) => {
+                            if reader.starts_with(sequence) {
+                                reader.pos += sequence.len() * 8;
+                                true
+                            } else {
+                                false
+                            }
+                        }
+                        _ => reader.remaining() < 8,
+                    };
+                    if done {
+                        break;
+                    }
+                    let item_path = format!("{field}[{}]", items.len());
+                    let start = reader.pos;
+                    let item = self.decode_segments(&rs.fields, reader, &item_path, scopes, spans, visiting)?;
+                    spans.push(FieldSpan { path: item_path, span: Span { offset_bits: start, len_bits: reader.pos - start } });
+                    items.push(Value::Struct(item));
+                }
+                visiting.pop();
+                Ok(Value::Array(items))
+            }
+        }
+    }
+
+    /// Encodes a bare payload from its field values. `CountInPacket` fields may be left out, in
+    /// which case they're filled in from the length of the field they count
+    pub fn encode_payload(&self, direction: Direction, name: &str, fields: &Fields) -> Result<Vec<u8>> {
+        let payload = self.get_payload(direction, name)?;
+        let mut writer = BitWriter::default();
+        self.encode_segments(&payload.segments, fields, &mut writer, "", &mut Vec::new())?;
+        Ok(writer.bytes)
+    }
+
+    fn encode_segments<'a>(
+        &'a self,
+        segments: &'a [PacketSegment],
+        fields: &Fields,
+        writer: &mut BitWriter,
+        path: &str,
+        visiting: &mut Vec<&'a str>,
+    ) -> Result<()> {
+        // counts implied by the length of the fields that reference them
+        let mut implied_counts = BTreeMap::new();
+        for segment in segments {
+            if let PacketSegment::Unsized { name, termination: Some(Terminator::CountInPacket { field_name }), .. } = segment {
+                if let Some(count) = fields.get(name).and_then(Value::element_count) {
+                    implied_counts.insert(field_name.as_str(), count);
+                }
+            }
+        }
+
+        for segment in segments {
+            let field = join(path, segment.get_name());
+            let given = fields.get(segment.get_name());
+            match segment {
+                PacketSegment::Sized { bits, datatype, .. } => {
+                    let implied = implied_counts.get(segment.get_name()).map(|c| Value::UInt(*c as u64));
+                    if let (Some(given), Some(implied)) = (given, &implied) {
+                        if given.as_i128() != implied.as_i128() {
+                            return Err(CodecError::LengthMismatch {
+                                field: field.clone(),
+                                expected: given.as_i128().unwrap_or(0) as usize,
+                                found: implied.as_i128().unwrap_or(0) as usize,
+                            });
+                        }
+                    }
+                    encode_sized(&field, *bits, datatype, given.or(implied.as_ref()), writer)?;
+                }
+                PacketSegment::Unsized { datatype, termination, .. } => {
+                    let value = given.ok_or_else(|| CodecError::MissingField { field: field.clone() })?;
+                    self.encode_unsized(&field, datatype, termination.as_ref(), value, writer, visiting)?;
+                }
+                PacketSegment::Struct { struct_name, .. } => {
+                    let rs = self.enter_struct(&field, struct_name, visiting)?;
+                    let value = given.ok_or_else(|| CodecError::MissingField { field: field.clone() })?;
+                    self.encode_segments(&rs.fields, struct_of(&field, value)?, writer, &field, visiting)?;
+                    visiting.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_unsized<'a>(
+        &'a self,
+        field: &str,
+        datatype: &'a UnsizedDataType,
+        termination: Option<&Terminator>,
+        value: &Value,
+        writer: &mut BitWriter,
+        visiting: &mut Vec<&'a str>,
+    ) -> Result<()> {
+        let length = match datatype {
+            UnsizedDataType::Raw => {
+                let bytes = bytes_of(field, value)?;
+                writer.write_bytes(&bytes);
+                bytes.len()
+            }
+            UnsizedDataType::StringUTF8 => {
+                let s = string_of(field, value)?;
+                writer.write_bytes(s.as_bytes());
+                s.len()
+            }
+            UnsizedDataType::Array { item_struct } => {
+                let rs = self.enter_struct(field, item_struct, visiting)?;
+                let items = items_of(field, value)?;
+                for (i, item) in items.iter().enumerate() {
+                    let item_path = format!("{field}[{i}]");
+                    self.encode_segments(&rs.fields, struct_of(&item_path, item)?, writer, &item_path, visiting)?;
+                }
+                visiting.pop();
+                items.len()
+            }
+        };
+
+        match termination {
+            Some(Terminator::CountFixed { count }) if *count as usize != length => {
+                Err(CodecError::LengthMismatch { field: field.to_owned(), expected: *count as usize, found: length })
+            }
+            Some(Terminator::Sequence { sequence }) => {
+                writer.write_bytes(sequence);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Wraps a payload in the direction's frame format, filling in sizes, metadata and CRCs
+    pub fn encode_frame(&self, direction: Direction, name: &str, fields: &Fields) -> Result<Vec<u8>> {
+        let format = self.uart.as_ref().ok_or(CodecError::NoFrameFormat)?.format(direction);
+        let payload = self.get_payload(direction, name)?;
+
+        let mut body = BitWriter::default();
+        self.encode_segments(&payload.segments, fields, &mut body, "", &mut Vec::new())?;
+
+        let total_bits = frame_bits(format, body.len)?;
+        let mut writer = BitWriter::default();
+        self.encode_elements(format, name, payload, &body, total_bits, &mut writer)?;
+        Ok(writer.bytes)
+    }
+
+    fn encode_elements(
+        &self,
+        elements: &[PacketFormatElement],
+        name: &str,
+        payload: &Payload,
+        body: &BitWriter,
+        total_bits: usize,
+        writer: &mut BitWriter,
+    ) -> Result<()> {
+        for element in elements {
+            match element {
+                PacketFormatElement::SizeTotal { size_bits, express_as } => {
+                    write_size("SizeTotal", total_bits, *size_bits, express_as, writer)?;
+                }
+                PacketFormatElement::SizeOfPayload { size_bits, express_as } => {
+                    write_size("SizeOfPayload", body.len, *size_bits, express_as, writer)?;
+                }
+                PacketFormatElement::SizeOfElements { size_bits, express_as, elements } => {
+                    write_size("SizeOfElements", frame_bits(elements, body.len)?, *size_bits, express_as, writer)?;
+                    self.encode_elements(elements, name, payload, body, total_bits, writer)?;
+                }
+                PacketFormatElement::Payload => writer.append(body),
+                PacketFormatElement::Metadata { segment, .. } => {
+                    let literal = payload
+                        .metadata
+                        .get(segment.get_name())
+                        .and_then(|m| m.as_many_ref().into_iter().next().cloned())
+                        .ok_or_else(|| CodecError::MissingMetadata { payload: name.to_owned(), key: segment.get_name().to_owned() })?;
+                    let value = match literal {
+                        LiteralValue::Int(i) => Value::Int(i),
+                        LiteralValue::String(s) => Value::String(s),
+                    };
+                    let PacketSegment::Sized { bits, datatype, .. } = segment else {
+                        return Err(CodecError::UnsupportedWidth { field: segment.get_name().to_owned(), bits: 0, reason: "metadata must be sized" });
+                    };
+                    encode_sized(segment.get_name(), *bits, datatype, Some(&value), writer)?;
+                }
+                PacketFormatElement::Crc { algorithm } => {
+                    let crc = algorithm.compute(&writer.bytes);
+                    writer.write_bits(crc as u64, algorithm.bits());
+                }
+                PacketFormatElement::Const { data, bits, .. } => write_const(data, *bits, writer),
+            }
+        }
+        Ok(())
+    }
+
+    /// Payloads whose metadata agrees with the metadata decoded from a frame
+    pub fn identify_payloads(&self, direction: Direction, metadata: &Fields) -> Vec<&str> {
+        self.payloads
+            .get(direction)
+            .iter()
+            .filter(|(_, payload)| {
+                metadata.iter().all(|(key, value)| {
+                    payload
+                        .metadata
+                        .get(key)
+                        .is_some_and(|m| m.as_many_ref().into_iter().any(|l| value.matches_literal(l)))
+                })
+            })
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Decodes one complete frame, identifying the payload from the frame's metadata
+    pub fn decode_frame(&self, direction: Direction, data: &[u8]) -> Result<DecodedFrame> {
+        let format = self.uart.as_ref().ok_or(CodecError::NoFrameFormat)?.format(direction);
+        let mut reader = BitReader::new(data);
+        let mut envelope = Envelope::default();
+        read_elements(format, &mut reader, None, envelope_bits(format)?, &mut envelope)?;
+        if reader.remaining() >= 8 {
+            return Err(CodecError::TrailingData { payload: "frame".to_owned(), bits: reader.remaining() });
+        }
+
+        let (payload_span, body) = envelope.payload.unwrap_or((Span { offset_bits: 0, len_bits: 0 }, Vec::new()));
+        let candidates = self.identify_payloads(direction, &envelope.metadata);
+        let mut first_error = None;
+        for name in candidates {
+            match self.decode_payload(direction, name, &body) {
+                Ok(payload) => return Ok(DecodedFrame { metadata: envelope.metadata, payload, payload_span }),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap_or(CodecError::NoMatchingPayload { metadata: envelope.metadata }))
+    }
+}
+
+fn decode_sized(field: &str, bits: u32, datatype: &SizedDataType, reader: &mut BitReader) -> Result<Value> {
+    match datatype {
+        SizedDataType::Integer { endianness, signing } => {
+            check_width(field, bits, 64)?;
+            let raw = reorder(field, reader.read_bits(field, bits as usize)?, bits, endianness)?;
+            Ok(decode_integer(raw, bits, signing))
+        }
+        SizedDataType::FloatIEEE { endianness } => {
+            let raw = reorder(field, reader.read_bits(field, bits as usize)?, bits, endianness)?;
+            match bits {
+                32 => Ok(Value::Float(f32::from_bits(raw as u32) as f64)),
+                64 => Ok(Value::Float(f64::from_bits(raw))),
+                _ => Err(CodecError::UnsupportedWidth { field: field.to_owned(), bits, reason: "floats must be 32 or 64 bits" }),
+            }
+        }
+        SizedDataType::Raw => Ok(Value::Bytes(reader.read_bytes(field, whole_bytes(field, bits)?)?)),
+        SizedDataType::StringUTF8 => {
+            let mut bytes = reader.read_bytes(field, whole_bytes(field, bits)?)?;
+            while bytes.last() == Some(&0) {
+                bytes.pop();
+            }
+            String::from_utf8(bytes)
+                .map(Value::String)
+                .map_err(|_| CodecError::InvalidUtf8 { field: field.to_owned() })
+        }
+        SizedDataType::Const { data } => {
+            let found = reader.read_bytes(field, whole_bytes(field, bits)?)?;
+            if found != *data {
+                return Err(CodecError::ConstMismatch { field: field.to_owned(), expected: data.clone(), found });
+            }
+            Ok(Value::Bytes(found))
+        }
+    }
+}
+
+fn encode_sized(field: &str, bits: u32, datatype: &SizedDataType, value: Option<&Value>, writer: &mut BitWriter) -> Result<()> {
+    // constants don't need a value, everything else does
+    if let SizedDataType::Const { data } = datatype {
+        if let Some(given) = value {
+            let given = bytes_of(field, given)?;
+            if given != *data {
+                return Err(CodecError::ConstMismatch { field: field.to_owned(), expected: data.clone(), found: given });
+            }
+        }
+        if data.len() != whole_bytes(field, bits)? {
+            return Err(CodecError::LengthMismatch { field: field.to_owned(), expected: bits as usize / 8, found: data.len() });
+        }
+        writer.write_bytes(data);
+        return Ok(());
+    }
+    let value = value.ok_or_else(|| CodecError::MissingField { field: field.to_owned() })?;
+
+    match datatype {
+        SizedDataType::Integer { endianness, signing } => {
+            check_width(field, bits, 64)?;
+            let integer = value
+                .as_i128()
+                .ok_or_else(|| CodecError::TypeMismatch { field: field.to_owned(), expected: "integer", found: value.kind() })?;
+            let raw = encode_integer(field, integer, bits, signing)?;
+            writer.write_bits(reorder(field, raw, bits, endianness)?, bits as usize);
+        }
+        SizedDataType::FloatIEEE { endianness } => {
+            let float = match value {
+                Value::Float(f) => *f,
+                other => other
+                    .as_i128()
+                    .ok_or_else(|| CodecError::TypeMismatch { field: field.to_owned(), expected: "float", found: value.kind() })?
+                    as f64,
+            };
+            let raw = match bits {
+                32 => (float as f32).to_bits() as u64,
+                64 => float.to_bits(),
+                _ => return Err(CodecError::UnsupportedWidth { field: field.to_owned(), bits, reason: "floats must be 32 or 64 bits" }),
+            };
+            writer.write_bits(reorder(field, raw, bits, endianness)?, bits as usize);
+        }
+        SizedDataType::Raw => {
+            let bytes = bytes_of(field, value)?;
+            let expected = whole_bytes(field, bits)?;
+            if bytes.len() != expected {
+                return Err(CodecError::LengthMismatch { field: field.to_owned(), expected, found: bytes.len() });
+            }
+            writer.write_bytes(&bytes);
+        }
+        SizedDataType::StringUTF8 => {
+            let s = string_of(field, value)?;
+            let capacity = whole_bytes(field, bits)?;
+            if s.len() > capacity {
+                return Err(CodecError::LengthMismatch { field: field.to_owned(), expected: capacity, found: s.len() });
+            }
+            writer.write_bytes(s.as_bytes());
+            writer.write_bytes(&vec![0; capacity - s.len()]);
+        }
+        SizedDataType::Const { .. } => unreachable!("constants are written above"),
+    }
+    Ok(())
+}
+
+fn write_const(data: &[u8], bits: Option<usize>, writer: &mut BitWriter) {
+    match bits {
+        // the last `bits` bits of the data, so that e.g. a 4 bit flag can be written as [0x0A]
+        Some(bits) => {
+            let mut reader = BitReader { data, pos: (data.len() * 8).saturating_sub(bits), end: data.len() * 8 };
+            while reader.remaining() > 0 {
+                let n = reader.remaining().min(64);
+                let value = reader.read_bits("", n).unwrap();
+                writer.write_bits(value, n);
+            }
+        }
+        None => writer.write_bytes(data),
+    }
+}
+
+fn write_size(field: &str, bits: usize, size_bits: u32, express_as: &BitsOrBytes, writer: &mut BitWriter) -> Result<()> {
+    let size = match express_as {
+        BitsOrBytes::Bits => bits,
+        BitsOrBytes::Bytes => bits.div_ceil(8),
+    } as u64;
+    check_width(field, size_bits, 64)?;
+    if size_bits < 64 && size >> size_bits != 0 {
+        return Err(CodecError::OutOfRange { field: field.to_owned(), bits: size_bits });
+    }
+    writer.write_bits(size, size_bits as usize);
+    Ok(())
+}
+
+fn to_bits(size: u64, express_as: &BitsOrBytes) -> usize {
+    match express_as {
+        BitsOrBytes::Bits => size as usize,
+        BitsOrBytes::Bytes => size as usize * 8,
+    }
+}
+
+/// Size of the elements of a frame format, given the size of the payload inside it
+fn frame_bits(elements: &[PacketFormatElement], payload_bits: usize) -> Result<usize> {
+    let mut total = 0;
+    for element in elements {
+        total += match element {
+            PacketFormatElement::Payload => payload_bits,
+            PacketFormatElement::SizeOfElements { size_bits, elements, .. } => *size_bits as usize + frame_bits(elements, payload_bits)?,
+            other => envelope_element_bits(other)?,
+        };
+    }
+    Ok(total)
+}
+
+/// Size of everything in the frame format except the payload
+fn envelope_bits(elements: &[PacketFormatElement]) -> Result<usize> {
+    frame_bits(elements, 0)
+}
+
+fn envelope_element_bits(element: &PacketFormatElement) -> Result<usize> {
+    Ok(match element {
+        PacketFormatElement::SizeTotal { size_bits, .. } | PacketFormatElement::SizeOfPayload { size_bits, .. } => *size_bits as usize,
+        PacketFormatElement::SizeOfElements { size_bits, elements, .. } => *size_bits as usize + envelope_bits(elements)?,
+        PacketFormatElement::Payload => 0,
+        PacketFormatElement::Metadata { segment: PacketSegment::Sized { bits, .. }, .. } => *bits as usize,
+        PacketFormatElement::Metadata { segment, .. } => {
+            return Err(CodecError::UnsupportedWidth { field: segment.get_name().to_owned(), bits: 0, reason: "metadata must be sized" })
+        }
+        PacketFormatElement::Crc { algorithm } => algorithm.bits(),
+        PacketFormatElement::Const { data, bits, .. } => bits.unwrap_or(data.len() * 8),
+    })
+}
+
+/// What we learn about a frame while reading its envelope
+#[derive(Default)]
+struct Envelope {
+    metadata: Fields,
+    total_bits: Option<usize>,
+    payload_bits: Option<usize>,
+    payload: Option<(Span, Vec<u8>)>,
+}
+
+/// Reads a frame format's elements. `region_bits` is the size of the enclosing `SizeOfElements`,
+/// if any, which bounds a payload nested in it. `static_bits` is the size of the whole envelope,
+/// less the payload
+fn read_elements(
+    elements: &[PacketFormatElement],
+    reader: &mut BitReader,
+    region_bits: Option<usize>,
+    static_bits: usize,
+    envelope: &mut Envelope,
+) -> Result<()> {
+    for (i, element) in elements.iter().enumerate() {
+        match element {
+            PacketFormatElement::SizeTotal { size_bits, express_as } => {
+                check_width("SizeTotal", *size_bits, 64)?;
+                let total = to_bits(reader.read_bits("SizeTotal", *size_bits as usize)?, express_as);
+                if total > reader.end {
+                    return Err(CodecError::UnexpectedEnd { field: "frame".to_owned(), needed_bits: total, available_bits: reader.end });
+                }
+                envelope.total_bits = Some(total);
+            }
+            PacketFormatElement::SizeOfPayload { size_bits, express_as } => {
+                check_width("SizeOfPayload", *size_bits, 64)?;
+                envelope.payload_bits = Some(to_bits(reader.read_bits("SizeOfPayload", *size_bits as usize)?, express_as));
+            }
+            PacketFormatElement::SizeOfElements { size_bits, express_as, elements } => {
+                check_width("SizeOfElements", *size_bits, 64)?;
+                let region = to_bits(reader.read_bits("SizeOfElements", *size_bits as usize)?, express_as);
+                read_elements(elements, reader, Some(region), static_bits, envelope)?;
+            }
+            PacketFormatElement::Payload => {
+                let bits = match (envelope.payload_bits, region_bits, envelope.total_bits) {
+                    (Some(bits), _, _) => bits,
+                    (None, Some(region), _) => region.saturating_sub(envelope_bits(elements)?),
+                    (None, None, Some(total)) => total.saturating_sub(static_bits),
+                    // everything left, less whatever follows the payload
+                    (None, None, None) => reader.remaining().saturating_sub(envelope_bits(&elements[i + 1..])?),
+                };
+                reader.ensure("Payload", bits)?;
+                let start = reader.pos;
+                envelope.payload = Some((Span { offset_bits: start, len_bits: bits }, read_bits_to_bytes(reader, "Payload", bits)?));
+            }
+            PacketFormatElement::Metadata { segment, .. } => {
+                let PacketSegment::Sized { bits, datatype, .. } = segment else {
+                    return Err(CodecError::UnsupportedWidth { field: segment.get_name().to_owned(), bits: 0, reason: "metadata must be sized" });
+                };
+                let value = decode_sized(segment.get_name(), *bits, datatype, reader)?;
+                envelope.metadata.insert(segment.get_name(), value);
+            }
+            PacketFormatElement::Crc { algorithm } => {
+                if !reader.pos.is_multiple_of(8) {
+                    return Err(CodecError::UnsupportedWidth { field: "Crc".to_owned(), bits: algorithm.bits() as u32, reason: "CRCs must start on a byte boundary" });
+                }
+                let expected = algorithm.compute(&reader.data[..reader.pos / 8]);
+                let found = reader.read_bits("Crc", algorithm.bits())? as u32;
+                if expected != found {
+                    return Err(CodecError::CrcMismatch { expected, found });
+                }
+            }
+            PacketFormatElement::Const { data, bits, .. } => {
+                let mut expected = BitWriter::default();
+                write_const(data, *bits, &mut expected);
+                let found = read_bits_to_bytes(reader, "Const", expected.len)?;
+                if found != expected.bytes {
+                    return Err(CodecError::ConstMismatch { field: "Const".to_owned(), expected: expected.bytes, found });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies `bits` bits out of the reader, left aligned in the returned bytes
+fn read_bits_to_bytes(reader: &mut BitReader, field: &str, bits: usize) -> Result<Vec<u8>> {
+    let mut out = BitWriter::default();
+    let mut left = bits;
+    while left > 0 {
+        let n = left.min(64);
+        out.write_bits(reader.read_bits(field, n)?, n);
+        left -= n;
+    }
+    Ok(out.bytes)
+}
@@ -0,0 +1,1554 @@
+//! Generates a C99 driver: a header and source pair with no dynamic allocation, plus a test
+//! harness and Makefile for the host compiler.
+//!
+//! Encoding goes through a bit writer one field at a time, so nothing relies on struct packing or
+//! the host's endianness. Frame formats are turned into straight-line code rather than
+//! interpreted. The driver talks to the device through a small user-supplied HAL of `write`,
+//! `read_with_timeout` and `delay_ms` function pointers.
+
+use super::layout::Format;
+use super::{BackendInfo, BackendOptions, CodeWriter, Codegen, CodegenError, Layout, OptionInfo, OutputSink};
+use crate::ir::*;
+use crate::value::{Fields, Value};
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "c",
+    description: "C99 header and source, with no dynamic allocation",
+    options: &[
+        OptionInfo { name: "prefix", description: "Prefix for every generated name, and the file names", default: Some("the device's name, in snake case") },
+        OptionInfo { name: "max-len", description: "Capacity of variable length fields the document doesn't bound", default: Some("64") },
+        OptionInfo { name: "tests", description: "Generate a test harness and Makefile", default: Some("true") },
+    ],
+    create: |options| Ok(Box::new(CBackend::new(options)?)),
+};
+
+pub struct CBackend {
+    prefix: Option<String>,
+    max_len: u32,
+    tests: bool,
+}
+
+impl CBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        let max_len = options.get_or("max-len", "64");
+        Ok(Self {
+            prefix: options.get("prefix").map(str::to_owned),
+            max_len: max_len.parse().map_err(|_| CodegenError::BadOption { option: "max-len".to_owned(), value: max_len.to_owned(), expected: "a number" })?,
+            tests: options.get_bool("tests", true)?,
+        })
+    }
+}
+
+impl Codegen for CBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let prefix = Name::new(&self.prefix.clone().unwrap_or_else(|| ir.device.name.snake()));
+        let gen = Gen::new(ir, Layout::new(ir, self.max_len), &prefix)?;
+        let p = &gen.p;
+        out.write_text(&format!("{p}.h"), &gen.header())?;
+        out.write_text(&format!("{p}.c"), &gen.source())?;
+        if self.tests {
+            out.write_text(&format!("test_{p}.c"), &gen.harness())?;
+            out.write_text("Makefile", &MAKEFILE.replace("pfx", p))?;
+        }
+        Ok(())
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern", "float", "for", "goto", "if", "inline", "int",
+    "long", "register", "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void", "volatile",
+    "while", "bool", "true", "false",
+];
+
+fn ident(name: &Name) -> String {
+    let snake = name.snake();
+    if KEYWORDS.contains(&snake.as_str()) {
+        format!("{snake}_")
+    } else if snake.starts_with(|c: char| c.is_ascii_digit()) || snake.is_empty() {
+        format!("_{snake}")
+    } else {
+        snake
+    }
+}
+
+fn int_type(bits: u32, signing: Signing) -> String {
+    let width = match bits {
+        0..=8 => 8,
+        9..=16 => 16,
+        17..=32 => 32,
+        _ => 64,
+    };
+    match signing {
+        Signing::Unsigned => format!("uint{width}_t"),
+        _ => format!("int{width}_t"),
+    }
+}
+
+fn little(endianness: Endianness) -> u8 {
+    (endianness == Endianness::LittleEndian) as u8
+}
+
+fn bytes_literal(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "NULL".to_owned();
+    }
+    format!("(const uint8_t[]){{{}}}", data.iter().map(|b| format!("0x{b:02x}")).collect::<Vec<_>>().join(", "))
+}
+
+/// A `/* */` comment, on one line if it fits
+fn comment(w: &mut CodeWriter, text: &str) {
+    let text = text.trim().replace("*/", "* /");
+    if text.is_empty() {
+        return;
+    }
+    if text.lines().count() == 1 {
+        w.line(format!("/* {text} */"));
+    } else {
+        w.line("/*");
+        w.comment(" *", &text);
+        w.line(" */");
+    }
+}
+
+struct Gen<'a> {
+    ir: &'a Ir,
+    layout: Layout<'a>,
+
+    /// Lower and upper case prefixes
+    p: String,
+    pu: String,
+    tx: Option<Format>,
+    rx: Option<Format>,
+}
+
+impl<'a> Gen<'a> {
+    fn new(ir: &'a Ir, layout: Layout<'a>, prefix: &Name) -> Result<Self, CodegenError> {
+        let [tx, rx] = Format::both(ir, "c")?;
+        Ok(Self { ir, layout, p: prefix.snake(), pu: prefix.screaming(), tx, rx })
+    }
+
+    fn format(&self, direction: Direction) -> Option<&Format> {
+        match direction {
+            Direction::Tx => self.tx.as_ref(),
+            Direction::Rx => self.rx.as_ref(),
+        }
+    }
+
+    fn dir(direction: Direction) -> &'static str {
+        match direction {
+            Direction::Tx => "tx",
+            Direction::Rx => "rx",
+        }
+    }
+
+    fn struct_type(&self, name: &Name) -> String {
+        format!("{}_{}_t", self.p, name.snake())
+    }
+
+    fn payload_type(&self, direction: Direction, name: &Name) -> String {
+        format!("{}_{}_{}_t", self.p, Self::dir(direction), name.snake())
+    }
+
+    /// Base name of a payload's functions
+    fn payload_fn(&self, direction: Direction, name: &Name) -> String {
+        format!("{}_{}_{}", self.p, Self::dir(direction), name.snake())
+    }
+
+    fn transaction_fn(&self, transaction: &Transaction) -> String {
+        match transaction.name.snake().as_str() {
+            name @ ("init" | "flush" | "status_str") => format!("{}_{name}_transaction", self.p),
+            name => format!("{}_{name}", self.p),
+        }
+    }
+
+    /// Member declarations for a field. Variable length collections get a `_len` or `_count` member
+    fn declare(&self, w: &mut CodeWriter, name: &str, ty: &Type, fields: &[Field]) {
+        let cap = |len: &Length| self.layout.cap(len, fields).max(1);
+        match ty {
+            Type::Int { bits, signing, .. } => w.line(format!("{} {name};", int_type(*bits, *signing))),
+            Type::Float { bits: 32, .. } => w.line(format!("float {name};")),
+            Type::Float { .. } => w.line(format!("double {name};")),
+            Type::Bytes(Length::Fixed(n)) => w.line(format!("uint8_t {name}[{}];", n.max(&1))),
+            Type::Bytes(len) => w.line(format!("uint8_t {name}[{}];", cap(len))).line(format!("size_t {name}_len;")),
+            Type::String(len) => w.line(format!("char {name}[{}]; /* NUL terminated */", self.layout.cap(len, fields) + 1)),
+            Type::Const(data) => w.line(format!("uint8_t {name}[{}];", data.len().max(1))),
+            Type::Struct(s) => w.line(format!("{} {name};", self.struct_type(s))),
+            Type::Array { item, len: Length::Fixed(n) } => w.line(format!("{} {name}[{}];", self.struct_type(item), n.max(&1))),
+            Type::Array { item, len } => w.line(format!("{} {name}[{}];", self.struct_type(item), cap(len))).line(format!("size_t {name}_count;")),
+        };
+    }
+
+    /// The companion member holding a field's length, if it has one
+    fn companion(ty: &Type) -> Option<&'static str> {
+        match ty {
+            Type::Bytes(Length::Fixed(_)) | Type::Array { len: Length::Fixed(_), .. } => None,
+            Type::Bytes(_) => Some("_len"),
+            Type::Array { .. } => Some("_count"),
+            _ => None,
+        }
+    }
+
+    fn typedef(&self, w: &mut CodeWriter, ty_name: &str, description: Option<&str>, fields: &[Field]) {
+        if let Some(description) = description {
+            comment(w, description);
+        }
+        w.block("typedef struct {", format!("}} {ty_name};"), |w| {
+            let mut any = false;
+            for f in fields.iter().filter(|f| f.is_user_facing()) {
+                if let Some(description) = &f.description {
+                    comment(w, description);
+                }
+                self.declare(w, &ident(&f.name), &f.ty, fields);
+                any = true;
+            }
+            if !any {
+                w.line("char reserved_; /* C structs can't be empty */");
+            }
+        });
+    }
+
+    fn header(&self) -> String {
+        let (p, pu, ir) = (&self.p, &self.pu, self.ir);
+        let mut w = CodeWriter::new("    ");
+        comment(
+            &mut w,
+            &format!(
+                "Driver for the {}: {}\n\nGenerated from an OpenPID document by `openpid gen c`. Don't edit by hand",
+                ir.device.name, ir.device.description
+            ),
+        );
+        w.line(format!("#ifndef {pu}_H"));
+        w.line(format!("#define {pu}_H"));
+        w.blank();
+        w.line("#include <stddef.h>");
+        w.line("#include <stdint.h>");
+        w.blank();
+        w.line("#ifdef __cplusplus");
+        w.line("extern \"C\" {");
+        w.line("#endif");
+        w.blank();
+        w.line("/* Largest encoded payload, in bytes */");
+        w.line(format!("#define {pu}_MAX_PAYLOAD_LEN {}", self.layout.max_payload_len()));
+        w.line("/* Largest frame in each direction, in bytes */");
+        w.line(format!("#define {pu}_TX_FRAME_LEN {}", self.layout.max_frame_len(Direction::Tx)));
+        w.line(format!("#define {pu}_RX_FRAME_LEN {}", self.layout.max_frame_len(Direction::Rx)));
+        w.blank();
+        w.line(STATUS_DECL.trim().replace("PFX", pu).replace("pfx", p));
+        for direction in [Direction::Tx, Direction::Rx] {
+            let Some(framing) = &ir.framing else { break };
+            for (key, _) in framing.metadata(direction) {
+                let values = ir
+                    .payloads(direction)
+                    .iter()
+                    .filter_map(|payload| Some((payload, payload.metadata(key.raw())?.packed()?.first().copied()?)))
+                    .collect::<Vec<_>>();
+                w.blank();
+                comment(&mut w, &format!("Value of the \"{key}\" metadata each {direction} payload is sent with"));
+                let constant = |payload: &Payload| format!("{pu}_{}_{}_{}", Self::dir(direction).to_uppercase(), key.screaming(), payload.name.screaming());
+                if values.iter().all(|(_, v)| *v <= i32::MAX as u64) && !values.is_empty() {
+                    w.block("typedef enum {", format!("}} {p}_{}_{}_t;", Self::dir(direction), key.snake()), |w| {
+                        for (i, (payload, value)) in values.iter().enumerate() {
+                            let comma = if i + 1 < values.len() { "," } else { "" };
+                            w.line(format!("{} = 0x{value:x}{comma}", constant(payload)));
+                        }
+                    });
+                } else {
+                    for (payload, value) in &values {
+                        w.line(format!("#define {} 0x{value:x}ull", constant(payload)));
+                    }
+                }
+            }
+        }
+        for s in &ir.structs {
+            w.blank();
+            self.typedef(&mut w, &self.struct_type(&s.name), s.description.as_deref(), &s.fields);
+        }
+        for payload in ir.all_payloads() {
+            let ty = self.payload_type(payload.direction, &payload.name);
+            let f = self.payload_fn(payload.direction, &payload.name);
+            w.blank();
+            self.typedef(&mut w, &ty, Some(&payload.description), &payload.fields);
+            w.blank();
+            w.line("/* Encodes the payload alone into out, setting *len to the bytes written */");
+            w.line(format!("{p}_status_t {f}_encode(const {ty} *v, uint8_t *out, size_t cap, size_t *len);"));
+            w.line(format!("{p}_status_t {f}_decode({ty} *v, const uint8_t *data, size_t len);"));
+            if ir.framing.is_some() {
+                w.line("/* Encodes the payload wrapped in its frame, and back */");
+                w.line(format!("{p}_status_t {f}_pack(const {ty} *v, uint8_t *out, size_t cap, size_t *len);"));
+                w.line(format!("{p}_status_t {f}_unpack({ty} *v, const uint8_t *data, size_t len);"));
+            }
+        }
+        if ir.framing.is_some() {
+            let max_metadata = [Direction::Tx, Direction::Rx]
+                .iter()
+                .filter_map(|d| self.format(*d))
+                .map(|f| f.elements.iter().filter(|e| matches!(e, FlatElement::Metadata { .. })).count())
+                .max()
+                .unwrap_or(0);
+            w.blank();
+            w.line(format!("#define {pu}_MAX_METADATA {}", max_metadata.max(1)));
+            w.blank();
+            w.line("/* A frame found in a buffer. The payload points into that buffer */");
+            w.block("typedef struct {", format!("}} {p}_frame_t;"), |w| {
+                w.line(format!("uint64_t metadata[{pu}_MAX_METADATA];"));
+                w.line("const uint8_t *payload;");
+                w.line("size_t payload_len;");
+                w.line("/* Length of the whole frame, in bytes */");
+                w.line("size_t len;");
+            });
+            for direction in [Direction::Tx, Direction::Rx] {
+                let d = Self::dir(direction);
+                w.blank();
+                w.line(format!("{p}_status_t {p}_{d}_frame_encode(const uint64_t *metadata, const uint8_t *payload, size_t payload_len, uint8_t *out, size_t cap, size_t *len);"));
+                w.line("/* Decodes a buffer holding exactly one frame */");
+                w.line(format!("{p}_status_t {p}_{d}_frame_decode(const uint8_t *data, size_t len, {p}_frame_t *frame);"));
+            }
+        }
+        w.blank();
+        w.line(HAL_DECL.trim().replace("PFX", pu).replace("pfx", p));
+        for transaction in &ir.transactions {
+            w.blank();
+            if let Some(decl) = self.result_decl(transaction) {
+                w.line(decl.trim_end());
+                w.blank();
+            }
+            comment(&mut w, &transaction.description);
+            w.line(format!("{};", self.transaction_signature(transaction)));
+        }
+        w.blank();
+        w.line("#ifdef __cplusplus");
+        w.line("}");
+        w.line("#endif");
+        w.blank();
+        w.line(format!("#endif /* {pu}_H */"));
+        w.finish()
+    }
+
+    fn result_type(&self, transaction: &Transaction) -> String {
+        format!("{}_{}_result_t", self.p, transaction.name.snake())
+    }
+
+    fn result_decl(&self, transaction: &Transaction) -> Option<String> {
+        if transaction.returns.is_empty() {
+            return None;
+        }
+        let mut w = CodeWriter::new("    ");
+        w.line(format!("/* What {} returns */", self.transaction_fn(transaction)));
+        w.block("typedef struct {", format!("}} {};", self.result_type(transaction)), |w| {
+            for ret in &transaction.returns {
+                self.declare(w, &ident(&ret.name), &ret.ty, self.layout.return_siblings(ret));
+            }
+        });
+        Some(w.finish())
+    }
+
+    fn transaction_signature(&self, transaction: &Transaction) -> String {
+        let mut params = vec![format!("{}_device_t *dev", self.p)];
+        for payload in self.ir.transaction_params(transaction) {
+            params.push(format!("const {} *{}", self.payload_type(Direction::Tx, payload), ident(payload)));
+        }
+        if !transaction.returns.is_empty() {
+            params.push(format!("{} *result", self.result_type(transaction)));
+        }
+        format!("{}_status_t {}({})", self.p, self.transaction_fn(transaction), params.join(", "))
+    }
+
+    fn source(&self) -> String {
+        let (p, pu, ir) = (&self.p, &self.pu, self.ir);
+        let mut w = CodeWriter::new("    ");
+        comment(&mut w, "Generated from an OpenPID document by `openpid gen c`. Don't edit by hand");
+        w.blank();
+        w.line("#include <string.h>");
+        w.blank();
+        w.line(format!("#include \"{p}.h\""));
+        w.blank();
+        w.line(HELPERS.trim().replace("PFX", pu).replace("pfx", p));
+
+        // encoders for structs, then payloads, so each only calls ones defined above it
+        for s in &ir.structs {
+            let ty = self.struct_type(&s.name);
+            w.blank();
+            self.codec_fns(&mut w, &format!("s_{}", s.name.snake()), &ty, &s.fields);
+        }
+        for payload in ir.all_payloads() {
+            let ty = self.payload_type(payload.direction, &payload.name);
+            let inner = format!("{}_{}", Self::dir(payload.direction), payload.name.snake());
+            let f = self.payload_fn(payload.direction, &payload.name);
+            w.blank();
+            self.codec_fns(&mut w, &inner, &ty, &payload.fields);
+            w.blank();
+            w.block(format!("{p}_status_t {f}_encode(const {ty} *v, uint8_t *out, size_t cap, size_t *len)\n{{"), "}", |w| {
+                w.line("writer_t w;");
+                w.line("writer_init(&w, out, cap);");
+                w.line(format!("TRY(enc_{inner}(&w, v));"));
+                w.line("*len = (w.pos + 7) / 8;");
+                w.line(format!("return {pu}_OK;"));
+            });
+            w.blank();
+            w.block(format!("{p}_status_t {f}_decode({ty} *v, const uint8_t *data, size_t len)\n{{"), "}", |w| {
+                w.line("reader_t r;");
+                w.line("reader_init(&r, data, len);");
+                w.line("memset(v, 0, sizeof *v);");
+                w.line(format!("TRY(dec_{inner}(&r, v));"));
+                w.line(format!("return REMAINING(&r) >= 8 ? {pu}_ERR_TRAILING : {pu}_OK;"));
+            });
+        }
+        if ir.framing.is_some() {
+            for direction in [Direction::Tx, Direction::Rx] {
+                if let Some(format) = self.format(direction) {
+                    w.blank();
+                    self.frame_encoder(&mut w, direction, format);
+                    w.blank();
+                    self.frame_parser(&mut w, direction, format);
+                }
+            }
+            for payload in ir.all_payloads() {
+                w.blank();
+                self.pack_fns(&mut w, payload);
+            }
+        }
+        w.blank();
+        w.line(DRIVER_FNS.trim().replace("PFX", pu).replace("pfx", p));
+        if ir.framing.is_some() {
+            w.blank();
+            w.line(RECEIVE_FRAMED.trim().replace("PFX", pu).replace("pfx", p));
+        }
+        let received = |payload: &&Payload| ir.transactions.iter().flat_map(|t| &t.actions).any(|a| *a == Action::Rx(payload.name.clone()));
+        for payload in ir.rx.iter().filter(received) {
+            w.blank();
+            self.receive_fn(&mut w, payload);
+        }
+        for transaction in &ir.transactions {
+            w.blank();
+            self.transaction_fn_body(&mut w, transaction);
+        }
+        w.finish()
+    }
+
+    /// Static `enc_<name>` and `dec_<name>` functions
+    fn codec_fns(&self, w: &mut CodeWriter, name: &str, ty: &str, fields: &[Field]) {
+        let pu = &self.pu;
+        w.block(format!("static {}_status_t enc_{name}(writer_t *w, const {ty} *v)\n{{", self.p), "}", |w| {
+            if fields.is_empty() {
+                w.line("(void)w;");
+                w.line("(void)v;");
+            }
+            for f in fields {
+                self.encode_field(w, f, fields);
+            }
+            w.line(format!("return {pu}_OK;"));
+        });
+        w.blank();
+        w.block(format!("static {}_status_t dec_{name}(reader_t *r, {ty} *v)\n{{", self.p), "}", |w| {
+            if fields.is_empty() {
+                w.line("(void)r;");
+                w.line("(void)v;");
+            }
+            for f in fields.iter().filter(|f| f.count_of.is_some()) {
+                w.line(format!("uint64_t n_{} = 0;", f.name.snake()));
+            }
+            for f in fields {
+                self.decode_field(w, f, fields);
+            }
+            w.line(format!("return {pu}_OK;"));
+        });
+    }
+
+    fn encode_field(&self, w: &mut CodeWriter, f: &Field, fields: &[Field]) {
+        let pu = &self.pu;
+        let v = format!("v->{}", ident(&f.name));
+        let cap = |len: &Length| self.layout.cap(len, fields);
+        match &f.ty {
+            Type::Int { bits, signing, endianness } => {
+                let value = match &f.count_of {
+                    Some(counted) => {
+                        let counted_field = fields.iter().find(|c| c.name == *counted);
+                        let counted_ident = ident(counted);
+                        match counted_field.map(|c| &c.ty) {
+                            Some(Type::String(len)) => {
+                                format!("str_len(v->{counted_ident}, {})", cap(len))
+                            }
+                            Some(ty) => format!("v->{counted_ident}{}", Self::companion(ty).unwrap_or("_len")),
+                            None => "0".to_owned(),
+                        }
+                    }
+                    None => v,
+                };
+                match signing {
+                    Signing::Unsigned => w.line(format!("TRY(put_uint(w, {bits}, {}, (uint64_t){value}));", little(*endianness))),
+                    _ => {
+                        w.line(format!("TRY(put_int(w, {bits}, {}, {}, (int64_t){value}));", little(*endianness), (*signing == Signing::OnesComplement) as u8))
+                    }
+                };
+            }
+            Type::Float { bits, endianness } => {
+                w.line(format!("TRY(put_f{bits}(w, {}, {v}));", little(*endianness)));
+            }
+            Type::Const(data) => {
+                w.line(format!("TRY(put_bytes(w, {}, {}));", bytes_literal(data), data.len()));
+            }
+            Type::Struct(s) => {
+                w.line(format!("TRY(enc_s_{}(w, &{v}));", s.snake()));
+            }
+            Type::Bytes(Length::Fixed(n)) => {
+                w.line(format!("TRY(put_bytes(w, {v}, {n}));"));
+            }
+            Type::Bytes(len) => {
+                w.line(format!("if ({v}_len > {}) return {pu}_ERR_CAPACITY;", cap(len)));
+                w.line(format!("TRY(put_bytes(w, {v}, {v}_len));"));
+                if let Length::Sequence(sequence) = len {
+                    w.line(format!("TRY(put_bytes(w, {}, {}));", bytes_literal(sequence), sequence.len()));
+                }
+            }
+            Type::String(len @ (Length::Fixed(n) | Length::Capacity(n))) => {
+                w.line(format!("TRY(put_padded(w, (const uint8_t *){v}, str_len({v}, {}), {n}));", cap(len)));
+            }
+            Type::String(len) => {
+                w.line(format!("TRY(put_bytes(w, (const uint8_t *){v}, str_len({v}, {})));", cap(len)));
+                if let Length::Sequence(sequence) = len {
+                    w.line(format!("TRY(put_bytes(w, {}, {}));", bytes_literal(sequence), sequence.len()));
+                }
+            }
+            Type::Array { item, len } => {
+                let count = match len {
+                    Length::Fixed(n) => n.to_string(),
+                    _ => {
+                        w.line(format!("if ({v}_count > {}) return {pu}_ERR_CAPACITY;", cap(len)));
+                        format!("{v}_count")
+                    }
+                };
+                w.block(format!("for (size_t i = 0; i < {count}; i++) {{"), "}", |w| {
+                    w.line(format!("TRY(enc_s_{}(w, &{v}[i]));", item.snake()));
+                });
+                if let Length::Sequence(sequence) = len {
+                    w.line(format!("TRY(put_bytes(w, {}, {}));", bytes_literal(sequence), sequence.len()));
+                }
+            }
+        }
+    }
+
+    fn decode_field(&self, w: &mut CodeWriter, f: &Field, fields: &[Field]) {
+        let pu = &self.pu;
+        let v = format!("v->{}", ident(&f.name));
+        let cap = |len: &Length| self.layout.cap(len, fields);
+        // how many elements a count field or the remaining data says there are, checked against capacity
+        let count = |w: &mut CodeWriter, len: &Length, target: &str, unit: &str| match len {
+            Length::CountField(c) => {
+                w.line(format!("if (n_{} > {}) return {pu}_ERR_CAPACITY;", c.snake(), cap(len)));
+                w.line(format!("{target} = (size_t)n_{};", c.snake()));
+            }
+            _ => {
+                w.line(format!("if (REMAINING(r) / {unit} > {}) return {pu}_ERR_CAPACITY;", cap(len)));
+                w.line(format!("{target} = REMAINING(r) / {unit};"));
+            }
+        };
+        match &f.ty {
+            Type::Int { bits, signing, endianness } => {
+                let le = little(*endianness);
+                let ones = (*signing == Signing::OnesComplement) as u8;
+                let target = if f.count_of.is_some() { format!("n_{}", f.name.snake()) } else { v.clone() };
+                let cast = int_type(*bits, *signing);
+                match (signing, f.count_of.is_some()) {
+                    (Signing::Unsigned, true) => w.line(format!("TRY(get_uint(r, {bits}, {le}, &{target}));")),
+                    (Signing::Unsigned, false) => w.block("{", "}", |w| {
+                        w.line("uint64_t raw;");
+                        w.line(format!("TRY(get_uint(r, {bits}, {le}, &raw));"));
+                        w.line(format!("{target} = ({cast})raw;"));
+                    }),
+                    (_, true) => w.block("{", "}", |w| {
+                        w.line("int64_t raw;");
+                        w.line(format!("TRY(get_int(r, {bits}, {le}, {ones}, &raw));"));
+                        w.line(format!("if (raw < 0) return {pu}_ERR_RANGE;"));
+                        w.line(format!("{target} = (uint64_t)raw;"));
+                    }),
+                    (_, false) => w.block("{", "}", |w| {
+                        w.line("int64_t raw;");
+                        w.line(format!("TRY(get_int(r, {bits}, {le}, {ones}, &raw));"));
+                        w.line(format!("{target} = ({cast})raw;"));
+                    }),
+                };
+            }
+            Type::Float { bits, endianness } => {
+                w.line(format!("TRY(get_f{bits}(r, {}, &{v}));", little(*endianness)));
+            }
+            Type::Const(data) => {
+                w.line(format!("TRY(expect_bytes(r, {}, {}));", bytes_literal(data), data.len()));
+            }
+            Type::Struct(s) => {
+                w.line(format!("TRY(dec_s_{}(r, &{v}));", s.snake()));
+            }
+            Type::Bytes(Length::Fixed(n)) => {
+                w.line(format!("TRY(get_bytes(r, {v}, {n}));"));
+            }
+            Type::Bytes(len @ Length::Sequence(sequence)) => {
+                w.line(format!("TRY(get_until(r, {v}, {}, &{v}_len, {}, {}));", cap(len), bytes_literal(sequence), sequence.len()));
+            }
+            Type::Bytes(len) => {
+                count(w, len, &format!("{v}_len"), "8");
+                w.line(format!("TRY(get_bytes(r, {v}, {v}_len));"));
+            }
+            Type::String(Length::Fixed(n) | Length::Capacity(n)) => {
+                w.line(format!("TRY(get_bytes(r, (uint8_t *){v}, {n}));"));
+                w.line(format!("{v}[{n}] = '\\0';"));
+            }
+            Type::String(len) => {
+                w.block("{", "}", |w| {
+                    w.line("size_t len;");
+                    match len {
+                        Length::Sequence(sequence) => {
+                            w.line(format!("TRY(get_until(r, (uint8_t *){v}, {}, &len, {}, {}));", cap(len), bytes_literal(sequence), sequence.len()));
+                        }
+                        _ => {
+                            count(w, len, "len", "8");
+                            w.line(format!("TRY(get_bytes(r, (uint8_t *){v}, len));"));
+                        }
+                    }
+                    w.line(format!("{v}[len] = '\\0';"));
+                });
+            }
+            Type::Array { item, len } => {
+                let dec = format!("dec_s_{}", item.snake());
+                match len {
+                    Length::Fixed(n) => w.block(format!("for (size_t i = 0; i < {n}; i++) {{"), "}", |w| {
+                        w.line(format!("TRY({dec}(r, &{v}[i]));"));
+                    }),
+                    Length::CountField(_) => {
+                        count(w, len, &format!("{v}_count"), "8");
+                        w.block(format!("for (size_t i = 0; i < {v}_count; i++) {{"), "}", |w| {
+                            w.line(format!("TRY({dec}(r, &{v}[i]));"));
+                        })
+                    }
+                    Length::Sequence(sequence) => {
+                        w.line(format!("{v}_count = 0;"));
+                        w.block(format!("while (!take_seq(r, {}, {})) {{", bytes_literal(sequence), sequence.len()), "}", |w| {
+                            w.line(format!("if ({v}_count == {}) return {pu}_ERR_CAPACITY;", cap(len)));
+                            w.line(format!("TRY({dec}(r, &{v}[{v}_count++]));"));
+                        })
+                    }
+                    _ => {
+                        w.line(format!("{v}_count = 0;"));
+                        w.block("while (REMAINING(r) >= 8) {", "}", |w| {
+                            w.line(format!("if ({v}_count == {}) return {pu}_ERR_CAPACITY;", cap(len)));
+                            w.line(format!("TRY({dec}(r, &{v}[{v}_count++]));"));
+                        })
+                    }
+                };
+            }
+        }
+    }
+
+    /// The value of a size element, given `total` and `payload_bits` in bits
+    fn size_expr(bits_expr: &str, unit: BitsOrBytes) -> String {
+        match unit {
+            BitsOrBytes::Bits => format!("(uint64_t)({bits_expr})"),
+            BitsOrBytes::Bytes => format!("(uint64_t)(({bits_expr} + 7) / 8)"),
+        }
+    }
+
+    fn frame_encoder(&self, w: &mut CodeWriter, direction: Direction, format: &Format) {
+        let (p, pu) = (&self.p, &self.pu);
+        let d = Self::dir(direction);
+        let envelope = format.envelope_bits;
+        w.block(
+            format!("{p}_status_t {p}_{d}_frame_encode(const uint64_t *metadata, const uint8_t *payload, size_t payload_len, uint8_t *out, size_t cap, size_t *len)\n{{"),
+            "}",
+            |w| {
+                w.line("writer_t w;");
+                w.line("const size_t payload_bits = payload_len * 8;");
+                w.line(format!("const size_t total = {envelope} + payload_bits;"));
+                w.line("(void)metadata;");
+                w.line("(void)total;");
+                w.line(format!("if ((total + 7) / 8 > cap) return {pu}_ERR_BUFFER;"));
+                w.line("memset(out, 0, (total + 7) / 8);");
+                w.line("writer_init(&w, out, cap);");
+                let mut metadata = 0;
+                for element in &format.elements {
+                    match element {
+                        FlatElement::SizeTotal { bits, unit } => {
+                            w.line(format!("TRY(put_uint(&w, {bits}, 0, {}));", Self::size_expr("total", *unit)));
+                        }
+                        FlatElement::SizeOfPayload { bits, unit } => {
+                            w.line(format!("TRY(put_uint(&w, {bits}, 0, {}));", Self::size_expr("payload_bits", *unit)));
+                        }
+                        FlatElement::SizeOfElements { bits, unit, static_bits, has_payload, .. } => {
+                            let covered = if *has_payload { format!("{static_bits} + payload_bits") } else { static_bits.to_string() };
+                            w.line(format!("TRY(put_uint(&w, {bits}, 0, {}));", Self::size_expr(&covered, *unit)));
+                        }
+                        FlatElement::Payload => {
+                            w.line("TRY(put_bytes(&w, payload, payload_len));");
+                        }
+                        FlatElement::Metadata { bits, endianness, .. } => {
+                            w.line(format!("TRY(put_uint(&w, {bits}, {}, metadata[{metadata}]));", little(*endianness)));
+                            metadata += 1;
+                        }
+                        FlatElement::Crc(crc) => {
+                            let (f, bits) = crc_fn(*crc);
+                            w.line(format!("TRY(put_uint(&w, {bits}, 0, {f}(out, w.pos / 8)));"));
+                        }
+                        FlatElement::Const { data, bits } => {
+                            w.line(format!("TRY(put_const(&w, {}, {}, {bits}));", bytes_literal(data), data.len()));
+                        }
+                    }
+                }
+                w.line("*len = (w.pos + 7) / 8;");
+                w.line(format!("return {pu}_OK;"));
+            },
+        );
+    }
+
+    /// `parse_<dir>_frame`, which reports running out of data as `ERR_TRUNCATED` so a caller can
+    /// retry with more, and the public `_frame_decode` on top of it
+    fn frame_parser(&self, w: &mut CodeWriter, direction: Direction, format: &Format) {
+        let (p, pu) = (&self.p, &self.pu);
+        let d = Self::dir(direction);
+        let envelope = format.envelope_bits;
+        let has_total = format.elements.iter().any(|e| matches!(e, FlatElement::SizeTotal { .. }));
+        w.block(
+            format!("static {p}_status_t parse_{d}_frame(const uint8_t *data, size_t len, long payload_len, int complete, {p}_frame_t *frame)\n{{"),
+            "}",
+            |w| {
+                w.line("reader_t r;");
+                w.line("uint64_t value;");
+                w.line("size_t payload_bits = 0;");
+                if has_total {
+                    w.line("size_t total = 0;");
+                }
+                w.line("(void)value;");
+                w.line("(void)payload_len;");
+                w.line("(void)complete;");
+                w.line("reader_init(&r, data, len);");
+                w.line("memset(frame, 0, sizeof *frame);");
+                // whether payload_bits is known by the time the payload comes
+                let mut payload_known = false;
+                let mut total_known = false;
+                let mut metadata = 0;
+                for (i, element) in format.elements.iter().enumerate() {
+                    match element {
+                        FlatElement::SizeTotal { bits, unit } => {
+                            w.line(format!("TRY(get_uint(&r, {bits}, 0, &value));"));
+                            w.line(format!("total = (size_t)value{};", if *unit == BitsOrBytes::Bytes { " * 8" } else { "" }));
+                            w.line(format!("if (total < {envelope}) return {pu}_ERR_SIZE;"));
+                            w.line(format!("if (total > len * 8) return {pu}_ERR_TRUNCATED;"));
+                            total_known = true;
+                        }
+                        FlatElement::SizeOfPayload { bits, unit } => {
+                            w.line(format!("TRY(get_uint(&r, {bits}, 0, &value));"));
+                            w.line(format!("payload_bits = (size_t)value{};", if *unit == BitsOrBytes::Bytes { " * 8" } else { "" }));
+                            payload_known = true;
+                        }
+                        FlatElement::SizeOfElements { bits, unit, static_bits, has_payload, .. } => {
+                            w.line(format!("TRY(get_uint(&r, {bits}, 0, &value));"));
+                            if *has_payload {
+                                w.line(format!("value{};", if *unit == BitsOrBytes::Bytes { " *= 8" } else { " += 0" }));
+                                w.line(format!("if (value < {static_bits}) return {pu}_ERR_SIZE;"));
+                                w.line(format!("payload_bits = (size_t)value - {static_bits};"));
+                                payload_known = true;
+                            }
+                        }
+                        FlatElement::Payload => {
+                            if !payload_known {
+                                if total_known {
+                                    w.line(format!("payload_bits = total - {envelope};"));
+                                } else {
+                                    let after: u64 = format.elements[i + 1..].iter().map(FlatElement::bits).sum();
+                                    w.block("if (payload_len >= 0) {", "", |w| {
+                                        w.line("payload_bits = (size_t)payload_len * 8;");
+                                    });
+                                    w.block("} else if (complete) {", "", |w| {
+                                        w.line(format!("payload_bits = REMAINING(&r) > {after} ? REMAINING(&r) - {after} : 0;"));
+                                    });
+                                    w.block("} else {", "}", |w| {
+                                        w.line(format!("return {pu}_ERR_LENGTH;"));
+                                    });
+                                }
+                            }
+                            w.line(format!("if (payload_bits % 8 != 0) return {pu}_ERR_SIZE;"));
+                            w.line(format!("if (REMAINING(&r) < payload_bits) return {pu}_ERR_TRUNCATED;"));
+                            w.line("frame->payload = data + r.pos / 8;");
+                            w.line("frame->payload_len = payload_bits / 8;");
+                            w.line("r.pos += payload_bits;");
+                        }
+                        FlatElement::Metadata { bits, endianness, .. } => {
+                            w.line(format!("TRY(get_uint(&r, {bits}, {}, &frame->metadata[{metadata}]));", little(*endianness)));
+                            metadata += 1;
+                        }
+                        FlatElement::Crc(crc) => {
+                            let (f, bits) = crc_fn(*crc);
+                            w.block("{", "}", |w| {
+                                w.line(format!("uint64_t expected = {f}(data, r.pos / 8);"));
+                                w.line(format!("TRY(get_uint(&r, {bits}, 0, &value));"));
+                                w.line(format!("if (value != expected) return {pu}_ERR_CRC;"));
+                            });
+                        }
+                        FlatElement::Const { data, bits } => {
+                            w.line(format!("TRY(expect_const(&r, {}, {}, {bits}));", bytes_literal(data), data.len()));
+                        }
+                    }
+                }
+                if has_total {
+                    w.line(format!("if ((total + 7) / 8 != (r.pos + 7) / 8) return {pu}_ERR_SIZE;"));
+                }
+                w.line(format!("if (complete && REMAINING(&r) >= 8) return {pu}_ERR_TRAILING;"));
+                w.line("frame->len = (r.pos + 7) / 8;");
+                w.line(format!("return {pu}_OK;"));
+            },
+        );
+        w.blank();
+        w.block(format!("{p}_status_t {p}_{d}_frame_decode(const uint8_t *data, size_t len, {p}_frame_t *frame)\n{{"), "}", |w| {
+            w.line(format!("{p}_status_t status = parse_{d}_frame(data, len, -1, 1, frame);"));
+            w.line(format!("return status == {pu}_ERR_LENGTH ? {pu}_ERR_TRUNCATED : status;"));
+        });
+    }
+
+    /// `match_<payload>`, `_pack` and `_unpack` for a payload
+    fn pack_fns(&self, w: &mut CodeWriter, payload: &Payload) {
+        let (p, pu) = (&self.p, &self.pu);
+        let d = Self::dir(payload.direction);
+        let ty = self.payload_type(payload.direction, &payload.name);
+        let f = self.payload_fn(payload.direction, &payload.name);
+        let inner = format!("{d}_{}", payload.name.snake());
+        let metadata = payload.metadata.iter().filter_map(|m| m.packed()).collect::<Vec<_>>();
+        w.line("/* Whether a frame's metadata is one this payload is sent with */");
+        w.block(format!("static int match_{inner}(const uint64_t *metadata)\n{{"), "}", |w| {
+            if metadata.is_empty() {
+                w.line("(void)metadata;");
+                w.line("return 1;");
+                return;
+            }
+            let checks = metadata
+                .iter()
+                .enumerate()
+                .map(|(i, values)| format!("({})", values.iter().map(|v| format!("metadata[{i}] == 0x{v:x}ull")).collect::<Vec<_>>().join(" || ")))
+                .collect::<Vec<_>>();
+            w.line(format!("return {};", checks.join(" && ")));
+        });
+        w.blank();
+        w.block(format!("{p}_status_t {f}_pack(const {ty} *v, uint8_t *out, size_t cap, size_t *len)\n{{"), "}", |w| {
+            w.line(format!("uint8_t body[{pu}_MAX_PAYLOAD_LEN + 1] = {{0}};"));
+            w.line(format!(
+                "const uint64_t metadata[{pu}_MAX_METADATA] = {{{}}};",
+                metadata.iter().map(|v| format!("0x{:x}ull", v[0])).chain(metadata.is_empty().then(|| "0".to_owned())).collect::<Vec<_>>().join(", ")
+            ));
+            w.line("size_t body_len;");
+            w.line(format!("TRY({f}_encode(v, body, sizeof body, &body_len));"));
+            w.line(format!("return {p}_{d}_frame_encode(metadata, body, body_len, out, cap, len);"));
+        });
+        w.blank();
+        w.block(format!("{p}_status_t {f}_unpack({ty} *v, const uint8_t *data, size_t len)\n{{"), "}", |w| {
+            w.line(format!("{p}_frame_t frame;"));
+            w.line(format!("TRY({p}_{d}_frame_decode(data, len, &frame));"));
+            w.line(format!("if (!match_{inner}(frame.metadata)) return {pu}_ERR_UNEXPECTED;"));
+            w.line(format!("return {f}_decode(v, frame.payload, frame.payload_len);"));
+        });
+    }
+
+    /// `receive_<payload>`, reading one RX payload from the device
+    fn receive_fn(&self, w: &mut CodeWriter, payload: &Payload) {
+        let (p, pu) = (&self.p, &self.pu);
+        let ty = self.payload_type(Direction::Rx, &payload.name);
+        let f = self.payload_fn(Direction::Rx, &payload.name);
+        let inner = format!("rx_{}", payload.name.snake());
+        let fixed = payload.size.fixed_bits().map(|bits| bits.div_ceil(8));
+        w.block(format!("static {p}_status_t receive_{inner}({p}_device_t *dev, {ty} *v)\n{{"), "}", |w| {
+            if self.ir.framing.is_some() {
+                w.line(format!("{p}_frame_t frame;"));
+                w.line(format!("TRY(receive_frame(dev, {}, &frame));", fixed.map_or("-1".to_owned(), |n| n.to_string())));
+                w.line(format!("if (!match_{inner}(frame.metadata)) return {pu}_ERR_UNEXPECTED;"));
+                w.line(format!("return {f}_decode(v, frame.payload, frame.payload_len);"));
+            } else {
+                let n = fixed.unwrap_or(0);
+                w.line(format!("TRY(read_exact(dev, dev->buf, {n}));"));
+                w.line(format!("return {f}_decode(v, dev->buf, {n});"));
+            }
+        });
+    }
+
+    fn transaction_fn_body(&self, w: &mut CodeWriter, transaction: &Transaction) {
+        let (p, pu) = (&self.p, &self.pu);
+        let params = self.ir.transaction_params(transaction);
+        w.block(format!("{}\n{{", self.transaction_signature(transaction)), "}", |w| {
+            let mut locals: Vec<(Direction, &Name)> = Vec::new();
+            for action in &transaction.actions {
+                let (direction, payload) = match action {
+                    Action::Tx(payload) if !params.contains(&payload) => (Direction::Tx, payload),
+                    Action::Rx(payload) => (Direction::Rx, payload),
+                    _ => continue,
+                };
+                if !locals.contains(&(direction, payload)) {
+                    locals.push((direction, payload));
+                    w.line(format!("{} {}_{};", self.payload_type(direction, payload), payload.snake(), Self::dir(direction)));
+                }
+            }
+            let sends = transaction.actions.iter().any(|a| matches!(a, Action::Tx(_)));
+            if sends {
+                w.line("size_t len;");
+            }
+            for (direction, payload) in &locals {
+                if *direction == Direction::Tx {
+                    w.line(format!("memset(&{0}_tx, 0, sizeof {0}_tx);", payload.snake()));
+                }
+            }
+            for action in &transaction.actions {
+                match action {
+                    Action::Tx(payload) => {
+                        let arg = if params.contains(&payload) { ident(payload) } else { format!("&{}_tx", payload.snake()) };
+                        let f = self.payload_fn(Direction::Tx, payload);
+                        if self.ir.framing.is_some() {
+                            w.line(format!("TRY({f}_pack({arg}, dev->buf, sizeof dev->buf, &len));"));
+                        } else {
+                            w.line(format!("TRY({f}_encode({arg}, dev->buf, sizeof dev->buf, &len));"));
+                        }
+                        w.line(format!("if (dev->hal->write(dev->hal->ctx, dev->buf, len) != 0) return {pu}_ERR_IO;"));
+                    }
+                    Action::Rx(payload) => {
+                        w.line(format!("TRY(receive_rx_{0}(dev, &{0}_rx));", payload.snake()));
+                    }
+                    Action::Sleep { milliseconds } => {
+                        w.line(format!("dev->hal->delay_ms(dev->hal->ctx, {milliseconds});"));
+                    }
+                    Action::Flush => {
+                        w.line(format!("{p}_flush(dev);"));
+                    }
+                }
+            }
+            for ret in &transaction.returns {
+                let name = ident(&ret.name);
+                let path = ret.path.iter().map(ident).collect::<Vec<_>>().join(".");
+                let source = format!("{}_rx.{path}", ret.payload.snake());
+                w.line(format!("memcpy(&result->{name}, &{source}, sizeof result->{name});"));
+                if let Some(companion) = Self::companion(&ret.ty) {
+                    w.line(format!("result->{name}{companion} = {source}{companion};"));
+                }
+            }
+            w.line(format!("return {pu}_OK;"));
+        });
+    }
+
+    /// Statements setting a zeroed struct's user facing fields to sample values
+    fn fill(&self, w: &mut CodeWriter, target: &str, values: &Fields, fields: &[Field]) {
+        for f in fields.iter().filter(|f| f.is_user_facing()) {
+            if let Some(value) = values.get(f.name.raw()) {
+                self.assign(w, &format!("{target}.{}", ident(&f.name)), value, &f.ty);
+            }
+        }
+    }
+
+    fn assign(&self, w: &mut CodeWriter, target: &str, value: &Value, ty: &Type) {
+        match (value, ty) {
+            (Value::Struct(values), _) => {
+                self.fill(w, target, values, self.ir.fields_of(ty).unwrap_or(&[]));
+            }
+            (Value::Array(items), Type::Array { item, .. }) => {
+                for (i, value) in items.iter().enumerate() {
+                    self.assign(w, &format!("{target}[{i}]"), value, &Type::Struct(item.clone()));
+                }
+                if let Some(companion) = Self::companion(ty) {
+                    w.line(format!("{target}{companion} = {};", items.len()));
+                }
+            }
+            (Value::Bytes(b), _) => {
+                if !b.is_empty() {
+                    w.line(format!("memcpy({target}, {}, {});", bytes_literal(b), b.len()));
+                }
+                if let Some(companion) = Self::companion(ty) {
+                    w.line(format!("{target}{companion} = {};", b.len()));
+                }
+            }
+            (Value::String(text), _) => {
+                w.line(format!("strcpy({target}, {text:?});"));
+            }
+            (Value::UInt(u), _) => {
+                w.line(format!("{target} = {u}u;"));
+            }
+            (Value::Float(f), _) => {
+                w.line(format!("{target} = {f:?};"));
+            }
+            (value, _) => {
+                w.line(format!("{target} = {value};"));
+            }
+        }
+    }
+
+    fn harness(&self) -> String {
+        let (p, pu, ir) = (&self.p, &self.pu, self.ir);
+        let mut w = CodeWriter::new("    ");
+        comment(&mut w, "Checks generated alongside the driver: every payload survives an encode/decode round trip,\nand every transaction runs against a fake HAL. Build and run with `make test`");
+        w.blank();
+        w.line(HARNESS_FAKES.trim().replace("PFX", pu).replace("pfx", p));
+        let mut tests = Vec::new();
+        for payload in ir.all_payloads() {
+            let ty = self.payload_type(payload.direction, &payload.name);
+            let f = self.payload_fn(payload.direction, &payload.name);
+            let name = format!("roundtrip_{}_{}", Self::dir(payload.direction), payload.name.snake());
+            w.blank();
+            w.block(format!("static void {name}(void)\n{{"), "}", |w| {
+                w.line(format!("{ty} in, out;"));
+                w.line(format!("uint8_t buf[{pu}_MAX_PAYLOAD_LEN + 1];"));
+                w.line("size_t len;");
+                w.line("memset(&in, 0, sizeof in);");
+                w.line("memset(&out, 0, sizeof out);");
+                self.fill(w, "in", &self.layout.sample_fields(&payload.fields, 0), &payload.fields);
+                w.line(format!("CHECK({f}_encode(&in, buf, sizeof buf, &len) == {pu}_OK);"));
+                w.line(format!("CHECK({f}_decode(&out, buf, len) == {pu}_OK);"));
+                w.line("CHECK(memcmp(&in, &out, sizeof in) == 0);");
+                if ir.framing.is_some() {
+                    w.line(format!("uint8_t frame[{pu}_{}_FRAME_LEN + 1];", Self::dir(payload.direction).to_uppercase()));
+                    w.line(format!("CHECK({f}_pack(&in, frame, sizeof frame, &len) == {pu}_OK);"));
+                    w.line(format!("CHECK({f}_unpack(&out, frame, len) == {pu}_OK);"));
+                    w.line("CHECK(memcmp(&in, &out, sizeof in) == 0);");
+                }
+            });
+            tests.push(name);
+        }
+        for transaction in &ir.transactions {
+            let name = format!("transaction_{}", transaction.name.snake());
+            let params = self.ir.transaction_params(transaction);
+            let sends = transaction.actions.iter().any(|a| matches!(a, Action::Tx(_)));
+            w.blank();
+            w.block(format!("static void {name}(void)\n{{"), "}", |w| {
+                w.line(format!("{p}_device_t dev;"));
+                w.line(format!("uint8_t frame[{pu}_RX_FRAME_LEN + 1];"));
+                w.line("size_t len;");
+                for payload in &params {
+                    w.line(format!("{} {}_tx;", self.payload_type(Direction::Tx, payload), payload.snake()));
+                }
+                if !transaction.returns.is_empty() {
+                    w.line(format!("{} result, expected;", self.result_type(transaction)));
+                }
+                w.line("(void)frame;");
+                w.line("(void)len;");
+                w.line("fake_reset();");
+                for action in &transaction.actions {
+                    if let Action::Rx(payload) = action {
+                        let ty = self.payload_type(Direction::Rx, payload);
+                        let f = self.payload_fn(Direction::Rx, payload);
+                        let encode = if ir.framing.is_some() { "pack" } else { "encode" };
+                        let fields = ir.get_payload(Direction::Rx, payload).map_or(&[][..], |p| &p.fields);
+                        w.block("{", "}", |w| {
+                            w.line(format!("{ty} response;"));
+                            w.line("memset(&response, 0, sizeof response);");
+                            self.fill(w, "response", &self.layout.sample_fields(fields, 0), fields);
+                            w.line(format!("CHECK({f}_{encode}(&response, frame, sizeof frame, &len) == {pu}_OK);"));
+                            w.line("fake_queue(frame, len);");
+                        });
+                    }
+                }
+                let mut args = vec!["&dev".to_owned()];
+                for payload in &params {
+                    let fields = ir.get_payload(Direction::Tx, payload).map_or(&[][..], |p| &p.fields);
+                    w.line(format!("memset(&{0}_tx, 0, sizeof {0}_tx);", payload.snake()));
+                    self.fill(w, &format!("{}_tx", payload.snake()), &self.layout.sample_fields(fields, 0), fields);
+                    args.push(format!("&{}_tx", payload.snake()));
+                }
+                if !transaction.returns.is_empty() {
+                    // zeroed first, so that padding compares equal
+                    w.line("memset(&result, 0, sizeof result);");
+                    w.line("memset(&expected, 0, sizeof expected);");
+                    for ret in &transaction.returns {
+                        if let Some(value) = self.layout.sample_return(ret) {
+                            self.assign(w, &format!("expected.{}", ident(&ret.name)), &value, &ret.ty);
+                        }
+                    }
+                    args.push("&result".to_owned());
+                }
+                w.line(format!("{p}_init(&dev, &fake_hal, 10);"));
+                w.line(format!("CHECK({}({}) == {pu}_OK);", self.transaction_fn(transaction), args.join(", ")));
+                if !transaction.returns.is_empty() {
+                    w.line("CHECK(memcmp(&result, &expected, sizeof result) == 0);");
+                }
+                w.line("CHECK(fake_rx_pos == fake_rx_len);");
+                w.line(if sends { "CHECK(fake_tx_len > 0);" } else { "CHECK(fake_tx_len == 0);" });
+            });
+            tests.push(name);
+        }
+        w.blank();
+        w.block("int main(void)\n{", "}", |w| {
+            for test in &tests {
+                w.line(format!("{test}();"));
+            }
+            w.block("if (failures) {", "}", |w| {
+                w.line("printf(\"%d check(s) failed\\n\", failures);");
+                w.line("return 1;");
+            });
+            w.line(format!("printf(\"all {} tests passed\\n\");", tests.len()));
+            w.line("return 0;");
+        });
+        w.finish()
+    }
+}
+
+fn crc_fn(crc: Crc) -> (&'static str, u32) {
+    match crc {
+        Crc::Crc32 => ("crc32", 32),
+        Crc::Crc16XModem => ("crc16_xmodem", 16),
+    }
+}
+
+const STATUS_DECL: &str = r#"
+typedef enum {
+    PFX_OK = 0,
+    /* An output buffer is too small */
+    PFX_ERR_BUFFER = -1,
+    /* The data ended early */
+    PFX_ERR_TRUNCATED = -2,
+    /* A value doesn't fit in its field */
+    PFX_ERR_RANGE = -3,
+    /* More elements than a field has room for */
+    PFX_ERR_CAPACITY = -4,
+    /* Constant bytes weren't what the document says */
+    PFX_ERR_CONST = -5,
+    PFX_ERR_CRC = -6,
+    /* A size field is inconsistent with the frame */
+    PFX_ERR_SIZE = -7,
+    /* Data was left over after decoding */
+    PFX_ERR_TRAILING = -8,
+    /* The device answered with a different payload than the transaction expects */
+    PFX_ERR_UNEXPECTED = -9,
+    PFX_ERR_TIMEOUT = -10,
+    /* The HAL reported a failure */
+    PFX_ERR_IO = -11,
+    /* The frame doesn't say how long its payload is */
+    PFX_ERR_LENGTH = -12
+} pfx_status_t;
+
+const char *pfx_status_str(pfx_status_t status);
+"#;
+
+const HAL_DECL: &str = r#"
+/* How the driver reaches the device, supplied by the application */
+typedef struct {
+    /* Passed back to each function */
+    void *ctx;
+    /* Sends all of data. Returns 0, or a negative value on failure */
+    int (*write)(void *ctx, const uint8_t *data, size_t len);
+    /* Reads up to len bytes, waiting at most timeout_ms for the first. Returns the number read, 0
+     * on timeout, or a negative value on failure */
+    int (*read_with_timeout)(void *ctx, uint8_t *data, size_t len, uint32_t timeout_ms);
+    void (*delay_ms)(void *ctx, uint32_t ms);
+} pfx_hal_t;
+
+typedef struct {
+    const pfx_hal_t *hal;
+    /* How long to wait for each byte of a response */
+    uint32_t timeout_ms;
+    uint8_t buf[(PFX_TX_FRAME_LEN > PFX_RX_FRAME_LEN ? PFX_TX_FRAME_LEN : PFX_RX_FRAME_LEN) + 1];
+} pfx_device_t;
+
+void pfx_init(pfx_device_t *dev, const pfx_hal_t *hal, uint32_t timeout_ms);
+
+/* Drops anything received but not yet read */
+void pfx_flush(pfx_device_t *dev);
+"#;
+
+const HELPERS: &str = r#"
+#define TRY(expr) do { pfx_status_t status_ = (expr); if (status_ != PFX_OK) return status_; } while (0)
+#define REMAINING(r) ((r)->len_bits - (r)->pos)
+
+const char *pfx_status_str(pfx_status_t status)
+{
+    switch (status) {
+    case PFX_OK: return "ok";
+    case PFX_ERR_BUFFER: return "buffer too small";
+    case PFX_ERR_TRUNCATED: return "data ended early";
+    case PFX_ERR_RANGE: return "value out of range";
+    case PFX_ERR_CAPACITY: return "too many elements for the field";
+    case PFX_ERR_CONST: return "constant bytes don't match";
+    case PFX_ERR_CRC: return "CRC mismatch";
+    case PFX_ERR_SIZE: return "size field inconsistent with the frame";
+    case PFX_ERR_TRAILING: return "data left over";
+    case PFX_ERR_UNEXPECTED: return "unexpected payload";
+    case PFX_ERR_TIMEOUT: return "timed out";
+    case PFX_ERR_IO: return "I/O error";
+    case PFX_ERR_LENGTH: return "unknown payload length";
+    }
+    return "unknown status";
+}
+
+/* Helpers are inline so the ones a document doesn't need compile away without warnings. Bits are
+ * packed most significant first, as in the rest of OpenPID */
+typedef struct {
+    uint8_t *buf;
+    size_t len_bits;
+    size_t pos;
+} writer_t;
+
+typedef struct {
+    const uint8_t *buf;
+    size_t len_bits;
+    size_t pos;
+} reader_t;
+
+static inline void writer_init(writer_t *w, uint8_t *buf, size_t len)
+{
+    w->buf = buf;
+    w->len_bits = len * 8;
+    w->pos = 0;
+}
+
+static inline void reader_init(reader_t *r, const uint8_t *buf, size_t len)
+{
+    r->buf = buf;
+    r->len_bits = len * 8;
+    r->pos = 0;
+}
+
+static inline uint64_t mask(unsigned bits)
+{
+    return bits >= 64 ? ~(uint64_t)0 : (((uint64_t)1 << bits) - 1);
+}
+
+/* Reverses the byte order of the low bits of value, which are whole bytes */
+static inline uint64_t swap_bytes(uint64_t value, unsigned bits)
+{
+    uint64_t out = 0;
+    unsigned i;
+    for (i = 0; i < bits / 8; i++) {
+        out = (out << 8) | ((value >> (8 * i)) & 0xFF);
+    }
+    return out;
+}
+
+static inline pfx_status_t put_bits(writer_t *w, unsigned bits, uint64_t value)
+{
+    unsigned i;
+    if (REMAINING(w) < bits) return PFX_ERR_BUFFER;
+    for (i = 0; i < bits; i++) {
+        size_t at = w->pos + i;
+        uint8_t bit = (uint8_t)(0x80u >> (at % 8));
+        if ((value >> (bits - 1 - i)) & 1u) {
+            w->buf[at / 8] |= bit;
+        } else {
+            w->buf[at / 8] &= (uint8_t)~bit;
+        }
+    }
+    w->pos += bits;
+    return PFX_OK;
+}
+
+static inline pfx_status_t get_bits(reader_t *r, unsigned bits, uint64_t *value)
+{
+    unsigned i;
+    uint64_t out = 0;
+    if (REMAINING(r) < bits) return PFX_ERR_TRUNCATED;
+    for (i = 0; i < bits; i++) {
+        size_t at = r->pos + i;
+        out = (out << 1) | ((r->buf[at / 8] >> (7 - at % 8)) & 1u);
+    }
+    r->pos += bits;
+    *value = out;
+    return PFX_OK;
+}
+
+static inline pfx_status_t put_uint(writer_t *w, unsigned bits, int little, uint64_t value)
+{
+    if (value & ~mask(bits)) return PFX_ERR_RANGE;
+    return put_bits(w, bits, little ? swap_bytes(value, bits) : value);
+}
+
+static inline pfx_status_t get_uint(reader_t *r, unsigned bits, int little, uint64_t *value)
+{
+    TRY(get_bits(r, bits, value));
+    if (little) *value = swap_bytes(*value, bits);
+    return PFX_OK;
+}
+
+static inline pfx_status_t put_int(writer_t *w, unsigned bits, int little, int ones, int64_t value)
+{
+    int64_t max, min;
+    if (bits == 0) return value == 0 ? PFX_OK : PFX_ERR_RANGE;
+    max = (int64_t)mask(bits - 1);
+    min = ones ? -max : -max - 1;
+    if (value < min || value > max) return PFX_ERR_RANGE;
+    if (value < 0 && ones) return put_uint(w, bits, little, ~(uint64_t)(-value) & mask(bits));
+    return put_uint(w, bits, little, (uint64_t)value & mask(bits));
+}
+
+static inline pfx_status_t get_int(reader_t *r, unsigned bits, int little, int ones, int64_t *value)
+{
+    uint64_t raw;
+    TRY(get_uint(r, bits, little, &raw));
+    if (bits > 0 && (raw >> (bits - 1)) & 1u) {
+        *value = ones ? -(int64_t)(~raw & mask(bits)) : (int64_t)(raw | ~mask(bits));
+    } else {
+        *value = (int64_t)raw;
+    }
+    return PFX_OK;
+}
+
+static inline pfx_status_t put_f32(writer_t *w, int little, float value)
+{
+    uint32_t raw;
+    memcpy(&raw, &value, sizeof raw);
+    return put_uint(w, 32, little, raw);
+}
+
+static inline pfx_status_t put_f64(writer_t *w, int little, double value)
+{
+    uint64_t raw;
+    memcpy(&raw, &value, sizeof raw);
+    return put_uint(w, 64, little, raw);
+}
+
+static inline pfx_status_t get_f32(reader_t *r, int little, float *value)
+{
+    uint64_t raw;
+    uint32_t narrow;
+    TRY(get_uint(r, 32, little, &raw));
+    narrow = (uint32_t)raw;
+    memcpy(value, &narrow, sizeof narrow);
+    return PFX_OK;
+}
+
+static inline pfx_status_t get_f64(reader_t *r, int little, double *value)
+{
+    uint64_t raw;
+    TRY(get_uint(r, 64, little, &raw));
+    memcpy(value, &raw, sizeof raw);
+    return PFX_OK;
+}
+
+static inline pfx_status_t put_bytes(writer_t *w, const uint8_t *data, size_t len)
+{
+    size_t i;
+    if (REMAINING(w) / 8 < len) return PFX_ERR_BUFFER;
+    if (w->pos % 8 == 0) {
+        if (len > 0) memcpy(w->buf + w->pos / 8, data, len);
+        w->pos += len * 8;
+        return PFX_OK;
+    }
+    for (i = 0; i < len; i++) {
+        TRY(put_bits(w, 8, data[i]));
+    }
+    return PFX_OK;
+}
+
+static inline pfx_status_t get_bytes(reader_t *r, uint8_t *out, size_t len)
+{
+    size_t i;
+    if (REMAINING(r) / 8 < len) return PFX_ERR_TRUNCATED;
+    for (i = 0; i < len; i++) {
+        uint64_t byte;
+        TRY(get_bits(r, 8, &byte));
+        out[i] = (uint8_t)byte;
+    }
+    return PFX_OK;
+}
+
+/* Writes len bytes of data, then NULs up to cap */
+static inline pfx_status_t put_padded(writer_t *w, const uint8_t *data, size_t len, size_t cap)
+{
+    if (len > cap) return PFX_ERR_CAPACITY;
+    TRY(put_bytes(w, data, len));
+    for (; len < cap; len++) {
+        TRY(put_bits(w, 8, 0));
+    }
+    return PFX_OK;
+}
+
+static inline size_t str_len(const char *s, size_t max)
+{
+    size_t len = 0;
+    while (len < max && s[len] != '\0') len++;
+    return len;
+}
+
+static inline pfx_status_t expect_bytes(reader_t *r, const uint8_t *expected, size_t len)
+{
+    size_t i;
+    for (i = 0; i < len; i++) {
+        uint64_t byte;
+        TRY(get_bits(r, 8, &byte));
+        if (byte != expected[i]) return PFX_ERR_CONST;
+    }
+    return PFX_OK;
+}
+
+/* Consumes seq if it comes next */
+static inline int take_seq(reader_t *r, const uint8_t *seq, size_t len)
+{
+    reader_t peek = *r;
+    size_t i;
+    for (i = 0; i < len; i++) {
+        uint64_t byte;
+        if (get_bits(&peek, 8, &byte) != PFX_OK || byte != seq[i]) return 0;
+    }
+    *r = peek;
+    return 1;
+}
+
+/* Reads bytes up to and including seq, keeping the ones before it */
+static inline pfx_status_t get_until(reader_t *r, uint8_t *out, size_t cap, size_t *len, const uint8_t *seq, size_t seq_len)
+{
+    *len = 0;
+    while (!take_seq(r, seq, seq_len)) {
+        uint64_t byte;
+        if (*len == cap) return PFX_ERR_CAPACITY;
+        TRY(get_bits(r, 8, &byte));
+        out[(*len)++] = (uint8_t)byte;
+    }
+    return PFX_OK;
+}
+
+/* The last bits bits of data */
+static inline pfx_status_t put_const(writer_t *w, const uint8_t *data, size_t len, unsigned bits)
+{
+    size_t i, start = len * 8 - bits;
+    for (i = start; i < len * 8; i++) {
+        TRY(put_bits(w, 1, (data[i / 8] >> (7 - i % 8)) & 1u));
+    }
+    return PFX_OK;
+}
+
+static inline pfx_status_t expect_const(reader_t *r, const uint8_t *data, size_t len, unsigned bits)
+{
+    size_t i, start = len * 8 - bits;
+    for (i = start; i < len * 8; i++) {
+        uint64_t bit;
+        TRY(get_bits(r, 1, &bit));
+        if (bit != ((data[i / 8] >> (7 - i % 8)) & 1u)) return PFX_ERR_CONST;
+    }
+    return PFX_OK;
+}
+
+/* CRC-32 (IEEE 802.3) */
+static inline uint64_t crc32(const uint8_t *data, size_t len)
+{
+    uint32_t crc = 0xFFFFFFFFu;
+    size_t i;
+    unsigned bit;
+    for (i = 0; i < len; i++) {
+        crc ^= data[i];
+        for (bit = 0; bit < 8; bit++) {
+            crc = (crc & 1u) ? (crc >> 1) ^ 0xEDB88320u : crc >> 1;
+        }
+    }
+    return ~crc;
+}
+
+/* CRC-16/XMODEM */
+static inline uint64_t crc16_xmodem(const uint8_t *data, size_t len)
+{
+    uint16_t crc = 0;
+    size_t i;
+    unsigned bit;
+    for (i = 0; i < len; i++) {
+        crc ^= (uint16_t)(data[i] << 8);
+        for (bit = 0; bit < 8; bit++) {
+            crc = (crc & 0x8000u) ? (uint16_t)((crc << 1) ^ 0x1021u) : (uint16_t)(crc << 1);
+        }
+    }
+    return crc;
+}
+"#;
+
+const DRIVER_FNS: &str = r#"
+void pfx_init(pfx_device_t *dev, const pfx_hal_t *hal, uint32_t timeout_ms)
+{
+    dev->hal = hal;
+    dev->timeout_ms = timeout_ms;
+}
+
+void pfx_flush(pfx_device_t *dev)
+{
+    while (dev->hal->read_with_timeout(dev->hal->ctx, dev->buf, sizeof dev->buf, 0) > 0) {
+    }
+}
+
+static pfx_status_t read_exact(pfx_device_t *dev, uint8_t *out, size_t len)
+{
+    size_t n = 0;
+    while (n < len) {
+        int got = dev->hal->read_with_timeout(dev->hal->ctx, out + n, len - n, dev->timeout_ms);
+        if (got < 0) return PFX_ERR_IO;
+        if (got == 0) return PFX_ERR_TIMEOUT;
+        n += (size_t)got;
+    }
+    return PFX_OK;
+}
+"#;
+
+const RECEIVE_FRAMED: &str = r#"
+/* Reads a byte at a time until a whole frame has arrived. payload_len is the payload's size in
+ * bytes when it's fixed, or -1 */
+static pfx_status_t receive_frame(pfx_device_t *dev, long payload_len, pfx_frame_t *frame)
+{
+    size_t n = 0;
+    for (;;) {
+        pfx_status_t status;
+        if (n == sizeof dev->buf) return PFX_ERR_BUFFER;
+        TRY(read_exact(dev, &dev->buf[n], 1));
+        n++;
+        status = parse_rx_frame(dev->buf, n, payload_len, 0, frame);
+        if (status != PFX_ERR_TRUNCATED) return status;
+    }
+}
+"#;
+
+const HARNESS_FAKES: &str = r#"
+#include <stdio.h>
+#include <string.h>
+
+#include "pfx.h"
+
+static int failures = 0;
+
+#define CHECK(expr) do { if (!(expr)) { printf("%s:%d: check failed: %s\n", __FILE__, __LINE__, #expr); failures++; } } while (0)
+
+/* A fake device that replays queued responses and counts what it's sent */
+static uint8_t fake_rx[1 << 16];
+static size_t fake_rx_len, fake_rx_pos, fake_tx_len;
+
+static void fake_reset(void)
+{
+    fake_rx_len = fake_rx_pos = fake_tx_len = 0;
+}
+
+static void fake_queue(const uint8_t *data, size_t len)
+{
+    memcpy(fake_rx + fake_rx_len, data, len);
+    fake_rx_len += len;
+}
+
+static int fake_write(void *ctx, const uint8_t *data, size_t len)
+{
+    (void)ctx;
+    (void)data;
+    fake_tx_len += len;
+    return 0;
+}
+
+static int fake_read(void *ctx, uint8_t *data, size_t len, uint32_t timeout_ms)
+{
+    size_t n = fake_rx_len - fake_rx_pos;
+    (void)ctx;
+    /* responses are all queued up front, so flushing mustn't drop them */
+    if (timeout_ms == 0) return 0;
+    if (n > len) n = len;
+    memcpy(data, fake_rx + fake_rx_pos, n);
+    fake_rx_pos += n;
+    return (int)n;
+}
+
+static void fake_delay(void *ctx, uint32_t ms)
+{
+    (void)ctx;
+    (void)ms;
+}
+
+static const pfx_hal_t fake_hal = { NULL, fake_write, fake_read, fake_delay };
+"#;
+
+const MAKEFILE: &str = "CC ?= cc
+CFLAGS ?= -std=c99 -Wall -Wextra -pedantic -O2
+
+test: test_pfx
+\t./test_pfx
+
+test_pfx: test_pfx.c pfx.c pfx.h
+\t$(CC) $(CFLAGS) -o $@ test_pfx.c pfx.c
+
+clean:
+\trm -f test_pfx
+
+.PHONY: test clean
+";
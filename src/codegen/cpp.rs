@@ -0,0 +1,1569 @@
+//! Generates a header-only C++17 driver: one `.hpp` per device with payload structs backed by
+//! `std::array`, `constexpr` sizes from the size analysis, and a `Device` template over any
+//! transport with `write`, `read` and `delay_ms`.
+//!
+//! Like the C backend, encoding goes through a bit writer one field at a time, and frame formats
+//! are turned into straight-line code. Nothing allocates and nothing throws: calls return a
+//! `Status`, or a `Result<T>` holding either the value or the `Status` saying why there isn't one.
+
+use super::layout::Format;
+use super::{BackendInfo, BackendOptions, CodeWriter, Codegen, CodegenError, Layout, OptionInfo, OutputSink};
+use crate::ir::*;
+use crate::value::{Fields, Value};
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "cpp",
+    description: "Header-only C++17, with constexpr sizes and a templated transport",
+    options: &[
+        OptionInfo { name: "namespace", description: "Namespace for the generated code, and the header's name", default: Some("the device's name, in snake case") },
+        OptionInfo { name: "max-len", description: "Capacity of variable length fields the document doesn't bound", default: Some("64") },
+        OptionInfo { name: "tests", description: "Generate a test harness and Makefile", default: Some("true") },
+    ],
+    create: |options| Ok(Box::new(CppBackend::new(options)?)),
+};
+
+pub struct CppBackend {
+    namespace: Option<String>,
+    max_len: u32,
+    tests: bool,
+}
+
+impl CppBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        let max_len = options.get_or("max-len", "64");
+        Ok(Self {
+            namespace: options.get("namespace").map(str::to_owned),
+            max_len: max_len.parse().map_err(|_| CodegenError::BadOption { option: "max-len".to_owned(), value: max_len.to_owned(), expected: "a number" })?,
+            tests: options.get_bool("tests", true)?,
+        })
+    }
+}
+
+impl Codegen for CppBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let namespace = Name::new(&self.namespace.clone().unwrap_or_else(|| ir.device.name.snake()));
+        let gen = Gen::new(ir, Layout::new(ir, self.max_len), &namespace)?;
+        let ns = &gen.ns;
+        out.write_text(&format!("{ns}.hpp"), &gen.header())?;
+        if self.tests {
+            out.write_text(&format!("test_{ns}.cpp"), &gen.harness())?;
+            out.write_text("Makefile", &MAKEFILE.replace("pfx", ns))?;
+        }
+        Ok(())
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "alignas", "alignof", "and", "and_eq", "asm", "auto", "bitand", "bitor", "bool", "break", "case", "catch", "char", "char8_t", "char16_t", "char32_t",
+    "class", "compl", "concept", "const", "consteval", "constexpr", "constinit", "const_cast", "continue", "co_await", "co_return", "co_yield",
+    "decltype", "default", "delete", "do", "double", "dynamic_cast", "else", "enum", "explicit", "export", "extern", "false", "float", "for", "friend",
+    "goto", "if", "inline", "int", "long", "mutable", "namespace", "new", "noexcept", "not", "not_eq", "nullptr", "operator", "or", "or_eq", "private",
+    "protected", "public", "register", "reinterpret_cast", "requires", "return", "short", "signed", "sizeof", "static", "static_assert", "static_cast",
+    "struct", "switch", "template", "this", "thread_local", "throw", "true", "try", "typedef", "typeid", "typename", "union", "unsigned", "using",
+    "virtual", "void", "volatile", "wchar_t", "while", "xor", "xor_eq",
+];
+
+fn ident(name: &Name) -> String {
+    let snake = name.snake();
+    if KEYWORDS.contains(&snake.as_str()) {
+        format!("{snake}_")
+    } else if snake.starts_with(|c: char| c.is_ascii_digit()) || snake.is_empty() {
+        format!("_{snake}")
+    } else {
+        snake
+    }
+}
+
+/// A struct member, kept clear of the members payload structs declare
+fn member(name: &Name) -> String {
+    match ident(name).as_str() {
+        "encode" | "decode" | "pack" | "unpack" | "matches" | "max_len" | "fixed_len" => format!("{}_", name.snake()),
+        other => other.to_owned(),
+    }
+}
+
+/// A struct or payload type, kept clear of the types the header declares itself
+fn type_name(name: &Name) -> String {
+    let pascal = name.pascal();
+    match pascal.as_str() {
+        "Status" | "Result" | "Vec" | "String" | "Device" | "Writer" | "Reader" | "Frame" => format!("{pascal}_"),
+        _ if pascal.starts_with(|c: char| c.is_ascii_digit()) || pascal.is_empty() => format!("_{pascal}"),
+        _ => pascal,
+    }
+}
+
+fn int_type(bits: u32, signing: Signing) -> String {
+    let width = match bits {
+        0..=8 => 8,
+        9..=16 => 16,
+        17..=32 => 32,
+        _ => 64,
+    };
+    match signing {
+        Signing::Unsigned => format!("std::uint{width}_t"),
+        _ => format!("std::int{width}_t"),
+    }
+}
+
+fn boolean(value: bool) -> &'static str {
+    if value {
+        "true"
+    } else {
+        "false"
+    }
+}
+
+fn little(endianness: Endianness) -> &'static str {
+    boolean(endianness == Endianness::LittleEndian)
+}
+
+/// A braced list for the `std::initializer_list` overloads of the writer and reader
+fn bytes_list(data: &[u8]) -> String {
+    format!("{{{}}}", data.iter().map(|b| format!("0x{b:02x}")).collect::<Vec<_>>().join(", "))
+}
+
+/// `//` comments, one per line of text
+fn comment(w: &mut CodeWriter, text: &str) {
+    let text = text.trim();
+    if !text.is_empty() {
+        w.comment("//", text);
+    }
+}
+
+fn dir(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Tx => "tx",
+        Direction::Rx => "rx",
+    }
+}
+
+fn crc_fn(crc: Crc) -> (&'static str, u32) {
+    match crc {
+        Crc::Crc32 => ("crc32", 32),
+        Crc::Crc16XModem => ("crc16_xmodem", 16),
+    }
+}
+
+struct Gen<'a> {
+    ir: &'a Ir,
+    layout: Layout<'a>,
+
+    /// The namespace, and the upper case prefix for macros
+    ns: String,
+    nsu: String,
+    tx: Option<Format>,
+    rx: Option<Format>,
+}
+
+impl<'a> Gen<'a> {
+    fn new(ir: &'a Ir, layout: Layout<'a>, namespace: &Name) -> Result<Self, CodegenError> {
+        let [tx, rx] = Format::both(ir, "cpp")?;
+        Ok(Self { ir, layout, ns: ident(namespace), nsu: namespace.screaming(), tx, rx })
+    }
+
+    fn format(&self, direction: Direction) -> Option<&Format> {
+        match direction {
+            Direction::Tx => self.tx.as_ref(),
+            Direction::Rx => self.rx.as_ref(),
+        }
+    }
+
+    fn framed(&self) -> bool {
+        self.ir.framing.is_some()
+    }
+
+    /// Replaces the placeholders in the static parts of the header
+    fn fill(&self, text: &str) -> String {
+        text.trim().replace("PFX", &self.nsu).replace("pfx", &self.ns)
+    }
+
+    fn payload_type(direction: Direction, name: &Name) -> String {
+        format!("{}::{}", dir(direction), type_name(name))
+    }
+
+    fn method_name(transaction: &Transaction) -> String {
+        match ident(&transaction.name).as_str() {
+            name @ ("transport" | "flush" | "send" | "receive" | "read_exact" | "receive_frame") => format!("{name}_transaction"),
+            name => name.to_owned(),
+        }
+    }
+
+    /// The C++ type of a field. Structs are qualified when `qualify` is set, for use inside the
+    /// `tx` and `rx` namespaces where a payload could share a struct's name
+    fn cpp_type(&self, ty: &Type, fields: &[Field], qualify: bool) -> String {
+        let cap = |len: &Length| self.layout.cap(len, fields);
+        let struct_ref = |name: &Name| if qualify { format!("::{}::{}", self.ns, type_name(name)) } else { type_name(name) };
+        match ty {
+            Type::Int { bits, signing, .. } => int_type(*bits, *signing),
+            Type::Float { bits: 32, .. } => "float".to_owned(),
+            Type::Float { .. } => "double".to_owned(),
+            Type::Bytes(Length::Fixed(n)) => format!("std::array<std::uint8_t, {n}>"),
+            Type::Bytes(len) => format!("Vec<std::uint8_t, {}>", cap(len)),
+            Type::String(len) => format!("String<{}>", cap(len)),
+            Type::Const(data) => format!("std::array<std::uint8_t, {}>", data.len()),
+            Type::Struct(s) => struct_ref(s),
+            Type::Array { item, len: Length::Fixed(n) } => format!("std::array<{}, {n}>", struct_ref(item)),
+            Type::Array { item, len } => format!("Vec<{}, {}>", struct_ref(item), cap(len)),
+        }
+    }
+
+    fn max_metadata(&self) -> usize {
+        [Direction::Tx, Direction::Rx]
+            .iter()
+            .filter_map(|d| self.format(*d))
+            .map(|f| f.elements.iter().filter(|e| matches!(e, FlatElement::Metadata { .. })).count())
+            .max()
+            .unwrap_or(0)
+            .max(1)
+    }
+
+    fn header(&self) -> String {
+        let (ns, nsu, ir) = (&self.ns, &self.nsu, self.ir);
+        let mut w = CodeWriter::new("    ");
+        comment(
+            &mut w,
+            &format!("Driver for the {}: {}\n\nGenerated from an OpenPID document by `openpid gen cpp`. Don't edit by hand", ir.device.name, ir.device.description),
+        );
+        w.line(format!("#ifndef {nsu}_HPP"));
+        w.line(format!("#define {nsu}_HPP"));
+        w.blank();
+        w.line(INCLUDES.trim());
+        w.blank();
+        w.line(format!("#define {nsu}_TRY(expr) do {{ ::{ns}::Status status_ = (expr); if (status_ != ::{ns}::Status::Ok) return status_; }} while (0)"));
+        w.blank();
+        w.line(format!("namespace {ns} {{"));
+        w.blank();
+        w.line(self.fill(COMMON));
+        w.blank();
+        w.line("// Largest encoded payload, in bytes");
+        w.line(format!("inline constexpr std::size_t max_payload_len = {};", self.layout.max_payload_len()));
+        w.line("// Largest frame in each direction, in bytes");
+        w.line(format!("inline constexpr std::size_t tx_frame_len = {};", self.layout.max_frame_len(Direction::Tx)));
+        w.line(format!("inline constexpr std::size_t rx_frame_len = {};", self.layout.max_frame_len(Direction::Rx)));
+        if self.framed() {
+            w.line("// Most metadata elements in either direction's frame format");
+            w.line(format!("inline constexpr std::size_t max_metadata = {};", self.max_metadata()));
+        }
+        self.metadata_enums(&mut w);
+        for s in &ir.structs {
+            w.blank();
+            self.struct_def(&mut w, &type_name(&s.name), s.description.as_deref(), &s.fields, None);
+        }
+        for direction in [Direction::Tx, Direction::Rx] {
+            if ir.payloads(direction).is_empty() {
+                continue;
+            }
+            w.blank();
+            w.line(format!("namespace {} {{", dir(direction)));
+            for p in ir.payloads(direction) {
+                w.blank();
+                self.struct_def(&mut w, &type_name(&p.name), Some(&p.description), &p.fields, Some(p));
+            }
+            w.blank();
+            w.line(format!("}}  // namespace {}", dir(direction)));
+        }
+        w.blank();
+        w.line("namespace detail {");
+        for s in &ir.structs {
+            w.blank();
+            self.codec_fns(&mut w, &type_name(&s.name), &s.fields);
+        }
+        for p in ir.all_payloads() {
+            w.blank();
+            self.codec_fns(&mut w, &Self::payload_type(p.direction, &p.name), &p.fields);
+        }
+        w.blank();
+        w.line("}  // namespace detail");
+        for p in ir.all_payloads() {
+            w.blank();
+            self.payload_fns(&mut w, p);
+        }
+        if self.framed() {
+            w.blank();
+            self.frame_fns(&mut w);
+            for p in ir.all_payloads() {
+                w.blank();
+                self.pack_fns(&mut w, p);
+            }
+        }
+        for transaction in ir.transactions.iter().filter(|t| t.returns.len() > 1) {
+            w.blank();
+            w.line(format!("// What `{}` returns", Self::method_name(transaction)));
+            w.block(format!("struct {}Response {{", type_name(&transaction.name)), "};", |w| {
+                for ret in &transaction.returns {
+                    w.line(format!("{} {}{{}};", self.cpp_type(&ret.ty, self.layout.return_siblings(ret), false), member(&ret.name)));
+                }
+            });
+        }
+        w.blank();
+        w.line(self.fill(TRANSPORT));
+        w.blank();
+        self.device(&mut w);
+        w.blank();
+        w.line(format!("}}  // namespace {ns}"));
+        w.blank();
+        w.line(format!("#undef {nsu}_TRY"));
+        w.blank();
+        w.line(format!("#endif  // {nsu}_HPP"));
+        w.finish()
+    }
+
+    fn metadata_enums(&self, w: &mut CodeWriter) {
+        let ir = self.ir;
+        for direction in [Direction::Tx, Direction::Rx] {
+            let Some(framing) = &ir.framing else { break };
+            for (key, _) in framing.metadata(direction) {
+                let values = ir
+                    .payloads(direction)
+                    .iter()
+                    .filter_map(|payload| Some((payload, payload.metadata(key.raw())?.packed()?.first().copied()?)))
+                    .collect::<Vec<_>>();
+                if values.is_empty() {
+                    continue;
+                }
+                w.blank();
+                comment(w, &format!("Value of the \"{key}\" metadata each {direction} payload is sent with"));
+                let pascal = if direction == Direction::Tx { "Tx" } else { "Rx" };
+                w.block(format!("enum class {pascal}{} : std::uint64_t {{", key.pascal()), "};", |w| {
+                    for (payload, value) in &values {
+                        w.line(format!("{} = 0x{value:x},", type_name(&payload.name)));
+                    }
+                });
+            }
+        }
+    }
+
+    /// A struct or payload's definition. Payloads also get their sizes and codec declarations
+    fn struct_def(&self, w: &mut CodeWriter, name: &str, description: Option<&str>, fields: &[Field], payload: Option<&Payload>) {
+        if let Some(description) = description {
+            comment(w, description);
+        }
+        let user_facing = fields.iter().filter(|f| f.is_user_facing()).collect::<Vec<_>>();
+        w.block(format!("struct {name} {{"), "};", |w| {
+            for f in &user_facing {
+                if let Some(description) = &f.description {
+                    comment(w, description);
+                }
+                w.line(format!("{} {}{{}};", self.cpp_type(&f.ty, fields, payload.is_some()), member(&f.name)));
+            }
+            if !user_facing.is_empty() {
+                w.blank();
+            }
+            if let Some(payload) = payload {
+                let fixed = payload.size.fixed_bits().map_or("std::nullopt".to_owned(), |bits| bits.div_ceil(8).to_string());
+                w.line("// Encoded size in bytes: the most it can take, and the exact size if it doesn't vary");
+                w.line(format!("static constexpr std::size_t max_len = {};", self.layout.fields_max_bits(fields).div_ceil(8)));
+                w.line(format!("static constexpr std::optional<std::size_t> fixed_len = {fixed};"));
+                w.blank();
+                w.line("// Encodes the payload alone into out, setting len to the bytes written");
+                w.line("Status encode(std::uint8_t *out, std::size_t cap, std::size_t &len) const;");
+                w.line(format!("static Result<{name}> decode(const std::uint8_t *data, std::size_t len);"));
+                if self.framed() {
+                    w.line("// Encodes the payload wrapped in its frame, and back");
+                    w.line("Status pack(std::uint8_t *out, std::size_t cap, std::size_t &len) const;");
+                    w.line(format!("static Result<{name}> unpack(const std::uint8_t *data, std::size_t len);"));
+                    w.line("// Whether a frame's metadata is one this payload is sent with");
+                    w.line("static bool matches(const std::uint64_t *metadata);");
+                }
+                w.blank();
+            }
+            if user_facing.is_empty() {
+                w.line(format!("friend bool operator==(const {name} &, const {name} &) {{ return true; }}"));
+            } else {
+                let compare = user_facing.iter().map(|f| format!("a.{0} == b.{0}", member(&f.name))).collect::<Vec<_>>().join(" && ");
+                w.line(format!("friend bool operator==(const {name} &a, const {name} &b) {{ return {compare}; }}"));
+            }
+            w.line(format!("friend bool operator!=(const {name} &a, const {name} &b) {{ return !(a == b); }}"));
+        });
+    }
+
+    /// `detail::encode_to` and `detail::decode_from` overloads for a struct or payload
+    fn codec_fns(&self, w: &mut CodeWriter, ty: &str, fields: &[Field]) {
+        let (w_, r_, v_) = if fields.is_empty() { ("[[maybe_unused]] Writer &w", "[[maybe_unused]] Reader &r", "") } else { ("Writer &w", "Reader &r", " v") };
+        w.block(format!("inline Status encode_to({w_}, const {ty} &{}) {{", v_.trim()), "}", |w| {
+            for f in fields {
+                self.encode_field(w, f);
+            }
+            w.line("return Status::Ok;");
+        });
+        w.blank();
+        w.block(format!("inline Status decode_from({r_}, {ty} &{}) {{", v_.trim()), "}", |w| {
+            for f in fields.iter().filter(|f| f.count_of.is_some()) {
+                w.line(format!("std::uint64_t n_{} = 0;", f.name.snake()));
+            }
+            for f in fields {
+                self.decode_field(w, f);
+            }
+            w.line("return Status::Ok;");
+        });
+    }
+
+    fn encode_field(&self, w: &mut CodeWriter, f: &Field) {
+        let nsu = &self.nsu;
+        let v = format!("v.{}", member(&f.name));
+        let terminator = |w: &mut CodeWriter, len: &Length| {
+            if let Length::Sequence(sequence) = len {
+                w.line(format!("{nsu}_TRY(w.put_bytes({}));", bytes_list(sequence)));
+            }
+        };
+        match &f.ty {
+            Type::Int { bits, signing, endianness } => {
+                let value = match &f.count_of {
+                    Some(counted) => format!("v.{}.size()", member(counted)),
+                    None => v,
+                };
+                match signing {
+                    Signing::Unsigned => w.line(format!("{nsu}_TRY(w.put_uint({bits}, {}, static_cast<std::uint64_t>({value})));", little(*endianness))),
+                    _ => w.line(format!(
+                        "{nsu}_TRY(w.put_int({bits}, {}, {}, static_cast<std::int64_t>({value})));",
+                        little(*endianness),
+                        boolean(*signing == Signing::OnesComplement)
+                    )),
+                };
+            }
+            Type::Float { endianness, .. } => {
+                w.line(format!("{nsu}_TRY(w.put_float({}, {v}));", little(*endianness)));
+            }
+            Type::Const(data) => {
+                w.line(format!("{nsu}_TRY(w.put_bytes({}));", bytes_list(data)));
+            }
+            Type::Struct(_) => {
+                w.line(format!("{nsu}_TRY(encode_to(w, {v}));"));
+            }
+            Type::Bytes(len) => {
+                w.line(format!("{nsu}_TRY(w.put_bytes({v}.data(), {v}.size()));"));
+                terminator(w, len);
+            }
+            Type::String(Length::Fixed(n) | Length::Capacity(n)) => {
+                w.line(format!("{nsu}_TRY(w.put_padded(bytes({v}.data()), {v}.size(), {n}));"));
+            }
+            Type::String(len) => {
+                w.line(format!("{nsu}_TRY(w.put_bytes(bytes({v}.data()), {v}.size()));"));
+                terminator(w, len);
+            }
+            Type::Array { len, .. } => {
+                w.block(format!("for (const auto &item : {v}) {{"), "}", |w| {
+                    w.line(format!("{nsu}_TRY(encode_to(w, item));"));
+                });
+                terminator(w, len);
+            }
+        }
+    }
+
+    fn decode_field(&self, w: &mut CodeWriter, f: &Field) {
+        let nsu = &self.nsu;
+        let v = format!("v.{}", member(&f.name));
+        // how many elements a count field or the remaining data says there are
+        let count = |len: &Length| match len {
+            Length::Fixed(n) | Length::Capacity(n) => n.to_string(),
+            Length::CountField(c) => format!("n_{}", c.snake()),
+            Length::Sequence(_) | Length::Remainder => "r.remaining() / 8".to_owned(),
+        };
+        match &f.ty {
+            Type::Int { bits, signing, endianness } => {
+                let le = little(*endianness);
+                let ones = boolean(*signing == Signing::OnesComplement);
+                match (signing, f.count_of.is_some()) {
+                    (Signing::Unsigned, true) => w.line(format!("{nsu}_TRY(r.get_uint({bits}, {le}, n_{}));", f.name.snake())),
+                    (Signing::Unsigned, false) => w.line(format!("{nsu}_TRY(r.get_uint({bits}, {le}, {v}));")),
+                    (_, true) => w.block("{", "}", |w| {
+                        w.line("std::int64_t raw = 0;");
+                        w.line(format!("{nsu}_TRY(r.get_int({bits}, {le}, {ones}, raw));"));
+                        w.line("if (raw < 0) return Status::Range;");
+                        w.line(format!("n_{} = static_cast<std::uint64_t>(raw);", f.name.snake()));
+                    }),
+                    (_, false) => w.line(format!("{nsu}_TRY(r.get_int({bits}, {le}, {ones}, {v}));")),
+                };
+            }
+            Type::Float { endianness, .. } => {
+                w.line(format!("{nsu}_TRY(r.get_float({}, {v}));", little(*endianness)));
+            }
+            Type::Const(data) => {
+                w.line(format!("{nsu}_TRY(r.expect_bytes({}));", bytes_list(data)));
+            }
+            Type::Struct(_) => {
+                w.line(format!("{nsu}_TRY(decode_from(r, {v}));"));
+            }
+            Type::Bytes(Length::Fixed(n)) => {
+                w.line(format!("{nsu}_TRY(r.get_bytes({v}.data(), {n}));"));
+            }
+            Type::String(Length::Fixed(n) | Length::Capacity(n)) => {
+                w.line(format!("{nsu}_TRY(r.get_padded({v}, {n}));"));
+            }
+            Type::Bytes(Length::Sequence(sequence)) | Type::String(Length::Sequence(sequence)) => {
+                w.line(format!("{nsu}_TRY(r.get_until({v}, {}));", bytes_list(sequence)));
+            }
+            Type::Bytes(len) | Type::String(len) => {
+                w.line(format!("{nsu}_TRY(r.get_into({v}, {}));", count(len)));
+            }
+            Type::Array { len: Length::Fixed(_), .. } => {
+                w.block(format!("for (auto &item : {v}) {{"), "}", |w| {
+                    w.line(format!("{nsu}_TRY(decode_from(r, item));"));
+                });
+            }
+            Type::Array { len: len @ Length::CountField(_), .. } => {
+                w.line(format!("if (!{v}.resize({})) return Status::Capacity;", count(len)));
+                w.block(format!("for (auto &item : {v}) {{"), "}", |w| {
+                    w.line(format!("{nsu}_TRY(decode_from(r, item));"));
+                });
+            }
+            Type::Array { len, .. } => {
+                let more = match len {
+                    Length::Sequence(sequence) => format!("!r.take_seq({})", bytes_list(sequence)),
+                    _ => "r.remaining() >= 8".to_owned(),
+                };
+                w.line(format!("{v}.clear();"));
+                w.block(format!("while ({more}) {{"), "}", |w| {
+                    w.line(format!("if (!{v}.push_back({{}})) return Status::Capacity;"));
+                    w.line(format!("{nsu}_TRY(decode_from(r, {v}.back()));"));
+                });
+            }
+        }
+    }
+
+    /// Public `encode` and `decode` on a payload
+    fn payload_fns(&self, w: &mut CodeWriter, payload: &Payload) {
+        let (ns, nsu) = (&self.ns, &self.nsu);
+        let ty = Self::payload_type(payload.direction, &payload.name);
+        w.block(format!("inline Status {ty}::encode(std::uint8_t *out, std::size_t cap, std::size_t &len) const {{"), "}", |w| {
+            w.line(format!("::{ns}::detail::Writer w(out, cap);"));
+            w.line(format!("{nsu}_TRY(::{ns}::detail::encode_to(w, *this));"));
+            w.line("len = w.len();");
+            w.line("return Status::Ok;");
+        });
+        w.blank();
+        w.block(format!("inline Result<{ty}> {ty}::decode(const std::uint8_t *data, std::size_t len) {{"), "}", |w| {
+            w.line(format!("::{ns}::detail::Reader r(data, len);"));
+            w.line(format!("{ty} v;"));
+            w.line(format!("{nsu}_TRY(::{ns}::detail::decode_from(r, v));"));
+            w.line("if (r.remaining() >= 8) return Status::Trailing;");
+            w.line("return v;");
+        });
+    }
+
+    /// The value of a size element, given an expression in bits
+    fn size_expr(bits_expr: &str, unit: BitsOrBytes) -> String {
+        match unit {
+            BitsOrBytes::Bits => format!("static_cast<std::uint64_t>({bits_expr})"),
+            BitsOrBytes::Bytes => format!("static_cast<std::uint64_t>(({bits_expr} + 7) / 8)"),
+        }
+    }
+
+    /// The `frame` namespace: the `Frame` a parse finds, and per direction an encoder, a parser
+    /// that reports running out of data as `Status::Truncated`, and a decoder on top of it
+    fn frame_fns(&self, w: &mut CodeWriter) {
+        w.line("namespace frame {");
+        w.blank();
+        w.line("// A frame found in a buffer. The payload points into that buffer");
+        w.block("struct Frame {", "};", |w| {
+            w.line("std::array<std::uint64_t, max_metadata> metadata{};");
+            w.line("const std::uint8_t *payload = nullptr;");
+            w.line("std::size_t payload_len = 0;");
+            w.line("// Length of the whole frame, in bytes");
+            w.line("std::size_t len = 0;");
+        });
+        for direction in [Direction::Tx, Direction::Rx] {
+            if let Some(format) = self.format(direction) {
+                w.blank();
+                self.frame_encoder(w, direction, format);
+                w.blank();
+                self.frame_parser(w, direction, format);
+            }
+        }
+        w.blank();
+        w.line("}  // namespace frame");
+    }
+
+    fn frame_encoder(&self, w: &mut CodeWriter, direction: Direction, format: &Format) {
+        let nsu = &self.nsu;
+        let d = dir(direction);
+        let envelope = format.envelope_bits;
+        let has_metadata = format.elements.iter().any(|e| matches!(e, FlatElement::Metadata { .. }));
+        let metadata = if has_metadata { "const std::uint64_t *metadata" } else { "[[maybe_unused]] const std::uint64_t *metadata" };
+        w.block(
+            format!("inline Status encode_{d}({metadata}, const std::uint8_t *payload, std::size_t payload_len, std::uint8_t *out, std::size_t cap, std::size_t &len) {{"),
+            "}",
+            |w| {
+                w.line("const std::size_t payload_bits = payload_len * 8;");
+                w.line(format!("[[maybe_unused]] const std::size_t total = {envelope} + payload_bits;"));
+                w.line("if ((total + 7) / 8 > cap) return Status::Buffer;");
+                w.line("std::memset(out, 0, (total + 7) / 8);");
+                w.line("detail::Writer w(out, cap);");
+                let mut metadata = 0;
+                for element in &format.elements {
+                    match element {
+                        FlatElement::SizeTotal { bits, unit } => {
+                            w.line(format!("{nsu}_TRY(w.put_uint({bits}, false, {}));", Self::size_expr("total", *unit)));
+                        }
+                        FlatElement::SizeOfPayload { bits, unit } => {
+                            w.line(format!("{nsu}_TRY(w.put_uint({bits}, false, {}));", Self::size_expr("payload_bits", *unit)));
+                        }
+                        FlatElement::SizeOfElements { bits, unit, static_bits, has_payload, .. } => {
+                            let covered = if *has_payload { format!("{static_bits} + payload_bits") } else { static_bits.to_string() };
+                            w.line(format!("{nsu}_TRY(w.put_uint({bits}, false, {}));", Self::size_expr(&covered, *unit)));
+                        }
+                        FlatElement::Payload => {
+                            w.line(format!("{nsu}_TRY(w.put_bytes(payload, payload_len));"));
+                        }
+                        FlatElement::Metadata { bits, endianness, .. } => {
+                            w.line(format!("{nsu}_TRY(w.put_uint({bits}, {}, metadata[{metadata}]));", little(*endianness)));
+                            metadata += 1;
+                        }
+                        FlatElement::Crc(crc) => {
+                            let (f, bits) = crc_fn(*crc);
+                            w.line(format!("{nsu}_TRY(w.put_uint({bits}, false, detail::{f}(out, w.pos() / 8)));"));
+                        }
+                        FlatElement::Const { data, bits } => {
+                            w.line(format!("{nsu}_TRY(w.put_const({}, {bits}));", bytes_list(data)));
+                        }
+                    }
+                }
+                w.line("len = w.len();");
+                w.line("return Status::Ok;");
+            },
+        );
+    }
+
+    fn frame_parser(&self, w: &mut CodeWriter, direction: Direction, format: &Format) {
+        let nsu = &self.nsu;
+        let d = dir(direction);
+        let envelope = format.envelope_bits;
+        let has_total = format.elements.iter().any(|e| matches!(e, FlatElement::SizeTotal { .. }));
+        w.line("// Parses a frame from the start of data. payload_len is the payload's size in bytes when it's");
+        w.line("// fixed, or -1. Unless complete is set, data left after the frame is fine");
+        w.block(
+            format!("inline Status parse_{d}(const std::uint8_t *data, std::size_t len, [[maybe_unused]] long payload_len, bool complete, Frame &frame) {{"),
+            "}",
+            |w| {
+                w.line("detail::Reader r(data, len);");
+                w.line("[[maybe_unused]] std::uint64_t value = 0;");
+                w.line("std::size_t payload_bits = 0;");
+                if has_total {
+                    w.line("std::size_t total = 0;");
+                }
+                w.line("frame = Frame{};");
+                // whether payload_bits is known by the time the payload comes
+                let mut payload_known = false;
+                let mut total_known = false;
+                let mut metadata = 0;
+                let bytes = |unit: &BitsOrBytes| if *unit == BitsOrBytes::Bytes { " * 8" } else { "" };
+                for (i, element) in format.elements.iter().enumerate() {
+                    match element {
+                        FlatElement::SizeTotal { bits, unit } => {
+                            w.line(format!("{nsu}_TRY(r.get_uint({bits}, false, value));"));
+                            w.line(format!("total = static_cast<std::size_t>(value){};", bytes(unit)));
+                            w.line(format!("if (total < {envelope}) return Status::Size;"));
+                            w.line("if (total > len * 8) return Status::Truncated;");
+                            total_known = true;
+                        }
+                        FlatElement::SizeOfPayload { bits, unit } => {
+                            w.line(format!("{nsu}_TRY(r.get_uint({bits}, false, value));"));
+                            w.line(format!("payload_bits = static_cast<std::size_t>(value){};", bytes(unit)));
+                            payload_known = true;
+                        }
+                        FlatElement::SizeOfElements { bits, unit, static_bits, has_payload, .. } => {
+                            w.line(format!("{nsu}_TRY(r.get_uint({bits}, false, value));"));
+                            if *has_payload {
+                                if *unit == BitsOrBytes::Bytes {
+                                    w.line("value *= 8;");
+                                }
+                                w.line(format!("if (value < {static_bits}) return Status::Size;"));
+                                w.line(format!("payload_bits = static_cast<std::size_t>(value) - {static_bits};"));
+                                payload_known = true;
+                            }
+                        }
+                        FlatElement::Payload => {
+                            if !payload_known {
+                                if total_known {
+                                    w.line(format!("payload_bits = total - {envelope};"));
+                                } else {
+                                    let after: u64 = format.elements[i + 1..].iter().map(FlatElement::bits).sum();
+                                    w.block("if (payload_len >= 0) {", "", |w| {
+                                        w.line("payload_bits = static_cast<std::size_t>(payload_len) * 8;");
+                                    });
+                                    w.block("} else if (complete) {", "", |w| {
+                                        w.line(format!("payload_bits = r.remaining() > {after} ? r.remaining() - {after} : 0;"));
+                                    });
+                                    w.block("} else {", "}", |w| {
+                                        w.line("return Status::Length;");
+                                    });
+                                }
+                            }
+                            w.line("if (payload_bits % 8 != 0) return Status::Size;");
+                            w.line("if (r.remaining() < payload_bits) return Status::Truncated;");
+                            w.line("frame.payload = data + r.pos() / 8;");
+                            w.line("frame.payload_len = payload_bits / 8;");
+                            w.line("r.skip(payload_bits);");
+                        }
+                        FlatElement::Metadata { bits, endianness, .. } => {
+                            w.line(format!("{nsu}_TRY(r.get_uint({bits}, {}, frame.metadata[{metadata}]));", little(*endianness)));
+                            metadata += 1;
+                        }
+                        FlatElement::Crc(crc) => {
+                            let (f, bits) = crc_fn(*crc);
+                            w.block("{", "}", |w| {
+                                w.line(format!("const std::uint64_t expected = detail::{f}(data, r.pos() / 8);"));
+                                w.line(format!("{nsu}_TRY(r.get_uint({bits}, false, value));"));
+                                w.line("if (value != expected) return Status::Crc;");
+                            });
+                        }
+                        FlatElement::Const { data, bits } => {
+                            w.line(format!("{nsu}_TRY(r.expect_const({}, {bits}));", bytes_list(data)));
+                        }
+                    }
+                }
+                if has_total {
+                    w.line("if ((total + 7) / 8 != (r.pos() + 7) / 8) return Status::Size;");
+                }
+                w.line("if (complete && r.remaining() >= 8) return Status::Trailing;");
+                w.line("frame.len = (r.pos() + 7) / 8;");
+                w.line("return Status::Ok;");
+            },
+        );
+        w.blank();
+        w.line("// Decodes a buffer holding exactly one frame");
+        w.block(format!("inline Result<Frame> decode_{d}(const std::uint8_t *data, std::size_t len) {{"), "}", |w| {
+            w.line("Frame frame;");
+            w.line(format!("const Status status = parse_{d}(data, len, -1, true, frame);"));
+            w.line("if (status == Status::Length) return Status::Truncated;");
+            w.line("if (status != Status::Ok) return status;");
+            w.line("return frame;");
+        });
+    }
+
+    /// `pack`, `unpack` and `matches` on a payload
+    fn pack_fns(&self, w: &mut CodeWriter, payload: &Payload) {
+        let (ns, nsu) = (&self.ns, &self.nsu);
+        let d = dir(payload.direction);
+        let ty = Self::payload_type(payload.direction, &payload.name);
+        let metadata = payload.metadata.iter().filter_map(|m| m.packed()).collect::<Vec<_>>();
+        w.block(format!("inline Status {ty}::pack(std::uint8_t *out, std::size_t cap, std::size_t &len) const {{"), "}", |w| {
+            w.line(format!("std::array<std::uint8_t, ::{ns}::max_payload_len + 1> body{{}};"));
+            w.line(format!(
+                "const std::array<std::uint64_t, ::{ns}::max_metadata> metadata{{{}}};",
+                metadata.iter().map(|v| format!("0x{:x}", v[0])).collect::<Vec<_>>().join(", ")
+            ));
+            w.line("std::size_t body_len = 0;");
+            w.line(format!("{nsu}_TRY(encode(body.data(), body.size(), body_len));"));
+            w.line(format!("return ::{ns}::frame::encode_{d}(metadata.data(), body.data(), body_len, out, cap, len);"));
+        });
+        w.blank();
+        w.block(format!("inline Result<{ty}> {ty}::unpack(const std::uint8_t *data, std::size_t len) {{"), "}", |w| {
+            w.line(format!("const Result<::{ns}::frame::Frame> frame = ::{ns}::frame::decode_{d}(data, len);"));
+            w.line("if (!frame) return frame.status();");
+            w.line("if (!matches(frame->metadata.data())) return Status::Unexpected;");
+            w.line("return decode(frame->payload, frame->payload_len);");
+        });
+        w.blank();
+        if metadata.is_empty() {
+            w.block(format!("inline bool {ty}::matches(const std::uint64_t *) {{"), "}", |w| {
+                w.line("return true;");
+            });
+            return;
+        }
+        w.block(format!("inline bool {ty}::matches(const std::uint64_t *metadata) {{"), "}", |w| {
+            let checks = metadata
+                .iter()
+                .enumerate()
+                .map(|(i, values)| format!("({})", values.iter().map(|v| format!("metadata[{i}] == 0x{v:x}")).collect::<Vec<_>>().join(" || ")))
+                .collect::<Vec<_>>();
+            w.line(format!("return {};", checks.join(" && ")));
+        });
+    }
+
+    fn return_type(&self, transaction: &Transaction) -> String {
+        match transaction.returns.as_slice() {
+            [] => "Status".to_owned(),
+            [ret] => format!("Result<{}>", self.cpp_type(&ret.ty, self.layout.return_siblings(ret), false)),
+            _ => format!("Result<{}Response>", type_name(&transaction.name)),
+        }
+    }
+
+    fn device(&self, w: &mut CodeWriter) {
+        let ir = self.ir;
+        comment(w, &ir.device.description);
+        w.line("template <typename Transport>");
+        w.block("class Device {", "};", |w| {
+            w.line("static_assert(is_transport<Transport>::value, \"Transport needs write, read and delay_ms; see is_transport\");");
+            w.blank();
+            w.dedent();
+            w.line("public:");
+            w.indent();
+            w.line(self.fill(DEVICE_COMMON));
+            w.blank();
+            if self.framed() {
+                w.line(self.fill(DEVICE_FRAMED));
+            } else {
+                w.line(self.fill(DEVICE_UNFRAMED));
+            }
+            for transaction in &ir.transactions {
+                w.blank();
+                self.transaction(w, transaction);
+            }
+            w.blank();
+            w.dedent();
+            w.line("private:");
+            w.indent();
+            w.line(self.fill(DEVICE_PRIVATE));
+            if self.framed() {
+                w.blank();
+                w.line(self.fill(RECEIVE_FRAME));
+            }
+            w.blank();
+            w.line("Transport &transport_;");
+            w.line("std::uint32_t timeout_ms_;");
+            w.line("std::array<std::uint8_t, (tx_frame_len > rx_frame_len ? tx_frame_len : rx_frame_len) + 1> buf_{};");
+        });
+    }
+
+    fn transaction(&self, w: &mut CodeWriter, transaction: &Transaction) {
+        let nsu = &self.nsu;
+        let params = self.ir.transaction_params(transaction);
+        let args = params.iter().map(|p| format!("const {} &{}", Self::payload_type(Direction::Tx, p), ident(p))).collect::<Vec<_>>();
+        comment(w, &transaction.description);
+        w.block(format!("{} {}({}) {{", self.return_type(transaction), Self::method_name(transaction), args.join(", ")), "}", |w| {
+            let mut declared: Vec<&Name> = Vec::new();
+            for action in &transaction.actions {
+                match action {
+                    Action::Tx(payload) if params.contains(&payload) => w.line(format!("{nsu}_TRY(send({}));", ident(payload))),
+                    Action::Tx(payload) => w.line(format!("{nsu}_TRY(send({}{{}}));", Self::payload_type(Direction::Tx, payload))),
+                    Action::Rx(payload) => {
+                        let local = format!("{}_rx", payload.snake());
+                        let call = format!("receive<{}>()", Self::payload_type(Direction::Rx, payload));
+                        if declared.contains(&payload) {
+                            w.line(format!("{local} = {call};"));
+                        } else {
+                            declared.push(payload);
+                            w.line(format!("auto {local} = {call};"));
+                        }
+                        w.line(format!("if (!{local}) return {local}.status();"))
+                    }
+                    Action::Sleep { milliseconds } => w.line(format!("transport_.delay_ms({milliseconds});")),
+                    Action::Flush => w.line("flush();"),
+                };
+            }
+            let value = |ret: &Return| format!("{}_rx->{}", ret.payload.snake(), ret.path.iter().map(member).collect::<Vec<_>>().join("."));
+            match transaction.returns.as_slice() {
+                [] => {
+                    w.line("return Status::Ok;");
+                }
+                [ret] => {
+                    w.line(format!("return {};", value(ret)));
+                }
+                returns => {
+                    w.line(format!("{}Response result_;", type_name(&transaction.name)));
+                    for ret in returns {
+                        w.line(format!("result_.{} = {};", member(&ret.name), value(ret)));
+                    }
+                    w.line("return result_;");
+                }
+            }
+        });
+    }
+
+    /// Statements setting a value-initialised object's user facing fields to sample values
+    fn fill_sample(&self, w: &mut CodeWriter, target: &str, values: &Fields, fields: &[Field]) {
+        for f in fields.iter().filter(|f| f.is_user_facing()) {
+            if let Some(value) = values.get(f.name.raw()) {
+                self.assign(w, &format!("{target}.{}", member(&f.name)), value, &f.ty);
+            }
+        }
+    }
+
+    fn assign(&self, w: &mut CodeWriter, target: &str, value: &Value, ty: &Type) {
+        // `Vec`s are sized first; `std::array`s already are
+        let resize = |w: &mut CodeWriter, len: usize| {
+            if ty.length().is_some_and(|len| !matches!(len, Length::Fixed(_))) {
+                w.line(format!("{target}.resize({len});"));
+            }
+        };
+        match (value, ty) {
+            (Value::Struct(values), _) => {
+                self.fill_sample(w, target, values, self.ir.fields_of(ty).unwrap_or(&[]));
+            }
+            (Value::Array(items), Type::Array { item, .. }) => {
+                resize(w, items.len());
+                for (i, value) in items.iter().enumerate() {
+                    self.assign(w, &format!("{target}[{i}]"), value, &Type::Struct(item.clone()));
+                }
+            }
+            (Value::Bytes(b), _) => {
+                resize(w, b.len());
+                for (i, byte) in b.iter().enumerate() {
+                    w.line(format!("{target}[{i}] = 0x{byte:02x};"));
+                }
+            }
+            (Value::String(text), _) => {
+                w.line(format!("{target}.assign({text:?});"));
+            }
+            (Value::UInt(u), _) => {
+                w.line(format!("{target} = {u}u;"));
+            }
+            (Value::Float(f), Type::Float { bits: 32, .. }) => {
+                w.line(format!("{target} = {f:?}f;"));
+            }
+            (Value::Float(f), _) => {
+                w.line(format!("{target} = {f:?};"));
+            }
+            (value, _) => {
+                w.line(format!("{target} = {value};"));
+            }
+        }
+    }
+
+    fn harness(&self) -> String {
+        let (ns, ir) = (&self.ns, self.ir);
+        let mut w = CodeWriter::new("    ");
+        comment(&mut w, "Checks generated alongside the driver: every payload survives an encode/decode round trip,\nand every transaction runs against a fake transport. Build and run with `make test`");
+        w.line("#include <cstdio>");
+        w.blank();
+        w.line(format!("#include \"{ns}.hpp\""));
+        w.blank();
+        w.line(format!("using namespace {ns};"));
+        w.blank();
+        w.line(HARNESS_FAKES.trim());
+        let mut tests = Vec::new();
+        for payload in ir.all_payloads() {
+            let ty = Self::payload_type(payload.direction, &payload.name);
+            let name = format!("roundtrip_{}_{}", dir(payload.direction), payload.name.snake());
+            w.blank();
+            w.block(format!("static void {name}() {{"), "}", |w| {
+                w.line(format!("{ty} in{{}};"));
+                self.fill_sample(w, "in", &self.layout.sample_fields(&payload.fields, 0), &payload.fields);
+                w.line("std::array<std::uint8_t, max_payload_len + 1> buf{};");
+                w.line("std::size_t len = 0;");
+                w.line("CHECK(in.encode(buf.data(), buf.size(), len) == Status::Ok);");
+                w.line(format!("const Result<{ty}> out = {ty}::decode(buf.data(), len);"));
+                w.line("CHECK(out && *out == in);");
+                if self.framed() {
+                    w.line(format!("std::array<std::uint8_t, {}_frame_len + 1> bytes{{}};", dir(payload.direction)));
+                    w.line("CHECK(in.pack(bytes.data(), bytes.size(), len) == Status::Ok);");
+                    w.line(format!("const Result<{ty}> unpacked = {ty}::unpack(bytes.data(), len);"));
+                    w.line("CHECK(unpacked && *unpacked == in);");
+                }
+            });
+            tests.push(name);
+        }
+        for transaction in &ir.transactions {
+            let name = format!("transaction_{}", transaction.name.snake());
+            let params = self.ir.transaction_params(transaction);
+            let args = params.iter().map(|p| format!("{}_tx", p.snake())).collect::<Vec<_>>();
+            let sends = transaction.actions.iter().any(|a| matches!(a, Action::Tx(_)));
+            let call = format!("device.{}({})", Self::method_name(transaction), args.join(", "));
+            w.blank();
+            w.block(format!("static void {name}() {{"), "}", |w| {
+                w.line("fake.reset();");
+                for action in &transaction.actions {
+                    if let Action::Rx(payload) = action {
+                        let ty = Self::payload_type(Direction::Rx, payload);
+                        let fields = ir.get_payload(Direction::Rx, payload).map_or(&[][..], |p| &p.fields);
+                        let encode = if self.framed() { "pack" } else { "encode" };
+                        w.block("{", "}", |w| {
+                            w.line(format!("{ty} response{{}};"));
+                            self.fill_sample(w, "response", &self.layout.sample_fields(fields, 0), fields);
+                            w.line("std::array<std::uint8_t, rx_frame_len + 1> bytes{};");
+                            w.line("std::size_t len = 0;");
+                            w.line(format!("CHECK(response.{encode}(bytes.data(), bytes.size(), len) == Status::Ok);"));
+                            w.line("fake.queue(bytes.data(), len);");
+                        });
+                    }
+                }
+                for payload in &params {
+                    let fields = ir.get_payload(Direction::Tx, payload).map_or(&[][..], |p| &p.fields);
+                    w.line(format!("{} {}_tx{{}};", Self::payload_type(Direction::Tx, payload), payload.snake()));
+                    self.fill_sample(w, &format!("{}_tx", payload.snake()), &self.layout.sample_fields(fields, 0), fields);
+                }
+                w.line("Device<FakeTransport> device(fake, 10);");
+                match transaction.returns.as_slice() {
+                    [] => {
+                        w.line(format!("CHECK({call} == Status::Ok);"));
+                    }
+                    [ret] => {
+                        w.line(format!("{} expected{{}};", self.cpp_type(&ret.ty, self.layout.return_siblings(ret), false)));
+                        if let Some(value) = self.layout.sample_return(ret) {
+                            self.assign(w, "expected", &value, &ret.ty);
+                        }
+                        w.line(format!("const auto result = {call};"));
+                        w.line("CHECK(result && *result == expected);");
+                    }
+                    returns => {
+                        w.line(format!("{}Response expected{{}};", type_name(&transaction.name)));
+                        for ret in returns {
+                            if let Some(value) = self.layout.sample_return(ret) {
+                                self.assign(w, &format!("expected.{}", member(&ret.name)), &value, &ret.ty);
+                            }
+                        }
+                        w.line(format!("const auto result = {call};"));
+                        for ret in returns {
+                            w.line(format!("CHECK(result && result->{0} == expected.{0});", member(&ret.name)));
+                        }
+                    }
+                }
+                w.line("CHECK(fake.rx_pos == fake.rx_len);");
+                w.line(if sends { "CHECK(fake.tx_len > 0);" } else { "CHECK(fake.tx_len == 0);" });
+            });
+            tests.push(name);
+        }
+        w.blank();
+        w.block("int main() {", "}", |w| {
+            for test in &tests {
+                w.line(format!("{test}();"));
+            }
+            w.block("if (failures) {", "}", |w| {
+                w.line("std::printf(\"%d check(s) failed\\n\", failures);");
+                w.line("return 1;");
+            });
+            w.line(format!("std::printf(\"all {} tests passed\\n\");", tests.len()));
+            w.line("return 0;");
+        });
+        w.finish()
+    }
+}
+
+const INCLUDES: &str = r#"
+#include <array>
+#include <cstddef>
+#include <cstdint>
+#include <cstring>
+#include <initializer_list>
+#include <optional>
+#include <string_view>
+#include <type_traits>
+#include <utility>
+"#;
+
+const COMMON: &str = r#"
+enum class Status : std::int8_t {
+    Ok = 0,
+    // An output buffer is too small
+    Buffer = -1,
+    // The data ended early
+    Truncated = -2,
+    // A value doesn't fit in its field
+    Range = -3,
+    // More elements than a field has room for
+    Capacity = -4,
+    // Constant bytes weren't what the document says
+    Const = -5,
+    Crc = -6,
+    // A size field is inconsistent with the frame
+    Size = -7,
+    // Data was left over after decoding
+    Trailing = -8,
+    // The device answered with a different payload than the transaction expects
+    Unexpected = -9,
+    Timeout = -10,
+    // The transport reported a failure
+    Io = -11,
+    // The frame doesn't say how long its payload is
+    Length = -12,
+};
+
+inline constexpr const char *to_string(Status status) {
+    switch (status) {
+    case Status::Ok: return "ok";
+    case Status::Buffer: return "buffer too small";
+    case Status::Truncated: return "data ended early";
+    case Status::Range: return "value out of range";
+    case Status::Capacity: return "too many elements for the field";
+    case Status::Const: return "constant bytes don't match";
+    case Status::Crc: return "CRC mismatch";
+    case Status::Size: return "size field inconsistent with the frame";
+    case Status::Trailing: return "data left over";
+    case Status::Unexpected: return "unexpected payload";
+    case Status::Timeout: return "timed out";
+    case Status::Io: return "I/O error";
+    case Status::Length: return "unknown payload length";
+    }
+    return "unknown status";
+}
+
+// Either a T, or the Status saying why there isn't one
+template <typename T>
+class Result {
+public:
+    Result(T value) : value_(std::move(value)) {}
+    Result(Status status) : status_(status) {}
+
+    bool ok() const { return value_.has_value(); }
+    explicit operator bool() const { return ok(); }
+    // Status::Ok when there's a value
+    Status status() const { return status_; }
+    const std::optional<T> &value() const { return value_; }
+
+    T &operator*() { return *value_; }
+    const T &operator*() const { return *value_; }
+    T *operator->() { return &*value_; }
+    const T *operator->() const { return &*value_; }
+
+private:
+    std::optional<T> value_;
+    Status status_ = Status::Ok;
+};
+
+// Up to N items stored inline, for fields whose length varies
+template <typename T, std::size_t N>
+class Vec {
+public:
+    using value_type = T;
+
+    static constexpr std::size_t capacity() { return N; }
+    std::size_t size() const { return len_; }
+    bool empty() const { return len_ == 0; }
+
+    T *data() { return items_.data(); }
+    const T *data() const { return items_.data(); }
+    T *begin() { return items_.data(); }
+    T *end() { return items_.data() + len_; }
+    const T *begin() const { return items_.data(); }
+    const T *end() const { return items_.data() + len_; }
+    T &operator[](std::size_t i) { return items_[i]; }
+    const T &operator[](std::size_t i) const { return items_[i]; }
+    T &back() { return items_[len_ - 1]; }
+
+    // These return false, changing nothing, when the result wouldn't fit
+    bool push_back(const T &item) {
+        if (len_ == N) return false;
+        items_[len_++] = item;
+        return true;
+    }
+
+    bool resize(std::size_t len) {
+        if (len > N) return false;
+        len_ = len;
+        return true;
+    }
+
+    bool assign(const T *items, std::size_t len) {
+        if (len > N) return false;
+        for (std::size_t i = 0; i < len; i++) items_[i] = items[i];
+        len_ = len;
+        return true;
+    }
+
+    void clear() { len_ = 0; }
+
+    friend bool operator==(const Vec &a, const Vec &b) {
+        if (a.len_ != b.len_) return false;
+        for (std::size_t i = 0; i < a.len_; i++) {
+            if (!(a.items_[i] == b.items_[i])) return false;
+        }
+        return true;
+    }
+    friend bool operator!=(const Vec &a, const Vec &b) { return !(a == b); }
+
+private:
+    std::array<T, N> items_{};
+    std::size_t len_ = 0;
+};
+
+// UTF-8 text of up to N bytes, stored inline without a NUL terminator
+template <std::size_t N>
+class String : public Vec<char, N> {
+public:
+    using Vec<char, N>::assign;
+
+    bool assign(std::string_view text) { return assign(text.data(), text.size()); }
+    std::string_view view() const { return std::string_view(this->data(), this->size()); }
+};
+
+namespace detail {
+
+inline constexpr std::uint64_t mask(unsigned bits) {
+    return bits >= 64 ? ~std::uint64_t{0} : ((std::uint64_t{1} << bits) - 1);
+}
+
+// Reverses the byte order of the low bits of value, which are whole bytes
+inline constexpr std::uint64_t swap_bytes(std::uint64_t value, unsigned bits) {
+    std::uint64_t out = 0;
+    for (unsigned i = 0; i < bits / 8; i++) out = (out << 8) | ((value >> (8 * i)) & 0xFF);
+    return out;
+}
+
+inline std::uint8_t *bytes(std::uint8_t *data) { return data; }
+inline const std::uint8_t *bytes(const std::uint8_t *data) { return data; }
+inline std::uint8_t *bytes(char *text) { return reinterpret_cast<std::uint8_t *>(text); }
+inline const std::uint8_t *bytes(const char *text) { return reinterpret_cast<const std::uint8_t *>(text); }
+
+// Packs values most significant bit first, as in the rest of OpenPID
+class Writer {
+public:
+    Writer(std::uint8_t *buf, std::size_t len) : buf_(buf), len_bits_(len * 8) {}
+
+    std::size_t pos() const { return pos_; }
+    // Bytes written, counting a partly written last byte
+    std::size_t len() const { return (pos_ + 7) / 8; }
+    std::size_t remaining() const { return len_bits_ - pos_; }
+
+    Status put_bits(unsigned bits, std::uint64_t value) {
+        if (remaining() < bits) return Status::Buffer;
+        for (unsigned i = 0; i < bits; i++) {
+            const std::size_t at = pos_ + i;
+            const auto bit = static_cast<std::uint8_t>(0x80u >> (at % 8));
+            if ((value >> (bits - 1 - i)) & 1u) {
+                buf_[at / 8] |= bit;
+            } else {
+                buf_[at / 8] &= static_cast<std::uint8_t>(~bit);
+            }
+        }
+        pos_ += bits;
+        return Status::Ok;
+    }
+
+    Status put_uint(unsigned bits, bool little, std::uint64_t value) {
+        if (value & ~mask(bits)) return Status::Range;
+        return put_bits(bits, little ? swap_bytes(value, bits) : value);
+    }
+
+    Status put_int(unsigned bits, bool little, bool ones, std::int64_t value) {
+        if (bits == 0) return value == 0 ? Status::Ok : Status::Range;
+        const auto max = static_cast<std::int64_t>(mask(bits - 1));
+        const std::int64_t min = ones ? -max : -max - 1;
+        if (value < min || value > max) return Status::Range;
+        if (value < 0 && ones) return put_uint(bits, little, ~static_cast<std::uint64_t>(-value) & mask(bits));
+        return put_uint(bits, little, static_cast<std::uint64_t>(value) & mask(bits));
+    }
+
+    Status put_float(bool little, float value) {
+        std::uint32_t raw;
+        std::memcpy(&raw, &value, sizeof raw);
+        return put_uint(32, little, raw);
+    }
+
+    Status put_float(bool little, double value) {
+        std::uint64_t raw;
+        std::memcpy(&raw, &value, sizeof raw);
+        return put_uint(64, little, raw);
+    }
+
+    Status put_bytes(const std::uint8_t *data, std::size_t len) {
+        if (remaining() / 8 < len) return Status::Buffer;
+        if (pos_ % 8 == 0) {
+            if (len > 0) std::memcpy(buf_ + pos_ / 8, data, len);
+            pos_ += len * 8;
+            return Status::Ok;
+        }
+        for (std::size_t i = 0; i < len; i++) PFX_TRY(put_bits(8, data[i]));
+        return Status::Ok;
+    }
+
+    Status put_bytes(std::initializer_list<std::uint8_t> data) { return put_bytes(data.begin(), data.size()); }
+
+    // Writes len bytes of data, then NULs up to cap
+    Status put_padded(const std::uint8_t *data, std::size_t len, std::size_t cap) {
+        if (len > cap) return Status::Capacity;
+        PFX_TRY(put_bytes(data, len));
+        for (; len < cap; len++) PFX_TRY(put_bits(8, 0));
+        return Status::Ok;
+    }
+
+    // The last bits bits of data
+    Status put_const(std::initializer_list<std::uint8_t> data, unsigned bits) {
+        for (std::size_t i = data.size() * 8 - bits; i < data.size() * 8; i++) {
+            PFX_TRY(put_bits(1, (data.begin()[i / 8] >> (7 - i % 8)) & 1u));
+        }
+        return Status::Ok;
+    }
+
+private:
+    std::uint8_t *buf_;
+    std::size_t len_bits_;
+    std::size_t pos_ = 0;
+};
+
+class Reader {
+public:
+    Reader(const std::uint8_t *buf, std::size_t len) : buf_(buf), len_bits_(len * 8) {}
+
+    std::size_t pos() const { return pos_; }
+    std::size_t remaining() const { return len_bits_ - pos_; }
+    void skip(std::size_t bits) { pos_ += bits; }
+
+    Status get_bits(unsigned bits, std::uint64_t &value) {
+        if (remaining() < bits) return Status::Truncated;
+        std::uint64_t out = 0;
+        for (unsigned i = 0; i < bits; i++) {
+            const std::size_t at = pos_ + i;
+            out = (out << 1) | ((buf_[at / 8] >> (7 - at % 8)) & 1u);
+        }
+        pos_ += bits;
+        value = out;
+        return Status::Ok;
+    }
+
+    template <typename T>
+    Status get_uint(unsigned bits, bool little, T &value) {
+        std::uint64_t raw = 0;
+        PFX_TRY(get_bits(bits, raw));
+        value = static_cast<T>(little ? swap_bytes(raw, bits) : raw);
+        return Status::Ok;
+    }
+
+    template <typename T>
+    Status get_int(unsigned bits, bool little, bool ones, T &value) {
+        std::uint64_t raw = 0;
+        PFX_TRY(get_uint(bits, little, raw));
+        if (bits > 0 && (raw >> (bits - 1)) & 1u) {
+            value = static_cast<T>(ones ? -static_cast<std::int64_t>(~raw & mask(bits)) : static_cast<std::int64_t>(raw | ~mask(bits)));
+        } else {
+            value = static_cast<T>(raw);
+        }
+        return Status::Ok;
+    }
+
+    Status get_float(bool little, float &value) {
+        std::uint32_t raw = 0;
+        PFX_TRY(get_uint(32, little, raw));
+        std::memcpy(&value, &raw, sizeof raw);
+        return Status::Ok;
+    }
+
+    Status get_float(bool little, double &value) {
+        std::uint64_t raw = 0;
+        PFX_TRY(get_uint(64, little, raw));
+        std::memcpy(&value, &raw, sizeof raw);
+        return Status::Ok;
+    }
+
+    Status get_bytes(std::uint8_t *out, std::size_t len) {
+        if (remaining() / 8 < len) return Status::Truncated;
+        for (std::size_t i = 0; i < len; i++) PFX_TRY(get_uint(8, false, out[i]));
+        return Status::Ok;
+    }
+
+    // Reads len bytes into a Vec or String
+    template <typename V>
+    Status get_into(V &out, std::uint64_t len) {
+        if (len > V::capacity()) return Status::Capacity;
+        if (remaining() / 8 < len) return Status::Truncated;
+        out.resize(static_cast<std::size_t>(len));
+        return get_bytes(bytes(out.data()), out.size());
+    }
+
+    // Reads len bytes, dropping the NULs that pad them
+    template <typename V>
+    Status get_padded(V &out, std::size_t len) {
+        PFX_TRY(get_into(out, len));
+        while (!out.empty() && out[out.size() - 1] == 0) out.resize(out.size() - 1);
+        return Status::Ok;
+    }
+
+    Status expect_bytes(std::initializer_list<std::uint8_t> expected) {
+        for (const std::uint8_t byte : expected) {
+            std::uint64_t found = 0;
+            PFX_TRY(get_bits(8, found));
+            if (found != byte) return Status::Const;
+        }
+        return Status::Ok;
+    }
+
+    // Consumes seq if it comes next
+    bool take_seq(std::initializer_list<std::uint8_t> seq) {
+        Reader peek = *this;
+        for (const std::uint8_t byte : seq) {
+            std::uint64_t found = 0;
+            if (peek.get_bits(8, found) != Status::Ok || found != byte) return false;
+        }
+        *this = peek;
+        return true;
+    }
+
+    // Reads bytes up to and including seq into a Vec or String, keeping the ones before it
+    template <typename V>
+    Status get_until(V &out, std::initializer_list<std::uint8_t> seq) {
+        out.clear();
+        while (!take_seq(seq)) {
+            std::uint64_t byte = 0;
+            if (out.size() == V::capacity()) return Status::Capacity;
+            PFX_TRY(get_bits(8, byte));
+            out.push_back(static_cast<typename V::value_type>(byte));
+        }
+        return Status::Ok;
+    }
+
+    Status expect_const(std::initializer_list<std::uint8_t> data, unsigned bits) {
+        for (std::size_t i = data.size() * 8 - bits; i < data.size() * 8; i++) {
+            std::uint64_t bit = 0;
+            PFX_TRY(get_bits(1, bit));
+            if (bit != ((data.begin()[i / 8] >> (7 - i % 8)) & 1u)) return Status::Const;
+        }
+        return Status::Ok;
+    }
+
+private:
+    const std::uint8_t *buf_;
+    std::size_t len_bits_;
+    std::size_t pos_ = 0;
+};
+
+// CRC-32 (IEEE 802.3)
+inline constexpr std::uint64_t crc32(const std::uint8_t *data, std::size_t len) {
+    std::uint32_t crc = 0xFFFFFFFFu;
+    for (std::size_t i = 0; i < len; i++) {
+        crc ^= data[i];
+        for (unsigned bit = 0; bit < 8; bit++) crc = (crc & 1u) ? (crc >> 1) ^ 0xEDB88320u : crc >> 1;
+    }
+    return ~crc;
+}
+
+// CRC-16/XMODEM
+inline constexpr std::uint64_t crc16_xmodem(const std::uint8_t *data, std::size_t len) {
+    std::uint16_t crc = 0;
+    for (std::size_t i = 0; i < len; i++) {
+        crc = static_cast<std::uint16_t>(crc ^ (data[i] << 8));
+        for (unsigned bit = 0; bit < 8; bit++) {
+            crc = (crc & 0x8000u) ? static_cast<std::uint16_t>((crc << 1) ^ 0x1021u) : static_cast<std::uint16_t>(crc << 1);
+        }
+    }
+    return crc;
+}
+
+}  // namespace detail
+"#;
+
+const TRANSPORT: &str = r#"
+// Whether T can carry a Device's traffic. It needs:
+//   bool write(const std::uint8_t *data, std::size_t len), sending all of data and returning
+//     whether that worked
+//   std::optional<std::size_t> read(std::uint8_t *data, std::size_t len, std::uint32_t timeout_ms),
+//     reading up to len bytes and waiting at most timeout_ms for the first. It returns how many
+//     arrived, 0 on timeout, or std::nullopt on failure
+//   void delay_ms(std::uint32_t ms)
+template <typename T, typename = void>
+struct is_transport : std::false_type {};
+
+template <typename T>
+struct is_transport<T,
+    std::void_t<decltype(static_cast<bool>(std::declval<T &>().write(std::declval<const std::uint8_t *>(), std::size_t{}))),
+        decltype(std::optional<std::size_t>{std::declval<T &>().read(std::declval<std::uint8_t *>(), std::size_t{}, std::uint32_t{})}),
+        decltype(std::declval<T &>().delay_ms(std::uint32_t{}))>> : std::true_type {};
+"#;
+
+const DEVICE_COMMON: &str = r#"
+// timeout_ms is how long to wait for each byte of a response
+explicit Device(Transport &transport, std::uint32_t timeout_ms = 100) : transport_(transport), timeout_ms_(timeout_ms) {}
+
+Transport &transport() { return transport_; }
+
+// Drops anything received but not yet read
+void flush() {
+    for (;;) {
+        const std::optional<std::size_t> got = transport_.read(buf_.data(), buf_.size(), 0);
+        if (!got || *got == 0) return;
+    }
+}
+"#;
+
+const DEVICE_FRAMED: &str = r#"
+// Sends one payload, wrapped in its frame
+template <typename P>
+Status send(const P &payload) {
+    std::size_t len = 0;
+    PFX_TRY(payload.pack(buf_.data(), buf_.size(), len));
+    return transport_.write(buf_.data(), len) ? Status::Ok : Status::Io;
+}
+
+// Reads one frame, which must hold a P
+template <typename P>
+Result<P> receive() {
+    frame::Frame found;
+    PFX_TRY(receive_frame(P::fixed_len ? static_cast<long>(*P::fixed_len) : -1, found));
+    if (!P::matches(found.metadata.data())) return Status::Unexpected;
+    return P::decode(found.payload, found.payload_len);
+}
+"#;
+
+const DEVICE_UNFRAMED: &str = r#"
+template <typename P>
+Status send(const P &payload) {
+    std::size_t len = 0;
+    PFX_TRY(payload.encode(buf_.data(), buf_.size(), len));
+    return transport_.write(buf_.data(), len) ? Status::Ok : Status::Io;
+}
+
+// Reads one P. Without a frame format, every payload the device sends has a fixed size
+template <typename P>
+Result<P> receive() {
+    static_assert(P::fixed_len.has_value(), "payloads received without a frame format need a fixed size");
+    PFX_TRY(read_exact(buf_.data(), *P::fixed_len));
+    return P::decode(buf_.data(), *P::fixed_len);
+}
+"#;
+
+const DEVICE_PRIVATE: &str = r#"
+Status read_exact(std::uint8_t *out, std::size_t len) {
+    std::size_t n = 0;
+    while (n < len) {
+        const std::optional<std::size_t> got = transport_.read(out + n, len - n, timeout_ms_);
+        if (!got) return Status::Io;
+        if (*got == 0) return Status::Timeout;
+        n += *got;
+    }
+    return Status::Ok;
+}
+"#;
+
+const RECEIVE_FRAME: &str = r#"
+// Reads a byte at a time until a whole frame has arrived. payload_len is the payload's size in
+// bytes when it's fixed, or -1
+Status receive_frame(long payload_len, frame::Frame &found) {
+    std::size_t n = 0;
+    for (;;) {
+        if (n == buf_.size()) return Status::Buffer;
+        PFX_TRY(read_exact(&buf_[n], 1));
+        n++;
+        const Status status = frame::parse_rx(buf_.data(), n, payload_len, false, found);
+        if (status != Status::Truncated) return status;
+    }
+}
+"#;
+
+const HARNESS_FAKES: &str = r#"
+static int failures = 0;
+
+#define CHECK(expr) do { if (!(expr)) { std::printf("%s:%d: check failed: %s\n", __FILE__, __LINE__, #expr); failures++; } } while (0)
+
+// A fake device that replays queued responses and counts what it's sent
+struct FakeTransport {
+    std::array<std::uint8_t, 1 << 16> rx{};
+    std::size_t rx_len = 0, rx_pos = 0, tx_len = 0;
+
+    void reset() { rx_len = rx_pos = tx_len = 0; }
+
+    void queue(const std::uint8_t *data, std::size_t len) {
+        for (std::size_t i = 0; i < len; i++) rx[rx_len++] = data[i];
+    }
+
+    bool write(const std::uint8_t *, std::size_t len) {
+        tx_len += len;
+        return true;
+    }
+
+    std::optional<std::size_t> read(std::uint8_t *data, std::size_t len, std::uint32_t timeout_ms) {
+        // responses are all queued up front, so flushing mustn't drop them
+        if (timeout_ms == 0) return 0;
+        std::size_t n = 0;
+        for (; n < len && rx_pos < rx_len; n++) data[n] = rx[rx_pos++];
+        return n;
+    }
+
+    void delay_ms(std::uint32_t) {}
+};
+
+static FakeTransport fake;
+"#;
+
+const MAKEFILE: &str = "CXX ?= c++
+CXXFLAGS ?= -std=c++17 -Wall -Wextra -pedantic -O2
+
+test: test_pfx
+\t./test_pfx
+
+test_pfx: test_pfx.cpp pfx.hpp
+\t$(CXX) $(CXXFLAGS) -o $@ test_pfx.cpp
+
+clean:
+\trm -f test_pfx
+
+.PHONY: test clean
+";
@@ -0,0 +1,190 @@
+//! Packet layout diagrams, drawn the way datasheets draw registers: rows of 32 bits, most
+//! significant bit first, with a cell per field labelled with its name and width. Frames are
+//! drawn with the payload's fields in place of the `Payload` element, so a diagram shows every
+//! bit on the wire.
+//!
+//! Variable length fields have no width to draw, so they take a fixed [VARIABLE_BITS] columns
+//! with a dashed outline, and rows after one lose their byte offset
+
+use super::docs::{crc_name, hex, literal};
+use crate::ir::*;
+
+const ROW_BITS: u64 = 32;
+const VARIABLE_BITS: u64 = 16;
+const BIT_WIDTH: u64 = 20;
+const ROW_HEIGHT: u64 = 44;
+const LEFT: u64 = 44;
+const TOP: u64 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CellKind {
+    /// Part of the frame rather than the payload
+    Envelope,
+    Field,
+
+    /// Always the same bits, whether in the frame or the payload
+    Constant,
+}
+
+/// One field or frame element
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Cell {
+    pub name: String,
+    pub detail: String,
+
+    /// None when the size depends on the data
+    pub bits: Option<u64>,
+    pub kind: CellKind,
+}
+
+/// A frame's cells, with `payload`'s fields and metadata values filled in, or a single `payload`
+/// cell if drawing the frame format on its own
+pub(crate) fn frame_cells(ir: &Ir, framing: &Framing, direction: Direction, payload: Option<&Payload>) -> Vec<Cell> {
+    fn walk(ir: &Ir, elements: &[FrameElement], payload: Option<&Payload>, out: &mut Vec<Cell>) {
+        for element in elements {
+            let envelope = |name: &str, detail: String, bits: u64| Cell { name: name.to_owned(), detail, bits: Some(bits), kind: CellKind::Envelope };
+            match element {
+                FrameElement::SizeTotal { bits, .. } => out.push(envelope("frame size", bits_text(*bits as u64), *bits as u64)),
+                FrameElement::SizeOfPayload { bits, .. } => out.push(envelope("payload size", bits_text(*bits as u64), *bits as u64)),
+                FrameElement::SizeOfElements { bits, elements, .. } => {
+                    out.push(envelope("size", bits_text(*bits as u64), *bits as u64));
+                    walk(ir, elements, payload, out);
+                }
+                FrameElement::Payload => match payload {
+                    Some(payload) => out.extend(field_cells(ir, &payload.fields, "")),
+                    None => out.push(Cell { name: "payload".to_owned(), detail: "varies".to_owned(), bits: None, kind: CellKind::Field }),
+                },
+                FrameElement::Metadata { name, .. } => {
+                    let bits = element.envelope_bits();
+                    let detail = match payload.and_then(|p| p.metadata(name.raw())).and_then(|m| m.values.first()) {
+                        Some(value) => format!("{} = {}", bits_text(bits), literal(value)),
+                        None => bits_text(bits),
+                    };
+                    out.push(envelope(name.raw(), detail, bits));
+                }
+                FrameElement::Crc(crc) => out.push(envelope(crc_name(*crc), bits_text(element.envelope_bits()), element.envelope_bits())),
+                FrameElement::Const { data, bits, .. } => {
+                    let bits = *bits as u64;
+                    let value = if bits.is_multiple_of(8) { hex(&data[data.len() - bits as usize / 8..]) } else { bits_text(bits) };
+                    out.push(Cell { name: "const".to_owned(), detail: format!("{} = {value}", bits_text(bits)), bits: Some(bits), kind: CellKind::Constant });
+                }
+            }
+        }
+    }
+    let mut out = Vec::new();
+    walk(ir, framing.format(direction), payload, &mut out);
+    out
+}
+
+/// Cells for a list of fields, with struct fields drawn in place and named `outer.inner`
+pub(crate) fn field_cells(ir: &Ir, fields: &[Field], prefix: &str) -> Vec<Cell> {
+    let mut out = Vec::new();
+    for field in fields {
+        let name = format!("{prefix}{}", field.name.raw());
+        if let Type::Struct(inner) = &field.ty {
+            if let Some(st) = ir.get_struct(inner) {
+                out.extend(field_cells(ir, &st.fields, &format!("{name}.")));
+                continue;
+            }
+        }
+        let bits = field.size.fixed_bits();
+        if bits == Some(0) {
+            continue;
+        }
+        let (detail, kind) = match (&field.ty, bits) {
+            (Type::Const(data), Some(bits)) if bits.is_multiple_of(8) => (format!("{} = {}", bits_text(bits), hex(data)), CellKind::Constant),
+            (Type::Array { item, len: Length::Fixed(n) }, Some(bits)) => (format!("{n} × {}, {}", item.raw(), bits_text(bits)), CellKind::Field),
+            (_, Some(bits)) => (bits_text(bits), CellKind::Field),
+            (_, None) => (field.size.to_string(), CellKind::Field),
+        };
+        out.push(Cell { name, detail, bits, kind });
+    }
+    out
+}
+
+fn bits_text(bits: u64) -> String {
+    match bits {
+        1 => "1 bit".to_owned(),
+        _ => format!("{bits} bits"),
+    }
+}
+
+/// Draws cells as a standalone SVG image
+pub(crate) fn svg(title: &str, cells: &[Cell]) -> String {
+    let mut body = String::new();
+    let mut position = 0;
+    // rows starting after this bit have no real offset, because a variable length field came first
+    let mut known_until: Option<u64> = None;
+    for cell in cells {
+        let mut remaining = cell.bits.unwrap_or(VARIABLE_BITS);
+        if cell.bits.is_none() && known_until.is_none() {
+            known_until = Some(position);
+        }
+        let (fill, dash) = match (cell.kind, cell.bits) {
+            (CellKind::Envelope, _) => ("#eaeef2", ""),
+            (CellKind::Constant, _) => ("#fff8c5", ""),
+            (CellKind::Field, Some(_)) => ("#ddf4ff", ""),
+            (CellKind::Field, None) => ("#ddf4ff", " stroke-dasharray=\"5 3\""),
+        };
+        let mut first = true;
+        while remaining > 0 {
+            let (row, column) = (position / ROW_BITS, position % ROW_BITS);
+            let span = remaining.min(ROW_BITS - column);
+            let (x, y, width) = (LEFT + column * BIT_WIDTH, TOP + row * ROW_HEIGHT, span * BIT_WIDTH);
+            let tooltip = format!("{}: {}", cell.name, cell.detail);
+            body.push_str(&format!("<g><title>{}</title>", escape(&tooltip)));
+            body.push_str(&format!("<rect x=\"{x}\" y=\"{y}\" width=\"{width}\" height=\"{ROW_HEIGHT}\" fill=\"{fill}\" stroke=\"#57606a\"{dash}/>"));
+            let center = x + width / 2;
+            let name = if first { cell.name.clone() } else { format!("{} (cont.)", cell.name) };
+            body.push_str(&format!("<text x=\"{center}\" y=\"{}\" font-size=\"12\">{}</text>", y + 19, escape(&fit(&name, width, 7))));
+            if first {
+                body.push_str(&format!("<text x=\"{center}\" y=\"{}\" font-size=\"10\" fill=\"#57606a\">{}</text>", y + 34, escape(&fit(&cell.detail, width, 6))));
+            }
+            body.push_str("</g>\n");
+            position += span;
+            remaining -= span;
+            first = false;
+        }
+    }
+    let rows = position.div_ceil(ROW_BITS).max(1);
+
+    let width = LEFT + ROW_BITS * BIT_WIDTH + 4;
+    let height = TOP + rows * ROW_HEIGHT + 4;
+    let mut out = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\" font-family=\"sans-serif\" text-anchor=\"middle\" role=\"img\" aria-label=\"{}\">\n<title>{}</title>\n",
+        escape(title),
+        escape(title)
+    );
+    out.push_str(&format!("<rect width=\"{width}\" height=\"{height}\" fill=\"#ffffff\"/>\n"));
+    for byte in 0..ROW_BITS / 8 {
+        out.push_str(&format!("<text x=\"{}\" y=\"12\" font-size=\"11\">+{byte}</text>", LEFT + (byte * 8 + 4) * BIT_WIDTH));
+    }
+    for bit in 0..ROW_BITS {
+        out.push_str(&format!("<text x=\"{}\" y=\"26\" font-size=\"9\" fill=\"#57606a\">{}</text>", LEFT + bit * BIT_WIDTH + BIT_WIDTH / 2, 7 - bit % 8));
+    }
+    out.push('\n');
+    for row in 0..rows {
+        if known_until.is_none_or(|until| row * ROW_BITS <= until) {
+            out.push_str(&format!("<text x=\"{}\" y=\"{}\" font-size=\"11\" text-anchor=\"end\">{}</text>\n", LEFT - 6, TOP + row * ROW_HEIGHT + 26, row * ROW_BITS / 8));
+        }
+    }
+    out.push_str(&body);
+    out.push_str("</svg>\n");
+    out
+}
+
+/// Shortens text to fit `width` pixels, at roughly `char_width` pixels a character
+fn fit(text: &str, width: u64, char_width: u64) -> String {
+    let room = (width.saturating_sub(4) / char_width) as usize;
+    if text.chars().count() <= room {
+        text.to_owned()
+    } else if room >= 2 {
+        text.chars().take(room - 1).chain(std::iter::once('…')).collect()
+    } else {
+        String::new()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,623 @@
+//! The document model shared by the `markdown` and `html` backends. [Page::build] lays out the
+//! whole reference once, as headings, paragraphs, tables and lists of [Inline] text, and each
+//! backend only decides how to render them. Anchors are stable, so other documents can link in:
+//! `tx-<payload>`, `rx-<payload>`, `struct-<name>`, `transaction-<name>` and `frame-tx`/`frame-rx`,
+//! with names in kebab case
+
+use super::{diagram, sequence};
+use crate::ir::*;
+
+/// A run of text within a paragraph, table cell or list item
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Inline {
+    Text(String),
+    Code(String),
+
+    /// A link to an anchor in the same document
+    Link { text: String, anchor: String },
+}
+
+pub(crate) type Rich = Vec<Inline>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Block {
+    Heading { level: u8, anchor: Option<String>, text: String },
+    Paragraph(Rich),
+    Table { columns: Vec<String>, rows: Vec<Vec<Rich>> },
+    List { ordered: bool, items: Vec<Rich> },
+
+    /// A packet layout drawing. `name` is unique within the page, for renderers that write
+    /// diagrams to their own files
+    Diagram { name: String, caption: String, svg: String },
+
+    /// A transaction's sequence diagram, as Mermaid source
+    Sequence { mermaid: String },
+}
+
+/// A device's reference documentation, ready to render
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Page {
+    pub title: String,
+    pub blocks: Vec<Block>,
+}
+
+impl Page {
+    /// Lays out the documentation, with a layout diagram per frame format, payload and struct and a
+    /// sequence diagram per transaction if `diagrams`
+    pub fn build(ir: &Ir, diagrams: bool) -> Page {
+        let units = ir.all_payloads().flat_map(|p| &p.fields).chain(ir.structs.iter().flat_map(|s| &s.fields)).any(|f| f.units.is_some());
+        let mut page = Builder { ir, blocks: Vec::new(), units, diagrams };
+        page.intro();
+        page.contents();
+        if let Some(framing) = &ir.framing {
+            page.heading(2, Some("frames"), "Frame formats");
+            page.paragraph(vec![text("Every payload is sent inside a frame. Offsets from the end of a frame are written "), code("end - n"), text(".")]);
+            for direction in [Direction::Tx, Direction::Rx] {
+                page.frame(framing, direction);
+            }
+        }
+        for direction in [Direction::Tx, Direction::Rx] {
+            let (anchor, title, intro) = match direction {
+                Direction::Tx => ("tx", "TX payloads", "Payloads sent to the device."),
+                Direction::Rx => ("rx", "RX payloads", "Payloads received from the device."),
+            };
+            page.heading(2, Some(anchor), title);
+            page.paragraph(vec![text(intro)]);
+            if ir.payloads(direction).is_empty() {
+                page.paragraph(vec![text("None.")]);
+            }
+            for payload in ir.payloads(direction) {
+                page.payload(payload);
+            }
+        }
+        if !ir.structs.is_empty() {
+            page.heading(2, Some("structs"), "Structs");
+            page.paragraph(vec![text("Groups of fields used by payloads. Offsets are from the start of the struct.")]);
+            for st in &ir.structs {
+                page.structure(st);
+            }
+        }
+        if !ir.transactions.is_empty() {
+            page.heading(2, Some("transactions"), "Transactions");
+            page.paragraph(vec![text("Exchanges with the device, as the steps a driver takes in order.")]);
+            for transaction in &ir.transactions {
+                page.transaction(transaction);
+            }
+        }
+        Page { title: ir.device.name.raw().to_owned(), blocks: page.blocks }
+    }
+
+    /// Level 2 and 3 headings with anchors, for a table of contents
+    pub fn outline(&self) -> impl Iterator<Item = (u8, &str, &str)> {
+        self.blocks.iter().filter_map(|b| match b {
+            Block::Heading { level: level @ (2 | 3), anchor: Some(anchor), text } => Some((*level, anchor.as_str(), text.as_str())),
+            _ => None,
+        })
+    }
+}
+
+pub(crate) fn text(text: impl Into<String>) -> Inline {
+    Inline::Text(text.into())
+}
+
+pub(crate) fn code(text: impl Into<String>) -> Inline {
+    Inline::Code(text.into())
+}
+
+fn columns(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+fn link(text: impl Into<String>, anchor: impl Into<String>) -> Inline {
+    Inline::Link { text: text.into(), anchor: anchor.into() }
+}
+
+pub(crate) fn payload_anchor(direction: Direction, name: &Name) -> String {
+    match direction {
+        Direction::Tx => format!("tx-{}", name.kebab()),
+        Direction::Rx => format!("rx-{}", name.kebab()),
+    }
+}
+
+pub(crate) fn struct_anchor(name: &Name) -> String {
+    format!("struct-{}", name.kebab())
+}
+
+pub(crate) fn transaction_anchor(name: &Name) -> String {
+    format!("transaction-{}", name.kebab())
+}
+
+/// Bytes as `0xAA 0x01`
+pub(crate) fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("0x{b:02X}")).collect::<Vec<_>>().join(" ")
+}
+
+pub(crate) fn crc_name(crc: Crc) -> &'static str {
+    match crc {
+        Crc::Crc32 => "CRC-32",
+        Crc::Crc16XModem => "CRC-16/XMODEM",
+    }
+}
+
+/// A metadata value as written in the document, in hex for unsigned integers
+pub(crate) fn literal(value: &LiteralValue) -> String {
+    match value {
+        LiteralValue::Int(i) if *i >= 0 => format!("0x{i:02X}"),
+        LiteralValue::Int(i) => i.to_string(),
+        LiteralValue::String(s) => format!("{s:?}"),
+    }
+}
+
+/// An integer's short name, such as `u16`, or `i4` for a signed one
+pub(crate) fn int_name(bits: u32, signing: Signing) -> String {
+    match signing {
+        Signing::Unsigned => format!("u{bits}"),
+        _ => format!("i{bits}"),
+    }
+}
+
+/// The smallest and largest values an integer can hold
+pub(crate) fn int_range(bits: u32, signing: Signing) -> (i128, i128) {
+    let max = (1i128 << bits) - 1;
+    match signing {
+        Signing::Unsigned => (0, max),
+        Signing::TwosComplement => (-(max / 2) - 1, max / 2),
+        Signing::OnesComplement => (-(max / 2), max / 2),
+    }
+}
+
+struct Builder<'a> {
+    ir: &'a Ir,
+    blocks: Vec<Block>,
+
+    /// Whether any field has units, so field tables need the column
+    units: bool,
+    diagrams: bool,
+}
+
+impl<'a> Builder<'a> {
+    fn heading(&mut self, level: u8, anchor: Option<&str>, title: &str) {
+        self.blocks.push(Block::Heading { level, anchor: anchor.map(str::to_owned), text: title.to_owned() });
+    }
+
+    fn paragraph(&mut self, rich: Rich) {
+        self.blocks.push(Block::Paragraph(rich));
+    }
+
+    fn diagram(&mut self, name: String, caption: String, cells: impl FnOnce() -> Vec<diagram::Cell>) {
+        if self.diagrams {
+            let svg = diagram::svg(&caption, &cells());
+            self.blocks.push(Block::Diagram { name, caption, svg });
+        }
+    }
+
+    fn intro(&mut self) {
+        let ir = self.ir;
+        self.heading(1, None, ir.device.name.raw());
+        if !ir.device.description.is_empty() {
+            self.paragraph(vec![text(&ir.device.description)]);
+        }
+        let mut versions = Vec::new();
+        if let Some(version) = &ir.doc_version {
+            versions.push(text("Document version "));
+            versions.push(code(version));
+            versions.push(text(". "));
+        }
+        if let Some(version) = &ir.openpid_version {
+            versions.push(text("Written for OpenPID "));
+            versions.push(code(version));
+            versions.push(text("."));
+        }
+        if !versions.is_empty() {
+            self.paragraph(versions);
+        }
+        self.paragraph(vec![text(
+            "Offsets are in bytes from the start of the payload. A field that doesn't start on a byte boundary has its offset written as byte.bit, counting bits from the most \
+             significant. Fields after a variable length field have no fixed offset.",
+        )]);
+    }
+
+    fn contents(&mut self) {
+        let ir = self.ir;
+        let mut items = Vec::new();
+        if ir.framing.is_some() {
+            items.push(vec![link("Frame formats", "frames")]);
+        }
+        for (direction, title) in [(Direction::Tx, "TX payloads"), (Direction::Rx, "RX payloads")] {
+            let mut item = vec![link(title, direction.to_string().to_lowercase())];
+            for (i, payload) in ir.payloads(direction).iter().enumerate() {
+                item.push(text(if i == 0 { ": " } else { ", " }));
+                item.push(link(payload.name.raw(), payload_anchor(direction, &payload.name)));
+            }
+            items.push(item);
+        }
+        if !ir.structs.is_empty() {
+            let mut item = vec![link("Structs", "structs")];
+            for (i, st) in ir.structs.iter().enumerate() {
+                item.push(text(if i == 0 { ": " } else { ", " }));
+                item.push(link(st.name.raw(), struct_anchor(&st.name)));
+            }
+            items.push(item);
+        }
+        if !ir.transactions.is_empty() {
+            let mut item = vec![link("Transactions", "transactions")];
+            for (i, transaction) in ir.transactions.iter().enumerate() {
+                item.push(text(if i == 0 { ": " } else { ", " }));
+                item.push(link(transaction.name.raw(), transaction_anchor(&transaction.name)));
+            }
+            items.push(item);
+        }
+        self.blocks.push(Block::List { ordered: false, items });
+    }
+
+    fn frame(&mut self, framing: &Framing, direction: Direction) {
+        let (anchor, title) = match direction {
+            Direction::Tx => ("frame-tx", "TX frames"),
+            Direction::Rx => ("frame-rx", "RX frames"),
+        };
+        self.heading(3, Some(anchor), title);
+
+        let mut flat = Vec::new();
+        flatten(framing.format(direction), &mut flat);
+        let mut offsets = vec![None; flat.len()];
+        let mut at = Some(0u64);
+        for (i, element) in flat.iter().enumerate() {
+            offsets[i] = at.map(byte_offset);
+            at = at.filter(|_| !matches!(element, FrameElement::Payload)).map(|at| at + own_bits(element));
+        }
+        if let Some(last_payload) = flat.iter().rposition(|e| matches!(e, FrameElement::Payload)) {
+            let mut from_end = 0;
+            for i in (last_payload + 1..flat.len()).rev() {
+                from_end += own_bits(flat[i]);
+                offsets[i] = Some(format!("end - {}", byte_offset(from_end)));
+            }
+        }
+
+        let keys: Vec<&Name> = framing.metadata(direction).into_iter().map(|(name, _)| name).collect();
+        let mut rows = Vec::new();
+        for (element, offset) in flat.iter().zip(offsets) {
+            let offset = vec![text(offset.unwrap_or_else(|| "—".to_owned()))];
+            let size = match element {
+                FrameElement::Payload => vec![text("varies")],
+                _ => vec![text(Size::Fixed(own_bits(element)).to_string())],
+            };
+            let (name, description): (Rich, Rich) = match element {
+                FrameElement::SizeTotal { unit, .. } => (vec![text("Frame size")], vec![text(format!("Size of the whole frame, in {}", units(*unit)))]),
+                FrameElement::SizeOfPayload { unit, .. } => (vec![text("Payload size")], vec![text(format!("Size of the payload, in {}", units(*unit)))]),
+                FrameElement::SizeOfElements { unit, elements, .. } => {
+                    let mut description = vec![text(format!("Size of the {} following element(s), in {}: ", count_nested(elements), units(*unit)))];
+                    for (i, name) in nested_names(elements).into_iter().enumerate() {
+                        if i > 0 {
+                            description.push(text(", "));
+                        }
+                        description.push(name);
+                    }
+                    (vec![text("Size")], description)
+                }
+                FrameElement::Payload => {
+                    let mut description = vec![text("One of the "), link(format!("{direction} payloads"), direction.to_string().to_lowercase())];
+                    if !keys.is_empty() {
+                        description.push(text(", identified by "));
+                        for (i, key) in keys.iter().enumerate() {
+                            if i > 0 {
+                                description.push(text(" and "));
+                            }
+                            description.push(code(key.raw()));
+                        }
+                    }
+                    (vec![text("Payload")], description)
+                }
+                FrameElement::Metadata { name, ty, description } => {
+                    let mut rich = vec![text(format!("{}. ", ty.summary()))];
+                    if let Some(description) = description {
+                        rich.push(text(format!("{description}. ")));
+                    }
+                    rich.push(text("Values are listed with each payload"));
+                    (vec![code(name.raw())], rich)
+                }
+                FrameElement::Crc(crc) => (vec![text(crc_name(*crc))], vec![text("Over every byte before it, big-endian")]),
+                FrameElement::Const { data, bits, description } => {
+                    let mut rich = vec![text("Always "), code(const_text(data, *bits))];
+                    if let Some(description) = description {
+                        rich.push(text(format!(". {description}")));
+                    }
+                    (vec![text("Constant")], rich)
+                }
+            };
+            rows.push(vec![offset, size, name, description]);
+        }
+        self.blocks.push(Block::Table { columns: columns(&["Offset", "Size", "Element", "Description"]), rows });
+        let ir = self.ir;
+        self.diagram(anchor.to_owned(), format!("{direction} frame layout"), || diagram::frame_cells(ir, framing, direction, None));
+
+        if !keys.is_empty() && !self.ir.payloads(direction).is_empty() {
+            let mut columns = vec!["Payload".to_owned()];
+            columns.extend(keys.iter().map(|key| key.raw().to_owned()));
+            let mut rows = vec![];
+            for payload in self.ir.payloads(direction) {
+                let mut row = vec![vec![link(payload.name.raw(), payload_anchor(direction, &payload.name))]];
+                for key in &keys {
+                    row.push(payload.metadata(key.raw()).map(metadata_values).unwrap_or_default());
+                }
+                rows.push(row);
+            }
+            let intro = vec![text(match direction {
+                Direction::Tx => "Metadata values for each payload:",
+                Direction::Rx => "Metadata values for each payload. A payload with several values is recognised by any of them:",
+            })];
+            self.paragraph(intro);
+            self.blocks.push(Block::Table { columns, rows });
+        }
+    }
+
+    fn payload(&mut self, payload: &Payload) {
+        let anchor = payload_anchor(payload.direction, &payload.name);
+        self.heading(3, Some(&anchor), payload.name.raw());
+        if !payload.description.is_empty() {
+            self.paragraph(vec![text(&payload.description)]);
+        }
+        let mut facts = vec![vec![text(format!("Size: {}", payload.size))]];
+        for metadata in &payload.metadata {
+            let mut item = vec![code(metadata.name.raw()), text(": ")];
+            item.extend(metadata_values(metadata));
+            if metadata.values.len() > 1 {
+                item.push(text(match payload.direction {
+                    Direction::Tx => ". The first is sent",
+                    Direction::Rx => ". Any is accepted",
+                }));
+            }
+            facts.push(item);
+        }
+        let users: Vec<&Transaction> = self
+            .ir
+            .transactions
+            .iter()
+            .filter(|t| {
+                t.actions.iter().any(|a| match a {
+                    Action::Tx(name) => payload.direction == Direction::Tx && *name == payload.name,
+                    Action::Rx(name) => payload.direction == Direction::Rx && *name == payload.name,
+                    _ => false,
+                })
+            })
+            .collect();
+        if !users.is_empty() {
+            let mut item = vec![text("Used by ")];
+            for (i, transaction) in users.iter().enumerate() {
+                if i > 0 {
+                    item.push(text(", "));
+                }
+                item.push(link(transaction.name.raw(), transaction_anchor(&transaction.name)));
+            }
+            facts.push(item);
+        }
+        self.blocks.push(Block::List { ordered: false, items: facts });
+        let ir = self.ir;
+        let caption = match ir.framing {
+            Some(_) => format!("{} frame layout", payload.name.raw()),
+            None => format!("{} layout", payload.name.raw()),
+        };
+        self.diagram(anchor, caption, || match &ir.framing {
+            Some(framing) => diagram::frame_cells(ir, framing, payload.direction, Some(payload)),
+            None => diagram::field_cells(ir, &payload.fields, ""),
+        });
+        self.fields(&payload.fields);
+    }
+
+    fn structure(&mut self, st: &Struct) {
+        self.heading(3, Some(&struct_anchor(&st.name)), st.name.raw());
+        if let Some(description) = &st.description {
+            self.paragraph(vec![text(description)]);
+        }
+        let mut facts = vec![vec![text(format!("Size: {}", st.size))]];
+        let users: Vec<Rich> = self
+            .ir
+            .all_payloads()
+            .filter(|p| p.fields.iter().any(|f| f.ty.struct_name() == Some(&st.name)))
+            .map(|p| vec![link(format!("{} {}", p.direction, p.name.raw()), payload_anchor(p.direction, &p.name))])
+            .chain(self.ir.structs.iter().filter(|s| s.fields.iter().any(|f| f.ty.struct_name() == Some(&st.name))).map(|s| vec![link(s.name.raw(), struct_anchor(&s.name))]))
+            .collect();
+        if !users.is_empty() {
+            let mut item = vec![text("Used by ")];
+            for (i, user) in users.into_iter().enumerate() {
+                if i > 0 {
+                    item.push(text(", "));
+                }
+                item.extend(user);
+            }
+            facts.push(item);
+        }
+        self.blocks.push(Block::List { ordered: false, items: facts });
+        let ir = self.ir;
+        self.diagram(struct_anchor(&st.name), format!("{} layout", st.name.raw()), || diagram::field_cells(ir, &st.fields, ""));
+        self.fields(&st.fields);
+    }
+
+    fn fields(&mut self, fields: &[Field]) {
+        if fields.is_empty() {
+            self.paragraph(vec![text("No fields.")]);
+            return;
+        }
+        let mut columns = columns(&["Offset", "Size", "Field", "Type", "Endianness", "Values"]);
+        if self.units {
+            columns.push("Units".to_owned());
+        }
+        columns.push("Description".to_owned());
+        let mut rows = Vec::new();
+        for field in fields {
+            let mut row = vec![
+                vec![text(field.offset_bits.map(byte_offset).unwrap_or_else(|| "—".to_owned()))],
+                vec![text(field.size.to_string())],
+                vec![code(field.name.raw())],
+                self.type_text(&field.ty),
+                vec![text(match field.ty {
+                    Type::Int { bits, endianness, .. } | Type::Float { bits, endianness } if bits > 8 => match endianness {
+                        Endianness::BigEndian => "big",
+                        Endianness::LittleEndian => "little",
+                    },
+                    _ => "",
+                })],
+                values(field),
+            ];
+            if self.units {
+                row.push(field.units.iter().map(text).collect());
+            }
+            row.push(field.description.iter().map(text).collect());
+            rows.push(row);
+        }
+        self.blocks.push(Block::Table { columns, rows });
+    }
+
+    /// A field's type, with its length if it's variable and a link to any struct it uses
+    fn type_text(&self, ty: &Type) -> Rich {
+        let struct_link = |name: &Name| link(name.raw(), struct_anchor(name));
+        let mut rich = match ty {
+            Type::Struct(name) => return vec![struct_link(name)],
+            Type::Array { item, .. } => vec![text("array of "), struct_link(item)],
+            _ => vec![text(ty.summary())],
+        };
+        match ty.length() {
+            Some(Length::Fixed(n)) if matches!(ty, Type::Array { .. }) => rich.push(text(format!(", {n} items"))),
+            Some(Length::Fixed(_)) => (),
+            Some(Length::Capacity(_)) => rich.push(text(", NUL padded")),
+            Some(Length::CountField(count)) => {
+                rich.push(text(", length in "));
+                rich.push(code(count.raw()));
+            }
+            Some(Length::Sequence(sequence)) => {
+                rich.push(text(", ends with "));
+                rich.push(code(hex(sequence)));
+            }
+            Some(Length::Remainder) => rich.push(text(", to the end of the payload")),
+            None => (),
+        }
+        rich
+    }
+
+    fn transaction(&mut self, transaction: &Transaction) {
+        self.heading(3, Some(&transaction_anchor(&transaction.name)), transaction.name.raw());
+        if !transaction.description.is_empty() {
+            self.paragraph(vec![text(&transaction.description)]);
+        }
+        let steps = transaction
+            .actions
+            .iter()
+            .map(|action| match action {
+                Action::Tx(name) => vec![text("Send "), link(name.raw(), payload_anchor(Direction::Tx, name))],
+                Action::Rx(name) => vec![text("Receive "), link(name.raw(), payload_anchor(Direction::Rx, name))],
+                Action::Sleep { milliseconds } => vec![text(format!("Wait {milliseconds} ms"))],
+                Action::Flush => vec![text("Discard any unread input")],
+            })
+            .collect();
+        self.blocks.push(Block::List { ordered: true, items: steps });
+        if self.diagrams {
+            self.blocks.push(Block::Sequence { mermaid: sequence::mermaid(self.ir, transaction) });
+        }
+        if transaction.returns.is_empty() {
+            self.paragraph(vec![text("Returns nothing.")]);
+            return;
+        }
+        self.paragraph(vec![text("Returns:")]);
+        let rows = transaction
+            .returns
+            .iter()
+            .map(|ret| {
+                let path = std::iter::once(ret.payload.raw()).chain(ret.path.iter().map(Name::raw)).collect::<Vec<_>>().join(".");
+                vec![vec![code(ret.name.raw())], self.type_text(&ret.ty), vec![link(path, payload_anchor(Direction::Rx, &ret.payload))]]
+            })
+            .collect();
+        self.blocks.push(Block::Table { columns: columns(&["Name", "Type", "From"]), rows });
+    }
+}
+
+/// A frame format in wire order, without `SizeOfElements` nesting
+fn flatten<'a>(elements: &'a [FrameElement], out: &mut Vec<&'a FrameElement>) {
+    for element in elements {
+        out.push(element);
+        if let FrameElement::SizeOfElements { elements, .. } = element {
+            flatten(elements, out);
+        }
+    }
+}
+
+/// Bits an element takes up by itself, not counting elements nested in it
+fn own_bits(element: &FrameElement) -> u64 {
+    match element {
+        FrameElement::SizeOfElements { bits, .. } => *bits as u64,
+        _ => element.envelope_bits(),
+    }
+}
+
+fn count_nested(elements: &[FrameElement]) -> usize {
+    let mut flat = Vec::new();
+    flatten(elements, &mut flat);
+    flat.len()
+}
+
+fn nested_names(elements: &[FrameElement]) -> Vec<Inline> {
+    let mut flat = Vec::new();
+    flatten(elements, &mut flat);
+    flat.into_iter()
+        .map(|element| match element {
+            FrameElement::SizeTotal { .. } => text("frame size"),
+            FrameElement::SizeOfPayload { .. } => text("payload size"),
+            FrameElement::SizeOfElements { .. } => text("size"),
+            FrameElement::Payload => text("payload"),
+            FrameElement::Metadata { name, .. } => code(name.raw()),
+            FrameElement::Crc(crc) => text(crc_name(*crc)),
+            FrameElement::Const { .. } => text("constant"),
+        })
+        .collect()
+}
+
+fn units(unit: BitsOrBytes) -> &'static str {
+    match unit {
+        BitsOrBytes::Bits => "bits",
+        BitsOrBytes::Bytes => "bytes",
+    }
+}
+
+/// The last `bits` bits of `data`, as hex bytes or a binary number when it isn't whole bytes
+fn const_text(data: &[u8], bits: u32) -> String {
+    if bits.is_multiple_of(8) {
+        hex(&data[data.len() - bits as usize / 8..])
+    } else {
+        let value = data.iter().fold(0u128, |acc, b| (acc << 8) | *b as u128) & ((1u128 << bits) - 1);
+        format!("0b{value:0width$b}", width = bits as usize)
+    }
+}
+
+fn metadata_values(metadata: &Metadata) -> Rich {
+    let mut rich = Vec::new();
+    for (i, value) in metadata.values.iter().enumerate() {
+        if i > 0 {
+            rich.push(text(", "));
+        }
+        rich.push(code(literal(value)));
+    }
+    rich
+}
+
+/// What a field can hold: an integer's range, a constant's bytes, or which field a count is for
+fn values(field: &Field) -> Rich {
+    let mut rich = Vec::new();
+    if let Some(counted) = &field.count_of {
+        rich.push(text("Length of "));
+        rich.push(code(counted.raw()));
+        rich.push(text(", "));
+    }
+    match &field.ty {
+        Type::Int { bits, signing, .. } => {
+            let (min, max) = int_range(*bits, *signing);
+            rich.push(text(format!("{min} to {max}")));
+        }
+        Type::Const(data) => {
+            rich.push(text("Always "));
+            rich.push(code(const_text(data, field.size.fixed_bits().unwrap_or(data.len() as u64 * 8) as u32)));
+        }
+        Type::Bytes(Length::Fixed(n) | Length::Capacity(n)) => rich.push(text(format!("{n} bytes"))),
+        Type::String(Length::Fixed(n)) => rich.push(text(format!("{n} bytes"))),
+        Type::String(Length::Capacity(n)) => rich.push(text(format!("Up to {n} bytes"))),
+        _ => (),
+    }
+    rich
+}
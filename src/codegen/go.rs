@@ -0,0 +1,1678 @@
+//! Generates a Go package for gateway services and host tools: a struct per struct and payload
+//! with `Encode`/`Decode` methods, a bit packer over `encoding/binary`, and a `Device` with a method
+//! per transaction over any `io.ReadWriter`.
+//!
+//! Every zero value encodes: fixed length bytes and arrays are Go arrays, and fixed length strings
+//! are sent NUL padded. Like the `python` backend, frame formats are emitted as data and
+//! interpreted by the package's `wire.go`, which follows `openpid_runtime::frame`.
+
+use super::layout::flat_formats;
+use super::{BackendInfo, BackendOptions, CodeWriter, Codegen, CodegenError, Layout, OptionInfo, OutputSink};
+use crate::ir::*;
+use crate::value::{Fields, Value};
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "go",
+    description: "Go package using encoding/binary, with a device over any io.ReadWriter",
+    options: &[
+        OptionInfo { name: "package", description: "Name of the Go package", default: Some("the device's name, lower case") },
+        OptionInfo { name: "module", description: "Module path for go.mod", default: Some("the package name") },
+        OptionInfo { name: "tests", description: "Generate table tests", default: Some("true") },
+    ],
+    create: |options| Ok(Box::new(GoBackend::new(options)?)),
+};
+
+pub struct GoBackend {
+    package: Option<String>,
+    module: Option<String>,
+    tests: bool,
+}
+
+impl GoBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        let package = options.get("package").map(str::to_owned);
+        if let Some(package) = &package {
+            if !package.starts_with(|c: char| c.is_ascii_lowercase()) || !package.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()) {
+                return Err(CodegenError::BadOption { option: "package".to_owned(), value: package.clone(), expected: "a lower case Go package name" });
+            }
+        }
+        Ok(Self { package, module: options.get("module").map(str::to_owned), tests: options.get_bool("tests", true)? })
+    }
+}
+
+impl Codegen for GoBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let package = self.package.clone().unwrap_or_else(|| {
+            let name = ir.device.name.snake().replace('_', "");
+            if name.starts_with(|c: char| c.is_ascii_lowercase()) {
+                name
+            } else {
+                format!("device{name}")
+            }
+        });
+        let module = self.module.as_deref().unwrap_or(&package);
+        let gen = Gen { ir, package: &package };
+        let formats = flat_formats(ir, "go")?;
+
+        out.write_text("go.mod", &format!("module {module}\n\ngo 1.21\n"))?;
+        out.write_text("wire.go", &format!("{}{}", gen.header(), tabs(WIRE.trim_start())))?;
+        out.write_text("payloads.go", &gen.payloads()?)?;
+        if let Some(formats) = &formats {
+            out.write_text("frame.go", &gen.frame(formats))?;
+        }
+        out.write_text("device.go", &gen.device())?;
+        if self.tests {
+            out.write_text(&format!("{package}_test.go"), &gen.tests())?;
+        }
+        Ok(())
+    }
+}
+
+/// Static Go is written here with four space indents, and gofmt wants tabs
+fn tabs(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let spaces = line.len() - line.trim_start_matches(' ').len();
+            format!("{}{}\n", "\t".repeat(spaces / 4), &line[spaces / 4 * 4..])
+        })
+        .collect()
+}
+
+/// Names the generated package declares itself, which types can't take
+const RESERVED: &[&str] = &[
+    "Codec", "Payload", "PayloadInfo", "Direction", "Signing", "ByteOrder", "Writer", "Reader", "Element", "ElementKind", "Frame", "Device",
+    "Encode", "Decode", "Pack", "Unpack", "EncodeFrame", "DecodeFrame", "ParseFrame", "NewReader", "NewDevice", "TxFormat", "RxFormat", "TX",
+    "RX", "Unsigned", "TwosComplement", "OnesComplement", "BigEndian", "LittleEndian",
+];
+
+/// Keywords, predeclared identifiers and the imports of device.go, which locals can't take
+const LOCALS: &[&str] = &[
+    "break", "case", "chan", "const", "continue", "default", "defer", "else", "fallthrough", "for", "func", "go", "goto", "if", "import",
+    "interface", "map", "package", "range", "return", "select", "struct", "switch", "type", "var", "any", "append", "bool", "byte", "cap",
+    "clear", "close", "complex", "copy", "delete", "error", "false", "imag", "int", "iota", "len", "make", "max", "min", "new", "nil", "panic",
+    "print", "println", "real", "recover", "rune", "string", "true", "uint", "errors", "fmt", "io", "time", "d", "out", "err",
+];
+
+/// An exported identifier
+fn exported(name: &Name) -> String {
+    let pascal = name.pascal();
+    if pascal.starts_with(|c: char| c.is_ascii_digit()) || pascal.is_empty() {
+        format!("X{pascal}")
+    } else {
+        pascal
+    }
+}
+
+/// A struct field, kept clear of the `Encode`, `Decode` and `Info` methods
+fn field_name(name: &Name) -> String {
+    match exported(name).as_str() {
+        name @ ("Encode" | "Decode" | "Info") => format!("{name}Field"),
+        name => name.to_owned(),
+    }
+}
+
+fn local(name: &Name) -> String {
+    let camel = name.camel();
+    if LOCALS.contains(&camel.as_str()) || camel.starts_with(|c: char| c.is_ascii_digit()) || camel.is_empty() {
+        format!("{camel}Payload")
+    } else {
+        camel
+    }
+}
+
+fn int_type(bits: u32, signing: Signing) -> String {
+    let width = match bits {
+        0..=8 => 8,
+        9..=16 => 16,
+        17..=32 => 32,
+        _ => 64,
+    };
+    format!("{}int{width}", if signing == Signing::Unsigned { "u" } else { "" })
+}
+
+fn order(endianness: Endianness) -> &'static str {
+    match endianness {
+        Endianness::BigEndian => "BigEndian",
+        Endianness::LittleEndian => "LittleEndian",
+    }
+}
+
+fn signing(signing: Signing) -> &'static str {
+    match signing {
+        Signing::Unsigned => "Unsigned",
+        Signing::TwosComplement => "TwosComplement",
+        Signing::OnesComplement => "OnesComplement",
+    }
+}
+
+fn bytes_literal(data: &[u8]) -> String {
+    format!("[]byte{{{}}}", data.iter().map(|b| format!("0x{b:02x}")).collect::<Vec<_>>().join(", "))
+}
+
+fn doc(w: &mut CodeWriter, text: &str) {
+    w.comment("//", text);
+}
+
+/// Lines of `name type`, with the types lined up as gofmt does
+fn aligned(w: &mut CodeWriter, rows: &[(String, String)]) {
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, ty) in rows {
+        w.line(format!("{name:width$} {ty}"));
+    }
+}
+
+fn element(element: &FlatElement) -> String {
+    let bytes = |unit: &BitsOrBytes| if *unit == BitsOrBytes::Bytes { ", Bytes: true" } else { "" };
+    match element {
+        FlatElement::SizeTotal { bits, unit } => format!("{{Kind: KindSizeTotal, Bits: {bits}{}}}", bytes(unit)),
+        FlatElement::SizeOfPayload { bits, unit } => format!("{{Kind: KindSizeOfPayload, Bits: {bits}{}}}", bytes(unit)),
+        FlatElement::SizeOfElements { bits, unit, covers, .. } => {
+            format!("{{Kind: KindSizeOfElements, Bits: {bits}{}, Covers: {covers}}}", bytes(unit))
+        }
+        FlatElement::Payload => "{Kind: KindPayload}".to_owned(),
+        FlatElement::Metadata { bits, endianness, .. } => match endianness {
+            Endianness::BigEndian => format!("{{Kind: KindMetadata, Bits: {bits}}}"),
+            Endianness::LittleEndian => format!("{{Kind: KindMetadata, Bits: {bits}, Order: LittleEndian}}"),
+        },
+        FlatElement::Crc(Crc::Crc32) => "{Kind: KindCRC32, Bits: 32}".to_owned(),
+        FlatElement::Crc(Crc::Crc16XModem) => "{Kind: KindCRC16XModem, Bits: 16}".to_owned(),
+        FlatElement::Const { data, bits } => format!("{{Kind: KindConst, Bits: {bits}, Data: {}}}", bytes_literal(data)),
+    }
+}
+
+struct Gen<'a> {
+    ir: &'a Ir,
+    package: &'a str,
+}
+
+impl Gen<'_> {
+    fn framed(&self) -> bool {
+        self.ir.framing.is_some()
+    }
+
+    fn header(&self) -> String {
+        format!("// Code generated by openpid gen go from an OpenPID document. DO NOT EDIT.\n\npackage {}\n\n", self.package)
+    }
+
+    fn struct_type(&self, name: &Name) -> String {
+        let ty = exported(name);
+        if RESERVED.contains(&ty.as_str()) {
+            format!("{ty}Struct")
+        } else {
+            ty
+        }
+    }
+
+    fn payload_type(&self, direction: Direction, name: &Name) -> String {
+        let ty = exported(name);
+        let other = match direction {
+            Direction::Tx => Direction::Rx,
+            Direction::Rx => Direction::Tx,
+        };
+        let clashes = RESERVED.contains(&ty.as_str())
+            || self.ir.structs.iter().any(|s| self.struct_type(&s.name) == ty)
+            || self.ir.payloads(other).iter().any(|p| exported(&p.name) == ty);
+        if clashes {
+            format!("{ty}{}", if direction == Direction::Tx { "Tx" } else { "Rx" })
+        } else {
+            ty
+        }
+    }
+
+    /// Name of a transaction's method, kept clear of the device's own
+    fn method_name(transaction: &Transaction) -> String {
+        match exported(&transaction.name).as_str() {
+            name @ ("Send" | "Receive" | "Sleep") => format!("{name}Transaction"),
+            name => name.to_owned(),
+        }
+    }
+
+    fn go_type(&self, ty: &Type) -> String {
+        match ty {
+            Type::Int { bits, signing, .. } => int_type(*bits, *signing),
+            Type::Float { bits, .. } => format!("float{bits}"),
+            Type::Bytes(Length::Fixed(n)) => format!("[{n}]byte"),
+            Type::Bytes(_) | Type::Const(_) => "[]byte".to_owned(),
+            Type::String(_) => "string".to_owned(),
+            Type::Struct(name) => self.struct_type(name),
+            Type::Array { item, len: Length::Fixed(n) } => format!("[{n}]{}", self.struct_type(item)),
+            Type::Array { item, .. } => format!("[]{}", self.struct_type(item)),
+        }
+    }
+
+    fn payloads(&self) -> Result<String, CodegenError> {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("\t");
+        w.line(self.header().trim_end());
+        for s in &ir.structs {
+            w.blank();
+            let ty = self.struct_type(&s.name);
+            match s.description.as_deref().map(str::trim).filter(|d| !d.is_empty()) {
+                Some(description) => doc(&mut w, &format!("{ty} is a struct: {description}")),
+                None => doc(&mut w, &format!("{ty} is a struct shared between payloads")),
+            }
+            self.codec(&mut w, &ty, &s.fields);
+        }
+        for p in ir.all_payloads() {
+            w.blank();
+            let ty = self.payload_type(p.direction, &p.name);
+            let role = if p.direction == Direction::Tx { "a TX payload, sent to the device" } else { "an RX payload, received from the device" };
+            match p.description.trim() {
+                "" => doc(&mut w, &format!("{ty} is {role}")),
+                description => doc(&mut w, &format!("{ty} is {role}: {description}")),
+            }
+            self.codec(&mut w, &ty, &p.fields);
+            let metadata = if self.framed() {
+                p.metadata
+                    .iter()
+                    .filter(|m| m.ty.is_some())
+                    .map(|m| {
+                        m.packed().ok_or_else(|| CodegenError::Unsupported {
+                            backend: "go",
+                            what: format!("metadata \"{}\" of payload \"{}\": only integers and strings up to 8 bytes", m.name, p.name),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+            } else {
+                Vec::new()
+            };
+            let metadata = metadata.iter().map(|values| format!("{{{}}}", values.iter().map(|v| format!("{v:#x}")).collect::<Vec<_>>().join(", ")));
+            let metadata = match metadata.collect::<Vec<_>>() {
+                values if values.is_empty() => String::new(),
+                values => format!(", Metadata: [][]uint64{{{}}}", values.join(", ")),
+            };
+            let fixed_len = p.size.fixed_bits().map_or(-1, |bits| bits.div_ceil(8) as i64);
+            let direction = if p.direction == Direction::Tx { "TX" } else { "RX" };
+            w.blank();
+            w.block(format!("func (*{ty}) Info() PayloadInfo {{"), "}", |w| {
+                w.line(format!("return PayloadInfo{{Name: \"{ty}\", Direction: {direction}, FixedLen: {fixed_len}{metadata}}}"));
+            });
+        }
+        Ok(w.finish())
+    }
+
+    /// A struct type with its `Encode` and `Decode` methods
+    fn codec(&self, w: &mut CodeWriter, ty: &str, fields: &[Field]) {
+        let user_facing = fields.iter().filter(|f| f.is_user_facing()).collect::<Vec<_>>();
+        let padded = |f: &Field| matches!(f.ty, Type::String(Length::Fixed(_)));
+        if user_facing.is_empty() {
+            w.line(format!("type {ty} struct{{}}"));
+        } else if user_facing.iter().any(|f| f.description.is_some() || padded(f)) {
+            // gofmt only lines up runs of fields without comments between them, so space them out
+            w.block(format!("type {ty} struct {{"), "}", |w| {
+                for (i, f) in user_facing.iter().enumerate() {
+                    if i > 0 {
+                        w.blank();
+                    }
+                    if let Some(description) = &f.description {
+                        doc(w, description);
+                    }
+                    if let Type::String(Length::Fixed(n)) = f.ty {
+                        doc(w, &format!("Sent NUL padded to {n} bytes"));
+                    }
+                    w.line(format!("{} {}", field_name(&f.name), self.go_type(&f.ty)));
+                }
+            });
+        } else {
+            w.block(format!("type {ty} struct {{"), "}", |w| {
+                aligned(w, &user_facing.iter().map(|f| (field_name(&f.name), self.go_type(&f.ty))).collect::<Vec<_>>());
+            });
+        }
+        w.blank();
+        w.block(format!("func (p *{ty}) Encode(w *Writer) {{"), "}", |w| {
+            for f in fields {
+                self.encode_field(w, f);
+            }
+        });
+        w.blank();
+        w.block(format!("func (p *{ty}) Decode(r *Reader) {{"), "}", |w| {
+            for f in fields {
+                self.decode_field(w, f);
+            }
+        });
+    }
+
+    fn encode_field(&self, w: &mut CodeWriter, f: &Field) {
+        let value = format!("p.{}", field_name(&f.name));
+        match &f.ty {
+            Type::Int { bits, signing: s, endianness } => {
+                let value = match &f.count_of {
+                    Some(counted) => format!("len(p.{})", field_name(counted)),
+                    None => value,
+                };
+                match s {
+                    Signing::Unsigned => w.line(format!("w.PutUint({bits}, uint64({value}), {})", order(*endianness))),
+                    s => w.line(format!("w.PutInt({bits}, int64({value}), {}, {})", signing(*s), order(*endianness))),
+                };
+            }
+            Type::Float { bits, endianness } => {
+                w.line(format!("w.PutFloat{bits}({value}, {})", order(*endianness)));
+            }
+            Type::Const(data) => {
+                w.line(format!("w.PutBytes({})", bytes_literal(data)));
+            }
+            Type::Struct(_) => {
+                w.line(format!("{value}.Encode(w)"));
+            }
+            Type::Bytes(len) | Type::String(len) => {
+                let bytes = match (&f.ty, len) {
+                    (Type::String(_), _) => format!("[]byte({value})"),
+                    (_, Length::Fixed(_)) => format!("{value}[:]"),
+                    _ => value,
+                };
+                match (&f.ty, len) {
+                    (Type::String(_), Length::Fixed(n)) | (_, Length::Capacity(n)) => w.line(format!("w.PutPadded({bytes}, {n})")),
+                    (_, Length::Sequence(sequence)) => {
+                        w.line(format!("w.PutBytes({bytes})"));
+                        w.line(format!("w.PutBytes({})", bytes_literal(sequence)))
+                    }
+                    _ => w.line(format!("w.PutBytes({bytes})")),
+                };
+            }
+            Type::Array { len, .. } => {
+                if let Length::Capacity(n) = len {
+                    w.line(format!("w.CheckCapacity(len({value}), {n})"));
+                }
+                w.block(format!("for i := range {value} {{"), "}", |w| {
+                    w.line(format!("{value}[i].Encode(w)"));
+                });
+                if let Length::Sequence(sequence) = len {
+                    w.line(format!("w.PutBytes({})", bytes_literal(sequence)));
+                }
+            }
+        }
+    }
+
+    fn decode_field(&self, w: &mut CodeWriter, f: &Field) {
+        let target = format!("p.{}", field_name(&f.name));
+        let count = |len: &Length| match len {
+            Length::Fixed(n) | Length::Capacity(n) => n.to_string(),
+            Length::CountField(c) => format!("n{}", c.pascal()),
+            Length::Sequence(_) | Length::Remainder => "r.Remaining() / 8".to_owned(),
+        };
+        match &f.ty {
+            Type::Int { bits, signing: s, endianness } => {
+                if f.count_of.is_some() {
+                    w.line(format!("n{} := r.Count({bits}, {}, {})", f.name.pascal(), signing(*s), order(*endianness)));
+                } else if *s == Signing::Unsigned {
+                    w.line(format!("{target} = {}(r.Uint({bits}, {}))", int_type(*bits, *s), order(*endianness)));
+                } else {
+                    w.line(format!("{target} = {}(r.Int({bits}, {}, {}))", int_type(*bits, *s), signing(*s), order(*endianness)));
+                }
+            }
+            Type::Float { bits, endianness } => {
+                w.line(format!("{target} = r.Float{bits}({})", order(*endianness)));
+            }
+            Type::Const(data) => {
+                w.line(format!("r.Expect({})", bytes_literal(data)));
+            }
+            Type::Struct(_) => {
+                w.line(format!("{target}.Decode(r)"));
+            }
+            Type::Bytes(len) | Type::String(len) => {
+                let bytes = match len {
+                    Length::Sequence(sequence) => format!("r.Until({})", bytes_literal(sequence)),
+                    _ => format!("r.Bytes({})", count(len)),
+                };
+                match (&f.ty, len) {
+                    (Type::String(_), Length::Fixed(_) | Length::Capacity(_)) => w.line(format!("{target} = r.UTF8(trimNul({bytes}))")),
+                    (Type::String(_), _) => w.line(format!("{target} = r.UTF8({bytes})")),
+                    (_, Length::Fixed(_)) => w.line(format!("copy({target}[:], {bytes})")),
+                    _ => w.line(format!("{target} = {bytes}")),
+                };
+            }
+            Type::Array { item, len } => {
+                let item = self.struct_type(item);
+                match len {
+                    Length::Fixed(_) => {
+                        w.block(format!("for i := range {target} {{"), "}", |w| {
+                            w.line(format!("{target}[i].Decode(r)"));
+                        });
+                    }
+                    Length::CountField(_) | Length::Capacity(_) => {
+                        w.line(format!("{target} = make([]{item}, r.Alloc({}))", count(len)));
+                        w.block(format!("for i := range {target} {{"), "}", |w| {
+                            w.line(format!("{target}[i].Decode(r)"));
+                        });
+                    }
+                    Length::Sequence(_) | Length::Remainder => {
+                        let more = match len {
+                            Length::Sequence(sequence) => format!("!r.Take({})", bytes_literal(sequence)),
+                            _ => "r.Err() == nil && r.Remaining() >= 8".to_owned(),
+                        };
+                        w.line(format!("{target} = nil"));
+                        w.block(format!("for {more} {{"), "}", |w| {
+                            w.line(format!("var item {item}"));
+                            w.line("item.Decode(r)");
+                            w.line(format!("{target} = append({target}, item)"));
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn frame(&self, formats: &[Vec<FlatElement>; 2]) -> String {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("\t");
+        w.line(self.header().trim_end());
+        w.blank();
+        w.line("import \"fmt\"");
+        for (direction, format) in [Direction::Tx, Direction::Rx].into_iter().zip(formats) {
+            let pascal = if direction == Direction::Tx { "Tx" } else { "Rx" };
+            w.blank();
+            doc(&mut w, &format!("{pascal}Format is the frame format of payloads {}", if direction == Direction::Tx { "sent to the device" } else { "received from the device" }));
+            w.block(format!("var {pascal}Format = []Element{{"), "}", |w| {
+                for e in format {
+                    w.line(format!("{},", element(e)));
+                }
+            });
+            for (i, (key, _)) in ir.framing.iter().flat_map(|f| f.metadata(direction)).enumerate() {
+                let values = ir
+                    .payloads(direction)
+                    .iter()
+                    .filter_map(|p| Some((p, p.metadata(key.raw())?.packed()?.first().copied()?)))
+                    .map(|(p, value)| (format!("{pascal}{}{}", exported(key), exported(&p.name)), format!("uint64 = {value:#x}")))
+                    .collect::<Vec<_>>();
+                if values.is_empty() {
+                    continue;
+                }
+                w.blank();
+                doc(&mut w, &format!("Values of the \"{key}\" metadata, element {i} of {direction} frames"));
+                w.block("const (", ")", |w| aligned(w, &values));
+            }
+        }
+        w.blank();
+        w.line(tabs(FRAME_FNS.trim()).trim_end());
+        w.finish()
+    }
+
+    fn device(&self) -> String {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("\t");
+        w.line("// Code generated by openpid gen go from an OpenPID document. DO NOT EDIT.");
+        w.blank();
+        doc(&mut w, &format!("Package {} drives the {}: {}", self.package, ir.device.name, ir.device.description.trim()));
+        w.line(format!("package {}", self.package));
+        w.blank();
+        w.line(tabs(DEVICE.trim()).trim_end());
+        w.blank();
+        doc(&mut w, "Send writes a payload");
+        w.block("func (d *Device) Send(p Payload) error {", "}", |w| {
+            w.line(if self.framed() { "data, err := Pack(p)" } else { "data, err := Encode(p)" });
+            w.block("if err != nil {", "}", |w| {
+                w.line("return err");
+            });
+            w.line("_, err = d.port.Write(data)");
+            w.line("return err");
+        });
+        w.blank();
+        doc(&mut w, "Receive reads one payload into p");
+        w.block("func (d *Device) Receive(p Payload) error {", "}", |w| {
+            if self.framed() {
+                w.line(tabs(RECEIVE_FRAMED.trim()).trim_end());
+            } else {
+                w.line("// without a frame format, every payload has a fixed size");
+                w.line("data := make([]byte, p.Info().FixedLen)");
+                w.block("if err := d.read(data); err != nil {", "}", |w| {
+                    w.line("return err");
+                });
+                w.line("return Decode(p, data)");
+            }
+        });
+        for transaction in &ir.transactions {
+            if transaction.returns.len() > 1 {
+                w.blank();
+                let ty = format!("{}Response", exported(&transaction.name));
+                doc(&mut w, &format!("{ty} is what {} returns", Self::method_name(transaction)));
+                w.block(format!("type {ty} struct {{"), "}", |w| {
+                    aligned(w, &transaction.returns.iter().map(|ret| (exported(&ret.name), self.go_type(&ret.ty))).collect::<Vec<_>>());
+                });
+            }
+            w.blank();
+            self.transaction(&mut w, transaction);
+        }
+        w.finish()
+    }
+
+    fn transaction(&self, w: &mut CodeWriter, transaction: &Transaction) {
+        let params = self.ir.transaction_params(transaction);
+        let signature = params.iter().map(|p| format!("{} *{}", local(p), self.payload_type(Direction::Tx, p))).collect::<Vec<_>>();
+        let returns = match transaction.returns.as_slice() {
+            [] => None,
+            [ret] => Some(self.go_type(&ret.ty)),
+            _ => Some(format!("{}Response", exported(&transaction.name))),
+        };
+        let name = Self::method_name(transaction);
+        match transaction.description.trim() {
+            "" => {}
+            description => doc(w, &format!("{name}: {description}")),
+        }
+        let result = returns.as_ref().map_or("error".to_owned(), |ty| format!("({ty}, error)"));
+        let fail = if returns.is_some() { "return out, err" } else { "return err" };
+        let check = |w: &mut CodeWriter, call: String| {
+            w.block(format!("if err := {call}; err != nil {{"), "}", |w| {
+                w.line(fail);
+            });
+        };
+        w.block(format!("func (d *Device) {name}({}) {result} {{", signature.join(", ")), "}", |w| {
+            if let Some(ty) = &returns {
+                w.line(format!("var out {ty}"));
+            }
+            let mut declared: Vec<&Name> = Vec::new();
+            for action in &transaction.actions {
+                match action {
+                    Action::Tx(payload) if params.contains(&payload) => check(w, format!("d.Send({})", local(payload))),
+                    Action::Tx(payload) => check(w, format!("d.Send(&{}{{}})", self.payload_type(Direction::Tx, payload))),
+                    Action::Rx(payload) if transaction.returns.iter().any(|r| r.payload == *payload) => {
+                        if !declared.contains(&payload) {
+                            w.line(format!("var {}Rx {}", payload.camel(), self.payload_type(Direction::Rx, payload)));
+                            declared.push(payload);
+                        }
+                        check(w, format!("d.Receive(&{}Rx)", payload.camel()));
+                    }
+                    Action::Rx(payload) => check(w, format!("d.Receive(&{}{{}})", self.payload_type(Direction::Rx, payload))),
+                    Action::Sleep { milliseconds } => {
+                        w.line(format!("d.Sleep({milliseconds} * time.Millisecond)"));
+                    }
+                    Action::Flush => check(w, "d.discardInput()".to_owned()),
+                }
+            }
+            let value = |ret: &Return| format!("{}Rx.{}", ret.payload.camel(), ret.path.iter().map(field_name).collect::<Vec<_>>().join("."));
+            match transaction.returns.as_slice() {
+                [] => {
+                    w.line("return nil");
+                }
+                [ret] => {
+                    w.line(format!("return {}, nil", value(ret)));
+                }
+                returns => {
+                    let members = returns.iter().map(|ret| format!("{}: {}", exported(&ret.name), value(ret))).collect::<Vec<_>>();
+                    w.line(format!("out = {}Response{{{}}}", exported(&transaction.name), members.join(", ")));
+                    w.line("return out, nil");
+                }
+            }
+        });
+    }
+
+    /// A Go expression for a sample value of a field's type
+    fn literal(&self, value: &Value, ty: &Type) -> String {
+        match (value, ty) {
+            (Value::Struct(values), Type::Struct(name)) => self.sample(&self.struct_type(name), values, self.ir.fields_of(ty).unwrap_or(&[])),
+            (Value::Array(items), Type::Array { item, .. }) => {
+                // gofmt -s leaves out the item type
+                let items = items.iter().map(|i| self.literal(i, &Type::Struct(item.clone())).replacen(&self.struct_type(item), "", 1)).collect::<Vec<_>>();
+                format!("{}{{{}}}", self.go_type(ty), items.join(", "))
+            }
+            (Value::Bytes(b), Type::Bytes(Length::Fixed(n))) => bytes_literal(b).replacen("[]", &format!("[{n}]"), 1),
+            (Value::Bytes(b), _) => bytes_literal(b),
+            (Value::String(text), _) => format!("{text:?}"),
+            (Value::Float(f), _) => format!("{f:?}"),
+            (value, _) => value.to_string(),
+        }
+    }
+
+    /// A composite literal of a struct or payload, from sample values for its user facing fields
+    fn sample(&self, ty: &str, values: &Fields, fields: &[Field]) -> String {
+        let members = fields
+            .iter()
+            .filter(|f| f.is_user_facing())
+            .filter_map(|f| Some(format!("{}: {}", field_name(&f.name), self.literal(values.get(f.name.raw())?, &f.ty))))
+            .collect::<Vec<_>>();
+        format!("{ty}{{{}}}", members.join(", "))
+    }
+
+    fn payload_sample(&self, layout: &Layout, direction: Direction, name: &Name) -> String {
+        let fields = self.ir.get_payload(direction, name).map_or(&[][..], |p| &p.fields);
+        format!("&{}", self.sample(&self.payload_type(direction, name), &layout.sample_fields(fields, 0), fields))
+    }
+
+    fn tests(&self) -> String {
+        let ir = self.ir;
+        // slices grow, so only count fields limit how many samples there are
+        let layout = Layout::new(ir, u32::MAX);
+        let mut w = CodeWriter::new("\t");
+        w.line(self.header().trim_end());
+        w.blank();
+        w.line(tabs(TEST_SUPPORT.trim()).trim_end());
+        w.blank();
+        w.block(format!("func {}(t *testing.T, payloads ...Payload) []byte {{", if self.framed() { "frames" } else { "encoded" }), "}", |w| {
+            w.line("t.Helper()");
+            w.line("var out []byte");
+            w.block("for _, p := range payloads {", "}", |w| {
+                w.line(if self.framed() { "data, err := Pack(p)" } else { "data, err := Encode(p)" });
+                w.block("if err != nil {", "}", |w| {
+                    w.line("t.Fatal(err)");
+                });
+                w.line("out = append(out, data...)");
+            });
+            w.line("return out");
+        });
+        w.blank();
+        w.block("func TestRoundTrip(t *testing.T) {", "}", |w| {
+            w.block("tests := []struct {", "}{", |w| {
+                aligned(w, &[("name".to_owned(), "string".to_owned()), ("value".to_owned(), "Payload".to_owned()), ("decoded".to_owned(), "Payload".to_owned())]);
+            });
+            w.indent();
+            for p in ir.all_payloads() {
+                let ty = self.payload_type(p.direction, &p.name);
+                let value = self.payload_sample(&layout, p.direction, &p.name);
+                w.line(format!("{{\"{}.{ty}\", {value}, &{ty}{{}}}},", if p.direction == Direction::Tx { "tx" } else { "rx" }));
+            }
+            w.dedent();
+            w.line("}");
+            w.block("for _, tt := range tests {", "}", |w| {
+                w.block("t.Run(tt.name, func(t *testing.T) {", "})", |w| {
+                    w.line(tabs(ROUND_TRIP.trim()).trim_end());
+                    if self.framed() {
+                        w.line(tabs(ROUND_TRIP_FRAMED.trim()).trim_end());
+                    }
+                });
+            });
+        });
+        w.blank();
+        w.block("func TestTransactions(t *testing.T) {", "}", |w| {
+            w.block("tests := []struct {", "}{", |w| {
+                aligned(
+                    w,
+                    &[
+                        ("name".to_owned(), "string".to_owned()),
+                        ("responses".to_owned(), "[][]byte".to_owned()),
+                        ("sends".to_owned(), "bool".to_owned()),
+                        ("run".to_owned(), "func(d *Device) error".to_owned()),
+                    ],
+                );
+            });
+            w.indent();
+            for transaction in &ir.transactions {
+                self.transaction_case(w, &layout, transaction);
+            }
+            w.dedent();
+            w.line("}");
+            w.block("for _, tt := range tests {", "}", |w| {
+                w.block("t.Run(tt.name, func(t *testing.T) {", "})", |w| {
+                    w.line(tabs(RUN_TRANSACTION.trim()).trim_end());
+                });
+            });
+        });
+        w.finish()
+    }
+
+    fn transaction_case(&self, w: &mut CodeWriter, layout: &Layout, transaction: &Transaction) {
+        // responses arrive in batches: those read before the first write, then those after each write
+        let mut batches: Vec<Vec<String>> = vec![Vec::new()];
+        for action in &transaction.actions {
+            match action {
+                Action::Tx(_) => batches.push(Vec::new()),
+                Action::Rx(payload) => {
+                    let batch = batches.last_mut().expect("batches starts non-empty");
+                    batch.push(self.payload_sample(layout, Direction::Rx, payload));
+                }
+                _ => {}
+            }
+        }
+        while batches.last().is_some_and(Vec::is_empty) {
+            batches.pop();
+        }
+        let helper = if self.framed() { "frames" } else { "encoded" };
+        let batches = batches.iter().map(|b| if b.is_empty() { "nil".to_owned() } else { format!("{helper}(t, {})", b.join(", ")) }).collect::<Vec<_>>();
+        let responses = if batches.is_empty() { "nil".to_owned() } else { format!("[][]byte{{{}}}", batches.join(", ")) };
+        let args = self.ir.transaction_params(transaction).iter().map(|p| self.payload_sample(layout, Direction::Tx, p)).collect::<Vec<_>>();
+        let sends = transaction.actions.iter().any(|a| matches!(a, Action::Tx(_)));
+        let name = Self::method_name(transaction);
+        let call = format!("d.{name}({})", args.join(", "));
+        if transaction.returns.is_empty() {
+            w.line(format!("{{\"{name}\", {responses}, {sends}, func(d *Device) error {{ return {call} }}}},"));
+        } else {
+            let expected = match transaction.returns.as_slice() {
+                // untyped constants would compare as int or float64
+                [ret @ Return { ty: Type::Int { .. } | Type::Float { .. }, .. }] => {
+                    format!("{}({})", self.go_type(&ret.ty), layout.sample_return(ret).map(|value| self.literal(&value, &ret.ty)).unwrap_or_default())
+                }
+                [ret] => layout.sample_return(ret).map(|value| self.literal(&value, &ret.ty)).unwrap_or_default(),
+                returns => {
+                    let members = returns
+                        .iter()
+                        .filter_map(|ret| Some(format!("{}: {}", exported(&ret.name), self.literal(&layout.sample_return(ret)?, &ret.ty))))
+                        .collect::<Vec<_>>();
+                    format!("{}Response{{{}}}", exported(&transaction.name), members.join(", "))
+                }
+            };
+            w.block(format!("{{\"{name}\", {responses}, {sends}, func(d *Device) error {{"), "}},", |w| {
+                w.line(format!("got, err := {call}"));
+                w.line(format!("return expect(got, {expected}, err)"));
+            });
+        }
+    }
+}
+
+const WIRE: &str = r#"
+import (
+    "bytes"
+    "encoding/binary"
+    "errors"
+    "fmt"
+    "hash/crc32"
+    "math"
+    "unicode/utf8"
+)
+
+// ErrCodec is wrapped by every encoding and decoding error
+var ErrCodec = errors.New("codec error")
+
+// ErrIncomplete is wrapped by errors from data that ended early
+var ErrIncomplete = fmt.Errorf("%w: data ended early", ErrCodec)
+
+func codecError(format string, args ...any) error {
+    return fmt.Errorf("%w: %s", ErrCodec, fmt.Sprintf(format, args...))
+}
+
+// Signing is how an integer field represents negative numbers
+type Signing uint8
+
+const (
+    Unsigned Signing = iota
+    TwosComplement
+    // OnesComplement negates by flipping every bit, so its minimum is -max
+    OnesComplement
+)
+
+// ByteOrder of an integer or float field. Bits within a field always go most significant first
+type ByteOrder bool
+
+const (
+    BigEndian    ByteOrder = false
+    LittleEndian ByteOrder = true
+)
+
+func (o ByteOrder) binaryOrder() interface {
+    binary.ByteOrder
+    binary.AppendByteOrder
+} {
+    if o == LittleEndian {
+        return binary.LittleEndian
+    }
+    return binary.BigEndian
+}
+
+// Codec is implemented by pointers to every struct and payload type
+type Codec interface {
+    Encode(w *Writer)
+    Decode(r *Reader)
+}
+
+// Direction a payload travels in
+type Direction uint8
+
+const (
+    // TX payloads are sent to the device
+    TX Direction = iota
+    // RX payloads are received from it
+    RX
+)
+
+// PayloadInfo describes how a payload is sent
+type PayloadInfo struct {
+    Name      string
+    Direction Direction
+
+    // FixedLen is the payload's size in bytes, or -1 if it varies
+    FixedLen int
+
+    // Metadata holds the values this payload accepts for each metadata element of its frame. The
+    // first is the one sent
+    Metadata [][]uint64
+}
+
+// Payload is implemented by pointers to every TX and RX payload type
+type Payload interface {
+    Codec
+    Info() PayloadInfo
+}
+
+// Encode returns v's encoding
+func Encode(v Codec) ([]byte, error) {
+    var w Writer
+    v.Encode(&w)
+    return w.Bytes(), w.Err()
+}
+
+// Decode fills v from data, which must hold exactly one value
+func Decode(v Codec, data []byte) error {
+    r := NewReader(data)
+    v.Decode(r)
+    return r.Finish()
+}
+
+func toRaw(bits int, v int64, s Signing) (uint64, error) {
+    if s == Unsigned || bits == 0 {
+        if v < 0 || v>>bits != 0 {
+            return 0, codecError("%d doesn't fit in %d unsigned bits", v, bits)
+        }
+        return uint64(v), nil
+    }
+    high := int64(1)<<(bits-1) - 1
+    low := -high - 1
+    if s == OnesComplement {
+        low = -high
+    }
+    if v < low || v > high {
+        return 0, codecError("%d doesn't fit in %d signed bits", v, bits)
+    }
+    mask := uint64(1)<<bits - 1
+    if v < 0 && s == OnesComplement {
+        return ^uint64(-v) & mask, nil
+    }
+    return uint64(v) & mask, nil
+}
+
+func fromRaw(bits int, raw uint64, s Signing) int64 {
+    if s == Unsigned || bits == 0 || raw>>(bits-1)&1 == 0 {
+        return int64(raw)
+    }
+    mask := uint64(1)<<bits - 1
+    if s == OnesComplement {
+        return -int64(^raw & mask)
+    }
+    return int64(raw | ^mask)
+}
+
+func swap(v uint64, bits int) uint64 {
+    var out uint64
+    for i := 0; i < bits/8; i++ {
+        out = out<<8 | v&0xff
+        v >>= 8
+    }
+    return out
+}
+
+func trimNul(b []byte) []byte {
+    return bytes.TrimRight(b, "\x00")
+}
+
+// Writer packs values most significant bit first. The first error sticks and later writes are
+// dropped, so encoders only check Err once at the end
+type Writer struct {
+    buf  []byte
+    bits int
+    err  error
+}
+
+func (w *Writer) fail(err error) {
+    if w.err == nil {
+        w.err = err
+    }
+}
+
+// Err is the first error encountered
+func (w *Writer) Err() error {
+    return w.err
+}
+
+// Len is the number of bits written
+func (w *Writer) Len() int {
+    return w.bits
+}
+
+// Bytes returns what's been written, zero padded to a whole byte
+func (w *Writer) Bytes() []byte {
+    return w.buf
+}
+
+// PutBits writes the low bits of v
+func (w *Writer) PutBits(bits int, v uint64) {
+    if w.err != nil {
+        return
+    }
+    if v>>bits != 0 {
+        w.fail(codecError("%d doesn't fit in %d bits", v, bits))
+        return
+    }
+    for bits > 0 {
+        if w.bits%8 == 0 {
+            w.buf = append(w.buf, 0)
+        }
+        room := 8 - w.bits%8
+        n := min(room, bits)
+        bits -= n
+        w.buf[len(w.buf)-1] |= byte(v>>bits&(1<<n-1)) << (room - n)
+        w.bits += n
+    }
+}
+
+// PutUint writes an unsigned integer. Byte aligned fields of standard widths go through encoding/binary
+func (w *Writer) PutUint(bits int, v uint64, order ByteOrder) {
+    if w.err != nil {
+        return
+    }
+    if v>>bits != 0 {
+        w.fail(codecError("%d doesn't fit in %d unsigned bits", v, bits))
+        return
+    }
+    if w.bits%8 == 0 {
+        o := order.binaryOrder()
+        switch bits {
+        case 16:
+            w.buf = o.AppendUint16(w.buf, uint16(v))
+            w.bits += 16
+            return
+        case 32:
+            w.buf = o.AppendUint32(w.buf, uint32(v))
+            w.bits += 32
+            return
+        case 64:
+            w.buf = o.AppendUint64(w.buf, v)
+            w.bits += 64
+            return
+        }
+    }
+    if order == LittleEndian {
+        v = swap(v, bits)
+    }
+    w.PutBits(bits, v)
+}
+
+// PutInt writes a signed integer
+func (w *Writer) PutInt(bits int, v int64, s Signing, order ByteOrder) {
+    if w.err != nil {
+        return
+    }
+    raw, err := toRaw(bits, v, s)
+    if err != nil {
+        w.fail(err)
+        return
+    }
+    w.PutUint(bits, raw, order)
+}
+
+func (w *Writer) PutFloat32(v float32, order ByteOrder) {
+    w.PutUint(32, uint64(math.Float32bits(v)), order)
+}
+
+func (w *Writer) PutFloat64(v float64, order ByteOrder) {
+    w.PutUint(64, math.Float64bits(v), order)
+}
+
+func (w *Writer) PutBytes(data []byte) {
+    if w.err != nil {
+        return
+    }
+    if w.bits%8 != 0 {
+        for _, b := range data {
+            w.PutBits(8, uint64(b))
+        }
+        return
+    }
+    w.buf = append(w.buf, data...)
+    w.bits += len(data) * 8
+}
+
+// PutPadded writes data, then zeros up to capacity bytes
+func (w *Writer) PutPadded(data []byte, capacity int) {
+    if len(data) > capacity {
+        w.fail(codecError("%d bytes don't fit in %d", len(data), capacity))
+        return
+    }
+    w.PutBytes(data)
+    w.PutBytes(make([]byte, capacity-len(data)))
+}
+
+// CheckCapacity fails if a field holds more than capacity elements
+func (w *Writer) CheckCapacity(n, capacity int) {
+    if n > capacity {
+        w.fail(codecError("%d elements don't fit in %d", n, capacity))
+    }
+}
+
+// putConst writes the last bits bits of data
+func (w *Writer) putConst(data []byte, bits int) {
+    for i := len(data)*8 - bits; i < len(data)*8; i++ {
+        w.PutBits(1, uint64(data[i/8]>>(7-i%8)&1))
+    }
+}
+
+// Reader unpacks values most significant bit first. Like Writer, the first error sticks and later
+// reads return zero values
+type Reader struct {
+    data []byte
+    pos  int
+    err  error
+}
+
+func NewReader(data []byte) *Reader {
+    return &Reader{data: data}
+}
+
+func (r *Reader) fail(err error) {
+    if r.err == nil {
+        r.err = err
+    }
+}
+
+// Err is the first error encountered
+func (r *Reader) Err() error {
+    return r.err
+}
+
+// Remaining is the number of bits left to read
+func (r *Reader) Remaining() int {
+    return len(r.data)*8 - r.pos
+}
+
+func (r *Reader) Bits(bits int) uint64 {
+    if r.err != nil {
+        return 0
+    }
+    if bits > r.Remaining() {
+        r.fail(fmt.Errorf("%w: needed %d bits, %d left", ErrIncomplete, bits, r.Remaining()))
+        return 0
+    }
+    var v uint64
+    for bits > 0 {
+        room := 8 - r.pos%8
+        n := min(room, bits)
+        v = v<<n | uint64(r.data[r.pos/8]>>(room-n)&(1<<n-1))
+        r.pos += n
+        bits -= n
+    }
+    return v
+}
+
+// Uint reads an unsigned integer. Byte aligned fields of standard widths go through encoding/binary
+func (r *Reader) Uint(bits int, order ByteOrder) uint64 {
+    if r.err == nil && r.pos%8 == 0 && bits <= r.Remaining() {
+        b := r.data[r.pos/8:]
+        o := order.binaryOrder()
+        switch bits {
+        case 16:
+            r.pos += 16
+            return uint64(o.Uint16(b))
+        case 32:
+            r.pos += 32
+            return uint64(o.Uint32(b))
+        case 64:
+            r.pos += 64
+            return o.Uint64(b)
+        }
+    }
+    v := r.Bits(bits)
+    if order == LittleEndian {
+        v = swap(v, bits)
+    }
+    return v
+}
+
+func (r *Reader) Int(bits int, s Signing, order ByteOrder) int64 {
+    return fromRaw(bits, r.Uint(bits, order), s)
+}
+
+// Count reads the length of a variable length field
+func (r *Reader) Count(bits int, s Signing, order ByteOrder) int {
+    n := r.Int(bits, s, order)
+    if n < 0 {
+        r.fail(codecError("negative count %d", n))
+        return 0
+    }
+    return int(n)
+}
+
+// Alloc checks a count read from the data before it's used to size a slice, since each element
+// takes at least a bit
+func (r *Reader) Alloc(n int) int {
+    if r.err == nil && n > r.Remaining() {
+        r.fail(fmt.Errorf("%w: %d elements can't fit in %d bits", ErrIncomplete, n, r.Remaining()))
+    }
+    if r.err != nil {
+        return 0
+    }
+    return n
+}
+
+func (r *Reader) Float32(order ByteOrder) float32 {
+    return math.Float32frombits(uint32(r.Uint(32, order)))
+}
+
+func (r *Reader) Float64(order ByteOrder) float64 {
+    return math.Float64frombits(r.Uint(64, order))
+}
+
+func (r *Reader) Bytes(n int) []byte {
+    if r.err != nil {
+        return nil
+    }
+    if n*8 > r.Remaining() {
+        r.fail(fmt.Errorf("%w: needed %d bytes, %d bits left", ErrIncomplete, n, r.Remaining()))
+        return nil
+    }
+    out := make([]byte, n)
+    if r.pos%8 != 0 {
+        for i := range out {
+            out[i] = byte(r.Bits(8))
+        }
+        return out
+    }
+    copy(out, r.data[r.pos/8:])
+    r.pos += n * 8
+    return out
+}
+
+// UTF8 fails unless b is valid UTF-8
+func (r *Reader) UTF8(b []byte) string {
+    if r.err == nil && !utf8.Valid(b) {
+        r.fail(codecError("invalid UTF-8 %q", b))
+    }
+    return string(b)
+}
+
+func (r *Reader) Expect(data []byte) {
+    if found := r.Bytes(len(data)); r.err == nil && !bytes.Equal(found, data) {
+        r.fail(codecError("expected constant % x, found % x", data, found))
+    }
+}
+
+// Take consumes sequence if it comes next. It also reports true after an error, to end loops
+func (r *Reader) Take(sequence []byte) bool {
+    if r.err != nil {
+        return true
+    }
+    if r.Remaining() < len(sequence)*8 {
+        return false
+    }
+    pos := r.pos
+    if bytes.Equal(r.Bytes(len(sequence)), sequence) {
+        return true
+    }
+    r.pos = pos
+    return false
+}
+
+// Until reads up to and including sequence, returning the bytes before it
+func (r *Reader) Until(sequence []byte) []byte {
+    var out []byte
+    for !r.Take(sequence) {
+        out = append(out, r.Bytes(1)...)
+    }
+    return out
+}
+
+func (r *Reader) matchConst(data []byte, bits int) bool {
+    for i := len(data)*8 - bits; i < len(data)*8; i++ {
+        if r.Bits(1) != uint64(data[i/8]>>(7-i%8)&1) {
+            return false
+        }
+    }
+    return true
+}
+
+// Finish returns the first error, or an error if a whole byte or more is left over
+func (r *Reader) Finish() error {
+    if r.err == nil && r.Remaining() >= 8 {
+        r.fail(codecError("%d bits left over", r.Remaining()))
+    }
+    return r.err
+}
+
+// CRC16XModem is the CRC-16/XMODEM of data
+func CRC16XModem(data []byte) uint16 {
+    var crc uint16
+    for _, b := range data {
+        crc ^= uint16(b) << 8
+        for i := 0; i < 8; i++ {
+            if crc&0x8000 != 0 {
+                crc = crc<<1 ^ 0x1021
+            } else {
+                crc <<= 1
+            }
+        }
+    }
+    return crc
+}
+
+// ElementKind is what a frame element holds
+type ElementKind uint8
+
+const (
+    // KindSizeTotal is the whole frame's size
+    KindSizeTotal ElementKind = iota
+    // KindSizeOfPayload is the payload's size
+    KindSizeOfPayload
+    // KindSizeOfElements is the size of the Covers elements right after it
+    KindSizeOfElements
+    KindPayload
+    // KindMetadata is a value that tells payloads apart, such as a message ID
+    KindMetadata
+    // KindCRC32 is the CRC-32 of the frame so far, big-endian
+    KindCRC32
+    // KindCRC16XModem is the CRC-16/XMODEM of the frame so far, big-endian
+    KindCRC16XModem
+    // KindConst is the last Bits bits of Data
+    KindConst
+)
+
+// Element is one part of a frame format
+type Element struct {
+    Kind ElementKind
+    Bits int
+
+    // Bytes is set for sizes counted in bytes rather than bits
+    Bytes bool
+
+    // Covers is how many of the following elements a KindSizeOfElements counts
+    Covers int
+
+    // Order of a KindMetadata value
+    Order ByteOrder
+
+    // Data of a KindConst
+    Data []byte
+}
+
+// Frame is a parsed frame
+type Frame struct {
+    Metadata []uint64
+    Payload  []byte
+
+    // Len is the frame's length in bytes
+    Len int
+}
+
+func envelopeBits(format []Element) int {
+    bits := 0
+    for _, e := range format {
+        bits += e.Bits
+    }
+    return bits
+}
+
+// coveredBits is the size of the elements a KindSizeOfElements at index i counts
+func coveredBits(format []Element, i, payloadBits int) int {
+    bits := 0
+    for _, e := range format[i+1 : min(i+1+format[i].Covers, len(format))] {
+        if e.Kind == KindPayload {
+            bits += payloadBits
+        }
+        bits += e.Bits
+    }
+    return bits
+}
+
+func sizeValue(bits int, inBytes bool) uint64 {
+    if inBytes {
+        return uint64((bits + 7) / 8)
+    }
+    return uint64(bits)
+}
+
+func sizeBits(v uint64, inBytes bool) int {
+    if inBytes {
+        return int(v) * 8
+    }
+    return int(v)
+}
+
+func crc(kind ElementKind, data []byte) uint64 {
+    if kind == KindCRC32 {
+        return uint64(crc32.ChecksumIEEE(data))
+    }
+    return uint64(CRC16XModem(data))
+}
+
+// matches is whether a frame's metadata is what a payload is sent with
+func matches(info PayloadInfo, metadata []uint64) bool {
+    for i, accepted := range info.Metadata {
+        if i >= len(metadata) {
+            break
+        }
+        found := false
+        for _, v := range accepted {
+            found = found || v == metadata[i]
+        }
+        if !found {
+            return false
+        }
+    }
+    return true
+}
+
+// EncodeFrame wraps payload in a frame, with a value for each of the format's metadata elements in order
+func EncodeFrame(format []Element, metadata []uint64, payload []byte) ([]byte, error) {
+    payloadBits := len(payload) * 8
+    total := envelopeBits(format) + payloadBits
+    var w Writer
+    next := 0
+    for i, e := range format {
+        switch e.Kind {
+        case KindSizeTotal:
+            w.PutUint(e.Bits, sizeValue(total, e.Bytes), BigEndian)
+        case KindSizeOfPayload:
+            w.PutUint(e.Bits, sizeValue(payloadBits, e.Bytes), BigEndian)
+        case KindSizeOfElements:
+            w.PutUint(e.Bits, sizeValue(coveredBits(format, i, payloadBits), e.Bytes), BigEndian)
+        case KindPayload:
+            w.PutBytes(payload)
+        case KindMetadata:
+            if next >= len(metadata) {
+                return nil, codecError("the frame format needs more than %d metadata values", len(metadata))
+            }
+            w.PutUint(e.Bits, metadata[next], e.Order)
+            next++
+        case KindCRC32, KindCRC16XModem:
+            if w.bits%8 != 0 {
+                return nil, codecError("CRC at bit %d isn't byte aligned", w.bits)
+            }
+            w.PutUint(e.Bits, crc(e.Kind, w.buf), BigEndian)
+        case KindConst:
+            w.putConst(e.Data, e.Bits)
+        }
+    }
+    return w.Bytes(), w.Err()
+}
+
+// DecodeFrame parses data, which must hold exactly one frame
+func DecodeFrame(format []Element, data []byte) (*Frame, error) {
+    return parseFrame(format, data, -1, true)
+}
+
+// ParseFrame parses a frame from the start of data. payloadLen is the payload's size in bytes, for
+// formats without a size field, or -1. Unless complete is set, running out of data returns a nil
+// Frame and no error, so it can be called again as more bytes arrive
+func ParseFrame(format []Element, data []byte, payloadLen int, complete bool) (*Frame, error) {
+    f, err := parseFrame(format, data, payloadLen, complete)
+    if !complete && errors.Is(err, ErrIncomplete) {
+        return nil, nil
+    }
+    return f, err
+}
+
+func parseFrame(format []Element, data []byte, payloadLen int, complete bool) (*Frame, error) {
+    envelope := envelopeBits(format)
+    payloadIndex := -1
+    for i, e := range format {
+        if e.Kind == KindPayload {
+            payloadIndex = i
+        }
+    }
+    r := NewReader(data)
+    f := &Frame{}
+    total, payloadBits := -1, -1
+    for i, e := range format {
+        switch e.Kind {
+        case KindSizeTotal:
+            total = sizeBits(r.Uint(e.Bits, BigEndian), e.Bytes)
+            if r.err != nil {
+                return nil, r.err
+            }
+            if total < envelope {
+                return nil, codecError("frame size %d bits is smaller than its envelope", total)
+            }
+            if total > len(data)*8 {
+                return nil, fmt.Errorf("%w: frame is %d bits, have %d", ErrIncomplete, total, len(data)*8)
+            }
+        case KindSizeOfPayload:
+            payloadBits = sizeBits(r.Uint(e.Bits, BigEndian), e.Bytes)
+        case KindSizeOfElements:
+            region := sizeBits(r.Uint(e.Bits, BigEndian), e.Bytes)
+            if r.err == nil && payloadIndex > i && payloadIndex <= i+e.Covers {
+                payloadBits = region - coveredBits(format, i, 0)
+                if payloadBits < 0 {
+                    return nil, codecError("size field is smaller than the elements it covers")
+                }
+            }
+        case KindPayload:
+            bits := payloadBits
+            switch {
+            case bits >= 0:
+            case total >= 0:
+                bits = total - envelope
+            case payloadLen >= 0:
+                bits = payloadLen * 8
+            case complete:
+                bits = max(r.Remaining()-envelopeBits(format[i+1:]), 0)
+            default:
+                return nil, codecError("the frame doesn't say how long its payload is")
+            }
+            if bits%8 != 0 {
+                return nil, codecError("payload of %d bits isn't whole bytes", bits)
+            }
+            f.Payload = r.Bytes(bits / 8)
+        case KindMetadata:
+            f.Metadata = append(f.Metadata, r.Uint(e.Bits, e.Order))
+        case KindCRC32, KindCRC16XModem:
+            if r.pos%8 != 0 {
+                return nil, codecError("CRC at bit %d isn't byte aligned", r.pos)
+            }
+            expected := crc(e.Kind, data[:r.pos/8])
+            if found := r.Uint(e.Bits, BigEndian); r.err == nil && found != expected {
+                return nil, codecError("CRC mismatch: expected %#x, found %#x", expected, found)
+            }
+        case KindConst:
+            if !r.matchConst(e.Data, e.Bits) && r.err == nil {
+                return nil, codecError("constant bits don't match")
+            }
+        }
+        if r.err != nil {
+            return nil, r.err
+        }
+    }
+    if total >= 0 && (total+7)/8 != (r.pos+7)/8 {
+        return nil, codecError("frame size %d bits doesn't match its contents", total)
+    }
+    if complete && r.Remaining() >= 8 {
+        return nil, codecError("%d bits left over after the frame", r.Remaining())
+    }
+    f.Len = (r.pos + 7) / 8
+    return f, nil
+}
+"#;
+
+const FRAME_FNS: &str = r#"
+func formatOf(d Direction) []Element {
+    if d == RX {
+        return RxFormat
+    }
+    return TxFormat
+}
+
+// Pack encodes a payload wrapped in its frame
+func Pack(p Payload) ([]byte, error) {
+    payload, err := Encode(p)
+    if err != nil {
+        return nil, err
+    }
+    info := p.Info()
+    metadata := make([]uint64, len(info.Metadata))
+    for i, values := range info.Metadata {
+        metadata[i] = values[0]
+    }
+    return EncodeFrame(formatOf(info.Direction), metadata, payload)
+}
+
+// Unpack decodes data, which must hold exactly one frame, into p
+func Unpack(data []byte, p Payload) error {
+    info := p.Info()
+    f, err := DecodeFrame(formatOf(info.Direction), data)
+    if err != nil {
+        return err
+    }
+    if !matches(info, f.Metadata) {
+        return fmt.Errorf("%w: frame metadata %v isn't %s's", ErrCodec, f.Metadata, info.Name)
+    }
+    return Decode(p, f.Payload)
+}
+"#;
+
+const DEVICE: &str = r#"
+import (
+    "errors"
+    "fmt"
+    "io"
+    "time"
+)
+
+// ErrTimeout means the device didn't answer in time
+var ErrTimeout = errors.New("timed out waiting for the device")
+
+// ErrUnexpectedPayload means the device answered with a different payload than the transaction expects
+var ErrUnexpectedPayload = fmt.Errorf("%w: unexpected payload", ErrCodec)
+
+// inputResetter is implemented by transports that can drop bytes received but not yet read, such as
+// go.bug.st/serial's Port
+type inputResetter interface {
+    ResetInputBuffer() error
+}
+
+// Device sends payloads and runs transactions over a port
+type Device struct {
+    port io.ReadWriter
+
+    // Sleep waits between actions. It's time.Sleep unless replaced, for example in tests
+    Sleep func(time.Duration)
+}
+
+// NewDevice talks to a device over port, such as a serial port or a bus adapter. A Read that
+// returns no bytes, as go.bug.st/serial does when its read timeout passes, is a timeout
+func NewDevice(port io.ReadWriter) *Device {
+    return &Device{port: port, Sleep: time.Sleep}
+}
+
+// read fills buf, treating a read of nothing, or the end of the stream, as a timeout
+func (d *Device) read(buf []byte) error {
+    for n := 0; n < len(buf); {
+        m, err := d.port.Read(buf[n:])
+        n += m
+        switch {
+        case n == len(buf):
+            return nil
+        case errors.Is(err, io.EOF) || (err == nil && m == 0):
+            return fmt.Errorf("%w: read %d of %d bytes", ErrTimeout, n, len(buf))
+        case err != nil:
+            return err
+        }
+    }
+    return nil
+}
+
+func (d *Device) discardInput() error {
+    if r, ok := d.port.(inputResetter); ok {
+        return r.ResetInputBuffer()
+    }
+    return nil
+}
+"#;
+
+const RECEIVE_FRAMED: &str = r#"
+info := p.Info()
+var data []byte
+var b [1]byte
+for {
+    if err := d.read(b[:]); err != nil {
+        return err
+    }
+    data = append(data, b[0])
+    f, err := ParseFrame(RxFormat, data, info.FixedLen, false)
+    if err != nil {
+        return err
+    }
+    if f == nil {
+        continue
+    }
+    if !matches(info, f.Metadata) {
+        return fmt.Errorf("%w: expected %s, got metadata %v", ErrUnexpectedPayload, info.Name, f.Metadata)
+    }
+    return Decode(p, f.Payload)
+}
+"#;
+
+const TEST_SUPPORT: &str = r#"
+import (
+    "bytes"
+    "fmt"
+    "reflect"
+    "testing"
+    "time"
+)
+
+// expect reports a transaction returning something other than the samples it was sent
+func expect(got, want any, err error) error {
+    if err == nil && !reflect.DeepEqual(got, want) {
+        return fmt.Errorf("returned %+v, expected %+v", got, want)
+    }
+    return err
+}
+
+// fakePort replays canned responses in batches, the first straight away and another after each
+// write, and records what's written. An empty read is a timeout, as with a serial port
+type fakePort struct {
+    batches [][]byte
+    rx      bytes.Buffer
+    written [][]byte
+}
+
+func newFakePort(batches ...[]byte) *fakePort {
+    p := &fakePort{batches: batches}
+    p.respond()
+    return p
+}
+
+func (p *fakePort) respond() {
+    if len(p.batches) > 0 {
+        p.rx.Write(p.batches[0])
+        p.batches = p.batches[1:]
+    }
+}
+
+func (p *fakePort) Write(data []byte) (int, error) {
+    p.written = append(p.written, bytes.Clone(data))
+    p.respond()
+    return len(data), nil
+}
+
+func (p *fakePort) Read(buf []byte) (int, error) {
+    if p.rx.Len() == 0 {
+        return 0, nil
+    }
+    return p.rx.Read(buf)
+}
+"#;
+
+const ROUND_TRIP: &str = r#"
+data, err := Encode(tt.value)
+if err != nil {
+    t.Fatal(err)
+}
+if err := Decode(tt.decoded, data); err != nil {
+    t.Fatal(err)
+}
+again, err := Encode(tt.decoded)
+if err != nil {
+    t.Fatal(err)
+}
+if !bytes.Equal(data, again) {
+    t.Errorf("encoded % x, then % x after decoding", data, again)
+}
+"#;
+
+const ROUND_TRIP_FRAMED: &str = r#"
+frame, err := Pack(tt.value)
+if err != nil {
+    t.Fatal(err)
+}
+if err := Unpack(frame, tt.decoded); err != nil {
+    t.Fatal(err)
+}
+"#;
+
+const RUN_TRANSACTION: &str = r#"
+port := newFakePort(tt.responses...)
+d := NewDevice(port)
+d.Sleep = func(time.Duration) {}
+if err := tt.run(d); err != nil {
+    t.Fatal(err)
+}
+if len(port.batches) != 0 || port.rx.Len() != 0 {
+    t.Errorf("responses left unread")
+}
+if sent := len(port.written) > 0; sent != tt.sends {
+    t.Errorf("made %d writes", len(port.written))
+}
+"#;
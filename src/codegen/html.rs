@@ -0,0 +1,131 @@
+//! Renders a device's reference documentation as one standalone HTML file, with its styles
+//! inline, diagrams embedded and a table of contents, so it can be opened straight from disk or attached to a
+//! release. The content is the same as the `markdown` backend's.
+
+use super::docs::{Block, Inline, Page};
+use super::{BackendInfo, BackendOptions, Codegen, CodegenError, OptionInfo, OutputSink};
+use crate::ir::Ir;
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "html",
+    description: "Standalone HTML reference documentation, with layout tables and cross-links",
+    options: &[
+        OptionInfo { name: "file", description: "Name of the generated file", default: Some("the device's name in kebab case, with .html") },
+        OptionInfo { name: "diagrams", description: "Draw a packet layout diagram for each frame format, payload and struct", default: Some("true") },
+    ],
+    create: |options| Ok(Box::new(HtmlBackend::new(options)?)),
+};
+
+pub struct HtmlBackend {
+    file: Option<String>,
+    diagrams: bool,
+}
+
+impl HtmlBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        Ok(Self { file: options.get("file").map(str::to_owned), diagrams: options.get_bool("diagrams", true)? })
+    }
+}
+
+impl Codegen for HtmlBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let file = self.file.clone().unwrap_or_else(|| format!("{}.html", ir.device.name.kebab()));
+        out.write_text(&file, &render(&Page::build(ir, self.diagrams)))
+    }
+}
+
+const STYLE: &str = r#"
+body { font-family: system-ui, sans-serif; line-height: 1.5; color: #1f2328; margin: 0; display: flex; }
+nav { position: sticky; top: 0; height: 100vh; overflow-y: auto; min-width: 14rem; padding: 1rem; box-sizing: border-box; border-right: 1px solid #d0d7de; background: #f6f8fa; }
+nav ul { list-style: none; padding-left: 0; margin: 0; }
+nav li.sub { padding-left: 1rem; font-size: 0.9em; }
+main { padding: 1rem 2rem; max-width: 72rem; }
+h1, h2, h3 { scroll-margin-top: 1rem; }
+h2 { border-bottom: 1px solid #d0d7de; padding-bottom: 0.3rem; }
+table { border-collapse: collapse; margin: 1rem 0; }
+th, td { border: 1px solid #d0d7de; padding: 0.3rem 0.6rem; text-align: left; vertical-align: top; }
+th { background: #f6f8fa; }
+code { font-family: ui-monospace, monospace; font-size: 0.9em; background: #eff1f3; padding: 0.1em 0.3em; border-radius: 4px; }
+a { color: #0969da; text-decoration: none; }
+a:hover { text-decoration: underline; }
+:target { background: #fff8c5; }
+figure { margin: 1rem 0; overflow-x: auto; }
+figcaption { font-size: 0.9em; color: #57606a; }
+@media print { nav { display: none; } }
+"#;
+
+pub(crate) fn render(page: &Page) -> String {
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str("<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n");
+    out.push_str(&format!("<title>{}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n", escape(&page.title)));
+
+    out.push_str("<nav>\n<ul>\n");
+    for (level, anchor, text) in page.outline() {
+        let class = if level > 2 { " class=\"sub\"" } else { "" };
+        out.push_str(&format!("<li{class}><a href=\"#{}\">{}</a></li>\n", escape(anchor), escape(text)));
+    }
+    out.push_str("</ul>\n</nav>\n<main>\n");
+
+    for block in &page.blocks {
+        match block {
+            Block::Heading { level, anchor, text } => {
+                let id = anchor.as_ref().map(|a| format!(" id=\"{}\"", escape(a))).unwrap_or_default();
+                out.push_str(&format!("<h{level}{id}>{}</h{level}>\n", escape(text)));
+            }
+            Block::Paragraph(rich) => out.push_str(&format!("<p>{}</p>\n", inline(rich))),
+            Block::Table { columns, rows } => {
+                out.push_str("<table>\n<thead>\n<tr>");
+                for column in columns {
+                    out.push_str(&format!("<th>{}</th>", escape(column)));
+                }
+                out.push_str("</tr>\n</thead>\n<tbody>\n");
+                for row in rows {
+                    out.push_str("<tr>");
+                    for cell in row {
+                        out.push_str(&format!("<td>{}</td>", inline(cell)));
+                    }
+                    out.push_str("</tr>\n");
+                }
+                out.push_str("</tbody>\n</table>\n");
+            }
+            Block::List { ordered, items } => {
+                let tag = if *ordered { "ol" } else { "ul" };
+                out.push_str(&format!("<{tag}>\n"));
+                for item in items {
+                    out.push_str(&format!("<li>{}</li>\n", inline(item)));
+                }
+                out.push_str(&format!("</{tag}>\n"));
+            }
+            Block::Diagram { svg, caption, .. } => out.push_str(&format!("<figure>\n{svg}<figcaption>{}</figcaption>\n</figure>\n", escape(caption))),
+            // Mermaid needs a script to draw, and the page works offline. The steps list already says the same
+            Block::Sequence { .. } => (),
+        }
+    }
+    out.push_str("</main>\n</body>\n</html>\n");
+    out
+}
+
+fn inline(rich: &[Inline]) -> String {
+    rich.iter()
+        .map(|part| match part {
+            Inline::Text(text) => escape(text),
+            Inline::Code(text) => format!("<code>{}</code>", escape(text)),
+            Inline::Link { text, anchor } => format!("<a href=\"#{}\">{}</a>", escape(anchor), escape(text)),
+        })
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,459 @@
+//! Describes one direction's frames as a Kaitai Struct `.ksy` file, for its Web IDE, visualizer and
+//! parser generators. The frame envelope is the top level `seq`, with the payload as a `switch-on`
+//! of its metadata, and every payload and struct becomes a type.
+//!
+//! Kaitai can't check CRCs or read ones' complement numbers, so those are only documented. The
+//! reverse direction lives in `openpid::import::kaitai`
+
+use std::collections::BTreeSet;
+
+use super::docs::crc_name;
+use super::{BackendInfo, BackendOptions, CodeWriter, Codegen, CodegenError, OptionInfo, OutputSink};
+use crate::ir::*;
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "kaitai",
+    description: "Kaitai Struct (.ksy) description of one direction's frames",
+    options: &[OptionInfo { name: "direction", description: "Which frames to describe, tx or rx", default: Some("rx") }],
+    create: |options| Ok(Box::new(KaitaiBackend::new(options)?)),
+};
+
+pub struct KaitaiBackend {
+    direction: Direction,
+}
+
+impl KaitaiBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        let direction = match options.get_or("direction", "rx") {
+            "tx" => Direction::Tx,
+            "rx" => Direction::Rx,
+            other => return Err(CodegenError::BadOption { option: "direction".to_owned(), value: other.to_owned(), expected: "tx or rx" }),
+        };
+        Ok(Self { direction })
+    }
+}
+
+fn unsupported(what: impl Into<String>) -> CodegenError {
+    CodegenError::Unsupported { backend: "kaitai", what: what.into() }
+}
+
+impl Codegen for KaitaiBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let mut w = CodeWriter::new("  ");
+        w.line("meta:");
+        w.indent();
+        w.line(format!("id: {}", ir.device.name.snake()));
+        w.line(format!("title: {}", yaml_str(ir.device.name.raw())));
+        w.line("bit-endian: be");
+        w.dedent();
+        w.line(format!("doc: {}", yaml_str(&ir.device.description)));
+        w.line("seq:");
+        w.indent();
+        match &ir.framing {
+            Some(framing) => Envelope::new(ir, framing, self.direction).write(&mut w)?,
+            // without a frame format, a capture is a single payload, which is only known when there's one
+            None => match ir.payloads(self.direction) {
+                [payload] => attr(&mut w, "payload", &[("type", payload_type(payload))], None),
+                _ => return Err(unsupported("documents without a frame format, unless there's a single payload to describe")),
+            },
+        }
+        w.dedent();
+        w.line("types:");
+        w.indent();
+        for st in &ir.structs {
+            w.line(format!("{}:", st.name.snake()));
+            w.indent();
+            if let Some(description) = &st.description {
+                w.line(format!("doc: {}", yaml_str(description)));
+            }
+            fields(&mut w, &st.fields)?;
+            w.dedent();
+        }
+        for payload in ir.payloads(self.direction) {
+            w.line(format!("{}:", payload_type(payload)));
+            w.indent();
+            if !payload.description.is_empty() {
+                w.line(format!("doc: {}", yaml_str(&payload.description)));
+            }
+            fields(&mut w, &payload.fields)?;
+            w.dedent();
+        }
+        w.dedent();
+        let file = format!("{}_{}.ksy", ir.device.name.snake(), key(self.direction));
+        out.write_text(&file, &w.finish())
+    }
+}
+
+fn key(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Tx => "tx",
+        Direction::Rx => "rx",
+    }
+}
+
+/// Payload types are prefixed with their direction, so they can't clash with structs
+fn payload_type(payload: &Payload) -> String {
+    format!("{}_{}", key(payload.direction), payload.name.snake())
+}
+
+/// A YAML double quoted string
+fn yaml_str(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn byte_list(data: &[u8]) -> String {
+    format!("[{}]", data.iter().map(|b| format!("0x{b:02x}")).collect::<Vec<_>>().join(", "))
+}
+
+/// Writes one `seq` entry. Values are written as given, so strings need quoting by the caller
+fn attr(w: &mut CodeWriter, id: &str, properties: &[(&str, String)], doc: Option<String>) {
+    w.line(format!("- id: {id}"));
+    w.indent();
+    for (key, value) in properties {
+        w.line(format!("{key}: {value}"));
+    }
+    if let Some(doc) = doc {
+        w.line(format!("doc: {}", yaml_str(&doc)));
+    }
+    w.dedent();
+}
+
+/// The Kaitai type of an integer, and anything about it Kaitai can't express. Byte sized integers
+/// on byte boundaries get `u2le` style types, anything else is read as big-endian bits
+fn int_type(bits: u32, signing: Signing, endianness: Endianness, aligned: bool) -> (String, Vec<&'static str>) {
+    let mut notes = Vec::new();
+    if signing == Signing::OnesComplement {
+        notes.push("ones' complement");
+    }
+    if aligned && matches!(bits, 8 | 16 | 32 | 64) {
+        let sign = if signing == Signing::TwosComplement { "s" } else { "u" };
+        let endian = match (bits, endianness) {
+            (8, _) => "",
+            (_, Endianness::BigEndian) => "be",
+            (_, Endianness::LittleEndian) => "le",
+        };
+        return (format!("{sign}{}{endian}", bits / 8), notes);
+    }
+    if signing == Signing::TwosComplement {
+        notes.push("two's complement");
+    }
+    if endianness == Endianness::LittleEndian && bits > 8 {
+        notes.push("little-endian, so the bytes read here are reversed");
+    }
+    (format!("b{bits}"), notes)
+}
+
+/// A field's `doc`, with its units and anything its Kaitai type leaves out
+fn field_doc(description: Option<&str>, units: Option<&str>, notes: &[&str]) -> Option<String> {
+    let mut parts: Vec<String> = description.map(str::to_owned).into_iter().collect();
+    if let Some(units) = units {
+        parts.push(format!("Units: {units}"));
+    }
+    if !notes.is_empty() {
+        let notes = notes.join(", ");
+        parts.push(format!("{}{}", notes[..1].to_uppercase(), &notes[1..]));
+    }
+    (!parts.is_empty()).then(|| parts.join(". "))
+}
+
+/// Length properties of a bytes or string field
+fn length(len: &Length, what: &str) -> Result<Vec<(&'static str, String)>, CodegenError> {
+    Ok(match len {
+        Length::Fixed(n) => vec![("size", n.to_string())],
+        Length::Capacity(n) => vec![("size", n.to_string()), ("terminator", "0".to_owned()), ("pad-right", "0".to_owned())],
+        Length::CountField(name) => vec![("size", name.snake())],
+        Length::Sequence(sequence) if sequence.len() == 1 => vec![("terminator", sequence[0].to_string())],
+        Length::Sequence(_) => return Err(unsupported(format!("{what} ended by a sequence longer than one byte"))),
+        Length::Remainder => vec![("size-eos", "true".to_owned())],
+    })
+}
+
+fn fields(w: &mut CodeWriter, fields: &[Field]) -> Result<(), CodegenError> {
+    if fields.is_empty() {
+        w.line("seq: []");
+        return Ok(());
+    }
+    w.line("seq:");
+    w.indent();
+    for field in fields {
+        let id = field.name.snake();
+        let aligned = field.offset_bits.is_none_or(|offset| offset.is_multiple_of(8));
+        let description = field.description.as_deref();
+        let units = field.units.as_deref();
+        match &field.ty {
+            Type::Int { bits, signing, endianness } => {
+                let (ty, notes) = int_type(*bits, *signing, *endianness, aligned);
+                attr(w, &id, &[("type", ty)], field_doc(description, units, &notes));
+            }
+            Type::Float { bits, endianness } => {
+                let endian = if *endianness == Endianness::BigEndian { "be" } else { "le" };
+                attr(w, &id, &[("type", format!("f{}{endian}", bits / 8))], field_doc(description, units, &[]));
+            }
+            Type::Bytes(len) => attr(w, &id, &length(len, "bytes")?, field_doc(description, units, &[])),
+            Type::String(len) => {
+                let mut properties = vec![("type", "str".to_owned()), ("encoding", "UTF-8".to_owned())];
+                properties.extend(length(len, "strings")?);
+                attr(w, &id, &properties, field_doc(description, units, &[]));
+            }
+            Type::Const(data) => {
+                let bits = field.size.fixed_bits().unwrap_or(data.len() as u64 * 8);
+                if bits.is_multiple_of(8) && aligned {
+                    attr(w, &id, &[("contents", byte_list(&data[data.len() - bits as usize / 8..]))], field_doc(description, None, &[]));
+                } else {
+                    let value = data.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64) & (u64::MAX >> (64 - bits));
+                    attr(w, &id, &[("type", format!("b{bits}")), ("valid", format!("0x{value:x}"))], field_doc(description, None, &[]));
+                }
+            }
+            Type::Struct(name) => attr(w, &id, &[("type", name.snake())], field_doc(description, None, &[])),
+            Type::Array { item, len } => {
+                let repeat = match len {
+                    Length::Fixed(n) | Length::Capacity(n) => vec![("repeat", "expr".to_owned()), ("repeat-expr", n.to_string())],
+                    Length::CountField(name) => vec![("repeat", "expr".to_owned()), ("repeat-expr", name.snake())],
+                    Length::Remainder => vec![("repeat", "eos".to_owned())],
+                    Length::Sequence(_) => return Err(unsupported("arrays ended by a terminator")),
+                };
+                let mut properties = vec![("type", item.snake())];
+                properties.extend(repeat);
+                attr(w, &id, &properties, field_doc(description, None, &[]));
+            }
+        }
+    }
+    w.dedent();
+    Ok(())
+}
+
+/// Writes a frame format as the top level `seq`
+struct Envelope<'a> {
+    ir: &'a Ir,
+    framing: &'a Framing,
+    direction: Direction,
+
+    /// Bits from the start of the frame, to find the payload's offset from the end
+    position: u64,
+    ids: BTreeSet<String>,
+
+    /// Kaitai expression for the payload's size in bytes
+    payload_size: Option<String>,
+}
+
+impl<'a> Envelope<'a> {
+    fn new(ir: &'a Ir, framing: &'a Framing, direction: Direction) -> Self {
+        Self { ir, framing, direction, position: 0, ids: BTreeSet::new(), payload_size: None }
+    }
+
+    /// An attribute id that hasn't been used yet
+    fn id(&mut self, base: &str) -> String {
+        let mut id = base.to_owned();
+        let mut n = 1;
+        while !self.ids.insert(id.clone()) {
+            n += 1;
+            id = format!("{base}_{n}");
+        }
+        id
+    }
+
+    fn envelope_bits(&self) -> u64 {
+        self.framing.format(self.direction).iter().map(FrameElement::envelope_bits).sum()
+    }
+
+    fn write(mut self, w: &mut CodeWriter) -> Result<(), CodegenError> {
+        let elements = self.framing.format(self.direction);
+        if !self.envelope_bits().is_multiple_of(8) {
+            return Err(unsupported("frame envelopes that aren't whole bytes"));
+        }
+        self.elements(w, elements)
+    }
+
+    /// A size field's value in bytes, as an expression
+    fn bytes(id: &str, unit: BitsOrBytes, minus_bits: u64) -> String {
+        let value = match unit {
+            BitsOrBytes::Bytes => id.to_owned(),
+            BitsOrBytes::Bits => format!("{id} / 8"),
+        };
+        match minus_bits / 8 {
+            0 => value,
+            n => format!("{value} - {n}"),
+        }
+    }
+
+    fn size_attr(&mut self, w: &mut CodeWriter, base: &str, bits: u32, doc: &str) -> String {
+        let id = self.id(base);
+        let (ty, _) = int_type(bits, Signing::Unsigned, Endianness::BigEndian, self.position.is_multiple_of(8));
+        attr(w, &id, &[("type", ty)], Some(doc.to_owned()));
+        self.position += bits as u64;
+        id
+    }
+
+    fn elements(&mut self, w: &mut CodeWriter, elements: &[FrameElement]) -> Result<(), CodegenError> {
+        for element in elements {
+            match element {
+                FrameElement::SizeTotal { bits, unit } => {
+                    let id = self.size_attr(w, "frame_size", *bits, &format!("Size of the whole frame, in {}", unit_name(*unit)));
+                    self.payload_size = Some(Self::bytes(&id, *unit, self.envelope_bits()));
+                }
+                FrameElement::SizeOfPayload { bits, unit } => {
+                    let id = self.size_attr(w, "payload_size", *bits, &format!("Size of the payload, in {}", unit_name(*unit)));
+                    self.payload_size = Some(Self::bytes(&id, *unit, 0));
+                }
+                FrameElement::SizeOfElements { bits, unit, elements: covered } => {
+                    let id = self.size_attr(w, "size", *bits, &format!("Size of the {} elements after it, in {}", covered.len(), unit_name(*unit)));
+                    if contains_payload(covered) {
+                        let covered_bits: u64 = covered.iter().map(FrameElement::envelope_bits).sum();
+                        self.payload_size = Some(Self::bytes(&id, *unit, covered_bits));
+                    }
+                    self.elements(w, covered)?;
+                }
+                FrameElement::Payload => self.payload(w)?,
+                FrameElement::Metadata { name, ty, description } => {
+                    let id = self.id(&name.snake());
+                    let properties = match ty {
+                        Type::Int { bits, endianness, .. } => vec![("type", int_type(*bits, Signing::Unsigned, *endianness, self.position.is_multiple_of(8)).0)],
+                        Type::String(Length::Fixed(n) | Length::Capacity(n)) | Type::Bytes(Length::Fixed(n) | Length::Capacity(n)) => {
+                            vec![("type", "str".to_owned()), ("encoding", "ASCII".to_owned()), ("size", n.to_string()), ("terminator", "0".to_owned()), ("pad-right", "0".to_owned())]
+                        }
+                        _ => return Err(unsupported("metadata other than integers and fixed size strings")),
+                    };
+                    attr(w, &id, &properties, description.clone());
+                    self.position += element.envelope_bits();
+                }
+                FrameElement::Crc(crc) => {
+                    let id = self.id("crc");
+                    let bits = element.envelope_bits() as u32;
+                    let (ty, _) = int_type(bits, Signing::Unsigned, Endianness::BigEndian, self.position.is_multiple_of(8));
+                    attr(w, &id, &[("type", ty)], Some(format!("{} of every byte of the frame before it. Kaitai doesn't check it", crc_name(*crc))));
+                    self.position += bits as u64;
+                }
+                FrameElement::Const { data, bits, description } => {
+                    let id = self.id("magic");
+                    let bits = *bits as u64;
+                    if bits.is_multiple_of(8) && self.position.is_multiple_of(8) {
+                        attr(w, &id, &[("contents", byte_list(&data[data.len() - bits as usize / 8..]))], description.clone());
+                    } else {
+                        let value = data.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64) & (u64::MAX >> (64 - bits));
+                        attr(w, &id, &[("type", format!("b{bits}")), ("valid", format!("0x{value:x}"))], description.clone());
+                    }
+                    self.position += bits;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn payload(&mut self, w: &mut CodeWriter) -> Result<(), CodegenError> {
+        if !self.position.is_multiple_of(8) {
+            return Err(unsupported("payloads that don't start on a byte boundary"));
+        }
+        let payloads = self.ir.payloads(self.direction);
+        let size = match self.payload_size.take() {
+            Some(size) => Some(size),
+            // switched types read exactly their own size, so only variable size payloads need bounding
+            None if payloads.iter().all(|p| p.size.fixed_bits().is_some()) => None,
+            None => {
+                let after = self.envelope_bits() - self.position;
+                Some(match after / 8 {
+                    0 => "_io.size - _io.pos".to_owned(),
+                    n => format!("_io.size - _io.pos - {n}"),
+                })
+            }
+        };
+        let metadata = self.framing.metadata(self.direction);
+        let id = self.id("payload");
+        w.line(format!("- id: {id}"));
+        w.indent();
+        if let Some(size) = size {
+            w.line(format!("size: {size}"));
+        }
+        match (metadata.as_slice(), payloads) {
+            // a switch even with one case, so the importer knows the payload from the envelope
+            ([], [payload]) => {
+                w.line(format!("type: {}", payload_type(payload)));
+            }
+            ([], _) => return Err(unsupported("several payloads in a direction whose frames don't identify them")),
+            _ => {
+                let (switch_on, cases) = self.cases(&metadata)?;
+                w.line("type:");
+                w.indent();
+                w.line(format!("switch-on: {switch_on}"));
+                w.line("cases:");
+                w.indent();
+                for (value, ty) in cases {
+                    w.line(format!("{value}: {ty}"));
+                }
+                w.dedent();
+                w.dedent();
+            }
+        }
+        w.dedent();
+        Ok(())
+    }
+
+    /// The expression to switch payload types on, and the type for each of its values
+    fn cases(&self, metadata: &[(&Name, &Type)]) -> Result<(String, Vec<(String, String)>), CodegenError> {
+        let payloads = self.ir.payloads(self.direction);
+        if let [(name, Type::String(_) | Type::Bytes(_))] = metadata {
+            let mut cases = Vec::new();
+            for payload in payloads {
+                for value in payload.metadata(name.raw()).map(|m| m.values.as_slice()).unwrap_or_default() {
+                    // a Kaitai string literal, in a YAML single quoted key
+                    cases.push((format!("'{}'", yaml_str(&value.to_string()).replace('\'', "''")), payload_type(payload)));
+                }
+            }
+            return Ok((name.snake(), cases));
+        }
+        let widths = metadata
+            .iter()
+            .map(|(_, ty)| match ty {
+                Type::Int { bits, .. } => Ok(*bits),
+                _ => Err(unsupported("payloads identified by several metadata elements, unless they're all integers")),
+            })
+            .collect::<Result<Vec<u32>, _>>()?;
+        if widths.iter().sum::<u32>() > 64 {
+            return Err(unsupported("metadata elements over 64 bits in total"));
+        }
+        // several metadata elements are combined into one number, the first one most significant
+        let mut shift = 0;
+        let mut terms = Vec::new();
+        for ((name, _), bits) in metadata.iter().zip(&widths).rev() {
+            terms.push(if shift == 0 { name.snake() } else { format!("({} << {shift})", name.snake()) });
+            shift += bits;
+        }
+        terms.reverse();
+        let mut cases = Vec::new();
+        for payload in payloads {
+            let mut combined: Vec<u64> = vec![0];
+            for ((name, _), bits) in metadata.iter().zip(&widths) {
+                let values = payload.metadata(name.raw()).and_then(Metadata::packed).unwrap_or_default();
+                combined = combined.iter().flat_map(|c| values.iter().map(move |v| if *bits == 64 { *v } else { (c << bits) | v })).collect();
+            }
+            cases.extend(combined.into_iter().map(|value| (format!("0x{value:x}"), payload_type(payload))));
+        }
+        Ok((terms.join(" | "), cases))
+    }
+}
+
+fn contains_payload(elements: &[FrameElement]) -> bool {
+    elements.iter().any(|e| match e {
+        FrameElement::Payload => true,
+        FrameElement::SizeOfElements { elements, .. } => contains_payload(elements),
+        _ => false,
+    })
+}
+
+fn unit_name(unit: BitsOrBytes) -> &'static str {
+    match unit {
+        BitsOrBytes::Bits => "bits",
+        BitsOrBytes::Bytes => "bytes",
+    }
+}
@@ -0,0 +1,210 @@
+//! Sizing decisions shared by backends for languages without growable collections, where every
+//! variable length field needs a fixed capacity, and the sample values generated tests encode
+
+use super::CodegenError;
+use crate::ir::{Direction, Field, FlatElement, Ir, Length, Name, Return, Signing, Type};
+use crate::value::{Fields, Value};
+
+/// Both directions' frame formats, flattened for backends whose runtime interprets them as data, or
+/// `None` when payloads go unframed
+pub fn flat_formats(ir: &Ir, backend: &'static str) -> Result<Option<[Vec<FlatElement>; 2]>, CodegenError> {
+    let Some(framing) = &ir.framing else {
+        check_delimited(ir, backend)?;
+        return Ok(None);
+    };
+    let flatten = |direction| {
+        framing.flatten(direction).ok_or_else(|| CodegenError::Unsupported { backend, what: "metadata other than integers and strings up to 8 bytes".to_owned() })
+    };
+    Ok(Some([flatten(Direction::Tx)?, flatten(Direction::Rx)?]))
+}
+
+/// Without frames, only its size tells where an RX payload ends
+fn check_delimited(ir: &Ir, backend: &'static str) -> Result<(), CodegenError> {
+    match ir.rx.iter().find(|p| !p.size.is_fixed()) {
+        Some(p) => Err(CodegenError::Unsupported { backend, what: format!("variable size RX payload \"{}\" without a frame format to delimit it", p.name) }),
+        None => Ok(()),
+    }
+}
+
+/// The frame format of one direction, checked for what straight-line code can handle
+pub struct Format {
+    pub elements: Vec<FlatElement>,
+    pub envelope_bits: u64,
+}
+
+impl Format {
+    /// The TX and RX formats, or `None`s when payloads go unframed, checking the document only has
+    /// what straight-line code handles: one payload a frame, on a byte boundary like its CRC, and
+    /// metadata that packs into an integer
+    pub fn both(ir: &Ir, backend: &'static str) -> Result<[Option<Format>; 2], CodegenError> {
+        let unsupported = |what: String| CodegenError::Unsupported { backend, what };
+        let format = |direction: Direction| -> Result<Option<Format>, CodegenError> {
+            let Some(framing) = &ir.framing else {
+                return Ok(None);
+            };
+            let elements = framing.flatten(direction).ok_or_else(|| unsupported("metadata other than integers and strings up to 8 bytes".to_owned()))?;
+            if elements.iter().filter(|e| **e == FlatElement::Payload).count() != 1 {
+                return Err(unsupported(format!("{direction} frame formats without exactly one payload")));
+            }
+            // payloads are whole bytes, so static offsets decide alignment
+            let mut offset = 0;
+            for element in &elements {
+                if matches!(element, FlatElement::Payload | FlatElement::Crc(_)) && offset % 8 != 0 {
+                    return Err(unsupported(format!("a {direction} payload or CRC that doesn't start on a byte boundary")));
+                }
+                offset += element.bits();
+            }
+            Ok(Some(Format { envelope_bits: elements.iter().map(FlatElement::bits).sum(), elements }))
+        };
+        if ir.framing.is_none() {
+            check_delimited(ir, backend)?;
+        }
+        for p in ir.all_payloads() {
+            for m in p.metadata.iter().filter(|m| m.ty.is_some()) {
+                if m.packed().is_none() {
+                    return Err(unsupported(format!("metadata \"{}\" of payload \"{}\": only integers and strings up to 8 bytes", m.name, p.name)));
+                }
+            }
+        }
+        Ok([format(Direction::Tx)?, format(Direction::Rx)?])
+    }
+
+    /// Bits before the payload, which don't depend on it
+    pub fn payload_offset(&self) -> u64 {
+        self.elements.iter().take_while(|e| **e != FlatElement::Payload).map(FlatElement::bits).sum()
+    }
+}
+
+/// Capacities and worst-case sizes for one document
+#[derive(Debug, Clone, Copy)]
+pub struct Layout<'a> {
+    pub ir: &'a Ir,
+
+    /// Capacity given to variable length fields the document doesn't bound
+    pub max_len: u32,
+}
+
+impl<'a> Layout<'a> {
+    pub fn new(ir: &'a Ir, max_len: u32) -> Self {
+        Self { ir, max_len }
+    }
+
+    /// Element count bound for a variable length field among `fields`
+    pub fn cap(&self, len: &Length, fields: &[Field]) -> u32 {
+        match len {
+            Length::Fixed(n) | Length::Capacity(n) => *n,
+            Length::CountField(count) => {
+                let max = fields.iter().find(|f| f.name == *count).map_or(u64::MAX, |f| match f.ty {
+                    Type::Int { bits, .. } if bits < 64 => (1u64 << bits) - 1,
+                    _ => u64::MAX,
+                });
+                max.min(self.max_len as u64) as u32
+            }
+            Length::Sequence(_) | Length::Remainder => self.max_len,
+        }
+    }
+
+    /// Largest encoding of a type, given the capacities chosen for unbounded fields
+    pub fn max_bits(&self, ty: &Type, fields: &[Field]) -> u64 {
+        let terminator = |len: &Length| match len {
+            Length::Sequence(sequence) => sequence.len() as u64 * 8,
+            _ => 0,
+        };
+        match ty {
+            Type::Int { bits, .. } | Type::Float { bits, .. } => *bits as u64,
+            Type::Const(data) => data.len() as u64 * 8,
+            Type::Struct(name) => self.struct_max_bits(name),
+            Type::Bytes(len) | Type::String(len) => self.cap(len, fields) as u64 * 8 + terminator(len),
+            Type::Array { item, len } => self.cap(len, fields) as u64 * self.struct_max_bits(item) + terminator(len),
+        }
+    }
+
+    pub fn fields_max_bits(&self, fields: &[Field]) -> u64 {
+        fields.iter().map(|f| self.max_bits(&f.ty, fields)).sum()
+    }
+
+    pub fn struct_max_bits(&self, name: &Name) -> u64 {
+        self.ir.get_struct(name).map_or(0, |s| self.fields_max_bits(&s.fields))
+    }
+
+    /// Largest encoded payload in either direction, in bytes
+    pub fn max_payload_len(&self) -> u64 {
+        self.ir.all_payloads().map(|p| self.fields_max_bits(&p.fields).div_ceil(8)).max().unwrap_or(0)
+    }
+
+    /// Largest frame in a direction, in bytes, or largest payload without framing
+    pub fn max_frame_len(&self, direction: Direction) -> u64 {
+        let envelope = self.ir.framing.as_ref().map_or(0, |f| f.format(direction).iter().map(|e| e.envelope_bits()).sum());
+        let payload = self.ir.payloads(direction).iter().map(|p| self.fields_max_bits(&p.fields)).max().unwrap_or(0);
+        (envelope + payload).div_ceil(8)
+    }
+
+    /// The fields the value at the end of a transaction's return path sits among
+    pub fn return_siblings(&self, ret: &Return) -> &'a [Field] {
+        let mut fields = self.ir.get_payload(Direction::Rx, &ret.payload).map_or(&[][..], |p| &p.fields);
+        for name in &ret.path[..ret.path.len().saturating_sub(1)] {
+            fields = fields.iter().find(|f| f.name == *name).and_then(|f| self.ir.fields_of(&f.ty)).unwrap_or(&[]);
+        }
+        fields
+    }
+
+    /// The field a transaction's return value comes from
+    pub fn return_field(&self, ret: &Return) -> Option<&'a Field> {
+        self.return_siblings(ret).iter().find(|f| Some(&f.name) == ret.path.last())
+    }
+
+    /// Values for every field, including constants and counts, that fit their fields and the
+    /// capacities above and are never zero or empty where the field allows otherwise. `seed`
+    /// varies them, so that a struct's items differ from each other
+    pub fn sample_fields(&self, fields: &[Field], seed: u64) -> Fields {
+        let mut values = fields.iter().zip(seed..).map(|(f, seed)| (f.name.raw().to_owned(), self.sample(&f.ty, fields, seed))).collect::<Vec<_>>();
+        for (i, field) in fields.iter().enumerate() {
+            let counted = field.count_of.as_ref().and_then(|of| values.iter().find(|(name, _)| name == of.raw())).and_then(|(_, v)| v.element_count());
+            if let (Some(count), Type::Int { signing, .. }) = (counted, &field.ty) {
+                values[i].1 = if *signing == Signing::Unsigned { Value::UInt(count as u64) } else { Value::Int(count as i64) };
+            }
+        }
+        Fields(values)
+    }
+
+    /// The value a transaction returns for `ret` when it receives [Layout::sample_fields]
+    pub fn sample_return(&self, ret: &Return) -> Option<Value> {
+        let payload = self.ir.get_payload(Direction::Rx, &ret.payload)?;
+        let mut value = Value::Struct(self.sample_fields(&payload.fields, 0));
+        for name in &ret.path {
+            value = match value {
+                Value::Struct(fields) => fields.get(name.raw())?.clone(),
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    fn sample(&self, ty: &Type, fields: &[Field], seed: u64) -> Value {
+        // at most a few elements, avoiding any terminating sequence's bytes
+        let len = |len: &Length| match len {
+            Length::Fixed(n) => *n as usize,
+            _ => self.cap(len, fields).min(3) as usize,
+        };
+        let allowed = |b: &u8| !matches!(ty.length(), Some(Length::Sequence(sequence)) if sequence.contains(b));
+        match ty {
+            Type::Int { bits, signing, .. } => {
+                let magnitude = 0x8765_4321_FEDC_BA98u64.rotate_left(seed as u32 * 8) >> (64 - bits + 1).min(63);
+                match signing {
+                    Signing::Unsigned => Value::UInt(magnitude.max(1)),
+                    _ if *bits == 1 => Value::Int(if *signing == Signing::TwosComplement { -1 } else { 0 }),
+                    _ => Value::Int(-(magnitude.max(1) as i64)),
+                }
+            }
+            Type::Float { .. } => Value::Float(-1.25 * (seed + 1) as f64),
+            Type::Bytes(l) => Value::Bytes((0xA1u8..=0xFF).chain(0x01..0xA1).skip(seed as usize % 0xFF).filter(allowed).take(len(l)).collect()),
+            Type::String(l) => Value::String("openpid".bytes().cycle().skip(seed as usize % 7).filter(allowed).take(len(l)).map(char::from).collect()),
+            Type::Const(data) => Value::Bytes(data.clone()),
+            Type::Struct(name) => Value::Struct(self.ir.get_struct(name).map_or_else(Fields::new, |s| self.sample_fields(&s.fields, seed))),
+            Type::Array { item, len: l } => {
+                let fields = self.ir.get_struct(item).map_or(&[][..], |s| &s.fields);
+                Value::Array((0..len(l) as u64).map(|i| Value::Struct(self.sample_fields(fields, seed + i + 1))).collect())
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Renders a device's reference documentation as a single GitHub flavoured Markdown file, with
+//! a table per payload and struct, frame formats, and transactions as numbered steps. Headings
+//! carry `<a id>` anchors, so cross-links don't depend on how a site slugs heading text. Layout
+//! diagrams are written next to it as `diagrams/<anchor>.svg` and linked as images, and each
+//! transaction's sequence diagram is a `mermaid` code block, which GitHub and GitLab draw.
+
+use super::docs::{Block, Inline, Page};
+use super::{BackendInfo, BackendOptions, Codegen, CodegenError, OptionInfo, OutputSink};
+use crate::ir::Ir;
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "markdown",
+    description: "Markdown reference documentation, with layout tables and cross-links",
+    options: &[
+        OptionInfo { name: "file", description: "Name of the generated file", default: Some("the device's name in kebab case, with .md") },
+        OptionInfo { name: "diagrams", description: "Draw a packet layout diagram for each frame format, payload and struct", default: Some("true") },
+    ],
+    create: |options| Ok(Box::new(MarkdownBackend::new(options)?)),
+};
+
+pub struct MarkdownBackend {
+    file: Option<String>,
+    diagrams: bool,
+}
+
+impl MarkdownBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        Ok(Self { file: options.get("file").map(str::to_owned), diagrams: options.get_bool("diagrams", true)? })
+    }
+}
+
+impl Codegen for MarkdownBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let file = self.file.clone().unwrap_or_else(|| format!("{}.md", ir.device.name.kebab()));
+        let page = Page::build(ir, self.diagrams);
+        // image links are relative to the Markdown file
+        let dir = file.rsplit_once('/').map(|(dir, _)| format!("{dir}/")).unwrap_or_default();
+        for block in &page.blocks {
+            if let Block::Diagram { name, svg, .. } = block {
+                out.write_text(&format!("{dir}diagrams/{name}.svg"), svg)?;
+            }
+        }
+        out.write_text(&file, &render(&page))
+    }
+}
+
+pub(crate) fn render(page: &Page) -> String {
+    let mut out = String::new();
+    for block in &page.blocks {
+        match block {
+            Block::Heading { level, anchor, text } => {
+                if let Some(anchor) = anchor {
+                    out.push_str(&format!("<a id=\"{anchor}\"></a>\n\n"));
+                }
+                out.push_str(&format!("{} {}\n", "#".repeat(*level as usize), escape(text)));
+            }
+            Block::Paragraph(rich) => out.push_str(&format!("{}\n", inline(rich))),
+            Block::Table { columns, rows } => {
+                out.push_str(&format!("| {} |\n", columns.iter().map(|c| escape(c)).collect::<Vec<_>>().join(" | ")));
+                out.push_str(&format!("|{}\n", " --- |".repeat(columns.len())));
+                for row in rows {
+                    out.push_str(&format!("| {} |\n", row.iter().map(|cell| inline(cell)).collect::<Vec<_>>().join(" | ")));
+                }
+            }
+            Block::List { ordered, items } => {
+                for (i, item) in items.iter().enumerate() {
+                    match ordered {
+                        true => out.push_str(&format!("{}. {}\n", i + 1, inline(item))),
+                        false => out.push_str(&format!("- {}\n", inline(item))),
+                    }
+                }
+            }
+            Block::Diagram { name, caption, .. } => out.push_str(&format!("![{}](diagrams/{name}.svg)\n", escape(caption))),
+            Block::Sequence { mermaid } => out.push_str(&format!("```mermaid\n{mermaid}```\n")),
+        }
+        out.push('\n');
+    }
+    out.truncate(out.trim_end().len());
+    out.push('\n');
+    out
+}
+
+fn inline(rich: &[Inline]) -> String {
+    rich.iter()
+        .map(|part| match part {
+            Inline::Text(text) => escape(text),
+            Inline::Code(text) => code(text),
+            Inline::Link { text, anchor } => format!("[{}](#{anchor})", escape(text)),
+        })
+        .collect()
+}
+
+/// Escapes Markdown punctuation, and keeps text on one line so it can't break a table
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '|' | '#' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' | '\r' => out.push(' '),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// A code span, fenced with enough backticks for its contents. Pipes are still escaped, since
+/// tables split cells before parsing code spans
+fn code(text: &str) -> String {
+    let text = text.replace(['\n', '\r'], " ").replace('|', "\\|");
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest + 1);
+    let pad = if text.starts_with('`') || text.ends_with('`') { " " } else { "" };
+    format!("{fence}{pad}{text}{pad}{fence}")
+}
@@ -0,0 +1,259 @@
+//! Code generation. Backends turn a resolved [Ir] into files written to an [OutputSink], and are
+//! looked up by name in a [Registry], configured with [BackendOptions]
+//!
+//! A third party generator only needs to implement [Codegen]: spec resolution, validation and size
+//! analysis have already happened by the time it sees the [Ir]
+
+pub mod c;
+pub mod cpp;
+mod diagram;
+pub(crate) mod docs;
+pub mod go;
+pub mod html;
+pub mod kaitai;
+mod layout;
+pub mod markdown;
+pub mod micropython;
+mod output;
+pub mod python;
+pub mod rust;
+pub mod sequence;
+pub mod typescript;
+pub mod wireshark;
+pub mod zig;
+
+use std::{collections::BTreeMap, fmt::Display};
+
+pub use layout::Layout;
+pub use output::{check_path, CodeWriter, DirectorySink, OutputSink, VirtualTree};
+
+use crate::codec::{CodecError, Direction};
+use crate::ir::Ir;
+
+#[derive(Debug)]
+pub enum CodegenError {
+    IOError(std::io::Error),
+
+    /// A `struct_name` or `item_struct` naming a struct the document doesn't define
+    UnknownStruct { wanted_by: String, field: String, struct_name: String },
+
+    /// Every problem found while resolving a document, in the order they were found
+    Invalid(Vec<CodegenError>),
+
+    /// A size or layout problem, such as a recursive struct or an unsupported width
+    Codec(CodecError),
+
+    /// A `CountInPacket` terminator names a field that doesn't come before it, or isn't an integer
+    BadCountField { wanted_by: String, field: String, count_field: String },
+
+    /// An RX field with no way to know where it ends
+    UnterminatedRx { wanted_by: String, field: String },
+
+    UnknownPayload { transaction: String, direction: Direction, payload: String },
+
+    /// A transaction's `returns` entry that doesn't name a field of a payload it receives
+    BadReturn { transaction: String, value: String },
+
+    /// A payload without a value for one of its frame format's `Metadata` elements
+    MissingMetadata { payload: String, key: String },
+
+    /// Two names that are only distinguishable by case or punctuation, so would clash in generated code
+    NameCollision { kind: &'static str, first: String, second: String },
+
+    UnknownBackend { name: String },
+    UnknownOption { backend: String, option: String },
+    BadOption { option: String, value: String, expected: &'static str },
+
+    /// An option the backend can't do without in this build
+    MissingOption { backend: &'static str, option: &'static str, reason: &'static str },
+
+    /// A backend tried to write outside of its output directory, or wrote the same file twice
+    BadPath { path: String, reason: &'static str },
+
+    /// The document uses something this backend can't express
+    Unsupported { backend: &'static str, what: String },
+}
+
+impl Display for CodegenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodegenError::UnknownStruct { wanted_by, field, struct_name } => {
+                write!(f, "Undefined struct \"{struct_name}\" referenced by {wanted_by}->{field}")
+            }
+            CodegenError::IOError(e) => {
+                write!(f, "Input/Output Error: {:?}", e)
+            }
+            CodegenError::Invalid(errors) => {
+                write!(f, "Found {} problem(s) in the document:", errors.len())?;
+                for error in errors {
+                    write!(f, "\n  {error}")?;
+                }
+                Ok(())
+            }
+            CodegenError::Codec(e) => write!(f, "{e}"),
+            CodegenError::BadCountField { wanted_by, field, count_field } => {
+                write!(f, "{wanted_by}->{field} is counted by \"{count_field}\", which must be an integer field before it")
+            }
+            CodegenError::UnterminatedRx { wanted_by, field } => {
+                write!(f, "Unterminated unsized reads not possible. One was found in {wanted_by}->{field}")
+            }
+            CodegenError::UnknownPayload { transaction, direction, payload } => {
+                write!(f, "Undefined {direction} payload \"{payload}\" referenced by transaction \"{transaction}\"")
+            }
+            CodegenError::BadReturn { transaction, value } => {
+                write!(f, "Transaction \"{transaction}\" returns \"{value}\", which isn't a field of a payload it receives")
+            }
+            CodegenError::MissingMetadata { payload, key } => write!(f, "Payload \"{payload}\" has no value for metadata \"{key}\""),
+            CodegenError::NameCollision { kind, first, second } => {
+                write!(f, "{kind} names \"{first}\" and \"{second}\" would clash in generated code")
+            }
+            CodegenError::UnknownBackend { name } => write!(f, "No code generator named \"{name}\""),
+            CodegenError::UnknownOption { backend, option } => write!(f, "Backend \"{backend}\" has no option \"{option}\""),
+            CodegenError::BadOption { option, value, expected } => {
+                write!(f, "Option \"{option}\" should be {expected}, not \"{value}\"")
+            }
+            CodegenError::MissingOption { backend, option, reason } => write!(f, "The {backend} backend needs the \"{option}\" option: {reason}"),
+            CodegenError::BadPath { path, reason } => write!(f, "Can't write \"{path}\": {reason}"),
+            CodegenError::Unsupported { backend, what } => write!(f, "The {backend} backend doesn't support {what}"),
+        }
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+impl From<std::io::Error> for CodegenError {
+    fn from(value: std::io::Error) -> Self {
+        CodegenError::IOError(value)
+    }
+}
+
+impl From<CodecError> for CodegenError {
+    fn from(value: CodecError) -> Self {
+        CodegenError::Codec(value)
+    }
+}
+
+/// A code generator
+pub trait Codegen {
+    /// Writes this backend's files for `ir` into `out`, with paths relative to the output root
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError>;
+}
+
+/// Free-form `key=value` settings for a backend, for example `module=imu`. Each backend declares
+/// the options it understands in its [BackendInfo]
+#[derive(Debug, Clone, Default)]
+pub struct BackendOptions {
+    values: BTreeMap<String, String>,
+}
+
+impl BackendOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.values.insert(key.into(), value.into());
+        self
+    }
+
+    /// Parses and sets a `key=value` pair, as given on the command line. A bare `key` means `key=true`
+    pub fn parse(&mut self, option: &str) -> &mut Self {
+        match option.split_once('=') {
+            Some((key, value)) => self.set(key.trim(), value.trim()),
+            None => self.set(option.trim(), "true"),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn get_or<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        self.get(key).unwrap_or(default)
+    }
+
+    pub fn get_bool(&self, key: &str, default: bool) -> Result<bool, CodegenError> {
+        match self.get(key) {
+            None => Ok(default),
+            Some("true" | "yes" | "1") => Ok(true),
+            Some("false" | "no" | "0") => Ok(false),
+            Some(other) => Err(CodegenError::BadOption { option: key.to_owned(), value: other.to_owned(), expected: "true or false" }),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(String::as_str)
+    }
+}
+
+/// An option a backend understands, for `--help` style listings
+#[derive(Debug, Clone, Copy)]
+pub struct OptionInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub default: Option<&'static str>,
+}
+
+/// Describes a backend and how to build it from options
+#[derive(Debug, Clone, Copy)]
+pub struct BackendInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub options: &'static [OptionInfo],
+    pub create: fn(&BackendOptions) -> Result<Box<dyn Codegen>, CodegenError>,
+}
+
+/// Backends by name
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    backends: BTreeMap<&'static str, BackendInfo>,
+}
+
+impl Registry {
+    /// An empty registry, for tools that only want their own backends
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every backend that ships with this crate
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(rust::INFO);
+        registry.register(c::INFO);
+        registry.register(cpp::INFO);
+        registry.register(python::INFO);
+        registry.register(micropython::INFO);
+        registry.register(typescript::INFO);
+        registry.register(go::INFO);
+        registry.register(zig::INFO);
+        registry.register(markdown::INFO);
+        registry.register(html::INFO);
+        registry.register(sequence::INFO);
+        registry.register(wireshark::INFO);
+        registry.register(kaitai::INFO);
+        registry
+    }
+
+    /// Adds a backend, replacing any with the same name
+    pub fn register(&mut self, info: BackendInfo) -> &mut Self {
+        self.backends.insert(info.name, info);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BackendInfo> {
+        self.backends.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BackendInfo> {
+        self.backends.values()
+    }
+
+    /// Builds a backend, checking that it understands every option given
+    pub fn create(&self, name: &str, options: &BackendOptions) -> Result<Box<dyn Codegen>, CodegenError> {
+        let info = self.get(name).ok_or_else(|| CodegenError::UnknownBackend { name: name.to_owned() })?;
+        if let Some(option) = options.keys().find(|k| !info.options.iter().any(|o| o.name == *k)) {
+            return Err(CodegenError::UnknownOption { backend: name.to_owned(), option: option.to_owned() });
+        }
+        (info.create)(options)
+    }
+}
@@ -0,0 +1,186 @@
+//! Where generated files go, and a small helper for writing indented source
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use super::CodegenError;
+
+/// Receives a backend's files. Paths use `/` separators and are relative to the output root
+pub trait OutputSink {
+    fn write_file(&mut self, path: &str, contents: &[u8]) -> Result<(), CodegenError>;
+
+    fn write_text(&mut self, path: &str, contents: &str) -> Result<(), CodegenError> {
+        self.write_file(path, contents.as_bytes())
+    }
+}
+
+/// Checks that a backend's path stays inside the output root
+pub fn check_path(path: &str) -> Result<(), CodegenError> {
+    let bad = |reason| Err(CodegenError::BadPath { path: path.to_owned(), reason });
+    if path.is_empty() || path.starts_with('/') || path.contains('\\') || path.contains(':') {
+        return bad("paths must be relative and use / separators");
+    }
+    if path.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+        return bad("paths can't have empty, . or .. components");
+    }
+    Ok(())
+}
+
+/// Keeps generated files in memory, for tests and for tools that post-process the output
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VirtualTree {
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl VirtualTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, path: &str) -> Option<&[u8]> {
+        self.files.get(path).map(Vec::as_slice)
+    }
+
+    /// A file's contents, if it exists and is UTF8
+    pub fn get_text(&self, path: &str) -> Option<&str> {
+        self.get(path).and_then(|c| std::str::from_utf8(c).ok())
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.files.iter().map(|(p, c)| (p.as_str(), c.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Copies every file into another sink, e.g. a [DirectorySink] once generation succeeded
+    pub fn write_to(&self, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        for (path, contents) in &self.files {
+            out.write_file(path, contents)?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for VirtualTree {
+    fn write_file(&mut self, path: &str, contents: &[u8]) -> Result<(), CodegenError> {
+        check_path(path)?;
+        if self.files.contains_key(path) {
+            return Err(CodegenError::BadPath { path: path.to_owned(), reason: "already written" });
+        }
+        self.files.insert(path.to_owned(), contents.to_vec());
+        Ok(())
+    }
+}
+
+/// Writes files under a directory on disk, creating subdirectories as needed
+#[derive(Debug, Clone)]
+pub struct DirectorySink {
+    root: PathBuf,
+}
+
+impl DirectorySink {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl OutputSink for DirectorySink {
+    fn write_file(&mut self, path: &str, contents: &[u8]) -> Result<(), CodegenError> {
+        check_path(path)?;
+        let full = path.split('/').fold(self.root.clone(), |acc, part| acc.join(part));
+        if let Some(parent) = full.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(full, contents)?;
+        Ok(())
+    }
+}
+
+/// Builds up source text line by line, tracking indentation
+#[derive(Debug, Clone)]
+pub struct CodeWriter {
+    out: String,
+    level: usize,
+    unit: &'static str,
+}
+
+impl CodeWriter {
+    /// `unit` is one level of indentation, like `"    "` or `"\t"`
+    pub fn new(unit: &'static str) -> Self {
+        Self { out: String::new(), level: 0, unit }
+    }
+
+    /// Writes one line at the current indentation. Embedded newlines start new, equally indented lines
+    pub fn line(&mut self, text: impl AsRef<str>) -> &mut Self {
+        for line in text.as_ref().split('\n') {
+            if !line.is_empty() {
+                for _ in 0..self.level {
+                    self.out.push_str(self.unit);
+                }
+                self.out.push_str(line);
+            }
+            self.out.push('\n');
+        }
+        self
+    }
+
+    pub fn blank(&mut self) -> &mut Self {
+        self.out.push('\n');
+        self
+    }
+
+    /// Writes each line of `text` behind a comment marker such as `"///"` or `"#"`
+    pub fn comment(&mut self, marker: &str, text: &str) -> &mut Self {
+        for line in text.trim().lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                self.line(marker);
+            } else {
+                self.line(format!("{marker} {line}"));
+            }
+        }
+        self
+    }
+
+    pub fn indent(&mut self) -> &mut Self {
+        self.level += 1;
+        self
+    }
+
+    pub fn dedent(&mut self) -> &mut Self {
+        self.level = self.level.saturating_sub(1);
+        self
+    }
+
+    /// Writes `open`, the body one level deeper, then `close` unless it's empty
+    pub fn block(&mut self, open: impl AsRef<str>, close: impl AsRef<str>, body: impl FnOnce(&mut Self)) -> &mut Self {
+        self.line(open);
+        self.indent();
+        body(self);
+        self.dedent();
+        if !close.as_ref().is_empty() {
+            self.line(close);
+        }
+        self
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
@@ -0,0 +1,1241 @@
+//! Generates a typed Python package: `dataclass` payloads encoded with `struct`, `IntEnum`s of
+//! metadata values, and a device class over a pyserial-like port or an smbus2-like bus.
+//!
+//! Frame formats are emitted as tuples of element objects and interpreted by the package's
+//! `wire` module, which follows `openpid_runtime::frame`.
+
+use super::layout::flat_formats;
+use super::{BackendInfo, BackendOptions, CodeWriter, Codegen, CodegenError, Layout, OptionInfo, OutputSink};
+use crate::ir::*;
+use crate::value::{Fields, Value};
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "python",
+    description: "Python package with dataclass payloads, for pyserial and smbus2",
+    options: &[
+        OptionInfo { name: "package", description: "Name of the Python package", default: Some("the device's name, in snake case") },
+        OptionInfo { name: "tests", description: "Generate a pytest suite", default: Some("true") },
+    ],
+    create: |options| Ok(Box::new(PythonBackend::new(options)?)),
+};
+
+pub struct PythonBackend {
+    package: Option<String>,
+    tests: bool,
+}
+
+impl PythonBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        Ok(Self { package: options.get("package").map(str::to_owned), tests: options.get_bool("tests", true)? })
+    }
+
+    fn pyproject(&self, ir: &Ir, package: &str) -> String {
+        // doc_version is free-form, so only use it when it looks like a release number
+        let version = ir
+            .doc_version
+            .as_deref()
+            .filter(|v| !v.is_empty() && v.split('.').all(|p| p.parse::<u64>().is_ok()))
+            .unwrap_or("0.1.0");
+        format!(
+            "[project]\nname = {:?}\nversion = {version:?}\ndescription = {:?}\nrequires-python = \">=3.8\"\ndependencies = []\n\n\
+             [project.optional-dependencies]\nserial = [\"pyserial\"]\ni2c = [\"smbus2\"]\ntest = [\"pytest\"]\n\n\
+             [build-system]\nrequires = [\"setuptools>=61\"]\nbuild-backend = \"setuptools.build_meta\"\n\n\
+             [tool.setuptools]\npackages = [{package:?}]\n",
+            package.replace('_', "-"),
+            format!("Driver for the {}, generated from its OpenPID document", ir.device.name),
+        )
+    }
+}
+
+impl Codegen for PythonBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let package = self.package.clone().unwrap_or_else(|| ident(&ir.device.name));
+        let gen = Gen { ir, framed: ir.framing.is_some() };
+        let formats = flat_formats(ir, "python")?;
+
+        out.write_text("pyproject.toml", &self.pyproject(ir, &package))?;
+        out.write_text(&format!("{package}/__init__.py"), &gen.init())?;
+        out.write_text(&format!("{package}/wire.py"), &format!("{}{}", header("Bit packing, CRCs and frame envelopes the generated modules share"), WIRE))?;
+        out.write_text(&format!("{package}/structs.py"), &gen.structs())?;
+        out.write_text(&format!("{package}/tx.py"), &gen.payloads(Direction::Tx)?)?;
+        out.write_text(&format!("{package}/rx.py"), &gen.payloads(Direction::Rx)?)?;
+        if let Some(formats) = &formats {
+            out.write_text(&format!("{package}/frame.py"), &gen.frame(formats))?;
+        }
+        out.write_text(&format!("{package}/device.py"), &gen.device())?;
+        if self.tests {
+            out.write_text(&format!("tests/test_{package}.py"), &gen.tests(&package))?;
+        }
+        Ok(())
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif", "else", "except",
+    "finally", "for", "from", "global", "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try",
+    "while", "with", "yield",
+];
+
+/// A snake case identifier, with a trailing underscore if it's a keyword
+pub(crate) fn ident(name: &Name) -> String {
+    let snake = name.snake();
+    if KEYWORDS.contains(&snake.as_str()) {
+        format!("{snake}_")
+    } else if snake.starts_with(|c: char| c.is_ascii_digit()) || snake.is_empty() {
+        format!("_{snake}")
+    } else {
+        snake
+    }
+}
+
+pub(crate) fn class_name(name: &Name) -> String {
+    let pascal = name.pascal();
+    if pascal.starts_with(|c: char| c.is_ascii_digit()) || pascal.is_empty() {
+        format!("_{pascal}")
+    } else {
+        pascal
+    }
+}
+
+/// A dataclass attribute, kept clear of the methods and helpers payload classes use
+pub(crate) fn attr(name: &Name) -> String {
+    match ident(name).as_str() {
+        "encode" | "decode" | "pack" | "unpack" | "matches" | "field" => format!("{}_", name.snake()),
+        other => other.to_owned(),
+    }
+}
+
+/// A local variable in decode functions, kept clear of the names they use
+fn local(name: &Name) -> String {
+    match ident(name).as_str() {
+        "reader" | "writer" | "cls" | "self" | "wire" | "structs" | "frame" => format!("{}_", name.snake()),
+        other => other.to_owned(),
+    }
+}
+
+pub(crate) fn bytes_literal(data: &[u8]) -> String {
+    format!("b\"{}\"", data.iter().map(|b| format!("\\x{b:02x}")).collect::<String>())
+}
+
+/// A tuple literal, with the trailing comma a single element needs
+pub(crate) fn tuple(items: impl IntoIterator<Item = String>) -> String {
+    let items = items.into_iter().collect::<Vec<_>>();
+    match items.as_slice() {
+        [item] => format!("({item},)"),
+        _ => format!("({})", items.join(", ")),
+    }
+}
+
+pub(crate) fn docstring(w: &mut CodeWriter, text: &str) {
+    let text = text.trim().replace('\\', "\\\\").replace("\"\"\"", "\\\"\\\"\\\"");
+    if text.is_empty() {
+        return;
+    }
+    if text.lines().count() == 1 {
+        w.line(format!("\"\"\"{text}\"\"\""));
+    } else {
+        w.line(format!("\"\"\"{text}\n\"\"\""));
+    }
+}
+
+fn header(summary: &str) -> String {
+    format!("\"\"\"{summary}\n\nGenerated from an OpenPID document by `openpid gen python`. Don't edit by hand\n\"\"\"\n\n")
+}
+
+/// The `struct` format for an integer or float, if there is one
+fn struct_format(ty: &Type) -> Option<String> {
+    let order = |endianness: &Endianness| if *endianness == Endianness::LittleEndian { '<' } else { '>' };
+    match ty {
+        Type::Int { bits, signing, endianness } if *signing != Signing::OnesComplement => {
+            let code = match bits {
+                8 => 'b',
+                16 => 'h',
+                32 => 'i',
+                64 => 'q',
+                _ => return None,
+            };
+            let code = if *signing == Signing::Unsigned { code.to_ascii_uppercase() } else { code };
+            Some(format!("{}{code}", order(endianness)))
+        }
+        Type::Float { bits: 32, endianness } => Some(format!("{}f", order(endianness))),
+        Type::Float { endianness, .. } => Some(format!("{}d", order(endianness))),
+        _ => None,
+    }
+}
+
+fn signing(signing: Signing) -> &'static str {
+    match signing {
+        Signing::Unsigned => "wire.UNSIGNED",
+        Signing::TwosComplement => "wire.TWOS_COMPLEMENT",
+        Signing::OnesComplement => "wire.ONES_COMPLEMENT",
+    }
+}
+
+fn element(element: &FlatElement) -> String {
+    let unit = |unit: &BitsOrBytes| match unit {
+        BitsOrBytes::Bits => "wire.BITS",
+        BitsOrBytes::Bytes => "wire.BYTES",
+    };
+    match element {
+        FlatElement::SizeTotal { bits, unit: u } => format!("wire.SizeTotal({bits}, {})", unit(u)),
+        FlatElement::SizeOfPayload { bits, unit: u } => format!("wire.SizeOfPayload({bits}, {})", unit(u)),
+        FlatElement::SizeOfElements { bits, unit: u, covers, .. } => format!("wire.SizeOfElements({bits}, {}, {covers})", unit(u)),
+        FlatElement::Payload => "wire.Payload()".to_owned(),
+        FlatElement::Metadata { bits, endianness, .. } => {
+            format!("wire.Metadata({bits}{})", if *endianness == Endianness::LittleEndian { ", little=True" } else { "" })
+        }
+        FlatElement::Crc(Crc::Crc32) => "wire.Crc(wire.CRC32)".to_owned(),
+        FlatElement::Crc(Crc::Crc16XModem) => "wire.Crc(wire.CRC16_XMODEM)".to_owned(),
+        FlatElement::Const { data, bits } => format!("wire.Const({}, {bits})", bytes_literal(data)),
+    }
+}
+
+struct Gen<'a> {
+    ir: &'a Ir,
+    framed: bool,
+}
+
+impl Gen<'_> {
+    fn device_class(&self) -> String {
+        let name = class_name(&self.ir.device.name);
+        match name.as_str() {
+            "Serial" | "I2C" | "Transport" | "Timeout" | "UnexpectedPayload" => format!("{name}Device"),
+            _ => name,
+        }
+    }
+
+    /// Name of a transaction's method, kept clear of the device's own attributes
+    fn method_name(transaction: &Transaction) -> String {
+        match ident(&transaction.name).as_str() {
+            name @ ("transport" | "sleep" | "send" | "receive") => format!("{name}_transaction"),
+            name => name.to_owned(),
+        }
+    }
+
+    fn init(&self) -> String {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("    ");
+        let description = format!("Driver for the {}: {}", ir.device.name, ir.device.description);
+        w.line(header(description.trim_end_matches([':', ' '])).trim_end());
+        w.blank();
+        w.line(format!("from .device import I2C, Serial, Timeout, UnexpectedPayload, {}", self.device_class()));
+        w.line("from .wire import CodecError");
+        w.blank();
+        let mut all = vec!["CodecError", "I2C", "Serial", "Timeout", "UnexpectedPayload"];
+        let device = self.device_class();
+        all.push(&device);
+        all.sort_unstable();
+        w.line(format!("__all__ = [{}]", all.iter().map(|n| format!("\"{n}\"")).collect::<Vec<_>>().join(", ")));
+        w.finish()
+    }
+
+    fn python_type(ty: &Type) -> String {
+        match ty {
+            Type::Int { .. } => "int".to_owned(),
+            Type::Float { .. } => "float".to_owned(),
+            Type::Bytes(_) | Type::Const(_) => "bytes".to_owned(),
+            Type::String(_) => "str".to_owned(),
+            Type::Struct(name) => format!("structs.{}", class_name(name)),
+            Type::Array { item, .. } => format!("list[structs.{}]", class_name(item)),
+        }
+    }
+
+    fn default(ty: &Type) -> String {
+        match ty {
+            Type::Int { .. } => "0".to_owned(),
+            Type::Float { .. } => "0.0".to_owned(),
+            Type::Bytes(Length::Fixed(n)) => format!("bytes({n})"),
+            Type::Bytes(_) | Type::Const(_) => "b\"\"".to_owned(),
+            Type::String(Length::Fixed(n)) => format!("\"\\0\" * {n}"),
+            Type::String(_) => "\"\"".to_owned(),
+            Type::Struct(name) => format!("field(default_factory=structs.{})", class_name(name)),
+            Type::Array { item, len: Length::Fixed(n) } => {
+                format!("field(default_factory=lambda: [structs.{}() for _ in range({n})])", class_name(item))
+            }
+            Type::Array { .. } => "field(default_factory=list)".to_owned(),
+        }
+    }
+
+    fn structs(&self) -> String {
+        let mut w = CodeWriter::new("    ");
+        w.line(header("Structs shared between payloads").trim_end());
+        w.blank();
+        w.line("from __future__ import annotations");
+        w.blank();
+        w.line("from dataclasses import dataclass, field");
+        w.blank();
+        // structs that use other structs name them `structs.Name`, like the payload modules do. They
+        // come after the structs they use, so the module has them by then
+        w.line("from . import structs, wire");
+        for s in &self.ir.structs {
+            w.blank();
+            w.blank();
+            Self::class(&mut w, &s.name, s.description.as_deref(), &s.fields, s.size, "wire.Codec", None);
+        }
+        w.finish()
+    }
+
+    fn payloads(&self, direction: Direction) -> Result<String, CodegenError> {
+        let mut w = CodeWriter::new("    ");
+        let base = match (self.framed, direction) {
+            (false, _) => "wire.Codec",
+            (true, Direction::Tx) => "frame.TxPayload",
+            (true, Direction::Rx) => "frame.RxPayload",
+        };
+        w.line(header(&format!("Payloads {}", if direction == Direction::Tx { "sent to the device" } else { "received from the device" })).trim_end());
+        w.blank();
+        w.line("from __future__ import annotations");
+        w.blank();
+        w.line("from dataclasses import dataclass, field");
+        w.blank();
+        w.line(if self.framed { "from . import frame, structs, wire" } else { "from . import structs, wire" });
+        for p in self.ir.payloads(direction) {
+            let metadata = p
+                .metadata
+                .iter()
+                .filter(|m| m.ty.is_some())
+                .map(|m| {
+                    m.packed().ok_or_else(|| CodegenError::Unsupported {
+                        backend: "python",
+                        what: format!("metadata \"{}\" of payload \"{}\": only integers and strings up to 8 bytes", m.name, p.name),
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let metadata = self.framed.then(|| {
+                let values = metadata.iter().map(|values| tuple(values.iter().map(|v| format!("{v:#x}")))).collect::<Vec<_>>();
+                format!("METADATA = {}", tuple(values))
+            });
+            w.blank();
+            w.blank();
+            Self::class(&mut w, &p.name, Some(&p.description), &p.fields, p.size, base, metadata);
+        }
+        Ok(w.finish())
+    }
+
+    fn class(w: &mut CodeWriter, name: &Name, description: Option<&str>, fields: &[Field], size: Size, base: &str, metadata: Option<String>) {
+        let class = class_name(name);
+        w.line("@dataclass");
+        w.block(format!("class {class}({base}):"), "", |w| {
+            if let Some(description) = description.filter(|d| !d.trim().is_empty()) {
+                docstring(w, description);
+                w.blank();
+            }
+            let mut user_facing = fields.iter().filter(|f| f.is_user_facing()).peekable();
+            if user_facing.peek().is_some() {
+                for f in user_facing {
+                    w.line(format!("{}: {} = {}", attr(&f.name), Self::python_type(&f.ty), Self::default(&f.ty)));
+                    if let Some(description) = &f.description {
+                        docstring(w, description);
+                    }
+                }
+                w.blank();
+            }
+            w.line(format!("FIXED_LEN = {}", size.fixed_bits().map_or("None".to_owned(), |bits| bits.div_ceil(8).to_string())));
+            if let Some(metadata) = metadata {
+                w.line(metadata);
+            }
+            w.blank();
+            w.block("def _encode_to(self, writer: wire.Writer) -> None:", "", |w| {
+                if fields.is_empty() {
+                    w.line("pass");
+                }
+                for f in fields {
+                    Self::encode_field(w, f, fields);
+                }
+            });
+            w.blank();
+            w.line("@classmethod");
+            w.block(format!("def _decode_from(cls, reader: wire.Reader) -> {class}:"), "", |w| {
+                for f in fields {
+                    Self::decode_field(w, f);
+                }
+                let args = fields.iter().filter(|f| f.is_user_facing()).map(|f| format!("{}={}", attr(&f.name), local(&f.name))).collect::<Vec<_>>();
+                w.line(format!("return cls({})", args.join(", ")));
+            });
+        });
+    }
+
+    fn encode_field(w: &mut CodeWriter, f: &Field, fields: &[Field]) {
+        let value = format!("self.{}", attr(&f.name));
+        match &f.ty {
+            ty @ Type::Int { bits, signing: s, endianness } => {
+                let value = match &f.count_of {
+                    Some(counted) => match fields.iter().find(|c| c.name == *counted).map(|c| &c.ty) {
+                        Some(Type::String(_)) => format!("len(self.{}.encode())", attr(counted)),
+                        _ => format!("len(self.{})", attr(counted)),
+                    },
+                    None => value,
+                };
+                match struct_format(ty) {
+                    Some(format) => w.line(format!("writer.pack(\"{format}\", {value})")),
+                    None => w.line(format!(
+                        "writer.put_int({bits}, {value}, {}{})",
+                        signing(*s),
+                        if *endianness == Endianness::LittleEndian { ", little=True" } else { "" }
+                    )),
+                };
+            }
+            ty @ Type::Float { .. } => {
+                w.line(format!("writer.pack(\"{}\", {value})", struct_format(ty).unwrap_or_default()));
+            }
+            Type::Const(data) => {
+                w.line(format!("writer.put_bytes({})", bytes_literal(data)));
+            }
+            Type::Struct(_) => {
+                w.line(format!("{value}._encode_to(writer)"));
+            }
+            Type::Bytes(len) | Type::String(len) => {
+                let bytes = if matches!(f.ty, Type::String(_)) { format!("{value}.encode()") } else { value };
+                match len {
+                    Length::Fixed(n) => w.line(format!("writer.put_fixed({bytes}, {n})")),
+                    Length::Capacity(n) => w.line(format!("writer.put_padded({bytes}, {n})")),
+                    Length::CountField(_) | Length::Remainder => w.line(format!("writer.put_bytes({bytes})")),
+                    Length::Sequence(sequence) => {
+                        w.line(format!("writer.put_bytes({bytes})"));
+                        w.line(format!("writer.put_bytes({})", bytes_literal(sequence)))
+                    }
+                };
+            }
+            Type::Array { len, .. } => {
+                if let Length::Fixed(n) = len {
+                    w.line(format!("wire.check_len({n}, len({value}))"));
+                }
+                w.block(format!("for item in {value}:"), "", |w| {
+                    w.line("item._encode_to(writer)");
+                });
+                if let Length::Sequence(sequence) = len {
+                    w.line(format!("writer.put_bytes({})", bytes_literal(sequence)));
+                }
+            }
+        }
+    }
+
+    fn decode_field(w: &mut CodeWriter, f: &Field) {
+        let l = if f.count_of.is_some() { format!("n_{}", f.name.snake()) } else { local(&f.name) };
+        let count = |len: &Length| match len {
+            Length::Fixed(n) | Length::Capacity(n) => n.to_string(),
+            Length::CountField(c) => format!("n_{}", c.snake()),
+            Length::Sequence(_) | Length::Remainder => "reader.remaining // 8".to_owned(),
+        };
+        match &f.ty {
+            ty @ Type::Int { bits, signing: s, endianness } => {
+                match struct_format(ty) {
+                    Some(format) => w.line(format!("{l} = reader.unpack(\"{format}\")")),
+                    None => w.line(format!(
+                        "{l} = reader.get_int({bits}, {}{})",
+                        signing(*s),
+                        if *endianness == Endianness::LittleEndian { ", little=True" } else { "" }
+                    )),
+                };
+                if f.count_of.is_some() && *s != Signing::Unsigned {
+                    w.block(format!("if {l} < 0:"), "", |w| {
+                        w.line(format!("raise wire.CodecError(\"negative count {{{l}}}\")").replace("(\"", "(f\""));
+                    });
+                }
+            }
+            ty @ Type::Float { .. } => {
+                w.line(format!("{l} = reader.unpack(\"{}\")", struct_format(ty).unwrap_or_default()));
+            }
+            Type::Const(data) => {
+                w.line(format!("reader.expect({})", bytes_literal(data)));
+            }
+            Type::Struct(name) => {
+                w.line(format!("{l} = structs.{}._decode_from(reader)", class_name(name)));
+            }
+            Type::Bytes(len) | Type::String(len) => {
+                let bytes = match len {
+                    Length::Sequence(sequence) => format!("reader.read_until({})", bytes_literal(sequence)),
+                    _ => format!("reader.get_bytes({})", count(len)),
+                };
+                match (&f.ty, len) {
+                    (Type::String(_), Length::Capacity(_)) => w.line(format!("{l} = wire.to_str({bytes}.rstrip(b\"\\0\"))")),
+                    (Type::String(_), _) => w.line(format!("{l} = wire.to_str({bytes})")),
+                    _ => w.line(format!("{l} = {bytes}")),
+                };
+            }
+            Type::Array { item, len } => {
+                let item = format!("structs.{}", class_name(item));
+                match len {
+                    Length::Sequence(sequence) => {
+                        w.line(format!("{l} = []"));
+                        w.block(format!("while not reader.take({}):", bytes_literal(sequence)), "", |w| {
+                            w.line(format!("{l}.append({item}._decode_from(reader))"));
+                        });
+                    }
+                    Length::Remainder => {
+                        w.line(format!("{l} = []"));
+                        w.block("while reader.remaining >= 8:", "", |w| {
+                            w.line(format!("{l}.append({item}._decode_from(reader))"));
+                        });
+                    }
+                    _ => {
+                        w.line(format!("{l} = [{item}._decode_from(reader) for _ in range({})]", count(len)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn frame(&self, formats: &[Vec<FlatElement>; 2]) -> String {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("    ");
+        w.line(header("Frame formats from the document's `[uart]` section").trim_end());
+        w.blank();
+        w.line("from __future__ import annotations");
+        w.blank();
+        w.line("from enum import IntEnum");
+        w.blank();
+        w.line("from . import wire");
+        for (direction, format) in [Direction::Tx, Direction::Rx].into_iter().zip(formats) {
+            let upper = direction.to_string().to_uppercase();
+            let pascal = if direction == Direction::Tx { "Tx" } else { "Rx" };
+            w.blank();
+            if direction == Direction::Rx {
+                w.blank();
+            }
+            w.block(format!("{upper}_FORMAT = ("), ")", |w| {
+                for e in format {
+                    w.line(format!("{},", element(e)));
+                }
+            });
+            w.line(format!("{upper}_ENVELOPE_BITS = wire.envelope_bits({upper}_FORMAT)"));
+            for (i, (key, _)) in ir.framing.iter().flat_map(|f| f.metadata(direction)).enumerate() {
+                let values = ir
+                    .payloads(direction)
+                    .iter()
+                    .filter_map(|p| Some((p, p.metadata(key.raw())?.packed()?.first().copied()?)))
+                    .collect::<Vec<_>>();
+                if values.is_empty() {
+                    continue;
+                }
+                w.blank();
+                w.blank();
+                w.block(format!("class {pascal}{}(IntEnum):", class_name(key)), "", |w| {
+                    docstring(w, &format!("Values of the \"{key}\" metadata, element {i} of {direction} frames"));
+                    w.blank();
+                    for (p, value) in &values {
+                        w.line(format!("{} = {value:#x}", p.name.screaming()));
+                    }
+                });
+            }
+            w.blank();
+            w.blank();
+            w.block(format!("class {pascal}Payload(wire.Codec):"), "", |w| {
+                docstring(w, &format!("A payload in {direction} frames"));
+                w.blank();
+                w.line("# accepted values of each metadata element, sent with the first");
+                w.line("METADATA: tuple = ()");
+                w.blank();
+                w.block("def pack(self) -> bytes:", "", |w| {
+                    docstring(w, "Encodes the payload wrapped in its frame");
+                    w.line(format!("return wire.encode({upper}_FORMAT, [values[0] for values in self.METADATA], self.encode())"));
+                });
+                w.blank();
+                w.line("@classmethod");
+                w.block("def unpack(cls, data: bytes):", "", |w| {
+                    docstring(w, "Decodes a buffer holding exactly one frame of this payload");
+                    w.line(format!("found = wire.decode({upper}_FORMAT, data)"));
+                    w.block("if not cls.matches(found.metadata):", "", |w| {
+                        w.line("raise wire.CodecError(f\"frame metadata {found.metadata} isn't {cls.__name__}'s\")");
+                    });
+                    w.line("return cls.decode(found.payload)");
+                });
+                w.blank();
+                w.line("@classmethod");
+                w.block("def matches(cls, metadata) -> bool:", "", |w| {
+                    w.line("return all(value in accepted for value, accepted in zip(metadata, cls.METADATA))");
+                });
+            });
+        }
+        w.finish()
+    }
+
+    fn device(&self) -> String {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("    ");
+        w.line(header(&format!("A driver for the {} over pyserial or smbus2", ir.device.name)).trim_end());
+        w.blank();
+        w.line("from __future__ import annotations");
+        w.blank();
+        w.line("import time");
+        w.line("from dataclasses import dataclass");
+        w.blank();
+        w.line(if self.framed { "from . import frame, rx, structs, tx, wire" } else { "from . import rx, structs, tx, wire" });
+        w.blank();
+        w.blank();
+        w.line(TRANSPORTS.trim());
+        for transaction in &ir.transactions {
+            if transaction.returns.len() > 1 {
+                w.blank();
+                w.blank();
+                w.line("@dataclass");
+                w.block(format!("class {}Response:", class_name(&transaction.name)), "", |w| {
+                    docstring(w, &format!("What `{}` returns", Self::method_name(transaction)));
+                    w.blank();
+                    for ret in &transaction.returns {
+                        w.line(format!("{}: {}", ident(&ret.name), Self::python_type(&ret.ty)));
+                    }
+                });
+            }
+        }
+        w.blank();
+        w.blank();
+        w.block(format!("class {}:", self.device_class()), "", |w| {
+            docstring(w, &ir.device.description);
+            w.blank();
+            w.block("def __init__(self, transport, sleep=time.sleep):", "", |w| {
+                docstring(w, "`transport` is a `Serial` or `I2C`. `sleep` takes seconds, and is swapped out in tests");
+                w.line("self.transport = transport");
+                w.line("self.sleep = sleep");
+            });
+            w.blank();
+            w.block("def send(self, payload) -> None:", "", |w| {
+                w.line(if self.framed { "self.transport.write(payload.pack())" } else { "self.transport.write(payload.encode())" });
+            });
+            w.blank();
+            w.block("def receive(self, payload_type):", "", |w| {
+                docstring(w, "Reads one payload of the given type");
+                if self.framed {
+                    w.line(RECEIVE_FRAMED.trim());
+                } else {
+                    w.line("# without a frame format, every payload has a fixed size");
+                    w.line("return payload_type.decode(self.transport.read(payload_type.FIXED_LEN))");
+                }
+            });
+            for transaction in &ir.transactions {
+                w.blank();
+                self.transaction(w, transaction);
+            }
+        });
+        w.finish()
+    }
+
+    fn transaction(&self, w: &mut CodeWriter, transaction: &Transaction) {
+        let params = self.ir.transaction_params(transaction);
+        let mut signature = vec!["self".to_owned()];
+        for payload in &params {
+            signature.push(format!("{}: tx.{}", local(payload), class_name(payload)));
+        }
+        let returns = match transaction.returns.as_slice() {
+            [] => "None".to_owned(),
+            [ret] => Self::python_type(&ret.ty),
+            _ => format!("{}Response", class_name(&transaction.name)),
+        };
+        w.block(format!("def {}({}) -> {returns}:", Self::method_name(transaction), signature.join(", ")), "", |w| {
+            docstring(w, &transaction.description);
+            for action in &transaction.actions {
+                match action {
+                    Action::Tx(payload) if params.contains(&payload) => w.line(format!("self.send({})", local(payload))),
+                    Action::Tx(payload) => w.line(format!("self.send(tx.{}())", class_name(payload))),
+                    Action::Rx(payload) if transaction.returns.iter().any(|r| r.payload == *payload) => {
+                        w.line(format!("{}_rx = self.receive(rx.{})", payload.snake(), class_name(payload)))
+                    }
+                    Action::Rx(payload) => w.line(format!("self.receive(rx.{})", class_name(payload))),
+                    Action::Sleep { milliseconds } => w.line(format!("self.sleep({})", *milliseconds as f64 / 1000.0)),
+                    Action::Flush => w.line("self.transport.discard_input()"),
+                };
+            }
+            let value = |ret: &Return| format!("{}_rx.{}", ret.payload.snake(), ret.path.iter().map(attr).collect::<Vec<_>>().join("."));
+            match transaction.returns.as_slice() {
+                [] => {}
+                [ret] => {
+                    w.line(format!("return {}", value(ret)));
+                }
+                returns => {
+                    let args = returns.iter().map(|ret| format!("{}={}", ident(&ret.name), value(ret))).collect::<Vec<_>>();
+                    w.line(format!("return {}Response({})", class_name(&transaction.name), args.join(", ")));
+                }
+            }
+        });
+    }
+
+    /// A sample value as a Python expression
+    fn literal(&self, value: &Value, ty: &Type) -> String {
+        match (value, ty) {
+            (Value::Struct(values), Type::Struct(name)) => self.sample(&format!("structs.{}", class_name(name)), values, self.ir.fields_of(ty).unwrap_or(&[])),
+            (Value::Array(items), Type::Array { item, .. }) => {
+                format!("[{}]", items.iter().map(|i| self.literal(i, &Type::Struct(item.clone()))).collect::<Vec<_>>().join(", "))
+            }
+            (Value::Bytes(b), _) => bytes_literal(b),
+            (Value::String(text), _) => format!("{text:?}"),
+            (Value::Float(f), _) => format!("{f:?}"),
+            (value, _) => value.to_string(),
+        }
+    }
+
+    /// A dataclass built from sample values for its user facing fields
+    fn sample(&self, class: &str, values: &Fields, fields: &[Field]) -> String {
+        let args = fields
+            .iter()
+            .filter(|f| f.is_user_facing())
+            .filter_map(|f| Some(format!("{}={}", attr(&f.name), self.literal(values.get(f.name.raw())?, &f.ty))))
+            .collect::<Vec<_>>();
+        format!("{class}({})", args.join(", "))
+    }
+
+    fn tests(&self, package: &str) -> String {
+        let ir = self.ir;
+        // lists grow, so only count fields limit how many samples there are
+        let layout = Layout::new(ir, u32::MAX);
+        let payload_sample = |direction: Direction, name: &Name| {
+            let module = if direction == Direction::Tx { "tx" } else { "rx" };
+            let fields = ir.get_payload(direction, name).map_or(&[][..], |p| &p.fields);
+            self.sample(&format!("{module}.{}", class_name(name)), &layout.sample_fields(fields, 0), fields)
+        };
+        let mut w = CodeWriter::new("    ");
+        w.line("\"\"\"Checks generated alongside the driver: every payload survives an encode/decode round trip,");
+        w.line("and every transaction runs against an in-memory fake port. Run with `pytest`");
+        w.line("\"\"\"");
+        w.blank();
+        let mut modules = vec!["rx", "tx"];
+        if ir.transactions.iter().any(|t| t.returns.len() > 1) {
+            modules.insert(0, "device");
+        }
+        if !ir.structs.is_empty() {
+            modules.push("structs");
+        }
+        modules.sort_unstable();
+        w.line(format!("from {package} import Serial, {}, {}", self.device_class(), modules.join(", ")));
+        w.blank();
+        w.blank();
+        w.line(TEST_FAKES.trim());
+        for p in ir.all_payloads() {
+            let module = if p.direction == Direction::Tx { "tx" } else { "rx" };
+            let class = format!("{module}.{}", class_name(&p.name));
+            w.blank();
+            w.blank();
+            w.block(format!("def test_roundtrip_{module}_{}():", p.name.snake()), "", |w| {
+                w.line(format!("value = {}", payload_sample(p.direction, &p.name)));
+                w.line(format!("assert {class}.decode(value.encode()) == value"));
+                if self.framed {
+                    w.line(format!("assert {class}.unpack(value.pack()) == value"));
+                }
+            });
+        }
+        for transaction in &ir.transactions {
+            let responses = transaction
+                .actions
+                .iter()
+                .filter_map(|a| match a {
+                    Action::Rx(payload) => Some(format!("{}.{}()", payload_sample(Direction::Rx, payload), if self.framed { "pack" } else { "encode" })),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let args = self.ir.transaction_params(transaction).iter().map(|p| payload_sample(Direction::Tx, p)).collect::<Vec<_>>();
+            let value = |ret: &Return| Some(self.literal(&layout.sample_return(ret)?, &ret.ty));
+            let expected = match transaction.returns.as_slice() {
+                [] => None,
+                [ret] => value(ret),
+                returns => {
+                    let args = returns.iter().map(|r| Some(format!("{}={}", ident(&r.name), value(r)?))).collect::<Option<Vec<_>>>();
+                    args.map(|args| format!("device.{}Response({})", class_name(&transaction.name), args.join(", ")))
+                }
+            };
+            let sends = transaction.actions.iter().any(|a| matches!(a, Action::Tx(_)));
+            w.blank();
+            w.blank();
+            w.block(format!("def test_transaction_{}():", transaction.name.snake()), "", |w| {
+                w.line(format!("port = FakePort({})", if responses.is_empty() { "b\"\"".to_owned() } else { responses.join(" + ") }));
+                w.line(format!("driver = {}(Serial(port), sleep=lambda seconds: None)", self.device_class()));
+                let call = format!("driver.{}({})", Self::method_name(transaction), args.join(", "));
+                match &expected {
+                    Some(expected) => w.line(format!("assert {call} == {expected}")),
+                    None => w.line(call),
+                };
+                w.line("assert not port.rx");
+                w.line(if sends { "assert port.tx" } else { "assert not port.tx" });
+            });
+        }
+        w.finish()
+    }
+}
+
+const WIRE: &str = r#"from __future__ import annotations
+
+import struct
+from dataclasses import dataclass
+
+UNSIGNED = "unsigned"
+TWOS_COMPLEMENT = "twos_complement"
+ONES_COMPLEMENT = "ones_complement"
+
+BITS = "bits"
+BYTES = "bytes"
+
+CRC32 = "crc32"
+CRC16_XMODEM = "crc16_xmodem"
+
+
+class CodecError(ValueError):
+    """A value doesn't fit its field, or data doesn't decode as the document says"""
+
+
+class Incomplete(CodecError):
+    """The data ended early"""
+
+
+def check_len(expected: int, found: int) -> None:
+    if expected != found:
+        raise CodecError(f"expected {expected} elements, found {found}")
+
+
+def to_str(data: bytes) -> str:
+    try:
+        return data.decode()
+    except UnicodeDecodeError as e:
+        raise CodecError(f"invalid UTF-8: {e}") from None
+
+
+def to_raw(value: int, bits: int, signing: str) -> int:
+    """The unsigned bit pattern of a signed value, range checked"""
+    if signing == UNSIGNED:
+        if not 0 <= value < 1 << bits:
+            raise CodecError(f"{value} doesn't fit in {bits} unsigned bits")
+        return value
+    if bits == 0:
+        if value != 0:
+            raise CodecError(f"{value} doesn't fit in 0 bits")
+        return 0
+    mask = (1 << bits) - 1
+    high = (1 << (bits - 1)) - 1
+    low = -high if signing == ONES_COMPLEMENT else -high - 1
+    if not low <= value <= high:
+        raise CodecError(f"{value} doesn't fit in {bits} signed bits")
+    if value < 0 and signing == ONES_COMPLEMENT:
+        return ~(-value) & mask
+    return value & mask
+
+
+def from_raw(raw: int, bits: int, signing: str) -> int:
+    if signing == UNSIGNED or bits == 0 or not (raw >> (bits - 1)) & 1:
+        return raw
+    mask = (1 << bits) - 1
+    if signing == ONES_COMPLEMENT:
+        return -(~raw & mask)
+    return raw - (1 << bits)
+
+
+def swap_bytes(value: int, bits: int) -> int:
+    return int.from_bytes(value.to_bytes(bits // 8, "big"), "little")
+
+
+class Writer:
+    """Packs values most significant bit first"""
+
+    def __init__(self) -> None:
+        self._value = 0
+        self.bits = 0
+
+    def put_uint(self, bits: int, value: int) -> None:
+        if not 0 <= value < 1 << bits:
+            raise CodecError(f"{value} doesn't fit in {bits} bits")
+        self._value = (self._value << bits) | value
+        self.bits += bits
+
+    def put_int(self, bits: int, value: int, signing: str = UNSIGNED, little: bool = False) -> None:
+        raw = to_raw(value, bits, signing)
+        self.put_uint(bits, swap_bytes(raw, bits) if little else raw)
+
+    def pack(self, fmt: str, value) -> None:
+        try:
+            data = struct.pack(fmt, value)
+        except struct.error as e:
+            raise CodecError(f"{value!r}: {e}") from None
+        self.put_bytes(data)
+
+    def put_bytes(self, data: bytes) -> None:
+        self.put_uint(len(data) * 8, int.from_bytes(data, "big"))
+
+    def put_fixed(self, data: bytes, length: int) -> None:
+        check_len(length, len(data))
+        self.put_bytes(data)
+
+    def put_padded(self, data: bytes, capacity: int) -> None:
+        """Writes data, then NULs up to capacity"""
+        if len(data) > capacity:
+            raise CodecError(f"{len(data)} bytes don't fit in {capacity}")
+        self.put_bytes(data + bytes(capacity - len(data)))
+
+    def finish(self) -> bytes:
+        pad = -self.bits % 8
+        return (self._value << pad).to_bytes((self.bits + pad) // 8, "big")
+
+
+class Reader:
+    """Unpacks values most significant bit first"""
+
+    def __init__(self, data: bytes) -> None:
+        self.data = bytes(data)
+        self.pos = 0
+
+    @property
+    def remaining(self) -> int:
+        """Bits left to read"""
+        return len(self.data) * 8 - self.pos
+
+    def get_uint(self, bits: int) -> int:
+        if bits > self.remaining:
+            raise Incomplete(f"needed {bits} bits, {self.remaining} left")
+        if bits == 0:
+            return 0
+        start, end = self.pos // 8, (self.pos + bits + 7) // 8
+        chunk = int.from_bytes(self.data[start:end], "big")
+        self.pos += bits
+        return (chunk >> (end * 8 - self.pos)) & ((1 << bits) - 1)
+
+    def get_int(self, bits: int, signing: str = UNSIGNED, little: bool = False) -> int:
+        raw = self.get_uint(bits)
+        return from_raw(swap_bytes(raw, bits) if little else raw, bits, signing)
+
+    def unpack(self, fmt: str):
+        return struct.unpack(fmt, self.get_bytes(struct.calcsize(fmt)))[0]
+
+    def get_bytes(self, length: int) -> bytes:
+        if self.pos % 8 or length * 8 > self.remaining:
+            return self.get_uint(length * 8).to_bytes(length, "big")
+        start = self.pos // 8
+        self.pos += length * 8
+        return self.data[start : start + length]
+
+    def expect(self, data: bytes) -> None:
+        if self.get_bytes(len(data)) != data:
+            raise CodecError("constant bytes don't match")
+
+    def take(self, sequence: bytes) -> bool:
+        """Consumes sequence if it comes next"""
+        if self.remaining < len(sequence) * 8:
+            return False
+        pos = self.pos
+        if self.get_bytes(len(sequence)) == sequence:
+            return True
+        self.pos = pos
+        return False
+
+    def read_until(self, sequence: bytes) -> bytes:
+        """Reads up to and including sequence, returning the bytes before it"""
+        out = bytearray()
+        while not self.take(sequence):
+            out += self.get_bytes(1)
+        return bytes(out)
+
+    def finish(self) -> None:
+        if self.remaining >= 8:
+            raise CodecError(f"{self.remaining} bits left over")
+
+
+class Codec:
+    """Encoding shared by structs and payloads, which implement `_encode_to` and `_decode_from`"""
+
+    # size in bytes, when it doesn't vary
+    FIXED_LEN = None
+
+    def encode(self) -> bytes:
+        writer = Writer()
+        self._encode_to(writer)
+        return writer.finish()
+
+    @classmethod
+    def decode(cls, data: bytes):
+        reader = Reader(data)
+        value = cls._decode_from(reader)
+        reader.finish()
+        return value
+
+
+def crc32(data: bytes) -> int:
+    crc = 0xFFFFFFFF
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ 0xEDB88320 if crc & 1 else crc >> 1
+    return crc ^ 0xFFFFFFFF
+
+
+def crc16_xmodem(data: bytes) -> int:
+    crc = 0
+    for byte in data:
+        crc ^= byte << 8
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x1021 if crc & 0x8000 else crc << 1) & 0xFFFF
+    return crc
+
+
+CRCS = {CRC32: (crc32, 32), CRC16_XMODEM: (crc16_xmodem, 16)}
+
+
+@dataclass(frozen=True)
+class SizeTotal:
+    bits: int
+    unit: str
+
+
+@dataclass(frozen=True)
+class SizeOfPayload:
+    bits: int
+    unit: str
+
+
+@dataclass(frozen=True)
+class SizeOfElements:
+    """Size of the `covers` elements that immediately follow this one"""
+
+    bits: int
+    unit: str
+    covers: int
+
+
+@dataclass(frozen=True)
+class Payload:
+    @property
+    def bits(self) -> int:
+        return 0
+
+
+@dataclass(frozen=True)
+class Metadata:
+    bits: int
+    little: bool = False
+
+
+@dataclass(frozen=True)
+class Crc:
+    algorithm: str
+
+    @property
+    def bits(self) -> int:
+        return CRCS[self.algorithm][1]
+
+
+@dataclass(frozen=True)
+class Const:
+    """The last `bits` bits of `data`"""
+
+    data: bytes
+    bits: int
+
+
+@dataclass
+class Frame:
+    metadata: tuple
+    payload: bytes
+
+    # length of the whole frame in bytes
+    length: int
+
+
+def envelope_bits(fmt) -> int:
+    return sum(e.bits for e in fmt)
+
+
+def _covered_bits(fmt, index: int, covers: int, payload_bits: int) -> int:
+    return sum(payload_bits if isinstance(e, Payload) else e.bits for e in fmt[index + 1 : index + 1 + covers])
+
+
+def _size_value(bits: int, unit: str) -> int:
+    return bits if unit == BITS else (bits + 7) // 8
+
+
+def _size_bits(value: int, unit: str) -> int:
+    return value if unit == BITS else value * 8
+
+
+def encode(fmt, metadata, payload: bytes) -> bytes:
+    """Wraps payload in a frame, with a value for each `Metadata` element in order"""
+    payload_bits = len(payload) * 8
+    total = envelope_bits(fmt) + payload_bits
+    writer = Writer()
+    metadata = list(metadata)
+    if len(metadata) != sum(isinstance(e, Metadata) for e in fmt):
+        raise CodecError(f"the frame format needs a value for each metadata element, got {metadata}")
+    metadata = iter(metadata)
+    for i, element in enumerate(fmt):
+        if isinstance(element, SizeTotal):
+            writer.put_uint(element.bits, _size_value(total, element.unit))
+        elif isinstance(element, SizeOfPayload):
+            writer.put_uint(element.bits, _size_value(payload_bits, element.unit))
+        elif isinstance(element, SizeOfElements):
+            writer.put_uint(element.bits, _size_value(_covered_bits(fmt, i, element.covers, payload_bits), element.unit))
+        elif isinstance(element, Payload):
+            writer.put_bytes(payload)
+        elif isinstance(element, Metadata):
+            writer.put_int(element.bits, next(metadata), little=element.little)
+        elif isinstance(element, Crc):
+            if writer.bits % 8:
+                raise CodecError(f"CRC at bit {writer.bits} isn't byte aligned")
+            compute, bits = CRCS[element.algorithm]
+            writer.put_uint(bits, compute(writer.finish()))
+        else:
+            writer.put_uint(element.bits, int.from_bytes(element.data, "big") & ((1 << element.bits) - 1))
+    return writer.finish()
+
+
+def decode(fmt, data: bytes) -> Frame:
+    """Decodes a buffer holding exactly one frame"""
+    return parse(fmt, data)
+
+
+def parse(fmt, data: bytes, payload_len=None, complete: bool = True):
+    """Parses a frame from the start of data. payload_len is the payload's size in bytes, for
+    formats without a size field. Unless complete is set, running out of data returns None so this
+    can be called again as more bytes arrive
+    """
+    try:
+        return _parse(fmt, bytes(data), payload_len, complete)
+    except Incomplete:
+        if complete:
+            raise
+        return None
+
+
+def _parse(fmt, data: bytes, payload_len, complete: bool) -> Frame:
+    payload_index = next((i for i, e in enumerate(fmt) if isinstance(e, Payload)), None)
+    envelope = envelope_bits(fmt)
+    reader = Reader(data)
+    metadata = []
+    payload = b""
+    total = None
+    payload_bits = None
+    for i, element in enumerate(fmt):
+        if isinstance(element, SizeTotal):
+            total = _size_bits(reader.get_uint(element.bits), element.unit)
+            if total < envelope:
+                raise CodecError(f"frame size {total} bits is smaller than its envelope")
+            if total > len(data) * 8:
+                raise Incomplete(f"frame is {total} bits, have {len(data) * 8}")
+        elif isinstance(element, SizeOfPayload):
+            payload_bits = _size_bits(reader.get_uint(element.bits), element.unit)
+        elif isinstance(element, SizeOfElements):
+            region = _size_bits(reader.get_uint(element.bits), element.unit)
+            if payload_index is not None and i < payload_index <= i + element.covers:
+                payload_bits = region - _covered_bits(fmt, i, element.covers, 0)
+                if payload_bits < 0:
+                    raise CodecError("size field is smaller than the elements it covers")
+        elif isinstance(element, Payload):
+            bits = payload_bits
+            if bits is None and total is not None:
+                bits = total - envelope
+            elif bits is None and payload_len is not None:
+                bits = payload_len * 8
+            elif bits is None and complete:
+                bits = max(reader.remaining - envelope_bits(fmt[i + 1 :]), 0)
+            elif bits is None:
+                raise CodecError("the frame doesn't say how long its payload is")
+            if bits % 8:
+                raise CodecError(f"payload of {bits} bits isn't whole bytes")
+            payload = reader.get_bytes(bits // 8)
+        elif isinstance(element, Metadata):
+            metadata.append(reader.get_int(element.bits, little=element.little))
+        elif isinstance(element, Crc):
+            if reader.pos % 8:
+                raise CodecError(f"CRC at bit {reader.pos} isn't byte aligned")
+            compute, bits = CRCS[element.algorithm]
+            expected = compute(data[: reader.pos // 8])
+            found = reader.get_uint(bits)
+            if expected != found:
+                raise CodecError(f"CRC mismatch: expected {expected:#x}, found {found:#x}")
+        elif reader.get_uint(element.bits) != int.from_bytes(element.data, "big") & ((1 << element.bits) - 1):
+            raise CodecError("constant bits don't match")
+    if total is not None and (total + 7) // 8 != (reader.pos + 7) // 8:
+        raise CodecError(f"frame size {total} bits doesn't match its contents")
+    if complete and reader.remaining >= 8:
+        raise CodecError(f"{reader.remaining} bits left over after the frame")
+    return Frame(tuple(metadata), payload, (reader.pos + 7) // 8)
+"#;
+
+const TRANSPORTS: &str = r#"
+class Timeout(IOError):
+    """The device didn't answer in time"""
+
+
+class UnexpectedPayload(wire.CodecError):
+    """The device answered with a different payload than the transaction expects"""
+
+
+class Serial:
+    """A UART, through a pyserial `Serial` or anything with its `write`, `read` and
+    `reset_input_buffer`. Reads honour the port's own timeout
+    """
+
+    # bytes arrive as a stream, rather than in reads sized by the driver
+    streaming = True
+
+    def __init__(self, port) -> None:
+        self.port = port
+
+    def write(self, data: bytes) -> None:
+        self.port.write(data)
+        if hasattr(self.port, "flush"):
+            self.port.flush()
+
+    def read(self, length: int) -> bytes:
+        data = bytes(self.port.read(length))
+        if len(data) < length:
+            raise Timeout(f"read {len(data)} of {length} bytes")
+        return data
+
+    def discard_input(self) -> None:
+        """Drops anything received but not yet read"""
+        self.port.reset_input_buffer()
+
+
+class I2C:
+    """A device on an I2C bus, through an smbus2 `SMBus` or anything with its `i2c_rdwr`.
+    `i2c_msg` defaults to smbus2's
+    """
+
+    streaming = False
+
+    def __init__(self, bus, address: int, i2c_msg=None) -> None:
+        if i2c_msg is None:
+            from smbus2 import i2c_msg
+        self.bus = bus
+        self.address = address
+        self.i2c_msg = i2c_msg
+
+    def write(self, data: bytes) -> None:
+        self.bus.i2c_rdwr(self.i2c_msg.write(self.address, data))
+
+    def read(self, length: int) -> bytes:
+        message = self.i2c_msg.read(self.address, length)
+        self.bus.i2c_rdwr(message)
+        return bytes(message)
+
+    def discard_input(self) -> None:
+        pass
+"#;
+
+const RECEIVE_FRAMED: &str = r#"if not self.transport.streaming and payload_type.FIXED_LEN is not None:
+    # the frame's size is known, so read it in one go
+    length = (frame.RX_ENVELOPE_BITS + payload_type.FIXED_LEN * 8 + 7) // 8
+    found = wire.decode(frame.RX_FORMAT, self.transport.read(length))
+else:
+    buffer = bytearray()
+    found = None
+    while found is None:
+        buffer += self.transport.read(1)
+        found = wire.parse(frame.RX_FORMAT, buffer, payload_type.FIXED_LEN, complete=False)
+if not payload_type.matches(found.metadata):
+    raise UnexpectedPayload(f"expected {payload_type.__name__}, got metadata {found.metadata}")
+return payload_type.decode(found.payload)"#;
+
+const TEST_FAKES: &str = r#"
+class FakePort:
+    """An in-memory stand-in for a pyserial port that replays canned responses and records writes"""
+
+    def __init__(self, responses: bytes) -> None:
+        self.rx = bytearray(responses)
+        self.tx = bytearray()
+
+    def write(self, data: bytes) -> int:
+        self.tx += data
+        return len(data)
+
+    def read(self, length: int) -> bytes:
+        data = bytes(self.rx[:length])
+        del self.rx[:length]
+        return data
+
+    def reset_input_buffer(self) -> None:
+        # responses are all queued up front, so flushing mustn't drop them
+        pass
+"#;
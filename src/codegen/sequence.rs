@@ -0,0 +1,228 @@
+//! Draws each transaction as a sequence diagram between the host and the device, in Mermaid, D2
+//! or PlantUML. Messages are labelled with the payload and its metadata, followed by a note of the
+//! payload's fields, and `Sleep`s and `Flush`es appear as notes (or delays, in PlantUML) on the
+//! host's side. The document has no state machines yet, so transactions are all there is to draw.
+
+use super::docs::{int_name, literal};
+use super::{BackendInfo, BackendOptions, CodeWriter, Codegen, CodegenError, OptionInfo, OutputSink};
+use crate::ir::*;
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "sequence",
+    description: "Sequence diagrams of each transaction, as Mermaid, D2 or PlantUML source",
+    options: &[OptionInfo { name: "format", description: "mermaid, d2 or plantuml", default: Some("mermaid") }],
+    create: |options| Ok(Box::new(SequenceBackend::new(options)?)),
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Mermaid,
+    D2,
+    PlantUml,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Mermaid => "mmd",
+            Format::D2 => "d2",
+            Format::PlantUml => "puml",
+        }
+    }
+}
+
+pub struct SequenceBackend {
+    format: Format,
+}
+
+impl SequenceBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        let format = match options.get_or("format", "mermaid") {
+            "mermaid" => Format::Mermaid,
+            "d2" => Format::D2,
+            "plantuml" => Format::PlantUml,
+            other => return Err(CodegenError::BadOption { option: "format".to_owned(), value: other.to_owned(), expected: "mermaid, d2 or plantuml" }),
+        };
+        Ok(Self { format })
+    }
+}
+
+impl Codegen for SequenceBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        for transaction in &ir.transactions {
+            let path = format!("{}.{}", transaction.name.kebab(), self.format.extension());
+            out.write_text(&path, &diagram(ir, transaction, self.format))?;
+        }
+        Ok(())
+    }
+}
+
+/// A step of a transaction, with its labels worked out
+enum Step {
+    Message { direction: Direction, label: String, fields: String },
+    Wait(u32),
+    Flush,
+}
+
+fn steps(ir: &Ir, transaction: &Transaction) -> Vec<Step> {
+    transaction
+        .actions
+        .iter()
+        .map(|action| match action {
+            Action::Tx(name) | Action::Rx(name) => {
+                let direction = if matches!(action, Action::Tx(_)) { Direction::Tx } else { Direction::Rx };
+                let payload = ir.get_payload(direction, name);
+                let metadata: Vec<String> = payload
+                    .map(|p| p.metadata.iter().map(|m| format!("{} {}", m.name.raw(), m.values.iter().map(literal).collect::<Vec<_>>().join("/"))).collect())
+                    .unwrap_or_default();
+                let label = match metadata.is_empty() {
+                    true => name.raw().to_owned(),
+                    false => format!("{} ({})", name.raw(), metadata.join(", ")),
+                };
+                let fields = payload.map(|p| field_summary(&p.fields)).unwrap_or_default();
+                Step::Message { direction, label, fields }
+            }
+            Action::Sleep { milliseconds } => Step::Wait(*milliseconds),
+            Action::Flush => Step::Flush,
+        })
+        .collect()
+}
+
+/// Fields as `name: type`, leaving out constants, which every frame carries the same
+fn field_summary(fields: &[Field]) -> String {
+    let summary: Vec<String> = fields.iter().filter(|f| !f.ty.is_const()).map(|f| format!("{}: {}", f.name.raw(), short_type(&f.ty))).collect();
+    match summary.is_empty() {
+        true => "no fields".to_owned(),
+        false => summary.join(", "),
+    }
+}
+
+fn short_type(ty: &Type) -> String {
+    match ty {
+        Type::Int { bits, signing, .. } => int_name(*bits, *signing),
+        Type::Float { bits, .. } => format!("f{bits}"),
+        Type::Bytes(_) => "bytes".to_owned(),
+        Type::String(_) => "string".to_owned(),
+        Type::Const(_) => "const".to_owned(),
+        Type::Struct(name) => name.raw().to_owned(),
+        Type::Array { item, .. } => format!("{}[]", item.raw()),
+    }
+}
+
+fn returns(transaction: &Transaction) -> Option<String> {
+    match transaction.returns.is_empty() {
+        true => None,
+        false => Some(format!("returns {}", transaction.returns.iter().map(|r| r.name.raw()).collect::<Vec<_>>().join(", "))),
+    }
+}
+
+/// One transaction's diagram source
+pub(crate) fn diagram(ir: &Ir, transaction: &Transaction, format: Format) -> String {
+    match format {
+        Format::Mermaid => mermaid(ir, transaction),
+        Format::D2 => d2(ir, transaction),
+        Format::PlantUml => plantuml(ir, transaction),
+    }
+}
+
+pub(crate) fn mermaid(ir: &Ir, transaction: &Transaction) -> String {
+    // `;` ends a statement and `#` starts an entity code, even in message text
+    let clean = |text: &str| text.replace(';', ",").replace('#', "");
+    let mut w = CodeWriter::new("    ");
+    w.line("sequenceDiagram");
+    w.indent();
+    w.line(format!("title {}", clean(transaction.name.raw())));
+    w.line("participant Host");
+    w.line(format!("participant Device as {}", clean(ir.device.name.raw())));
+    for step in steps(ir, transaction) {
+        match step {
+            Step::Message { direction, label, fields } => {
+                let arrow = match direction {
+                    Direction::Tx => "Host->>Device",
+                    Direction::Rx => "Device->>Host",
+                };
+                w.line(format!("{arrow}: {}", clean(&label)));
+                w.line(format!("Note over Host,Device: {}", clean(&fields)));
+            }
+            Step::Wait(ms) => {
+                w.line(format!("Note over Host: wait {ms} ms"));
+            }
+            Step::Flush => {
+                w.line("Note over Host: discard unread input");
+            }
+        }
+    }
+    if let Some(returns) = returns(transaction) {
+        w.line(format!("Note over Host: {returns}"));
+    }
+    w.finish()
+}
+
+fn d2(ir: &Ir, transaction: &Transaction) -> String {
+    let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut w = CodeWriter::new("  ");
+    // a sequence diagram's children are all actors, so the diagram goes in a container labelled with the name
+    w.line(format!("{}: {{", quote(transaction.name.raw())));
+    w.indent();
+    w.line("shape: sequence_diagram");
+    w.line("host: Host");
+    w.line(format!("device: {}", quote(ir.device.name.raw())));
+    // notes are objects nested in an actor, so each needs its own key
+    let mut notes = 0;
+    let mut note = |w: &mut CodeWriter, actor: &str, text: &str| {
+        notes += 1;
+        w.line(format!("{actor}.note{notes}: {}", quote(text)));
+    };
+    for step in steps(ir, transaction) {
+        match step {
+            Step::Message { direction, label, fields } => {
+                let (from, to) = match direction {
+                    Direction::Tx => ("host", "device"),
+                    Direction::Rx => ("device", "host"),
+                };
+                w.line(format!("{from} -> {to}: {}", quote(&label)));
+                note(&mut w, to, &fields);
+            }
+            Step::Wait(ms) => note(&mut w, "host", &format!("wait {ms} ms")),
+            Step::Flush => note(&mut w, "host", "discard unread input"),
+        }
+    }
+    if let Some(returns) = returns(transaction) {
+        note(&mut w, "host", &returns);
+    }
+    w.dedent();
+    w.line("}");
+    w.finish()
+}
+
+fn plantuml(ir: &Ir, transaction: &Transaction) -> String {
+    let quote = |text: &str| text.replace('"', "'");
+    let mut w = CodeWriter::new("  ");
+    w.line("@startuml");
+    w.line(format!("title {}", quote(transaction.name.raw())));
+    w.line("participant Host");
+    w.line(format!("participant \"{}\" as Device", quote(ir.device.name.raw())));
+    for step in steps(ir, transaction) {
+        match step {
+            Step::Message { direction, label, fields } => {
+                let arrow = match direction {
+                    Direction::Tx => "Host -> Device",
+                    Direction::Rx => "Device -> Host",
+                };
+                w.line(format!("{arrow} : {}", quote(&label)));
+                w.line(format!("note over Host, Device : {}", quote(&fields)));
+            }
+            Step::Wait(ms) => {
+                w.line(format!("...{ms} ms..."));
+            }
+            Step::Flush => {
+                w.line("note over Host : discard unread input");
+            }
+        }
+    }
+    if let Some(returns) = returns(transaction) {
+        w.line(format!("note over Host : {}", quote(&returns)));
+    }
+    w.line("@enduml");
+    w.finish()
+}
@@ -0,0 +1,1452 @@
+//! Generates a TypeScript package for browser configurators and Node tools: an `interface` per
+//! struct and payload with a codec object of the same name, `DataView`-backed bit packing, and an
+//! async device class over Web Serial's streams or a Node duplex stream such as serialport's.
+//!
+//! Like the `python` backend, frame formats are emitted as data and interpreted by the package's
+//! `wire` module, which follows `openpid_runtime::frame`.
+
+use super::layout::flat_formats;
+use super::{BackendInfo, BackendOptions, CodeWriter, Codegen, CodegenError, Layout, OptionInfo, OutputSink};
+use crate::ir::*;
+use crate::value::{Fields, Value};
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "typescript",
+    description: "TypeScript package for Web Serial and Node streams, with typed payload interfaces",
+    options: &[
+        OptionInfo { name: "package", description: "Name of the npm package", default: Some("the device's name, in kebab case") },
+        OptionInfo { name: "tests", description: "Generate a node:test suite", default: Some("true") },
+    ],
+    create: |options| Ok(Box::new(TypeScriptBackend::new(options)?)),
+};
+
+pub struct TypeScriptBackend {
+    package: Option<String>,
+    tests: bool,
+}
+
+impl TypeScriptBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        Ok(Self { package: options.get("package").map(str::to_owned), tests: options.get_bool("tests", true)? })
+    }
+
+    fn package_json(&self, ir: &Ir, package: &str) -> String {
+        // doc_version is free-form, so only use it when it looks like a release number
+        let version = ir
+            .doc_version
+            .as_deref()
+            .filter(|v| !v.is_empty() && v.split('.').all(|p| p.parse::<u64>().is_ok()))
+            .unwrap_or("0.1.0");
+        let description = format!("Driver for the {}, generated from its OpenPID document", ir.device.name);
+        format!(
+            "{{\n  \"name\": {package:?},\n  \"version\": {version:?},\n  \"description\": {description:?},\n  \"type\": \"module\",\n  \
+             \"main\": \"dist/src/index.js\",\n  \"types\": \"dist/src/index.d.ts\",\n  \"files\": [\"dist/src\"],\n  \
+             \"scripts\": {{\n    \"build\": \"tsc\",\n    \"test\": \"tsc && node --test dist/test/\"\n  }},\n  \
+             \"devDependencies\": {{\n    \"@types/node\": \"^20.0.0\",\n    \"typescript\": \"^5.4.0\"\n  }}\n}}\n"
+        )
+    }
+}
+
+impl Codegen for TypeScriptBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let package = self.package.clone().unwrap_or_else(|| ir.device.name.kebab());
+        let gen = Gen { ir, framed: ir.framing.is_some() };
+        let formats = flat_formats(ir, "typescript")?;
+
+        out.write_text("package.json", &self.package_json(ir, &package))?;
+        out.write_text("tsconfig.json", TSCONFIG.trim_start())?;
+        out.write_text("src/wire.ts", &format!("{}{}", header("Bit packing, CRCs and frame envelopes the generated modules share"), WIRE.trim_start()))?;
+        out.write_text("src/structs.ts", &gen.structs())?;
+        out.write_text("src/tx.ts", &gen.payloads(Direction::Tx)?)?;
+        out.write_text("src/rx.ts", &gen.payloads(Direction::Rx)?)?;
+        if let Some(formats) = &formats {
+            out.write_text("src/frame.ts", &gen.frame(formats))?;
+        }
+        out.write_text("src/device.ts", &gen.device())?;
+        out.write_text("src/index.ts", &gen.index())?;
+        if self.tests {
+            out.write_text(&format!("test/{package}.test.ts"), &gen.tests())?;
+        }
+        Ok(())
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "break", "case", "catch", "class", "const", "continue", "debugger", "default", "delete", "do", "else", "enum", "export", "extends", "false",
+    "finally", "for", "function", "if", "import", "in", "instanceof", "new", "null", "return", "super", "switch", "this", "throw", "true", "try",
+    "typeof", "var", "void", "while", "with", "as", "implements", "interface", "let", "package", "private", "protected", "public", "static",
+    "yield", "await", "async", "arguments", "eval", "undefined",
+];
+
+/// A camel case identifier, with a trailing underscore if it's reserved
+fn ident(name: &Name) -> String {
+    let camel = name.camel();
+    if KEYWORDS.contains(&camel.as_str()) {
+        format!("{camel}_")
+    } else if camel.starts_with(|c: char| c.is_ascii_digit()) || camel.is_empty() {
+        format!("_{camel}")
+    } else {
+        camel
+    }
+}
+
+fn type_name(name: &Name) -> String {
+    let pascal = name.pascal();
+    if pascal.starts_with(|c: char| c.is_ascii_digit()) || pascal.is_empty() {
+        format!("_{pascal}")
+    } else {
+        pascal
+    }
+}
+
+/// A local variable in codec functions, kept clear of their parameters and imports
+fn local(name: &Name) -> String {
+    match ident(name).as_str() {
+        name @ ("reader" | "writer" | "value" | "wire" | "structs") => format!("{name}_"),
+        name => name.to_owned(),
+    }
+}
+
+fn bytes_literal(data: &[u8]) -> String {
+    format!("Uint8Array.of({})", data.iter().map(|b| format!("0x{b:02x}")).collect::<Vec<_>>().join(", "))
+}
+
+fn doc(w: &mut CodeWriter, text: &str) {
+    let text = text.trim().replace("*/", "*\\/");
+    if text.is_empty() {
+        return;
+    }
+    if text.lines().count() == 1 {
+        w.line(format!("/** {text} */"));
+    } else {
+        w.line("/**");
+        w.comment(" *", &text);
+        w.line(" */");
+    }
+}
+
+fn header(summary: &str) -> String {
+    format!("// {summary}\n//\n// Generated from an OpenPID document by `openpid gen typescript`. Don't edit by hand\n\n")
+}
+
+/// Integers wider than this are `bigint`s, since `number` only holds 53 bits exactly
+const NUMBER_BITS: u32 = 32;
+
+fn signing(signing: Signing) -> &'static str {
+    match signing {
+        Signing::Unsigned => "\"unsigned\"",
+        Signing::TwosComplement => "\"twos\"",
+        Signing::OnesComplement => "\"ones\"",
+    }
+}
+
+/// Trailing arguments to `putInt` and `getInt`, left off when they're the defaults
+fn int_args(s: Signing, endianness: Endianness) -> String {
+    match (s, endianness) {
+        (Signing::Unsigned, Endianness::BigEndian) => String::new(),
+        (s, Endianness::BigEndian) => format!(", {}", signing(s)),
+        (s, Endianness::LittleEndian) => format!(", {}, true", signing(s)),
+    }
+}
+
+fn element(element: &FlatElement) -> String {
+    let unit = |unit: &BitsOrBytes| match unit {
+        BitsOrBytes::Bits => "\"bits\"",
+        BitsOrBytes::Bytes => "\"bytes\"",
+    };
+    match element {
+        FlatElement::SizeTotal { bits, unit: u } => format!("{{ kind: \"sizeTotal\", bits: {bits}, unit: {} }}", unit(u)),
+        FlatElement::SizeOfPayload { bits, unit: u } => format!("{{ kind: \"sizeOfPayload\", bits: {bits}, unit: {} }}", unit(u)),
+        FlatElement::SizeOfElements { bits, unit: u, covers, .. } => {
+            format!("{{ kind: \"sizeOfElements\", bits: {bits}, unit: {}, covers: {covers} }}", unit(u))
+        }
+        FlatElement::Payload => "{ kind: \"payload\" }".to_owned(),
+        FlatElement::Metadata { bits, endianness, .. } => {
+            format!("{{ kind: \"metadata\", bits: {bits}, little: {} }}", *endianness == Endianness::LittleEndian)
+        }
+        FlatElement::Crc(Crc::Crc32) => "{ kind: \"crc\", algorithm: \"crc32\" }".to_owned(),
+        FlatElement::Crc(Crc::Crc16XModem) => "{ kind: \"crc\", algorithm: \"crc16Xmodem\" }".to_owned(),
+        FlatElement::Const { data, bits } => format!("{{ kind: \"const\", data: {}, bits: {bits} }}", bytes_literal(data)),
+    }
+}
+
+struct Gen<'a> {
+    ir: &'a Ir,
+    framed: bool,
+}
+
+impl Gen<'_> {
+    fn device_class(&self) -> String {
+        let name = type_name(&self.ir.device.name);
+        match name.as_str() {
+            // everything else the package's index exports
+            "Transport" | "Timeout" | "UnexpectedPayload" | "NodeStreamTransport" | "WebStreamTransport" | "DuplexLike" | "DeviceOptions" | "Inbox"
+            | "CodecError" | "Incomplete" | "Codec" | "PayloadCodec" | "Writer" | "Reader" | "Frame" | "FrameElement" | "Signing" | "Unit" => {
+                format!("{name}Device")
+            }
+            _ => name,
+        }
+    }
+
+    /// Name of a transaction's method, kept clear of the device's own members
+    fn method_name(transaction: &Transaction) -> String {
+        match ident(&transaction.name).as_str() {
+            name @ ("transport" | "sleep" | "send" | "receive" | "constructor") => format!("{name}Transaction"),
+            name => name.to_owned(),
+        }
+    }
+
+    /// The TypeScript type of a field. `structs` is the prefix struct types need where they're used
+    fn ts_type(ty: &Type, structs: &str) -> String {
+        match ty {
+            Type::Int { bits, .. } if *bits > NUMBER_BITS => "bigint".to_owned(),
+            Type::Int { .. } | Type::Float { .. } => "number".to_owned(),
+            Type::Bytes(_) | Type::Const(_) => "Uint8Array".to_owned(),
+            Type::String(_) => "string".to_owned(),
+            Type::Struct(name) => format!("{structs}{}", type_name(name)),
+            Type::Array { item, .. } => format!("{structs}{}[]", type_name(item)),
+        }
+    }
+
+    fn default(ty: &Type, structs: &str) -> String {
+        match ty {
+            Type::Int { bits, .. } if *bits > NUMBER_BITS => "0n".to_owned(),
+            Type::Int { .. } | Type::Float { .. } => "0".to_owned(),
+            Type::Bytes(Length::Fixed(n)) => format!("new Uint8Array({n})"),
+            Type::Bytes(_) | Type::Const(_) => "new Uint8Array(0)".to_owned(),
+            Type::String(Length::Fixed(n)) => format!("\"\\0\".repeat({n})"),
+            Type::String(_) => "\"\"".to_owned(),
+            Type::Struct(name) => format!("{structs}{}.create()", type_name(name)),
+            Type::Array { item, len: Length::Fixed(n) } => format!("Array.from({{ length: {n} }}, () => {structs}{}.create())", type_name(item)),
+            Type::Array { .. } => "[]".to_owned(),
+        }
+    }
+
+    fn structs(&self) -> String {
+        let mut w = CodeWriter::new("  ");
+        w.line(header("Structs shared between payloads").trim_end());
+        w.blank();
+        w.line("import * as wire from \"./wire.js\";");
+        for s in &self.ir.structs {
+            w.blank();
+            Self::codec(&mut w, &s.name, s.description.as_deref(), &s.fields, s.size, "", None);
+        }
+        w.finish()
+    }
+
+    fn payloads(&self, direction: Direction) -> Result<String, CodegenError> {
+        let mut w = CodeWriter::new("  ");
+        w.line(header(&format!("Payloads {}", if direction == Direction::Tx { "sent to the device" } else { "received from the device" })).trim_end());
+        w.blank();
+        let uses_structs = self.ir.payloads(direction).iter().flat_map(|p| &p.fields).any(|f| matches!(f.ty, Type::Struct(_) | Type::Array { .. }));
+        if uses_structs {
+            w.line("import * as structs from \"./structs.js\";");
+        }
+        w.line("import * as wire from \"./wire.js\";");
+        for p in self.ir.payloads(direction) {
+            let metadata = p
+                .metadata
+                .iter()
+                .filter(|m| m.ty.is_some())
+                .map(|m| {
+                    m.packed().ok_or_else(|| CodegenError::Unsupported {
+                        backend: "typescript",
+                        what: format!("metadata \"{}\" of payload \"{}\": only integers and strings up to 8 bytes", m.name, p.name),
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let metadata = if self.framed { metadata } else { Vec::new() };
+            let metadata = metadata.iter().map(|values| format!("[{}]", values.iter().map(|v| format!("{v:#x}n")).collect::<Vec<_>>().join(", ")));
+            let payload = (direction, format!("[{}]", metadata.collect::<Vec<_>>().join(", ")));
+            w.blank();
+            Self::codec(&mut w, &p.name, Some(&p.description), &p.fields, p.size, "structs.", Some(payload));
+        }
+        Ok(w.finish())
+    }
+
+    /// An interface and the codec object of the same name. `payload` is the direction and
+    /// metadata of payloads
+    fn codec(w: &mut CodeWriter, name: &Name, description: Option<&str>, fields: &[Field], size: Size, structs: &str, payload: Option<(Direction, String)>) {
+        let ty = type_name(name);
+        if let Some(description) = description {
+            doc(w, description);
+        }
+        let user_facing = fields.iter().filter(|f| f.is_user_facing()).collect::<Vec<_>>();
+        if user_facing.is_empty() {
+            w.line(format!("export interface {ty} {{}}"));
+        } else {
+            w.block(format!("export interface {ty} {{"), "}", |w| {
+                for f in &user_facing {
+                    if let Some(description) = &f.description {
+                        doc(w, description);
+                    }
+                    w.line(format!("{}: {};", ident(&f.name), Self::ts_type(&f.ty, structs)));
+                }
+            });
+        }
+        w.blank();
+        let codec = if payload.is_some() { "PayloadCodec" } else { "Codec" };
+        w.block(format!("export const {ty}: wire.{codec}<{ty}> = {{"), "};", |w| {
+            if let Some((direction, metadata)) = &payload {
+                w.line(format!("name: \"{ty}\","));
+                w.line(format!("direction: \"{}\",", if *direction == Direction::Tx { "tx" } else { "rx" }));
+                w.line(format!("metadata: {metadata},"));
+            }
+            w.line(format!("fixedLen: {},", size.fixed_bits().map_or("null".to_owned(), |bits| bits.div_ceil(8).to_string())));
+            let defaults = user_facing.iter().map(|f| format!("{}: {}, ", ident(&f.name), Self::default(&f.ty, structs))).collect::<String>();
+            w.line(format!("create: (init = {{}}) => ({{ {defaults}...init }}),"));
+            if fields.is_empty() {
+                w.line("encodeTo() {},");
+                w.line("decodeFrom: () => ({}),");
+                return;
+            }
+            w.block("encodeTo(writer, value) {", "},", |w| {
+                for f in fields {
+                    Self::encode_field(w, f, fields, structs);
+                }
+            });
+            w.block("decodeFrom(reader) {", "},", |w| {
+                for f in fields {
+                    Self::decode_field(w, f, structs);
+                }
+                let members = user_facing
+                    .iter()
+                    .map(|f| match (ident(&f.name), local(&f.name)) {
+                        (name, l) if name == l => name,
+                        (name, l) => format!("{name}: {l}"),
+                    })
+                    .collect::<Vec<_>>();
+                if members.is_empty() {
+                    w.line("return {};");
+                } else {
+                    w.line(format!("return {{ {} }};", members.join(", ")));
+                }
+            });
+        });
+    }
+
+    fn encode_field(w: &mut CodeWriter, f: &Field, fields: &[Field], structs: &str) {
+        let value = format!("value.{}", ident(&f.name));
+        match &f.ty {
+            Type::Int { bits, signing: s, endianness } => {
+                let value = match &f.count_of {
+                    Some(counted) => match fields.iter().find(|c| c.name == *counted).map(|c| &c.ty) {
+                        Some(Type::String(_)) => format!("wire.toBytes(value.{}).length", ident(counted)),
+                        _ => format!("value.{}.length", ident(counted)),
+                    },
+                    None => value,
+                };
+                w.line(format!("writer.putInt({bits}, {value}{});", int_args(*s, *endianness)));
+            }
+            Type::Float { bits, endianness } => {
+                w.line(format!("writer.putFloat({bits}, {value}{});", if *endianness == Endianness::LittleEndian { ", true" } else { "" }));
+            }
+            Type::Const(data) => {
+                w.line(format!("writer.putBytes({});", bytes_literal(data)));
+            }
+            Type::Struct(name) => {
+                w.line(format!("{structs}{}.encodeTo(writer, {value});", type_name(name)));
+            }
+            Type::Bytes(len) | Type::String(len) => {
+                let bytes = if matches!(f.ty, Type::String(_)) { format!("wire.toBytes({value})") } else { value };
+                match len {
+                    Length::Fixed(n) => w.line(format!("writer.putFixed({bytes}, {n});")),
+                    Length::Capacity(n) => w.line(format!("writer.putPadded({bytes}, {n});")),
+                    Length::CountField(_) | Length::Remainder => w.line(format!("writer.putBytes({bytes});")),
+                    Length::Sequence(sequence) => {
+                        w.line(format!("writer.putBytes({bytes});"));
+                        w.line(format!("writer.putBytes({});", bytes_literal(sequence)))
+                    }
+                };
+            }
+            Type::Array { item, len } => {
+                if let Length::Fixed(n) = len {
+                    w.line(format!("wire.checkLen({n}, {value}.length);"));
+                }
+                w.line(format!("for (const item of {value}) {structs}{}.encodeTo(writer, item);", type_name(item)));
+                if let Length::Sequence(sequence) = len {
+                    w.line(format!("writer.putBytes({});", bytes_literal(sequence)));
+                }
+            }
+        }
+    }
+
+    fn decode_field(w: &mut CodeWriter, f: &Field, structs: &str) {
+        let l = if f.count_of.is_some() { format!("n{}", f.name.pascal()) } else { local(&f.name) };
+        let count = |len: &Length| match len {
+            Length::Fixed(n) | Length::Capacity(n) => n.to_string(),
+            Length::CountField(c) => format!("n{}", c.pascal()),
+            Length::Sequence(_) | Length::Remainder => "reader.remaining >> 3".to_owned(),
+        };
+        match &f.ty {
+            Type::Int { bits, signing: s, endianness } => {
+                let read = format!("reader.getInt({bits}{})", int_args(*s, *endianness));
+                if *bits > NUMBER_BITS && f.count_of.is_none() {
+                    w.line(format!("const {l} = {read};"));
+                } else {
+                    w.line(format!("const {l} = Number({read});"));
+                }
+                if f.count_of.is_some() && *s != Signing::Unsigned {
+                    w.line(format!("if ({l} < 0) throw new wire.CodecError(`negative count ${{{l}}}`);"));
+                }
+            }
+            Type::Float { bits, endianness } => {
+                w.line(format!("const {l} = reader.getFloat({bits}{});", if *endianness == Endianness::LittleEndian { ", true" } else { "" }));
+            }
+            Type::Const(data) => {
+                w.line(format!("reader.expect({});", bytes_literal(data)));
+            }
+            Type::Struct(name) => {
+                w.line(format!("const {l} = {structs}{}.decodeFrom(reader);", type_name(name)));
+            }
+            Type::Bytes(len) | Type::String(len) => {
+                let bytes = match len {
+                    Length::Sequence(sequence) => format!("reader.readUntil({})", bytes_literal(sequence)),
+                    _ => format!("reader.getBytes({})", count(len)),
+                };
+                match (&f.ty, len) {
+                    (Type::String(_), Length::Capacity(_)) => w.line(format!("const {l} = wire.toStr(wire.trimNul({bytes}));")),
+                    (Type::String(_), _) => w.line(format!("const {l} = wire.toStr({bytes});")),
+                    _ => w.line(format!("const {l} = {bytes};")),
+                };
+            }
+            Type::Array { item, len } => {
+                let item = format!("{structs}{}", type_name(item));
+                match len {
+                    Length::Sequence(sequence) => {
+                        w.line(format!("const {l}: {item}[] = [];"));
+                        w.line(format!("while (!reader.take({})) {l}.push({item}.decodeFrom(reader));", bytes_literal(sequence)));
+                    }
+                    Length::Remainder => {
+                        w.line(format!("const {l}: {item}[] = [];"));
+                        w.line(format!("while (reader.remaining >= 8) {l}.push({item}.decodeFrom(reader));"));
+                    }
+                    _ => {
+                        w.line(format!("const {l} = Array.from({{ length: {} }}, () => {item}.decodeFrom(reader));", count(len)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn frame(&self, formats: &[Vec<FlatElement>; 2]) -> String {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("  ");
+        w.line(header("Frame formats from the document's `[uart]` section").trim_end());
+        w.blank();
+        w.line("import * as wire from \"./wire.js\";");
+        for (direction, format) in [Direction::Tx, Direction::Rx].into_iter().zip(formats) {
+            let upper = direction.to_string().to_uppercase();
+            let pascal = if direction == Direction::Tx { "Tx" } else { "Rx" };
+            w.blank();
+            w.block(format!("export const {upper}_FORMAT: readonly wire.FrameElement[] = ["), "];", |w| {
+                for e in format {
+                    w.line(format!("{},", element(e)));
+                }
+            });
+            w.line(format!("export const {upper}_ENVELOPE_BITS = wire.envelopeBits({upper}_FORMAT);"));
+            for (i, (key, _)) in ir.framing.iter().flat_map(|f| f.metadata(direction)).enumerate() {
+                let values = ir
+                    .payloads(direction)
+                    .iter()
+                    .filter_map(|p| Some((p, p.metadata(key.raw())?.packed()?.first().copied()?)))
+                    .collect::<Vec<_>>();
+                if values.is_empty() {
+                    continue;
+                }
+                w.blank();
+                doc(&mut w, &format!("Values of the \"{key}\" metadata, element {i} of {direction} frames"));
+                w.block(format!("export const {pascal}{} = {{", type_name(key)), "} as const;", |w| {
+                    for (p, value) in &values {
+                        w.line(format!("{}: {value:#x}n,", p.name.screaming()));
+                    }
+                });
+            }
+        }
+        w.blank();
+        w.line(FRAME_FNS.trim());
+        w.finish()
+    }
+
+    fn device(&self) -> String {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("  ");
+        w.line(header(&format!("A driver for the {} over Web Serial or a Node stream", ir.device.name)).trim_end());
+        w.blank();
+        if self.framed {
+            w.line("import { RX_FORMAT, pack } from \"./frame.js\";");
+        }
+        w.line("import * as rx from \"./rx.js\";");
+        if ir.transactions.iter().flat_map(|t| &t.returns).any(|r| matches!(r.ty, Type::Struct(_) | Type::Array { .. })) {
+            w.line("import * as structs from \"./structs.js\";");
+        }
+        w.line("import * as tx from \"./tx.js\";");
+        w.line("import * as wire from \"./wire.js\";");
+        w.blank();
+        w.line(TRANSPORTS.trim());
+        for transaction in &ir.transactions {
+            if transaction.returns.len() > 1 {
+                w.blank();
+                doc(&mut w, &format!("What `{}` returns", Self::method_name(transaction)));
+                w.block(format!("export interface {}Response {{", type_name(&transaction.name)), "}", |w| {
+                    for ret in &transaction.returns {
+                        w.line(format!("{}: {};", ident(&ret.name), Self::ts_type(&ret.ty, "structs.")));
+                    }
+                });
+            }
+        }
+        w.blank();
+        w.block("export interface DeviceOptions {", "}", |w| {
+            w.line("/** Waits between actions. Defaults to a timer, and is swapped out in tests */");
+            w.line("sleep?: (ms: number) => Promise<void>;");
+        });
+        w.blank();
+        doc(&mut w, &ir.device.description);
+        w.block(format!("export class {} {{", self.device_class()), "}", |w| {
+            w.line("readonly transport: Transport;");
+            w.line("private readonly sleep: (ms: number) => Promise<void>;");
+            w.blank();
+            w.block("constructor(transport: Transport, options: DeviceOptions = {}) {", "}", |w| {
+                w.line("this.transport = transport;");
+                w.line("this.sleep = options.sleep ?? ((ms) => new Promise((resolve) => setTimeout(resolve, ms)));");
+            });
+            w.blank();
+            w.block("async send<T>(codec: wire.PayloadCodec<T>, value: T): Promise<void> {", "}", |w| {
+                w.line(if self.framed { "await this.transport.write(pack(codec, value));" } else { "await this.transport.write(wire.encode(codec, value));" });
+            });
+            w.blank();
+            w.line("/** Reads one payload of the given type */");
+            w.block("async receive<T>(codec: wire.PayloadCodec<T>): Promise<T> {", "}", |w| {
+                if self.framed {
+                    w.line(RECEIVE_FRAMED.trim());
+                } else {
+                    w.line("// without a frame format, every payload has a fixed size");
+                    w.line("return wire.decode(codec, await this.transport.read(codec.fixedLen ?? 0));");
+                }
+            });
+            for transaction in &ir.transactions {
+                w.blank();
+                self.transaction(w, transaction);
+            }
+        });
+        w.finish()
+    }
+
+    fn transaction(&self, w: &mut CodeWriter, transaction: &Transaction) {
+        let params = self.ir.transaction_params(transaction);
+        let signature = params.iter().map(|p| format!("{}: tx.{}", ident(p), type_name(p))).collect::<Vec<_>>();
+        let returns = match transaction.returns.as_slice() {
+            [] => "void".to_owned(),
+            [ret] => Self::ts_type(&ret.ty, "structs."),
+            _ => format!("{}Response", type_name(&transaction.name)),
+        };
+        doc(w, &transaction.description);
+        w.block(format!("async {}({}): Promise<{returns}> {{", Self::method_name(transaction), signature.join(", ")), "}", |w| {
+            for action in &transaction.actions {
+                match action {
+                    Action::Tx(payload) if params.contains(&payload) => w.line(format!("await this.send(tx.{}, {});", type_name(payload), ident(payload))),
+                    Action::Tx(payload) => w.line(format!("await this.send(tx.{0}, tx.{0}.create());", type_name(payload))),
+                    Action::Rx(payload) if transaction.returns.iter().any(|r| r.payload == *payload) => {
+                        w.line(format!("const {}Rx = await this.receive(rx.{});", payload.camel(), type_name(payload)))
+                    }
+                    Action::Rx(payload) => w.line(format!("await this.receive(rx.{});", type_name(payload))),
+                    Action::Sleep { milliseconds } => w.line(format!("await this.sleep({milliseconds});")),
+                    Action::Flush => w.line("this.transport.discardInput();"),
+                };
+            }
+            let value = |ret: &Return| format!("{}Rx.{}", ret.payload.camel(), ret.path.iter().map(ident).collect::<Vec<_>>().join("."));
+            match transaction.returns.as_slice() {
+                [] => {}
+                [ret] => {
+                    w.line(format!("return {};", value(ret)));
+                }
+                returns => {
+                    let members = returns.iter().map(|ret| format!("{}: {}", ident(&ret.name), value(ret))).collect::<Vec<_>>();
+                    if members.is_empty() {
+                    w.line("return {};");
+                } else {
+                    w.line(format!("return {{ {} }};", members.join(", ")));
+                }
+                }
+            }
+        });
+    }
+
+    fn index(&self) -> String {
+        let mut w = CodeWriter::new("  ");
+        w.line(header(format!("Driver for the {}: {}", self.ir.device.name, self.ir.device.description).trim_end_matches([':', ' '])).trim_end());
+        w.blank();
+        w.line("export * from \"./device.js\";");
+        if self.framed {
+            w.line("export * from \"./frame.js\";");
+        }
+        w.line("export * as rx from \"./rx.js\";");
+        w.line("export * as structs from \"./structs.js\";");
+        w.line("export * as tx from \"./tx.js\";");
+        w.line("export * from \"./wire.js\";");
+        w.finish()
+    }
+
+    /// A TypeScript expression for a sample value of a field's type
+    fn literal(&self, value: &Value, ty: &Type) -> String {
+        match (value, ty) {
+            (Value::Struct(values), Type::Struct(name)) => self.sample(&format!("structs.{}", type_name(name)), values, self.ir.fields_of(ty).unwrap_or(&[])),
+            (Value::Array(items), Type::Array { item, .. }) => {
+                format!("[{}]", items.iter().map(|i| self.literal(i, &Type::Struct(item.clone()))).collect::<Vec<_>>().join(", "))
+            }
+            (Value::Int(v), Type::Int { bits, .. }) if *bits > NUMBER_BITS => format!("{v}n"),
+            (Value::UInt(v), Type::Int { bits, .. }) if *bits > NUMBER_BITS => format!("{v}n"),
+            (Value::Bytes(b), _) => bytes_literal(b),
+            (Value::String(text), _) => format!("{text:?}"),
+            (Value::Float(f), _) => format!("{f:?}"),
+            (value, _) => value.to_string(),
+        }
+    }
+
+    /// A codec's `create`, given sample values for its user facing fields
+    fn sample(&self, codec: &str, values: &Fields, fields: &[Field]) -> String {
+        let members = fields
+            .iter()
+            .filter(|f| f.is_user_facing())
+            .filter_map(|f| Some(format!("{}: {}", ident(&f.name), self.literal(values.get(f.name.raw())?, &f.ty))))
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            format!("{codec}.create()")
+        } else {
+            format!("{codec}.create({{ {} }})", members.join(", "))
+        }
+    }
+
+    fn tests(&self) -> String {
+        let ir = self.ir;
+        // arrays grow, so only count fields limit how many samples there are
+        let layout = Layout::new(ir, u32::MAX);
+        let payload_sample = |direction: Direction, name: &Name| {
+            let module = if direction == Direction::Tx { "tx" } else { "rx" };
+            let fields = ir.get_payload(direction, name).map_or(&[][..], |p| &p.fields);
+            self.sample(&format!("{module}.{}", type_name(name)), &layout.sample_fields(fields, 0), fields)
+        };
+        let mut w = CodeWriter::new("  ");
+        w.line("// Checks generated alongside the driver: every payload survives an encode/decode round trip, and");
+        w.line("// every transaction runs against a mocked port, through both the Node and the web stream transports.");
+        w.line("// Run with `npm test`");
+        w.blank();
+        w.line("import assert from \"node:assert/strict\";");
+        w.line("import { test } from \"node:test\";");
+        w.blank();
+        let mut imports = vec!["NodeStreamTransport", "WebStreamTransport", "decode", "encode", "rx", "tx"];
+        if !ir.structs.is_empty() {
+            imports.push("structs");
+        }
+        if self.framed {
+            imports.extend(["pack", "unpack"]);
+        }
+        let device = self.device_class();
+        imports.push(&device);
+        imports.sort_unstable_by_key(|i| i.to_lowercase());
+        w.line(format!("import {{ {} }} from \"../src/index.js\";", imports.join(", ")));
+        w.blank();
+        w.line(TEST_MOCKS.trim());
+        for p in ir.all_payloads() {
+            let module = if p.direction == Direction::Tx { "tx" } else { "rx" };
+            let codec = format!("{module}.{}", type_name(&p.name));
+            w.blank();
+            w.block(format!("test(\"{codec} round trips\", () => {{"), "});", |w| {
+                w.line(format!("const value = {};", payload_sample(p.direction, &p.name)));
+                w.line(format!("assert.deepEqual(decode({codec}, encode({codec}, value)), value);"));
+                if self.framed {
+                    w.line(format!("assert.deepEqual(unpack({codec}, pack({codec}, value)), value);"));
+                }
+            });
+        }
+        for transaction in &ir.transactions {
+            // responses arrive in batches: those read before the first write, then those after each write
+            let mut batches = vec![Vec::new()];
+            for action in &transaction.actions {
+                match action {
+                    Action::Tx(_) => batches.push(Vec::new()),
+                    Action::Rx(payload) => {
+                        let (codec, value) = (format!("rx.{}", type_name(payload)), payload_sample(Direction::Rx, payload));
+                        let batch = batches.last_mut().expect("batches starts non-empty");
+                        batch.push(if self.framed { format!("pack({codec}, {value})") } else { format!("encode({codec}, {value})") });
+                    }
+                    _ => {}
+                }
+            }
+            let batches = batches.iter().map(|b| format!("[{}]", b.join(", "))).collect::<Vec<_>>();
+            let args = self.ir.transaction_params(transaction).iter().map(|p| payload_sample(Direction::Tx, p)).collect::<Vec<_>>();
+            let expected = match transaction.returns.as_slice() {
+                [] => None,
+                [ret] => layout.sample_return(ret).map(|value| self.literal(&value, &ret.ty)),
+                returns => {
+                    let members = returns
+                        .iter()
+                        .filter_map(|ret| Some(format!("{}: {}", ident(&ret.name), self.literal(&layout.sample_return(ret)?, &ret.ty))))
+                        .collect::<Vec<_>>();
+                    Some(format!("{{ {} }}", members.join(", ")))
+                }
+            };
+            let sends = transaction.actions.iter().any(|a| matches!(a, Action::Tx(_)));
+            w.blank();
+            w.block("for (const kind of [\"node\", \"web\"] as const) {", "}", |w| {
+                w.block(format!("test(`{} over a ${{kind}} stream`, async () => {{", Self::method_name(transaction)), "});", |w| {
+                    w.line(format!("const port = new MockPort([{}]);", batches.join(", ")));
+                    w.line(format!("const device = new {}(transportFor(kind, port), {{ sleep: async () => {{}} }});", self.device_class()));
+                    let call = format!("await device.{}({})", Self::method_name(transaction), args.join(", "));
+                    match &expected {
+                        Some(expected) => w.line(format!("assert.deepEqual({call}, {expected});")),
+                        None => w.line(format!("{call};")),
+                    };
+                    w.line("assert.equal(port.pending, 0);");
+                    w.line(if sends { "assert.ok(port.written.length > 0);" } else { "assert.equal(port.written.length, 0);" });
+                });
+            });
+        }
+        w.finish()
+    }
+}
+
+const TSCONFIG: &str = r#"
+{
+  "compilerOptions": {
+    "target": "ES2020",
+    "module": "NodeNext",
+    "moduleResolution": "NodeNext",
+    "lib": ["ES2020", "DOM"],
+    "types": ["node"],
+    "strict": true,
+    "declaration": true,
+    "outDir": "dist",
+    "rootDir": "."
+  },
+  "include": ["src", "test"]
+}
+"#;
+
+const WIRE: &str = r#"
+/** A value doesn't fit its field, or data doesn't decode as the document says */
+export class CodecError extends Error {
+  constructor(message: string) {
+    super(message);
+    this.name = new.target.name;
+  }
+}
+
+/** The data ended early */
+export class Incomplete extends CodecError {}
+
+export type Signing = "unsigned" | "twos" | "ones";
+
+/** How a struct or payload is encoded. Each has an interface and a codec object of the same name */
+export interface Codec<T> {
+  /** Size in bytes, when it doesn't vary */
+  readonly fixedLen: number | null;
+  /** A value with every field at its default, overridden by init */
+  create(init?: Partial<T>): T;
+  encodeTo(writer: Writer, value: T): void;
+  decodeFrom(reader: Reader): T;
+}
+
+export interface PayloadCodec<T> extends Codec<T> {
+  readonly name: string;
+  readonly direction: "tx" | "rx";
+  /** Accepted values of each metadata element, sent with the first */
+  readonly metadata: readonly (readonly bigint[])[];
+}
+
+export function encode<T>(codec: Codec<T>, value: T): Uint8Array {
+  const writer = new Writer();
+  codec.encodeTo(writer, value);
+  return writer.finish();
+}
+
+/** Decodes a buffer holding exactly one value */
+export function decode<T>(codec: Codec<T>, data: Uint8Array): T {
+  const reader = new Reader(data);
+  const value = codec.decodeFrom(reader);
+  reader.finish();
+  return value;
+}
+
+/** Whether a frame's metadata is what a payload is sent with */
+export function matches<T>(codec: PayloadCodec<T>, metadata: readonly bigint[]): boolean {
+  return codec.metadata.every((accepted, i) => i >= metadata.length || accepted.includes(metadata[i]));
+}
+
+export function checkLen(expected: number, found: number): void {
+  if (expected !== found) throw new CodecError(`expected ${expected} elements, found ${found}`);
+}
+
+export function toBytes(text: string): Uint8Array {
+  return new TextEncoder().encode(text);
+}
+
+export function toStr(data: Uint8Array): string {
+  try {
+    return new TextDecoder("utf-8", { fatal: true }).decode(data);
+  } catch (e) {
+    throw new CodecError(`invalid UTF-8: ${e}`);
+  }
+}
+
+/** data without its trailing NULs */
+export function trimNul(data: Uint8Array): Uint8Array {
+  let end = data.length;
+  while (end > 0 && data[end - 1] === 0) end--;
+  return data.subarray(0, end);
+}
+
+function toBig(value: number | bigint): bigint {
+  if (typeof value === "number" && !Number.isSafeInteger(value)) throw new CodecError(`${value} isn't an integer`);
+  return BigInt(value);
+}
+
+/** The unsigned bit pattern of a signed value, range checked */
+function toRaw(bits: number, value: bigint, signing: Signing): bigint {
+  if (signing === "unsigned" || bits === 0) {
+    if (value < 0n || value >> BigInt(bits) !== 0n) throw new CodecError(`${value} doesn't fit in ${bits} unsigned bits`);
+    return value;
+  }
+  const high = (1n << BigInt(bits - 1)) - 1n;
+  const low = signing === "ones" ? -high : -high - 1n;
+  if (value < low || value > high) throw new CodecError(`${value} doesn't fit in ${bits} signed bits`);
+  const mask = (1n << BigInt(bits)) - 1n;
+  return value < 0n && signing === "ones" ? ~-value & mask : value & mask;
+}
+
+function fromRaw(bits: number, raw: bigint, signing: Signing): bigint {
+  if (signing === "unsigned" || bits === 0 || ((raw >> BigInt(bits - 1)) & 1n) === 0n) return raw;
+  const mask = (1n << BigInt(bits)) - 1n;
+  return signing === "ones" ? -(~raw & mask) : raw - (1n << BigInt(bits));
+}
+
+function swapBytes(value: bigint, bits: number): bigint {
+  let out = 0n;
+  for (let i = 0; i < bits / 8; i++) {
+    out = (out << 8n) | (value & 0xffn);
+    value >>= 8n;
+  }
+  return out;
+}
+
+/** Whether an integer can go through a DataView: byte aligned, a standard width and not ones' complement */
+function viewable(pos: number, bits: number, signing: Signing): boolean {
+  return pos % 8 === 0 && (bits === 8 || bits === 16 || bits === 32 || bits === 64) && signing !== "ones";
+}
+
+/** Packs values most significant bit first */
+export class Writer {
+  private buf = new Uint8Array(32);
+  /** Bits written so far */
+  bits = 0;
+
+  private reserve(bits: number): void {
+    const needed = (this.bits + bits + 7) >> 3;
+    if (needed > this.buf.length) {
+      const grown = new Uint8Array(Math.max(needed, this.buf.length * 2));
+      grown.set(this.buf);
+      this.buf = grown;
+    }
+  }
+
+  putUint(bits: number, value: bigint): void {
+    if (value < 0n || value >> BigInt(bits) !== 0n) throw new CodecError(`${value} doesn't fit in ${bits} unsigned bits`);
+    this.reserve(bits);
+    let left = bits;
+    while (left > 0) {
+      const room = 8 - (this.bits & 7);
+      const n = Math.min(room, left);
+      left -= n;
+      this.buf[this.bits >> 3] |= Number((value >> BigInt(left)) & ((1n << BigInt(n)) - 1n)) << (room - n);
+      this.bits += n;
+    }
+  }
+
+  putInt(bits: number, value: number | bigint, signing: Signing = "unsigned", little = false): void {
+    const raw = toRaw(bits, toBig(value), signing);
+    if (!viewable(this.bits, bits, signing)) {
+      this.putUint(bits, little ? swapBytes(raw, bits) : raw);
+      return;
+    }
+    this.reserve(bits);
+    const view = new DataView(this.buf.buffer);
+    const at = this.bits >> 3;
+    if (bits === 8) view.setUint8(at, Number(raw));
+    else if (bits === 16) view.setUint16(at, Number(raw), little);
+    else if (bits === 32) view.setUint32(at, Number(raw), little);
+    else view.setBigUint64(at, raw, little);
+    this.bits += bits;
+  }
+
+  putFloat(bits: number, value: number, little = false): void {
+    const view = new DataView(new ArrayBuffer(bits / 8));
+    if (bits === 32) view.setFloat32(0, value, little);
+    else view.setFloat64(0, value, little);
+    this.putBytes(new Uint8Array(view.buffer));
+  }
+
+  putBytes(data: Uint8Array): void {
+    if (this.bits % 8 !== 0) {
+      for (const byte of data) this.putUint(8, BigInt(byte));
+      return;
+    }
+    this.reserve(data.length * 8);
+    this.buf.set(data, this.bits >> 3);
+    this.bits += data.length * 8;
+  }
+
+  putFixed(data: Uint8Array, length: number): void {
+    checkLen(length, data.length);
+    this.putBytes(data);
+  }
+
+  /** Writes data, then NULs up to capacity */
+  putPadded(data: Uint8Array, capacity: number): void {
+    if (data.length > capacity) throw new CodecError(`${data.length} bytes don't fit in ${capacity}`);
+    this.putBytes(data);
+    this.putBytes(new Uint8Array(capacity - data.length));
+  }
+
+  finish(): Uint8Array {
+    return this.buf.slice(0, (this.bits + 7) >> 3);
+  }
+}
+
+/** Unpacks values most significant bit first */
+export class Reader {
+  private readonly data: Uint8Array;
+  private readonly view: DataView;
+  pos = 0;
+
+  constructor(data: Uint8Array) {
+    this.data = data;
+    this.view = new DataView(data.buffer, data.byteOffset, data.byteLength);
+  }
+
+  /** Bits left to read */
+  get remaining(): number {
+    return this.data.length * 8 - this.pos;
+  }
+
+  getUint(bits: number): bigint {
+    if (bits > this.remaining) throw new Incomplete(`needed ${bits} bits, ${this.remaining} left`);
+    let value = 0n;
+    let left = bits;
+    while (left > 0) {
+      const room = 8 - (this.pos & 7);
+      const n = Math.min(room, left);
+      value = (value << BigInt(n)) | BigInt((this.data[this.pos >> 3] >> (room - n)) & ((1 << n) - 1));
+      this.pos += n;
+      left -= n;
+    }
+    return value;
+  }
+
+  getInt(bits: number, signing: Signing = "unsigned", little = false): bigint {
+    if (!viewable(this.pos, bits, signing) || bits > this.remaining) {
+      const raw = this.getUint(bits);
+      return fromRaw(bits, little ? swapBytes(raw, bits) : raw, signing);
+    }
+    const at = this.pos >> 3;
+    let raw: bigint;
+    if (bits === 8) raw = BigInt(this.view.getUint8(at));
+    else if (bits === 16) raw = BigInt(this.view.getUint16(at, little));
+    else if (bits === 32) raw = BigInt(this.view.getUint32(at, little));
+    else raw = this.view.getBigUint64(at, little);
+    this.pos += bits;
+    return fromRaw(bits, raw, signing);
+  }
+
+  getFloat(bits: number, little = false): number {
+    const bytes = this.getBytes(bits / 8);
+    const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+    return bits === 32 ? view.getFloat32(0, little) : view.getFloat64(0, little);
+  }
+
+  getBytes(length: number): Uint8Array {
+    if (length * 8 > this.remaining) throw new Incomplete(`needed ${length} bytes, ${this.remaining} bits left`);
+    if (this.pos % 8 !== 0) {
+      const out = new Uint8Array(length);
+      for (let i = 0; i < length; i++) out[i] = Number(this.getUint(8));
+      return out;
+    }
+    const start = this.pos >> 3;
+    this.pos += length * 8;
+    // copied, so a decoded value doesn't alias the buffer, which may be a Node Buffer
+    return new Uint8Array(this.data.subarray(start, start + length));
+  }
+
+  expect(data: Uint8Array): void {
+    const found = this.getBytes(data.length);
+    if (found.some((byte, i) => byte !== data[i])) throw new CodecError("constant bytes don't match");
+  }
+
+  /** Consumes sequence if it comes next */
+  take(sequence: Uint8Array): boolean {
+    if (this.remaining < sequence.length * 8) return false;
+    const pos = this.pos;
+    if (this.getBytes(sequence.length).every((byte, i) => byte === sequence[i])) return true;
+    this.pos = pos;
+    return false;
+  }
+
+  /** Reads up to and including sequence, returning the bytes before it */
+  readUntil(sequence: Uint8Array): Uint8Array {
+    const out: number[] = [];
+    while (!this.take(sequence)) out.push(...this.getBytes(1));
+    return Uint8Array.from(out);
+  }
+
+  finish(): void {
+    if (this.remaining >= 8) throw new CodecError(`${this.remaining} bits left over`);
+  }
+}
+
+export function crc32(data: Uint8Array): number {
+  let crc = 0xffffffff;
+  for (const byte of data) {
+    crc ^= byte;
+    for (let i = 0; i < 8; i++) crc = crc & 1 ? (crc >>> 1) ^ 0xedb88320 : crc >>> 1;
+  }
+  return (crc ^ 0xffffffff) >>> 0;
+}
+
+export function crc16Xmodem(data: Uint8Array): number {
+  let crc = 0;
+  for (const byte of data) {
+    crc ^= byte << 8;
+    for (let i = 0; i < 8; i++) crc = (crc & 0x8000 ? (crc << 1) ^ 0x1021 : crc << 1) & 0xffff;
+  }
+  return crc;
+}
+
+const CRCS = { crc32: [crc32, 32], crc16Xmodem: [crc16Xmodem, 16] } as const;
+
+export type Unit = "bits" | "bytes";
+
+export type FrameElement =
+  | { kind: "sizeTotal"; bits: number; unit: Unit }
+  | { kind: "sizeOfPayload"; bits: number; unit: Unit }
+  /** Size of the `covers` elements that immediately follow this one */
+  | { kind: "sizeOfElements"; bits: number; unit: Unit; covers: number }
+  | { kind: "payload" }
+  | { kind: "metadata"; bits: number; little: boolean }
+  | { kind: "crc"; algorithm: "crc32" | "crc16Xmodem" }
+  /** The last `bits` bits of `data` */
+  | { kind: "const"; data: Uint8Array; bits: number };
+
+export interface Frame {
+  metadata: bigint[];
+  payload: Uint8Array;
+  /** Length of the whole frame in bytes */
+  length: number;
+}
+
+function elementBits(element: FrameElement): number {
+  switch (element.kind) {
+    case "payload":
+      return 0;
+    case "crc":
+      return CRCS[element.algorithm][1];
+    default:
+      return element.bits;
+  }
+}
+
+export function envelopeBits(format: readonly FrameElement[]): number {
+  return format.reduce((sum, element) => sum + elementBits(element), 0);
+}
+
+function coveredBits(format: readonly FrameElement[], index: number, covers: number, payloadBits: number): number {
+  return format
+    .slice(index + 1, index + 1 + covers)
+    .reduce((sum, element) => sum + (element.kind === "payload" ? payloadBits : elementBits(element)), 0);
+}
+
+function sizeValue(bits: number, unit: Unit): bigint {
+  return BigInt(unit === "bits" ? bits : (bits + 7) >> 3);
+}
+
+function sizeBits(value: bigint, unit: Unit): number {
+  return Number(value) * (unit === "bits" ? 1 : 8);
+}
+
+function constValue(element: { data: Uint8Array; bits: number }): bigint {
+  const value = element.data.reduce((acc, byte) => (acc << 8n) | BigInt(byte), 0n);
+  return value & ((1n << BigInt(element.bits)) - 1n);
+}
+
+/** Wraps payload in a frame, with a value for each `metadata` element in order */
+export function encodeFrame(format: readonly FrameElement[], metadata: readonly bigint[], payload: Uint8Array): Uint8Array {
+  const payloadBits = payload.length * 8;
+  const total = envelopeBits(format) + payloadBits;
+  const writer = new Writer();
+  if (metadata.length !== format.filter((e) => e.kind === "metadata").length) {
+    throw new CodecError(`the frame format needs a value for each metadata element, got ${metadata.length}`);
+  }
+  let next = 0;
+  for (const [i, element] of format.entries()) {
+    switch (element.kind) {
+      case "sizeTotal":
+        writer.putUint(element.bits, sizeValue(total, element.unit));
+        break;
+      case "sizeOfPayload":
+        writer.putUint(element.bits, sizeValue(payloadBits, element.unit));
+        break;
+      case "sizeOfElements":
+        writer.putUint(element.bits, sizeValue(coveredBits(format, i, element.covers, payloadBits), element.unit));
+        break;
+      case "payload":
+        writer.putBytes(payload);
+        break;
+      case "metadata":
+        writer.putInt(element.bits, metadata[next++], "unsigned", element.little);
+        break;
+      case "crc": {
+        if (writer.bits % 8 !== 0) throw new CodecError(`CRC at bit ${writer.bits} isn't byte aligned`);
+        const [compute, bits] = CRCS[element.algorithm];
+        writer.putUint(bits, BigInt(compute(writer.finish())));
+        break;
+      }
+      case "const":
+        writer.putUint(element.bits, constValue(element));
+        break;
+    }
+  }
+  return writer.finish();
+}
+
+/** Decodes a buffer holding exactly one frame */
+export function decodeFrame(format: readonly FrameElement[], data: Uint8Array): Frame {
+  const frame = parseFrame(format, data, null, true);
+  if (frame === null) throw new Incomplete("the frame ended early");
+  return frame;
+}
+
+/**
+ * Parses a frame from the start of data. payloadLen is the payload's size in bytes, for formats
+ * without a size field. Unless complete is set, running out of data returns null so this can be
+ * called again as more bytes arrive
+ */
+export function parseFrame(format: readonly FrameElement[], data: Uint8Array, payloadLen: number | null, complete: boolean): Frame | null {
+  try {
+    return parse(format, data, payloadLen, complete);
+  } catch (e) {
+    if (e instanceof Incomplete && !complete) return null;
+    throw e;
+  }
+}
+
+function parse(format: readonly FrameElement[], data: Uint8Array, payloadLen: number | null, complete: boolean): Frame {
+  const payloadIndex = format.findIndex((e) => e.kind === "payload");
+  const envelope = envelopeBits(format);
+  const reader = new Reader(data);
+  const metadata: bigint[] = [];
+  let payload: Uint8Array = new Uint8Array(0);
+  let total: number | null = null;
+  let payloadBits: number | null = null;
+  for (const [i, element] of format.entries()) {
+    switch (element.kind) {
+      case "sizeTotal":
+        total = sizeBits(reader.getUint(element.bits), element.unit);
+        if (total < envelope) throw new CodecError(`frame size ${total} bits is smaller than its envelope`);
+        if (total > data.length * 8) throw new Incomplete(`frame is ${total} bits, have ${data.length * 8}`);
+        break;
+      case "sizeOfPayload":
+        payloadBits = sizeBits(reader.getUint(element.bits), element.unit);
+        break;
+      case "sizeOfElements": {
+        const region = sizeBits(reader.getUint(element.bits), element.unit);
+        if (payloadIndex > i && payloadIndex <= i + element.covers) {
+          payloadBits = region - coveredBits(format, i, element.covers, 0);
+          if (payloadBits < 0) throw new CodecError("size field is smaller than the elements it covers");
+        }
+        break;
+      }
+      case "payload": {
+        let bits = payloadBits;
+        if (bits === null && total !== null) bits = total - envelope;
+        else if (bits === null && payloadLen !== null) bits = payloadLen * 8;
+        else if (bits === null && complete) bits = Math.max(reader.remaining - envelopeBits(format.slice(i + 1)), 0);
+        else if (bits === null) throw new CodecError("the frame doesn't say how long its payload is");
+        if (bits % 8 !== 0) throw new CodecError(`payload of ${bits} bits isn't whole bytes`);
+        payload = reader.getBytes(bits / 8);
+        break;
+      }
+      case "metadata":
+        metadata.push(reader.getInt(element.bits, "unsigned", element.little));
+        break;
+      case "crc": {
+        if (reader.pos % 8 !== 0) throw new CodecError(`CRC at bit ${reader.pos} isn't byte aligned`);
+        const [compute, bits] = CRCS[element.algorithm];
+        const expected = BigInt(compute(data.subarray(0, reader.pos >> 3)));
+        const found = reader.getUint(bits);
+        if (expected !== found) throw new CodecError(`CRC mismatch: expected 0x${expected.toString(16)}, found 0x${found.toString(16)}`);
+        break;
+      }
+      case "const":
+        if (reader.getUint(element.bits) !== constValue(element)) throw new CodecError("constant bits don't match");
+        break;
+    }
+  }
+  if (total !== null && (total + 7) >> 3 !== (reader.pos + 7) >> 3) throw new CodecError(`frame size ${total} bits doesn't match its contents`);
+  if (complete && reader.remaining >= 8) throw new CodecError(`${reader.remaining} bits left over after the frame`);
+  return { metadata, payload, length: (reader.pos + 7) >> 3 };
+}
+"#;
+
+const FRAME_FNS: &str = r#"
+function formatOf<T>(codec: wire.PayloadCodec<T>): readonly wire.FrameElement[] {
+  return codec.direction === "tx" ? TX_FORMAT : RX_FORMAT;
+}
+
+/** Encodes a payload wrapped in its frame */
+export function pack<T>(codec: wire.PayloadCodec<T>, value: T): Uint8Array {
+  return wire.encodeFrame(formatOf(codec), codec.metadata.map((values) => values[0]), wire.encode(codec, value));
+}
+
+/** Decodes a buffer holding exactly one frame of a payload */
+export function unpack<T>(codec: wire.PayloadCodec<T>, data: Uint8Array): T {
+  const found = wire.decodeFrame(formatOf(codec), data);
+  if (!wire.matches(codec, found.metadata)) throw new wire.CodecError(`frame metadata ${found.metadata} isn't ${codec.name}'s`);
+  return wire.decode(codec, found.payload);
+}
+"#;
+
+const TRANSPORTS: &str = r#"
+/** The device didn't answer in time */
+export class Timeout extends Error {
+  constructor(message: string) {
+    super(message);
+    this.name = "Timeout";
+  }
+}
+
+/** The device answered with a different payload than the transaction expects */
+export class UnexpectedPayload extends wire.CodecError {}
+
+/** Where the device reads and writes bytes */
+export interface Transport {
+  write(data: Uint8Array): Promise<void>;
+  /** Resolves with exactly length bytes, or rejects with a Timeout */
+  read(length: number): Promise<Uint8Array>;
+  /** Drops anything received but not yet read */
+  discardInput(): void;
+}
+
+/** Bytes received but not yet read, shared by the stream transports */
+class Inbox {
+  private chunks: Uint8Array[] = [];
+  private size = 0;
+  private closed: Error | null = null;
+  private wake: (() => void) | null = null;
+
+  push(chunk: Uint8Array): void {
+    this.chunks.push(chunk);
+    this.size += chunk.length;
+    this.notify();
+  }
+
+  close(reason: Error): void {
+    this.closed = reason;
+    this.notify();
+  }
+
+  clear(): void {
+    this.chunks = [];
+    this.size = 0;
+  }
+
+  async take(length: number, timeoutMs: number): Promise<Uint8Array> {
+    const deadline = Date.now() + timeoutMs;
+    while (this.size < length) {
+      if (this.closed !== null) throw this.closed;
+      const left = deadline - Date.now();
+      if (left <= 0) throw new Timeout(`read ${this.size} of ${length} bytes`);
+      await new Promise<void>((resolve) => {
+        const timer = setTimeout(resolve, left);
+        this.wake = () => {
+          clearTimeout(timer);
+          resolve();
+        };
+      });
+    }
+    const out = new Uint8Array(length);
+    let filled = 0;
+    while (filled < length) {
+      const chunk = this.chunks[0];
+      const n = Math.min(chunk.length, length - filled);
+      out.set(chunk.subarray(0, n), filled);
+      filled += n;
+      if (n === chunk.length) this.chunks.shift();
+      else this.chunks[0] = chunk.subarray(n);
+    }
+    this.size -= length;
+    return out;
+  }
+
+  private notify(): void {
+    const wake = this.wake;
+    this.wake = null;
+    if (wake !== null) wake();
+  }
+}
+
+/** The parts of a Node duplex stream, such as serialport's `SerialPort`, the transport uses */
+export interface DuplexLike {
+  write(chunk: Uint8Array, callback: (error?: Error | null) => void): unknown;
+  on(event: "data", listener: (chunk: Uint8Array) => void): unknown;
+}
+
+/** A Node duplex stream, such as a serialport `SerialPort` */
+export class NodeStreamTransport implements Transport {
+  private readonly inbox = new Inbox();
+  private readonly stream: DuplexLike;
+  private readonly timeoutMs: number;
+
+  constructor(stream: DuplexLike, timeoutMs = 1000) {
+    this.stream = stream;
+    this.timeoutMs = timeoutMs;
+    stream.on("data", (chunk) => this.inbox.push(new Uint8Array(chunk)));
+  }
+
+  write(data: Uint8Array): Promise<void> {
+    return new Promise((resolve, reject) => {
+      this.stream.write(data, (error) => (error ? reject(error) : resolve()));
+    });
+  }
+
+  read(length: number): Promise<Uint8Array> {
+    return this.inbox.take(length, this.timeoutMs);
+  }
+
+  discardInput(): void {
+    this.inbox.clear();
+  }
+}
+
+/**
+ * A pair of byte streams, such as a Web Serial `SerialPort`'s `readable` and `writable`. It holds
+ * their locks until closed
+ */
+export class WebStreamTransport implements Transport {
+  private readonly inbox = new Inbox();
+  private readonly reader: ReadableStreamDefaultReader<Uint8Array>;
+  private readonly writer: WritableStreamDefaultWriter<Uint8Array>;
+  private readonly timeoutMs: number;
+
+  constructor(readable: ReadableStream<Uint8Array>, writable: WritableStream<Uint8Array>, timeoutMs = 1000) {
+    this.reader = readable.getReader();
+    this.writer = writable.getWriter();
+    this.timeoutMs = timeoutMs;
+    void this.pump();
+  }
+
+  private async pump(): Promise<void> {
+    try {
+      for (;;) {
+        const { value, done } = await this.reader.read();
+        if (done) break;
+        this.inbox.push(value);
+      }
+      this.inbox.close(new Error("stream closed"));
+    } catch (e) {
+      this.inbox.close(e instanceof Error ? e : new Error(String(e)));
+    }
+  }
+
+  async write(data: Uint8Array): Promise<void> {
+    await this.writer.write(data);
+  }
+
+  read(length: number): Promise<Uint8Array> {
+    return this.inbox.take(length, this.timeoutMs);
+  }
+
+  discardInput(): void {
+    this.inbox.clear();
+  }
+
+  /** Releases the streams, so the port can be closed */
+  async close(): Promise<void> {
+    await this.reader.cancel();
+    this.reader.releaseLock();
+    this.writer.releaseLock();
+  }
+}
+"#;
+
+const RECEIVE_FRAMED: &str = r#"
+let buffer = new Uint8Array(0);
+let found: wire.Frame | null = null;
+while (found === null) {
+  const byte = await this.transport.read(1);
+  const grown = new Uint8Array(buffer.length + 1);
+  grown.set(buffer);
+  grown.set(byte, buffer.length);
+  buffer = grown;
+  found = wire.parseFrame(RX_FORMAT, buffer, codec.fixedLen, false);
+}
+if (!wire.matches(codec, found.metadata)) {
+  throw new UnexpectedPayload(`expected ${codec.name}, got metadata ${found.metadata}`);
+}
+return wire.decode(codec, found.payload);
+"#;
+
+const TEST_MOCKS: &str = r#"
+/**
+ * Stands in for a serial port as both a Node duplex and a pair of web streams. It replays canned
+ * responses in batches, the first straight away and another after each write, and records writes
+ */
+class MockPort {
+  readonly written: Uint8Array[] = [];
+  readonly readable: ReadableStream<Uint8Array>;
+  readonly writable: WritableStream<Uint8Array>;
+  private readonly batches: Uint8Array[][];
+  private readonly listeners: ((chunk: Uint8Array) => void)[] = [];
+  private controller: ReadableStreamDefaultController<Uint8Array> | null = null;
+
+  constructor(batches: Uint8Array[][]) {
+    this.batches = batches;
+    this.readable = new ReadableStream({
+      start: (controller) => {
+        this.controller = controller;
+      },
+    });
+    this.writable = new WritableStream({ write: (chunk) => this.received(chunk) });
+    setTimeout(() => this.respond(), 0);
+  }
+
+  /** Batches not yet sent */
+  get pending(): number {
+    return this.batches.filter((batch) => batch.length > 0).length;
+  }
+
+  write(chunk: Uint8Array, callback: (error?: Error | null) => void): boolean {
+    this.received(chunk);
+    callback(null);
+    return true;
+  }
+
+  on(event: "data", listener: (chunk: Uint8Array) => void): this {
+    this.listeners.push(listener);
+    return this;
+  }
+
+  private received(chunk: Uint8Array): void {
+    this.written.push(chunk);
+    setTimeout(() => this.respond(), 0);
+  }
+
+  private respond(): void {
+    for (const chunk of this.batches.shift() ?? []) {
+      for (const listener of this.listeners) listener(chunk);
+      if (this.controller !== null) this.controller.enqueue(chunk);
+    }
+  }
+}
+
+function transportFor(kind: "node" | "web", port: MockPort) {
+  return kind === "node" ? new NodeStreamTransport(port, 200) : new WebStreamTransport(port.readable, port.writable, 200);
+}
+"#;
@@ -0,0 +1,803 @@
+//! Generates a Wireshark Lua dissector. Every envelope element and payload field becomes a named
+//! protocol field, so frames can be filtered on, for example `rich.tx.set_name.len > 4`.
+//!
+//! Like the `python` backend, frame formats and payload layouts are emitted as tables and
+//! interpreted by a fixed runtime section of the script, which follows `openpid_runtime::frame`:
+//! frames are split using their size fields, or the payload's static size, and payloads are
+//! identified by their metadata values. Bad CRCs, unexpected constants and unknown payloads are
+//! reported as expert info. Captures that don't record which way a frame went are tried both ways.
+
+use std::collections::BTreeMap;
+
+use super::{BackendInfo, BackendOptions, CodeWriter, Codegen, CodegenError, OptionInfo, OutputSink};
+use crate::ir::*;
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "wireshark",
+    description: "Wireshark Lua dissector for captured frames",
+    options: &[OptionInfo { name: "protocol", description: "Filter name of the protocol", default: Some("the device's name, in snake case") }],
+    create: |options| Ok(Box::new(WiresharkBackend::new(options)?)),
+};
+
+pub struct WiresharkBackend {
+    protocol: Option<String>,
+}
+
+impl WiresharkBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        let protocol = options.get("protocol").map(str::to_owned);
+        if let Some(protocol) = &protocol {
+            if !valid_protocol(protocol) {
+                return Err(CodegenError::BadOption { option: "protocol".to_owned(), value: protocol.clone(), expected: "lower case letters, digits and underscores" });
+            }
+        }
+        Ok(Self { protocol })
+    }
+}
+
+fn valid_protocol(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase()) && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn unsupported(what: impl Into<String>) -> CodegenError {
+    CodegenError::Unsupported { backend: "wireshark", what: what.into() }
+}
+
+impl Codegen for WiresharkBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let protocol = self.protocol.clone().unwrap_or_else(|| match ir.device.name.snake() {
+            name if valid_protocol(&name) => name,
+            name => format!("openpid_{name}"),
+        });
+        let framing = ir.framing.as_ref().ok_or_else(|| unsupported("documents without a frame format, since nothing marks where a payload starts"))?;
+        let gen = Gen { ir, framing, protocol: &protocol };
+        out.write_text(&format!("{protocol}.lua"), &gen.script()?)
+    }
+}
+
+struct Gen<'a> {
+    ir: &'a Ir,
+    framing: &'a Framing,
+    protocol: &'a str,
+}
+
+/// A Lua string literal. Anything outside printable ASCII is written as decimal escapes, which
+/// every Lua version reads the same way
+fn lua_str(text: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in text {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7E => out.push(b as char),
+            _ => out.push_str(&format!("\\{b:03}")),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The `ProtoField` constructor for an unsigned or signed integer of this width
+fn int_constructor(bits: u32, signed: bool) -> String {
+    let width = match bits {
+        0..=8 => 8,
+        9..=16 => 16,
+        17..=24 => 24,
+        25..=32 => 32,
+        _ => 64,
+    };
+    format!("ProtoField.{}int{width}", if signed { "" } else { "u" })
+}
+
+fn unit(unit: BitsOrBytes) -> u32 {
+    match unit {
+        BitsOrBytes::Bits => 1,
+        BitsOrBytes::Bytes => 8,
+    }
+}
+
+impl Gen<'_> {
+    fn script(&self) -> Result<String, CodegenError> {
+        let ir = self.ir;
+        let protocol = self.protocol;
+        let mut w = CodeWriter::new("    ");
+        w.comment("--", &format!("Wireshark dissector for the {}: {}", ir.device.name, ir.device.description));
+        w.comment("--", "Generated by openpid from the device's OpenPID document. Changes will be overwritten.");
+        w.line("--");
+        w.comment("--", "Copy this file into Wireshark's personal Lua plugins folder, listed under Help > About Wireshark >");
+        w.comment("--", "Folders. It dissects captures with the USER0 link type (DLT 147), and TCP streams chosen with");
+        w.comment("--", "Decode As, such as a serial port shared over the network. A packet may hold several frames.");
+        w.blank();
+        w.line(format!("local proto = Proto({}, {})", lua_str(protocol.as_bytes()), lua_str(format!("{} (OpenPID)", ir.device.name).as_bytes())));
+        w.blank();
+        w.line("proto.prefs.direction = Pref.enum(\"Direction\", 0, \"Which way frames travel, when the capture doesn't record it\", {");
+        w.indent();
+        w.line("{ 1, \"Automatic\", 0 },");
+        w.line("{ 2, \"To the device (TX)\", 1 },");
+        w.line("{ 3, \"From the device (RX)\", 2 },");
+        w.dedent();
+        w.line("}, false)");
+        w.blank();
+
+        w.line("local f = {}");
+        w.line(format!("f.direction = ProtoField.string(\"{protocol}.direction\", \"Direction\")"));
+        w.line(format!("f.payload = ProtoField.string(\"{protocol}.payload\", \"Payload\")"));
+        w.line(format!("f.data = ProtoField.bytes(\"{protocol}.data\", \"Unknown payload\")"));
+        let mut formats = Vec::new();
+        for direction in [Direction::Tx, Direction::Rx] {
+            w.blank();
+            formats.push(self.envelope_fields(&mut w, direction)?);
+        }
+        for st in ir.structs.iter().filter(|st| !st.fields.is_empty()) {
+            w.blank();
+            self.proto_fields(&mut w, &format!("struct.{}", st.name.snake()), &st.fields);
+        }
+        for payload in ir.all_payloads().filter(|p| !p.fields.is_empty()) {
+            w.blank();
+            self.proto_fields(&mut w, &format!("{}.{}", key(payload.direction), payload.name.snake()), &payload.fields);
+        }
+        w.blank();
+        w.line("local experts = {");
+        w.indent();
+        for (name, abbrev, text, group, severity) in [
+            ("bad_crc", "crc.bad", "Bad CRC", "CHECKSUM", "ERROR"),
+            ("bad_const", "const.bad", "Unexpected constant", "MALFORMED", "ERROR"),
+            ("bad_size", "size.bad", "Size doesn't match the frame", "MALFORMED", "ERROR"),
+            ("malformed", "malformed", "Malformed payload", "MALFORMED", "ERROR"),
+            ("unknown_payload", "payload.unknown", "No payload has these metadata values", "UNDECODED", "WARN"),
+            ("truncated", "truncated", "Incomplete frame", "MALFORMED", "WARN"),
+        ] {
+            w.line(format!("{name} = ProtoExpert.new(\"{protocol}.{abbrev}\", \"{text}\", expert.group.{group}, expert.severity.{severity}),"));
+        }
+        w.dedent();
+        w.line("}");
+        w.blank();
+        w.line("local fields = {}");
+        w.line("for _, field in pairs(f) do");
+        w.line("    fields[#fields + 1] = field");
+        w.line("end");
+        w.line("proto.fields = fields");
+        w.line("proto.experts = { experts.bad_crc, experts.bad_const, experts.bad_size, experts.malformed, experts.unknown_payload, experts.truncated }");
+        w.blank();
+
+        w.comment("--", "Field layouts. Lengths are { fixed = n }, { count = \"field\" }, { sequence = \"terminator\" } or { remainder = true }");
+        w.line("local structs = {}");
+        for st in &ir.structs {
+            w.line(format!("structs[{}] = {{", lua_str(st.name.snake().as_bytes())));
+            w.indent();
+            self.layout(&mut w, &format!("struct.{}", st.name.snake()), &st.fields)?;
+            w.dedent();
+            w.line("}");
+        }
+        w.blank();
+        w.comment("--", "Payloads by direction. `metadata` lists the accepted values of each of the format's metadata elements");
+        w.line("local payloads = { tx = {}, rx = {} }");
+        for payload in ir.all_payloads() {
+            let direction = key(payload.direction);
+            let metadata: Vec<String> = self
+                .framing
+                .metadata(payload.direction)
+                .iter()
+                .map(|(name, _)| {
+                    let values = payload.metadata(name.raw()).and_then(Metadata::packed).unwrap_or_default();
+                    format!("{{ {} }}", values.iter().map(u64::to_string).collect::<Vec<_>>().join(", "))
+                })
+                .collect();
+            w.line(format!("payloads.{direction}[#payloads.{direction} + 1] = {{"));
+            w.indent();
+            w.line(format!("name = {},", lua_str(payload.name.raw().as_bytes())));
+            w.line(format!("metadata = {{ {} }},", metadata.join(", ")));
+            if let Some(bits) = payload.size.fixed_bits() {
+                w.line(format!("bits = {bits},"));
+            }
+            if payload.fields.is_empty() {
+                w.line("fields = {},");
+            } else {
+                w.line("fields = {");
+                w.indent();
+                self.layout(&mut w, &format!("{direction}.{}", payload.name.snake()), &payload.fields)?;
+                w.dedent();
+                w.line("},");
+            }
+            w.dedent();
+            w.line("}");
+        }
+        w.blank();
+        w.comment("--", "Frame formats, in wire order. `after` is the envelope bits following the payload");
+        w.line("local formats = {}");
+        for (direction, elements) in [Direction::Tx, Direction::Rx].into_iter().zip(formats) {
+            let envelope: u64 = elements.iter().map(|(e, _)| e.bits()).sum();
+            w.line(format!("formats.{} = {{", key(direction)));
+            w.indent();
+            w.line(format!("envelope_bits = {envelope},"));
+            for (i, (element, field)) in elements.iter().enumerate() {
+                let after: u64 = elements[i + 1..].iter().map(|(e, _)| e.bits()).sum();
+                w.line(format!("{},", self.element(element, field.as_deref(), after)?));
+            }
+            w.dedent();
+            w.line("}");
+        }
+        w.blank();
+        w.line(RUNTIME.trim());
+        Ok(w.finish())
+    }
+
+    /// Declares a direction's envelope fields, returning its elements with the key of each one's field
+    fn envelope_fields(&self, w: &mut CodeWriter, direction: Direction) -> Result<Vec<(FlatElement, Option<String>)>, CodegenError> {
+        let protocol = self.protocol;
+        let elements = self.framing.flatten(direction).ok_or_else(|| unsupported("metadata other than integers and strings up to 8 bytes"))?;
+        let mut used: BTreeMap<String, usize> = BTreeMap::new();
+        let mut out = Vec::new();
+        // a metadata element's values name the payloads they identify, when it's the only one
+        let metadata = self.framing.metadata(direction);
+        for element in elements {
+            let (base, label) = match &element {
+                FlatElement::SizeTotal { .. } => ("frame_size".to_owned(), "Frame size".to_owned()),
+                FlatElement::SizeOfPayload { .. } => ("payload_size".to_owned(), "Payload size".to_owned()),
+                FlatElement::SizeOfElements { .. } => ("size".to_owned(), "Size".to_owned()),
+                FlatElement::Payload => {
+                    out.push((element, None));
+                    continue;
+                }
+                FlatElement::Metadata { name, .. } => (name.snake(), name.raw().to_owned()),
+                FlatElement::Crc(Crc::Crc32) => ("crc".to_owned(), "CRC-32".to_owned()),
+                FlatElement::Crc(Crc::Crc16XModem) => ("crc".to_owned(), "CRC-16/XMODEM".to_owned()),
+                FlatElement::Const { .. } => ("const".to_owned(), "Constant".to_owned()),
+            };
+            let count = used.entry(base.clone()).or_default();
+            *count += 1;
+            let name = if *count == 1 { base } else { format!("{base}_{count}") };
+            let field_key = format!("frame.{}.{name}", key(direction));
+            let abbrev = format!("{protocol}.{field_key}");
+            let declaration = match &element {
+                FlatElement::Crc(_) => format!("{}({}, {}, base.HEX)", int_constructor(element.bits() as u32, false), lua_str(abbrev.as_bytes()), lua_str(label.as_bytes())),
+                FlatElement::Const { bits, .. } if bits % 8 == 0 => format!("ProtoField.bytes({}, {})", lua_str(abbrev.as_bytes()), lua_str(label.as_bytes())),
+                FlatElement::Const { bits, .. } if *bits > 32 => return Err(unsupported("constants over 32 bits that aren't whole bytes")),
+                FlatElement::Const { bits, .. } => format!("{}({}, {}, base.HEX)", int_constructor(*bits, false), lua_str(abbrev.as_bytes()), lua_str(label.as_bytes())),
+                FlatElement::Metadata { name, bits, .. } if metadata.len() == 1 && *bits <= 32 => {
+                    let names: Vec<String> = self
+                        .ir
+                        .payloads(direction)
+                        .iter()
+                        .flat_map(|p| p.metadata(name.raw()).and_then(Metadata::packed).unwrap_or_default().into_iter().map(move |v| format!("[{v}] = {}", lua_str(p.name.raw().as_bytes()))))
+                        .collect();
+                    format!("{}({}, {}, base.DEC, {{ {} }})", int_constructor(*bits, false), lua_str(abbrev.as_bytes()), lua_str(label.as_bytes()), names.join(", "))
+                }
+                _ => format!("{}({}, {}, base.DEC)", int_constructor(element.bits() as u32, false), lua_str(abbrev.as_bytes()), lua_str(label.as_bytes())),
+            };
+            w.line(format!("f[{}] = {declaration}", lua_str(field_key.as_bytes())));
+            out.push((element, Some(field_key)));
+        }
+        Ok(out)
+    }
+
+    /// Declares a `ProtoField` for each field of a struct or payload
+    fn proto_fields(&self, w: &mut CodeWriter, owner: &str, fields: &[Field]) {
+        for field in fields {
+            let field_key = format!("{owner}.{}", field.name.snake());
+            let abbrev = lua_str(format!("{}.{field_key}", self.protocol).as_bytes());
+            let label = match &field.units {
+                Some(units) => format!("{} ({units})", field.name.raw()),
+                None => field.name.raw().to_owned(),
+            };
+            let label = lua_str(label.as_bytes());
+            let description = field.description.as_deref().map(|d| lua_str(d.as_bytes())).unwrap_or_else(|| "nil".to_owned());
+            let declaration = match &field.ty {
+                Type::Int { bits, signing, .. } => {
+                    format!("{}({abbrev}, {label}, base.DEC, nil, nil, {description})", int_constructor(*bits, *signing != Signing::Unsigned))
+                }
+                Type::Float { bits: 32, .. } => format!("ProtoField.float({abbrev}, {label}, nil, {description})"),
+                Type::Float { .. } => format!("ProtoField.double({abbrev}, {label}, nil, {description})"),
+                Type::String(_) => format!("ProtoField.string({abbrev}, {label}, base.UNICODE, {description})"),
+                Type::Const(_) if !field.size.fixed_bits().unwrap_or(0).is_multiple_of(8) => {
+                    format!("{}({abbrev}, {label}, base.HEX, nil, nil, {description})", int_constructor(field.size.fixed_bits().unwrap_or(0) as u32, false))
+                }
+                Type::Bytes(_) | Type::Const(_) => format!("ProtoField.bytes({abbrev}, {label}, base.NONE, {description})"),
+                Type::Struct(_) | Type::Array { .. } => format!("ProtoField.none({abbrev}, {label}, {description})"),
+            };
+            w.line(format!("f[{}] = {declaration}", lua_str(field_key.as_bytes())));
+        }
+    }
+
+    /// A struct or payload's fields, as entries of a Lua table
+    fn layout(&self, w: &mut CodeWriter, owner: &str, fields: &[Field]) -> Result<(), CodegenError> {
+        for field in fields {
+            let mut entry = vec![format!("name = {}", lua_str(field.name.raw().as_bytes())), format!("field = f[{}]", lua_str(format!("{owner}.{}", field.name.snake()).as_bytes()))];
+            match &field.ty {
+                Type::Int { bits, signing, endianness } => {
+                    entry.push("kind = \"int\"".to_owned());
+                    entry.push(format!("bits = {bits}"));
+                    match signing {
+                        Signing::Unsigned => (),
+                        Signing::TwosComplement => entry.push("signing = \"twos\"".to_owned()),
+                        Signing::OnesComplement => entry.push("signing = \"ones\"".to_owned()),
+                    }
+                    if *endianness == Endianness::LittleEndian && *bits > 8 {
+                        entry.push("little = true".to_owned());
+                    }
+                }
+                Type::Float { bits, endianness } => {
+                    entry.push("kind = \"float\"".to_owned());
+                    entry.push(format!("bits = {bits}"));
+                    if *endianness == Endianness::LittleEndian {
+                        entry.push("little = true".to_owned());
+                    }
+                }
+                Type::Bytes(len) | Type::String(len) => {
+                    entry.push(format!("kind = \"{}\"", if matches!(field.ty, Type::String(_)) { "string" } else { "bytes" }));
+                    entry.push(format!("length = {}", length(len)));
+                    if matches!(len, Length::Capacity(_)) {
+                        entry.push("padded = true".to_owned());
+                    }
+                }
+                Type::Const(data) => {
+                    let bits = field.size.fixed_bits().unwrap_or(data.len() as u64 * 8);
+                    entry.push("kind = \"const\"".to_owned());
+                    entry.push(format!("bits = {bits}"));
+                    entry.push(const_value(data, bits)?);
+                }
+                Type::Struct(name) => {
+                    entry.push("kind = \"struct\"".to_owned());
+                    entry.push(format!("struct = {}", lua_str(name.snake().as_bytes())));
+                }
+                Type::Array { item, len } => {
+                    entry.push("kind = \"array\"".to_owned());
+                    entry.push(format!("struct = {}", lua_str(item.snake().as_bytes())));
+                    entry.push(format!("length = {}", length(len)));
+                }
+            }
+            w.line(format!("{{ {} }},", entry.join(", ")));
+        }
+        Ok(())
+    }
+
+    fn element(&self, element: &FlatElement, field: Option<&str>, after: u64) -> Result<String, CodegenError> {
+        let field = field.map(|k| format!(", field = f[{}]", lua_str(k.as_bytes()))).unwrap_or_default();
+        Ok(match element {
+            FlatElement::SizeTotal { bits, unit: u } => format!("{{ kind = \"size_total\", bits = {bits}, unit = {}{field} }}", unit(*u)),
+            FlatElement::SizeOfPayload { bits, unit: u } => format!("{{ kind = \"size_of_payload\", bits = {bits}, unit = {}{field} }}", unit(*u)),
+            FlatElement::SizeOfElements { bits, unit: u, static_bits, has_payload, .. } => {
+                format!("{{ kind = \"size_of_elements\", bits = {bits}, unit = {}, static_bits = {static_bits}, has_payload = {has_payload}{field} }}", unit(*u))
+            }
+            FlatElement::Payload => format!("{{ kind = \"payload\", bits = 0, after = {after} }}"),
+            FlatElement::Metadata { bits, endianness, .. } => {
+                let little = if *endianness == Endianness::LittleEndian && *bits > 8 { ", little = true" } else { "" };
+                format!("{{ kind = \"metadata\", bits = {bits}{little}{field} }}")
+            }
+            FlatElement::Crc(Crc::Crc32) => format!("{{ kind = \"crc\", bits = 32, algorithm = \"crc32\"{field} }}"),
+            FlatElement::Crc(Crc::Crc16XModem) => format!("{{ kind = \"crc\", bits = 16, algorithm = \"crc16_xmodem\"{field} }}"),
+            FlatElement::Const { data, bits } => format!("{{ kind = \"const\", bits = {bits}, {}{field} }}", const_value(data, *bits as u64)?),
+        })
+    }
+}
+
+fn key(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Tx => "tx",
+        Direction::Rx => "rx",
+    }
+}
+
+fn length(len: &Length) -> String {
+    match len {
+        Length::Fixed(n) | Length::Capacity(n) => format!("{{ fixed = {n} }}"),
+        Length::CountField(name) => format!("{{ count = {} }}", lua_str(name.raw().as_bytes())),
+        Length::Sequence(sequence) => format!("{{ sequence = {} }}", lua_str(sequence)),
+        Length::Remainder => "{ remainder = true }".to_owned(),
+    }
+}
+
+/// The last `bits` bits of `data`: `data = "..."` when they're whole bytes, or `value = n`
+fn const_value(data: &[u8], bits: u64) -> Result<String, CodegenError> {
+    if bits.is_multiple_of(8) {
+        return Ok(format!("data = {}", lua_str(&data[data.len() - bits as usize / 8..])));
+    }
+    if bits > 32 {
+        return Err(unsupported("constants over 32 bits that aren't whole bytes"));
+    }
+    let value = data.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64) & ((1 << bits) - 1);
+    Ok(format!("value = {value}"))
+}
+
+const RUNTIME: &str = r#"
+-- Everything below is the same for every device
+
+local bit = bit or bit32
+
+local function tonum(value)
+    if type(value) == "number" then
+        return value
+    end
+    return value:tonumber()
+end
+
+-- The bytes covering `bits` bits from bit `pos` of `tvb`
+local function span(tvb, pos, bits)
+    local first = math.floor(pos / 8)
+    return tvb(first, math.ceil((pos + bits) / 8) - first)
+end
+
+-- `n` bytes starting at any bit position, as a ByteArray, and the range they came from
+local function bytes_at(tvb, pos, n)
+    local range = span(tvb, pos, n * 8)
+    if pos % 8 == 0 then
+        return range:bytes(), range
+    end
+    local data = ByteArray.new()
+    data:set_size(n)
+    for i = 0, n - 1 do
+        data:set_index(i, range:bitfield(pos % 8 + i * 8, 8))
+    end
+    return data, range
+end
+
+-- An unsigned integer, most significant bit first: a number up to 32 bits and a UInt64 above
+local function read_uint(tvb, pos, bits, little)
+    local range = span(tvb, pos, bits)
+    if not little then
+        return range:bitfield(pos % 8, bits), range
+    end
+    local data = bytes_at(tvb, pos, bits / 8)
+    local low, high = 0, 0
+    for i = data:len() - 1, 0, -1 do
+        if i >= 4 then
+            high = high * 256 + data:get_index(i)
+        else
+            low = low * 256 + data:get_index(i)
+        end
+    end
+    if bits <= 32 then
+        return low, range
+    end
+    return UInt64.new(low, high), range
+end
+
+local function to_signed(value, bits, signing)
+    if bits <= 32 then
+        if value < 2 ^ (bits - 1) then
+            return value
+        elseif signing == "ones" then
+            return value - (2 ^ bits - 1)
+        end
+        return value - 2 ^ bits
+    end
+    local signed = Int64.new(value:lower(), value:higher())
+    local negative
+    if bits == 64 then
+        negative = signed < Int64.new(0)
+    else
+        negative = value:rshift(bits - 1):tonumber() == 1
+        if negative then
+            signed = signed - Int64.new(1):lshift(bits)
+        end
+    end
+    if negative and signing == "ones" then
+        signed = signed + Int64.new(1)
+    end
+    return signed
+end
+
+local function read_float(tvb, pos, bits, little)
+    local data, range = bytes_at(tvb, pos, bits / 8)
+    local format = (little and "<" or ">") .. (bits == 32 and "f" or "d")
+    return Struct.unpack(format, data:raw()), range
+end
+
+local crc16_table, crc32_table = {}, {}
+for i = 0, 255 do
+    local c16, c32 = bit.lshift(i, 8), i
+    for _ = 1, 8 do
+        if bit.band(c16, 0x8000) ~= 0 then
+            c16 = bit.bxor(bit.lshift(c16, 1), 0x1021)
+        else
+            c16 = bit.lshift(c16, 1)
+        end
+        c16 = bit.band(c16, 0xFFFF)
+        if bit.band(c32, 1) ~= 0 then
+            c32 = bit.bxor(bit.rshift(c32, 1), 0xEDB88320)
+        else
+            c32 = bit.rshift(c32, 1)
+        end
+    end
+    crc16_table[i] = c16
+    crc32_table[i] = c32
+end
+
+local function crc(algorithm, data)
+    local value
+    if algorithm == "crc16_xmodem" then
+        value = 0
+        for i = 0, data:len() - 1 do
+            local index = bit.band(bit.bxor(bit.rshift(value, 8), data:get_index(i)), 0xFF)
+            value = bit.band(bit.bxor(bit.lshift(value, 8), crc16_table[index]), 0xFFFF)
+        end
+        return value
+    end
+    value = 0xFFFFFFFF
+    for i = 0, data:len() - 1 do
+        value = bit.bxor(bit.rshift(value, 8), crc32_table[bit.band(bit.bxor(value, data:get_index(i)), 0xFF)])
+    end
+    return bit.bnot(value) % 4294967296
+end
+
+local function at_sequence(tvb, pos, limit, sequence)
+    return pos + #sequence * 8 <= limit and bytes_at(tvb, pos, #sequence):raw() == sequence
+end
+
+-- Reads a constant, returning its value, the range it came from and whether it matched
+local function read_const(tvb, pos, d)
+    if d.data then
+        local data, range = bytes_at(tvb, pos, #d.data)
+        return data, range, data:raw() == d.data
+    end
+    local value, range = read_uint(tvb, pos, d.bits, false)
+    return value, range, value == d.value
+end
+
+local dissect_fields
+
+local function dissect_group(tvb, pos, limit, fields, item)
+    local start = pos
+    pos = dissect_fields(tvb, pos, limit, fields, item)
+    item:set_len(math.ceil(pos / 8) - math.floor(start / 8))
+    return pos
+end
+
+-- Adds fields from bit `pos` to the tree, returning the bit after the last one
+dissect_fields = function(tvb, pos, limit, fields, tree)
+    local values = {}
+    for _, d in ipairs(fields) do
+        if d.kind == "int" then
+            local value, range = read_uint(tvb, pos, d.bits, d.little)
+            if d.signing then
+                value = to_signed(value, d.bits, d.signing)
+            end
+            tree:add(d.field, range, value)
+            values[d.name] = value
+            pos = pos + d.bits
+        elseif d.kind == "float" then
+            local value, range = read_float(tvb, pos, d.bits, d.little)
+            tree:add(d.field, range, value)
+            pos = pos + d.bits
+        elseif d.kind == "const" then
+            local value, range, ok = read_const(tvb, pos, d)
+            local item = tree:add(d.field, range, value)
+            if not ok then
+                item:add_proto_expert_info(experts.bad_const)
+            end
+            pos = pos + d.bits
+        elseif d.kind == "bytes" or d.kind == "string" then
+            local count, terminator = nil, 0
+            if d.length.fixed then
+                count = d.length.fixed
+            elseif d.length.count then
+                count = tonum(values[d.length.count])
+            elseif d.length.sequence then
+                count, terminator = 0, #d.length.sequence * 8
+                while not at_sequence(tvb, pos + count * 8, limit, d.length.sequence) do
+                    if pos + count * 8 >= limit then
+                        error("no terminator after " .. d.name)
+                    end
+                    count = count + 1
+                end
+            else
+                count = math.floor((limit - pos) / 8)
+            end
+            local data, range = bytes_at(tvb, pos, count)
+            local value = data
+            if d.kind == "string" then
+                value = data:raw()
+                local nul = d.padded and string.find(value, "\0", 1, true)
+                if nul then
+                    value = string.sub(value, 1, nul - 1)
+                end
+            end
+            tree:add(d.field, range, value)
+            pos = pos + count * 8 + terminator
+        elseif d.kind == "struct" then
+            pos = dissect_group(tvb, pos, limit, structs[d.struct], tree:add(d.field, span(tvb, pos, 0)))
+        elseif d.kind == "array" then
+            local start = pos
+            local item = tree:add(d.field, span(tvb, pos, 0))
+            local count = d.length.fixed or (d.length.count and tonum(values[d.length.count]))
+            local i = 0
+            while true do
+                if d.length.sequence then
+                    if at_sequence(tvb, pos, limit, d.length.sequence) then
+                        pos = pos + #d.length.sequence * 8
+                        break
+                    elseif pos >= limit then
+                        error("no terminator after " .. d.name)
+                    end
+                elseif count then
+                    if i >= count then
+                        break
+                    end
+                elseif pos >= limit then
+                    break
+                end
+                pos = dissect_group(tvb, pos, limit, structs[d.struct], item:add(span(tvb, pos, 0), string.format("[%d]", i)))
+                i = i + 1
+            end
+            item:set_len(math.ceil(pos / 8) - math.floor(start / 8))
+            item:append_text(string.format(" (%d items)", i))
+        end
+    end
+    return pos
+end
+
+-- The payload whose metadata values match the frame's
+local function identify(direction, metadata)
+    local candidates = payloads[direction]
+    if #metadata == 0 then
+        return #candidates == 1 and candidates[1] or nil
+    end
+    for _, payload in ipairs(candidates) do
+        local matches = true
+        for i, accepted in ipairs(payload.metadata) do
+            local found = false
+            for _, value in ipairs(accepted) do
+                found = found or value == metadata[i]
+            end
+            matches = matches and found
+        end
+        if matches then
+            return payload
+        end
+    end
+    return nil
+end
+
+-- Reads a frame's envelope from byte `start`, without adding anything to the tree yet
+local function parse(tvb, start, direction)
+    local format = formats[direction]
+    local frame = { direction = direction, start = start, items = {}, metadata = {}, problems = 0 }
+    local pos, limit = start * 8, tvb:len() * 8
+    local total, payload_bits
+    for _, e in ipairs(format) do
+        local item = { element = e, pos = pos, bits = e.bits }
+        if e.kind == "payload" then
+            frame.payload = frame.payload or identify(direction, frame.metadata)
+            if not payload_bits and total then
+                payload_bits = total - format.envelope_bits
+            elseif not payload_bits and frame.payload and frame.payload.bits then
+                payload_bits = frame.payload.bits
+            elseif not payload_bits then
+                payload_bits = limit - pos - e.after
+            end
+            if payload_bits < 0 or pos + payload_bits + e.after > limit then
+                frame.short = true
+                return frame
+            end
+            item.bits = payload_bits
+        elseif pos + e.bits > limit then
+            frame.short = true
+            return frame
+        elseif e.kind == "const" then
+            local value, _, ok = read_const(tvb, pos, e)
+            item.value = value
+            if not ok then
+                item.problem = { experts.bad_const }
+            end
+        elseif e.kind == "crc" then
+            item.value = read_uint(tvb, pos, e.bits, false)
+            if pos % 8 ~= 0 then
+                item.problem = { experts.bad_crc, "CRC doesn't start on a byte boundary" }
+            else
+                local expected = crc(e.algorithm, tvb(start, pos / 8 - start):bytes())
+                if expected ~= item.value then
+                    item.problem = { experts.bad_crc, string.format("Bad CRC, expected 0x%0" .. math.floor(e.bits / 4) .. "X", expected) }
+                end
+            end
+        else
+            item.value = read_uint(tvb, pos, e.bits, e.little)
+            local value = tonum(item.value)
+            if e.kind == "metadata" then
+                frame.metadata[#frame.metadata + 1] = value
+            elseif e.kind == "size_total" then
+                total = value * e.unit
+                frame.size_item = item
+            elseif e.kind == "size_of_payload" then
+                payload_bits = value * e.unit
+            elseif e.kind == "size_of_elements" and e.has_payload then
+                payload_bits = value * e.unit - e.static_bits
+            end
+        end
+        frame.items[#frame.items + 1] = item
+        pos = pos + item.bits
+    end
+    frame.payload = frame.payload or identify(direction, frame.metadata)
+    if total and math.ceil(total / 8) ~= math.ceil(pos / 8) - start then
+        frame.size_item.problem = { experts.bad_size }
+    end
+    frame.len = math.ceil(pos / 8) - start
+    for _, item in ipairs(frame.items) do
+        if item.problem then
+            frame.problems = frame.problems + 1
+        end
+    end
+    if not frame.payload then
+        frame.problems = frame.problems + 1
+    end
+    return frame
+end
+
+local function render(tvb, frame, root)
+    local tree = root:add(proto, tvb(frame.start, frame.len))
+    local summary = string.upper(frame.direction) .. " " .. (frame.payload and frame.payload.name or "unknown")
+    tree:append_text(", " .. summary)
+    tree:add(f.direction, tvb(frame.start, 0), frame.direction == "tx" and "To the device (TX)" or "From the device (RX)"):set_generated()
+    for _, item in ipairs(frame.items) do
+        local e = item.element
+        local node
+        if e.kind == "payload" then
+            local range = span(tvb, item.pos, item.bits)
+            if frame.payload then
+                node = tree:add(f.payload, range, frame.payload.name)
+                local ok, result = pcall(dissect_fields, tvb, item.pos, item.pos + item.bits, frame.payload.fields, node)
+                if not ok then
+                    node:add_proto_expert_info(experts.malformed, tostring(result))
+                elseif result ~= item.pos + item.bits then
+                    node:add_proto_expert_info(experts.malformed, string.format("Fields take %d bits of a %d bit payload", result - item.pos, item.bits))
+                end
+            else
+                node = tree:add(f.data, range)
+                node:add_proto_expert_info(experts.unknown_payload)
+            end
+        else
+            node = tree:add(e.field, span(tvb, item.pos, item.bits), item.value)
+            if item.problem then
+                node:add_proto_expert_info(item.problem[1], item.problem[2])
+            elseif e.kind == "crc" then
+                node:append_text(" [correct]")
+            end
+        end
+    end
+    return summary
+end
+
+local function directions(pinfo)
+    local choice = proto.prefs.direction
+    if choice == 1 or (choice == 0 and pinfo.p2p_dir == 0) then
+        return { "tx" }
+    elseif choice == 2 or (choice == 0 and pinfo.p2p_dir == 1) then
+        return { "rx" }
+    end
+    return { "tx", "rx" }
+end
+
+-- The direction that explains the frame best: complete, with a known payload and no bad fields
+local function best_frame(tvb, start, pinfo)
+    local best, best_score
+    for _, direction in ipairs(directions(pinfo)) do
+        local frame = parse(tvb, start, direction)
+        local score = frame.short and math.huge or frame.problems
+        if not best or score < best_score then
+            best, best_score = frame, score
+        end
+    end
+    return best
+end
+
+function proto.dissector(tvb, pinfo, root)
+    pinfo.cols.protocol = proto.name
+    local summaries = {}
+    local start = 0
+    while start < tvb:len() do
+        local frame = best_frame(tvb, start, pinfo)
+        if frame.short then
+            root:add(proto, tvb(start)):add_proto_expert_info(experts.truncated)
+            pinfo.desegment_offset = start
+            pinfo.desegment_len = DESEGMENT_ONE_MORE_SEGMENT
+            break
+        end
+        summaries[#summaries + 1] = render(tvb, frame, root)
+        start = start + math.max(frame.len, 1)
+    end
+    pinfo.cols.info = table.concat(summaries, ", ")
+    return tvb:len()
+end
+
+DissectorTable.get("wtap_encap"):add((wtap_encaps or wtap).USER0, proto)
+DissectorTable.get("tcp.port"):add_for_decode_as(proto)
+"#;
@@ -0,0 +1,1329 @@
+//! Generates a Zig package: a struct per struct and payload with `encode`/`decode`, sized at
+//! comptime so nothing allocates, and a `Device` with a method per transaction over any
+//! `std.io.AnyReader` and `std.io.AnyWriter`.
+//!
+//! Fixed size structs of big-endian integers and floats become `packed struct`s, encoded with a
+//! single `@bitCast`. Variable length fields are `std.BoundedArray`s with capacities from
+//! [Layout], and frame formats are data interpreted by the package's `wire.zig`, which follows
+//! `openpid_runtime::frame`.
+
+use super::layout::{flat_formats, Layout};
+use super::{BackendInfo, BackendOptions, CodeWriter, Codegen, CodegenError, OptionInfo, OutputSink};
+use crate::ir::*;
+use crate::value::{Fields, Value};
+
+pub const INFO: BackendInfo = BackendInfo {
+    name: "zig",
+    description: "Zig package with comptime sizes, over std.io readers and writers",
+    options: &[
+        OptionInfo { name: "module", description: "Name of the Zig module", default: Some("the device's name in snake_case") },
+        OptionInfo { name: "max-len", description: "Capacity of variable length fields the document doesn't bound", default: Some("64") },
+        OptionInfo { name: "tests", description: "Generate `zig build test` tests", default: Some("true") },
+    ],
+    create: |options| Ok(Box::new(ZigBackend::new(options)?)),
+};
+
+pub struct ZigBackend {
+    module: Option<String>,
+    max_len: u32,
+    tests: bool,
+}
+
+impl ZigBackend {
+    pub fn new(options: &BackendOptions) -> Result<Self, CodegenError> {
+        let max_len = options.get_or("max-len", "64");
+        Ok(Self {
+            module: options.get("module").map(str::to_owned),
+            max_len: max_len.parse().map_err(|_| CodegenError::BadOption { option: "max-len".to_owned(), value: max_len.to_owned(), expected: "a number" })?,
+            tests: options.get_bool("tests", true)?,
+        })
+    }
+}
+
+impl Codegen for ZigBackend {
+    fn generate(&self, ir: &Ir, out: &mut dyn OutputSink) -> Result<(), CodegenError> {
+        let module = self.module.clone().unwrap_or_else(|| ir.device.name.snake());
+        let gen = Gen { ir, layout: Layout::new(ir, self.max_len) };
+        let formats = flat_formats(ir, "zig")?;
+
+        out.write_text("build.zig", &build_zig(&module, self.tests))?;
+        out.write_text("build.zig.zon", &build_zig_zon(ir, &module))?;
+        out.write_text("src/wire.zig", WIRE.trim_start())?;
+        out.write_text(&format!("src/{module}.zig"), &gen.module(formats.as_ref())?)?;
+        if self.tests {
+            out.write_text(&format!("src/test_{module}.zig"), &gen.tests(&module))?;
+        }
+        Ok(())
+    }
+}
+
+fn build_zig(module: &str, tests: bool) -> String {
+    let mut w = CodeWriter::new("    ");
+    w.line("const std = @import(\"std\");");
+    w.blank();
+    w.block("pub fn build(b: *std.Build) void {", "}", |w| {
+        w.line("const target = b.standardTargetOptions(.{});");
+        w.line("const optimize = b.standardOptimizeOption(.{});");
+        w.line(format!("_ = b.addModule(\"{module}\", .{{ .root_source_file = b.path(\"src/{module}.zig\"), .target = target, .optimize = optimize }});"));
+        if tests {
+            w.blank();
+            w.line(format!("const tests = b.addTest(.{{ .root_source_file = b.path(\"src/test_{module}.zig\"), .target = target, .optimize = optimize }});"));
+            w.line("b.step(\"test\", \"Run the generated tests\").dependOn(&b.addRunArtifact(tests).step);");
+        }
+    });
+    w.finish()
+}
+
+fn build_zig_zon(ir: &Ir, module: &str) -> String {
+    // doc_version is free-form, so only use it when it looks like a release number
+    let version = ir
+        .doc_version
+        .as_deref()
+        .filter(|v| v.split('.').count() == 3 && v.split('.').all(|p| p.parse::<u64>().is_ok()))
+        .unwrap_or("0.1.0");
+    format!(
+        ".{{\n    .name = \"{module}\",\n    .version = \"{version}\",\n    .minimum_zig_version = \"0.13.0\",\n    .paths = .{{ \"build.zig\", \"build.zig.zon\", \"src\" }},\n}}\n"
+    )
+}
+
+const KEYWORDS: &[&str] = &[
+    "addrspace", "align", "allowzero", "and", "anyframe", "anytype", "asm", "async", "await", "break", "callconv", "catch", "comptime", "const",
+    "continue", "defer", "else", "enum", "errdefer", "error", "export", "extern", "fn", "for", "if", "inline", "linksection", "noalias", "noinline",
+    "nosuspend", "opaque", "or", "orelse", "packed", "pub", "resume", "return", "struct", "suspend", "switch", "test", "threadlocal", "try", "union",
+    "unreachable", "usingnamespace", "var", "volatile", "while", "anyerror", "anyopaque", "bool", "comptime_float", "comptime_int", "false", "isize",
+    "noreturn", "null", "true", "type", "undefined", "usize", "void", "f16", "f32", "f64", "f80", "f128", "c_char", "c_short", "c_ushort", "c_int",
+    "c_uint", "c_long", "c_ulong", "c_longlong", "c_ulonglong", "c_longdouble",
+];
+
+/// Top level declarations of the generated module and `Device`'s, which parameters can't shadow
+const DECLS: &[&str] = &[
+    "std", "wire", "structs", "tx", "rx", "tx_format", "rx_format", "max_len", "max_frame_len", "pack", "unpack", "formatOf", "sleepMs", "self",
+    "init", "send", "receive", "discardInput", "reader", "writer", "sleep", "buf",
+];
+
+fn ident(name: String) -> String {
+    let primitive = name.len() > 1 && name.starts_with(['i', 'u']) && name[1..].chars().all(|c| c.is_ascii_digit());
+    if KEYWORDS.contains(&name.as_str()) || primitive || name.starts_with(|c: char| c.is_ascii_digit()) || name.is_empty() {
+        format!("@\"{name}\"")
+    } else {
+        name
+    }
+}
+
+/// A struct field, kept clear of the `info`, `encode` and `decode` declarations
+fn field_name(name: &Name) -> String {
+    match name.snake().as_str() {
+        name @ ("info" | "encode" | "decode") => format!("{name}_field"),
+        name => ident(name.to_owned()),
+    }
+}
+
+fn type_name(name: &Name) -> String {
+    let pascal = name.pascal();
+    if pascal.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("T{pascal}")
+    } else {
+        pascal
+    }
+}
+
+fn param(name: &Name) -> String {
+    let snake = name.snake();
+    if DECLS.contains(&snake.as_str()) {
+        format!("{snake}_payload")
+    } else {
+        ident(snake)
+    }
+}
+
+fn int_format(signing: Signing, endianness: Endianness) -> &'static str {
+    match (endianness, signing) {
+        (Endianness::BigEndian, Signing::OnesComplement) => ".{ .ones_complement = true }",
+        (Endianness::BigEndian, _) => ".{}",
+        (Endianness::LittleEndian, Signing::OnesComplement) => ".{ .endian = .little, .ones_complement = true }",
+        (Endianness::LittleEndian, _) => ".{ .endian = .little }",
+    }
+}
+
+fn endian(endianness: Endianness) -> &'static str {
+    match endianness {
+        Endianness::BigEndian => ".big",
+        Endianness::LittleEndian => ".little",
+    }
+}
+
+fn bytes_literal(data: &[u8]) -> String {
+    format!("&.{{ {} }}", data.iter().map(|b| format!("0x{b:02x}")).collect::<Vec<_>>().join(", "))
+}
+
+fn doc(w: &mut CodeWriter, text: &str) {
+    w.comment("///", text);
+}
+
+fn element(element: &FlatElement) -> String {
+    let unit = |unit: &BitsOrBytes| if *unit == BitsOrBytes::Bytes { ", .unit = .bytes" } else { "" };
+    match element {
+        FlatElement::SizeTotal { bits, unit: u } => format!(".{{ .size_total = .{{ .bits = {bits}{} }} }}", unit(u)),
+        FlatElement::SizeOfPayload { bits, unit: u } => format!(".{{ .size_of_payload = .{{ .bits = {bits}{} }} }}", unit(u)),
+        FlatElement::SizeOfElements { bits, unit: u, covers, .. } => {
+            format!(".{{ .size_of_elements = .{{ .bits = {bits}{}, .covers = {covers} }} }}", unit(u))
+        }
+        FlatElement::Payload => ".payload".to_owned(),
+        FlatElement::Metadata { bits, endianness, .. } => match endianness {
+            Endianness::BigEndian => format!(".{{ .metadata = .{{ .bits = {bits} }} }}"),
+            Endianness::LittleEndian => format!(".{{ .metadata = .{{ .bits = {bits}, .endian = .little }} }}"),
+        },
+        FlatElement::Crc(Crc::Crc32) => ".crc32".to_owned(),
+        FlatElement::Crc(Crc::Crc16XModem) => ".crc16_xmodem".to_owned(),
+        FlatElement::Const { data, bits } => format!(".{{ .constant = .{{ .data = {}, .bits = {bits} }} }}", bytes_literal(data)),
+    }
+}
+
+struct Gen<'a> {
+    ir: &'a Ir,
+    layout: Layout<'a>,
+}
+
+impl Gen<'_> {
+    fn framed(&self) -> bool {
+        self.ir.framing.is_some()
+    }
+
+    fn zig_type(&self, ty: &Type, fields: &[Field]) -> String {
+        match ty {
+            Type::Int { bits, signing, .. } => format!("{}{bits}", if *signing == Signing::Unsigned { "u" } else { "i" }),
+            Type::Float { bits, .. } => format!("f{bits}"),
+            Type::Bytes(Length::Fixed(n)) | Type::String(Length::Fixed(n)) => format!("[{n}]u8"),
+            Type::Bytes(len) | Type::String(len) => format!("std.BoundedArray(u8, {})", self.layout.cap(len, fields)),
+            Type::Const(data) => format!("[{}]u8", data.len()),
+            Type::Struct(name) => format!("structs.{}", type_name(name)),
+            Type::Array { item, len: Length::Fixed(n) } => format!("[{n}]structs.{}", type_name(item)),
+            Type::Array { item, len } => format!("std.BoundedArray(structs.{}, {})", type_name(item), self.layout.cap(len, fields)),
+        }
+    }
+
+    fn default(ty: &Type) -> String {
+        match ty {
+            Type::Int { .. } | Type::Float { .. } => "0".to_owned(),
+            Type::Bytes(Length::Fixed(n)) | Type::String(Length::Fixed(n)) => format!("[_]u8{{0}} ** {n}"),
+            Type::Array { item, len: Length::Fixed(n) } => format!("[_]structs.{}{{.{{}}}} ** {n}", type_name(item)),
+            _ => ".{}".to_owned(),
+        }
+    }
+
+    /// Total width when a struct can be a `packed struct`: fixed size, and only big-endian
+    /// integers and floats in two's complement, or structs that are packed themselves, so that the
+    /// backing integer's bits are the wire's
+    fn packed_bits(&self, fields: &[Field]) -> Option<u32> {
+        let mut total = 0;
+        for f in fields {
+            total += match &f.ty {
+                Type::Int { bits, signing: Signing::Unsigned | Signing::TwosComplement, endianness } if f.count_of.is_none() => {
+                    (*endianness == Endianness::BigEndian || *bits <= 8).then_some(*bits)?
+                }
+                Type::Float { bits, endianness: Endianness::BigEndian } => *bits,
+                Type::Struct(name) => self.packed_bits(&self.ir.get_struct(name)?.fields)?,
+                _ => return None,
+            };
+        }
+        (!fields.is_empty() && total <= 64).then_some(total)
+    }
+
+    fn module(&self, formats: Option<&[Vec<FlatElement>; 2]>) -> Result<String, CodegenError> {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("    ");
+        w.comment("//!", &format!("Driver for the {}: {}", ir.device.name, ir.device.description.trim()));
+        w.line("//!");
+        w.line("//! Generated by `openpid gen zig` from an OpenPID document. Do not edit.");
+        w.blank();
+        w.line("const std = @import(\"std\");");
+        w.line("pub const wire = @import(\"wire.zig\");");
+        w.blank();
+        doc(&mut w, "Capacity of variable length fields the document doesn't bound");
+        w.line(format!("pub const max_len = {};", self.layout.max_len));
+        doc(&mut w, &format!("Largest {} in either direction, in bytes", if self.framed() { "frame" } else { "payload" }));
+        w.line(format!("pub const max_frame_len = {};", self.layout.max_frame_len(Direction::Tx).max(self.layout.max_frame_len(Direction::Rx))));
+        w.blank();
+        doc(&mut w, "Structs shared between payloads");
+        w.block("pub const structs = struct {", "};", |w| {
+            for (i, s) in ir.structs.iter().enumerate() {
+                if i > 0 {
+                    w.blank();
+                }
+                if let Some(description) = &s.description {
+                    doc(w, description);
+                }
+                self.codec(w, &type_name(&s.name), &s.fields, None);
+            }
+        });
+        for direction in [Direction::Tx, Direction::Rx] {
+            w.blank();
+            let (namespace, role) = match direction {
+                Direction::Tx => ("tx", "Payloads sent to the device"),
+                Direction::Rx => ("rx", "Payloads received from the device"),
+            };
+            doc(&mut w, role);
+            let mut result = Ok(());
+            w.block(format!("pub const {namespace} = struct {{"), "};", |w| {
+                for (i, p) in ir.payloads(direction).iter().enumerate() {
+                    if i > 0 {
+                        w.blank();
+                    }
+                    if !p.description.trim().is_empty() {
+                        doc(w, p.description.trim());
+                    }
+                    match self.info(p) {
+                        Ok(info) => self.codec(w, &type_name(&p.name), &p.fields, Some(info)),
+                        Err(e) => result = Err(e),
+                    }
+                }
+            });
+            result?;
+        }
+        if let Some(formats) = formats {
+            self.frame(&mut w, formats);
+        }
+        for transaction in ir.transactions.iter().filter(|t| t.returns.len() > 1) {
+            w.blank();
+            let ty = format!("{}Response", type_name(&transaction.name));
+            doc(&mut w, &format!("What `Device.{}` returns", Self::method_name(transaction)));
+            w.block(format!("pub const {ty} = struct {{"), "};", |w| {
+                for ret in &transaction.returns {
+                    w.line(format!("{}: {},", field_name(&ret.name), self.zig_type(&ret.ty, self.layout.return_siblings(ret))));
+                }
+            });
+        }
+        w.blank();
+        self.device(&mut w);
+        Ok(w.finish())
+    }
+
+    /// The `info` declaration of a payload
+    fn info(&self, p: &Payload) -> Result<String, CodegenError> {
+        let metadata = if self.framed() {
+            p.metadata
+                .iter()
+                .filter(|m| m.ty.is_some())
+                .map(|m| {
+                    m.packed().ok_or_else(|| CodegenError::Unsupported {
+                        backend: "zig",
+                        what: format!("metadata \"{}\" of payload \"{}\": only integers and strings up to 8 bytes", m.name, p.name),
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            Vec::new()
+        };
+        let metadata = metadata
+            .iter()
+            .map(|values| format!("&.{{ {} }}", values.iter().map(|v| format!("{v:#x}")).collect::<Vec<_>>().join(", ")))
+            .collect::<Vec<_>>();
+        let metadata = if metadata.is_empty() { String::new() } else { format!(", .metadata = &.{{ {} }}", metadata.join(", ")) };
+        let fixed_len = p.size.fixed_bits().map_or("null".to_owned(), |bits| bits.div_ceil(8).to_string());
+        let direction = if p.direction == Direction::Tx { "tx" } else { "rx" };
+        Ok(format!(
+            "pub const info = wire.Info{{ .name = \"{}\", .direction = .{direction}{metadata}, .fixed_len = {fixed_len}, .max_len = {} }};",
+            type_name(&p.name),
+            self.layout.fields_max_bits(&p.fields).div_ceil(8)
+        ))
+    }
+
+    /// A struct type with its fields, an optional `info` declaration and `encode`/`decode`
+    fn codec(&self, w: &mut CodeWriter, ty: &str, fields: &[Field], info: Option<String>) {
+        let user_facing = fields.iter().filter(|f| f.is_user_facing()).collect::<Vec<_>>();
+        if let Some(bits) = self.packed_bits(fields) {
+            w.block(format!("pub const {ty} = packed struct(u{bits}) {{"), "};", |w| {
+                if fields.len() > 1 {
+                    w.line("// declared last field first, so the first field sits in the most significant bits");
+                }
+                for f in fields.iter().rev() {
+                    if let Some(description) = &f.description {
+                        doc(w, description);
+                    }
+                    w.line(format!("{}: {} = {},", field_name(&f.name), self.zig_type(&f.ty, fields), Self::default(&f.ty)));
+                }
+                w.blank();
+                if let Some(info) = &info {
+                    w.line(info);
+                    w.blank();
+                }
+                w.block(format!("pub fn encode(self: {ty}, w: *wire.Writer) wire.Error!void {{"), "}", |w| {
+                    w.line(format!("try w.putInt(u{bits}, @bitCast(self), .{{}});"));
+                });
+                w.blank();
+                w.block(format!("pub fn decode(r: *wire.Reader) wire.Error!{ty} {{"), "}", |w| {
+                    w.line(format!("return @bitCast(try r.getInt(u{bits}, .{{}}));"));
+                });
+            });
+            return;
+        }
+        w.block(format!("pub const {ty} = struct {{"), "};", |w| {
+            for f in &user_facing {
+                if let Some(description) = &f.description {
+                    doc(w, description);
+                }
+                if let Type::String(Length::Fixed(n)) = f.ty {
+                    doc(w, &format!("NUL padded to {n} bytes"));
+                }
+                w.line(format!("{}: {} = {},", field_name(&f.name), self.zig_type(&f.ty, fields), Self::default(&f.ty)));
+            }
+            if !user_facing.is_empty() {
+                w.blank();
+            }
+            if let Some(info) = &info {
+                w.line(info);
+                w.blank();
+            }
+            w.block(format!("pub fn encode(self: {ty}, w: *wire.Writer) wire.Error!void {{"), "}", |w| {
+                if user_facing.is_empty() {
+                    w.line("_ = self;");
+                }
+                if fields.is_empty() {
+                    w.line("_ = w;");
+                }
+                for f in fields {
+                    self.encode_field(w, f);
+                }
+            });
+            w.blank();
+            w.block(format!("pub fn decode(r: *wire.Reader) wire.Error!{ty} {{"), "}", |w| {
+                if fields.is_empty() {
+                    w.line("_ = r;");
+                }
+                if !user_facing.is_empty() {
+                    w.line(format!("var self = {ty}{{}};"));
+                }
+                for f in fields {
+                    self.decode_field(w, f, fields);
+                }
+                w.line(if user_facing.is_empty() { "return .{};" } else { "return self;" });
+            });
+        });
+    }
+
+    fn encode_field(&self, w: &mut CodeWriter, f: &Field) {
+        let value = format!("self.{}", field_name(&f.name));
+        match &f.ty {
+            Type::Int { bits, signing, endianness } => {
+                let ty = format!("{}{bits}", if *signing == Signing::Unsigned { "u" } else { "i" });
+                match &f.count_of {
+                    Some(counted) => w.line(format!("try w.putCount({ty}, self.{}.len, {});", field_name(counted), int_format(*signing, *endianness))),
+                    None => w.line(format!("try w.putInt({ty}, {value}, {});", int_format(*signing, *endianness))),
+                };
+            }
+            Type::Float { endianness, .. } => {
+                w.line(format!("try w.putFloat({value}, {});", endian(*endianness)));
+            }
+            Type::Const(data) => {
+                w.line(format!("try w.putBytes({});", bytes_literal(data)));
+            }
+            Type::Struct(_) => {
+                w.line(format!("try {value}.encode(w);"));
+            }
+            Type::Bytes(len) | Type::String(len) => {
+                match len {
+                    Length::Fixed(_) => w.line(format!("try w.putBytes(&{value});")),
+                    Length::Capacity(n) => w.line(format!("try w.putPadded({value}.constSlice(), {n});")),
+                    _ => w.line(format!("try w.putBytes({value}.constSlice());")),
+                };
+                if let Length::Sequence(sequence) = len {
+                    w.line(format!("try w.putBytes({});", bytes_literal(sequence)));
+                }
+            }
+            Type::Array { len, .. } => {
+                let items = if matches!(len, Length::Fixed(_)) { value } else { format!("{value}.constSlice()") };
+                w.line(format!("for ({items}) |item| try item.encode(w);"));
+                if let Length::Sequence(sequence) = len {
+                    w.line(format!("try w.putBytes({});", bytes_literal(sequence)));
+                }
+            }
+        }
+    }
+
+    fn decode_field(&self, w: &mut CodeWriter, f: &Field, fields: &[Field]) {
+        let target = format!("self.{}", field_name(&f.name));
+        let count = |len: &Length| match len {
+            Length::Fixed(n) | Length::Capacity(n) => n.to_string(),
+            Length::CountField(c) => format!("n_{}", c.snake()),
+            Length::Sequence(_) | Length::Remainder => "r.remaining() / 8".to_owned(),
+        };
+        match &f.ty {
+            Type::Int { bits, signing, endianness } => {
+                let ty = format!("{}{bits}", if *signing == Signing::Unsigned { "u" } else { "i" });
+                if f.count_of.is_some() {
+                    w.line(format!("const n_{} = try r.getCount({ty}, {});", f.name.snake(), int_format(*signing, *endianness)));
+                } else {
+                    w.line(format!("{target} = try r.getInt({ty}, {});", int_format(*signing, *endianness)));
+                }
+            }
+            Type::Float { endianness, .. } => {
+                w.line(format!("{target} = try r.getFloat({}, {});", self.zig_type(&f.ty, fields), endian(*endianness)));
+            }
+            Type::Const(data) => {
+                w.line(format!("try r.expect({});", bytes_literal(data)));
+            }
+            Type::Struct(name) => {
+                w.line(format!("{target} = try structs.{}.decode(r);", type_name(name)));
+            }
+            Type::Bytes(len) | Type::String(len) => {
+                let string = matches!(f.ty, Type::String(_));
+                match len {
+                    Length::Fixed(_) => {
+                        w.line(format!("try r.getBytes(&{target});"));
+                        if string {
+                            w.line(format!("try wire.checkUtf8(&{target});"));
+                        }
+                    }
+                    Length::Capacity(n) if string => {
+                        w.line(format!("try r.getPadded(&{target}, {n});"));
+                    }
+                    Length::Sequence(sequence) => {
+                        w.line(format!("try r.getUntil(&{target}, {});", bytes_literal(sequence)));
+                    }
+                    _ => {
+                        w.line(format!("try {target}.resize({});", count(len)));
+                        w.line(format!("try r.getBytes({target}.slice());"));
+                    }
+                }
+                if string && !matches!(len, Length::Fixed(_) | Length::Capacity(_)) {
+                    w.line(format!("try wire.checkUtf8({target}.constSlice());"));
+                }
+            }
+            Type::Array { item, len } => {
+                let item = format!("structs.{}", type_name(item));
+                match len {
+                    Length::Fixed(_) => {
+                        w.line(format!("for (&{target}) |*item| item.* = try {item}.decode(r);"));
+                    }
+                    Length::CountField(_) | Length::Capacity(_) => {
+                        w.line(format!("try {target}.resize({});", count(len)));
+                        w.line(format!("for ({target}.slice()) |*item| item.* = try {item}.decode(r);"));
+                    }
+                    Length::Sequence(sequence) => {
+                        w.line(format!("while (!(try r.take({}))) try {target}.append(try {item}.decode(r));", bytes_literal(sequence)));
+                    }
+                    Length::Remainder => {
+                        w.line(format!("while (r.remaining() >= 8) try {target}.append(try {item}.decode(r));"));
+                    }
+                }
+            }
+        }
+    }
+
+    fn frame(&self, w: &mut CodeWriter, formats: &[Vec<FlatElement>; 2]) {
+        let ir = self.ir;
+        for (direction, format) in [Direction::Tx, Direction::Rx].into_iter().zip(formats) {
+            let lower = if direction == Direction::Tx { "tx" } else { "rx" };
+            w.blank();
+            doc(w, &format!("Frame format of payloads {}", if direction == Direction::Tx { "sent to the device" } else { "received from the device" }));
+            w.block(format!("pub const {lower}_format = [_]wire.Element{{"), "};", |w| {
+                for e in format {
+                    w.line(format!("{},", element(e)));
+                }
+            });
+            for (i, (key, _)) in ir.framing.iter().flat_map(|f| f.metadata(direction)).enumerate() {
+                let values = ir
+                    .payloads(direction)
+                    .iter()
+                    .filter_map(|p| Some((p, p.metadata(key.raw())?.packed()?.first().copied()?)))
+                    .collect::<Vec<_>>();
+                if values.is_empty() {
+                    continue;
+                }
+                w.blank();
+                doc(w, &format!("Values of the \"{key}\" metadata, element {i} of {direction} frames"));
+                w.block(format!("pub const {lower}_{} = struct {{", key.snake()), "};", |w| {
+                    for (p, value) in values {
+                        w.line(format!("pub const {} = {value:#x};", ident(p.name.snake())));
+                    }
+                });
+            }
+        }
+        w.blank();
+        w.line(FRAME_FNS.trim());
+    }
+
+    /// Name of a transaction's method, kept clear of the device's own
+    fn method_name(transaction: &Transaction) -> String {
+        let camel = transaction.name.camel();
+        if DECLS.contains(&camel.as_str()) {
+            format!("{camel}Transaction")
+        } else {
+            ident(camel)
+        }
+    }
+
+    fn device(&self, w: &mut CodeWriter) {
+        doc(w, "Sends payloads and runs transactions over a reader and writer, such as a serial port's");
+        w.block("pub const Device = struct {", "};", |w| {
+            w.line(DEVICE_FIELDS.trim());
+            w.blank();
+            w.block("pub fn send(self: *Device, value: anytype) !void {", "}", |w| {
+                let encode = if self.framed() { "pack(value, &self.buf)" } else { "wire.encode(value, &self.buf)" };
+                w.line(format!("try self.writer.writeAll(try {encode});"));
+            });
+            w.blank();
+            doc(w, "Receives a payload of type `P`. Reading nothing is `error.Timeout`");
+            w.block("pub fn receive(self: *Device, comptime P: type) !P {", "}", |w| {
+                w.line(if self.framed() { RECEIVE_FRAMED.trim() } else { RECEIVE_UNFRAMED.trim() });
+            });
+            for transaction in &self.ir.transactions {
+                w.blank();
+                self.transaction(w, transaction);
+            }
+        });
+        w.blank();
+        w.line("fn sleepMs(ms: u32) void {");
+        w.line("    std.time.sleep(@as(u64, ms) * std.time.ns_per_ms);");
+        w.line("}");
+    }
+
+    fn transaction(&self, w: &mut CodeWriter, transaction: &Transaction) {
+        let params = self.ir.transaction_params(transaction);
+        let mut signature = vec!["self: *Device".to_owned()];
+        signature.extend(params.iter().map(|p| format!("{}: tx.{}", param(p), type_name(p))));
+        let returns = match transaction.returns.as_slice() {
+            [] => "void".to_owned(),
+            [ret] => self.zig_type(&ret.ty, self.layout.return_siblings(ret)),
+            _ => format!("{}Response", type_name(&transaction.name)),
+        };
+        if !transaction.description.trim().is_empty() {
+            doc(w, transaction.description.trim());
+        }
+        w.block(format!("pub fn {}({}) !{returns} {{", Self::method_name(transaction), signature.join(", ")), "}", |w| {
+            let returned = |payload: &Name| transaction.returns.iter().any(|r| r.payload == *payload);
+            let times = |payload: &Name| transaction.actions.iter().filter(|a| matches!(a, Action::Rx(p) if p == payload)).count();
+            let mut declared: Vec<&Name> = Vec::new();
+            for action in &transaction.actions {
+                match action {
+                    Action::Tx(payload) if params.contains(&payload) => w.line(format!("try self.send({});", param(payload))),
+                    Action::Tx(payload) => w.line(format!("try self.send(tx.{}{{}});", type_name(payload))),
+                    Action::Rx(payload) if returned(payload) => {
+                        let local = format!("{}_rx", payload.snake());
+                        if declared.contains(&payload) {
+                            w.line(format!("{local} = try self.receive(rx.{});", type_name(payload)))
+                        } else {
+                            declared.push(payload);
+                            let binding = if times(payload) > 1 { "var" } else { "const" };
+                            w.line(format!("{binding} {local} = try self.receive(rx.{});", type_name(payload)))
+                        }
+                    }
+                    Action::Rx(payload) => w.line(format!("_ = try self.receive(rx.{});", type_name(payload))),
+                    Action::Sleep { milliseconds } => w.line(format!("self.sleep({milliseconds});")),
+                    Action::Flush => w.line("try self.discardInput();"),
+                };
+            }
+            let value = |ret: &Return| format!("{}_rx.{}", ret.payload.snake(), ret.path.iter().map(field_name).collect::<Vec<_>>().join("."));
+            match transaction.returns.as_slice() {
+                [] => {}
+                [ret] => {
+                    w.line(format!("return {};", value(ret)));
+                }
+                returns => {
+                    let members = returns.iter().map(|ret| format!(".{} = {}", field_name(&ret.name), value(ret))).collect::<Vec<_>>();
+                    w.line(format!("return .{{ {} }};", members.join(", ")));
+                }
+            }
+        });
+    }
+
+    /// A Zig expression, for the test module, of a sample value of a field's type. `siblings` are
+    /// the fields around it, which size its `BoundedArray`
+    fn literal(&self, value: &Value, ty: &Type, siblings: &[Field]) -> String {
+        let bounded = |items: String| format!("try {}.fromSlice({items})", self.zig_type(ty, siblings).replace("structs.", "dev.structs."));
+        match (value, ty) {
+            (Value::Struct(values), Type::Struct(_)) => self.sample("", values, self.ir.fields_of(ty).unwrap_or(&[])),
+            (Value::Array(items), Type::Array { item, len }) => {
+                let fields = self.ir.get_struct(item).map_or(&[][..], |s| &s.fields);
+                let items = items.iter().map(|i| self.literal(i, &Type::Struct(item.clone()), fields)).collect::<Vec<_>>().join(", ");
+                match len {
+                    Length::Fixed(_) => format!(".{{ {items} }}"),
+                    _ => bounded(format!("&[_]dev.structs.{}{{ {items} }}", type_name(item))),
+                }
+            }
+            (Value::Bytes(b), Type::Bytes(Length::Fixed(_))) => format!(".{{ {} }}", b.iter().map(|b| format!("0x{b:02x}")).collect::<Vec<_>>().join(", ")),
+            (Value::String(text), Type::String(Length::Fixed(_))) => format!(".{{ {} }}", text.chars().map(|c| format!("{c:?}")).collect::<Vec<_>>().join(", ")),
+            (Value::Bytes(b), _) => bounded(format!("&[_]u8{{ {} }}", b.iter().map(|b| format!("0x{b:02x}")).collect::<Vec<_>>().join(", "))),
+            (Value::String(text), _) => bounded(format!("{text:?}")),
+            (Value::Float(f), _) => format!("{f:?}"),
+            (value, _) => value.to_string(),
+        }
+    }
+
+    /// A struct literal, typed by `ty` or left for Zig to infer, from sample values for its user facing fields
+    fn sample(&self, ty: &str, values: &Fields, fields: &[Field]) -> String {
+        let members = fields
+            .iter()
+            .filter(|f| f.is_user_facing())
+            .filter_map(|f| Some(format!(".{} = {}", field_name(&f.name), self.literal(values.get(f.name.raw())?, &f.ty, fields))))
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            format!("{ty}.{{}}")
+        } else {
+            format!("{ty}.{{ {} }}", members.join(", "))
+        }
+    }
+
+    fn payload_sample(&self, direction: Direction, name: &Name) -> String {
+        let fields = self.ir.get_payload(direction, name).map_or(&[][..], |p| &p.fields);
+        let ty = format!("dev.{}.{}", if direction == Direction::Tx { "tx" } else { "rx" }, type_name(name));
+        self.sample(&ty, &self.layout.sample_fields(fields, 0), fields).replacen(".{", "{", 1)
+    }
+
+    fn tests(&self, module: &str) -> String {
+        let ir = self.ir;
+        let mut w = CodeWriter::new("    ");
+        w.line(format!("//! Tests for `{module}.zig`, run by `zig build test`"));
+        w.line("//!");
+        w.line("//! Generated by `openpid gen zig` from an OpenPID document. Do not edit.");
+        w.blank();
+        w.line("const std = @import(\"std\");");
+        w.line("const testing = std.testing;");
+        w.line(format!("const dev = @import(\"{module}.zig\");"));
+        w.blank();
+        w.line(TEST_SUPPORT.trim());
+        w.blank();
+        w.block("fn frames(allocator: std.mem.Allocator, payloads: anytype) ![]u8 {", "}", |w| {
+            w.line("var out = std.ArrayList(u8).init(allocator);");
+            w.block("inline for (payloads) |payload| {", "}", |w| {
+                w.line("var buf: [dev.max_frame_len]u8 = undefined;");
+                w.line(if self.framed() { "try out.appendSlice(try dev.pack(payload, &buf));" } else { "try out.appendSlice(try dev.wire.encode(payload, &buf));" });
+            });
+            w.line("return out.toOwnedSlice();");
+        });
+        w.blank();
+        w.block("fn roundTrip(value: anytype) !void {", "}", |w| {
+            w.line("const P = @TypeOf(value);");
+            w.line("var buf: [P.info.max_len]u8 = undefined;");
+            w.line("const data = try dev.wire.encode(value, &buf);");
+            w.line("var again: [P.info.max_len]u8 = undefined;");
+            w.line("try testing.expectEqualSlices(u8, data, try dev.wire.encode(try dev.wire.decode(P, data), &again));");
+            if self.framed() {
+                w.line("var frame: [dev.max_frame_len]u8 = undefined;");
+                w.line("try expectSame(value, try dev.unpack(P, try dev.pack(value, &frame)));");
+            }
+        });
+        w.blank();
+        w.block("test \"round trip\" {", "}", |w| {
+            for p in ir.all_payloads() {
+                w.line(format!("try roundTrip({});", self.payload_sample(p.direction, &p.name)));
+            }
+        });
+        for transaction in &ir.transactions {
+            w.blank();
+            self.transaction_test(&mut w, transaction);
+        }
+        w.finish()
+    }
+
+    fn transaction_test(&self, w: &mut CodeWriter, transaction: &Transaction) {
+        // responses arrive in batches: those read before the first write, then those after each write
+        let mut batches: Vec<Vec<String>> = vec![Vec::new()];
+        for action in &transaction.actions {
+            match action {
+                Action::Tx(_) => batches.push(Vec::new()),
+                Action::Rx(payload) => batches.last_mut().expect("batches starts non-empty").push(self.payload_sample(Direction::Rx, payload)),
+                _ => {}
+            }
+        }
+        while batches.last().is_some_and(Vec::is_empty) {
+            batches.pop();
+        }
+        let batches = batches
+            .iter()
+            .map(|b| if b.is_empty() { "\"\"".to_owned() } else { format!("try frames(allocator, .{{ {} }})", b.join(", ")) })
+            .collect::<Vec<_>>();
+        let args = self.ir.transaction_params(transaction).iter().map(|p| self.payload_sample(Direction::Tx, p)).collect::<Vec<_>>();
+        let sends = transaction.actions.iter().any(|a| matches!(a, Action::Tx(_)));
+        let name = Self::method_name(transaction);
+        w.block(format!("test \"transaction {name}\" {{"), "}", |w| {
+            w.line("var arena = std.heap.ArenaAllocator.init(testing.allocator);");
+            w.line("defer arena.deinit();");
+            w.line("const allocator = arena.allocator();");
+            let batches = if batches.is_empty() { "&.{}".to_owned() } else { format!("&.{{ {} }}", batches.join(", ")) };
+            w.line(format!("var port = try FakePort.init(allocator, {batches});"));
+            w.line("var device = dev.Device.init(port.reader(), port.writer());");
+            w.line("device.sleep = noSleep;");
+            let call = format!("device.{name}({})", args.join(", "));
+            let sample = |ret: &Return| self.layout.sample_return(ret).map(|value| self.literal(&value, &ret.ty, self.layout.return_siblings(ret)));
+            match transaction.returns.as_slice() {
+                [] => w.line(format!("try {call};")),
+                [ret] => {
+                    let ty = self.zig_type(&ret.ty, self.layout.return_siblings(ret)).replace("structs.", "dev.structs.");
+                    w.line(format!("const expected: {ty} = {};", sample(ret).unwrap_or_default()));
+                    w.line(format!("try expectSame(expected, try {call});"))
+                }
+                returns => {
+                    let members = returns.iter().filter_map(|ret| Some(format!(".{} = {}", field_name(&ret.name), sample(ret)?))).collect::<Vec<_>>();
+                    w.line(format!("const expected = dev.{}Response{{ {} }};", type_name(&transaction.name), members.join(", ")));
+                    w.line(format!("try expectSame(expected, try {call});"))
+                }
+            };
+            w.line(format!("try port.expectDone({sends});"));
+        });
+    }
+}
+
+const WIRE: &str = r#"
+//! Bit packing and frame encoding for generated OpenPID modules. Bits go most significant first,
+//! and frames follow `openpid_runtime::frame`. Nothing here allocates: writers fill caller buffers
+//! and parsed frames point into the data they came from.
+
+const std = @import("std");
+
+pub const Error = error{
+    /// A value doesn't fit its field, or a buffer is too small
+    Overflow,
+    /// The data ended early
+    Incomplete,
+    /// The data doesn't decode: a constant, CRC or UTF-8 check failed, or bytes were left over
+    Invalid,
+};
+
+/// How an integer field is laid out. Bits within a field always go most significant first
+pub const IntFormat = struct {
+    endian: std.builtin.Endian = .big,
+    /// Negative numbers flip every bit, so the minimum is -max
+    ones_complement: bool = false,
+};
+
+pub const Writer = struct {
+    buf: []u8,
+    bits: usize = 0,
+
+    pub fn init(buf: []u8) Writer {
+        @memset(buf, 0);
+        return .{ .buf = buf };
+    }
+
+    /// What's been written, with a partly written last byte zero padded
+    pub fn written(self: *const Writer) []u8 {
+        return self.buf[0 .. (self.bits + 7) / 8];
+    }
+
+    /// Writes the low `bits` bits of value
+    pub fn putBits(self: *Writer, value: u64, bits: u7) Error!void {
+        if (bits < 64 and value >> @intCast(bits) != 0) return error.Overflow;
+        if (self.bits + bits > self.buf.len * 8) return error.Overflow;
+        var left = bits;
+        while (left > 0) {
+            const room: u7 = @intCast(8 - self.bits % 8);
+            const n = @min(room, left);
+            left -= n;
+            const chunk: u8 = @truncate((value >> @intCast(left)) & ((@as(u64, 1) << @intCast(n)) - 1));
+            self.buf[self.bits / 8] |= chunk << @intCast(room - n);
+            self.bits += n;
+        }
+    }
+
+    pub fn putInt(self: *Writer, comptime T: type, value: T, comptime format: IntFormat) Error!void {
+        const info = @typeInfo(T).Int;
+        const U = std.meta.Int(.unsigned, info.bits);
+        if (format.ones_complement and value == std.math.minInt(T)) return error.Overflow;
+        const raw: U = if (info.signedness == .unsigned)
+            value
+        else if (format.ones_complement and value < 0)
+            ~@as(U, @intCast(-value))
+        else
+            @bitCast(value);
+        try self.putBits(if (format.endian == .little and info.bits % 8 == 0) @byteSwap(raw) else raw, info.bits);
+    }
+
+    pub fn putFloat(self: *Writer, value: anytype, comptime endian: std.builtin.Endian) Error!void {
+        const U = std.meta.Int(.unsigned, @bitSizeOf(@TypeOf(value)));
+        try self.putInt(U, @bitCast(value), .{ .endian = endian });
+    }
+
+    pub fn putBytes(self: *Writer, data: []const u8) Error!void {
+        for (data) |byte| try self.putBits(byte, 8);
+    }
+
+    /// Writes data, then zeros up to capacity bytes
+    pub fn putPadded(self: *Writer, data: []const u8, capacity: usize) Error!void {
+        if (data.len > capacity) return error.Overflow;
+        try self.putBytes(data);
+        for (data.len..capacity) |_| try self.putBits(0, 8);
+    }
+
+    /// Writes the length of a variable length field into a count field of type T
+    pub fn putCount(self: *Writer, comptime T: type, len: usize, comptime format: IntFormat) Error!void {
+        try self.putInt(T, std.math.cast(T, len) orelse return error.Overflow, format);
+    }
+
+    /// Writes the last `bits` bits of data
+    pub fn putConst(self: *Writer, data: []const u8, bits: usize) Error!void {
+        for (data.len * 8 - bits..data.len * 8) |i| try self.putBits((data[i / 8] >> @intCast(7 - i % 8)) & 1, 1);
+    }
+};
+
+pub const Reader = struct {
+    data: []const u8,
+    pos: usize = 0,
+
+    pub fn init(data: []const u8) Reader {
+        return .{ .data = data };
+    }
+
+    /// Bits left to read
+    pub fn remaining(self: *const Reader) usize {
+        return self.data.len * 8 - self.pos;
+    }
+
+    pub fn getBits(self: *Reader, bits: u7) Error!u64 {
+        if (bits > self.remaining()) return error.Incomplete;
+        var value: u64 = 0;
+        var left = bits;
+        while (left > 0) {
+            const room: u7 = @intCast(8 - self.pos % 8);
+            const n = @min(room, left);
+            const mask: u8 = @truncate((@as(u9, 1) << @intCast(n)) - 1);
+            value = (value << @intCast(n)) | ((self.data[self.pos / 8] >> @intCast(room - n)) & mask);
+            self.pos += n;
+            left -= n;
+        }
+        return value;
+    }
+
+    pub fn getInt(self: *Reader, comptime T: type, comptime format: IntFormat) Error!T {
+        const info = @typeInfo(T).Int;
+        const U = std.meta.Int(.unsigned, info.bits);
+        const read: U = @intCast(try self.getBits(info.bits));
+        const raw = if (format.endian == .little and info.bits % 8 == 0) @byteSwap(read) else read;
+        return if (info.signedness == .unsigned)
+            raw
+        else if (format.ones_complement and raw >> (info.bits - 1) == 1)
+            -@as(T, @intCast(~raw))
+        else
+            @bitCast(raw);
+    }
+
+    pub fn getFloat(self: *Reader, comptime T: type, comptime endian: std.builtin.Endian) Error!T {
+        return @bitCast(try self.getInt(std.meta.Int(.unsigned, @bitSizeOf(T)), .{ .endian = endian }));
+    }
+
+    pub fn getBytes(self: *Reader, out: []u8) Error!void {
+        if (out.len * 8 > self.remaining()) return error.Incomplete;
+        for (out) |*byte| byte.* = @intCast(try self.getBits(8));
+    }
+
+    /// Reads a count field, as the length of the field it counts
+    pub fn getCount(self: *Reader, comptime T: type, comptime format: IntFormat) Error!usize {
+        return std.math.cast(usize, try self.getInt(T, format)) orelse error.Invalid;
+    }
+
+    /// Reads a NUL padded string of capacity bytes into a `std.BoundedArray(u8, _)`
+    pub fn getPadded(self: *Reader, out: anytype, capacity: usize) Error!void {
+        try out.resize(capacity);
+        try self.getBytes(out.slice());
+        out.len = std.mem.trimRight(u8, out.constSlice(), "\x00").len;
+        try checkUtf8(out.constSlice());
+    }
+
+    /// Reads bytes into a `std.BoundedArray(u8, _)` up to sequence, consuming the sequence too
+    pub fn getUntil(self: *Reader, out: anytype, sequence: []const u8) Error!void {
+        out.len = 0;
+        while (!(try self.take(sequence))) {
+            var byte: [1]u8 = undefined;
+            try self.getBytes(&byte);
+            try out.append(byte[0]);
+        }
+    }
+
+    /// Consumes data, failing unless it comes next
+    pub fn expect(self: *Reader, data: []const u8) Error!void {
+        for (data) |byte| if ((try self.getBits(8)) != byte) return error.Invalid;
+    }
+
+    /// Consumes sequence if it comes next
+    pub fn take(self: *Reader, sequence: []const u8) Error!bool {
+        if (sequence.len * 8 > self.remaining()) return false;
+        var peek = self.*;
+        for (sequence) |byte| if ((try peek.getBits(8)) != byte) return false;
+        self.* = peek;
+        return true;
+    }
+
+    /// Whether the next bits are the last `bits` bits of data
+    pub fn matchConst(self: *Reader, data: []const u8, bits: usize) Error!bool {
+        var matched = true;
+        for (data.len * 8 - bits..data.len * 8) |i| {
+            if ((try self.getBits(1)) != (data[i / 8] >> @intCast(7 - i % 8)) & 1) matched = false;
+        }
+        return matched;
+    }
+
+    /// Fails if a whole byte or more is left over
+    pub fn finish(self: *const Reader) Error!void {
+        if (self.remaining() >= 8) return error.Invalid;
+    }
+};
+
+pub fn checkUtf8(data: []const u8) Error!void {
+    if (!std.unicode.utf8ValidateSlice(data)) return error.Invalid;
+}
+
+/// Encodes value into buf, returning the bytes written
+pub fn encode(value: anytype, buf: []u8) Error![]u8 {
+    var w = Writer.init(buf);
+    try value.encode(&w);
+    return w.written();
+}
+
+/// Decodes a T from data, which must hold exactly one
+pub fn decode(comptime T: type, data: []const u8) Error!T {
+    var r = Reader.init(data);
+    const value = try T.decode(&r);
+    try r.finish();
+    return value;
+}
+
+pub fn crc16Xmodem(data: []const u8) u16 {
+    var value: u16 = 0;
+    for (data) |byte| {
+        value ^= @as(u16, byte) << 8;
+        for (0..8) |_| value = if (value & 0x8000 != 0) (value << 1) ^ 0x1021 else value << 1;
+    }
+    return value;
+}
+
+pub const Direction = enum { tx, rx };
+
+/// How a payload is sent, declared as `info` on each payload type
+pub const Info = struct {
+    name: []const u8,
+    direction: Direction,
+    /// The values accepted for each metadata element of the frame. The first is the one sent
+    metadata: []const []const u64 = &.{},
+    /// Size in bytes, if every value encodes to the same size
+    fixed_len: ?usize,
+    /// Largest encoding in bytes
+    max_len: usize,
+};
+
+pub const Unit = enum { bits, bytes };
+
+pub const Size = struct {
+    bits: u7,
+    unit: Unit = .bits,
+};
+
+/// One part of a frame format
+pub const Element = union(enum) {
+    /// The whole frame's size
+    size_total: Size,
+    size_of_payload: Size,
+    /// The size of the `covers` elements right after it
+    size_of_elements: struct { bits: u7, unit: Unit = .bits, covers: usize },
+    payload,
+    /// A value that tells payloads apart, such as a message ID
+    metadata: struct { bits: u7, endian: std.builtin.Endian = .big },
+    /// CRC-32 of the frame so far, big-endian
+    crc32,
+    /// CRC-16/XMODEM of the frame so far, big-endian
+    crc16_xmodem,
+    /// The last `bits` bits of data
+    constant: struct { data: []const u8, bits: usize },
+};
+
+pub const Frame = struct {
+    metadata: std.BoundedArray(u64, 8) = .{},
+    /// The payload, within the parsed data
+    payload: []const u8 = &.{},
+    /// The frame's length in bytes
+    len: usize = 0,
+};
+
+fn elementBits(element: Element) usize {
+    return switch (element) {
+        .size_total, .size_of_payload => |size| size.bits,
+        .size_of_elements => |size| size.bits,
+        .payload => 0,
+        .metadata => |metadata| metadata.bits,
+        .crc32 => 32,
+        .crc16_xmodem => 16,
+        .constant => |constant| constant.bits,
+    };
+}
+
+fn envelopeBits(format: []const Element) usize {
+    var bits: usize = 0;
+    for (format) |element| bits += elementBits(element);
+    return bits;
+}
+
+/// The size of the elements a `size_of_elements` at index i counts
+fn coveredBits(format: []const Element, i: usize, payload_bits: usize) usize {
+    var bits: usize = 0;
+    for (format[i + 1 .. @min(i + 1 + format[i].size_of_elements.covers, format.len)]) |element| {
+        bits += if (element == .payload) payload_bits else elementBits(element);
+    }
+    return bits;
+}
+
+fn sizeValue(bits: usize, unit: Unit) u64 {
+    return if (unit == .bytes) (bits + 7) / 8 else bits;
+}
+
+fn sizeBits(value: u64, unit: Unit) Error!usize {
+    const n = std.math.cast(usize, value) orelse return error.Invalid;
+    return if (unit == .bytes) std.math.mul(usize, n, 8) catch error.Invalid else n;
+}
+
+fn swap(value: u64, bits: u7) u64 {
+    var out: u64 = 0;
+    var v = value;
+    for (0..bits / 8) |_| {
+        out = (out << 8) | (v & 0xff);
+        v >>= 8;
+    }
+    return out;
+}
+
+fn crc(element: Element, data: []const u8) u64 {
+    return if (element == .crc32) std.hash.Crc32.hash(data) else crc16Xmodem(data);
+}
+
+/// Whether a frame's metadata is what a payload is sent with
+pub fn matches(info: Info, metadata: []const u64) bool {
+    for (info.metadata, 0..) |accepted, i| {
+        if (i >= metadata.len) break;
+        if (std.mem.indexOfScalar(u64, accepted, metadata[i]) == null) return false;
+    }
+    return true;
+}
+
+/// Wraps payload in a frame in buf, with a value for each of the format's metadata elements in order
+pub fn encodeFrame(format: []const Element, metadata: []const u64, payload: []const u8, buf: []u8) Error![]u8 {
+    const payload_bits = payload.len * 8;
+    const total = envelopeBits(format) + payload_bits;
+    var w = Writer.init(buf);
+    var next: usize = 0;
+    for (format, 0..) |element, i| {
+        switch (element) {
+            .size_total => |size| try w.putBits(sizeValue(total, size.unit), size.bits),
+            .size_of_payload => |size| try w.putBits(sizeValue(payload_bits, size.unit), size.bits),
+            .size_of_elements => |size| try w.putBits(sizeValue(coveredBits(format, i, payload_bits), size.unit), size.bits),
+            .payload => try w.putBytes(payload),
+            .metadata => |m| {
+                if (next >= metadata.len) return error.Invalid;
+                try w.putBits(if (m.endian == .little) swap(metadata[next], m.bits) else metadata[next], m.bits);
+                next += 1;
+            },
+            .crc32, .crc16_xmodem => {
+                if (w.bits % 8 != 0) return error.Invalid;
+                try w.putBits(crc(element, w.written()), @intCast(elementBits(element)));
+            },
+            .constant => |c| try w.putConst(c.data, c.bits),
+        }
+    }
+    return w.written();
+}
+
+/// Parses a frame from the start of data. `payload_len` is the payload's size in bytes, for formats
+/// without a size field. Unless `complete` is set, running out of data returns null, so it can be
+/// called again as more bytes arrive
+pub fn parseFrame(format: []const Element, data: []const u8, payload_len: ?usize, complete: bool) Error!?Frame {
+    return parse(format, data, payload_len, complete) catch |err| switch (err) {
+        error.Incomplete => if (complete) err else null,
+        else => err,
+    };
+}
+
+fn parse(format: []const Element, data: []const u8, payload_len: ?usize, complete: bool) Error!Frame {
+    const envelope = envelopeBits(format);
+    var payload_index: ?usize = null;
+    for (format, 0..) |element, i| {
+        if (element == .payload) payload_index = i;
+    }
+    var r = Reader.init(data);
+    var frame = Frame{};
+    var total: ?usize = null;
+    var payload_bits: ?usize = null;
+    for (format, 0..) |element, i| {
+        switch (element) {
+            .size_total => |size| {
+                const bits = try sizeBits(try r.getBits(size.bits), size.unit);
+                if (bits < envelope) return error.Invalid;
+                if (bits > data.len * 8) return error.Incomplete;
+                total = bits;
+            },
+            .size_of_payload => |size| payload_bits = try sizeBits(try r.getBits(size.bits), size.unit),
+            .size_of_elements => |size| {
+                const region = try sizeBits(try r.getBits(size.bits), size.unit);
+                if (payload_index) |p| {
+                    if (p > i and p <= i + size.covers) payload_bits = std.math.sub(usize, region, coveredBits(format, i, 0)) catch return error.Invalid;
+                }
+            },
+            .payload => {
+                const bits = payload_bits orelse if (total) |t|
+                    t - envelope
+                else if (payload_len) |len|
+                    len * 8
+                else if (complete)
+                    r.remaining() -| envelopeBits(format[i + 1 ..])
+                else
+                    return error.Invalid;
+                if (bits % 8 != 0 or r.pos % 8 != 0) return error.Invalid;
+                if (bits > r.remaining()) return error.Incomplete;
+                frame.payload = data[r.pos / 8 ..][0 .. bits / 8];
+                r.pos += bits;
+            },
+            .metadata => |m| {
+                const value = try r.getBits(m.bits);
+                frame.metadata.append(if (m.endian == .little) swap(value, m.bits) else value) catch return error.Invalid;
+            },
+            .crc32, .crc16_xmodem => {
+                if (r.pos % 8 != 0) return error.Invalid;
+                const expected = crc(element, data[0 .. r.pos / 8]);
+                if ((try r.getBits(@intCast(elementBits(element)))) != expected) return error.Invalid;
+            },
+            .constant => |c| if (!(try r.matchConst(c.data, c.bits))) return error.Invalid,
+        }
+    }
+    if (total) |t| {
+        if ((t + 7) / 8 != (r.pos + 7) / 8) return error.Invalid;
+    }
+    if (complete and r.remaining() >= 8) return error.Invalid;
+    frame.len = (r.pos + 7) / 8;
+    return frame;
+}
+"#;
+
+const FRAME_FNS: &str = r#"
+fn formatOf(direction: wire.Direction) []const wire.Element {
+    return switch (direction) {
+        .tx => &tx_format,
+        .rx => &rx_format,
+    };
+}
+
+/// Encodes a payload wrapped in its frame into buf, returning the frame
+pub fn pack(value: anytype, buf: []u8) wire.Error![]u8 {
+    const P = @TypeOf(value);
+    var scratch: [P.info.max_len]u8 = undefined;
+    const payload = try wire.encode(value, &scratch);
+    var metadata: [P.info.metadata.len]u64 = undefined;
+    for (P.info.metadata, &metadata) |values, *m| m.* = values[0];
+    return wire.encodeFrame(formatOf(P.info.direction), &metadata, payload, buf);
+}
+
+/// Decodes a payload of type P from data, which must hold exactly one frame
+pub fn unpack(comptime P: type, data: []const u8) wire.Error!P {
+    const frame = (try wire.parseFrame(formatOf(P.info.direction), data, P.info.fixed_len, true)) orelse return error.Incomplete;
+    if (!wire.matches(P.info, frame.metadata.constSlice())) return error.Invalid;
+    return wire.decode(P, frame.payload);
+}
+"#;
+
+const DEVICE_FIELDS: &str = r#"
+reader: std.io.AnyReader,
+writer: std.io.AnyWriter,
+/// Waits between actions. Replace it to run transactions without waiting, for example in tests
+sleep: *const fn (ms: u32) void = sleepMs,
+/// Drops bytes received but not yet read, for ports that can
+discard_input: ?Discard = null,
+buf: [max_frame_len]u8 = undefined,
+
+pub const Discard = struct {
+    context: *anyopaque,
+    discardFn: *const fn (context: *anyopaque) anyerror!void,
+};
+
+/// A reader that returns no bytes, such as a serial port whose read timeout passed, fails
+/// transactions with `error.Timeout`
+pub fn init(reader: std.io.AnyReader, writer: std.io.AnyWriter) Device {
+    return .{ .reader = reader, .writer = writer };
+}
+
+fn discardInput(self: *Device) !void {
+    if (self.discard_input) |discard| try discard.discardFn(discard.context);
+}
+"#;
+
+const RECEIVE_FRAMED: &str = r#"
+var len: usize = 0;
+while (true) {
+    if (len == self.buf.len) return error.Overflow;
+    if ((try self.reader.read(self.buf[len..][0..1])) == 0) return error.Timeout;
+    len += 1;
+    const frame = (try wire.parseFrame(&rx_format, self.buf[0..len], P.info.fixed_len, false)) orelse continue;
+    if (!wire.matches(P.info, frame.metadata.constSlice())) return error.UnexpectedPayload;
+    return wire.decode(P, frame.payload);
+}
+"#;
+
+const RECEIVE_UNFRAMED: &str = r#"
+// without a frame format, every payload has a fixed size
+const data = self.buf[0..P.info.fixed_len.?];
+if ((try self.reader.readAll(data)) < data.len) return error.Timeout;
+return wire.decode(P, data);
+"#;
+
+const TEST_SUPPORT: &str = r#"
+/// Replays canned responses in batches, the first straight away and another after each write.
+/// Reading nothing is a timeout, as with a serial port
+const FakePort = struct {
+    batches: []const []const u8,
+    rx: std.ArrayList(u8),
+    read_pos: usize = 0,
+    writes: usize = 0,
+
+    fn init(allocator: std.mem.Allocator, batches: []const []const u8) !FakePort {
+        var port = FakePort{ .batches = batches, .rx = std.ArrayList(u8).init(allocator) };
+        try port.respond();
+        return port;
+    }
+
+    fn respond(self: *FakePort) !void {
+        if (self.batches.len == 0) return;
+        try self.rx.appendSlice(self.batches[0]);
+        self.batches = self.batches[1..];
+    }
+
+    fn read(context: *const anyopaque, buf: []u8) anyerror!usize {
+        const self: *FakePort = @ptrCast(@alignCast(@constCast(context)));
+        const n = @min(buf.len, self.rx.items.len - self.read_pos);
+        @memcpy(buf[0..n], self.rx.items[self.read_pos..][0..n]);
+        self.read_pos += n;
+        return n;
+    }
+
+    fn write(context: *const anyopaque, data: []const u8) anyerror!usize {
+        const self: *FakePort = @ptrCast(@alignCast(@constCast(context)));
+        self.writes += 1;
+        try self.respond();
+        return data.len;
+    }
+
+    fn reader(self: *FakePort) std.io.AnyReader {
+        return .{ .context = self, .readFn = read };
+    }
+
+    fn writer(self: *FakePort) std.io.AnyWriter {
+        return .{ .context = self, .writeFn = write };
+    }
+
+    fn expectDone(self: *const FakePort, sends: bool) !void {
+        try testing.expectEqual(@as(usize, 0), self.batches.len);
+        try testing.expectEqual(self.rx.items.len, self.read_pos);
+        try testing.expectEqual(sends, self.writes > 0);
+    }
+};
+
+fn noSleep(_: u32) void {}
+
+/// Like `testing.expectEqualDeep`, but only compares the items a `BoundedArray` holds
+fn expectSame(expected: anytype, actual: @TypeOf(expected)) !void {
+    const T = @TypeOf(expected);
+    switch (@typeInfo(T)) {
+        .Struct => |info| if (@hasDecl(T, "constSlice")) {
+            try testing.expectEqual(expected.len, actual.len);
+            for (expected.constSlice(), actual.constSlice()) |e, a| try expectSame(e, a);
+        } else inline for (info.fields) |field| {
+            try expectSame(@field(expected, field.name), @field(actual, field.name));
+        },
+        .Array => for (expected, actual) |e, a| try expectSame(e, a),
+        else => try testing.expectEqual(expected, actual),
+    }
+}
+"#;
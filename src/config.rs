@@ -1,9 +1,10 @@
 use std::collections::BTreeMap;
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 
 //TODO: We need a better way to initialize the metadata values from the toml
 //this is probably in the right direction. 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum LiteralValue {
     String(String),
@@ -11,16 +12,16 @@ pub enum LiteralValue {
     //TODO: boolean
 }
 
-impl ToString for LiteralValue {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for LiteralValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            LiteralValue::Int(i) => i.to_string(),
-            LiteralValue::String(s) => s.to_string()
+            LiteralValue::Int(i) => write!(f, "{i}"),
+            LiteralValue::String(s) => write!(f, "{s}")
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)]
 pub enum OneOrMany<T> where T: Clone {
     One(T),
@@ -43,22 +44,27 @@ impl<T> OneOrMany<T> where T: Clone {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The unit a size element counts in
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BitsOrBytes {
     Bits,
     Bytes
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A group of fields that payloads and other structs can include by name
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct ReusableStruct {
     /// Name of this struct, used in codegen and to reference this struct from other fields
     pub name: String,
     pub fields: Vec<PacketSegment>,
+
+    /// Optional description documentation
     pub description: Option<String>
     //TODO: privacy?
 }
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Endianness {
     /// Most significant bit shows up first (at a lower memory address). If we visualize memory
     /// addresses as increasing from left to right, the most significant bit would be on the left,
@@ -72,7 +78,7 @@ pub enum Endianness {
     LittleEndian
 }
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Signing {
     /// Uses the first bit to flag negative numbers. 
     OnesComplement,
@@ -86,8 +92,9 @@ pub enum Signing {
 }
 
 /// Strategy for terminating an array. How should we know when to stop reading from the device?
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)] //make untagged?
+#[schemars(deny_unknown_fields)]
 pub enum Terminator {
     /// Reads/Writes this many elements
     CountFixed { count: u32 },
@@ -113,8 +120,9 @@ pub enum Terminator {
 // packets that are completely sized can be read from stream in a single shot
 /// Represents a particular piece of data's type. In the literal sense, describes its
 /// interpretation. The actual length of the data is specified in bits elsewhere
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "type")]
+#[schemars(deny_unknown_fields)]
 pub enum SizedDataType {
     //TODO: string and array are unsized. Maybe we should embed size into this enum
 
@@ -137,8 +145,10 @@ pub enum SizedDataType {
     Const { data: Vec<u8> }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The type of a field whose length isn't known until it's sent or received
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "type")]
+#[schemars(deny_unknown_fields)]
 pub enum UnsizedDataType {
     /// Several Repetitions of a given type
     Array {
@@ -166,18 +176,24 @@ pub enum UnsizedDataType {
     }*/
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A CRC algorithm, computed over every byte of the frame before it
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Crc {
     // there are tons of CRC implementations. TODO: list as many as possible here, including
     // infamous CRC16 XMODEM
+    /// CRC-32, as used by Ethernet and zlib
     Crc32,
+
+    /// CRC-16/XMODEM: polynomial 0x1021, starting from zero
     Crc16XModem,
 }
 
 //in variants that are integer sizes, leave out signing flag beacuse ones&twos complement repr's are the same
 //for positive numbers
-#[derive(Serialize, Deserialize, Debug)]
+/// One part of a frame, in the order it's sent
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "type")]
+#[schemars(deny_unknown_fields)]
 pub enum PacketFormatElement {
     /// The Total Size of the packet, including the payload, all headers (Crc etc.) 
     SizeTotal {
@@ -227,27 +243,43 @@ pub enum PacketFormatElement {
 /// lowest level description of your interface  
 type PacketFormat = Vec<PacketFormatElement>;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Frame formats for a UART, or any other byte stream
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct UARTConfig {
+    /// How frames sent to the device are laid out
     pub tx_format: PacketFormat, 
+
+    /// How frames received from the device are laid out
     pub rx_format: PacketFormat,
     //TODO: baud rate, stop bits etc.
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A field of a payload or struct
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)]
+#[schemars(deny_unknown_fields)]
 pub enum PacketSegment {
+    /// A field that always takes the same number of bits
     Sized {
+        /// Field name, in snake case
         name: String,
         
+        /// Width of the field
         bits: u32,
 
         #[serde(rename = "type")]
         datatype: SizedDataType,
 
-        description: Option<String>
+        /// Optional description documentation
+        description: Option<String>,
+
+        /// Physical units of a number, such as `mg` or `degC`. Only used in documentation
+        units: Option<String>
     },
+    /// A field whose length depends on its contents, given by `termination`
     Unsized {
+        /// Field name, in snake case
         name: String,
 
         #[serde(rename = "type")]
@@ -259,9 +291,18 @@ pub enum PacketSegment {
         /// packet/payload size, if included in the packet format
         termination: Option<Terminator>,
         
+        /// Optional description documentation
         description: Option<String>
     },
-    Struct { name: String, struct_name: String }
+
+    /// Includes the fields of a struct from `structs`
+    Struct {
+        /// Field name, in snake case
+        name: String,
+
+        /// Key of the struct in `structs`
+        struct_name: String
+    }
 }
 
 impl PacketSegment {
@@ -274,7 +315,9 @@ impl PacketSegment {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The contents of one kind of frame, and the metadata values that identify it. Keys other than
+/// `segments` and `description` are metadata, named by the frame format's `Metadata` elements
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct Payload {
     /// Data inside this packet, in segments
     pub segments: Vec<PacketSegment>,
@@ -288,18 +331,23 @@ pub struct Payload {
     pub description: String
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Payloads by direction, keyed by name
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+#[schemars(deny_unknown_fields)]
 pub struct AllPayloads {
     /// Packet formats that are sendable
+    #[serde(default)]
     pub tx: BTreeMap<String, Payload>,
 
     /// Packet formats that are recievable
+    #[serde(default)]
     pub rx: BTreeMap<String, Payload>,
 }
 
 /// An action that can be taken during a transaction
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(tag = "type")]
+#[schemars(deny_unknown_fields)]
 pub enum Action {
     /// Send a packet with the given name
     Tx { payload: String },
@@ -316,7 +364,8 @@ pub enum Action {
 
 //TODO: a way to sleep + flush buffer
 /// Represents a grouping of packets, send or receive, to be performed in order
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct Transaction {
     /// An ordered list of actions to take during a transaction, like sending or recieving a
     /// packet, or like sleeping or flushing the buffer
@@ -329,19 +378,65 @@ pub struct Transaction {
     pub description: String
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// What the document describes
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct DeviceInfo {
+    /// The device's name, as its maker writes it. Unlike other names, it needn't be snake case
     pub name: String,
+
+    /// A sentence or two about the device
     pub description: String
 }
 
+/// Where an import comes from
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportSource {
+    /// A file, relative to the one importing it
+    Path(String),
+
+    /// A package's `openpid.toml`, found in `openpid_packages/` next to the root document or in a
+    /// directory listed in `OPENPID_PATH`
+    Package(String),
+}
+
+/// Another file whose structs, payloads, transactions and frame format become part of this
+/// document, such as a product line's shared envelope and structs
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[schemars(deny_unknown_fields)]
+pub struct Import {
+    #[serde(flatten)]
+    pub source: ImportSource,
+
+    /// Prefix for the imported names, as `namespace_name`, so libraries can't clash with this
+    /// document or each other. Without one, names are imported as they are
+    pub namespace: Option<String>,
+}
+
+/// An imported file. It's any part of a document: a library of structs needn't describe a
+/// device, and its `device_info` is ignored if it does
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct Library {
+    pub device_info: Option<DeviceInfo>,
+    pub openpid_version: Option<String>,
+    pub doc_version: Option<String>,
+    pub imports: Vec<Import>,
+    pub uart: Option<UARTConfig>,
+    pub structs: BTreeMap<String, ReusableStruct>,
+    pub payloads: AllPayloads,
+    pub transactions: BTreeMap<String, Transaction>,
+}
+
 //TODO: stub
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct SPIConfig {
 }
 
 //TODO: stub
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct I2CConfig {
 }
 
@@ -352,17 +447,24 @@ pub struct I2CConfig {
 //transaction, but registers are basically fixed-size packets. 
 //TODO change ids so that they have types wrapped around them
 //TODO: config for I2C, SPI, UART (default baud etc.)
-#[derive(Serialize, Deserialize, Debug)]
+/// An OpenPID document, describing a device's binary protocol
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct OpenPID {
     /// Information about the device
     pub device_info: DeviceInfo,
 
-    /// Version of OpenPID to use
+    /// Version of the OpenPID spec the document is written for, such as "0.2". Documents without
+    /// one are read as the oldest version and migrated. See [crate::migrate]
     pub openpid_version: Option<String>,
 
     /// This document's version
     pub doc_version: Option<String>,
 
+    /// Files merged into this one before it's used. See [crate::include]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub imports: Vec<Import>,
+
     /// If the OpenPID frames are to be used in a UART interface, must be Some, and
     /// appropriate global configuration should also exist. It's acceptable for different supported
     /// protocols to require additional configuration on a per-payload basis. We sugggest
@@ -381,10 +483,12 @@ pub struct OpenPID {
 
     /// Referenced by packets, describes re-usable packet contents that may be sent or recieved
     /// to/from the device 
+    #[serde(default)]
     pub structs: BTreeMap<String, ReusableStruct>,
 
     /// Describes the actual contents of the packets themselves, the next highest level description
     /// of your interface
+    #[serde(default)]
     pub payloads: AllPayloads,
 
     /// The highest level of your interface representable by OpenPID. If you want higher-level
@@ -402,6 +506,13 @@ pub struct OpenPID {
     //TODO: higher level config for responses
 }
 
+/// JSON Schema for OpenPID documents, derived from these types with their doc comments as
+/// descriptions. Editors use it to complete and check `openpid.toml`, for example through Taplo's
+/// `#:schema` directive. The repository's `openpid.schema.json` is this, pretty printed
+pub fn json_schema() -> schemars::Schema {
+    schemars::schema_for!(OpenPID)
+}
+
 /*pub struct Transition {
 
     //TODO: language-agnostic boolean expression
@@ -0,0 +1,344 @@
+//! What changed between two versions of a device's document, and whether drivers generated from
+//! the old one still work. Each [Change] has an impact on the wire, where a driver built from
+//! the old document talks to a device built to the new one, and on the API, where code written
+//! against the old generated driver is compiled against the new one. Together they suggest how
+//! `doc_version` should move:
+//!
+//! - a breaking change, such as a field added anywhere, a type width or payload ID
+//!   changed, a payload removed or a transaction's returns changed, needs a major version
+//! - a compatible one, such as a payload or transaction added, needs a minor version
+//! - anything else, such as a description, needs a patch
+//!
+//! Documents are compared after [resolving](crate::ir::Ir), so imports and types are already
+//! merged and checked. Renames can't be told apart from a removal and an addition
+
+use std::fmt::Display;
+
+use semver::Version;
+use serde::Serialize;
+
+use crate::codec::Direction;
+use crate::ir::{Field, FrameElement, Ir, Length, Payload, Struct, Transaction, Type};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Impact {
+    /// Nothing a driver or its users can notice, such as documentation
+    None,
+
+    /// Old drivers and code keep working, and new ones can use what was added
+    Compatible,
+
+    Breaking,
+}
+
+impl Display for Impact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Impact::None => "none",
+            Impact::Compatible => "compatible",
+            Impact::Breaking => "breaking",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    /// What changed, as a path into the document such as `payloads.rx.reading.accel`
+    pub path: String,
+    pub description: String,
+    pub wire: Impact,
+    pub api: Impact,
+}
+
+impl Change {
+    /// The worse of the change's two impacts
+    pub fn impact(&self) -> Impact {
+        self.wire.max(self.api)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bump {
+    Patch,
+    Minor,
+    Major,
+}
+
+impl Bump {
+    /// The next version after `version`. Below 1.0.0, breaking changes bump the minor version and
+    /// everything else the patch, as Cargo does
+    pub fn apply(self, version: &Version) -> Version {
+        let (major, minor, patch) = match (self, version.major) {
+            (Bump::Major, 0) => (0, version.minor + 1, 0),
+            (Bump::Minor, 0) | (Bump::Patch, _) => (version.major, version.minor, version.patch + 1),
+            (Bump::Major, _) => (version.major + 1, 0, 0),
+            (Bump::Minor, _) => (version.major, version.minor + 1, 0),
+        };
+        Version::new(major, minor, patch)
+    }
+}
+
+impl Display for Bump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bump::Patch => write!(f, "patch"),
+            Bump::Minor => write!(f, "minor"),
+            Bump::Major => write!(f, "major"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Diff {
+    pub changes: Vec<Change>,
+}
+
+impl Diff {
+    /// The smallest version bump that covers every change, or None if nothing changed
+    pub fn bump(&self) -> Option<Bump> {
+        self.changes
+            .iter()
+            .map(|c| match c.impact() {
+                Impact::Breaking => Bump::Major,
+                Impact::Compatible => Bump::Minor,
+                Impact::None => Bump::Patch,
+            })
+            .max()
+    }
+
+    pub fn is_breaking(&self) -> bool {
+        self.changes.iter().any(|c| c.impact() == Impact::Breaking)
+    }
+
+    /// The version the new document should have, given the old document's `doc_version`
+    pub fn suggested_version(&self, old: &str) -> Option<Version> {
+        let old = Version::parse(old.trim_start_matches('v')).ok()?;
+        Some(self.bump().map_or(old.clone(), |bump| bump.apply(&old)))
+    }
+
+    fn push(&mut self, path: impl Into<String>, description: impl Into<String>, wire: Impact, api: Impact) {
+        self.changes.push(Change { path: path.into(), description: description.into(), wire, api });
+    }
+}
+
+/// Who sets the fields of a payload or struct, which decides whether adding one breaks code
+#[derive(Clone, Copy, PartialEq)]
+enum Owner {
+    /// A payload the device sends, whose fields drivers only read
+    Rx,
+
+    /// A payload the driver sends, or a struct that may be in one
+    Built,
+}
+
+/// Compares the resolved `old` and `new` documents
+pub fn diff(old: &Ir, new: &Ir) -> Diff {
+    let mut diff = Diff::default();
+    if old.device.name != new.device.name {
+        let description = format!("Device renamed from \"{}\" to \"{}\", which renames the generated driver", old.device.name, new.device.name);
+        diff.push("device_info.name", description, Impact::None, Impact::Breaking);
+    }
+    if old.device.description != new.device.description {
+        diff.push("device_info.description", "Description changed", Impact::None, Impact::None);
+    }
+    framing(&mut diff, old, new);
+    structs(&mut diff, &old.structs, &new.structs);
+    for direction in [Direction::Tx, Direction::Rx] {
+        payloads(&mut diff, direction, old.payloads(direction), new.payloads(direction));
+    }
+    transactions(&mut diff, &old.transactions, &new.transactions);
+    diff
+}
+
+fn framing(diff: &mut Diff, old: &Ir, new: &Ir) {
+    match (&old.framing, &new.framing) {
+        (Some(_), None) => diff.push("uart", "The UART frame format was removed", Impact::Breaking, Impact::Breaking),
+        (None, Some(_)) => diff.push("uart", "A UART frame format was added, so every payload is now framed", Impact::Breaking, Impact::None),
+        (Some(old), Some(new)) => {
+            for (direction, key) in [(Direction::Tx, "tx_format"), (Direction::Rx, "rx_format")] {
+                let (old, new) = (old.format(direction), new.format(direction));
+                if old != new {
+                    let description = match envelope_bits(old) == envelope_bits(new) {
+                        true => format!("The {direction} frame format changed"),
+                        false => format!("The {direction} frame envelope changed from {} to {} bits", envelope_bits(old), envelope_bits(new)),
+                    };
+                    diff.push(format!("uart.{key}"), description, Impact::Breaking, Impact::None);
+                }
+            }
+        }
+        (None, None) => {}
+    }
+}
+
+fn envelope_bits(format: &[FrameElement]) -> u64 {
+    format.iter().map(FrameElement::envelope_bits).sum()
+}
+
+fn structs(diff: &mut Diff, old: &[Struct], new: &[Struct]) {
+    for s in old {
+        let path = format!("structs.{}", s.name);
+        match new.iter().find(|n| n.name == s.name) {
+            // payloads using it report their own changes
+            None => diff.push(path, "Struct removed", Impact::None, Impact::Breaking),
+            Some(n) => {
+                if s.description != n.description {
+                    diff.push(&path, "Description changed", Impact::None, Impact::None);
+                }
+                fields(diff, &path, Owner::Built, &s.fields, &n.fields);
+            }
+        }
+    }
+    for s in new.iter().filter(|n| !old.iter().any(|s| s.name == n.name)) {
+        diff.push(format!("structs.{}", s.name), "Struct added", Impact::None, Impact::Compatible);
+    }
+}
+
+fn payloads(diff: &mut Diff, direction: Direction, old: &[Payload], new: &[Payload]) {
+    let key = direction.to_string().to_lowercase();
+    for p in old {
+        let path = format!("payloads.{key}.{}", p.name);
+        let Some(n) = new.iter().find(|n| n.name == p.name) else {
+            diff.push(path, format!("{direction} payload removed"), Impact::Breaking, Impact::Breaking);
+            continue;
+        };
+        if p.description != n.description {
+            diff.push(&path, "Description changed", Impact::None, Impact::None);
+        }
+        for m in &p.metadata {
+            match n.metadata(m.name.raw()) {
+                None => diff.push(format!("{path}.{}", m.name), format!("Metadata \"{}\" removed", m.name), Impact::Breaking, Impact::None),
+                Some(nm) if nm.values != m.values => {
+                    let values = |m: &crate::ir::Metadata| m.values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ");
+                    let description = format!("\"{}\" changed from {} to {}", m.name, values(m), values(nm));
+                    diff.push(format!("{path}.{}", m.name), description, Impact::Breaking, Impact::None);
+                }
+                Some(_) => {}
+            }
+        }
+        for m in n.metadata.iter().filter(|nm| p.metadata(nm.name.raw()).is_none()) {
+            diff.push(format!("{path}.{}", m.name), format!("Metadata \"{}\" added", m.name), Impact::Breaking, Impact::None);
+        }
+        let owner = match direction {
+            Direction::Rx => Owner::Rx,
+            Direction::Tx => Owner::Built,
+        };
+        fields(diff, &path, owner, &p.fields, &n.fields);
+    }
+    for p in new.iter().filter(|n| !old.iter().any(|p| p.name == n.name)) {
+        diff.push(format!("payloads.{key}.{}", p.name), format!("{direction} payload added"), Impact::Compatible, Impact::Compatible);
+    }
+}
+
+/// Compares the fields of a payload or a struct
+fn fields(diff: &mut Diff, path: &str, owner: Owner, old: &[Field], new: &[Field]) {
+    let kept_old = old.iter().filter(|f| new.iter().any(|n| n.name == f.name)).map(|f| &f.name).collect::<Vec<_>>();
+    let kept_new = new.iter().filter(|n| old.iter().any(|f| f.name == n.name)).map(|f| &f.name).collect::<Vec<_>>();
+
+    for f in old.iter().filter(|f| !kept_old.contains(&&f.name)) {
+        let api = if f.is_user_facing() { Impact::Breaking } else { Impact::None };
+        diff.push(format!("{path}.{}", f.name), "Field removed", Impact::Breaking, api);
+    }
+
+    // wherever it goes: old decoders reject what's left over after the fields they know, and a
+    // device expects more than old drivers send
+    for f in new.iter().filter(|n| !kept_new.contains(&&n.name)) {
+        let api = match (f.is_user_facing(), owner) {
+            (false, _) => Impact::None,
+            (true, Owner::Rx) => Impact::Compatible,
+            (true, Owner::Built) => Impact::Breaking,
+        };
+        diff.push(format!("{path}.{}", f.name), "Field added", Impact::Breaking, api);
+    }
+
+    if kept_old != kept_new {
+        diff.push(path, "Fields reordered", Impact::Breaking, Impact::None);
+    }
+    for f in old {
+        let Some(n) = new.iter().find(|n| n.name == f.name) else { continue };
+        let field_path = format!("{path}.{}", f.name);
+        if let Some((description, wire, api)) = type_change(&f.ty, &n.ty) {
+            diff.push(&field_path, description, wire, api);
+        }
+        if f.units != n.units {
+            let units = |u: &Option<String>| u.clone().unwrap_or_else(|| "none".to_owned());
+            diff.push(&field_path, format!("Units changed from {} to {}", units(&f.units), units(&n.units)), Impact::None, Impact::None);
+        }
+        if f.description != n.description {
+            diff.push(&field_path, "Description changed", Impact::None, Impact::None);
+        }
+    }
+}
+
+fn length_summary(len: &Length) -> String {
+    match len {
+        Length::Fixed(n) => format!("{n} long"),
+        Length::Capacity(n) => format!("up to {n} bytes"),
+        Length::CountField(name) => format!("counted by \"{name}\""),
+        Length::Sequence(bytes) => format!("terminated by {bytes:02x?}"),
+        Length::Remainder => "the rest of the payload".to_owned(),
+    }
+}
+
+/// How a field's type changed, with its wire and API impact
+fn type_change(old: &Type, new: &Type) -> Option<(String, Impact, Impact)> {
+    if old == new {
+        return None;
+    }
+    let change = match (old, new) {
+        (Type::Int { bits: ob, signing: os, endianness: oe }, Type::Int { bits: nb, signing: ns, endianness: ne }) => {
+            if ob != nb {
+                (format!("Width changed from {ob} to {nb} bits"), Impact::Breaking, Impact::Breaking)
+            } else if os != ns {
+                (format!("Changed from {} to {}", old.summary(), new.summary()), Impact::Breaking, Impact::Breaking)
+            } else {
+                debug_assert_ne!(oe, ne);
+                (format!("Endianness changed from {oe:?} to {ne:?}"), Impact::Breaking, Impact::None)
+            }
+        }
+        (Type::Float { bits: ob, .. }, Type::Float { bits: nb, .. }) if ob != nb => (format!("Width changed from {ob} to {nb} bits"), Impact::Breaking, Impact::Breaking),
+        (Type::Float { endianness: oe, .. }, Type::Float { endianness: ne, .. }) => (format!("Endianness changed from {oe:?} to {ne:?}"), Impact::Breaking, Impact::None),
+        (Type::Const(_), Type::Const(_)) => ("Constant value changed".to_owned(), Impact::Breaking, Impact::None),
+        (Type::Bytes(ol), Type::Bytes(nl)) | (Type::String(ol), Type::String(nl)) | (Type::Array { len: ol, .. }, Type::Array { len: nl, .. })
+            if old.struct_name() == new.struct_name() =>
+        {
+            // fixed lengths are arrays in generated code; the others are vectors either way
+            let api = match (ol, nl) {
+                (Length::Fixed(_), _) | (_, Length::Fixed(_)) => Impact::Breaking,
+                _ => Impact::None,
+            };
+            (format!("Length changed from {} to {}", length_summary(ol), length_summary(nl)), Impact::Breaking, api)
+        }
+        _ => (format!("Type changed from {} to {}", old.summary(), new.summary()), Impact::Breaking, Impact::Breaking),
+    };
+    Some(change)
+}
+
+fn transactions(diff: &mut Diff, old: &[Transaction], new: &[Transaction]) {
+    for t in old {
+        let path = format!("transactions.{}", t.name);
+        let Some(n) = new.iter().find(|n| n.name == t.name) else {
+            diff.push(path, "Transaction removed", Impact::None, Impact::Breaking);
+            continue;
+        };
+        if t.description != n.description {
+            diff.push(&path, "Description changed", Impact::None, Impact::None);
+        }
+        if t.actions != n.actions {
+            diff.push(format!("{path}.actions"), "Actions changed", Impact::Breaking, Impact::None);
+        }
+        // returns are a generated method's return type, so any change to them breaks callers
+        let returns = |t: &Transaction| t.returns.iter().map(|r| (r.name.clone(), r.ty.clone())).collect::<Vec<_>>();
+        if returns(t) != returns(n) {
+            let summary = |t: &Transaction| {
+                let names = t.returns.iter().map(|r| format!("{}: {}", r.name, r.ty.summary())).collect::<Vec<_>>();
+                if names.is_empty() { "nothing".to_owned() } else { names.join(", ") }
+            };
+            diff.push(format!("{path}.returns"), format!("Returns changed from {} to {}", summary(t), summary(n)), Impact::None, Impact::Breaking);
+        }
+    }
+    for t in new.iter().filter(|n| !old.iter().any(|t| t.name == n.name)) {
+        diff.push(format!("transactions.{}", t.name), "Transaction added", Impact::None, Impact::Compatible);
+    }
+}
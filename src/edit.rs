@@ -0,0 +1,436 @@
+//! Programmatic edits to an OpenPID document that leave the rest of the file as it was written,
+//! comments and all. Renames follow references: renaming a payload updates the transactions that
+//! send or receive it, renaming a struct updates every `struct_name` and `item_struct`, and renaming
+//! a field updates `CountInPacket` terminators and transaction `returns` that go through it.
+
+use std::fmt::Display;
+
+use toml_edit::{DocumentMut, Item, Key, Table, TableLike, Value};
+
+use crate::codec::Direction;
+use crate::config::{Payload, ReusableStruct};
+use crate::format::{format, format_document, FormatError};
+
+#[derive(Debug)]
+pub enum EditError {
+    /// The input isn't TOML
+    Syntax(toml_edit::TomlError),
+
+    /// Nothing to edit by that name
+    NotFound { what: &'static str, name: String },
+
+    /// Something by the new name already exists
+    Exists { what: &'static str, name: String },
+
+    /// Part of the document the edit needs isn't shaped like an OpenPID document, such as a
+    /// `segments` that isn't an array
+    Malformed { path: String },
+
+    /// The payload or struct to add couldn't be written as TOML
+    Toml(toml::ser::Error),
+}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EditError::Syntax(e) => write!(f, "Couldn't parse the document: {e}"),
+            EditError::NotFound { what, name } => write!(f, "No {what} named \"{name}\""),
+            EditError::Exists { what, name } => write!(f, "There's already a {what} named \"{name}\""),
+            EditError::Malformed { path } => write!(f, "{path} isn't laid out like an OpenPID document"),
+            EditError::Toml(e) => write!(f, "Couldn't write TOML: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+impl From<toml_edit::TomlError> for EditError {
+    fn from(value: toml_edit::TomlError) -> Self {
+        EditError::Syntax(value)
+    }
+}
+
+impl From<toml::ser::Error> for EditError {
+    fn from(value: toml::ser::Error) -> Self {
+        EditError::Toml(value)
+    }
+}
+
+/// What a field belongs to
+#[derive(Debug, Clone, Copy)]
+pub enum FieldOwner<'a> {
+    Payload(Direction, &'a str),
+    Struct(&'a str),
+}
+
+/// An OpenPID document open for editing. Display it to get the edited file back
+#[derive(Debug, Clone)]
+pub struct Editor {
+    doc: DocumentMut,
+}
+
+impl Display for Editor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.doc)
+    }
+}
+
+fn direction_key(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Tx => "tx",
+        Direction::Rx => "rx",
+    }
+}
+
+fn set_str(value: &mut Value, text: &str) {
+    let decor = value.decor().clone();
+    *value = text.into();
+    *value.decor_mut() = decor;
+}
+
+fn str_of<'t>(table: &'t dyn TableLike, key: &str) -> Option<&'t str> {
+    table.get(key).and_then(Item::as_str)
+}
+
+fn set_key(table: &mut dyn TableLike, key: &str, text: &str) {
+    if let Some(Item::Value(value)) = table.get_mut(key) {
+        set_str(value, text);
+    }
+}
+
+/// The tables of an array of inline tables, or of an `[[array of tables]]`
+pub(crate) fn rows(item: &Item) -> Vec<&dyn TableLike> {
+    match item {
+        Item::Value(Value::Array(array)) => array.iter().filter_map(|v| v.as_inline_table().map(|t| t as &dyn TableLike)).collect(),
+        Item::ArrayOfTables(tables) => tables.iter().map(|t| t as &dyn TableLike).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn rows_mut(item: &mut Item) -> Vec<&mut dyn TableLike> {
+    match item {
+        Item::Value(Value::Array(array)) => array.iter_mut().filter_map(|v| v.as_inline_table_mut().map(|t| t as &mut dyn TableLike)).collect(),
+        Item::ArrayOfTables(tables) => tables.iter_mut().map(|t| t as &mut dyn TableLike).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Renames a key in place, keeping its position and decoration
+fn rename_key(item: &mut Item, old: &str, new: &str) {
+    fn renamed(key: Key, old: &str, new: &str) -> Key {
+        match key.get() == old {
+            true => Key::new(new).with_leaf_decor(key.leaf_decor().clone()),
+            false => key,
+        }
+    }
+    match item {
+        Item::Table(table) => {
+            let keys = table.iter().map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
+            let entries = keys.iter().filter_map(|k| table.remove_entry(k)).collect::<Vec<_>>();
+            for (key, value) in entries {
+                table.insert_formatted(&renamed(key, old, new), value);
+            }
+        }
+        Item::Value(Value::InlineTable(table)) => {
+            let keys = table.iter().map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
+            let entries = keys.iter().filter_map(|k| table.remove_entry(k)).collect::<Vec<_>>();
+            for (key, value) in entries {
+                table.insert_formatted(&renamed(key, old, new), value);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Every table in the document, inline or not, and in arrays
+fn visit_tables(item: &mut Item, f: &mut dyn FnMut(&mut dyn TableLike)) {
+    fn visit_value(value: &mut Value, f: &mut dyn FnMut(&mut dyn TableLike)) {
+        match value {
+            Value::InlineTable(table) => {
+                f(table);
+                for (_, v) in table.iter_mut() {
+                    visit_value(v, f);
+                }
+            }
+            Value::Array(array) => array.iter_mut().for_each(|v| visit_value(v, f)),
+            _ => {}
+        }
+    }
+    match item {
+        Item::Table(table) => {
+            f(table);
+            for (_, child) in table.iter_mut() {
+                visit_tables(child, f);
+            }
+        }
+        Item::ArrayOfTables(tables) => {
+            for table in tables.iter_mut() {
+                f(table);
+                for (_, child) in table.iter_mut() {
+                    visit_tables(child, f);
+                }
+            }
+        }
+        Item::Value(value) => visit_value(value, f),
+        Item::None => {}
+    }
+}
+
+/// Every explicit table's position, for placing new ones
+fn positions(table: &Table, path: &mut Vec<String>, out: &mut Vec<(Vec<String>, usize)>) {
+    for (key, item) in table.iter() {
+        if let Some(child) = item.as_table() {
+            path.push(key.to_owned());
+            if let Some(position) = child.position() {
+                out.push((path.clone(), position));
+            }
+            positions(child, path, out);
+            path.pop();
+        }
+    }
+}
+
+fn shift_positions(table: &mut Table, from: usize) {
+    for (_, item) in table.iter_mut() {
+        if let Some(child) = item.as_table_mut() {
+            if let Some(position) = child.position().filter(|p| *p >= from) {
+                child.set_position(position + 1);
+            }
+            shift_positions(child, from);
+        }
+    }
+}
+
+impl Editor {
+    pub fn parse(source: &str) -> Result<Self, EditError> {
+        Ok(Self { doc: source.parse()? })
+    }
+
+    /// The edited document in canonical form. See [crate::format]
+    pub fn formatted(&self) -> Result<String, FormatError> {
+        format(&self.to_string())
+    }
+
+    fn payloads(&self, direction: Direction) -> Option<&Item> {
+        self.doc.get("payloads").and_then(|p| p.get(direction_key(direction)))
+    }
+
+    fn payloads_mut(&mut self, direction: Direction) -> Option<&mut Item> {
+        self.doc.get_mut("payloads").and_then(|p| p.get_mut(direction_key(direction)))
+    }
+
+    /// Parses a one-table TOML snippet rendered in canonical form, returning that table
+    fn canonical_table(path: &[&str], value: toml::Value) -> Result<Table, EditError> {
+        let mut root = toml::Table::new();
+        let mut table = &mut root;
+        for part in &path[..path.len() - 1] {
+            table = table.entry(part.to_string()).or_insert_with(|| toml::Table::new().into()).as_table_mut().unwrap();
+        }
+        table.insert(path[path.len() - 1].to_owned(), value);
+        let text = format_document(&toml::to_string(&root)?.parse()?);
+        let mut doc: DocumentMut = text.parse()?;
+        let mut item = doc.as_item_mut();
+        for part in path {
+            item = item.get_mut(part).ok_or_else(|| EditError::Malformed { path: path.join(".") })?;
+        }
+        std::mem::take(item).into_table().map_err(|_| EditError::Malformed { path: path.join(".") })
+    }
+
+    /// Inserts `table` at `path` as a `[section]` right after the last one under the same parent,
+    /// or after `fallback` sections when there are none
+    fn insert_section(&mut self, path: &[&str], mut table: Table, fallback: &[&[&str]]) {
+        let mut all = Vec::new();
+        positions(self.doc.as_table(), &mut Vec::new(), &mut all);
+        let parent = &path[..path.len() - 1];
+        let after = |prefix: &[&str]| all.iter().filter(|(p, _)| p.len() > prefix.len() && p.iter().zip(prefix).all(|(a, b)| a == b)).map(|(_, pos)| *pos).max();
+        let position = std::iter::once(parent).chain(fallback.iter().copied()).find_map(after).map(|p| p + 1);
+        let position = position.unwrap_or_else(|| all.iter().map(|(_, p)| p + 1).max().unwrap_or(1));
+        shift_positions(self.doc.as_table_mut(), position);
+
+        table.set_position(position);
+        table.decor_mut().set_prefix("\n");
+        let mut parent_table = self.doc.as_table_mut();
+        for part in parent {
+            let entry = parent_table.entry(part).or_insert_with(|| {
+                let mut implicit = Table::new();
+                implicit.set_implicit(true);
+                Item::Table(implicit)
+            });
+            parent_table = entry.as_table_mut().expect("parents of sections are tables");
+        }
+        parent_table.insert(path[path.len() - 1], Item::Table(table));
+    }
+
+    /// Adds a payload after the others of its direction, written in canonical form
+    pub fn add_payload(&mut self, direction: Direction, name: &str, payload: &Payload) -> Result<(), EditError> {
+        if self.payloads(direction).and_then(|p| p.get(name)).is_some() {
+            return Err(EditError::Exists { what: "payload", name: name.to_owned() });
+        }
+        let path = ["payloads", direction_key(direction), name];
+        let table = Self::canonical_table(&path, toml::Value::try_from(payload)?)?;
+        let fallback: &[&[&str]] = match direction {
+            Direction::Tx => &[&["structs"], &["uart"], &["device_info"]],
+            Direction::Rx => &[&["payloads", "tx"], &["structs"], &["uart"], &["device_info"]],
+        };
+        self.insert_section(&path, table, fallback);
+        Ok(())
+    }
+
+    /// Adds a struct after the others
+    pub fn add_struct(&mut self, name: &str, rs: &ReusableStruct) -> Result<(), EditError> {
+        if self.doc.get("structs").and_then(|s| s.get(name)).is_some() {
+            return Err(EditError::Exists { what: "struct", name: name.to_owned() });
+        }
+        let path = ["structs", name];
+        let table = Self::canonical_table(&path, toml::Value::try_from(rs)?)?;
+        self.insert_section(&path, table, &[&["uart"], &["device_info"]]);
+        Ok(())
+    }
+
+    /// Renames a payload, and the transactions' references to it
+    pub fn rename_payload(&mut self, direction: Direction, old: &str, new: &str) -> Result<(), EditError> {
+        let payloads = self.payloads_mut(direction).ok_or_else(|| EditError::NotFound { what: "payload", name: old.to_owned() })?;
+        if payloads.get(old).is_none() {
+            return Err(EditError::NotFound { what: "payload", name: old.to_owned() });
+        }
+        if payloads.get(new).is_some() {
+            return Err(EditError::Exists { what: "payload", name: new.to_owned() });
+        }
+        rename_key(payloads, old, new);
+
+        let action = match direction {
+            Direction::Tx => "Tx",
+            Direction::Rx => "Rx",
+        };
+        let Some(transactions) = self.doc.get_mut("transactions").and_then(Item::as_table_like_mut) else { return Ok(()) };
+        for (_, transaction) in transactions.iter_mut() {
+            let Some(transaction) = transaction.as_table_like_mut() else { continue };
+            if let Some(actions) = transaction.get_mut("actions") {
+                for a in rows_mut(actions) {
+                    if str_of(a, "type") == Some(action) && str_of(a, "payload") == Some(old) {
+                        set_key(a, "payload", new);
+                    }
+                }
+            }
+            if direction == Direction::Rx {
+                if let Some(Item::Value(Value::Array(returns))) = transaction.get_mut("returns") {
+                    for value in returns.iter_mut() {
+                        let Some(path) = value.as_str() else { continue };
+                        if let Some(rest) = path.strip_prefix(old).filter(|rest| rest.is_empty() || rest.starts_with('.')) {
+                            let path = format!("{new}{rest}");
+                            set_str(value, &path);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renames a struct, and every `struct_name` and `item_struct` that refers to it
+    pub fn rename_struct(&mut self, old: &str, new: &str) -> Result<(), EditError> {
+        let structs = self.doc.get_mut("structs").ok_or_else(|| EditError::NotFound { what: "struct", name: old.to_owned() })?;
+        if structs.get(old).is_none() {
+            return Err(EditError::NotFound { what: "struct", name: old.to_owned() });
+        }
+        if structs.get(new).is_some() {
+            return Err(EditError::Exists { what: "struct", name: new.to_owned() });
+        }
+        rename_key(structs, old, new);
+        if let Some(rs) = structs.get_mut(new).and_then(Item::as_table_like_mut) {
+            if str_of(rs, "name") == Some(old) {
+                set_key(rs, "name", new);
+            }
+        }
+
+        visit_tables(self.doc.as_item_mut(), &mut |table| {
+            for key in ["struct_name", "item_struct"] {
+                if str_of(table, key) == Some(old) {
+                    set_key(table, key, new);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn fields_mut(&mut self, owner: FieldOwner) -> Result<&mut Item, EditError> {
+        let (what, name, item) = match owner {
+            FieldOwner::Payload(direction, name) => ("payload", name, self.payloads_mut(direction).and_then(|p| p.get_mut(name))),
+            FieldOwner::Struct(name) => ("struct", name, self.doc.get_mut("structs").and_then(|s| s.get_mut(name))),
+        };
+        let key = if what == "struct" { "fields" } else { "segments" };
+        let item = item.ok_or_else(|| EditError::NotFound { what, name: name.to_owned() })?;
+        item.get_mut(key).ok_or_else(|| EditError::Malformed { path: format!("{name}.{key}") })
+    }
+
+    /// Renames a field of a payload or struct, along with the `CountInPacket` terminators that
+    /// count by it and the transaction `returns` that reach it
+    pub fn rename_field(&mut self, owner: FieldOwner, old: &str, new: &str) -> Result<(), EditError> {
+        // work out the new returns first, while their paths still resolve
+        let mut renamed = Vec::new();
+        for (transaction, item) in self.doc.get("transactions").and_then(Item::as_table_like).into_iter().flat_map(|t| t.iter()) {
+            let Some(returns) = item.get("returns").and_then(Item::as_array) else { continue };
+            for (i, value) in returns.iter().enumerate() {
+                if let Some(path) = value.as_str().and_then(|path| self.rename_in_path(path, owner, old, new)) {
+                    renamed.push((transaction.to_owned(), i, path));
+                }
+            }
+        }
+
+        let fields = self.fields_mut(owner)?;
+        let names = rows(fields).into_iter().filter_map(|f| str_of(f, "name")).collect::<Vec<_>>();
+        if !names.contains(&old) {
+            return Err(EditError::NotFound { what: "field", name: old.to_owned() });
+        }
+        if names.contains(&new) {
+            return Err(EditError::Exists { what: "field", name: new.to_owned() });
+        }
+        for field in rows_mut(fields) {
+            if str_of(field, "name") == Some(old) {
+                set_key(field, "name", new);
+            }
+            if let Some(termination) = field.get_mut("termination").and_then(Item::as_table_like_mut) {
+                if str_of(termination, "field_name") == Some(old) {
+                    set_key(termination, "field_name", new);
+                }
+            }
+        }
+        for (transaction, i, path) in renamed {
+            let returns = self.doc["transactions"][&transaction]["returns"].as_array_mut().expect("returns was just read as an array");
+            set_str(returns.get_mut(i).unwrap(), &path);
+        }
+        Ok(())
+    }
+
+    /// A `returns` path with the part naming `owner`'s field `old` renamed, if it has one
+    fn rename_in_path(&self, path: &str, owner: FieldOwner, old: &str, new: &str) -> Option<String> {
+        let mut parts = path.split('.').map(str::to_owned).collect::<Vec<_>>();
+        let payload = parts.first()?.clone();
+        // the struct the current part is a field of, or None while still in the payload
+        let mut struct_name: Option<String> = None;
+        let mut changed = false;
+        for part in parts.iter_mut().skip(1) {
+            let current = match &struct_name {
+                None => FieldOwner::Payload(Direction::Rx, &payload),
+                Some(name) => FieldOwner::Struct(name),
+            };
+            let fields = match current {
+                FieldOwner::Payload(direction, name) => self.payloads(direction)?.get(name)?.get("segments")?,
+                FieldOwner::Struct(name) => self.doc.get("structs")?.get(name)?.get("fields")?,
+            };
+            let field = rows(fields).into_iter().find(|f| str_of(*f, "name") == Some(part.as_str()))?;
+            let is_owner = match (current, owner) {
+                (FieldOwner::Payload(d, a), FieldOwner::Payload(e, b)) => d == e && a == b,
+                (FieldOwner::Struct(a), FieldOwner::Struct(b)) => a == b,
+                _ => false,
+            };
+            let next = str_of(field, "struct_name").map(str::to_owned);
+            if is_owner && part == old {
+                *part = new.to_owned();
+                changed = true;
+            }
+            struct_name = Some(next.unwrap_or_default());
+        }
+        changed.then(|| parts.join("."))
+    }
+}
@@ -0,0 +1,612 @@
+//! Canonical formatting of OpenPID documents.
+//!
+//! Formatting works on the TOML syntax tree rather than on a parsed [crate::OpenPID], so comments
+//! survive. Sections come in a fixed order, each payload, struct and transaction gets its own
+//! `[table]`, segment and frame format arrays put one inline table per line with their keys in a
+//! fixed order and lined up in columns, and payload metadata and `Const` bytes are written in hex.
+//! Comments that can't stay where they were, such as ones inside an `[[array of tables]]` entry
+//! that becomes an inline table, move to the line above.
+
+use std::fmt::Display;
+
+use toml_edit::{Array, Decor, DocumentMut, Item, Key, Table, TableLike, Value};
+
+#[derive(Debug)]
+pub enum FormatError {
+    /// The input isn't TOML
+    Syntax(toml_edit::TomlError),
+
+    /// The formatted document would mean something different from the input. This is a bug in the
+    /// formatter, and the input should be left as it is
+    Changed,
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatError::Syntax(e) => write!(f, "Couldn't parse the document: {e}"),
+            FormatError::Changed => write!(f, "Formatting would have changed the document's meaning, so it was left alone"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl From<toml_edit::TomlError> for FormatError {
+    fn from(value: toml_edit::TomlError) -> Self {
+        FormatError::Syntax(value)
+    }
+}
+
+/// Formats a document. Fails rather than return something that parses differently from `source`
+pub fn format(source: &str) -> Result<String, FormatError> {
+    let doc: DocumentMut = source.parse()?;
+    let formatted = format_document(&doc);
+    let before: toml::Table = toml::from_str(source).map_err(|_| FormatError::Changed)?;
+    let after: toml::Table = toml::from_str(&formatted).map_err(|_| FormatError::Changed)?;
+    if before != after {
+        return Err(FormatError::Changed);
+    }
+    Ok(formatted)
+}
+
+/// Formats an already parsed document, without checking the result
+pub(crate) fn format_document(doc: &DocumentMut) -> String {
+    let mut printer = Printer { out: String::new(), header: Vec::new(), first: first_item(doc.as_table()) };
+    printer.header = printer.first.as_ref().map(|(_, prefix)| split_header(prefix).0).unwrap_or_default();
+    for line in &printer.header.clone() {
+        printer.line(0, line);
+    }
+    if !printer.header.is_empty() {
+        printer.out.push('\n');
+    }
+    printer.section(&[], doc.as_table(), None);
+    let trailing = comments(raw(doc.trailing().as_str()), false);
+    if trailing.lines.iter().any(|l| matches!(l, Line::Comment(_))) {
+        printer.out.push('\n');
+        printer.comment_lines(0, &trim_blanks(trailing.lines));
+    }
+    printer.out
+}
+
+// Key orders. Keys not listed keep the order they were written in, after the listed ones
+const ROOT: &[&str] = &["openpid_version", "doc_version", "imports", "device_info", "uart", "spi", "i2c", "structs", "payloads", "transactions"];
+const DEVICE_INFO: &[&str] = &["name", "description"];
+const UART: &[&str] = &["tx_format", "rx_format"];
+const DIRECTIONS: &[&str] = &["tx", "rx"];
+const STRUCT: &[&str] = &["name", "description", "fields"];
+const TRANSACTION: &[&str] = &["description", "actions", "returns"];
+const SEGMENT: &[&str] = &["name", "bits", "struct_name", "type", "termination", "units", "description"];
+const TYPE: &[&str] = &["type", "signing", "endianness", "item_struct", "data"];
+const TERMINATION: &[&str] = &["count", "field_name", "sequence"];
+const FRAME_ELEMENT: &[&str] = &["type", "size_bits", "express_as", "algorithm", "data", "bits", "segment", "elements", "description"];
+const ACTION: &[&str] = &["type", "payload", "milliseconds"];
+const IMPORT: &[&str] = &["path", "package", "namespace"];
+
+/// Key order of inline tables under `key`, or of the inline tables in an array under `key`
+fn order_for(key: &str) -> &'static [&'static str] {
+    match key {
+        "segments" | "fields" | "segment" => SEGMENT,
+        "type" => TYPE,
+        "termination" => TERMINATION,
+        "tx_format" | "rx_format" | "elements" => FRAME_ELEMENT,
+        "actions" => ACTION,
+        "imports" => IMPORT,
+        _ => &[],
+    }
+}
+
+/// Arrays of bytes, written in hex
+fn is_bytes(key: &str) -> bool {
+    matches!(key, "data" | "sequence")
+}
+
+/// How a table is laid out, from where it is in the document
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    /// The root, `[payloads]`, and tables of named things such as `[structs]`. Every table in them
+    /// gets its own section
+    Container(&'static [&'static str]),
+
+    /// A section of keys in this order, with `last` at the end
+    Section { first: &'static [&'static str], last: &'static [&'static str] },
+
+    /// A payload, whose keys other than `description` and `segments` are metadata
+    Payload,
+}
+
+fn kind_of(path: &[String]) -> Kind {
+    let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+    match path.as_slice() {
+        [] => Kind::Container(ROOT),
+        ["payloads"] => Kind::Container(DIRECTIONS),
+        ["structs"] | ["transactions"] | ["payloads", "tx" | "rx"] => Kind::Container(&[]),
+        ["device_info"] => Kind::Section { first: DEVICE_INFO, last: &[] },
+        ["uart"] => Kind::Section { first: UART, last: &[] },
+        ["structs", _] => Kind::Section { first: STRUCT, last: &[] },
+        ["transactions", _] => Kind::Section { first: TRANSACTION, last: &[] },
+        ["payloads", "tx" | "rx", _] => Kind::Payload,
+        _ => Kind::Section { first: &[], last: &[] },
+    }
+}
+
+/// `keys` with `first` in that order at the start and `last` at the end
+fn ordered<'k>(keys: impl Iterator<Item = &'k str>, first: &[&str], last: &[&str]) -> Vec<&'k str> {
+    let rank = |k: &str| match (first.iter().position(|f| *f == k), last.iter().position(|l| *l == k)) {
+        (Some(i), _) => (0, i),
+        (None, Some(i)) => (2, i),
+        (None, None) => (1, 0),
+    };
+    let mut sorted = keys.collect::<Vec<_>>();
+    sorted.sort_by_key(|k| rank(k));
+    sorted
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    Blank,
+    Comment(String),
+}
+
+/// The comments in a piece of whitespace between tokens
+#[derive(Debug, Default)]
+struct Comments {
+    /// A comment on the same line as the token before
+    same_line: Option<String>,
+
+    /// Whole lines, blank or comments
+    lines: Vec<Line>,
+}
+
+fn raw(text: Option<&str>) -> &str {
+    text.unwrap_or_default()
+}
+
+/// Splits whitespace into comments. `mid_line` is whether it starts after a token on the same line,
+/// rather than at the start of a line
+fn comments(text: &str, mid_line: bool) -> Comments {
+    let mut found = Comments::default();
+    let pieces = text.split('\n').collect::<Vec<_>>();
+    for (i, piece) in pieces.iter().enumerate() {
+        let piece = piece.trim();
+        if i == 0 && mid_line {
+            if piece.starts_with('#') {
+                found.same_line = Some(piece.to_owned());
+            }
+        } else if piece.starts_with('#') {
+            found.lines.push(Line::Comment(piece.to_owned()));
+        } else if i + 1 < pieces.len() && found.lines.last() != Some(&Line::Blank) {
+            found.lines.push(Line::Blank);
+        }
+    }
+    found
+}
+
+fn trim_blanks(mut lines: Vec<Line>) -> Vec<Line> {
+    while lines.last() == Some(&Line::Blank) {
+        lines.pop();
+    }
+    while lines.first() == Some(&Line::Blank) {
+        lines.remove(0);
+    }
+    lines
+}
+
+/// Drops blank lines right before the thing the comments describe, keeping one before them
+fn above(lines: Vec<Line>, keep_leading_blank: bool) -> Vec<Line> {
+    let leading = lines.first() == Some(&Line::Blank);
+    let mut lines = trim_blanks(lines);
+    if leading && keep_leading_blank {
+        lines.insert(0, Line::Blank);
+    }
+    lines
+}
+
+/// Comments at the top of a file, up to its last blank line, describe the file rather than the
+/// first thing in it, so stay at the top when the first thing moves. Returns them and the rest
+fn split_header(prefix: &str) -> (Vec<Line>, Vec<Line>) {
+    let lines = comments(prefix, false).lines;
+    match lines.iter().rposition(|l| *l == Line::Blank) {
+        Some(i) => (trim_blanks(lines[..i].to_vec()), lines[i + 1..].to_vec()),
+        None => (Vec::new(), lines),
+    }
+}
+
+/// The path of whatever comes first in the file, and the whitespace before it
+fn first_item(root: &Table) -> Option<(Vec<String>, String)> {
+    if let Some((key, _)) = root.iter().find(|(_, item)| item.is_value()) {
+        let prefix = root.key(key).map(|k| raw(k.leaf_decor().prefix().and_then(|p| p.as_str())).to_owned());
+        return Some((vec![key.to_owned()], prefix.unwrap_or_default()));
+    }
+
+    fn walk(table: &Table, path: &mut Vec<String>, best: &mut Option<(usize, Vec<String>, String)>) {
+        for (key, item) in table.iter() {
+            let Some(child) = item.as_table() else { continue };
+            path.push(key.to_owned());
+            if let Some(position) = child.position().filter(|_| !child.is_implicit()) {
+                if best.as_ref().is_none_or(|(b, ..)| position < *b) {
+                    *best = Some((position, path.clone(), raw(child.decor().prefix().and_then(|p| p.as_str())).to_owned()));
+                }
+            }
+            walk(child, path, best);
+            path.pop();
+        }
+    }
+    let mut best = None;
+    walk(root, &mut Vec::new(), &mut best);
+    best.map(|(_, path, prefix)| (path, prefix))
+}
+
+/// An inline table as one row of an aligned array
+struct Row {
+    above: Vec<Line>,
+    cells: Vec<(String, String)>,
+    same_line: Option<String>,
+}
+
+struct Printer {
+    out: String,
+
+    /// Comments that stay at the top of the file
+    header: Vec<Line>,
+
+    /// What came first in the file, whose comments the header was taken from
+    first: Option<(Vec<String>, String)>,
+}
+
+const INDENT: &str = "    ";
+
+impl Printer {
+    fn line(&mut self, depth: usize, line: &Line) {
+        match line {
+            Line::Blank => self.out.push('\n'),
+            Line::Comment(text) => {
+                self.out.push_str(&INDENT.repeat(depth));
+                self.out.push_str(text);
+                self.out.push('\n');
+            }
+        }
+    }
+
+    fn comment_lines(&mut self, depth: usize, lines: &[Line]) {
+        for line in lines {
+            self.line(depth, line);
+        }
+    }
+
+    /// Comments before the thing at `path`, leaving out the file's header
+    fn prefix_lines(&self, path: &[String], prefix: &str) -> Vec<Line> {
+        match &self.first {
+            Some((first, _)) if first == path => split_header(prefix).1,
+            _ => comments(prefix, false).lines,
+        }
+    }
+
+    fn separate(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn section(&mut self, path: &[String], table: &dyn TableLike, decor: Option<&Decor>) {
+        let kind = kind_of(path);
+        let (order, last): (&[&str], &[&str]) = match kind {
+            Kind::Container(order) => (order, &[]),
+            Kind::Section { first, last } => (first, last),
+            Kind::Payload => (&["description"], &["segments"]),
+        };
+        let keys = ordered(table.iter().map(|(k, _)| k), order, last);
+
+        // containers turn every table into a section, sections only `[tables]`
+        let is_section = |item: &Item| match kind {
+            Kind::Container(_) => item.is_table_like(),
+            _ => item.is_table(),
+        };
+        let values = keys.iter().filter(|k| !is_section(table.get(k).unwrap())).copied().collect::<Vec<_>>();
+        let sections = keys.iter().filter(|k| is_section(table.get(k).unwrap())).copied().collect::<Vec<_>>();
+
+        let decor_comments = decor.map(|d| self.prefix_lines(path, raw(d.prefix().and_then(|p| p.as_str())))).unwrap_or_default();
+        let has_comments = decor_comments.iter().any(|l| matches!(l, Line::Comment(_)));
+        let header = !path.is_empty() && (matches!(kind, Kind::Section { .. } | Kind::Payload) || !values.is_empty() || table.is_empty() || has_comments);
+        if header {
+            self.separate();
+            self.comment_lines(0, &trim_blanks(decor_comments));
+            let name = path.iter().map(|p| Key::new(p.as_str()).display_repr().into_owned()).collect::<Vec<_>>().join(".");
+            self.out.push_str(&format!("[{name}]"));
+            if let Some(comment) = decor.and_then(|d| comments(raw(d.suffix().and_then(|s| s.as_str())), true).same_line) {
+                self.out.push_str(&format!(" {comment}"));
+            }
+            self.out.push('\n');
+        }
+
+        for (i, key) in values.iter().enumerate() {
+            let hex = kind == Kind::Payload && !matches!(*key, "description" | "segments");
+            self.key_value(path, table, key, hex, i == 0);
+        }
+
+        for key in sections {
+            let mut child = path.to_vec();
+            child.push(key.to_owned());
+            let item = table.get(key).unwrap();
+            match item {
+                Item::Table(t) => self.section(&child, t, Some(t.decor())),
+                _ => {
+                    let t = item.as_table_like().unwrap();
+                    let prefix = table.key(key).map(|k| k.leaf_decor().clone());
+                    self.section(&child, t, prefix.as_ref());
+                }
+            }
+        }
+    }
+
+    fn key_value(&mut self, path: &[String], table: &dyn TableLike, key: &str, hex: bool, first: bool) {
+        let item = table.get(key).unwrap();
+        let mut own_path = path.to_vec();
+        own_path.push(key.to_owned());
+        let prefix = table.key(key).map(|k| raw(k.leaf_decor().prefix().and_then(|p| p.as_str())).to_owned()).unwrap_or_default();
+        let mut lines = above(self.prefix_lines(&own_path, &prefix), !first);
+
+        let mut hoisted = Vec::new();
+        let (text, same_line) = match item {
+            Item::ArrayOfTables(tables) => {
+                let mut rows = tables.iter().map(|t| self.row(key, t, raw(t.decor().prefix().and_then(|p| p.as_str())), None)).collect::<Vec<_>>();
+                // entries of an array of tables are usually separated by blank lines, which mean nothing once they're rows
+                for row in &mut rows {
+                    row.above.retain(|l| *l != Line::Blank);
+                }
+                (self.rows(key, rows, &[]), None)
+            }
+            Item::Value(value) => {
+                let same_line = comments(raw(value.decor().suffix().and_then(|s| s.as_str())), true).same_line;
+                match value {
+                    Value::Array(array) if multiline(array) => (self.multiline(key, array, hex), same_line),
+                    _ => (inline(key, value, hex, &mut hoisted), same_line),
+                }
+            }
+            // tables are handled as sections, and `Item::None` never comes out of a parse
+            _ => return,
+        };
+        lines.extend(hoisted);
+        self.comment_lines(0, &lines);
+        self.out.push_str(&format!("{} = {text}", Key::new(key).display_repr()));
+        if let Some(comment) = same_line {
+            self.out.push_str(&format!(" {comment}"));
+        }
+        self.out.push('\n');
+    }
+
+    fn row(&self, key: &str, table: &dyn TableLike, prefix: &str, same_line: Option<String>) -> Row {
+        let mut above = comments(prefix, false).lines;
+        let mut cells = Vec::new();
+        for field in ordered(table.iter().map(|(k, _)| k), order_for(key), &[]) {
+            let item = table.get(field).unwrap();
+            if let Some(k) = table.key(field) {
+                above.extend(comments(raw(k.leaf_decor().prefix().and_then(|p| p.as_str())), false).lines);
+            }
+            let text = match item {
+                Item::Value(v) => inline(field, v, false, &mut above),
+                Item::Table(t) => inline_table(field, t, &mut above),
+                Item::ArrayOfTables(tables) => {
+                    let items = tables.iter().map(|t| inline_table(field, t, &mut above)).collect::<Vec<_>>();
+                    format!("[{}]", items.join(", "))
+                }
+                Item::None => continue,
+            };
+            cells.push((Key::new(field).display_repr().into_owned(), text));
+        }
+        Row { above, cells, same_line }
+    }
+
+    /// Rows of inline tables, one per line, with their keys lined up
+    fn rows(&self, key: &str, rows: Vec<Row>, closing: &[Line]) -> String {
+        let mut columns: Vec<String> = Vec::new();
+        for row in &rows {
+            for (column, _) in &row.cells {
+                if !columns.contains(column) {
+                    columns.push(column.clone());
+                }
+            }
+        }
+        let columns = ordered(columns.iter().map(String::as_str), order_for(key), &[]).into_iter().map(str::to_owned).collect::<Vec<_>>();
+        // rows line up with the other rows that have the same keys, each column as wide as its
+        // widest cell with its comma. Only the leading columns of plain values line up: nested
+        // tables vary too much in length for columns after them to help
+        let keys = |row: &Row| columns.iter().map(|c| row.cells.iter().any(|(k, _)| k == c)).collect::<Vec<_>>();
+        let layouts = rows
+            .iter()
+            .map(|row| {
+                let same = rows.iter().filter(|r| keys(r) == keys(row)).flat_map(|r| &r.cells).collect::<Vec<_>>();
+                let widths = columns
+                    .iter()
+                    .map(|c| same.iter().filter(|(k, _)| k == c).map(|(k, v)| k.len() + v.len() + 4).max().unwrap_or_default())
+                    .collect::<Vec<_>>();
+                let aligned = columns
+                    .iter()
+                    .position(|c| same.iter().any(|(k, v)| k == c && (v.starts_with('{') || v.starts_with('['))))
+                    .unwrap_or(columns.len());
+                (widths, aligned)
+            })
+            .collect::<Vec<_>>();
+
+        let mut out = String::from("[\n");
+        for (i, row) in rows.iter().enumerate() {
+            for line in above(row.above.clone(), i != 0) {
+                match line {
+                    Line::Blank => out.push('\n'),
+                    Line::Comment(text) => out.push_str(&format!("{INDENT}{text}\n")),
+                }
+            }
+            out.push_str(INDENT);
+            if row.cells.is_empty() {
+                out.push_str("{}");
+            } else {
+                let present = columns.iter().enumerate().filter_map(|(j, c)| row.cells.iter().find(|(k, _)| k == c).map(|(k, v)| (j, format!("{k} = {v}"))));
+                let present = present.collect::<Vec<_>>();
+                let (widths, aligned) = &layouts[i];
+                let mut parts = Vec::new();
+                for (i, (j, cell)) in present.iter().enumerate() {
+                    parts.push(match i + 1 == present.len() {
+                        true => cell.clone(),
+                        false if j < aligned => format!("{:<1$}", format!("{cell},"), widths[*j]),
+                        false => format!("{cell},"),
+                    });
+                }
+                out.push_str(&format!("{{ {} }}", parts.join(" ")));
+            }
+            out.push(',');
+            if let Some(comment) = &row.same_line {
+                out.push_str(&format!(" {comment}"));
+            }
+            out.push('\n');
+        }
+        for line in trim_blanks(closing.to_vec()) {
+            if let Line::Comment(text) = line {
+                out.push_str(&format!("{INDENT}{text}\n"));
+            }
+        }
+        out.push(']');
+        out
+    }
+
+    /// An array written one element per line, keeping the comments between elements
+    fn multiline(&self, key: &str, array: &Array, hex: bool) -> String {
+        let elements = array.iter().collect::<Vec<_>>();
+        let mut rows = Vec::new();
+        let mut closing = Vec::new();
+        for (i, value) in elements.iter().enumerate() {
+            let mut prefix = comments(raw(value.decor().prefix().and_then(|p| p.as_str())), true);
+            // a comment after an element's comma is parsed as the start of the next element's prefix
+            let next = match elements.get(i + 1) {
+                Some(next) => comments(raw(next.decor().prefix().and_then(|p| p.as_str())), true).same_line,
+                None => comments(raw(array.trailing().as_str()), true).same_line,
+            };
+            // without a trailing comma, comments after the last element are parsed as its suffix
+            let mut suffix = comments(raw(value.decor().suffix().and_then(|s| s.as_str())), true);
+            let mut same_line = [suffix.same_line.take(), next].into_iter().flatten().collect::<Vec<_>>();
+            match i + 1 == elements.len() {
+                true => closing.append(&mut suffix.lines),
+                false => same_line.extend(suffix.lines.into_iter().filter_map(|l| match l {
+                    Line::Comment(c) => Some(c),
+                    Line::Blank => None,
+                })),
+            }
+            let same_line = (!same_line.is_empty()).then(|| same_line.join(" "));
+
+            rows.push(match value {
+                Value::InlineTable(t) => self.row(key, t, "", same_line).with_above(std::mem::take(&mut prefix.lines)),
+                other => Row { above: std::mem::take(&mut prefix.lines), cells: Vec::new(), same_line }.with_scalar(inline(key, other, hex || is_bytes(key), &mut Vec::new())),
+            });
+        }
+        closing.extend(comments(raw(array.trailing().as_str()), true).lines);
+
+        if rows.iter().all(|r| r.scalar().is_some()) {
+            let mut out = String::from("[\n");
+            for (i, row) in rows.iter().enumerate() {
+                for line in above(row.above.clone(), i != 0) {
+                    match line {
+                        Line::Blank => out.push('\n'),
+                        Line::Comment(text) => out.push_str(&format!("{INDENT}{text}\n")),
+                    }
+                }
+                out.push_str(&format!("{INDENT}{},", row.scalar().unwrap_or_default()));
+                if let Some(comment) = &row.same_line {
+                    out.push_str(&format!(" {comment}"));
+                }
+                out.push('\n');
+            }
+            for line in trim_blanks(closing) {
+                if let Line::Comment(text) = line {
+                    out.push_str(&format!("{INDENT}{text}\n"));
+                }
+            }
+            out.push(']');
+            return out;
+        }
+        self.rows(key, rows, &closing)
+    }
+}
+
+impl Row {
+    fn with_above(mut self, mut lines: Vec<Line>) -> Self {
+        lines.append(&mut self.above);
+        self.above = lines;
+        self
+    }
+
+    /// Scalars in a multi-line array are rows with a single unnamed cell
+    fn with_scalar(mut self, text: String) -> Self {
+        self.cells = vec![(String::new(), text)];
+        self
+    }
+
+    fn scalar(&self) -> Option<&str> {
+        match self.cells.as_slice() {
+            [(key, text)] if key.is_empty() => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// Whether an array is written one element per line: arrays of tables, and arrays with comments
+fn multiline(array: &Array) -> bool {
+    let has_comment = |d: &Decor| [d.prefix(), d.suffix()].into_iter().flatten().any(|r| raw(r.as_str()).contains('#'));
+    array.iter().any(|v| v.is_inline_table() || has_comment(v.decor())) || raw(array.trailing().as_str()).contains('#')
+}
+
+fn hex(value: &Value) -> Option<String> {
+    let integer = value.as_integer().filter(|i| *i >= 0)?;
+    let mut digits = format!("{integer:X}");
+    if digits.len() % 2 == 1 {
+        digits.insert(0, '0');
+    }
+    Some(format!("0x{digits}"))
+}
+
+/// A value on one line. Comments inside it are added to `hoisted`, to go above it
+fn inline(key: &str, value: &Value, hex_integers: bool, hoisted: &mut Vec<Line>) -> String {
+    match value {
+        Value::Array(array) => {
+            let decor = array.iter().flat_map(|v| [v.decor().prefix(), v.decor().suffix()]).flatten().map(|r| raw(r.as_str()));
+            for text in decor.chain([raw(array.trailing().as_str())]) {
+                let found = comments(text, true);
+                hoisted.extend(found.same_line.map(Line::Comment));
+                hoisted.extend(found.lines.into_iter().filter(|l| matches!(l, Line::Comment(_))));
+            }
+            let items = array.iter().map(|v| inline(key, v, hex_integers || is_bytes(key), hoisted)).collect::<Vec<_>>();
+            format!("[{}]", items.join(", "))
+        }
+        Value::InlineTable(table) => inline_table(key, table, hoisted),
+        Value::Integer(_) if hex_integers || is_bytes(key) => hex(value).unwrap_or_else(|| scalar(value)),
+        _ => scalar(value),
+    }
+}
+
+fn inline_table(key: &str, table: &dyn TableLike, hoisted: &mut Vec<Line>) -> String {
+    let mut cells = Vec::new();
+    for field in ordered(table.iter().map(|(k, _)| k), order_for(key), &[]) {
+        if let Some(k) = table.key(field) {
+            hoisted.extend(comments(raw(k.leaf_decor().prefix().and_then(|p| p.as_str())), false).lines.into_iter().filter(|l| matches!(l, Line::Comment(_))));
+        }
+        let text = match table.get(field).unwrap() {
+            Item::Value(v) => inline(field, v, false, hoisted),
+            Item::Table(t) => inline_table(field, t, hoisted),
+            Item::ArrayOfTables(tables) => {
+                let items = tables.iter().map(|t| inline_table(field, t, hoisted)).collect::<Vec<_>>();
+                format!("[{}]", items.join(", "))
+            }
+            Item::None => continue,
+        };
+        cells.push(format!("{} = {text}", Key::new(field).display_repr()));
+    }
+    match cells.is_empty() {
+        true => "{}".to_owned(),
+        false => format!("{{ {} }}", cells.join(", ")),
+    }
+}
+
+/// A string, number, boolean or date as it was written
+fn scalar(value: &Value) -> String {
+    let mut value = value.clone();
+    value.decor_mut().clear();
+    value.to_string()
+}
@@ -0,0 +1,478 @@
+//! Imports Kaitai Struct `.ksy` descriptions, the reverse of the `kaitai` backend.
+//!
+//! A top level `seq` with a `switch-on` type becomes the frame format: the switched attribute is
+//! the payload, the attribute it switches on is metadata, and the attribute its `size` uses is a
+//! size element. Each case's type becomes a payload, and any other type a struct. Payload types
+//! named `tx_*` or `rx_*`, as the backend writes them, go in that direction. Without a switch, the
+//! top level `seq` is a single payload.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_yaml::Value;
+
+use super::{ImportError, Imported};
+use crate::codec::Direction;
+use crate::config::*;
+use crate::migrate::CURRENT_VERSION;
+
+/// Converts a `.ksy` document. Payloads whose type names don't say which way they go are put in
+/// `direction`
+pub fn import(source: &str, direction: Direction) -> Result<Imported, ImportError> {
+    let root: Value = serde_yaml::from_str(source).map_err(|e| ImportError::Syntax(e.to_string()))?;
+    let meta = &root["meta"];
+    let id = meta["id"].as_str().ok_or(ImportError::Missing { what: "meta/id" })?;
+    let mut importer = Importer { direction, endian: None, types: BTreeMap::new(), structs: BTreeMap::new(), unmapped: Vec::new() };
+    match meta["endian"].as_str() {
+        Some("le") => importer.endian = Some(Endianness::LittleEndian),
+        Some("be") => importer.endian = Some(Endianness::BigEndian),
+        _ if meta["endian"].is_null() => (),
+        _ => importer.note("meta/endian", "only `le` and `be` map, so integers without their own endianness are read as little-endian"),
+    }
+    if meta["bit-endian"].as_str() == Some("le") {
+        importer.note("meta/bit-endian", "OpenPID reads bit fields most significant bit first, as `bit-endian: be` does");
+    }
+    if !meta["imports"].is_null() {
+        importer.note("meta/imports", "imported files aren't read, so their types are missing");
+    }
+    importer.collect_types("types", &root["types"]);
+    importer.unsupported_type_keys("", &root);
+
+    let mut document = OpenPID {
+        device_info: DeviceInfo { name: meta["title"].as_str().unwrap_or(id).to_owned(), description: root["doc"].as_str().unwrap_or_default().to_owned() },
+        openpid_version: Some(CURRENT_VERSION.to_string()),
+        doc_version: None,
+        imports: Vec::new(),
+        uart: None,
+        spi: None,
+        i2c: None,
+        structs: BTreeMap::new(),
+        payloads: AllPayloads { tx: BTreeMap::new(), rx: BTreeMap::new() },
+        transactions: BTreeMap::new(),
+    };
+
+    // a file of frames, one after another, describes the frame in its item type
+    let mut frame = &root;
+    let mut frame_path = "seq".to_owned();
+    if let Some([attr]) = root["seq"].as_sequence().map(Vec::as_slice) {
+        if let (Some("eos"), Some(ty)) = (attr["repeat"].as_str(), attr["type"].as_str()) {
+            if let Some(item) = importer.types.get(ty) {
+                frame = item;
+                frame_path = format!("types/{ty}/seq");
+            }
+        }
+    }
+    let seq = frame["seq"].as_sequence().map(Vec::as_slice).unwrap_or_default();
+    let mut payload_types = BTreeSet::new();
+    match seq.iter().position(|attr| attr["type"].is_mapping()) {
+        Some(switch) => {
+            let format = importer.frame(&frame_path, seq, switch, &mut document.payloads, &mut payload_types);
+            document.uart = Some(UARTConfig { tx_format: format.clone(), rx_format: format });
+            if document.payloads.tx.is_empty() != document.payloads.rx.is_empty() {
+                importer.note(&frame_path, "the frame format is used for both directions, since a .ksy file only describes one");
+            }
+        }
+        None => {
+            let segments = importer.seq(&frame_path, seq);
+            let payload = Payload { segments, metadata: BTreeMap::new(), description: root["doc"].as_str().unwrap_or_default().to_owned() };
+            match direction {
+                Direction::Tx => document.payloads.tx.insert(id.to_owned(), payload),
+                Direction::Rx => document.payloads.rx.insert(id.to_owned(), payload),
+            };
+        }
+    }
+
+    // types nothing referred to still come across, as structs
+    let names: Vec<String> = importer.types.keys().cloned().collect();
+    for name in names {
+        if !payload_types.contains(&name) && !std::ptr::eq(importer.types[&name], frame) {
+            importer.use_struct(&name);
+        }
+    }
+    document.structs = importer.structs;
+    Ok(Imported { document, unmapped: importer.unmapped })
+}
+
+struct Importer<'a> {
+    direction: Direction,
+
+    /// `meta/endian`, for integer types without `le` or `be`
+    endian: Option<Endianness>,
+
+    /// Every user type by name, including nested ones
+    types: BTreeMap<String, &'a Value>,
+    structs: BTreeMap<String, ReusableStruct>,
+    unmapped: Vec<String>,
+}
+
+/// A Kaitai built in type
+enum Primitive {
+    Int { bits: u32, signing: Signing, endianness: Option<Endianness> },
+    Float { bits: u32, endianness: Option<Endianness> },
+    Str { zero_terminated: bool },
+}
+
+impl Primitive {
+    fn parse(ty: &str) -> Option<Self> {
+        let (base, endianness) = match (ty.strip_suffix("le"), ty.strip_suffix("be")) {
+            (Some(base), _) => (base, Some(Endianness::LittleEndian)),
+            (_, Some(base)) => (base, Some(Endianness::BigEndian)),
+            _ => (ty, None),
+        };
+        let number = |digits: &str| digits.parse::<u32>().ok();
+        match (base.split_at_checked(1), endianness) {
+            _ if ty == "str" || ty == "strz" => Some(Primitive::Str { zero_terminated: ty == "strz" }),
+            (Some(("u", n)), _) if matches!(number(n), Some(1 | 2 | 4 | 8)) => Some(Primitive::Int { bits: number(n)? * 8, signing: Signing::Unsigned, endianness }),
+            (Some(("s", n)), _) if matches!(number(n), Some(1 | 2 | 4 | 8)) => Some(Primitive::Int { bits: number(n)? * 8, signing: Signing::TwosComplement, endianness }),
+            (Some(("f", n)), _) if matches!(number(n), Some(4 | 8)) => Some(Primitive::Float { bits: number(n)? * 8, endianness }),
+            (Some(("b", n)), None) if matches!(number(n), Some(1..=64)) => Some(Primitive::Int { bits: number(n)?, signing: Signing::Unsigned, endianness: Some(Endianness::BigEndian) }),
+            _ => None,
+        }
+    }
+}
+
+/// How a `size`, `repeat-expr` or similar expression maps
+enum Expression {
+    Literal(u32),
+    Field(String),
+
+    /// `field - n`
+    FieldMinus(String, u32),
+    Other,
+}
+
+impl Expression {
+    fn parse(value: &Value) -> Self {
+        if let Some(n) = value.as_u64() {
+            return Expression::Literal(n as u32);
+        }
+        let Some(text) = value.as_str() else { return Expression::Other };
+        let is_field = |s: &str| s.starts_with(|c: char| c.is_ascii_lowercase()) && s.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        match text.split_once('-').map(|(a, b)| (a.trim(), b.trim())) {
+            Some((field, n)) if is_field(field) && n.parse::<u32>().is_ok() => Expression::FieldMinus(field.to_owned(), n.parse().unwrap_or_default()),
+            None if is_field(text.trim()) => Expression::Field(text.trim().to_owned()),
+            _ => Expression::Other,
+        }
+    }
+}
+
+/// Bytes from `contents`, which is a string or a list of numbers and strings
+fn contents(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::String(s) => Some(s.as_bytes().to_vec()),
+        Value::Sequence(items) => {
+            let mut out = Vec::new();
+            for item in items {
+                match item {
+                    Value::Number(n) => out.push(u8::try_from(n.as_u64()?).ok()?),
+                    Value::String(s) => out.extend(s.as_bytes()),
+                    _ => return None,
+                }
+            }
+            Some(out)
+        }
+        _ => None,
+    }
+}
+
+impl<'a> Importer<'a> {
+    fn note(&mut self, path: &str, what: &str) {
+        self.unmapped.push(format!("{path}: {what}"));
+    }
+
+    fn collect_types(&mut self, path: &str, types: &'a Value) {
+        let Some(types) = types.as_mapping() else { return };
+        for (name, ty) in types {
+            let Some(name) = name.as_str() else { continue };
+            if self.types.insert(name.to_owned(), ty).is_some() {
+                self.note(&format!("{path}/{name}"), "nested types share one namespace, so this one replaced another of the same name");
+            }
+            self.collect_types(&format!("{path}/{name}/types"), &ty["types"]);
+        }
+    }
+
+    /// Notes the keys of a type that have no equivalent
+    fn unsupported_type_keys(&mut self, path: &str, ty: &Value) {
+        for (key, what) in [
+            ("instances", "instances are calculated or read out of order, which OpenPID can't describe, so they're left out"),
+            ("params", "parameterized types have no equivalent; parameters are left out"),
+            ("enums", "enum names are left out, and their values kept as plain integers"),
+        ] {
+            if !ty[key].is_null() {
+                self.note(&format!("{path}{key}"), what);
+            }
+        }
+    }
+
+    /// Converts a user type into a struct the first time it's used, returning whether it exists
+    fn use_struct(&mut self, name: &str) -> bool {
+        if self.structs.contains_key(name) {
+            return true;
+        }
+        let Some(ty) = self.types.get(name).copied() else { return false };
+        // a placeholder stops types that contain themselves from recursing forever
+        self.structs.insert(name.to_owned(), ReusableStruct { name: name.to_owned(), fields: Vec::new(), description: None });
+        let path = format!("types/{name}/");
+        self.unsupported_type_keys(&path, ty);
+        let fields = self.seq(&format!("{path}seq"), ty["seq"].as_sequence().map(Vec::as_slice).unwrap_or_default());
+        let description = ty["doc"].as_str().map(str::to_owned);
+        self.structs.insert(name.to_owned(), ReusableStruct { name: name.to_owned(), fields, description });
+        true
+    }
+
+    fn seq(&mut self, path: &str, attrs: &[Value]) -> Vec<PacketSegment> {
+        let mut out = Vec::new();
+        for attr in attrs {
+            let Some(id) = attr["id"].as_str() else {
+                self.note(path, "attributes without an `id` are left out");
+                continue;
+            };
+            if let Some(segment) = self.attribute(&format!("{path}/{id}"), id, attr, &out) {
+                out.push(segment);
+            }
+        }
+        out
+    }
+
+    /// Converts one attribute. `earlier` is the attributes before it, which sizes and counts can refer to
+    fn attribute(&mut self, path: &str, id: &str, attr: &Value, earlier: &[PacketSegment]) -> Option<PacketSegment> {
+        for (key, what) in [
+            ("if", "conditional attributes have no equivalent, so this one is always read"),
+            ("process", "processed data (compressed, XORed...) is read as it is on the wire"),
+            ("valid", "the `valid` check is left out"),
+            ("enum", "the enum name is left out, keeping the plain integer"),
+        ] {
+            if !attr[key].is_null() {
+                self.note(path, what);
+            }
+        }
+        if attr["consume"].as_bool() == Some(false) || attr["include"].as_bool() == Some(true) {
+            self.note(path, "terminators are always consumed and left out of the data");
+        }
+        let single = self.single(path, id, attr, earlier)?;
+        let Some(repeat) = attr["repeat"].as_str() else { return Some(single) };
+
+        // arrays are always of structs, so anything else is wrapped in a struct of its own
+        let item_struct = match single {
+            PacketSegment::Struct { struct_name, .. } => struct_name,
+            other => {
+                let name = format!("{id}_item");
+                let field = match other {
+                    PacketSegment::Sized { bits, datatype, description, units, .. } => PacketSegment::Sized { name: "value".to_owned(), bits, datatype, description, units },
+                    PacketSegment::Unsized { datatype, termination, description, .. } => PacketSegment::Unsized { name: "value".to_owned(), datatype, termination, description },
+                    PacketSegment::Struct { .. } => unreachable!(),
+                };
+                self.structs.insert(name.clone(), ReusableStruct { name: name.clone(), fields: vec![field], description: None });
+                name
+            }
+        };
+        let termination = match (repeat, Expression::parse(&attr["repeat-expr"])) {
+            ("expr", Expression::Literal(count)) => Some(Terminator::CountFixed { count }),
+            ("expr", Expression::Field(field_name)) if earlier.iter().any(|s| s.get_name() == field_name) => Some(Terminator::CountInPacket { field_name }),
+            ("eos", _) => None,
+            _ => {
+                self.note(path, "only repeat counts that are numbers or earlier fields map, so this repeats to the end of the data");
+                None
+            }
+        };
+        Some(PacketSegment::Unsized { name: id.to_owned(), datatype: UnsizedDataType::Array { item_struct }, termination, description: attr["doc"].as_str().map(str::to_owned) })
+    }
+
+    /// Converts an attribute, ignoring `repeat`
+    fn single(&mut self, path: &str, id: &str, attr: &Value, earlier: &[PacketSegment]) -> Option<PacketSegment> {
+        let name = id.to_owned();
+        let description = attr["doc"].as_str().map(str::to_owned);
+        if !attr["contents"].is_null() {
+            let Some(data) = contents(&attr["contents"]) else {
+                self.note(path, "`contents` should be a string or a list of bytes, so it's left out");
+                return None;
+            };
+            return Some(PacketSegment::Sized { name, bits: data.len() as u32 * 8, datatype: SizedDataType::Const { data }, description, units: None });
+        }
+        let ty = match &attr["type"] {
+            Value::Null => None,
+            Value::String(ty) => Some(ty.as_str()),
+            _ => {
+                self.note(path, "switched types only map at the top of a frame, so this is read as bytes");
+                None
+            }
+        };
+        match ty.map(|ty| (ty, Primitive::parse(ty))) {
+            Some((_, Some(Primitive::Int { bits, signing, endianness }))) => {
+                let endianness = self.endianness(path, bits, endianness);
+                Some(PacketSegment::Sized { name, bits, datatype: SizedDataType::Integer { endianness, signing }, description, units: None })
+            }
+            Some((_, Some(Primitive::Float { bits, endianness }))) => {
+                let endianness = self.endianness(path, bits, endianness);
+                Some(PacketSegment::Sized { name, bits, datatype: SizedDataType::FloatIEEE { endianness }, description, units: None })
+            }
+            Some((_, Some(Primitive::Str { zero_terminated }))) => {
+                if let Some(encoding) = attr["encoding"].as_str() {
+                    if !matches!(encoding.to_ascii_uppercase().as_str(), "UTF-8" | "UTF8" | "ASCII") {
+                        self.note(path, &format!("{encoding} strings are read as UTF-8"));
+                    }
+                }
+                let terminator = if zero_terminated { Some(0) } else { attr["terminator"].as_u64().map(|t| t as u8) };
+                Some(self.bytes(path, name, attr, earlier, terminator, SizedDataType::StringUTF8, UnsizedDataType::StringUTF8, description))
+            }
+            Some((ty, None)) => {
+                let struct_name = ty.rsplit("::").next().unwrap_or(ty);
+                if !ty.contains('(') && self.use_struct(struct_name) {
+                    return Some(PacketSegment::Struct { name, struct_name: struct_name.to_owned() });
+                }
+                self.note(path, &format!("type `{ty}` isn't defined in this file, so it's read as bytes"));
+                let terminator = attr["terminator"].as_u64().map(|t| t as u8);
+                Some(self.bytes(path, name, attr, earlier, terminator, SizedDataType::Raw, UnsizedDataType::Raw, description))
+            }
+            None => {
+                let terminator = attr["terminator"].as_u64().map(|t| t as u8);
+                Some(self.bytes(path, name, attr, earlier, terminator, SizedDataType::Raw, UnsizedDataType::Raw, description))
+            }
+        }
+    }
+
+    fn endianness(&mut self, path: &str, bits: u32, endianness: Option<Endianness>) -> Endianness {
+        match (endianness.or(self.endian), bits) {
+            (Some(endianness), _) => endianness,
+            (None, 0..=8) => Endianness::default(),
+            (None, _) => {
+                self.note(path, "no endianness is given, so this is read as little-endian");
+                Endianness::LittleEndian
+            }
+        }
+    }
+
+    /// A string or raw bytes, sized by `size`, `size-eos` or a terminator
+    #[allow(clippy::too_many_arguments)]
+    fn bytes(&mut self, path: &str, name: String, attr: &Value, earlier: &[PacketSegment], terminator: Option<u8>, sized: SizedDataType, unsized_type: UnsizedDataType, description: Option<String>) -> PacketSegment {
+        let unsized_segment = |termination| PacketSegment::Unsized { name: name.clone(), datatype: unsized_type.clone(), termination, description: description.clone() };
+        if !attr["size"].is_null() {
+            return match Expression::parse(&attr["size"]) {
+                Expression::Literal(bytes) => PacketSegment::Sized { name: name.clone(), bits: bytes * 8, datatype: sized, description: description.clone(), units: None },
+                Expression::Field(field_name) if earlier.iter().any(|s| s.get_name() == field_name) => unsized_segment(Some(Terminator::CountInPacket { field_name })),
+                _ => {
+                    self.note(path, "only sizes that are numbers or earlier fields map, so this runs to the end of the data");
+                    unsized_segment(None)
+                }
+            };
+        }
+        if let Some(terminator) = terminator {
+            return unsized_segment(Some(Terminator::Sequence { sequence: vec![terminator] }));
+        }
+        if attr["size-eos"].as_bool() != Some(true) {
+            self.note(path, "has no size, so it runs to the end of the data");
+        }
+        unsized_segment(None)
+    }
+
+    /// Bits an attribute always takes, if it's a fixed size
+    fn attr_bits(attr: &Value) -> Option<u32> {
+        if let Some(data) = contents(&attr["contents"]) {
+            return Some(data.len() as u32 * 8);
+        }
+        match attr["type"].as_str().and_then(Primitive::parse) {
+            Some(Primitive::Int { bits, .. } | Primitive::Float { bits, .. }) => Some(bits),
+            _ => attr["size"].as_u64().map(|bytes| bytes as u32 * 8),
+        }
+    }
+
+    /// Builds the frame format from the attributes around the switched one at `switch`, adding a
+    /// payload for each case
+    fn frame(&mut self, path: &str, seq: &[Value], switch: usize, payloads: &mut AllPayloads, payload_types: &mut BTreeSet<String>) -> Vec<PacketFormatElement> {
+        let payload = &seq[switch];
+        let payload_path = format!("{path}/{}", payload["id"].as_str().unwrap_or("payload"));
+        let switch_on = payload["type"]["switch-on"].as_str().map(str::trim).unwrap_or_default().to_owned();
+        let is_field = |id: &str| seq.iter().any(|attr| attr["id"].as_str() == Some(id));
+        if !is_field(&switch_on) {
+            self.note(&payload_path, "only switching on a single attribute of the frame maps, so payloads have no metadata to tell them apart");
+        }
+
+        // the payload's size is either an attribute of its own, or the whole frame's size less the envelope
+        let envelope_bits: Option<u32> = seq.iter().enumerate().filter(|(i, _)| *i != switch).map(|(_, attr)| Self::attr_bits(attr)).sum();
+        let (size_field, total) = match Expression::parse(&payload["size"]) {
+            Expression::Field(field) if is_field(&field) => (Some(field), false),
+            Expression::FieldMinus(field, n) if is_field(&field) && envelope_bits == Some(n * 8) => (Some(field), true),
+            _ if payload["size"].is_null() || payload["size"].as_str().is_some_and(|s| s.contains("_io.size")) => (None, false),
+            _ => {
+                self.note(&payload_path, "the payload's size should be an attribute, or the frame's size less the rest of the frame, so it's found from the payload instead");
+                (None, false)
+            }
+        };
+
+        let mut format = Vec::new();
+        for (i, attr) in seq.iter().enumerate() {
+            let id = attr["id"].as_str().unwrap_or_default();
+            let attr_path = format!("{path}/{id}");
+            let description = attr["doc"].as_str().map(str::to_owned);
+            let int_bits = attr["type"].as_str().and_then(Primitive::parse).and_then(|p| match p {
+                Primitive::Int { bits, .. } => Some(bits),
+                _ => None,
+            });
+            if i == switch {
+                format.push(PacketFormatElement::Payload);
+            } else if let Some(data) = contents(&attr["contents"]) {
+                format.push(PacketFormatElement::Const { bits: None, data, description });
+            } else if id == switch_on {
+                if let Some(segment) = self.attribute(&attr_path, id, attr, &[]) {
+                    format.push(PacketFormatElement::Metadata { segment, description });
+                }
+            } else if let (true, Some(size_bits)) = (size_field.as_deref() == Some(id), int_bits) {
+                format.push(match total {
+                    true => PacketFormatElement::SizeTotal { size_bits, express_as: BitsOrBytes::Bytes },
+                    false => PacketFormatElement::SizeOfPayload { size_bits, express_as: BitsOrBytes::Bytes },
+                });
+            } else if let (true, Some(bits @ (16 | 32))) = (id.contains("crc") || id.contains("checksum"), int_bits) {
+                // the kaitai backend names the algorithm in the doc; anything else is a guess from the width
+                let doc = description.as_deref().unwrap_or_default();
+                let algorithm = if bits == 16 { Crc::Crc16XModem } else { Crc::Crc32 };
+                let named = if bits == 16 { "CRC-16/XMODEM" } else { "CRC-32" };
+                if !doc.contains(named) {
+                    self.note(&attr_path, &format!("taken to be a {named} from its name and size; check the algorithm"));
+                }
+                format.push(PacketFormatElement::Crc { algorithm });
+            } else {
+                self.note(&attr_path, "frames can only hold sizes, metadata, constants and CRCs besides the payload, so this is left out");
+            }
+        }
+
+        let cases = payload["type"]["cases"].as_mapping().cloned().unwrap_or_default();
+        for (key, target) in &cases {
+            let Some(target) = target.as_str() else { continue };
+            let value = match key {
+                Value::Number(n) if n.as_i64().is_some() => Some(LiteralValue::Int(n.as_i64().unwrap_or_default())),
+                Value::String(s) if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') => Some(LiteralValue::String(s[1..s.len() - 1].to_owned())),
+                _ => {
+                    self.note(&format!("{payload_path}/cases/{}", serde_yaml::to_string(key).unwrap_or_default().trim()), "only numbers and strings map to metadata values, so this case is left out");
+                    None
+                }
+            };
+            let (direction, name) = match (target.strip_prefix("tx_"), target.strip_prefix("rx_")) {
+                (Some(name), _) => (Direction::Tx, name),
+                (_, Some(name)) => (Direction::Rx, name),
+                _ => (self.direction, target),
+            };
+            let payloads = match direction {
+                Direction::Tx => &mut payloads.tx,
+                Direction::Rx => &mut payloads.rx,
+            };
+            if !payloads.contains_key(name) {
+                let Some(ty) = self.types.get(target).copied() else {
+                    self.note(&payload_path, &format!("case type `{target}` isn't defined in this file, so it's left out"));
+                    continue;
+                };
+                payload_types.insert(target.to_owned());
+                let type_path = format!("types/{target}/");
+                self.unsupported_type_keys(&type_path, ty);
+                let segments = self.seq(&format!("{type_path}seq"), ty["seq"].as_sequence().map(Vec::as_slice).unwrap_or_default());
+                let description = ty["doc"].as_str().unwrap_or_default().to_owned();
+                payloads.insert(name.to_owned(), Payload { segments, metadata: BTreeMap::new(), description });
+            }
+            if let (Some(value), Some(payload)) = (value, payloads.get_mut(name)) {
+                let values = payload.metadata.remove(&switch_on).map(OneOrMany::as_many).unwrap_or_default();
+                let values = [values, vec![value]].concat();
+                let values = if values.len() == 1 { OneOrMany::One(values[0].clone()) } else { OneOrMany::Many(values) };
+                payload.metadata.insert(switch_on.clone(), values);
+            }
+        }
+        format
+    }
+}
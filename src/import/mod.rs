@@ -0,0 +1,63 @@
+//! Converts descriptions written for other tools into OpenPID documents. Importers are best effort:
+//! they map what they can and list everything else in [Imported::unmapped], so the result is a
+//! starting point to review rather than a finished document
+
+pub mod kaitai;
+
+use std::fmt::Display;
+
+use crate::config::OpenPID;
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// The input isn't valid in its own format
+    Syntax(String),
+
+    /// The input is missing something every OpenPID document needs, such as a name
+    Missing { what: &'static str },
+
+    Toml(toml::ser::Error),
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Syntax(e) => write!(f, "Couldn't parse the input: {e}"),
+            ImportError::Missing { what } => write!(f, "The input has no {what}"),
+            ImportError::Toml(e) => write!(f, "Couldn't write the document as TOML: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<toml::ser::Error> for ImportError {
+    fn from(value: toml::ser::Error) -> Self {
+        ImportError::Toml(value)
+    }
+}
+
+/// A converted document, and what couldn't be converted
+#[derive(Debug)]
+pub struct Imported {
+    pub document: OpenPID,
+
+    /// Each construct with no OpenPID equivalent, prefixed with where it was found
+    pub unmapped: Vec<String>,
+}
+
+impl Imported {
+    /// The document as TOML, with the unmapped constructs listed in a comment at the top
+    pub fn to_toml(&self) -> Result<String, ImportError> {
+        let mut out = String::new();
+        if !self.unmapped.is_empty() {
+            out.push_str("# Imported with these differences from the original:\n");
+            for note in &self.unmapped {
+                out.push_str(&format!("#   {note}\n"));
+            }
+            out.push('\n');
+        }
+        out.push_str(&toml::to_string_pretty(&self.document)?);
+        Ok(out)
+    }
+}
@@ -0,0 +1,249 @@
+//! Multi-file documents. A document's `imports` name other files, by path or package, whose
+//! structs, payloads, transactions and frame format are merged into it before it's validated or
+//! used, so a product line can share one envelope and one set of structs:
+//!
+//! ```toml
+//! imports = [
+//!     { path = "../common/envelope.toml" },
+//!     { package = "imu_structs", namespace = "imu" },
+//! ]
+//! ```
+//!
+//! Imported files are [Library]s: any part of a document, with imports of their own. A namespace
+//! prefixes everything the import defines, as `imu_vec3`, along with the references to it inside
+//! the imported file. The same definition arriving twice, such as a library imported by two
+//! others, is merged once; two different definitions with the same name are an error, as is a
+//! file that imports itself
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::config::{Action, AllPayloads, Import, ImportSource, Library, PacketSegment, ReusableStruct, Transaction, UARTConfig, UnsizedDataType};
+use crate::migrate::{self, MigrateError};
+use crate::OpenPID;
+
+/// Where packages are looked for next to the root document
+pub const PACKAGES_DIR: &str = "openpid_packages";
+
+#[derive(Debug)]
+pub enum IncludeError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, error: Box<MigrateError> },
+
+    /// No package by this name in any of the directories searched
+    NoPackage { name: String, searched: Vec<PathBuf> },
+
+    /// A file that imports itself, directly or through others
+    Cycle { path: PathBuf },
+
+    /// An imported definition with the same name as a different one already in the document
+    Conflict { kind: &'static str, name: String, path: PathBuf },
+}
+
+impl Display for IncludeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IncludeError::Io { path, error } => write!(f, "Couldn't read {}: {error}", path.display()),
+            IncludeError::Parse { path, error } => write!(f, "{} isn't a valid OpenPID document: {error}", path.display()),
+            IncludeError::NoPackage { name, searched } => {
+                let searched = searched.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+                write!(f, "Couldn't find package \"{name}\" in {}", if searched.is_empty() { "any directory".to_owned() } else { searched.join(", ") })
+            }
+            IncludeError::Cycle { path } => write!(f, "{} imports itself", path.display()),
+            IncludeError::Conflict { kind, name, path } => {
+                write!(f, "{} defines {kind} \"{name}\" differently from the document importing it. Give the import a namespace", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for IncludeError {}
+
+/// Finds the file an import refers to
+pub trait Resolver {
+    /// `from` is the file doing the importing
+    fn locate(&self, source: &ImportSource, from: &Path) -> Result<PathBuf, IncludeError>;
+}
+
+/// Resolves paths relative to the importing file, and packages to `<dir>/<name>/openpid.toml`
+/// in the first of `dirs` that has one
+#[derive(Debug, Clone, Default)]
+pub struct Packages {
+    pub dirs: Vec<PathBuf>,
+}
+
+impl Packages {
+    /// [PACKAGES_DIR] next to `root`, then the directories in the `OPENPID_PATH` environment
+    /// variable
+    pub fn for_document(root: &Path) -> Self {
+        let mut dirs = vec![root.parent().unwrap_or(Path::new("")).join(PACKAGES_DIR)];
+        if let Some(paths) = std::env::var_os("OPENPID_PATH") {
+            dirs.extend(std::env::split_paths(&paths));
+        }
+        Packages { dirs }
+    }
+}
+
+impl Resolver for Packages {
+    fn locate(&self, source: &ImportSource, from: &Path) -> Result<PathBuf, IncludeError> {
+        match source {
+            ImportSource::Path(path) => Ok(from.parent().unwrap_or(Path::new("")).join(path)),
+            ImportSource::Package(name) => {
+                let found = self.dirs.iter().map(|dir| dir.join(name).join("openpid.toml")).find(|file| file.is_file());
+                found.ok_or_else(|| IncludeError::NoPackage { name: name.clone(), searched: self.dirs.clone() })
+            }
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<String, IncludeError> {
+    std::fs::read_to_string(path).map_err(|error| IncludeError::Io { path: path.to_owned(), error })
+}
+
+/// Reads the document at `path` and everything it imports, finding packages with [Packages::for_document]
+pub fn load(path: &Path) -> Result<OpenPID, IncludeError> {
+    load_with(path, &Packages::for_document(path))
+}
+
+pub fn load_with(path: &Path, resolver: &dyn Resolver) -> Result<OpenPID, IncludeError> {
+    let doc = migrate::parse(&read(path)?).map_err(|error| IncludeError::Parse { path: path.to_owned(), error: Box::new(error) })?;
+    resolve(doc, path, resolver)
+}
+
+/// Merges everything `doc` imports into it. `path` is where `doc` was read from, which relative
+/// imports start from
+pub fn resolve(mut doc: OpenPID, path: &Path, resolver: &dyn Resolver) -> Result<OpenPID, IncludeError> {
+    let mut stack = vec![path.canonicalize().unwrap_or_else(|_| path.to_owned())];
+    for import in std::mem::take(&mut doc.imports) {
+        let (file, library) = library(&import, path, resolver, &mut stack)?;
+        merge(&mut Parts { uart: &mut doc.uart, structs: &mut doc.structs, payloads: &mut doc.payloads, transactions: &mut doc.transactions }, library, &file)?;
+    }
+    Ok(doc)
+}
+
+/// Reads an import and everything it imports in turn, then applies its namespace
+fn library(import: &Import, from: &Path, resolver: &dyn Resolver, stack: &mut Vec<PathBuf>) -> Result<(PathBuf, Library), IncludeError> {
+    let file = resolver.locate(&import.source, from)?;
+    let canonical = file.canonicalize().map_err(|error| IncludeError::Io { path: file.clone(), error })?;
+    if stack.contains(&canonical) {
+        return Err(IncludeError::Cycle { path: file });
+    }
+    stack.push(canonical);
+    let mut library: Library = migrate::parse(&read(&file)?).map_err(|error| IncludeError::Parse { path: file.clone(), error: Box::new(error) })?;
+    for inner in std::mem::take(&mut library.imports) {
+        let (inner_file, inner) = self::library(&inner, &file, resolver, stack)?;
+        let parts = &mut Parts { uart: &mut library.uart, structs: &mut library.structs, payloads: &mut library.payloads, transactions: &mut library.transactions };
+        merge(parts, inner, &inner_file)?;
+    }
+    stack.pop();
+
+    if let Some(namespace) = &import.namespace {
+        apply_namespace(&mut library, namespace);
+    }
+    Ok((file, library))
+}
+
+/// The parts of a document or library that imports are merged into
+struct Parts<'a> {
+    uart: &'a mut Option<UARTConfig>,
+    structs: &'a mut BTreeMap<String, ReusableStruct>,
+    payloads: &'a mut AllPayloads,
+    transactions: &'a mut BTreeMap<String, Transaction>,
+}
+
+/// Definitions are compared by what they serialize to, so that a library imported twice
+/// doesn't conflict with itself
+fn same(a: &impl Serialize, b: &impl Serialize) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+fn merge_map<T: Serialize>(into: &mut BTreeMap<String, T>, from: BTreeMap<String, T>, kind: &'static str, path: &Path) -> Result<(), IncludeError> {
+    for (name, item) in from {
+        match into.get(&name) {
+            Some(existing) if same(existing, &item) => {}
+            Some(_) => return Err(IncludeError::Conflict { kind, name, path: path.to_owned() }),
+            None => {
+                into.insert(name, item);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn merge(into: &mut Parts, library: Library, path: &Path) -> Result<(), IncludeError> {
+    match (&*into.uart, library.uart) {
+        (Some(existing), Some(uart)) if !same(existing, &uart) => {
+            return Err(IncludeError::Conflict { kind: "the UART frame format", name: "uart".to_owned(), path: path.to_owned() });
+        }
+        (None, uart) => *into.uart = uart,
+        _ => {}
+    }
+    merge_map(into.structs, library.structs, "struct", path)?;
+    merge_map(&mut into.payloads.tx, library.payloads.tx, "TX payload", path)?;
+    merge_map(&mut into.payloads.rx, library.payloads.rx, "RX payload", path)?;
+    merge_map(into.transactions, library.transactions, "transaction", path)
+}
+
+/// Prefixes every name `library` defines, and its references to them
+fn apply_namespace(library: &mut Library, namespace: &str) {
+    let prefixed = |name: &str| format!("{namespace}_{name}");
+    let structs = library.structs.keys().cloned().collect::<BTreeSet<_>>();
+    let tx = library.payloads.tx.keys().cloned().collect::<BTreeSet<_>>();
+    let rx = library.payloads.rx.keys().cloned().collect::<BTreeSet<_>>();
+
+    let rename_segments = |segments: &mut Vec<PacketSegment>| {
+        for segment in segments {
+            match segment {
+                PacketSegment::Struct { struct_name: name, .. } | PacketSegment::Unsized { datatype: UnsizedDataType::Array { item_struct: name }, .. }
+                    if structs.contains(name) =>
+                {
+                    *name = prefixed(name);
+                }
+                _ => {}
+            }
+        }
+    };
+
+    library.structs = std::mem::take(&mut library.structs)
+        .into_iter()
+        .map(|(name, mut rs)| {
+            rs.name = prefixed(&rs.name);
+            rename_segments(&mut rs.fields);
+            (prefixed(&name), rs)
+        })
+        .collect();
+    for payloads in [&mut library.payloads.tx, &mut library.payloads.rx] {
+        *payloads = std::mem::take(payloads)
+            .into_iter()
+            .map(|(name, mut payload)| {
+                rename_segments(&mut payload.segments);
+                (prefixed(&name), payload)
+            })
+            .collect();
+    }
+    library.transactions = std::mem::take(&mut library.transactions)
+        .into_iter()
+        .map(|(name, mut transaction)| {
+            for action in &mut transaction.actions {
+                match action {
+                    Action::Tx { payload } if tx.contains(payload) => *payload = prefixed(payload),
+                    Action::Rx { payload } if rx.contains(payload) => *payload = prefixed(payload),
+                    _ => {}
+                }
+            }
+            for value in &mut transaction.returns {
+                let (payload, rest) = value.split_once('.').unwrap_or((value, ""));
+                if rx.contains(payload) {
+                    *value = match rest {
+                        "" => prefixed(payload),
+                        rest => format!("{}.{rest}", prefixed(payload)),
+                    };
+                }
+            }
+            (prefixed(&name), transaction)
+        })
+        .collect();
+}
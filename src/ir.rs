@@ -0,0 +1,903 @@
+//! A resolved, validated view of an OpenPID document, which every code generator works from.
+//!
+//! Building the IR checks the whole document and reports every problem at once. Once built,
+//! backends can rely on it: struct references point at structs that exist, `CountInPacket` fields
+//! are integers that come earlier in the same struct or payload, every payload has a value for
+//! each of its frame format's `Metadata` elements, transactions only reference payloads that exist
+//! and names are split into words so each backend can case them idiomatically
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use convert_case::{Boundary, Case, Converter};
+
+pub use crate::codec::Direction;
+use crate::codegen::CodegenError;
+use crate::config::{self, OpenPID, PacketFormatElement, PacketSegment, SizedDataType, Terminator, UnsizedDataType};
+pub use crate::config::{BitsOrBytes, Crc, Endianness, LiteralValue, Signing};
+pub use crate::size::Size;
+
+/// A name from the document, split into lowercase words. `GetModInfo`, `get_mod_info` and
+/// `get-mod-info` all have the words `get`, `mod` and `info`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Name {
+    raw: String,
+    words: Vec<String>,
+}
+
+impl Name {
+    pub fn new(raw: &str) -> Self {
+        let snake = Self::snake_case().convert(raw);
+        let words = snake.split('_').filter(|w| !w.is_empty()).map(str::to_owned).collect();
+        Self { raw: raw.to_owned(), words }
+    }
+
+    /// Converts names to snake case. Digits stay attached to the word they're in, so `vec3`
+    /// doesn't become `vec_3`
+    pub(crate) fn snake_case() -> Converter {
+        Converter::new()
+            .set_boundaries(&[Boundary::Underscore, Boundary::Hyphen, Boundary::Space, Boundary::LowerUpper, Boundary::Acronym, Boundary::DigitUpper])
+            .to_case(Case::Snake)
+    }
+
+    /// The name as written in the document
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+
+    /// `get_mod_info`
+    pub fn snake(&self) -> String {
+        self.words.join("_")
+    }
+
+    /// `GET_MOD_INFO`
+    pub fn screaming(&self) -> String {
+        self.snake().to_uppercase()
+    }
+
+    /// `get-mod-info`
+    pub fn kebab(&self) -> String {
+        self.words.join("-")
+    }
+
+    /// `GetModInfo`
+    pub fn pascal(&self) -> String {
+        self.words.iter().map(|w| capitalize(w)).collect()
+    }
+
+    /// `getModInfo`
+    pub fn camel(&self) -> String {
+        let mut words = self.words.iter();
+        let first = words.next().cloned().unwrap_or_default();
+        first + &words.map(|w| capitalize(w)).collect::<String>()
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+impl std::fmt::Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+/// How many elements (bytes, or array items) a variable length field has
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Length {
+    /// Exactly this many
+    Fixed(u32),
+
+    /// A sized string's capacity in bytes. Shorter strings are padded with NULs
+    Capacity(u32),
+
+    /// Given by an earlier integer field in the same struct or payload
+    CountField(Name),
+
+    /// Ends with this byte sequence, which isn't part of the value
+    Sequence(Vec<u8>),
+
+    /// Runs to the end of the payload. Only allowed when sending
+    Remainder,
+}
+
+/// A field's type, with its wire representation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Int { bits: u32, signing: Signing, endianness: Endianness },
+
+    /// 32 or 64 bit IEEE float
+    Float { bits: u32, endianness: Endianness },
+
+    Bytes(Length),
+    String(Length),
+
+    /// Always these bytes. Generated APIs don't expose constants as fields
+    Const(Vec<u8>),
+
+    /// One of [Ir::structs]
+    Struct(Name),
+
+    /// Repetitions of one of [Ir::structs]
+    Array { item: Name, len: Length },
+}
+
+impl Type {
+    pub fn is_const(&self) -> bool {
+        matches!(self, Type::Const(_))
+    }
+
+    /// The length of a variable length field
+    pub fn length(&self) -> Option<&Length> {
+        match self {
+            Type::Bytes(len) | Type::String(len) | Type::Array { len, .. } => Some(len),
+            _ => None,
+        }
+    }
+
+    /// The struct this type refers to, directly or as an array item
+    pub fn struct_name(&self) -> Option<&Name> {
+        match self {
+            Type::Struct(name) | Type::Array { item: name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The type in a few words, without lengths or links, such as `u16` or `array of vec3`
+    pub fn summary(&self) -> String {
+        match self {
+            Type::Int { bits, signing: Signing::OnesComplement, .. } => format!("i{bits}, ones' complement"),
+            Type::Int { bits, signing: Signing::Unsigned, .. } => format!("u{bits}"),
+            Type::Int { bits, .. } => format!("i{bits}"),
+            Type::Float { bits, .. } => format!("f{bits}"),
+            Type::Bytes(_) => "bytes".to_owned(),
+            Type::String(_) => "UTF-8 string".to_owned(),
+            Type::Const(_) => "constant".to_owned(),
+            Type::Struct(name) => name.raw().to_owned(),
+            Type::Array { item, .. } => format!("array of {}", item.raw()),
+        }
+    }
+}
+
+/// A bit offset as a byte offset, or `byte.bit` when it isn't on a byte boundary
+pub fn byte_offset(bits: u64) -> String {
+    match bits % 8 {
+        0 => (bits / 8).to_string(),
+        bit => format!("{}.{bit}", bits / 8),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: Name,
+    pub description: Option<String>,
+    pub ty: Type,
+    pub size: Size,
+
+    /// Bit offset from the start of the struct or payload, when every field before it is fixed size
+    pub offset_bits: Option<u64>,
+
+    /// The variable length field whose element count this field holds. Generated APIs usually
+    /// fill it in rather than exposing it
+    pub count_of: Option<Name>,
+
+    /// Physical units, for documentation
+    pub units: Option<String>,
+}
+
+impl Field {
+    /// Whether generated APIs should let users set this field, rather than it being a constant
+    /// or a count derived from another field
+    pub fn is_user_facing(&self) -> bool {
+        !self.ty.is_const() && self.count_of.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Struct {
+    pub name: Name,
+    pub description: Option<String>,
+    pub fields: Vec<Field>,
+    pub size: Size,
+}
+
+/// A payload's value for one metadata key
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub name: Name,
+
+    /// Every value the payload accepts. The first is the one used when sending
+    pub values: Vec<LiteralValue>,
+
+    /// The metadata's type, when the direction's frame format has a `Metadata` element for it
+    pub ty: Option<Type>,
+}
+
+impl Metadata {
+    /// The values as the unsigned integers a frame carries, as `openpid::runtime` packs them. None
+    /// without a frame element, or for types wider than 64 bits
+    pub fn packed(&self) -> Option<Vec<u64>> {
+        let ty = self.ty.as_ref()?;
+        self.values.iter().map(|v| pack_literal(ty, v)).collect()
+    }
+}
+
+/// Packs an integer or short string literal into an unsigned integer of the type's width
+pub fn pack_literal(ty: &Type, literal: &LiteralValue) -> Option<u64> {
+    match (ty, literal) {
+        (Type::Int { bits, signing, .. }, LiteralValue::Int(i)) => openpid_runtime::bits::from_signed(*i as i128, *bits, signing.into()).ok(),
+        (Type::Bytes(Length::Fixed(n) | Length::Capacity(n)) | Type::String(Length::Fixed(n) | Length::Capacity(n)), LiteralValue::String(s))
+            if *n <= 8 && s.len() <= *n as usize =>
+        {
+            let mut bytes = s.as_bytes().to_vec();
+            bytes.resize(*n as usize, 0);
+            Some(bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payload {
+    pub name: Name,
+    pub direction: Direction,
+    pub description: String,
+    pub fields: Vec<Field>,
+    pub size: Size,
+
+    /// Metadata the frame format uses come first, in frame order, followed by any others
+    pub metadata: Vec<Metadata>,
+}
+
+impl Payload {
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name.raw() == name)
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&Metadata> {
+        self.metadata.iter().find(|m| m.name.raw() == key)
+    }
+}
+
+/// A frame format element. Mirrors `PacketFormatElement`, with metadata typed and sizes resolved
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameElement {
+    SizeTotal { bits: u32, unit: BitsOrBytes },
+    SizeOfPayload { bits: u32, unit: BitsOrBytes },
+    SizeOfElements { bits: u32, unit: BitsOrBytes, elements: Vec<FrameElement> },
+    Payload,
+    Metadata { name: Name, ty: Type, description: Option<String> },
+    Crc(Crc),
+
+    /// The last `bits` bits of `data`
+    Const { data: Vec<u8>, bits: u32, description: Option<String> },
+}
+
+impl FrameElement {
+    /// Bits taken up by this element and any it contains, except the payload
+    pub fn envelope_bits(&self) -> u64 {
+        match self {
+            FrameElement::SizeTotal { bits, .. } | FrameElement::SizeOfPayload { bits, .. } => *bits as u64,
+            FrameElement::SizeOfElements { bits, elements, .. } => *bits as u64 + elements.iter().map(FrameElement::envelope_bits).sum::<u64>(),
+            FrameElement::Payload => 0,
+            FrameElement::Metadata { ty, .. } => match ty {
+                Type::Int { bits, .. } | Type::Float { bits, .. } => *bits as u64,
+                Type::Bytes(Length::Fixed(n) | Length::Capacity(n)) | Type::String(Length::Fixed(n) | Length::Capacity(n)) => *n as u64 * 8,
+                Type::Const(data) => data.len() as u64 * 8,
+                _ => 0,
+            },
+            FrameElement::Crc(crc) => match crc {
+                Crc::Crc32 => 32,
+                Crc::Crc16XModem => 16,
+            },
+            FrameElement::Const { bits, .. } => *bits as u64,
+        }
+    }
+}
+
+/// The frame formats of a `[uart]` section
+#[derive(Debug, Clone, PartialEq)]
+pub struct Framing {
+    pub tx: Vec<FrameElement>,
+    pub rx: Vec<FrameElement>,
+}
+
+impl Framing {
+    pub fn format(&self, direction: Direction) -> &[FrameElement] {
+        match direction {
+            Direction::Tx => &self.tx,
+            Direction::Rx => &self.rx,
+        }
+    }
+
+    /// Every `Metadata` element of a format, in wire order, including ones nested in `SizeOfElements`
+    pub fn metadata(&self, direction: Direction) -> Vec<(&Name, &Type)> {
+        fn walk<'a>(elements: &'a [FrameElement], out: &mut Vec<(&'a Name, &'a Type)>) {
+            for element in elements {
+                match element {
+                    FrameElement::Metadata { name, ty, .. } => out.push((name, ty)),
+                    FrameElement::SizeOfElements { elements, .. } => walk(elements, out),
+                    _ => (),
+                }
+            }
+        }
+        let mut out = Vec::new();
+        walk(self.format(direction), &mut out);
+        out
+    }
+
+    /// The format in wire order, with nesting flattened. None if a metadata element isn't an
+    /// integer or a string of at most 8 bytes, which frames can't carry as a number
+    pub fn flatten(&self, direction: Direction) -> Option<Vec<FlatElement>> {
+        fn walk(elements: &[FrameElement], out: &mut Vec<FlatElement>) -> Option<()> {
+            for element in elements {
+                match element {
+                    FrameElement::SizeTotal { bits, unit } => out.push(FlatElement::SizeTotal { bits: *bits, unit: *unit }),
+                    FrameElement::SizeOfPayload { bits, unit } => out.push(FlatElement::SizeOfPayload { bits: *bits, unit: *unit }),
+                    FrameElement::SizeOfElements { bits, unit, elements } => {
+                        let at = out.len();
+                        out.push(FlatElement::Payload);
+                        walk(elements, out)?;
+                        out[at] = FlatElement::SizeOfElements {
+                            bits: *bits,
+                            unit: *unit,
+                            covers: out.len() - at - 1,
+                            static_bits: elements.iter().map(FrameElement::envelope_bits).sum(),
+                            has_payload: out[at + 1..].contains(&FlatElement::Payload),
+                        };
+                    }
+                    FrameElement::Payload => out.push(FlatElement::Payload),
+                    FrameElement::Metadata { name, ty, .. } => {
+                        let (bits, endianness) = match ty {
+                            Type::Int { bits, endianness, .. } => (*bits, *endianness),
+                            Type::Bytes(Length::Fixed(n) | Length::Capacity(n)) | Type::String(Length::Fixed(n) | Length::Capacity(n)) if *n <= 8 => {
+                                (n * 8, Endianness::BigEndian)
+                            }
+                            _ => return None,
+                        };
+                        out.push(FlatElement::Metadata { name: name.clone(), bits, endianness });
+                    }
+                    FrameElement::Crc(crc) => out.push(FlatElement::Crc(*crc)),
+                    FrameElement::Const { data, bits, .. } => out.push(FlatElement::Const { data: data.clone(), bits: *bits }),
+                }
+            }
+            Some(())
+        }
+        let mut out = Vec::new();
+        walk(self.format(direction), &mut out)?;
+        Some(out)
+    }
+}
+
+/// A frame element with `SizeOfElements` nesting flattened away, for backends that emit
+/// straight-line framing code
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlatElement {
+    SizeTotal { bits: u32, unit: BitsOrBytes },
+    SizeOfPayload { bits: u32, unit: BitsOrBytes },
+
+    /// Size of the `covers` elements after this one: `static_bits`, plus the payload if `has_payload`
+    SizeOfElements { bits: u32, unit: BitsOrBytes, covers: usize, static_bits: u64, has_payload: bool },
+    Payload,
+
+    /// Metadata as an unsigned integer, like the runtime sends it
+    Metadata { name: Name, bits: u32, endianness: Endianness },
+    Crc(Crc),
+    Const { data: Vec<u8>, bits: u32 },
+}
+
+impl FlatElement {
+    /// Bits this element takes up, not counting covered elements or the payload
+    pub fn bits(&self) -> u64 {
+        match self {
+            FlatElement::SizeTotal { bits, .. } | FlatElement::SizeOfPayload { bits, .. } | FlatElement::SizeOfElements { bits, .. } => *bits as u64,
+            FlatElement::Payload => 0,
+            FlatElement::Metadata { bits, .. } | FlatElement::Const { bits, .. } => *bits as u64,
+            FlatElement::Crc(crc) => FrameElement::Crc(*crc).envelope_bits(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Tx(Name),
+    Rx(Name),
+    Sleep { milliseconds: u32 },
+    Flush,
+}
+
+/// A value a transaction returns, taken from a field of a payload it receives
+#[derive(Debug, Clone, PartialEq)]
+pub struct Return {
+    /// The field's name, prefixed with the payload's if two returns would otherwise share it
+    pub name: Name,
+
+    /// The RX payload the value comes from
+    pub payload: Name,
+
+    /// Field names leading to the value, through any structs
+    pub path: Vec<Name>,
+
+    pub ty: Type,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    pub name: Name,
+    pub description: String,
+    pub actions: Vec<Action>,
+    pub returns: Vec<Return>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub name: Name,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ir {
+    pub device: Device,
+    pub openpid_version: Option<String>,
+    pub doc_version: Option<String>,
+
+    /// Every struct, ordered so that each comes after the structs it uses
+    pub structs: Vec<Struct>,
+
+    pub tx: Vec<Payload>,
+    pub rx: Vec<Payload>,
+    pub transactions: Vec<Transaction>,
+
+    /// Frame formats, when the document has a `[uart]` section
+    pub framing: Option<Framing>,
+}
+
+impl Ir {
+    /// Resolves and validates a document, reporting every problem found as [CodegenError::Invalid]
+    pub fn build(pid: &OpenPID) -> Result<Ir, CodegenError> {
+        let mut builder = Builder { pid, errors: Vec::new(), struct_sizes: BTreeMap::new() };
+        let ir = builder.build();
+        if builder.errors.is_empty() {
+            Ok(ir)
+        } else {
+            Err(CodegenError::Invalid(builder.errors))
+        }
+    }
+
+    pub fn get_struct(&self, name: &Name) -> Option<&Struct> {
+        self.structs.iter().find(|s| s.name == *name)
+    }
+
+    pub fn payloads(&self, direction: Direction) -> &[Payload] {
+        match direction {
+            Direction::Tx => &self.tx,
+            Direction::Rx => &self.rx,
+        }
+    }
+
+    pub fn get_payload(&self, direction: Direction, name: &Name) -> Option<&Payload> {
+        self.payloads(direction).iter().find(|p| p.name == *name)
+    }
+
+    /// Every payload, TX first
+    pub fn all_payloads(&self) -> impl Iterator<Item = &Payload> {
+        self.tx.iter().chain(&self.rx)
+    }
+
+    /// The fields a type's struct has, for struct and array types
+    pub fn fields_of(&self, ty: &Type) -> Option<&[Field]> {
+        ty.struct_name().and_then(|n| self.get_struct(n)).map(|s| s.fields.as_slice())
+    }
+
+    /// Each distinct TX payload a transaction sends with fields the caller sets, which drivers take as parameters
+    pub fn transaction_params<'t>(&self, transaction: &'t Transaction) -> Vec<&'t Name> {
+        let mut params: Vec<&Name> = Vec::new();
+        for action in &transaction.actions {
+            if let Action::Tx(payload) = action {
+                let has_fields = self.get_payload(Direction::Tx, payload).is_some_and(|p| p.fields.iter().any(Field::is_user_facing));
+                if has_fields && !params.contains(&payload) {
+                    params.push(payload);
+                }
+            }
+        }
+        params
+    }
+}
+
+impl OpenPID {
+    /// Resolves this document for code generation. See [Ir::build]
+    pub fn to_ir(&self) -> Result<Ir, CodegenError> {
+        Ir::build(self)
+    }
+}
+
+struct Builder<'a> {
+    pid: &'a OpenPID,
+    errors: Vec<CodegenError>,
+    struct_sizes: BTreeMap<&'a str, Size>,
+}
+
+fn unsupported(field: &str, bits: u32, reason: &'static str) -> CodegenError {
+    CodegenError::Codec(crate::codec::CodecError::UnsupportedWidth { field: field.to_owned(), bits, reason })
+}
+
+impl<'a> Builder<'a> {
+    fn build(&mut self) -> Ir {
+        let pid = self.pid;
+        let mut structs = Vec::new();
+        for name in self.struct_order() {
+            let rs = &pid.structs[name];
+            let fields = self.lower_fields(&format!("[Struct {name}]"), &rs.fields);
+            let size = fields_size(&fields);
+            self.struct_sizes.insert(name, size);
+            structs.push(Struct { name: Name::new(name), description: rs.description.clone(), fields, size });
+        }
+
+        let framing = pid.uart.as_ref().map(|uart| Framing { tx: self.lower_format(&uart.tx_format), rx: self.lower_format(&uart.rx_format) });
+        let tx = self.lower_payloads(Direction::Tx, framing.as_ref());
+        let rx = self.lower_payloads(Direction::Rx, framing.as_ref());
+        for payload in &rx {
+            self.check_terminated(payload.name.raw(), &payload.fields, &structs, &mut Vec::new());
+        }
+
+        let transactions = pid.transactions.iter().map(|(name, t)| self.lower_transaction(name, t, &rx, &structs)).collect::<Vec<_>>();
+
+        self.check_unique("Struct", structs.iter().map(|s| &s.name));
+        self.check_unique("TX payload", tx.iter().map(|p| &p.name));
+        self.check_unique("RX payload", rx.iter().map(|p| &p.name));
+        self.check_unique("Transaction", transactions.iter().map(|t| &t.name));
+        for fields in structs.iter().map(|s| &s.fields).chain(tx.iter().chain(&rx).map(|p| &p.fields)) {
+            self.check_unique("Field", fields.iter().map(|f| &f.name));
+        }
+
+        Ir {
+            device: Device { name: Name::new(&pid.device_info.name), description: pid.device_info.description.clone() },
+            openpid_version: pid.openpid_version.clone(),
+            doc_version: pid.doc_version.clone(),
+            structs,
+            tx,
+            rx,
+            transactions,
+            framing,
+        }
+    }
+
+    /// Structs in dependency order. Missing and recursive structs are reported and left out
+    fn struct_order(&mut self) -> Vec<&'a str> {
+        let pid = self.pid;
+        let mut order = Vec::new();
+        let mut done = BTreeSet::new();
+        for name in pid.structs.keys() {
+            self.visit_struct(name, &mut Vec::new(), &mut done, &mut order);
+        }
+        order
+    }
+
+    fn visit_struct(&mut self, name: &'a str, visiting: &mut Vec<&'a str>, done: &mut BTreeSet<&'a str>, order: &mut Vec<&'a str>) {
+        if done.contains(name) {
+            return;
+        }
+        if visiting.contains(&name) {
+            self.errors.push(crate::codec::CodecError::RecursiveStruct { struct_name: name.to_owned() }.into());
+            done.insert(name);
+            return;
+        }
+        let Some(rs) = self.pid.structs.get(name) else { return };
+        visiting.push(name);
+        for segment in &rs.fields {
+            if let Some(dependency) = struct_ref(segment) {
+                if self.pid.structs.contains_key(dependency) {
+                    self.visit_struct(dependency, visiting, done, order);
+                }
+            }
+        }
+        visiting.pop();
+        if done.insert(name) {
+            order.push(name);
+        }
+    }
+
+    fn lower_fields(&mut self, wanted_by: &str, segments: &[PacketSegment]) -> Vec<Field> {
+        let mut fields: Vec<Field> = Vec::new();
+        let mut offset = Some(0);
+        for segment in segments {
+            let Some(ty) = self.lower_type(wanted_by, segment, &mut fields) else { continue };
+            let size = self.type_size(&ty, &fields);
+            let description = match segment {
+                PacketSegment::Sized { description, .. } | PacketSegment::Unsized { description, .. } => description.clone(),
+                PacketSegment::Struct { .. } => None,
+            };
+            let units = match segment {
+                PacketSegment::Sized { units, .. } => units.clone(),
+                _ => None,
+            };
+            fields.push(Field { name: Name::new(segment.get_name()), description, ty, size, offset_bits: offset, count_of: None, units });
+            offset = offset.zip(size.fixed_bits()).map(|(a, b)| a + b);
+        }
+        fields
+    }
+
+    /// Lowers a segment's type, marking its count field in `before` if it has one
+    fn lower_type(&mut self, wanted_by: &str, segment: &PacketSegment, before: &mut [Field]) -> Option<Type> {
+        let field = segment.get_name();
+        let path = format!("{wanted_by}->{field}");
+        match segment {
+            PacketSegment::Sized { bits, datatype, .. } => match self.lower_sized(&path, *bits, datatype) {
+                Ok(ty) => Some(ty),
+                Err(e) => {
+                    self.errors.push(e);
+                    None
+                }
+            },
+            PacketSegment::Struct { struct_name, .. } => {
+                self.check_struct(wanted_by, field, struct_name)?;
+                Some(Type::Struct(Name::new(struct_name)))
+            }
+            PacketSegment::Unsized { datatype, termination, .. } => {
+                let len = match termination {
+                    Some(Terminator::CountFixed { count }) => Length::Fixed(*count),
+                    Some(Terminator::CountInPacket { field_name }) => {
+                        match before.iter_mut().find(|f| f.name.raw() == field_name && matches!(f.ty, Type::Int { .. })) {
+                            Some(count) => {
+                                count.count_of = Some(Name::new(field));
+                                Length::CountField(count.name.clone())
+                            }
+                            None => {
+                                self.errors.push(CodegenError::BadCountField {
+                                    wanted_by: wanted_by.to_owned(),
+                                    field: field.to_owned(),
+                                    count_field: field_name.clone(),
+                                });
+                                return None;
+                            }
+                        }
+                    }
+                    Some(Terminator::Sequence { sequence }) => Length::Sequence(sequence.clone()),
+                    None => Length::Remainder,
+                };
+                match datatype {
+                    UnsizedDataType::Raw => Some(Type::Bytes(len)),
+                    UnsizedDataType::StringUTF8 => Some(Type::String(len)),
+                    UnsizedDataType::Array { item_struct } => {
+                        self.check_struct(wanted_by, field, item_struct)?;
+                        Some(Type::Array { item: Name::new(item_struct), len })
+                    }
+                }
+            }
+        }
+    }
+
+    fn lower_sized(&self, path: &str, bits: u32, datatype: &SizedDataType) -> Result<Type, CodegenError> {
+        let whole_bytes = || if bits.is_multiple_of(8) { Ok(bits / 8) } else { Err(unsupported(path, bits, "must be a whole number of bytes")) };
+        match datatype {
+            SizedDataType::Integer { endianness, signing } => {
+                if bits == 0 || bits > 64 {
+                    return Err(unsupported(path, bits, "integers must be 1 to 64 bits"));
+                }
+                if *endianness == Endianness::LittleEndian && !bits.is_multiple_of(8) {
+                    return Err(unsupported(path, bits, "little endian fields must be whole bytes"));
+                }
+                Ok(Type::Int { bits, signing: *signing, endianness: *endianness })
+            }
+            SizedDataType::FloatIEEE { endianness } => match bits {
+                32 | 64 => Ok(Type::Float { bits, endianness: *endianness }),
+                _ => Err(unsupported(path, bits, "floats must be 32 or 64 bits")),
+            },
+            SizedDataType::Raw => Ok(Type::Bytes(Length::Fixed(whole_bytes()?))),
+            SizedDataType::StringUTF8 => Ok(Type::String(Length::Capacity(whole_bytes()?))),
+            SizedDataType::Const { data } => {
+                if data.len() as u32 != whole_bytes()? {
+                    return Err(CodegenError::Codec(crate::codec::CodecError::LengthMismatch {
+                        field: path.to_owned(),
+                        expected: bits as usize / 8,
+                        found: data.len(),
+                    }));
+                }
+                Ok(Type::Const(data.clone()))
+            }
+        }
+    }
+
+    fn check_struct(&mut self, wanted_by: &str, field: &str, struct_name: &str) -> Option<()> {
+        if self.pid.structs.contains_key(struct_name) {
+            Some(())
+        } else {
+            self.errors.push(CodegenError::UnknownStruct { wanted_by: wanted_by.to_owned(), field: field.to_owned(), struct_name: struct_name.to_owned() });
+            None
+        }
+    }
+
+    fn type_size(&self, ty: &Type, before: &[Field]) -> Size {
+        let unknown = Size::Variable { min_bits: 0, max_bits: None };
+        let element = match ty {
+            Type::Int { bits, .. } | Type::Float { bits, .. } => return Size::Fixed(*bits as u64),
+            Type::Const(data) => return Size::Fixed(data.len() as u64 * 8),
+            Type::Struct(name) => return self.struct_sizes.get(name.raw()).copied().unwrap_or(unknown),
+            Type::Bytes(_) | Type::String(_) => Size::Fixed(8),
+            Type::Array { item, .. } => self.struct_sizes.get(item.raw()).copied().unwrap_or(unknown),
+        };
+        match ty.length() {
+            Some(Length::Fixed(n) | Length::Capacity(n)) => element.repeated(*n as u64, Some(*n as u64)),
+            Some(Length::CountField(count)) => {
+                let max = before.iter().find(|f| f.name == *count).and_then(|f| match f.ty {
+                    Type::Int { bits, .. } if bits < 64 => Some((1u64 << bits) - 1),
+                    _ => None,
+                });
+                element.repeated(0, max)
+            }
+            Some(Length::Sequence(sequence)) => Size::Variable { min_bits: sequence.len() as u64 * 8, max_bits: None },
+            Some(Length::Remainder) | None => unknown,
+        }
+    }
+
+    fn lower_format(&mut self, elements: &[PacketFormatElement]) -> Vec<FrameElement> {
+        let mut out = Vec::new();
+        for element in elements {
+            out.push(match element {
+                PacketFormatElement::SizeTotal { size_bits, express_as } => FrameElement::SizeTotal { bits: *size_bits, unit: *express_as },
+                PacketFormatElement::SizeOfPayload { size_bits, express_as } => FrameElement::SizeOfPayload { bits: *size_bits, unit: *express_as },
+                PacketFormatElement::SizeOfElements { size_bits, express_as, elements } => {
+                    FrameElement::SizeOfElements { bits: *size_bits, unit: *express_as, elements: self.lower_format(elements) }
+                }
+                PacketFormatElement::Payload => FrameElement::Payload,
+                PacketFormatElement::Metadata { segment, description } => {
+                    let ty = match segment {
+                        PacketSegment::Sized { bits, datatype, .. } => self.lower_sized(&format!("[Frame]->{}", segment.get_name()), *bits, datatype),
+                        _ => Err(unsupported(segment.get_name(), 0, "metadata must be a sized field")),
+                    };
+                    match ty {
+                        Ok(ty) => FrameElement::Metadata { name: Name::new(segment.get_name()), ty, description: description.clone() },
+                        Err(e) => {
+                            self.errors.push(e);
+                            continue;
+                        }
+                    }
+                }
+                PacketFormatElement::Crc { algorithm } => FrameElement::Crc(*algorithm),
+                PacketFormatElement::Const { data, bits, description } => {
+                    FrameElement::Const { data: data.clone(), bits: bits.unwrap_or(data.len() * 8) as u32, description: description.clone() }
+                }
+            });
+        }
+        out
+    }
+
+    fn lower_payloads(&mut self, direction: Direction, framing: Option<&Framing>) -> Vec<Payload> {
+        let pid = self.pid;
+        let mut payloads = Vec::new();
+        for (name, payload) in pid.payloads.get(direction) {
+            let fields = self.lower_fields(name, &payload.segments);
+            let mut metadata = Vec::new();
+            for (key, ty) in framing.map(|f| f.metadata(direction)).unwrap_or_default() {
+                match payload.metadata.get(key.raw()) {
+                    Some(values) => {
+                        metadata.push(Metadata { name: key.clone(), values: values.clone().as_many(), ty: Some(ty.clone()) })
+                    }
+                    None => self.errors.push(CodegenError::MissingMetadata { payload: name.clone(), key: key.raw().to_owned() }),
+                }
+            }
+            for (key, values) in &payload.metadata {
+                if !metadata.iter().any(|m| m.name.raw() == key) {
+                    metadata.push(Metadata { name: Name::new(key), values: values.clone().as_many(), ty: None });
+                }
+            }
+            payloads.push(Payload {
+                name: Name::new(name),
+                direction,
+                description: payload.description.clone(),
+                size: fields_size(&fields),
+                fields,
+                metadata,
+            });
+        }
+        payloads
+    }
+
+    /// Received data has to end somewhere, directly or through any struct
+    fn check_terminated<'s>(&mut self, wanted_by: &str, fields: &'s [Field], structs: &'s [Struct], visiting: &mut Vec<&'s Name>) {
+        for field in fields {
+            if field.ty.length() == Some(&Length::Remainder) {
+                self.errors.push(CodegenError::UnterminatedRx { wanted_by: wanted_by.to_owned(), field: field.name.raw().to_owned() });
+            }
+            if let Some(rs) = field.ty.struct_name().and_then(|n| structs.iter().find(|s| s.name == *n)) {
+                if !visiting.contains(&&rs.name) {
+                    visiting.push(&rs.name);
+                    self.check_terminated(&format!("{wanted_by}->[Struct {}]{}", rs.name, field.name), &rs.fields, structs, visiting);
+                    visiting.pop();
+                }
+            }
+        }
+    }
+
+    fn lower_transaction(&mut self, name: &str, transaction: &config::Transaction, rx: &[Payload], structs: &[Struct]) -> Transaction {
+        let pid = self.pid;
+        let mut actions = Vec::new();
+        for action in &transaction.actions {
+            let (direction, payload) = match action {
+                config::Action::Tx { payload } => (Direction::Tx, payload),
+                config::Action::Rx { payload } => (Direction::Rx, payload),
+                config::Action::Sleep { milliseconds } => {
+                    actions.push(Action::Sleep { milliseconds: *milliseconds });
+                    continue;
+                }
+                config::Action::Flush => {
+                    actions.push(Action::Flush);
+                    continue;
+                }
+            };
+            if !pid.payloads.get(direction).contains_key(payload) {
+                self.errors.push(CodegenError::UnknownPayload { transaction: name.to_owned(), direction, payload: payload.clone() });
+                continue;
+            }
+            actions.push(match direction {
+                Direction::Tx => Action::Tx(Name::new(payload)),
+                Direction::Rx => Action::Rx(Name::new(payload)),
+            });
+        }
+
+        let mut returns = Vec::new();
+        for value in &transaction.returns {
+            match self.resolve_return(value, &actions, rx, structs) {
+                Some(ret) => returns.push(ret),
+                None => self.errors.push(CodegenError::BadReturn { transaction: name.to_owned(), value: value.clone() }),
+            }
+        }
+        let clashing = returns.iter().filter(|r| returns.iter().filter(|other| other.name == r.name).count() > 1).map(|r| r.name.clone()).collect::<Vec<_>>();
+        for ret in returns.iter_mut().filter(|r| clashing.contains(&r.name)) {
+            ret.name = Name::new(&format!("{}_{}", ret.payload.snake(), ret.name.snake()));
+        }
+
+        Transaction { name: Name::new(name), description: transaction.description.clone(), actions, returns }
+    }
+
+    fn resolve_return(&self, value: &str, actions: &[Action], rx: &[Payload], structs: &[Struct]) -> Option<Return> {
+        let mut parts = value.split('.');
+        let payload_name = parts.next()?;
+        let payload = rx.iter().find(|p| p.name.raw() == payload_name)?;
+        if !actions.contains(&Action::Rx(payload.name.clone())) {
+            return None;
+        }
+        let mut fields = payload.fields.as_slice();
+        let mut path = Vec::new();
+        let mut ty = None;
+        for part in parts {
+            let field = fields.iter().find(|f| f.name.raw() == part && f.is_user_facing())?;
+            path.push(field.name.clone());
+            ty = Some(field.ty.clone());
+            fields = match &field.ty {
+                Type::Struct(name) => structs.iter().find(|s| s.name == *name).map(|s| s.fields.as_slice()).unwrap_or(&[]),
+                _ => &[],
+            };
+        }
+        Some(Return { name: path.last()?.clone(), payload: payload.name.clone(), path, ty: ty? })
+    }
+
+    fn check_unique<'n>(&mut self, kind: &'static str, names: impl Iterator<Item = &'n Name>) {
+        let mut seen: BTreeMap<String, &Name> = BTreeMap::new();
+        for name in names {
+            if let Some(first) = seen.insert(name.snake(), name) {
+                self.errors.push(CodegenError::NameCollision { kind, first: first.raw().to_owned(), second: name.raw().to_owned() });
+            }
+        }
+    }
+}
+
+fn struct_ref(segment: &PacketSegment) -> Option<&str> {
+    match segment {
+        PacketSegment::Struct { struct_name, .. } => Some(struct_name),
+        PacketSegment::Unsized { datatype: UnsizedDataType::Array { item_struct }, .. } => Some(item_struct),
+        _ => None,
+    }
+}
+
+fn fields_size(fields: &[Field]) -> Size {
+    fields.iter().fold(Size::ZERO, |total, f| total + f.size)
+}
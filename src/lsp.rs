@@ -0,0 +1,255 @@
+//! A language server for OpenPID documents, run by `openpid lsp` over stdin and stdout. It
+//! publishes [crate::analysis] diagnostics as documents change, and answers hover, go to
+//! definition, find references, rename and completion requests from the latest analysis
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _, PublishDiagnostics};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, References, Rename, Request as _};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse, DiagnosticSeverity, GotoDefinitionParams,
+    GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, ReferenceParams, RenameParams, ServerCapabilities, TextDocumentPositionParams, TextDocumentSyncCapability,
+    TextDocumentSyncKind, TextEdit, Uri, WorkspaceEdit,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::analysis::{Analysis, Severity, SymbolKind};
+
+type LspResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// An open document
+struct Document {
+    text: String,
+    analysis: Analysis,
+
+    /// The last analysis of a version that parsed, for completing names while the current
+    /// version is half typed
+    names: Analysis,
+}
+
+struct Server {
+    connection: Connection,
+    documents: HashMap<Uri, Document>,
+}
+
+/// Serves one client over stdin and stdout until it shuts the server down
+pub fn serve() -> LspResult<()> {
+    let (connection, io) = Connection::stdio();
+    serve_connection(connection)?;
+    io.join()?;
+    Ok(())
+}
+
+/// Serves one client over any connection, such as [Connection::memory] in tests, until it shuts
+/// the server down
+pub fn serve_connection(connection: Connection) -> LspResult<()> {
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    Server { connection, documents: HashMap::new() }.run()
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        rename_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions { trigger_characters: Some(vec!["\"".to_owned(), ".".to_owned()]), ..Default::default() }),
+        ..Default::default()
+    }
+}
+
+/// A byte offset from an LSP position, whose `character` counts UTF-16 code units
+pub fn offset(text: &str, position: Position) -> usize {
+    let mut start = 0;
+    for _ in 0..position.line {
+        match text[start..].find('\n') {
+            Some(i) => start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= position.character as usize || c == '\n' {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+/// The LSP position of a byte offset, the inverse of [offset]
+pub fn position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position { line: before.matches('\n').count() as u32, character: before[line_start..].encode_utf16().count() as u32 }
+}
+
+fn range(text: &str, span: Range<usize>) -> lsp_types::Range {
+    lsp_types::Range { start: position(text, span.start), end: position(text, span.end) }
+}
+
+/// The file a `file:` URI names, for finding its imports
+fn file_path(uri: &Uri) -> Option<PathBuf> {
+    if uri.scheme()?.as_str() != "file" {
+        return None;
+    }
+    Some(PathBuf::from(uri.path().as_estr().decode().into_string_lossy().into_owned()))
+}
+
+fn completion_kind(kind: SymbolKind) -> CompletionItemKind {
+    match kind {
+        SymbolKind::Payload(_) | SymbolKind::Struct => CompletionItemKind::STRUCT,
+        SymbolKind::Field => CompletionItemKind::FIELD,
+        SymbolKind::Transaction => CompletionItemKind::FUNCTION,
+        SymbolKind::Metadata(_) => CompletionItemKind::PROPERTY,
+    }
+}
+
+/// Runs a request handler on its deserialized params
+fn call<P: DeserializeOwned, T: Serialize>(request: Request, handler: impl FnOnce(P) -> Result<T, String>) -> Response {
+    let result = serde_json::from_value(request.params).map_err(|e| (ErrorCode::InvalidParams, e.to_string()));
+    match result.and_then(|params| handler(params).map_err(|e| (ErrorCode::InvalidRequest, e))) {
+        Ok(result) => Response::new_ok(request.id, result),
+        Err((code, message)) => Response::new_err(request.id, code as i32, message),
+    }
+}
+
+impl Server {
+    fn run(&mut self) -> LspResult<()> {
+        while let Ok(message) = self.connection.receiver.recv() {
+            match message {
+                Message::Request(request) => {
+                    if self.connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    let response = self.request(request);
+                    self.connection.sender.send(Message::Response(response))?;
+                }
+                Message::Notification(notification) => self.notification(notification)?,
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, request: Request) -> Response {
+        match request.method.as_str() {
+            HoverRequest::METHOD => call(request, |p: HoverParams| Ok(self.hover(p.text_document_position_params))),
+            GotoDefinition::METHOD => call(request, |p: GotoDefinitionParams| Ok(self.definition(p.text_document_position_params))),
+            References::METHOD => call(request, |p: ReferenceParams| Ok(self.references(p))),
+            Rename::METHOD => call(request, |p: RenameParams| self.rename(p)),
+            Completion::METHOD => call(request, |p: CompletionParams| Ok(self.complete(p))),
+            method => Response::new_err(request.id, ErrorCode::MethodNotFound as i32, format!("{method} isn't supported")),
+        }
+    }
+
+    fn notification(&mut self, notification: Notification) -> LspResult<()> {
+        let (uri, text) = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
+                (params.text_document.uri, params.text_document.text)
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
+                // with full sync the last change is the whole document
+                let Some(change) = params.content_changes.into_iter().last() else { return Ok(()) };
+                (params.text_document.uri, change.text)
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp_types::DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                return self.publish(params.text_document.uri, Vec::new());
+            }
+            _ => return Ok(()),
+        };
+
+        let analysis = match file_path(&uri) {
+            Some(path) => Analysis::for_file(&text, &path),
+            None => Analysis::new(&text),
+        };
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .map(|d| lsp_types::Diagnostic {
+                range: d.span.clone().map(|span| range(&text, span)).unwrap_or_default(),
+                severity: Some(match d.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                }),
+                source: Some("openpid".to_owned()),
+                message: d.message.clone(),
+                ..Default::default()
+            })
+            .collect();
+        let names = match (analysis.parsed(), self.documents.remove(&uri)) {
+            (false, Some(old)) => old.names,
+            _ => analysis.clone(),
+        };
+        self.documents.insert(uri.clone(), Document { text, analysis, names });
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&self, uri: Uri, diagnostics: Vec<lsp_types::Diagnostic>) -> LspResult<()> {
+        let params = PublishDiagnosticsParams { uri, diagnostics, version: None };
+        self.connection.sender.send(Message::Notification(Notification::new(PublishDiagnostics::METHOD.to_owned(), params)))?;
+        Ok(())
+    }
+
+    /// The document and symbol at a position, when the document's analysis is current
+    fn symbol_at(&self, at: &TextDocumentPositionParams) -> Option<(&Document, usize)> {
+        let document = self.documents.get(&at.text_document.uri)?;
+        if !document.analysis.parsed() {
+            return None;
+        }
+        let symbol = document.analysis.symbol_at(offset(&document.text, at.position))?;
+        Some((document, symbol))
+    }
+
+    fn hover(&self, at: TextDocumentPositionParams) -> Option<Hover> {
+        let (document, symbol) = self.symbol_at(&at)?;
+        let value = document.analysis.hover(symbol);
+        Some(Hover { contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value }), range: None })
+    }
+
+    fn definition(&self, at: TextDocumentPositionParams) -> Option<GotoDefinitionResponse> {
+        let (document, symbol) = self.symbol_at(&at)?;
+        let span = document.analysis.symbols[symbol].span.clone();
+        Some(GotoDefinitionResponse::Scalar(Location { uri: at.text_document.uri, range: range(&document.text, span) }))
+    }
+
+    fn references(&self, params: ReferenceParams) -> Option<Vec<Location>> {
+        let at = params.text_document_position;
+        let (document, symbol) = self.symbol_at(&at)?;
+        let mut spans = document.analysis.references_to(symbol);
+        if params.context.include_declaration {
+            spans.insert(0, document.analysis.symbols[symbol].span.clone());
+        }
+        Some(spans.into_iter().map(|span| Location { uri: at.text_document.uri.clone(), range: range(&document.text, span) }).collect())
+    }
+
+    fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>, String> {
+        let at = params.text_document_position;
+        let Some((document, symbol)) = self.symbol_at(&at) else { return Ok(None) };
+        let spans = document.analysis.rename(symbol, &params.new_name)?;
+        let edits = spans.into_iter().map(|span| TextEdit { range: range(&document.text, span), new_text: params.new_name.clone() }).collect();
+        Ok(Some(WorkspaceEdit { changes: Some(HashMap::from([(at.text_document.uri, edits)])), ..Default::default() }))
+    }
+
+    fn complete(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let at = params.text_document_position;
+        let document = self.documents.get(&at.text_document.uri)?;
+        let completions = document.names.complete(&document.text, offset(&document.text, at.position));
+        let items = completions
+            .into_iter()
+            .map(|c| CompletionItem { label: c.label, kind: Some(completion_kind(c.kind)), detail: c.detail, ..Default::default() })
+            .collect();
+        Some(CompletionResponse::Array(items))
+    }
+}
@@ -0,0 +1,648 @@
+//! `openpid`, the command line tool. Checks and formats OpenPID documents, runs code and docs
+//! generators, and encodes and decodes payloads from the terminal. Every command reads
+//! `openpid.toml` unless given `--file`, and prints JSON instead of text with `--json`, for CI.
+//!
+//! Exits with 0 on success, 1 when the document has errors or a command fails, and 2 on bad usage
+
+use std::{error::Error, fmt::Write as _, io::{self, Write as _}, path::{Path, PathBuf}, process::ExitCode};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use serde_json::json;
+
+use openpid::analysis::{Analysis, Severity};
+use openpid::codec::Direction;
+use openpid::codegen::{BackendOptions, DirectorySink, Registry, VirtualTree};
+use openpid::diff::Bump;
+use openpid::migrate::CURRENT_VERSION;
+use openpid::package::{self, LocalRegistry};
+use openpid::size::Size;
+use openpid::value::{DecodedPayload, Fields, Span};
+use openpid::OpenPID;
+
+#[derive(Parser)]
+#[command(name = "openpid", version, about = "Validate, format, generate from and decode with OpenPID documents")]
+struct Cli {
+    /// The OpenPID document to read
+    #[arg(short, long, global = true, default_value = "openpid.toml")]
+    file: PathBuf,
+
+    /// Print machine readable JSON instead of text
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Report every problem in the document, exiting with 1 if any are errors
+    Validate,
+
+    /// Print the document in canonical form, keeping its comments
+    Fmt {
+        /// Rewrite the file instead of printing it
+        #[arg(long, conflicts_with = "check")]
+        write: bool,
+
+        /// Only check whether the file is already formatted, exiting with 1 if it isn't
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Run a code generator
+    Gen {
+        /// Name of the backend, see `--list`
+        #[arg(required_unless_present = "list")]
+        backend: Option<String>,
+
+        /// Directory to write into
+        #[arg(short, long, default_value = "generated")]
+        out: PathBuf,
+
+        /// A backend option, such as `-O async=false`. A bare key means `key=true`
+        #[arg(short = 'O', long = "option", value_name = "KEY=VALUE")]
+        options: Vec<String>,
+
+        /// List the backends and their options instead
+        #[arg(long)]
+        list: bool,
+    },
+
+    /// Generate reference documentation
+    Doc {
+        #[arg(long, value_enum, default_value_t = DocFormat::Markdown)]
+        format: DocFormat,
+
+        /// Directory to write into
+        #[arg(short, long, default_value = "docs")]
+        out: PathBuf,
+
+        /// A backend option, such as `-O diagrams=false`
+        #[arg(short = 'O', long = "option", value_name = "KEY=VALUE")]
+        options: Vec<String>,
+    },
+
+    /// Decode a payload from hex, or with `auto` a whole frame, identifying its payload
+    Decode {
+        /// Name of the payload, or `auto`
+        payload: String,
+
+        /// The bytes, as hex. Spaces, colons and a `0x` prefix are ignored
+        hex: String,
+
+        /// Which payloads to look in. Needed when a name is used in both directions, otherwise
+        /// RX is tried before TX
+        #[arg(short, long, value_enum)]
+        direction: Option<Dir>,
+    },
+
+    /// Encode a payload from a JSON object of its fields, printing hex
+    Encode {
+        /// Name of the payload
+        payload: String,
+
+        /// The fields, such as `{"rate": 100, "name": "imu"}`
+        #[arg(value_name = "JSON")]
+        fields: String,
+
+        #[arg(short, long, value_enum)]
+        direction: Option<Dir>,
+
+        /// Encode only the payload, without the frame around it
+        #[arg(long)]
+        payload_only: bool,
+    },
+
+    /// Report the size of every struct and payload, and of each payload's frame
+    Size,
+
+    /// Update the document to the current version of the spec, rewriting it
+    Migrate {
+        /// Only check whether the document is current, exiting with 1 if it isn't
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Compare an older version of the document with this one, classifying each change as
+    /// breaking or compatible on the wire and in generated APIs, and suggesting a version bump
+    Diff {
+        /// The older document
+        old: PathBuf,
+
+        /// Exit with 1 unless `doc_version` moved at least as far as the changes need
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Publish the package next to the document, described by its `openpid-package.toml`, to a
+    /// registry
+    Publish {
+        /// The registry's directory. Defaults to `OPENPID_REGISTRY`
+        #[arg(long)]
+        registry: Option<PathBuf>,
+    },
+
+    /// Resolve the dependencies in `openpid-package.toml`, write `openpid.lock`, and install the
+    /// packages into `openpid_packages` where imports find them
+    Install {
+        /// The registry's directory. Defaults to `OPENPID_REGISTRY`
+        #[arg(long)]
+        registry: Option<PathBuf>,
+
+        /// Where fetched packages are kept. Defaults to `OPENPID_CACHE`, then `~/.cache/openpid`
+        #[arg(long)]
+        cache: Option<PathBuf>,
+    },
+
+    /// Print the JSON Schema editors check documents against, as checked in at `openpid.schema.json`
+    Schema,
+
+    /// Run a language server for editors, over stdin and stdout
+    #[cfg(feature = "lsp")]
+    Lsp,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DocFormat {
+    Markdown,
+    Html,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Dir {
+    Tx,
+    Rx,
+}
+
+impl From<Dir> for Direction {
+    fn from(value: Dir) -> Self {
+        match value {
+            Dir::Tx => Direction::Tx,
+            Dir::Rx => Direction::Rx,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        // the language server writes to stdout from its own thread
+        #[cfg(feature = "lsp")]
+        Command::Lsp => openpid::lsp::serve().map(|()| true).map_err(|e| e as Box<dyn Error>),
+        _ => {
+            let mut out = io::stdout().lock();
+            run(&cli, &mut out).and_then(|ok| Ok(out.flush().map(|()| ok)?))
+        }
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        // piped into `head` or similar, which stopped reading: nothing left to say
+        Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) => ExitCode::SUCCESS,
+        Err(e) => {
+            if cli.json {
+                let _ = writeln!(io::stdout().lock(), "{}", json!({ "error": e.to_string() }));
+            } else {
+                eprintln!("error: {e}");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs every command but `lsp`, writing what it prints to `out`
+fn run(cli: &Cli, out: &mut dyn io::Write) -> Outcome {
+    match &cli.command {
+        Command::Validate => validate(cli, out),
+        Command::Fmt { write, check } => fmt(cli, out, *write, *check),
+        Command::Gen { list: true, .. } => list_backends(cli, out),
+        Command::Gen { backend, out: dir, options, .. } => generate(cli, out, backend.as_deref().unwrap_or_default(), dir, options),
+        Command::Doc { format, out: dir, options } => {
+            let backend = match format {
+                DocFormat::Markdown => "markdown",
+                DocFormat::Html => "html",
+            };
+            generate(cli, out, backend, dir, options)
+        }
+        Command::Decode { payload, hex, direction } => decode(cli, out, payload, hex, *direction),
+        Command::Encode { payload, fields, direction, payload_only } => encode(cli, out, payload, fields, *direction, *payload_only),
+        Command::Size => size(cli, out),
+        Command::Migrate { check } => migrate(cli, out, *check),
+        Command::Diff { old, check } => diff(cli, out, old, *check),
+        Command::Publish { registry } => publish(cli, out, registry.as_deref()),
+        Command::Install { registry, cache } => install(cli, out, registry.as_deref(), cache.as_deref()),
+        Command::Schema => print_json(out, &openpid::config::json_schema()).map(|()| true),
+        #[cfg(feature = "lsp")]
+        Command::Lsp => unreachable!("served by main"),
+    }
+}
+
+/// What a command returns: whether it succeeded, or an error that stopped it
+type Outcome = Result<bool, Box<dyn Error>>;
+
+fn print_json(out: &mut dyn io::Write, value: &impl Serialize) -> Result<(), Box<dyn Error>> {
+    writeln!(out, "{}", serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+fn read(cli: &Cli) -> Result<String, Box<dyn Error>> {
+    std::fs::read_to_string(&cli.file).map_err(|e| format!("Couldn't read {}: {e}", cli.file.display()).into())
+}
+
+/// The document with everything it imports
+fn load(cli: &Cli) -> Result<OpenPID, Box<dyn Error>> {
+    Ok(openpid::include::load(&cli.file)?)
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Tx => "tx",
+        Direction::Rx => "rx",
+    }
+}
+
+#[derive(Serialize)]
+struct Diagnostic {
+    severity: &'static str,
+    message: String,
+
+    /// 1-based, for problems tied to a place in the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+}
+
+fn diagnostics(source: &str, path: &Path) -> Vec<Diagnostic> {
+    Analysis::for_file(source, path)
+        .diagnostics
+        .into_iter()
+        .map(|d| {
+            let severity = match d.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            let (line, column) = match d.span {
+                Some(span) => {
+                    let before = &source[..span.start];
+                    (Some(before.matches('\n').count() + 1), Some(before.rsplit('\n').next().unwrap_or_default().chars().count() + 1))
+                }
+                None => (None, None),
+            };
+            Diagnostic { severity, message: d.message, line, column }
+        })
+        .collect()
+}
+
+fn validate(cli: &Cli, out: &mut dyn io::Write) -> Outcome {
+    let found = diagnostics(&read(cli)?, &cli.file);
+    let valid = !found.iter().any(|d| d.severity == "error");
+    if cli.json {
+        print_json(out, &json!({ "file": cli.file, "valid": valid, "diagnostics": found }))?;
+    } else {
+        for d in &found {
+            match (d.line, d.column) {
+                (Some(line), Some(column)) => eprintln!("{}:{line}:{column}: {}: {}", cli.file.display(), d.severity, d.message),
+                _ => eprintln!("{}: {}: {}", cli.file.display(), d.severity, d.message),
+            }
+        }
+        if valid {
+            eprintln!("{} is valid", cli.file.display());
+        }
+    }
+    Ok(valid)
+}
+
+fn fmt(cli: &Cli, out: &mut dyn io::Write, write: bool, check: bool) -> Outcome {
+    let source = read(cli)?;
+    let formatted = openpid::format::format(&source)?;
+    let unchanged = formatted == source;
+    if check {
+        if cli.json {
+            print_json(out, &json!({ "file": cli.file, "formatted": unchanged }))?;
+        } else if !unchanged {
+            eprintln!("{} isn't formatted", cli.file.display());
+        }
+        return Ok(unchanged);
+    }
+    if write {
+        if !unchanged {
+            std::fs::write(&cli.file, &formatted)?;
+        }
+        if cli.json {
+            print_json(out, &json!({ "file": cli.file, "changed": !unchanged }))?;
+        }
+    } else if cli.json {
+        print_json(out, &json!({ "file": cli.file, "text": formatted }))?;
+    } else {
+        write!(out, "{formatted}")?;
+    }
+    Ok(true)
+}
+
+fn list_backends(cli: &Cli, out: &mut dyn io::Write) -> Outcome {
+    let registry = Registry::builtin();
+    if cli.json {
+        let backends = registry
+            .iter()
+            .map(|b| {
+                let options = b.options.iter().map(|o| json!({ "name": o.name, "description": o.description, "default": o.default })).collect::<Vec<_>>();
+                json!({ "name": b.name, "description": b.description, "options": options })
+            })
+            .collect::<Vec<_>>();
+        print_json(out, &backends)?;
+        return Ok(true);
+    }
+    for backend in registry.iter() {
+        writeln!(out, "{:<12} {}", backend.name, backend.description)?;
+        let width = backend.options.iter().map(|o| o.name.len()).max().unwrap_or_default();
+        for option in backend.options {
+            let default = option.default.map(|d| format!(" (default: {d})")).unwrap_or_default();
+            writeln!(out, "    -O {:<width$}  {}{default}", option.name, option.description)?;
+        }
+    }
+    Ok(true)
+}
+
+fn generate(cli: &Cli, out: &mut dyn io::Write, backend: &str, dir: &PathBuf, options: &[String]) -> Outcome {
+    let ir = load(cli)?.to_ir()?;
+    let mut parsed = BackendOptions::new();
+    for option in options {
+        parsed.parse(option);
+    }
+    let generator = Registry::builtin().create(backend, &parsed)?;
+
+    // generate everything before touching the disk, so a failure doesn't leave half an output
+    let mut tree = VirtualTree::new();
+    generator.generate(&ir, &mut tree)?;
+    tree.write_to(&mut DirectorySink::new(dir))?;
+
+    let files = tree.paths().collect::<Vec<_>>();
+    if cli.json {
+        print_json(out, &json!({ "backend": backend, "out": dir, "files": files }))?;
+    } else {
+        for file in files {
+            writeln!(out, "{}", dir.join(file).display())?;
+        }
+    }
+    Ok(true)
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let digits = hex.trim().trim_start_matches("0x").chars().filter(|c| !c.is_whitespace() && *c != ':').collect::<Vec<_>>();
+    if digits.len() % 2 != 0 {
+        return Err(format!("\"{hex}\" has an odd number of hex digits").into());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).map_err(|_| format!("\"{hex}\" isn't hex").into()))
+        .collect()
+}
+
+/// Picks the direction a payload name belongs to, complaining if it's missing or ambiguous
+fn direction_of(doc: &OpenPID, name: &str, given: Option<Dir>) -> Result<Direction, Box<dyn Error>> {
+    if let Some(given) = given {
+        return Ok(given.into());
+    }
+    match (doc.payloads.tx.contains_key(name), doc.payloads.rx.contains_key(name)) {
+        (true, true) => Err(format!("\"{name}\" is both a TX and an RX payload, pick one with --direction").into()),
+        (true, false) => Ok(Direction::Tx),
+        (false, true) => Ok(Direction::Rx),
+        (false, false) => Err(format!("No payload named \"{name}\"").into()),
+    }
+}
+
+fn print_payload(out: &mut dyn io::Write, payload: &DecodedPayload, offset: Option<Span>) -> io::Result<()> {
+    let width = payload.fields.iter().map(|(name, _)| name.len()).max().unwrap_or_default();
+    for (name, value) in payload.fields.iter() {
+        let span = payload.span_of(name).map(|s| offset.map_or(s, |o| s.offset_by(o.offset_bits)));
+        let bytes = span.map(|s| format!("  [{:?}]", s.byte_range())).unwrap_or_default();
+        writeln!(out, "  {name:<width$} = {value}{bytes}")?;
+    }
+    Ok(())
+}
+
+/// `decode --json` output. A struct rather than `json!`, which would sort the fields by name
+#[derive(Serialize)]
+struct DecodeReport<'a> {
+    direction: &'static str,
+
+    /// The frame's metadata, when decoding a frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a Fields>,
+
+    payload: &'a DecodedPayload,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_span: Option<Span>,
+}
+
+fn decode(cli: &Cli, out: &mut dyn io::Write, payload: &str, hex: &str, direction: Option<Dir>) -> Outcome {
+    let doc = load(cli)?;
+    let data = parse_hex(hex)?;
+
+    if payload != "auto" {
+        let direction = direction_of(&doc, payload, direction)?;
+        let decoded = doc.decode_payload(direction, payload, &data)?;
+        if cli.json {
+            print_json(out, &DecodeReport { direction: direction_name(direction), metadata: None, payload: &decoded, payload_span: None })?;
+        } else {
+            writeln!(out, "{direction} {}", decoded.name)?;
+            print_payload(out, &decoded, None)?;
+        }
+        return Ok(true);
+    }
+
+    let directions = match direction {
+        Some(d) => vec![d.into()],
+        None => vec![Direction::Rx, Direction::Tx],
+    };
+    let mut first_error = None;
+    for direction in directions {
+        let frame = match doc.decode_frame(direction, &data) {
+            Ok(frame) => frame,
+            Err(e) => {
+                first_error.get_or_insert(e);
+                continue;
+            }
+        };
+        if cli.json {
+            let report = DecodeReport { direction: direction_name(direction), metadata: Some(&frame.metadata), payload: &frame.payload, payload_span: Some(frame.payload_span) };
+            print_json(out, &report)?;
+        } else {
+            let metadata = frame.metadata.iter().fold(String::new(), |mut out, (name, value)| {
+                let _ = write!(out, ", {name} = {value}");
+                out
+            });
+            writeln!(out, "{direction} {} (payload at bytes {:?}{metadata})", frame.payload.name, frame.payload_span.byte_range())?;
+            print_payload(out, &frame.payload, Some(frame.payload_span))?;
+        }
+        return Ok(true);
+    }
+    Err(first_error.map(Into::into).unwrap_or_else(|| "Nothing to decode".into()))
+}
+
+fn encode(cli: &Cli, out: &mut dyn io::Write, payload: &str, fields: &str, direction: Option<Dir>, payload_only: bool) -> Outcome {
+    let doc = load(cli)?;
+    let direction = direction_of(&doc, payload, direction)?;
+    let fields: Fields = serde_json::from_str(fields).map_err(|e| format!("Couldn't read the fields: {e}"))?;
+    let bytes = match payload_only || doc.uart.is_none() {
+        true => doc.encode_payload(direction, payload, &fields)?,
+        false => doc.encode_frame(direction, payload, &fields)?,
+    };
+    let hex = bytes.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{b:02x}");
+        out
+    });
+    if cli.json {
+        print_json(out, &json!({ "direction": direction_name(direction), "payload": payload, "hex": hex }))?;
+    } else {
+        writeln!(out, "{hex}")?;
+    }
+    Ok(true)
+}
+
+fn size_json(size: Size) -> serde_json::Value {
+    json!({ "min_bits": size.min_bits(), "max_bits": size.max_bits() })
+}
+
+fn size(cli: &Cli, out: &mut dyn io::Write) -> Outcome {
+    let ir = load(cli)?.to_ir()?;
+    let frame = |direction: Direction, size: Size| {
+        let framing = ir.framing.as_ref()?;
+        Some(size + Size::Fixed(framing.format(direction).iter().map(|e| e.envelope_bits()).sum()))
+    };
+
+    if cli.json {
+        let structs = ir.structs.iter().map(|s| json!({ "name": s.name.raw(), "size": size_json(s.size) })).collect::<Vec<_>>();
+        let payloads = ir
+            .all_payloads()
+            .map(|p| {
+                json!({
+                    "direction": direction_name(p.direction),
+                    "name": p.name.raw(),
+                    "size": size_json(p.size),
+                    "frame": frame(p.direction, p.size).map(size_json),
+                })
+            })
+            .collect::<Vec<_>>();
+        print_json(out, &json!({ "structs": structs, "payloads": payloads }))?;
+        return Ok(true);
+    }
+
+    let width = ir.structs.iter().map(|s| s.name.raw().len()).chain(ir.all_payloads().map(|p| p.name.raw().len() + 3)).max().unwrap_or_default();
+    for s in &ir.structs {
+        writeln!(out, "{:<width$}  {}", s.name.raw(), s.size)?;
+    }
+    if !ir.structs.is_empty() {
+        writeln!(out)?;
+    }
+    for p in ir.all_payloads() {
+        let name = format!("{} {}", p.direction, p.name.raw());
+        let size = p.size.to_string();
+        match frame(p.direction, p.size) {
+            Some(frame) => writeln!(out, "{name:<width$}  {size:<24}  framed: {frame}")?,
+            None => writeln!(out, "{name:<width$}  {size}")?,
+        }
+    }
+    Ok(true)
+}
+
+/// The directory holding the document, its package manifest and its lockfile
+fn package_dir(cli: &Cli) -> &Path {
+    cli.file.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."))
+}
+
+fn registry(given: Option<&Path>) -> Result<LocalRegistry, Box<dyn Error>> {
+    let root = given.map(PathBuf::from).or_else(|| std::env::var_os("OPENPID_REGISTRY").map(PathBuf::from));
+    Ok(LocalRegistry::new(root.ok_or("No registry given, use --registry or set OPENPID_REGISTRY")?))
+}
+
+fn publish(cli: &Cli, out: &mut dyn io::Write, registry_dir: Option<&Path>) -> Outcome {
+    let manifest = package::Manifest::load(package_dir(cli))?;
+    let entry = registry(registry_dir)?.publish(package_dir(cli))?;
+    if cli.json {
+        print_json(out, &json!({ "name": manifest.package.name, "version": entry.version.to_string(), "checksum": entry.checksum }))?;
+    } else {
+        eprintln!("Published {} {} ({})", manifest.package.name, entry.version, entry.checksum);
+    }
+    Ok(true)
+}
+
+fn install(cli: &Cli, out: &mut dyn io::Write, registry_dir: Option<&Path>, cache: Option<&Path>) -> Outcome {
+    let cache = cache.map(PathBuf::from).or_else(package::default_cache).ok_or("No cache directory, use --cache or set OPENPID_CACHE")?;
+    let lock = package::install(package_dir(cli), &registry(registry_dir)?, &cache)?;
+    if cli.json {
+        let packages = lock.packages.iter().map(|p| json!({ "name": p.name, "version": p.version.to_string(), "checksum": p.checksum })).collect::<Vec<_>>();
+        print_json(out, &json!({ "packages": packages }))?;
+    } else {
+        for p in &lock.packages {
+            eprintln!("Installed {} {}", p.name, p.version);
+        }
+    }
+    Ok(true)
+}
+
+fn migrate(cli: &Cli, out: &mut dyn io::Write, check: bool) -> Outcome {
+    let source = read(cli)?;
+    let (migrated, from) = openpid::migrate::migrate(&source)?;
+    let current = migrated == source;
+    if cli.json {
+        let report = json!({ "file": cli.file, "from": from.to_string(), "to": CURRENT_VERSION.to_string(), "changed": !current });
+        print_json(out, &report)?;
+    } else if current {
+        eprintln!("{} is already OpenPID {CURRENT_VERSION}", cli.file.display());
+    } else {
+        let verb = if check { "needs migrating" } else { "migrated" };
+        eprintln!("{} is OpenPID {from}, {verb} to {CURRENT_VERSION}", cli.file.display());
+    }
+    if check {
+        return Ok(current);
+    }
+    if !current {
+        std::fs::write(&cli.file, migrated)?;
+    }
+    Ok(true)
+}
+
+fn diff(cli: &Cli, out: &mut dyn io::Write, old_file: &Path, check: bool) -> Outcome {
+    let old = openpid::include::load(old_file)?;
+    let new = load(cli)?;
+    let diff = openpid::diff::diff(&old.to_ir()?, &new.to_ir()?);
+    let bump = diff.bump();
+    let suggested = old.doc_version.as_deref().and_then(|v| diff.suggested_version(v));
+    let current = new.doc_version.as_deref().and_then(|v| semver::Version::parse(v.trim_start_matches('v')).ok());
+    let enough = match (&suggested, &current) {
+        (Some(suggested), Some(current)) => current >= suggested,
+        _ => bump.is_none(),
+    };
+
+    if cli.json {
+        let report = json!({
+            "changes": diff.changes,
+            "breaking": diff.is_breaking(),
+            "bump": bump,
+            "old_version": old.doc_version,
+            "new_version": new.doc_version,
+            "suggested_version": suggested.as_ref().map(ToString::to_string),
+        });
+        print_json(out, &report)?;
+    } else {
+        for change in &diff.changes {
+            writeln!(out, "{:<10}  {}: {} (wire: {}, API: {})", change.impact(), change.path, change.description, change.wire, change.api)?;
+        }
+        match (bump, &suggested) {
+            (None, _) => writeln!(out, "No changes")?,
+            (Some(bump), Some(suggested)) => writeln!(out, "\nSuggested bump: {bump}, {} to {suggested}", old.doc_version.as_deref().unwrap_or_default())?,
+            (Some(bump), None) => writeln!(out, "\nSuggested bump: {bump}")?,
+        }
+        if check && !enough {
+            let current = new.doc_version.as_deref().unwrap_or("missing");
+            eprintln!("doc_version is {current}, but the changes need {}", suggested.map_or_else(|| format!("a {} bump", bump.unwrap_or(Bump::Patch)), |v| v.to_string()));
+        }
+    }
+    Ok(!check || enough)
+}
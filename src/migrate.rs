@@ -0,0 +1,119 @@
+//! Versions of the OpenPID spec. A document's `openpid_version` says which one it's written for,
+//! and documents without one are read as the oldest. 0.2 only added `openpid_version` itself, so
+//! every known version reads as the current model unchanged, and `openpid migrate` just records
+//! [CURRENT_VERSION]. Documents written for a version newer than this crate knows are refused
+//! rather than misread
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use toml_edit::{DocumentMut, Item};
+
+/// A version of the spec. Patch versions don't change the model, so only these two parts count
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SpecVersion {
+    pub major: u64,
+    pub minor: u64,
+}
+
+/// The spec version this crate's model is
+pub const CURRENT_VERSION: SpecVersion = SpecVersion { major: 0, minor: 2 };
+
+/// The version of documents without an `openpid_version`
+pub const OLDEST_VERSION: SpecVersion = SpecVersion { major: 0, minor: 1 };
+
+impl Display for SpecVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for SpecVersion {
+    type Err = ();
+
+    /// Reads `0.2`, `0.2.1` or `v0.2`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim_start_matches('v').split('.').map(|p| p.parse::<u64>().map_err(|_| ()));
+        let (major, minor) = (parts.next().ok_or(())??, parts.next().ok_or(())??);
+        match (parts.next(), parts.next()) {
+            (None | Some(Ok(_)), None) => Ok(SpecVersion { major, minor }),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Syntax(toml_edit::TomlError),
+
+    /// `openpid_version` isn't a version
+    BadVersion { found: String },
+
+    /// Written for a version of the spec newer than this crate knows
+    Future { found: SpecVersion },
+
+    /// The document doesn't fit the model
+    Invalid(toml::de::Error),
+}
+
+impl Display for MigrateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrateError::Syntax(e) => write!(f, "{e}"),
+            MigrateError::BadVersion { found } => write!(f, "openpid_version {found} isn't a version such as \"{CURRENT_VERSION}\""),
+            MigrateError::Future { found } => {
+                write!(f, "The document is written for OpenPID {found}, newer than the {CURRENT_VERSION} this openpid understands. Update openpid to read it")
+            }
+            MigrateError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MigrateError {}
+
+/// The version `doc` is written for
+pub fn version(doc: &DocumentMut) -> Result<SpecVersion, MigrateError> {
+    let Some(item) = doc.get("openpid_version") else { return Ok(OLDEST_VERSION) };
+    let found = item.as_str().and_then(|s| s.parse().ok()).ok_or_else(|| MigrateError::BadVersion { found: item.to_string().trim().to_owned() })?;
+    if found > CURRENT_VERSION {
+        return Err(MigrateError::Future { found });
+    }
+    Ok(found)
+}
+
+/// Parses a document or [library](crate::config::Library) written for any known version into
+/// the current model
+pub fn parse<T: DeserializeOwned>(source: &str) -> Result<T, MigrateError> {
+    let doc = source.parse::<DocumentMut>().map_err(MigrateError::Syntax)?;
+    version(&doc)?;
+    toml::from_str(source).map_err(MigrateError::Invalid)
+}
+
+/// Rewrites a document for [CURRENT_VERSION], recording it in `openpid_version`. Also returns the
+/// version it was written for
+pub fn migrate(source: &str) -> Result<(String, SpecVersion), MigrateError> {
+    let mut doc = source.parse::<DocumentMut>().map_err(MigrateError::Syntax)?;
+    let from = version(&doc)?;
+    let version = CURRENT_VERSION.to_string();
+    match doc.get_mut("openpid_version").and_then(Item::as_value_mut) {
+        Some(value) => {
+            let decor = value.decor().clone();
+            *value = version.into();
+            *value.decor_mut() = decor;
+        }
+        None => {
+            doc.insert("openpid_version", toml_edit::value(version));
+            // the version goes first, under any comments heading the file, like a `#:schema` line
+            let first = doc.iter_mut().find(|(key, item)| key != "openpid_version" && (item.is_table() || item.is_array_of_tables()));
+            if let Some((_, Item::Table(table))) = first {
+                let heading = table.decor().prefix().and_then(|p| p.as_str()).unwrap_or_default().trim_end_matches('\n').to_owned();
+                table.decor_mut().set_prefix("\n");
+                if let Some(mut key) = doc.key_mut("openpid_version") {
+                    key.leaf_decor_mut().set_prefix(if heading.trim().is_empty() { String::new() } else { format!("{heading}\n") });
+                }
+            }
+        }
+    }
+    Ok((doc.to_string(), from))
+}
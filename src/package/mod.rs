@@ -0,0 +1,277 @@
+//! Packages of OpenPID documents, such as a product line's shared envelope or a set of common
+//! structs, that documents [import](crate::include) by name. A package is a directory with a
+//! manifest, [MANIFEST_FILE], next to its `openpid.toml`:
+//!
+//! ```toml
+//! [package]
+//! name = "imu_structs"
+//! version = "1.2.0"
+//! license = "MIT"
+//!
+//! [dependencies]
+//! envelope = "^1.0"
+//! ```
+//!
+//! A device's own manifest lists the packages it depends on. [install] [resolves](resolve) them
+//! against a [Registry], records the versions in [LOCK_FILE], fetches each one into a cache,
+//! verifies its checksum, and copies it into [PACKAGES_DIR] where imports find it. Every package
+//! is installed at one version, so a package and the device importing it agree on what a shared
+//! struct is
+
+pub mod registry;
+pub mod resolve;
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub use crate::include::PACKAGES_DIR;
+pub use registry::{IndexEntry, LocalRegistry, Registry};
+pub use resolve::resolve;
+
+/// The manifest's file name
+pub const MANIFEST_FILE: &str = "openpid-package.toml";
+
+/// The lockfile's file name, next to the manifest
+pub const LOCK_FILE: &str = "openpid.lock";
+
+#[derive(Debug)]
+pub enum PackageError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, error: toml::de::Error },
+
+    /// Package names are directory names, so they're limited to letters, digits, `_` and `-`
+    BadName { name: String },
+
+    /// The registry has no package by this name
+    NotFound { name: String },
+
+    /// No version of `name` satisfies what `required_by` asks for, alongside the other
+    /// requirements on it
+    NoMatch { name: String, requirement: VersionReq, required_by: String },
+
+    /// A package's contents don't hash to what the registry or the lockfile says they should
+    Checksum { name: String, version: Version, expected: String, found: String },
+
+    /// Publishing a version that's already in the registry
+    Exists { name: String, version: Version },
+
+    /// A package without an `openpid.toml`
+    NoDocument { path: PathBuf },
+
+    Toml(toml::ser::Error),
+}
+
+impl Display for PackageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackageError::Io { path, error } => write!(f, "Couldn't access {}: {error}", path.display()),
+            PackageError::Parse { path, error } => write!(f, "Couldn't parse {}: {error}", path.display()),
+            PackageError::BadName { name } => write!(f, "\"{name}\" isn't a valid package name. Use letters, digits, _ and -"),
+            PackageError::NotFound { name } => write!(f, "The registry has no package \"{name}\""),
+            PackageError::NoMatch { name, requirement, required_by } => {
+                write!(f, "No version of \"{name}\" matches {requirement}, required by {required_by}, and everything else required of it")
+            }
+            PackageError::Checksum { name, version, expected, found } => {
+                write!(f, "{name} {version} has checksum {found}, but {expected} was expected. The package has changed since it was published")
+            }
+            PackageError::Exists { name, version } => write!(f, "{name} {version} is already published"),
+            PackageError::NoDocument { path } => write!(f, "{} has no openpid.toml", path.display()),
+            PackageError::Toml(e) => write!(f, "Couldn't write TOML: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PackageError {}
+
+fn io(path: &Path) -> impl FnOnce(std::io::Error) -> PackageError + '_ {
+    move |error| PackageError::Io { path: path.to_owned(), error }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub package: PackageInfo,
+
+    /// Packages this one imports, and the versions it works with
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, VersionReq>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PackageInfo {
+    pub name: String,
+    pub version: Version,
+
+    /// The device the package describes. Packages of shared definitions don't have one
+    pub device: Option<String>,
+
+    /// An SPDX license expression
+    pub license: Option<String>,
+
+    pub description: Option<String>,
+}
+
+impl Manifest {
+    /// Reads the manifest in the package directory `dir`
+    pub fn load(dir: &Path) -> Result<Self, PackageError> {
+        let path = dir.join(MANIFEST_FILE);
+        let source = std::fs::read_to_string(&path).map_err(io(&path))?;
+        let manifest: Manifest = toml::from_str(&source).map_err(|error| PackageError::Parse { path, error })?;
+        check_name(&manifest.package.name)?;
+        manifest.dependencies.keys().try_for_each(|name| check_name(name))?;
+        Ok(manifest)
+    }
+}
+
+pub(crate) fn check_name(name: &str) -> Result<(), PackageError> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid { Ok(()) } else { Err(PackageError::BadName { name: name.to_owned() }) }
+}
+
+/// The exact versions a device's dependencies resolved to, so every checkout installs the same
+/// packages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Lockfile {
+    #[serde(rename = "package", default)]
+    pub packages: Vec<LockedPackage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: Version,
+    pub checksum: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
+}
+
+impl Lockfile {
+    /// Reads the lockfile in `dir`, which is empty if there isn't one
+    pub fn load(dir: &Path) -> Result<Self, PackageError> {
+        let path = dir.join(LOCK_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(source) => toml::from_str(&source).map_err(|error| PackageError::Parse { path, error }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Lockfile::default()),
+            Err(error) => Err(PackageError::Io { path, error }),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LockedPackage> {
+        self.packages.iter().find(|p| p.name == name)
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<(), PackageError> {
+        let path = dir.join(LOCK_FILE);
+        let text = toml::to_string(self).map_err(PackageError::Toml)?;
+        std::fs::write(&path, format!("# Written by `openpid install`. Don't edit it by hand\n\n{text}")).map_err(io(&path))
+    }
+}
+
+/// Whether a file in a package directory is part of the package: its documents, manifest,
+/// readme and license, but not installed packages, the lockfile or hidden files
+fn packaged(relative: &Path) -> bool {
+    let Some(name) = relative.file_name().and_then(|n| n.to_str()) else { return false };
+    let hidden = relative.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+    let installed = relative.starts_with(PACKAGES_DIR);
+    let wanted = name.ends_with(".toml") || name.starts_with("README") || name.starts_with("LICENSE");
+    wanted && !hidden && !installed && name != LOCK_FILE
+}
+
+/// The package's files relative to `dir`, in a stable order
+pub fn files(dir: &Path) -> Result<Vec<PathBuf>, PackageError> {
+    fn walk(dir: &Path, relative: &Path, found: &mut Vec<PathBuf>) -> Result<(), PackageError> {
+        let path = dir.join(relative);
+        for entry in std::fs::read_dir(&path).map_err(io(&path))? {
+            let entry = entry.map_err(io(&path))?;
+            let relative = relative.join(entry.file_name());
+            if entry.file_type().map_err(io(&path))?.is_dir() {
+                walk(dir, &relative, found)?;
+            } else if packaged(&relative) {
+                found.push(relative);
+            }
+        }
+        Ok(())
+    }
+    let mut found = Vec::new();
+    walk(dir, Path::new(""), &mut found)?;
+    found.sort();
+    Ok(found)
+}
+
+/// A SHA-256 over the package's files' paths and contents, as `sha256:` and hex
+pub fn checksum(dir: &Path) -> Result<String, PackageError> {
+    let mut hasher = Sha256::new();
+    for relative in files(dir)? {
+        let path = dir.join(&relative);
+        let contents = std::fs::read(&path).map_err(io(&path))?;
+        // paths are hashed with `/` whatever the platform, so checksums match everywhere
+        let name = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+    }
+    let hex = hasher.finalize().iter().map(|b| format!("{b:02x}")).collect::<String>();
+    Ok(format!("sha256:{hex}"))
+}
+
+/// Copies the package's files from `from` into `to`, replacing whatever was there
+pub(crate) fn copy_package(from: &Path, to: &Path) -> Result<(), PackageError> {
+    if to.exists() {
+        std::fs::remove_dir_all(to).map_err(io(to))?;
+    }
+    for relative in files(from)? {
+        let target = to.join(&relative);
+        std::fs::create_dir_all(target.parent().unwrap_or(to)).map_err(io(&target))?;
+        std::fs::copy(from.join(&relative), &target).map_err(io(&target))?;
+    }
+    Ok(())
+}
+
+/// Where fetched packages are kept: `OPENPID_CACHE`, or `.cache/openpid` in the home directory
+pub fn default_cache() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("OPENPID_CACHE") {
+        return Some(dir.into());
+    }
+    std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")).map(|home| Path::new(&home).join(".cache").join("openpid"))
+}
+
+/// Fetches `package` into `cache` unless it's already there, and checks its checksum
+pub fn fetch(package: &LockedPackage, registry: &dyn Registry, cache: &Path) -> Result<PathBuf, PackageError> {
+    let dir = cache.join(&package.name).join(package.version.to_string());
+    if !dir.is_dir() {
+        // fetched beside its final place and moved there, so a failed fetch isn't cached
+        let partial = cache.join(&package.name).join(format!("{}.partial", package.version));
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial).map_err(io(&partial))?;
+        }
+        std::fs::create_dir_all(&partial).map_err(io(&partial))?;
+        registry.fetch(&package.name, &package.version, &partial)?;
+        std::fs::rename(&partial, &dir).map_err(io(&dir))?;
+    }
+    let found = checksum(&dir)?;
+    if found != package.checksum {
+        return Err(PackageError::Checksum { name: package.name.clone(), version: package.version.clone(), expected: package.checksum.clone(), found });
+    }
+    Ok(dir)
+}
+
+/// Resolves the dependencies of the package in `dir`, preferring the versions in its lockfile,
+/// then writes the lockfile and installs every package into [PACKAGES_DIR]
+pub fn install(dir: &Path, registry: &dyn Registry, cache: &Path) -> Result<Lockfile, PackageError> {
+    let manifest = Manifest::load(dir)?;
+    let lock = resolve(&manifest, registry, &Lockfile::load(dir)?)?;
+    let packages = dir.join(PACKAGES_DIR);
+    for package in &lock.packages {
+        let fetched = fetch(package, registry, cache)?;
+        copy_package(&fetched, &packages.join(&package.name))?;
+    }
+    lock.write(dir)?;
+    Ok(lock)
+}
@@ -0,0 +1,112 @@
+//! Where packages come from. A [Registry] lists a package's published versions with their
+//! checksums and dependencies, and fetches one version's files. [LocalRegistry] is a plain
+//! directory, which works offline and can be shared over a network drive or synced like any
+//! other files:
+//!
+//! ```text
+//! index/<name>.toml               every published version of <name>
+//! packages/<name>/<version>/      its files, as published
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+use super::{check_name, checksum, copy_package, io, Manifest, PackageError};
+
+/// One published version of a package
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub version: Version,
+
+    /// The [checksum](super::checksum) of its files when they were published
+    pub checksum: String,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, VersionReq>,
+}
+
+pub trait Registry {
+    /// Every published version of `name`, in any order
+    fn versions(&self, name: &str) -> Result<Vec<IndexEntry>, PackageError>;
+
+    /// Writes the files of `name` at `version` into the empty directory `into`
+    fn fetch(&self, name: &str, version: &Version, into: &Path) -> Result<(), PackageError>;
+}
+
+/// The index file of one package
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    #[serde(rename = "version", default)]
+    versions: Vec<IndexEntry>,
+}
+
+/// A registry in a local directory
+#[derive(Debug, Clone)]
+pub struct LocalRegistry {
+    pub root: PathBuf,
+}
+
+impl LocalRegistry {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalRegistry { root: root.into() }
+    }
+
+    fn index_path(&self, name: &str) -> PathBuf {
+        self.root.join("index").join(format!("{name}.toml"))
+    }
+
+    fn package_dir(&self, name: &str, version: &Version) -> PathBuf {
+        self.root.join("packages").join(name).join(version.to_string())
+    }
+
+    fn index(&self, name: &str) -> Result<Option<Index>, PackageError> {
+        check_name(name)?;
+        let path = self.index_path(name);
+        match std::fs::read_to_string(&path) {
+            Ok(source) => toml::from_str(&source).map(Some).map_err(|error| PackageError::Parse { path, error }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(PackageError::Io { path, error }),
+        }
+    }
+
+    /// Publishes the package in `dir` at the version its manifest names. Published versions
+    /// can't be replaced, since lockfiles hold their checksums
+    pub fn publish(&self, dir: &Path) -> Result<IndexEntry, PackageError> {
+        let manifest = Manifest::load(dir)?;
+        let (name, version) = (&manifest.package.name, &manifest.package.version);
+        if !dir.join("openpid.toml").is_file() {
+            return Err(PackageError::NoDocument { path: dir.to_owned() });
+        }
+        let mut index = self.index(name)?.unwrap_or_default();
+        if index.versions.iter().any(|v| &v.version == version) {
+            return Err(PackageError::Exists { name: name.clone(), version: version.clone() });
+        }
+
+        let target = self.package_dir(name, version);
+        copy_package(dir, &target)?;
+        let entry = IndexEntry { version: version.clone(), checksum: checksum(&target)?, dependencies: manifest.dependencies };
+        index.versions.push(entry.clone());
+
+        let path = self.index_path(name);
+        std::fs::create_dir_all(path.parent().unwrap_or(&self.root)).map_err(io(&path))?;
+        std::fs::write(&path, toml::to_string(&index).map_err(PackageError::Toml)?).map_err(io(&path))?;
+        Ok(entry)
+    }
+}
+
+impl Registry for LocalRegistry {
+    fn versions(&self, name: &str) -> Result<Vec<IndexEntry>, PackageError> {
+        self.index(name)?.map(|index| index.versions).ok_or_else(|| PackageError::NotFound { name: name.to_owned() })
+    }
+
+    fn fetch(&self, name: &str, version: &Version, into: &Path) -> Result<(), PackageError> {
+        let dir = self.package_dir(name, version);
+        if !dir.is_dir() {
+            return Err(PackageError::NotFound { name: format!("{name} {version}") });
+        }
+        copy_package(&dir, into)
+    }
+}
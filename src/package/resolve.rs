@@ -0,0 +1,100 @@
+//! Picks one version of every package a manifest depends on, directly or through other packages,
+//! that satisfies every requirement on it. Newer versions are tried first, except that a version
+//! in the lockfile is kept while it still satisfies everything, so installing again doesn't
+//! upgrade anything unasked. A choice that leads to a conflict further down is undone and the
+//! next version tried
+
+use std::collections::{BTreeMap, VecDeque};
+
+use semver::VersionReq;
+
+use super::{IndexEntry, LockedPackage, Lockfile, Manifest, PackageError, Registry};
+
+#[derive(Clone)]
+struct Requirement {
+    name: String,
+    requirement: VersionReq,
+    required_by: String,
+}
+
+struct Resolver<'a> {
+    registry: &'a dyn Registry,
+    locked: &'a Lockfile,
+
+    /// Each package's versions, fetched from the registry once
+    versions: BTreeMap<String, Vec<IndexEntry>>,
+}
+
+impl Resolver<'_> {
+    /// The versions that satisfy `requirement`, in the order to try them
+    fn candidates(&mut self, requirement: &Requirement) -> Result<Vec<IndexEntry>, PackageError> {
+        if !self.versions.contains_key(&requirement.name) {
+            let versions = self.registry.versions(&requirement.name)?;
+            self.versions.insert(requirement.name.clone(), versions);
+        }
+        let mut candidates = self.versions[&requirement.name].iter().filter(|e| requirement.requirement.matches(&e.version)).cloned().collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.version.cmp(&a.version));
+        if let Some(locked) = self.locked.get(&requirement.name) {
+            if let Some(i) = candidates.iter().position(|e| e.version == locked.version) {
+                let entry = candidates.remove(i);
+                candidates.insert(0, entry);
+            }
+        }
+        Ok(candidates)
+    }
+
+    fn solve(&mut self, mut pending: VecDeque<Requirement>, chosen: &mut BTreeMap<String, IndexEntry>) -> Result<(), PackageError> {
+        let Some(next) = pending.pop_front() else { return Ok(()) };
+        let no_match = || PackageError::NoMatch { name: next.name.clone(), requirement: next.requirement.clone(), required_by: next.required_by.clone() };
+        if let Some(entry) = chosen.get(&next.name) {
+            return match next.requirement.matches(&entry.version) {
+                true => self.solve(pending, chosen),
+                false => Err(no_match()),
+            };
+        }
+
+        let mut error = no_match();
+        for entry in self.candidates(&next)? {
+            let mut pending = pending.clone();
+            let required_by = format!("{} {}", next.name, entry.version);
+            pending.extend(entry.dependencies.iter().map(|(name, requirement)| Requirement {
+                name: name.clone(),
+                requirement: requirement.clone(),
+                required_by: required_by.clone(),
+            }));
+            chosen.insert(next.name.clone(), entry);
+            match self.solve(pending, chosen) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    chosen.remove(&next.name);
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    }
+}
+
+/// Resolves `manifest`'s dependencies, keeping the versions in `locked` where they still fit.
+/// A locked version whose checksum no longer matches the registry's is an error, since the
+/// package has changed under the same version
+pub fn resolve(manifest: &Manifest, registry: &dyn Registry, locked: &Lockfile) -> Result<Lockfile, PackageError> {
+    let root = format!("{} {}", manifest.package.name, manifest.package.version);
+    let pending = manifest
+        .dependencies
+        .iter()
+        .map(|(name, requirement)| Requirement { name: name.clone(), requirement: requirement.clone(), required_by: root.clone() })
+        .collect();
+    let mut chosen = BTreeMap::new();
+    Resolver { registry, locked, versions: BTreeMap::new() }.solve(pending, &mut chosen)?;
+
+    let mut lock = Lockfile::default();
+    for (name, entry) in chosen {
+        if let Some(old) = locked.get(&name).filter(|old| old.version == entry.version && old.checksum != entry.checksum) {
+            return Err(PackageError::Checksum { name, version: entry.version, expected: old.checksum.clone(), found: entry.checksum });
+        }
+        let dependencies = entry.dependencies.into_keys().collect();
+        lock.packages.push(LockedPackage { name, version: entry.version, checksum: entry.checksum, dependencies });
+    }
+    Ok(lock)
+}
@@ -0,0 +1,588 @@
+//! Decoding plans compiled ahead of time from an [OpenPID] document.
+//!
+//! Where [crate::codec] walks the document's segments for every payload it decodes, a
+//! [CompiledCodec] resolves structs, count references and field offsets once. Decoding then
+//! produces borrowed views over the input buffer and never allocates. Payloads are checked once
+//! up front, rejecting what the codec rejects, and fields at a known offset are then read straight
+//! from it.
+
+use std::collections::BTreeMap;
+
+use crate::codec::{check_width, decode_integer, reorder, whole_bytes, CodecError, Direction};
+use crate::prelude::*;
+use openpid_runtime as rt;
+
+/// How many distinct `CountInPacket` fields a single payload may reference. Counts are kept in a
+/// fixed array while decoding, so that views can be copied around without allocating
+pub const MAX_COUNT_FIELDS: usize = 8;
+
+type Registers = [u64; MAX_COUNT_FIELDS];
+type Result<T> = std::result::Result<T, CodecError>;
+
+/// Plans for every payload in a document
+#[derive(Debug, Clone)]
+pub struct CompiledCodec {
+    tx: BTreeMap<String, PayloadPlan>,
+    rx: BTreeMap<String, PayloadPlan>,
+}
+
+impl CompiledCodec {
+    pub fn payload(&self, direction: Direction, name: &str) -> Option<&PayloadPlan> {
+        match direction {
+            Direction::Tx => self.tx.get(name),
+            Direction::Rx => self.rx.get(name),
+        }
+    }
+
+    pub fn payloads(&self, direction: Direction) -> impl Iterator<Item = &PayloadPlan> {
+        match direction {
+            Direction::Tx => self.tx.values(),
+            Direction::Rx => self.rx.values(),
+        }
+    }
+}
+
+/// A payload's decoding plan
+#[derive(Debug, Clone)]
+pub struct PayloadPlan {
+    name: String,
+    plan: SegmentsPlan,
+}
+
+#[derive(Debug, Clone)]
+struct SegmentsPlan {
+    fields: Vec<FieldPlan>,
+    size: Size,
+
+    /// Whether any field, including inside structs and arrays, is a const or a string, which
+    /// decoding can't accept without looking at
+    checks_contents: bool,
+}
+
+#[derive(Debug, Clone)]
+struct FieldPlan {
+    name: String,
+
+    /// Dotted path from the payload, for errors
+    path: String,
+
+    /// Offset from the start of the enclosing struct, when every preceding field is fixed size
+    offset_bits: Option<usize>,
+
+    /// Bits this field always takes up, if that's known ahead of time
+    fixed_bits: Option<usize>,
+
+    kind: FieldKind,
+
+    /// Count register this field's value is stored to, if a later field is counted by it
+    register: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+enum FieldKind {
+    Int { bits: u32, signing: Signing, endianness: Endianness },
+    Float { bits: u32, endianness: Endianness },
+    Bytes { len: Len },
+    Str { len: Len, trim_nul: bool },
+    Const { data: Vec<u8> },
+    Struct(SegmentsPlan),
+    Array { item: SegmentsPlan, len: Len },
+}
+
+#[derive(Debug, Clone)]
+enum Len {
+    Fixed(usize),
+    Register(usize),
+    Sequence(Vec<u8>),
+    Remainder,
+}
+
+impl OpenPID {
+    /// Compiles decoding plans for every payload
+    pub fn compile(&self) -> Result<CompiledCodec> {
+        let compile_all = |direction: Direction| -> Result<BTreeMap<String, PayloadPlan>> {
+            self.payloads
+                .get(direction)
+                .iter()
+                .map(|(name, payload)| {
+                    let mut registers = Vec::new();
+                    self.collect_counts(&payload.segments, &mut registers, &mut Vec::new())?;
+                    if registers.len() > MAX_COUNT_FIELDS {
+                        return Err(CodecError::TooManyCountFields { payload: name.clone(), max: MAX_COUNT_FIELDS });
+                    }
+                    let plan = self.compile_segments(&payload.segments, &registers, name)?;
+                    Ok((name.clone(), PayloadPlan { name: name.clone(), plan }))
+                })
+                .collect()
+        };
+        Ok(CompiledCodec { tx: compile_all(Direction::Tx)?, rx: compile_all(Direction::Rx)? })
+    }
+
+    /// Names of all fields referenced by `CountInPacket`, including inside structs
+    fn collect_counts<'a>(&'a self, segments: &'a [PacketSegment], registers: &mut Vec<&'a str>, visiting: &mut Vec<&'a str>) -> Result<()> {
+        for segment in segments {
+            let struct_name = match segment {
+                PacketSegment::Unsized { termination, datatype, .. } => {
+                    if let Some(Terminator::CountInPacket { field_name }) = termination {
+                        if !registers.contains(&field_name.as_str()) {
+                            registers.push(field_name);
+                        }
+                    }
+                    match datatype {
+                        UnsizedDataType::Array { item_struct } => Some(item_struct),
+                        _ => None,
+                    }
+                }
+                PacketSegment::Struct { struct_name, .. } => Some(struct_name),
+                PacketSegment::Sized { .. } => None,
+            };
+            if let Some(struct_name) = struct_name {
+                if visiting.contains(&struct_name.as_str()) {
+                    return Err(CodecError::RecursiveStruct { struct_name: struct_name.clone() });
+                }
+                let rs = self
+                    .structs
+                    .get(struct_name)
+                    .ok_or_else(|| CodecError::NoStruct { field: segment.get_name().to_owned(), struct_name: struct_name.clone() })?;
+                visiting.push(struct_name);
+                self.collect_counts(&rs.fields, registers, visiting)?;
+                visiting.pop();
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_segments(&self, segments: &[PacketSegment], registers: &[&str], path: &str) -> Result<SegmentsPlan> {
+        let mut fields = Vec::with_capacity(segments.len());
+        let mut offset = Some(0usize);
+        for segment in segments {
+            let field = format!("{path}.{}", segment.get_name());
+            let kind = match segment {
+                PacketSegment::Sized { bits, datatype, .. } => match datatype {
+                    SizedDataType::Integer { endianness, signing } => {
+                        check_width(&field, *bits, 64)?;
+                        // checks little endian integers are whole bytes
+                        reorder(&field, 0, *bits, endianness)?;
+                        FieldKind::Int { bits: *bits, signing: *signing, endianness: *endianness }
+                    }
+                    SizedDataType::FloatIEEE { endianness } => {
+                        if *bits != 32 && *bits != 64 {
+                            return Err(CodecError::UnsupportedWidth { field, bits: *bits, reason: "floats must be 32 or 64 bits" });
+                        }
+                        FieldKind::Float { bits: *bits, endianness: *endianness }
+                    }
+                    SizedDataType::Raw => FieldKind::Bytes { len: Len::Fixed(whole_bytes(&field, *bits)?) },
+                    SizedDataType::StringUTF8 => FieldKind::Str { len: Len::Fixed(whole_bytes(&field, *bits)?), trim_nul: true },
+                    SizedDataType::Const { data } => {
+                        // the field takes `bits` on the wire but is checked against `data`, so they must agree
+                        let expected = whole_bytes(&field, *bits)?;
+                        if data.len() != expected {
+                            return Err(CodecError::LengthMismatch { field, expected, found: data.len() });
+                        }
+                        FieldKind::Const { data: data.clone() }
+                    }
+                },
+                PacketSegment::Unsized { datatype, termination, .. } => {
+                    let len = match termination {
+                        Some(Terminator::CountFixed { count }) => Len::Fixed(*count as usize),
+                        Some(Terminator::CountInPacket { field_name }) => {
+                            Len::Register(registers.iter().position(|r| r == field_name).expect("count fields are collected before compiling"))
+                        }
+                        Some(Terminator::Sequence { sequence }) => Len::Sequence(sequence.clone()),
+                        None => Len::Remainder,
+                    };
+                    match datatype {
+                        UnsizedDataType::Raw => FieldKind::Bytes { len },
+                        UnsizedDataType::StringUTF8 => FieldKind::Str { len, trim_nul: false },
+                        UnsizedDataType::Array { item_struct } => {
+                            let rs = self.get_struct_plan(&field, item_struct)?;
+                            FieldKind::Array { item: self.compile_segments(&rs.fields, registers, &field)?, len }
+                        }
+                    }
+                }
+                PacketSegment::Struct { struct_name, .. } => {
+                    let rs = self.get_struct_plan(&field, struct_name)?;
+                    FieldKind::Struct(self.compile_segments(&rs.fields, registers, &field)?)
+                }
+            };
+            let fixed_bits = self.segments_size(std::slice::from_ref(segment))?.fixed_bits().map(|b| b as usize);
+            fields.push(FieldPlan {
+                name: segment.get_name().to_owned(),
+                path: field,
+                offset_bits: offset,
+                fixed_bits,
+                kind,
+                register: registers.iter().position(|r| *r == segment.get_name()),
+            });
+            offset = offset.zip(fixed_bits).map(|(o, b)| o + b);
+        }
+        let checks_contents = fields.iter().any(|f| match &f.kind {
+            FieldKind::Const { .. } | FieldKind::Str { .. } => true,
+            FieldKind::Struct(plan) | FieldKind::Array { item: plan, .. } => plan.checks_contents,
+            FieldKind::Int { .. } | FieldKind::Float { .. } | FieldKind::Bytes { .. } => false,
+        });
+        Ok(SegmentsPlan { fields, size: self.segments_size(segments)?, checks_contents })
+    }
+
+    fn get_struct_plan(&self, field: &str, struct_name: &str) -> Result<&ReusableStruct> {
+        self.structs
+            .get(struct_name)
+            .ok_or_else(|| CodecError::NoStruct { field: field.to_owned(), struct_name: struct_name.to_owned() })
+    }
+}
+
+impl PayloadPlan {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> Size {
+        self.plan.size
+    }
+
+    /// Offset of a top-level field from the start of the payload, if it doesn't depend on the data
+    pub fn field_offset_bits(&self, name: &str) -> Option<usize> {
+        self.plan.fields.iter().find(|f| f.name == name).and_then(|f| f.offset_bits)
+    }
+
+    /// Checks that `data` holds exactly one of this payload, with every const and string valid, and
+    /// returns a view over it. Fixed size payloads without consts or strings are checked by length
+    /// alone; others are walked once
+    pub fn decode<'p, 'a>(&'p self, data: &'a [u8]) -> Result<PayloadView<'p, 'a>> {
+        let view = StructView { plan: &self.plan, data, start: 0, end: data.len() * 8, registers: [0; MAX_COUNT_FIELDS] };
+        let len = match self.plan.size {
+            Size::Fixed(bits) => {
+                let bits = bits as usize;
+                if bits > view.end {
+                    return Err(CodecError::UnexpectedEnd { field: self.name.clone(), needed_bits: bits, available_bits: view.end });
+                }
+                if self.plan.checks_contents {
+                    view.check()?;
+                }
+                bits
+            }
+            Size::Variable { .. } => view.check()?,
+        };
+        if view.end - len >= 8 {
+            return Err(CodecError::TrailingData { payload: self.name.clone(), bits: view.end - len });
+        }
+        Ok(view)
+    }
+}
+
+/// A decoded payload. Borrows the plan and the input buffer
+pub type PayloadView<'p, 'a> = StructView<'p, 'a>;
+
+/// A borrowed view over a payload or struct
+#[derive(Debug, Clone, Copy)]
+pub struct StructView<'p, 'a> {
+    plan: &'p SegmentsPlan,
+    data: &'a [u8],
+    start: usize,
+    end: usize,
+    registers: Registers,
+}
+
+/// One decoded field
+#[derive(Debug, Clone, Copy)]
+pub enum FieldView<'p, 'a> {
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bytes(&'a [u8]),
+    Str(&'a str),
+    Struct(StructView<'p, 'a>),
+    Array(ArrayView<'p, 'a>),
+}
+
+/// A borrowed view over an array of structs
+#[derive(Debug, Clone, Copy)]
+pub struct ArrayView<'p, 'a> {
+    path: &'p str,
+    item: &'p SegmentsPlan,
+    len: &'p Len,
+    data: &'a [u8],
+    start: usize,
+    end: usize,
+    registers: Registers,
+}
+
+impl<'p, 'a> StructView<'p, 'a> {
+    /// Iterates over the fields in wire order
+    pub fn fields(&self) -> FieldIter<'p, 'a> {
+        FieldIter { view: *self, index: 0, pos: self.start, registers: self.registers, failed: false }
+    }
+
+    /// Looks up a field by name. Fields at a fixed offset whose size doesn't depend on the data are
+    /// read directly, others by walking the preceding fields
+    pub fn get(&self, name: &str) -> Result<Option<FieldView<'p, 'a>>> {
+        let Some(index) = self.plan.fields.iter().position(|f| f.name == name) else {
+            return Ok(None);
+        };
+        let field = &self.plan.fields[index];
+        if let (Some(offset), Some(_)) = (field.offset_bits, field.fixed_bits) {
+            let mut registers = self.registers;
+            return decode_field(field, self.data, self.start + offset, self.end, &mut registers).map(|(view, _)| Some(view));
+        }
+        match self.fields().nth(index) {
+            Some(result) => result.map(|(_, view)| Some(view)),
+            None => Ok(None),
+        }
+    }
+
+    /// Bits this struct takes up in the buffer
+    pub fn len_bits(&self) -> Result<usize> {
+        if let Some(bits) = self.plan.size.fixed_bits() {
+            return Ok(bits as usize);
+        }
+        let mut iter = self.fields();
+        for field in iter.by_ref() {
+            field?;
+        }
+        Ok(iter.pos - self.start)
+    }
+
+    /// Walks every field, looking inside structs and arrays that hold consts or strings, and returns
+    /// the bits this struct takes up
+    fn check(&self) -> Result<usize> {
+        let mut iter = self.fields();
+        for field in iter.by_ref() {
+            match field?.1 {
+                FieldView::Struct(view) if view.plan.checks_contents => {
+                    view.check()?;
+                }
+                FieldView::Array(array) if array.item.checks_contents => {
+                    for item in array.items() {
+                        item?.check()?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(iter.pos - self.start)
+    }
+
+    /// Copies the view into an owned [Fields], for comparing with the dynamic decoder or dumping
+    pub fn to_fields(&self) -> Result<Fields> {
+        self.fields().map(|f| f.and_then(|(name, view)| Ok((name, view.to_value()?)))).collect()
+    }
+}
+
+impl<'p, 'a> ArrayView<'p, 'a> {
+    /// Number of items, if it's known without walking the array
+    pub fn count(&self) -> Option<usize> {
+        match self.len {
+            Len::Fixed(count) => Some(*count),
+            Len::Register(r) => Some(self.registers[*r] as usize),
+            Len::Sequence(_) | Len::Remainder => None,
+        }
+    }
+
+    pub fn items(&self) -> ArrayIter<'p, 'a> {
+        ArrayIter { array: *self, index: 0, pos: self.start, done: false }
+    }
+
+    /// Bits this array takes up in the buffer, including any terminating sequence
+    pub fn len_bits(&self) -> Result<usize> {
+        if let (Some(count), Some(bits)) = (self.count(), self.item.size.fixed_bits()) {
+            return Ok(count * bits as usize);
+        }
+        let mut iter = self.items();
+        for item in iter.by_ref() {
+            item?;
+        }
+        Ok(iter.pos - self.start)
+    }
+}
+
+impl FieldView<'_, '_> {
+    pub fn to_value(&self) -> Result<Value> {
+        Ok(match self {
+            FieldView::Int(i) => Value::Int(*i),
+            FieldView::UInt(u) => Value::UInt(*u),
+            FieldView::Float(f) => Value::Float(*f),
+            FieldView::Bytes(b) => Value::Bytes(b.to_vec()),
+            FieldView::Str(s) => Value::String((*s).to_owned()),
+            FieldView::Struct(s) => Value::Struct(s.to_fields()?),
+            FieldView::Array(a) => Value::Array(a.items().map(|i| i.and_then(|s| s.to_fields()).map(Value::Struct)).collect::<Result<_>>()?),
+        })
+    }
+}
+
+/// Iterator over a struct's fields
+pub struct FieldIter<'p, 'a> {
+    view: StructView<'p, 'a>,
+    index: usize,
+    pos: usize,
+    registers: Registers,
+    failed: bool,
+}
+
+impl<'p, 'a> Iterator for FieldIter<'p, 'a> {
+    type Item = Result<(&'p str, FieldView<'p, 'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let field = self.view.plan.fields.get(self.index).filter(|_| !self.failed)?;
+        self.index += 1;
+        match decode_field(field, self.view.data, self.pos, self.view.end, &mut self.registers) {
+            Ok((view, pos)) => {
+                self.pos = pos;
+                Some(Ok((&field.name, view)))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Iterator over an array's items
+pub struct ArrayIter<'p, 'a> {
+    array: ArrayView<'p, 'a>,
+    index: usize,
+    pos: usize,
+    done: bool,
+}
+
+impl<'p, 'a> Iterator for ArrayIter<'p, 'a> {
+    type Item = Result<StructView<'p, 'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let array = &self.array;
+        let finished = match array.len {
+            Len::Fixed(_) | Len::Register(_) => Some(self.index) == array.count(),
+            Len::Sequence(sequence) => {
+                let at = self.pos / 8;
+                let found = self.pos.is_multiple_of(8) && array.data[..array.end / 8].get(at..at + sequence.len()) == Some(sequence.as_slice());
+                if found {
+                    self.pos += sequence.len() * 8;
+                } else if array.end - self.pos < 8 {
+                    self.done = true;
+                    return Some(Err(CodecError::SequenceNotFound { field: array.path.to_owned() }));
+                }
+                found
+            }
+            Len::Remainder => array.end - self.pos < 8,
+        };
+        if finished {
+            self.done = true;
+            return None;
+        }
+        let item = StructView { plan: array.item, data: array.data, start: self.pos, end: array.end, registers: array.registers };
+        match item.len_bits() {
+            Ok(bits) => {
+                self.pos += bits;
+                self.index += 1;
+                Some(Ok(item))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Reads a field already checked to be in bounds
+#[inline]
+fn read_bits(field: &FieldPlan, data: &[u8], pos: usize, bits: u32) -> Result<u64> {
+    rt::bits::read_bits(data, pos, bits).map_err(|e| CodecError::runtime(&field.path, e))
+}
+
+fn ensure(field: &FieldPlan, pos: usize, end: usize, bits: usize) -> Result<()> {
+    if end < pos || end - pos < bits {
+        return Err(CodecError::UnexpectedEnd { field: field.path.clone(), needed_bits: bits, available_bits: end.saturating_sub(pos) });
+    }
+    Ok(())
+}
+
+fn aligned_bytes<'a>(field: &FieldPlan, data: &'a [u8], pos: usize, end: usize, len: &Len, registers: &Registers) -> Result<(&'a [u8], usize)> {
+    if !pos.is_multiple_of(8) {
+        return Err(CodecError::UnsupportedWidth { field: field.path.clone(), bits: 8, reason: "byte fields must be byte aligned to be borrowed" });
+    }
+    let start = pos / 8;
+    let available = &data[start..end / 8];
+    let (bytes, consumed) = match len {
+        Len::Fixed(n) => (available.get(..*n), *n),
+        Len::Register(r) => {
+            let n = registers[*r] as usize;
+            (available.get(..n), n)
+        }
+        Len::Sequence(sequence) => {
+            let at = available
+                .windows(sequence.len())
+                .position(|w| w == sequence.as_slice())
+                .ok_or_else(|| CodecError::SequenceNotFound { field: field.path.clone() })?;
+            (Some(&available[..at]), at + sequence.len())
+        }
+        Len::Remainder => (Some(available), available.len()),
+    };
+    let bytes = bytes.ok_or_else(|| CodecError::UnexpectedEnd { field: field.path.clone(), needed_bits: consumed * 8, available_bits: end - pos })?;
+    Ok((bytes, pos + consumed * 8))
+}
+
+/// Decodes the field at `pos`, returning it and the position of the next field
+fn decode_field<'p, 'a>(field: &'p FieldPlan, data: &'a [u8], pos: usize, end: usize, registers: &mut Registers) -> Result<(FieldView<'p, 'a>, usize)> {
+    let (view, next) = match &field.kind {
+        FieldKind::Int { bits, signing, endianness } => {
+            ensure(field, pos, end, *bits as usize)?;
+            let raw = reorder(&field.path, read_bits(field, data, pos, *bits)?, *bits, endianness)?;
+            let view = match decode_integer(raw, *bits, signing) {
+                Value::Int(i) => FieldView::Int(i),
+                _ => FieldView::UInt(raw),
+            };
+            (view, pos + *bits as usize)
+        }
+        FieldKind::Float { bits, endianness } => {
+            ensure(field, pos, end, *bits as usize)?;
+            let raw = reorder(&field.path, read_bits(field, data, pos, *bits)?, *bits, endianness)?;
+            let value = if *bits == 32 { f32::from_bits(raw as u32) as f64 } else { f64::from_bits(raw) };
+            (FieldView::Float(value), pos + *bits as usize)
+        }
+        FieldKind::Bytes { len } => {
+            let (bytes, next) = aligned_bytes(field, data, pos, end, len, registers)?;
+            (FieldView::Bytes(bytes), next)
+        }
+        FieldKind::Str { len, trim_nul } => {
+            let (mut bytes, next) = aligned_bytes(field, data, pos, end, len, registers)?;
+            if *trim_nul {
+                while let [rest @ .., 0] = bytes {
+                    bytes = rest;
+                }
+            }
+            let s = std::str::from_utf8(bytes).map_err(|_| CodecError::InvalidUtf8 { field: field.path.clone() })?;
+            (FieldView::Str(s), next)
+        }
+        FieldKind::Const { data: expected } => {
+            let (bytes, next) = aligned_bytes(field, data, pos, end, &Len::Fixed(expected.len()), registers)?;
+            if bytes != expected.as_slice() {
+                return Err(CodecError::ConstMismatch { field: field.path.clone(), expected: expected.clone(), found: bytes.to_vec() });
+            }
+            (FieldView::Bytes(bytes), next)
+        }
+        FieldKind::Struct(plan) => {
+            let view = StructView { plan, data, start: pos, end, registers: *registers };
+            let bits = view.len_bits()?;
+            ensure(field, pos, end, bits)?;
+            (FieldView::Struct(view), pos + bits)
+        }
+        FieldKind::Array { item, len } => {
+            let view = ArrayView { path: &field.path, item, len, data, start: pos, end, registers: *registers };
+            let bits = view.len_bits()?;
+            ensure(field, pos, end, bits)?;
+            (FieldView::Array(view), pos + bits)
+        }
+    };
+    if let Some(register) = field.register {
+        registers[register] = match view {
+            FieldView::UInt(u) => u,
+            FieldView::Int(i) => i.max(0) as u64,
+            _ => return Err(CodecError::BadCount { field: field.path.clone(), count_field: field.path.clone() }),
+        };
+    }
+    Ok((view, next))
+}
@@ -0,0 +1,140 @@
+//! Lowers a document's frame formats and metadata to the borrowed descriptions used by the
+//! `no_std` [openpid_runtime] crate, so frames can be built and parsed without the spec loaded
+
+use openpid_runtime as rt;
+
+use crate::codec::{CodecError, Direction};
+use crate::prelude::*;
+
+impl From<&Endianness> for rt::Endianness {
+    fn from(value: &Endianness) -> Self {
+        match value {
+            Endianness::BigEndian => rt::Endianness::Big,
+            Endianness::LittleEndian => rt::Endianness::Little,
+        }
+    }
+}
+
+impl From<&Signing> for rt::Signing {
+    fn from(value: &Signing) -> Self {
+        match value {
+            Signing::OnesComplement => rt::Signing::OnesComplement,
+            Signing::TwosComplement => rt::Signing::TwosComplement,
+            Signing::Unsigned => rt::Signing::Unsigned,
+        }
+    }
+}
+
+impl From<&Crc> for rt::Crc {
+    fn from(value: &Crc) -> Self {
+        match value {
+            Crc::Crc32 => rt::Crc::Crc32,
+            Crc::Crc16XModem => rt::Crc::Crc16XModem,
+        }
+    }
+}
+
+impl From<&BitsOrBytes> for rt::SizeUnit {
+    fn from(value: &BitsOrBytes) -> Self {
+        match value {
+            BitsOrBytes::Bits => rt::SizeUnit::Bits,
+            BitsOrBytes::Bytes => rt::SizeUnit::Bytes,
+        }
+    }
+}
+
+fn metadata_unsupported(segment: &PacketSegment) -> CodecError {
+    CodecError::UnsupportedWidth {
+        field: segment.get_name().to_owned(),
+        bits: 0,
+        reason: "runtime metadata must be an integer, or raw/string data of at most 8 bytes",
+    }
+}
+
+impl OpenPID {
+    /// The direction's frame format, flattened for [openpid_runtime::frame]
+    pub fn runtime_format(&self, direction: Direction) -> Result<Vec<rt::Element<'_>>, CodecError> {
+        let format = self.uart.as_ref().ok_or(CodecError::NoFrameFormat)?.format(direction);
+        let mut out = Vec::new();
+        lower_elements(format, &mut out)?;
+        if out.iter().filter(|e| matches!(e, rt::Element::Metadata { .. })).count() > rt::frame::MAX_METADATA {
+            return Err(CodecError::runtime("frame", rt::Error::TooManyMetadata));
+        }
+        Ok(out)
+    }
+
+    /// A payload's metadata values, in the order the direction's frame format expects them
+    pub fn runtime_metadata(&self, direction: Direction, name: &str) -> Result<Vec<u64>, CodecError> {
+        let format = self.uart.as_ref().ok_or(CodecError::NoFrameFormat)?.format(direction);
+        let payload = self.get_payload(direction, name)?;
+        let mut values = Vec::new();
+        collect_metadata(format, name, payload, &mut values)?;
+        Ok(values)
+    }
+}
+
+fn lower_elements<'a>(elements: &'a [PacketFormatElement], out: &mut Vec<rt::Element<'a>>) -> Result<(), CodecError> {
+    for element in elements {
+        match element {
+            PacketFormatElement::SizeTotal { size_bits, express_as } => {
+                out.push(rt::Element::SizeTotal { bits: *size_bits, unit: express_as.into() })
+            }
+            PacketFormatElement::SizeOfPayload { size_bits, express_as } => {
+                out.push(rt::Element::SizeOfPayload { bits: *size_bits, unit: express_as.into() })
+            }
+            PacketFormatElement::SizeOfElements { size_bits, express_as, elements } => {
+                let at = out.len();
+                out.push(rt::Element::Payload);
+                lower_elements(elements, out)?;
+                out[at] = rt::Element::SizeOfElements { bits: *size_bits, unit: express_as.into(), covers: out.len() - at - 1 };
+            }
+            PacketFormatElement::Payload => out.push(rt::Element::Payload),
+            PacketFormatElement::Metadata { segment, .. } => {
+                let PacketSegment::Sized { bits, datatype, .. } = segment else {
+                    return Err(metadata_unsupported(segment));
+                };
+                let endianness = match datatype {
+                    SizedDataType::Integer { endianness, .. } => endianness.into(),
+                    SizedDataType::Raw | SizedDataType::StringUTF8 if *bits <= 64 && bits.is_multiple_of(8) => rt::Endianness::Big,
+                    _ => return Err(metadata_unsupported(segment)),
+                };
+                out.push(rt::Element::Metadata { bits: *bits, endianness });
+            }
+            PacketFormatElement::Crc { algorithm } => out.push(rt::Element::Crc(algorithm.into())),
+            PacketFormatElement::Const { data, bits, .. } => {
+                out.push(rt::Element::Const { data, bits: bits.unwrap_or(data.len() * 8) as u32 })
+            }
+        }
+    }
+    Ok(())
+}
+
+fn collect_metadata(elements: &[PacketFormatElement], name: &str, payload: &Payload, values: &mut Vec<u64>) -> Result<(), CodecError> {
+    for element in elements {
+        match element {
+            PacketFormatElement::SizeOfElements { elements, .. } => collect_metadata(elements, name, payload, values)?,
+            PacketFormatElement::Metadata { segment: segment @ PacketSegment::Sized { bits, datatype, .. }, .. } => {
+                let literal = payload
+                    .metadata
+                    .get(segment.get_name())
+                    .and_then(|m| m.as_many_ref().into_iter().next())
+                    .ok_or_else(|| CodecError::MissingMetadata { payload: name.to_owned(), key: segment.get_name().to_owned() })?;
+                let value = match (datatype, literal) {
+                    (SizedDataType::Integer { signing, .. }, LiteralValue::Int(i)) => rt::bits::from_signed(*i as i128, *bits, signing.into())
+                        .map_err(|_| CodecError::OutOfRange { field: segment.get_name().to_owned(), bits: *bits })?,
+                    (SizedDataType::Raw | SizedDataType::StringUTF8, LiteralValue::String(s)) if s.len() * 8 <= *bits as usize => {
+                        // NUL padded, as sized strings are
+                        let mut bytes = s.as_bytes().to_vec();
+                        bytes.resize(*bits as usize / 8, 0);
+                        bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+                    }
+                    _ => return Err(metadata_unsupported(segment)),
+                };
+                values.push(value);
+            }
+            PacketFormatElement::Metadata { segment, .. } => return Err(metadata_unsupported(segment)),
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! Static size analysis of payloads and structs
+
+use std::{fmt::Display, ops::Add};
+
+use crate::codec::CodecError;
+use crate::prelude::*;
+
+/// How many bits something takes up on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    /// Always exactly this many bits
+    Fixed(u64),
+
+    /// Depends on the data. `max_bits` is None when there's no upper bound, for example for
+    /// sequence-terminated or unterminated fields
+    Variable { min_bits: u64, max_bits: Option<u64> },
+}
+
+impl Size {
+    pub const ZERO: Size = Size::Fixed(0);
+
+    pub fn is_fixed(&self) -> bool {
+        matches!(self, Size::Fixed(_))
+    }
+
+    pub fn fixed_bits(&self) -> Option<u64> {
+        match self {
+            Size::Fixed(bits) => Some(*bits),
+            Size::Variable { .. } => None,
+        }
+    }
+
+    pub fn min_bits(&self) -> u64 {
+        match self {
+            Size::Fixed(bits) => *bits,
+            Size::Variable { min_bits, .. } => *min_bits,
+        }
+    }
+
+    pub fn max_bits(&self) -> Option<u64> {
+        match self {
+            Size::Fixed(bits) => Some(*bits),
+            Size::Variable { max_bits, .. } => *max_bits,
+        }
+    }
+
+    /// Size of `count` repetitions of this size, `count` itself being anywhere within the range
+    pub(crate) fn repeated(self, min_count: u64, max_count: Option<u64>) -> Size {
+        match (self, max_count) {
+            (Size::Fixed(bits), Some(max)) if max == min_count => Size::Fixed(bits * max),
+            _ => Size::Variable {
+                min_bits: self.min_bits() * min_count,
+                max_bits: self.max_bits().zip(max_count).map(|(bits, count)| bits.saturating_mul(count)),
+            },
+        }
+    }
+}
+
+impl Add for Size {
+    type Output = Size;
+
+    fn add(self, rhs: Size) -> Size {
+        match (self, rhs) {
+            (Size::Fixed(a), Size::Fixed(b)) => Size::Fixed(a + b),
+            _ => Size::Variable {
+                min_bits: self.min_bits() + rhs.min_bits(),
+                max_bits: self.max_bits().zip(rhs.max_bits()).map(|(a, b)| a.saturating_add(b)),
+            },
+        }
+    }
+}
+
+impl Display for Size {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn bits(f: &mut std::fmt::Formatter<'_>, bits: u64) -> std::fmt::Result {
+            match (bits / 8, bits % 8) {
+                (1, 0) => write!(f, "1 byte"),
+                (bytes, 0) => write!(f, "{bytes} bytes"),
+                _ if bits == 1 => write!(f, "1 bit"),
+                _ => write!(f, "{bits} bits"),
+            }
+        }
+        match self {
+            Size::Fixed(b) => bits(f, *b),
+            Size::Variable { min_bits, max_bits } => {
+                bits(f, *min_bits)?;
+                write!(f, " to ")?;
+                match max_bits {
+                    Some(max) => bits(f, *max),
+                    None => write!(f, "unbounded"),
+                }
+            }
+        }
+    }
+}
+
+impl OpenPID {
+    /// Size of a list of segments, such as a payload's or a struct's
+    pub fn segments_size(&self, segments: &[PacketSegment]) -> Result<Size, CodecError> {
+        self.segments_size_inner(segments, &mut Vec::new())
+    }
+
+    fn segments_size_inner<'a>(&'a self, segments: &'a [PacketSegment], visiting: &mut Vec<&'a str>) -> Result<Size, CodecError> {
+        let mut total = Size::ZERO;
+        for segment in segments {
+            total = total
+                + match segment {
+                    PacketSegment::Sized { bits, .. } => Size::Fixed(*bits as u64),
+                    PacketSegment::Struct { name, struct_name } => self.struct_size(name, struct_name, visiting)?,
+                    PacketSegment::Unsized { name, datatype, termination, .. } => {
+                        let element = match datatype {
+                            UnsizedDataType::Raw | UnsizedDataType::StringUTF8 => Size::Fixed(8),
+                            UnsizedDataType::Array { item_struct } => self.struct_size(name, item_struct, visiting)?,
+                        };
+                        match termination {
+                            Some(Terminator::CountFixed { count }) => element.repeated(*count as u64, Some(*count as u64)),
+                            Some(Terminator::CountInPacket { field_name }) => {
+                                let max = segments.iter().find(|s| s.get_name() == field_name).and_then(|s| match s {
+                                    PacketSegment::Sized { bits, .. } if *bits < 64 => Some((1u64 << bits) - 1),
+                                    _ => None,
+                                });
+                                element.repeated(0, max)
+                            }
+                            Some(Terminator::Sequence { sequence }) => {
+                                Size::Variable { min_bits: sequence.len() as u64 * 8, max_bits: None }
+                            }
+                            None => Size::Variable { min_bits: 0, max_bits: None },
+                        }
+                    }
+                };
+        }
+        Ok(total)
+    }
+
+    fn struct_size<'a>(&'a self, field: &str, struct_name: &'a str, visiting: &mut Vec<&'a str>) -> Result<Size, CodecError> {
+        if visiting.contains(&struct_name) {
+            return Err(CodecError::RecursiveStruct { struct_name: struct_name.to_owned() });
+        }
+        let rs = self
+            .structs
+            .get(struct_name)
+            .ok_or_else(|| CodecError::NoStruct { field: field.to_owned(), struct_name: struct_name.to_owned() })?;
+        visiting.push(struct_name);
+        let size = self.segments_size_inner(&rs.fields, visiting);
+        visiting.pop();
+        size
+    }
+}
+
+impl Payload {
+    /// Calculates a payload's size, not including headers etc.
+    pub fn get_size(&self, pid: &OpenPID) -> Result<Size, CodecError> {
+        pid.segments_size(&self.segments)
+    }
+}
@@ -0,0 +1,281 @@
+use std::{fmt, ops::Range};
+
+use serde::{
+    de::{MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::config::LiteralValue;
+
+/// A self-describing value decoded from (or to be encoded into) a payload. Scalars keep the
+/// interpretation given to them by the OpenPID document, so an `Unsigned` integer always decodes
+/// to [Value::UInt] and a `TwosComplement` one to [Value::Int].
+///
+/// Serializes untagged, so a decoded payload dumps to plain JSON/TOML and fixtures can be written
+/// by hand. When deserializing, integers come back as [Value::Int] where they fit; the encoder
+/// coerces between integer variants based on the field's declared type.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Value {
+    /// Signed integer, from a `TwosComplement` or `OnesComplement` field
+    Int(i64),
+
+    /// Unsigned integer
+    UInt(u64),
+
+    /// IEEE float, widened to 64 bits
+    Float(f64),
+
+    /// UTF8 string, with any trailing NUL padding removed
+    String(String),
+
+    /// Raw or constant bytes
+    Bytes(Vec<u8>),
+
+    /// A struct's fields, in the order they appear on the wire
+    Struct(Fields),
+
+    /// Repetitions of an array's item struct
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Name of this value's variant, for error messages
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "signed integer",
+            Value::UInt(_) => "unsigned integer",
+            Value::Float(_) => "float",
+            Value::String(_) => "string",
+            Value::Bytes(_) => "bytes",
+            Value::Struct(_) => "struct",
+            Value::Array(_) => "array",
+        }
+    }
+
+    /// Returns the value as an integer, if it is one
+    pub fn as_i128(&self) -> Option<i128> {
+        match self {
+            Value::Int(i) => Some(*i as i128),
+            Value::UInt(u) => Some(*u as i128),
+            _ => None,
+        }
+    }
+
+    /// Number of elements in a variable-length value: bytes of a string or raw field, or items of
+    /// an array. Used to fill in `CountInPacket` fields
+    pub fn element_count(&self) -> Option<usize> {
+        match self {
+            Value::String(s) => Some(s.len()),
+            Value::Bytes(b) => Some(b.len()),
+            Value::Array(a) => Some(a.len()),
+            _ => None,
+        }
+    }
+
+    /// Whether this value is what the config file's literal describes, for example when matching
+    /// decoded metadata against a payload's `FrameID`
+    pub fn matches_literal(&self, literal: &LiteralValue) -> bool {
+        match literal {
+            LiteralValue::Int(i) => self.as_i128() == Some(*i as i128),
+            LiteralValue::String(s) => matches!(self, Value::String(v) if v == s),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{i}"),
+            Value::UInt(u) => write!(f, "{u}"),
+            Value::Float(x) => write!(f, "{x}"),
+            Value::String(s) => write!(f, "{s:?}"),
+            Value::Bytes(b) => {
+                write!(f, "0x")?;
+                for byte in b {
+                    write!(f, "{byte:02x}")?;
+                }
+                Ok(())
+            }
+            Value::Struct(fields) => {
+                write!(f, "{{ ")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{name}: {value}")?;
+                }
+                write!(f, " }}")
+            }
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Named values in wire order. Serializes as a map, but unlike a map keeps the order the fields
+/// were decoded in (or written in, in a fixture)
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Fields(pub Vec<(String, Value)>);
+
+impl Fields {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    /// Appends a field, replacing any earlier field with the same name
+    pub fn insert(&mut self, name: impl Into<String>, value: Value) {
+        let name = name.into();
+        self.0.retain(|(n, _)| *n != name);
+        self.0.push((name, value));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Looks up a value by a dotted path, for example `"position.x"` or `"samples[2].x"`
+    pub fn lookup(&self, path: &str) -> Option<&Value> {
+        let mut parts = path.split('.');
+        let mut current = lookup_step(self, parts.next()?)?;
+        for part in parts {
+            match current {
+                Value::Struct(fields) => current = lookup_step(fields, part)?,
+                _ => return None,
+            }
+        }
+        Some(current)
+    }
+}
+
+fn lookup_step<'a>(fields: &'a Fields, part: &str) -> Option<&'a Value> {
+    match part.split_once('[') {
+        Some((name, index)) => {
+            let index: usize = index.strip_suffix(']')?.parse().ok()?;
+            match fields.get(name)? {
+                Value::Array(items) => items.get(index),
+                _ => None,
+            }
+        }
+        None => fields.get(part),
+    }
+}
+
+impl<S: Into<String>> FromIterator<(S, Value)> for Fields {
+    fn from_iter<T: IntoIterator<Item = (S, Value)>>(iter: T) -> Self {
+        Self(iter.into_iter().map(|(n, v)| (n.into(), v)).collect())
+    }
+}
+
+impl Serialize for Fields {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, value) in &self.0 {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Fields {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldsVisitor;
+
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = Fields;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map of field names to values")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Fields, A::Error> {
+                let mut fields = Fields::new();
+                while let Some((name, value)) = map.next_entry::<String, Value>()? {
+                    fields.0.push((name, value));
+                }
+                Ok(fields)
+            }
+        }
+
+        deserializer.deserialize_map(FieldsVisitor)
+    }
+}
+
+/// Where a field came from, in bits, relative to the start of its payload
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub offset_bits: usize,
+    pub len_bits: usize,
+}
+
+impl Span {
+    /// The bytes this span touches. Fields that aren't byte aligned include the partial bytes at
+    /// either end
+    pub fn byte_range(&self) -> Range<usize> {
+        self.offset_bits / 8..(self.offset_bits + self.len_bits).div_ceil(8)
+    }
+
+    /// Moves this span, e.g. from being relative to the payload to being relative to the frame
+    pub fn offset_by(&self, bits: usize) -> Span {
+        Span { offset_bits: self.offset_bits + bits, len_bits: self.len_bits }
+    }
+}
+
+/// The span of one (possibly nested) field, named by its dotted path (`samples[2].x`)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FieldSpan {
+    pub path: String,
+    pub span: Span,
+}
+
+/// A payload decoded against an OpenPID document
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DecodedPayload {
+    /// Name of the payload, as keyed in `[payloads.tx]`/`[payloads.rx]`
+    pub name: String,
+
+    pub fields: Fields,
+
+    /// Where each field, including nested ones, was found in the payload. Optional in fixtures
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<FieldSpan>,
+}
+
+impl DecodedPayload {
+    pub fn span_of(&self, path: &str) -> Option<Span> {
+        self.spans.iter().find(|s| s.path == path).map(|s| s.span)
+    }
+}
+
+/// A whole frame: the envelope's metadata and the payload it carried
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DecodedFrame {
+    /// Values of the frame format's `Metadata` elements, like a `FrameID`
+    pub metadata: Fields,
+
+    pub payload: DecodedPayload,
+
+    /// Where the payload sits in the frame
+    pub payload_span: Span,
+}
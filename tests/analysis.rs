@@ -0,0 +1,103 @@
+//! What the language server builds on: symbols, references, located diagnostics and completion
+
+use openpid::analysis::{Analysis, Severity, SymbolKind};
+use openpid::{CodegenError, OpenPID};
+
+const SOURCE: &str = r#"[device_info]
+name = "sensor"
+description = "A sensor"
+
+[uart]
+tx_format = [{ type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } }, { type = "Payload" }]
+rx_format = [{ type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } }, { type = "Payload" }]
+
+[structs.vec3]
+name = "vec3"
+description = "A vector"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "y", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+]
+
+[payloads.tx.read]
+description = "Reads"
+id = 0x01
+segments = []
+
+[payloads.rx.reading]
+description = "A reading"
+id = 0x02
+segments = [{ name = "accel", struct_name = "vec3" }]
+
+[transactions.read]
+description = "Reads the acceleration"
+actions = [{ type = "Tx", payload = "read" }, { type = "Rx", payload = "reading" }]
+returns = ["reading.accel.y"]
+"#;
+
+/// The offset of the `nth` occurrence of `needle`
+fn at(source: &str, needle: &str, nth: usize) -> usize {
+    source.match_indices(needle).nth(nth).unwrap().0
+}
+
+#[test]
+fn references_resolve_through_structs() {
+    let analysis = Analysis::new(SOURCE);
+    assert!(analysis.diagnostics.is_empty(), "{:?}", analysis.diagnostics);
+
+    // the `y` in `reading.accel.y` is vec3's field
+    let y = analysis.symbol_at(at(SOURCE, "accel.y", 0) + 6).unwrap();
+    assert_eq!(analysis.symbols[y].kind, SymbolKind::Field);
+    assert_eq!(analysis.symbols[y].span.start, at(SOURCE, "\"y\"", 0) + 1);
+    assert!(analysis.hover(y).contains("at byte 2"), "{}", analysis.hover(y));
+
+    let vec3 = analysis.symbol_at(at(SOURCE, "vec3", 0)).unwrap();
+    assert_eq!(analysis.symbols[vec3].kind, SymbolKind::Struct);
+    let renamed = analysis.rename(vec3, "vector").unwrap();
+    assert_eq!(renamed.len(), 3, "the key, its name and the struct_name");
+    assert!(analysis.rename(vec3, "two words").is_err());
+
+    let id = analysis.symbol_at(at(SOURCE, "id = 0x02", 0)).unwrap();
+    assert_eq!(analysis.symbols[id].kind, SymbolKind::Metadata(openpid::codec::Direction::Rx));
+}
+
+#[test]
+fn diagnostics_point_at_the_problem() {
+    let source = SOURCE.replace(r#"payload = "reading""#, r#"payload = "missing""#);
+    let analysis = Analysis::new(&source);
+    let error = analysis.diagnostics.iter().find(|d| d.severity == Severity::Error && d.message.contains("\"missing\"")).unwrap();
+    assert_eq!(error.span.clone().unwrap().start, at(&source, "missing", 0));
+
+    let analysis = Analysis::new("[device_info\n");
+    assert!(!analysis.parsed());
+    assert!(analysis.diagnostics[0].span.is_some());
+}
+
+#[test]
+fn validation_agrees_with_the_analysis() {
+    // the bundled document's CamelCase names are warnings, not errors
+    let bundled = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/openpid.toml")).unwrap();
+    let analysis = Analysis::new(&bundled);
+    assert!(analysis.diagnostics.iter().all(|d| d.severity == Severity::Warning), "{:?}", analysis.diagnostics);
+    assert!(analysis.diagnostics.iter().any(|d| d.message == "TX payload \"GetModInfo\" is not snake case"));
+    bundled.parse::<OpenPID>().unwrap().validate().unwrap();
+    let diagnostics = Analysis::new(SOURCE).diagnostics;
+    assert!(diagnostics.is_empty(), "{diagnostics:?}");
+
+    let broken = SOURCE.replace(r#"payload = "reading""#, r#"payload = "missing""#);
+    assert!(matches!(broken.parse::<OpenPID>().unwrap().validate(), Err(CodegenError::Invalid(_))));
+}
+
+#[test]
+fn completes_names_while_typing() {
+    let analysis = Analysis::new(SOURCE);
+    let typing = SOURCE.replace(r#"returns = ["reading.accel.y"]"#, r#"returns = ["reading.accel."#);
+    let offset = at(&typing, "accel.", 0) + 6;
+    let labels = analysis.complete(&typing, offset).into_iter().map(|c| c.label).collect::<Vec<_>>();
+    assert_eq!(labels, ["x", "y"]);
+
+    let typing = SOURCE.replace(r#"struct_name = "vec3" }]"#, r#"struct_name = ""#);
+    let offset = at(&typing, "struct_name = \"", 0) + 15;
+    let labels = analysis.complete(&typing, offset).into_iter().map(|c| c.label).collect::<Vec<_>>();
+    assert_eq!(labels, ["vec3"]);
+}
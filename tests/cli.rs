@@ -0,0 +1,110 @@
+//! The `openpid` binary's exit codes and JSON output, run against the bundled document
+#![cfg(feature = "cli")]
+
+use std::process::{Command, Output, Stdio};
+
+fn openpid(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_openpid"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .expect("the binary should run")
+}
+
+fn json(output: &Output) -> serde_json::Value {
+    serde_json::from_slice(&output.stdout).expect("the output should be JSON")
+}
+
+#[test]
+fn bundled_document_validates() {
+    let output = openpid(&["validate", "--json"]);
+    assert!(output.status.success());
+    assert_eq!(json(&output)["valid"], true);
+}
+
+#[test]
+fn invalid_document_fails_with_every_problem() {
+    let path = std::env::temp_dir().join("openpid-cli-invalid.toml");
+    let source = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/openpid.toml")).unwrap();
+    let source = source.replace(r#"payload = "GetModInfoResp""#, r#"payload = "Missing""#).replace(r#"FrameID = 0x13"#, "");
+    std::fs::write(&path, source).unwrap();
+
+    let output = openpid(&["validate", "--json", "--file", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let report = json(&output);
+    assert_eq!(report["valid"], false);
+    let errors = report["diagnostics"].as_array().unwrap().iter().filter(|d| d["severity"] == "error").map(|d| d["message"].as_str().unwrap()).collect::<Vec<_>>();
+    assert!(errors.iter().any(|e| e.contains("\"Missing\"")), "{report}");
+    assert!(errors.iter().any(|e| e.contains("SetConfigDone")), "{report}");
+}
+
+#[test]
+fn encoded_frame_decodes_to_the_same_payload() {
+    let output = openpid(&["encode", "SetConfig", r#"{"configid": 5, "value": [1, 2, 3]}"#]);
+    assert!(output.status.success());
+    let hex = String::from_utf8(output.stdout).unwrap();
+
+    let output = openpid(&["decode", "auto", hex.trim(), "--direction", "tx", "--json"]);
+    assert!(output.status.success());
+    let report = json(&output);
+    assert_eq!(report["metadata"]["FrameID"], 6);
+    assert_eq!(report["payload"]["name"], "SetConfig");
+    assert_eq!(report["payload"]["fields"]["value"], serde_json::json!([1, 2, 3]));
+}
+
+#[test]
+fn diff_reports_breaking_changes_and_checks_the_version() {
+    let path = std::env::temp_dir().join("openpid-cli-diff.toml");
+    let source = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/openpid.toml")).unwrap();
+    std::fs::write(&path, source.replace("FrameID = 0x02", "FrameID = 0x42")).unwrap();
+
+    let output = openpid(&["diff", "openpid.toml", "--json", "--file", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    let report = json(&output);
+    assert_eq!(report["breaking"], true, "{report}");
+    assert_eq!(report["bump"], "major");
+    assert_eq!(report["changes"][0]["path"], "payloads.rx.GetModInfoResp.FrameID");
+
+    // neither document has a doc_version to check
+    let output = openpid(&["diff", "openpid.toml", "--check", "--file", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn schema_command_prints_the_checked_in_schema() {
+    let output = openpid(&["schema"]);
+    assert!(output.status.success());
+    let checked_in = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/openpid.schema.json")).unwrap();
+    assert!(output.stdout == checked_in, "run `openpid schema > openpid.schema.json`");
+}
+
+#[test]
+fn closed_pipe_ends_quietly() {
+    let generated = std::env::temp_dir().join("openpid-cli-pipe");
+    let generated = generated.to_str().unwrap();
+    let commands: [&[&str]; 10] = [
+        &["schema"],
+        &["gen", "--list"],
+        &["gen", "--list", "--json"],
+        &["gen", "c", "-o", generated],
+        &["fmt"],
+        &["size"],
+        &["diff", "openpid.toml"],
+        &["decode", "SetConfig", "0501"],
+        &["encode", "SetConfig", r#"{"configid": 5, "value": [1]}"#],
+        &["size", "--json"],
+    ];
+    for args in commands {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_openpid"))
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("the binary should run");
+        drop(child.stdout.take());
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{args:?}");
+        assert!(output.stderr.is_empty(), "{args:?}: {}", String::from_utf8_lossy(&output.stderr));
+    }
+}
@@ -0,0 +1,225 @@
+//! Encoding and decoding payloads and frames with the dynamic codec
+
+use std::str::FromStr;
+
+use openpid::prelude::*;
+use openpid::value::Span;
+
+/// `node` contains itself through `link`
+const RECURSIVE: &str = r#"[device_info]
+name = "sensor"
+description = "A sensor"
+
+[structs.node]
+name = "node"
+fields = [{ name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } }, { name = "next", struct_name = "link" }]
+
+[structs.link]
+name = "link"
+fields = [{ name = "node", struct_name = "node" }]
+
+[payloads.tx.walk]
+description = "Walks"
+segments = [{ name = "start", struct_name = "node" }]
+
+[payloads.rx.walked]
+description = "Walked"
+segments = [{ name = "nodes", type = { type = "Array", item_struct = "node" }, termination = { count = 1 } }]
+
+[transactions]
+"#;
+
+#[test]
+fn recursive_structs_are_refused() {
+    let doc = OpenPID::from_str(RECURSIVE).unwrap();
+    let recursive = |e| matches!(e, CodecError::RecursiveStruct { ref struct_name } if struct_name == "node");
+    assert!(recursive(doc.decode_payload(Direction::Tx, "walk", &[1; 64]).unwrap_err()));
+    assert!(recursive(doc.decode_payload(Direction::Rx, "walked", &[1; 64]).unwrap_err()));
+
+    // however deeply the value given nests, encoding stops at the second `node`
+    let mut value = Value::Struct(Fields::new());
+    for id in 0..4 {
+        let node = Fields::from_iter([("id", Value::UInt(id)), ("next", Value::Struct(Fields::from_iter([("node", value)])))]);
+        value = Value::Struct(node);
+    }
+    assert!(recursive(doc.encode_payload(Direction::Tx, "walk", &Fields::from_iter([("start", value)])).unwrap_err()));
+}
+
+const DOC: &str = r#"[device_info]
+name = "sensor"
+description = "A sensor"
+
+[uart]
+tx_format = [
+    { type = "SizeTotal", size_bits = 8, express_as = "Bytes" },
+    { type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+    { type = "Payload" },
+    { type = "Crc", algorithm = "Crc16XModem" },
+]
+rx_format = [
+    { type = "SizeTotal", size_bits = 8, express_as = "Bytes" },
+    { type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+    { type = "Payload" },
+    { type = "Crc", algorithm = "Crc16XModem" },
+]
+
+[structs.vec3]
+name = "vec3"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "LittleEndian" } },
+    { name = "y", bits = 12, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "z", bits = 4, type = { type = "Integer", signing = "OnesComplement", endianness = "BigEndian" } },
+]
+
+[payloads.tx.configure]
+description = "Configures"
+id = 0x01
+segments = [
+    { name = "magic", bits = 16, type = { type = "Const", data = [0xCA, 0xFE] } },
+    { name = "rate", bits = 32, type = { type = "Integer", signing = "Unsigned", endianness = "LittleEndian" } },
+    { name = "gain", bits = 64, type = { type = "FloatIEEE", endianness = "BigEndian" } },
+    { name = "name", bits = 48, type = { type = "StringUTF8" } },
+    { name = "key", bits = 24, type = { type = "Raw" } },
+    { name = "origin", struct_name = "vec3" },
+    { name = "count", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "points", type = { type = "Array", item_struct = "vec3" }, termination = { field_name = "count" } },
+    { name = "label", type = { type = "StringUTF8" }, termination = { sequence = [0x0D, 0x0A] } },
+    { name = "pair", type = { type = "Array", item_struct = "vec3" }, termination = { count = 2 } },
+    { name = "rest", type = { type = "Raw" } },
+]
+
+[payloads.rx.reading]
+description = "A reading"
+id = 0x02
+segments = [
+    { name = "temperature", bits = 32, type = { type = "FloatIEEE", endianness = "LittleEndian" } },
+    { name = "flags", bits = 3, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "level", bits = 5, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+]
+
+[transactions]
+"#;
+
+fn vec3(x: i64, y: i64, z: i64) -> Value {
+    Value::Struct(Fields::from_iter([("x", Value::Int(x)), ("y", Value::Int(y)), ("z", Value::Int(z))]))
+}
+
+/// Every field of `configure`, as the decoder returns them
+fn configure() -> Fields {
+    Fields::from_iter([
+        ("magic", Value::Bytes(vec![0xCA, 0xFE])),
+        ("rate", Value::UInt(0x0102_0304)),
+        ("gain", Value::Float(-0.125)),
+        ("name", Value::String("imu".to_owned())),
+        ("key", Value::Bytes(vec![0xDE, 0xAD, 0x01])),
+        ("origin", vec3(-300, 2047, -7)),
+        ("count", Value::UInt(2)),
+        ("points", Value::Array(vec![vec3(1, -2048, 3), vec3(32767, -1, 0)])),
+        ("label", Value::String("front".to_owned())),
+        ("pair", Value::Array(vec![vec3(-32768, 5, -1), vec3(7, 6, 5)])),
+        ("rest", Value::Bytes(vec![9, 8, 7])),
+    ])
+}
+
+#[test]
+fn payloads_round_trip_with_every_type() {
+    let doc = OpenPID::from_str(DOC).unwrap();
+    let bytes = doc.encode_payload(Direction::Tx, "configure", &configure()).unwrap();
+    #[rustfmt::skip]
+    let expected = [
+        0xCA, 0xFE, 0x04, 0x03, 0x02, 0x01, 0xBF, 0xC0, 0, 0, 0, 0, 0, 0, b'i', b'm', b'u', 0, 0, 0, 0xDE, 0xAD, 0x01,
+        0xD4, 0xFE, 0x7F, 0xF8, 0x02,
+        0x01, 0x00, 0x80, 0x03, 0xFF, 0x7F, 0xFF, 0xF0,
+        b'f', b'r', b'o', b'n', b't', 0x0D, 0x0A,
+        0x00, 0x80, 0x00, 0x5E, 0x07, 0x00, 0x00, 0x65,
+        9, 8, 7,
+    ];
+    assert_eq!(bytes, expected);
+
+    let decoded = doc.decode_payload(Direction::Tx, "configure", &bytes).unwrap();
+    assert_eq!(decoded.fields, configure());
+    assert_eq!(decoded.span_of("origin.y").unwrap(), Span { offset_bits: 25 * 8, len_bits: 12 });
+    assert_eq!(decoded.span_of("points[1]").unwrap(), Span { offset_bits: 32 * 8, len_bits: 32 });
+    assert_eq!(decoded.fields.lookup("points[1].x"), Some(&Value::Int(32767)));
+
+    // counts are filled in from what they count, and must agree with it when given
+    let mut implied = configure();
+    implied.0.retain(|(name, _)| name != "count" && name != "magic");
+    assert_eq!(doc.encode_payload(Direction::Tx, "configure", &implied).unwrap(), bytes);
+    implied.insert("count", Value::UInt(3));
+    assert!(matches!(doc.encode_payload(Direction::Tx, "configure", &implied), Err(CodecError::LengthMismatch { .. })));
+
+    // sub-byte fields share a byte
+    let reading = Fields::from_iter([("temperature", Value::Float(21.5)), ("flags", Value::UInt(0b101)), ("level", Value::Int(-3))]);
+    let bytes = doc.encode_payload(Direction::Rx, "reading", &reading).unwrap();
+    assert_eq!(bytes, [0x00, 0x00, 0xAC, 0x41, 0b101_11101]);
+    assert_eq!(doc.decode_payload(Direction::Rx, "reading", &bytes).unwrap().fields, reading);
+}
+
+#[test]
+fn frames_round_trip_and_identify_their_payload() {
+    let doc = OpenPID::from_str(DOC).unwrap();
+    let reading = Fields::from_iter([("temperature", Value::Float(-1.0)), ("flags", Value::UInt(7)), ("level", Value::Int(15))]);
+    let frame = doc.encode_frame(Direction::Rx, "reading", &reading).unwrap();
+    assert_eq!(frame[..2], [9, 0x02]);
+    assert_eq!(u16::from_be_bytes([frame[7], frame[8]]) as u32, Crc::Crc16XModem.compute(&frame[..7]));
+
+    let decoded = doc.decode_frame(Direction::Rx, &frame).unwrap();
+    assert_eq!(decoded.metadata, Fields::from_iter([("id", Value::UInt(2))]));
+    assert_eq!(decoded.payload.name, "reading");
+    assert_eq!(decoded.payload.fields, reading);
+    assert_eq!(decoded.payload_span, Span { offset_bits: 16, len_bits: 40 });
+
+    let frame = doc.encode_frame(Direction::Tx, "configure", &configure()).unwrap();
+    assert_eq!(doc.decode_frame(Direction::Tx, &frame).unwrap().payload.fields, configure());
+}
+
+#[test]
+fn bad_values_and_frames_are_reported() {
+    let doc = OpenPID::from_str(DOC).unwrap();
+    let reading = |flags: Value| Fields::from_iter([("temperature", Value::Float(0.0)), ("flags", flags), ("level", Value::Int(0))]);
+    assert!(matches!(doc.encode_payload(Direction::Rx, "reading", &reading(Value::UInt(8))), Err(CodecError::OutOfRange { bits: 3, .. })));
+    assert!(matches!(doc.encode_payload(Direction::Rx, "reading", &reading(Value::String("7".to_owned()))), Err(CodecError::TypeMismatch { .. })));
+    assert!(matches!(doc.encode_payload(Direction::Rx, "missing", &Fields::new()), Err(CodecError::UnknownPayload { .. })));
+
+    let mut name = configure();
+    name.insert("name", Value::String("too long".to_owned()));
+    assert!(matches!(doc.encode_payload(Direction::Tx, "configure", &name), Err(CodecError::LengthMismatch { expected: 6, found: 8, .. })));
+
+    let bytes = doc.encode_payload(Direction::Tx, "configure", &configure()).unwrap();
+    let mut corrupt = bytes.clone();
+    corrupt[0] = 0;
+    assert!(matches!(doc.decode_payload(Direction::Tx, "configure", &corrupt), Err(CodecError::ConstMismatch { .. })));
+    assert!(matches!(doc.decode_payload(Direction::Rx, "reading", &[0; 6]), Err(CodecError::TrailingData { bits: 8, .. })));
+    assert!(matches!(doc.decode_payload(Direction::Rx, "reading", &[0; 4]), Err(CodecError::UnexpectedEnd { .. })));
+
+    let mut frame = doc.encode_frame(Direction::Tx, "configure", &configure()).unwrap();
+    *frame.last_mut().unwrap() ^= 0xFF;
+    assert!(matches!(doc.decode_frame(Direction::Tx, &frame), Err(CodecError::CrcMismatch { .. })));
+}
+
+#[test]
+fn decoded_values_serialize_to_plain_json_and_back() {
+    let doc = OpenPID::from_str(DOC).unwrap();
+    let reading = Fields::from_iter([("temperature", Value::Float(21.5)), ("flags", Value::UInt(5)), ("level", Value::Int(-3))]);
+    let frame = doc.decode_frame(Direction::Rx, &doc.encode_frame(Direction::Rx, "reading", &reading).unwrap()).unwrap();
+
+    let json = serde_json::to_value(&frame).unwrap();
+    assert_eq!(json["payload"]["fields"], serde_json::json!({ "temperature": 21.5, "flags": 5, "level": -3 }));
+    assert_eq!(serde_json::from_str::<DecodedFrame>(&serde_json::to_string(&frame).unwrap()).unwrap(), DecodedFrame {
+        // untagged integers come back signed, which the encoder accepts for unsigned fields
+        metadata: Fields::from_iter([("id", Value::Int(2))]),
+        payload: DecodedPayload { fields: Fields::from_iter([("temperature", Value::Float(21.5)), ("flags", Value::Int(5)), ("level", Value::Int(-3))]), ..frame.payload.clone() },
+        ..frame
+    });
+
+    // a fixture written by hand, keeping its field order
+    let fixture: Fields = serde_json::from_str(r#"{ "rest": [9, 8, 7], "label": "front", "name": "imu", "key": [222, 173, 1], "rate": 16909060, "gain": -0.125,
+        "origin": { "x": -300, "y": 2047, "z": -7 }, "points": [{ "x": 1, "y": -2048, "z": 3 }, { "x": 32767, "y": -1, "z": 0 }],
+        "pair": [{ "x": -32768, "y": 5, "z": -1 }, { "x": 7, "y": 6, "z": 5 }] }"#).unwrap();
+    assert_eq!(fixture.iter().map(|(name, _)| name).take(3).collect::<Vec<_>>(), ["rest", "label", "name"]);
+    assert_eq!(doc.encode_payload(Direction::Tx, "configure", &fixture).unwrap(), doc.encode_payload(Direction::Tx, "configure", &configure()).unwrap());
+
+    let toml = toml::to_string(&Fields::from_iter([("name", Value::String("imu".to_owned())), ("key", Value::Bytes(vec![1, 2]))])).unwrap();
+    assert_eq!(toml, "name = \"imu\"\nkey = [1, 2]\n");
+}
@@ -0,0 +1,408 @@
+//! Each backend generated into a [VirtualTree] from one document, checking what it wrote
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use openpid::codegen::{BackendOptions, DirectorySink, Registry, VirtualTree};
+use openpid::prelude::*;
+
+const DOC: &str = r#"doc_version = "1.2.0"
+
+[device_info]
+name = "Bench IMU"
+description = "An IMU on a serial port"
+
+[uart]
+tx_format = [
+    { type = "SizeTotal", size_bits = 8, express_as = "Bytes" },
+    { type = "Metadata", segment = { name = "frame_id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+    { type = "Payload" },
+    { type = "Crc", algorithm = "Crc16XModem" },
+]
+rx_format = [
+    { type = "SizeTotal", size_bits = 8, express_as = "Bytes" },
+    { type = "Metadata", segment = { name = "frame_id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+    { type = "Payload" },
+    { type = "Crc", algorithm = "Crc16XModem" },
+]
+
+[structs.vec3]
+name = "vec3"
+description = "A vector"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "LittleEndian" }, units = "mg" },
+    { name = "y", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "LittleEndian" }, units = "mg" },
+    { name = "z", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "LittleEndian" }, units = "mg" },
+]
+
+[payloads.tx.set_rate]
+description = "Sets the sample rate"
+frame_id = 0x01
+segments = [{ name = "rate", bits = 16, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" }, description = "Samples a second" }]
+
+[payloads.tx.read]
+description = "Asks for samples"
+frame_id = 0x02
+segments = []
+
+[payloads.rx.samples]
+description = "Samples"
+frame_id = 0x82
+segments = [
+    { name = "temperature", bits = 32, type = { type = "FloatIEEE", endianness = "BigEndian" } },
+    { name = "name", bits = 64, type = { type = "StringUTF8" } },
+    { name = "count", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "accel", type = { type = "Array", item_struct = "vec3" }, termination = { field_name = "count" } },
+]
+
+[transactions.set_rate]
+description = "Sets the sample rate"
+actions = [{ type = "Tx", payload = "set_rate" }]
+returns = []
+
+[transactions.read]
+description = "Reads samples"
+actions = [{ type = "Flush" }, { type = "Tx", payload = "read" }, { type = "Sleep", milliseconds = 5 }, { type = "Rx", payload = "samples" }]
+returns = ["samples.temperature", "samples.accel"]
+"#;
+
+fn generate(backend: &str, options: &[&str]) -> VirtualTree {
+    let mut parsed = BackendOptions::new();
+    for option in options {
+        parsed.parse(option);
+    }
+    let ir = OpenPID::from_str(DOC).unwrap().to_ir().unwrap();
+    let mut tree = VirtualTree::new();
+    Registry::builtin().create(backend, &parsed).unwrap().generate(&ir, &mut tree).unwrap();
+    tree
+}
+
+/// Writes a backend's output into a fresh directory of its own, outside this workspace so a
+/// generated crate isn't taken for one of its members
+fn write_out(backend: &str, options: &[&str]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("openpid-generated-{backend}"));
+    let _ = std::fs::remove_dir_all(&dir);
+    generate(backend, options).write_to(&mut DirectorySink::new(&dir)).unwrap();
+    dir
+}
+
+/// Whether `program` is installed, for the tests that build generated code with it
+fn installed(program: &str) -> bool {
+    Command::new(program).arg("--version").output().is_ok_and(|output| output.status.success())
+}
+
+/// Runs `program` in `dir`, failing with its output unless it succeeds
+fn run(dir: &Path, program: &str, args: &[&str]) {
+    let output = Command::new(program).current_dir(dir).args(args).output().unwrap();
+    let printed = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success(), "{program} {args:?} failed in {}:\n{printed}", dir.display());
+}
+
+#[test]
+fn rust_depends_on_the_runtime_by_path() {
+    let tree = generate("rust", &[]);
+    let manifest = tree.get_text("Cargo.toml").unwrap();
+    let runtime = manifest.lines().find(|l| l.starts_with("openpid-runtime = ")).unwrap();
+    let path = runtime.trim_start_matches("openpid-runtime = { path = \"").trim_end_matches("\" }");
+    assert!(std::path::Path::new(path).join("Cargo.toml").is_file(), "{runtime}");
+
+    let tree = generate("rust", &["runtime-path=../runtime"]);
+    assert!(tree.get_text("Cargo.toml").unwrap().contains("openpid-runtime = { path = \"../runtime\" }"));
+}
+
+#[test]
+fn rust_harness_uses_sample_values() {
+    let tree = generate("rust", &["runtime-path=../runtime"]);
+    assert!(tree.paths().any(|p| p == "src/structs.rs"), "{:?}", tree.paths().collect::<Vec<_>>());
+    let harness = tree.get_text("tests/harness.rs").unwrap();
+    assert!(!harness.contains("::default()"), "{harness}");
+    assert!(harness.contains("let payload = tx::SetRate { rate: "), "{harness}");
+    assert!(harness.contains("structs::Vec3 { x: -"), "{harness}");
+
+    // the transaction checks what it returns against the samples it was given
+    assert!(harness.contains("let expected: driver::ReadResponse = driver::ReadResponse { temperature: -1.25, accel: heapless::Vec::from_slice(&[structs::Vec3 {"));
+    assert!(harness.contains("assert_eq!(device.read().unwrap(), expected);"));
+    assert!(harness.contains("device.set_rate(&tx::SetRate { rate: "));
+
+    let rx = tree.get_text("src/rx.rs").unwrap();
+    assert!(rx.contains("pub accel: heapless::Vec<crate::structs::Vec3, 64>,"), "{rx}");
+}
+
+#[test]
+fn rust_async_driver_and_harness() {
+    let tree = generate("rust", &["runtime-path=../runtime"]);
+    let asynch = tree.get_text("src/asynch.rs").unwrap();
+    assert!(asynch.contains("pub async fn read(&mut self) -> Result<ReadResponse, Error<T::Error>>"), "{asynch}");
+    let harness = tree.get_text("tests/asynch.rs").unwrap();
+    assert!(harness.contains("assert_eq!(device.read().await.unwrap(), expected);"), "{harness}");
+    assert!(harness.contains("async fn tokio_transaction_set_rate()"));
+    // reading flushes, which would drop what tokio has buffered
+    assert!(!harness.contains("async fn tokio_transaction_read()"));
+
+    let tree = generate("rust", &["runtime-path=../runtime", "async=false"]);
+    assert!(tree.get_text("src/asynch.rs").is_none() && tree.get_text("tests/asynch.rs").is_none());
+}
+
+#[test]
+#[ignore = "fetches embedded-hal, tokio and the rest from crates.io; run with `cargo test -- --ignored`"]
+fn rust_harness_builds_and_passes() {
+    let dir = write_out("rust", &[]);
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    // shared between runs, so the dependencies only build once
+    let target = std::env::temp_dir().join("openpid-generated-target");
+    let target = target.to_str().unwrap();
+    run(&dir, &cargo, &["test", "--features", "tokio", "--target-dir", target]);
+    run(&dir, &cargo, &["build", "--features", "async", "--target-dir", target]);
+}
+
+#[test]
+fn c_harness_fills_in_samples() {
+    let tree = generate("c", &[]);
+    assert_eq!(tree.paths().collect::<Vec<_>>(), ["Makefile", "bench_imu.c", "bench_imu.h", "test_bench_imu.c"]);
+    let header = tree.get_text("bench_imu.h").unwrap();
+    assert!(header.contains("bench_imu_vec3_t accel[64];\n    size_t accel_count;"), "{header}");
+
+    let harness = tree.get_text("test_bench_imu.c").unwrap();
+    assert!(harness.contains("strcpy(in.name, \"pen\");\n    in.accel[0].x = -"), "{harness}");
+    assert!(harness.contains("in.accel_count = 3;"));
+    assert!(harness.contains("expected.temperature = -1.25;"));
+    assert!(harness.contains("CHECK(memcmp(&result, &expected, sizeof result) == 0);"));
+    assert!(harness.contains("set_rate_tx.rate = "));
+
+    let tree = generate("c", &["prefix=imu", "tests=false"]);
+    assert_eq!(tree.paths().collect::<Vec<_>>(), ["imu.c", "imu.h"]);
+}
+
+#[test]
+fn c_harness_builds_and_passes() {
+    if !installed("cc") || !installed("make") {
+        eprintln!("skipped: no cc or make");
+        return;
+    }
+    let dir = write_out("c", &[]);
+    run(&dir, "make", &["test", "CFLAGS=-std=c99 -Wall -Wextra -pedantic -Werror"]);
+}
+
+#[test]
+fn python_tests_use_samples() {
+    let tree = generate("python", &[]);
+    let tests = tree.get_text("tests/test_bench_imu.py").unwrap();
+    assert!(tests.contains("from bench_imu import Serial, BenchImu, device, rx, structs, tx"), "{tests}");
+    assert!(tests.contains("value = rx.Samples(temperature=-1.25, name=\"pen\", accel=[structs.Vec3(x=-"));
+    assert!(tests.contains("    assert driver.read() == device.ReadResponse(temperature=-1.25, accel=[structs.Vec3("));
+    assert!(!tests.contains("tx.SetRate()"));
+
+    // nested structs name each other through the module, like payloads do
+    let doc = DOC.replace("[payloads.tx.set_rate]", "[structs.pair]\nname = \"pair\"\nfields = [{ name = \"a\", struct_name = \"vec3\" }]\n\n[payloads.tx.set_rate]");
+    let ir = OpenPID::from_str(&doc).unwrap().to_ir().unwrap();
+    let mut tree = VirtualTree::new();
+    Registry::builtin().create("python", &BackendOptions::new()).unwrap().generate(&ir, &mut tree).unwrap();
+    let structs = tree.get_text("bench_imu/structs.py").unwrap();
+    assert!(structs.contains("from . import structs, wire") && structs.contains("a: structs.Vec3 = field(default_factory=structs.Vec3)"), "{structs}");
+}
+
+#[test]
+fn cpp_harness_fills_in_samples() {
+    let tree = generate("cpp", &[]);
+    assert_eq!(tree.paths().collect::<Vec<_>>(), ["Makefile", "bench_imu.hpp", "test_bench_imu.cpp"]);
+    let harness = tree.get_text("test_bench_imu.cpp").unwrap();
+    assert!(harness.contains("    in.name.assign(\"pen\");\n    in.accel.resize(3);\n    in.accel[0].x = -"), "{harness}");
+    assert!(harness.contains("in.temperature = -1.25f;"));
+    assert!(harness.contains("ReadResponse expected{};\n    expected.temperature = -1.25f;"));
+    assert!(harness.contains("CHECK(result && result->accel == expected.accel);"));
+    assert!(harness.contains("device.set_rate(set_rate_tx)"));
+}
+
+#[test]
+fn cpp_harness_builds_and_passes() {
+    if !installed("c++") || !installed("make") {
+        eprintln!("skipped: no c++ or make");
+        return;
+    }
+    let dir = write_out("cpp", &[]);
+    run(&dir, "make", &["test", "CXXFLAGS=-std=c++17 -Wall -Wextra -pedantic -Werror"]);
+}
+
+#[test]
+fn micropython_tests_use_samples() {
+    let tree = generate("micropython", &[]);
+    assert_eq!(tree.paths().collect::<Vec<_>>(), ["bench_imu.py", "package.json", "test_bench_imu.py"]);
+    let tests = tree.get_text("test_bench_imu.py").unwrap();
+    assert!(tests.contains("value = bench_imu.Samples(temperature=-1.25, name=\"pen\", accel=[bench_imu.Vec3(x=-"), "{tests}");
+    assert!(tests.contains("    assert same(device.read(), (-1.25, [bench_imu.Vec3("));
+    assert!(!tests.contains("SetRate()"));
+}
+
+#[test]
+fn micropython_tests_pass_under_cpython() {
+    if !installed("python3") {
+        eprintln!("skipped: no python3");
+        return;
+    }
+    let dir = write_out("micropython", &[]);
+    run(&dir, "python3", &["test_bench_imu.py"]);
+}
+
+#[test]
+fn typescript_tests_use_samples() {
+    let tree = generate("typescript", &[]);
+    let tests = tree.get_text("test/bench-imu.test.ts").unwrap();
+    assert!(tests.contains("import { BenchImu, decode, encode, NodeStreamTransport, pack, rx, structs, tx, unpack, WebStreamTransport }"), "{tests}");
+    assert!(tests.contains("const value = rx.Samples.create({ temperature: -1.25, name: \"pen\", accel: [structs.Vec3.create({ x: -"));
+    assert!(tests.contains("assert.deepEqual(await device.read(), { temperature: -1.25, accel: [structs.Vec3.create({"));
+    assert!(tests.contains("await device.setRate(tx.SetRate.create({ rate: "));
+}
+
+#[test]
+fn go_tests_use_samples() {
+    let tree = generate("go", &[]);
+    let tests = tree.get_text("benchimu_test.go").unwrap();
+    assert!(tests.contains("{\"rx.Samples\", &Samples{Temperature: -1.25, Name: \"pen\", Accel: []Vec3{{X: -"), "{tests}");
+    assert!(tests.contains("return expect(got, ReadResponse{Temperature: -1.25, Accel: []Vec3{{X: -"));
+    assert!(tests.contains("return d.SetRate(&SetRate{Rate: "));
+}
+
+#[test]
+fn zig_tests_use_samples() {
+    let tree = generate("zig", &[]);
+    let tests = tree.get_text("src/test_bench_imu.zig").unwrap();
+    assert!(tests.contains("try roundTrip(dev.rx.Samples{ .temperature = -1.25, .name = try std.BoundedArray(u8, 8).fromSlice(\"pen\"), .accel = try std.BoundedArray(dev.structs.Vec3, 64).fromSlice(&[_]dev.structs.Vec3{ .{ .x = -"), "{tests}");
+    assert!(tests.contains("const expected = dev.ReadResponse{ .temperature = -1.25, .accel = "));
+    assert!(tests.contains("try expectSame(expected, try device.read());"));
+    assert!(tests.contains("try device.setRate(dev.tx.SetRate{ .rate = "));
+}
+
+#[test]
+fn unframed_variable_responses_are_unsupported() {
+    // without the [uart] table, nothing says where the samples end
+    let doc = format!("{}{}", &DOC[..DOC.find("[uart]").unwrap()], &DOC[DOC.find("[structs.vec3]").unwrap()..]);
+    let ir = OpenPID::from_str(&doc).unwrap().to_ir().unwrap();
+    for backend in ["c", "cpp", "micropython", "python", "typescript", "go", "zig"] {
+        let mut tree = VirtualTree::new();
+        let error = Registry::builtin().create(backend, &BackendOptions::new()).unwrap().generate(&ir, &mut tree).unwrap_err();
+        assert!(matches!(&error, CodegenError::Unsupported { backend: b, what } if *b == backend && what.contains("\"samples\"")), "{backend}: {error}");
+    }
+}
+
+#[test]
+fn markdown_links_its_diagrams() {
+    let tree = generate("markdown", &[]);
+    let mut paths = tree.paths().collect::<Vec<_>>();
+    paths.sort_unstable();
+    assert_eq!(
+        paths,
+        [
+            "bench-imu.md",
+            "diagrams/frame-rx.svg",
+            "diagrams/frame-tx.svg",
+            "diagrams/rx-samples.svg",
+            "diagrams/struct-vec3.svg",
+            "diagrams/tx-read.svg",
+            "diagrams/tx-set-rate.svg"
+        ]
+    );
+    let page = tree.get_text("bench-imu.md").unwrap();
+    assert!(page.contains("- [TX payloads](#tx): [read](#tx-read), [set\\_rate](#tx-set-rate)\n"), "{page}");
+    assert!(page.contains("| 1 | 1 byte | `frame_id` | u8. Values are listed with each payload |\n"));
+    assert!(page.contains("| 12 | 1 byte | `count` | u8 |  | Length of `accel`, 0 to 255 |  |  |\n"));
+    assert!(page.contains("![samples frame layout](diagrams/rx-samples.svg)\n"));
+    assert!(page.contains("```mermaid\nsequenceDiagram\n    title read\n"));
+
+    let tree = generate("markdown", &["diagrams=false", "file=docs/imu.md"]);
+    assert_eq!(tree.paths().collect::<Vec<_>>(), ["docs/imu.md"]);
+    assert!(!tree.get_text("docs/imu.md").unwrap().contains("!["));
+}
+
+#[test]
+fn html_is_one_page_with_its_diagrams_inline() {
+    let tree = generate("html", &[]);
+    assert_eq!(tree.paths().collect::<Vec<_>>(), ["bench-imu.html"]);
+    let page = tree.get_text("bench-imu.html").unwrap();
+    assert!(page.contains("<title>Bench IMU</title>"), "{page}");
+    assert!(page.contains("<li class=\"sub\"><a href=\"#rx-samples\">samples</a></li>"));
+    assert_eq!(page.matches("<svg ").count(), 6);
+    assert!(page.contains("<h2 id=\"transactions\">"));
+    assert!(page.trim_end().ends_with("</html>"));
+    assert_eq!(generate("html", &["diagrams=false"]).get_text("bench-imu.html").unwrap().matches("<svg ").count(), 0);
+}
+
+/// Each `<tag>` closed by a matching `</tag>`, other than the self-closing ones
+fn balanced(svg: &str) -> bool {
+    let mut open = Vec::new();
+    for tag in svg.split('<').skip(1).map(|t| &t[..t.find('>').unwrap()]) {
+        if let Some(name) = tag.strip_prefix('/') {
+            if open.pop() != Some(name) {
+                return false;
+            }
+        } else if !tag.ends_with('/') {
+            open.push(tag.split(' ').next().unwrap());
+        }
+    }
+    open.is_empty()
+}
+
+#[test]
+fn diagrams_draw_fields_to_scale() {
+    let tree = generate("markdown", &[]);
+    for path in tree.paths().filter(|p| p.ends_with(".svg")) {
+        let svg = tree.get_text(path).unwrap();
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" ") && balanced(svg), "{path}: {svg}");
+    }
+
+    // 20 pixels a bit, 32 bits a row
+    let svg = tree.get_text("diagrams/tx-set-rate.svg").unwrap();
+    assert!(svg.contains("<title>set_rate frame layout</title>"), "{svg}");
+    assert!(svg.contains("<g><title>frame_id: 8 bits = 0x01</title><rect x=\"204\" y=\"32\" width=\"160\" height=\"44\""));
+    assert!(svg.contains("<g><title>rate: 16 bits</title><rect x=\"364\" y=\"32\" width=\"320\" height=\"44\""));
+    assert!(svg.contains("<g><title>CRC-16/XMODEM: 16 bits</title><rect x=\"44\" y=\"76\" width=\"320\""));
+
+    // fields crossing rows are drawn in pieces, and variable ones by their size range
+    let svg = tree.get_text("diagrams/rx-samples.svg").unwrap();
+    assert_eq!(svg.matches("<g><title>name: 64 bits</title>").count(), 3, "{svg}");
+    assert!(svg.contains("<g><title>accel: 0 bytes to 1530 bytes</title>"));
+    let frame = tree.get_text("diagrams/frame-rx.svg").unwrap();
+    assert!(frame.contains("<g><title>frame_id: 8 bits</title>") && frame.contains("<g><title>payload: varies</title>"), "{frame}");
+}
+
+#[test]
+fn sequence_diagrams_in_each_format() {
+    let tree = generate("sequence", &[]);
+    assert_eq!(tree.paths().collect::<Vec<_>>(), ["read.mmd", "set-rate.mmd"]);
+    let read = tree.get_text("read.mmd").unwrap();
+    assert!(read.starts_with("sequenceDiagram\n    title read\n    participant Host\n    participant Device as Bench IMU\n"), "{read}");
+    assert!(read.contains("    Host->>Device: read (frame_id 0x02)\n    Note over Host,Device: no fields\n    Note over Host: wait 5 ms\n    Device->>Host: samples (frame_id 0x82)\n"));
+    assert!(read.trim_end().ends_with("Note over Host: returns temperature, accel"));
+
+    let tree = generate("sequence", &["format=d2"]);
+    let read = tree.get_text("read.d2").unwrap();
+    assert!(read.starts_with("\"read\": {\n  shape: sequence_diagram\n"), "{read}");
+    assert!(read.contains("  host -> device: \"read (frame_id 0x02)\"\n") && read.contains("  device -> host: \"samples (frame_id 0x82)\"\n"));
+
+    let tree = generate("sequence", &["format=plantuml"]);
+    let read = tree.get_text("read.puml").unwrap();
+    assert!(read.starts_with("@startuml\ntitle read\n") && read.trim_end().ends_with("@enduml"), "{read}");
+    assert!(read.contains("\n...5 ms...\n"));
+
+    let mut options = BackendOptions::new();
+    options.parse("format=svg");
+    assert!(Registry::builtin().create("sequence", &options).is_err());
+}
+
+#[test]
+fn wireshark_dissector_describes_fields_and_frames() {
+    let tree = generate("wireshark", &[]);
+    assert_eq!(tree.paths().collect::<Vec<_>>(), ["bench_imu.lua"]);
+    let lua = tree.get_text("bench_imu.lua").unwrap();
+    assert!(lua.contains("local proto = Proto(\"bench_imu\", \"Bench IMU (OpenPID)\")"), "{lua}");
+    assert!(lua.contains("f[\"frame.rx.frame_id\"] = ProtoField.uint8(\"bench_imu.frame.rx.frame_id\", \"frame_id\", base.DEC, { [130] = \"samples\" })"));
+    assert!(lua.contains("f[\"struct.vec3.x\"] = ProtoField.int16(\"bench_imu.struct.vec3.x\", \"x (mg)\", base.DEC, nil, nil, nil)"));
+    assert!(lua.contains("f[\"tx.set_rate.rate\"] = ProtoField.uint16(\"bench_imu.tx.set_rate.rate\", \"rate\", base.DEC, nil, nil, \"Samples a second\")"));
+    assert!(lua.contains("{ name = \"accel\", field = f[\"rx.samples.accel\"], kind = \"array\", struct = \"vec3\", length = { count = \"count\" } },"));
+    assert!(lua.contains("    { kind = \"payload\", bits = 0, after = 16 },\n    { kind = \"crc\", bits = 16, algorithm = \"crc16_xmodem\", field = f[\"frame.rx.crc\"] },\n"));
+    assert!(lua.contains("DissectorTable.get(\"wtap_encap\"):add((wtap_encaps or wtap).USER0, proto)"));
+
+    let tree = generate("wireshark", &["protocol=imu"]);
+    let lua = tree.get_text("imu.lua").unwrap();
+    assert!(lua.contains("Proto(\"imu\", ") && lua.contains("\"imu.rx.samples.temperature\"") && !lua.contains("bench_imu."), "{lua}");
+}
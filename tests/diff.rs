@@ -0,0 +1,112 @@
+//! Classifying changes between two versions of a document, and the version bump they need
+
+use openpid::diff::{diff, Bump, Diff, Impact};
+use openpid::OpenPID;
+
+const OLD: &str = r#"doc_version = "1.4.2"
+
+[device_info]
+name = "sensor"
+description = "A sensor"
+
+[uart]
+tx_format = [{ type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } }, { type = "Payload" }]
+rx_format = [{ type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } }, { type = "Payload" }]
+
+[structs.vec3]
+name = "vec3"
+description = "A vector"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "y", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+]
+
+[payloads.tx.read]
+description = "Reads"
+id = 0x01
+segments = [{ name = "rate", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } }]
+
+[payloads.rx.reading]
+description = "A reading"
+id = 0x02
+segments = [
+    { name = "accel", struct_name = "vec3" },
+    { name = "temperature", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+]
+
+[transactions.read]
+description = "Reads the acceleration"
+actions = [{ type = "Tx", payload = "read" }, { type = "Rx", payload = "reading" }]
+returns = ["reading.accel"]
+"#;
+
+const TEMPERATURE: &str = r#"    { name = "temperature", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+"#;
+const HUMIDITY: &str = r#"    { name = "humidity", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+"#;
+
+fn compare(new: &str) -> Diff {
+    let old = toml::from_str::<OpenPID>(OLD).unwrap().to_ir().unwrap();
+    let new = toml::from_str::<OpenPID>(new).unwrap().to_ir().unwrap();
+    diff(&old, &new)
+}
+
+/// The one change to `path`, as (wire, API)
+fn impact(diff: &Diff, path: &str) -> (Impact, Impact) {
+    let found = diff.changes.iter().filter(|c| c.path == path).collect::<Vec<_>>();
+    assert_eq!(found.len(), 1, "{diff:#?}");
+    (found[0].wire, found[0].api)
+}
+
+#[test]
+fn fields_added_anywhere_break_the_wire() {
+    // decoders reject trailing bytes, so a field the device appends breaks old drivers as much as
+    // one inserted before others
+    let appended = compare(&OLD.replace(TEMPERATURE, &format!("{TEMPERATURE}{HUMIDITY}")));
+    assert_eq!(impact(&appended, "payloads.rx.reading.humidity"), (Impact::Breaking, Impact::Compatible));
+    assert_eq!(appended.changes[0].description, "Field added");
+    assert_eq!(appended.bump(), Some(Bump::Major));
+    assert_eq!(appended.suggested_version("1.4.2").unwrap().to_string(), "2.0.0");
+
+    let inserted = compare(&OLD.replace(TEMPERATURE, &format!("{HUMIDITY}{TEMPERATURE}")));
+    assert_eq!(inserted.changes, appended.changes);
+    assert_eq!(inserted.suggested_version("1.4.2").unwrap().to_string(), "2.0.0");
+    assert_eq!(inserted.suggested_version("0.3.1").unwrap().to_string(), "0.4.0");
+
+    // drivers build what they send, so a new field there breaks their API too
+    let sent = compare(&OLD.replace("} }]\n\n[payloads.rx", &format!("}} }},\n{HUMIDITY}]\n\n[payloads.rx")));
+    assert_eq!(impact(&sent, "payloads.tx.read.humidity"), (Impact::Breaking, Impact::Breaking));
+}
+
+#[test]
+fn widths_ids_and_returns_break() {
+    let widened = compare(&OLD.replace(r#"{ name = "y", bits = 16"#, r#"{ name = "y", bits = 32"#));
+    assert_eq!(impact(&widened, "structs.vec3.y"), (Impact::Breaking, Impact::Breaking));
+    assert!(widened.changes[0].description.contains("16 to 32"));
+
+    let renumbered = compare(&OLD.replace("id = 0x02", "id = 0x03"));
+    assert_eq!(impact(&renumbered, "payloads.rx.reading.id"), (Impact::Breaking, Impact::None));
+
+    let returns = compare(&OLD.replace(r#"returns = ["reading.accel"]"#, r#"returns = ["reading.accel", "reading.temperature"]"#));
+    assert_eq!(impact(&returns, "transactions.read.returns"), (Impact::None, Impact::Breaking));
+    assert_eq!(returns.bump(), Some(Bump::Major));
+}
+
+#[test]
+fn removals_additions_and_documentation() {
+    let removed = OLD.replace("[transactions.read]\ndescription = \"Reads the acceleration\"\nactions = [{ type = \"Tx\", payload = \"read\" }, { type = \"Rx\", payload = \"reading\" }]\nreturns = [\"reading.accel\"]\n", "[transactions]\n");
+    let removed = compare(&removed.replace("[payloads.tx.read]\ndescription = \"Reads\"\nid = 0x01", "[payloads.tx.other]\ndescription = \"Reads\"\nid = 0x01"));
+    assert_eq!(impact(&removed, "payloads.tx.read"), (Impact::Breaking, Impact::Breaking));
+    assert_eq!(impact(&removed, "payloads.tx.other"), (Impact::Compatible, Impact::Compatible));
+    assert_eq!(impact(&removed, "transactions.read"), (Impact::None, Impact::Breaking));
+
+    let documented = compare(&OLD.replace("description = \"A vector\"", "description = \"An acceleration\""));
+    assert_eq!(impact(&documented, "structs.vec3"), (Impact::None, Impact::None));
+    assert_eq!(documented.bump(), Some(Bump::Patch));
+
+    let added = compare(&format!("{OLD}\n[payloads.rx.alarm]\ndescription = \"An alarm\"\nid = 0x03\nsegments = []\n"));
+    assert_eq!(impact(&added, "payloads.rx.alarm"), (Impact::Compatible, Impact::Compatible));
+    assert_eq!(added.bump(), Some(Bump::Minor));
+    assert_eq!(added.suggested_version("1.4.2").unwrap().to_string(), "1.5.0");
+    assert_eq!(compare(OLD).bump(), None);
+}
@@ -0,0 +1,107 @@
+//! The formatter and the edit API keep what the user wrote outside of what they change
+
+use openpid::codec::Direction;
+use openpid::config::{OpenPID, Payload};
+use openpid::edit::{Editor, FieldOwner};
+use openpid::format::format;
+
+const SOURCE: &str = r#"# A sensor
+
+[device_info]
+description = "A sensor"
+name = "Sensor"
+
+[structs.Reading]
+name = "Reading"
+description = "One reading"
+fields = [
+    { name = "count", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    # the samples
+    { name = "samples", type = { type = "Raw" }, termination = { type = "CountInPacket", field_name = "count" } },
+]
+
+[payloads.tx.Read]
+description = "Reads"
+segments = []
+
+[payloads.rx.ReadResp]
+description = "A reading"
+segments = [ { struct_name = "Reading", name = "reading" } ] # just the one
+
+[transactions.Read]
+description = "Reads a reading"
+actions = [
+    { type = "Tx", payload = "Read" },
+    { type = "Rx", payload = "ReadResp" },
+]
+returns = ["ReadResp.reading.samples"]
+"#;
+
+#[test]
+fn formatting_keeps_comments_and_is_idempotent() {
+    let formatted = format(SOURCE).unwrap();
+    assert!(formatted.starts_with("# A sensor\n"), "{formatted}");
+    assert!(formatted.contains("# the samples"), "{formatted}");
+    assert!(formatted.contains("# just the one"), "{formatted}");
+    assert!(formatted.find("name = \"Sensor\"").unwrap() < formatted.find("description = \"A sensor\"").unwrap(), "{formatted}");
+    assert_eq!(format(&formatted).unwrap(), formatted);
+
+    let bundled = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/openpid.toml")).unwrap();
+    let formatted = format(&bundled).unwrap();
+    assert!(formatted.contains("FrameID = 0x01"), "{formatted}");
+    assert_eq!(format(&formatted).unwrap(), formatted);
+}
+
+#[test]
+fn rows_line_up_only_with_rows_that_have_the_same_keys() {
+    let source = r#"[payloads.rx.Status]
+segments = [
+    { name = "level", bits = 16, type = { type = "Raw" } },
+    { name = "n", bits = 8, type = { type = "Raw" } },
+    { name = "label", type = { type = "StringUTF8" }, termination = { sequence = [0] } },
+    { name = "a_long_name", description = "Long", bits = 8, type = { type = "Raw" } },
+]
+"#;
+    let formatted = format(source).unwrap();
+    assert!(formatted.contains("    { name = \"level\", bits = 16, type = { type = \"Raw\" } },\n    { name = \"n\",     bits = 8,  type = { type = \"Raw\" } },\n"), "{formatted}");
+    assert!(formatted.contains("    { name = \"label\", type = { type = \"StringUTF8\" }, termination"), "{formatted}");
+    assert!(formatted.contains("    { name = \"a_long_name\", bits = 8, type = { type = \"Raw\" }, description = \"Long\" },"), "{formatted}");
+    assert_eq!(format(&formatted).unwrap(), formatted);
+}
+
+#[test]
+fn renaming_a_struct_field_follows_references() {
+    let mut editor = Editor::parse(SOURCE).unwrap();
+    editor.rename_field(FieldOwner::Struct("Reading"), "count", "length").unwrap();
+    editor.rename_field(FieldOwner::Struct("Reading"), "samples", "values").unwrap();
+    editor.rename_struct("Reading", "Sample").unwrap();
+    editor.rename_payload(Direction::Rx, "ReadResp", "Reading").unwrap();
+    let edited = editor.to_string();
+
+    assert!(edited.contains(r#"field_name = "length""#), "{edited}");
+    assert!(edited.contains(r#"returns = ["Reading.reading.values"]"#), "{edited}");
+    assert!(edited.contains(r#"{ struct_name = "Sample", name = "reading" } ] # just the one"#), "{edited}");
+    assert!(edited.contains(r#"{ type = "Rx", payload = "Reading" },"#), "{edited}");
+    assert!(edited.contains("[structs.Sample]\nname = \"Sample\""), "{edited}");
+    assert!(edited.contains("    # the samples\n"), "{edited}");
+    let doc: OpenPID = toml::from_str(&edited).unwrap();
+    doc.to_ir().unwrap();
+
+    assert!(editor.rename_field(FieldOwner::Struct("Sample"), "missing", "x").is_err());
+    assert!(editor.rename_field(FieldOwner::Struct("Sample"), "length", "values").is_err());
+}
+
+#[test]
+fn added_payload_goes_after_its_direction() {
+    let mut editor = Editor::parse(SOURCE).unwrap();
+    let payload: Payload = toml::from_str(r#"description = "Stops"
+segments = [{ name = "now", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } }]"#).unwrap();
+    editor.add_payload(Direction::Tx, "Stop", &payload).unwrap();
+    let edited = editor.to_string();
+
+    assert!(edited.starts_with(SOURCE.split("[payloads.rx").next().unwrap()), "{edited}");
+    assert!(edited.contains("\n[payloads.tx.Stop]\ndescription = \"Stops\"\nsegments = [\n    { name = \"now\","), "{edited}");
+    assert!(edited.find("[payloads.tx.Stop]").unwrap() < edited.find("[payloads.rx.ReadResp]").unwrap(), "{edited}");
+    assert!(editor.add_payload(Direction::Tx, "Stop", &payload).is_err());
+    toml::from_str::<OpenPID>(&edited).unwrap().to_ir().unwrap();
+}
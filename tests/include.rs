@@ -0,0 +1,101 @@
+//! Documents split across files: path and package imports, namespaces, conflicts and cycles
+
+use std::path::{Path, PathBuf};
+
+use openpid::include::{self, IncludeError, Packages};
+
+const ENVELOPE: &str = r#"[uart]
+tx_format = [{ type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } }, { type = "Payload" }]
+rx_format = [{ type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } }, { type = "Payload" }]
+"#;
+
+const STRUCTS: &str = r#"[structs.vec3]
+name = "vec3"
+description = "A vector"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "y", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "z", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+]
+
+[structs.samples]
+name = "samples"
+description = "Some vectors"
+fields = [
+    { name = "count", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "items", type = { type = "Array", item_struct = "vec3" }, termination = { type = "CountInPacket", field_name = "count" } },
+]
+"#;
+
+const DEVICE: &str = r#"imports = [{ path = "common/envelope.toml" }, { package = "imu", namespace = "imu" }]
+
+[device_info]
+name = "sensor"
+description = "A sensor"
+
+[payloads.tx.read]
+description = "Reads"
+id = 0x01
+segments = []
+
+[payloads.rx.reading]
+description = "A reading"
+id = 0x02
+segments = [{ name = "accel", struct_name = "imu_vec3" }, { name = "history", struct_name = "imu_samples" }]
+
+[transactions.read]
+description = "Reads the acceleration"
+actions = [{ type = "Tx", payload = "read" }, { type = "Rx", payload = "reading" }]
+returns = ["reading.accel"]
+"#;
+
+/// A fresh directory holding `files`, with the device document as `device/openpid.toml`
+fn tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("openpid-include-{name}"));
+    let _ = std::fs::remove_dir_all(&root);
+    for (path, source) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, source).unwrap();
+    }
+    root
+}
+
+fn packages(root: &Path) -> Packages {
+    Packages { dirs: vec![root.join("packages")] }
+}
+
+#[test]
+fn imports_merge_into_one_document() {
+    let root = tree("merge", &[("device/openpid.toml", DEVICE), ("device/common/envelope.toml", ENVELOPE), ("packages/imu/openpid.toml", STRUCTS)]);
+    let doc = include::load_with(&root.join("device/openpid.toml"), &packages(&root)).unwrap();
+    assert!(doc.imports.is_empty());
+    assert!(doc.uart.is_some());
+    assert_eq!(doc.structs.keys().collect::<Vec<_>>(), ["imu_samples", "imu_vec3"]);
+    assert_eq!(doc.structs["imu_vec3"].name, "imu_vec3");
+
+    let ir = doc.to_ir().unwrap();
+    let history = ir.get_struct(&openpid::ir::Name::new("imu_samples")).unwrap();
+    assert!(history.fields.iter().any(|f| f.name.raw() == "items"));
+}
+
+#[test]
+fn packages_missing_or_conflicting_are_errors() {
+    let root = tree("missing", &[("device/openpid.toml", DEVICE), ("device/common/envelope.toml", ENVELOPE)]);
+    let error = include::load_with(&root.join("device/openpid.toml"), &packages(&root)).unwrap_err();
+    assert!(matches!(&error, IncludeError::NoPackage { name, .. } if name == "imu"), "{error}");
+
+    // without a namespace the package's vec3 lands on the document's own, which differs
+    let device = DEVICE.replace(r#", namespace = "imu""#, "").replace("imu_", "") + "\n[structs.vec3]\nname = \"vec3\"\ndescription = \"Another vector\"\nfields = []\n";
+    let root = tree("conflict", &[("device/openpid.toml", &device), ("device/common/envelope.toml", ENVELOPE), ("packages/imu/openpid.toml", STRUCTS)]);
+    let error = include::load_with(&root.join("device/openpid.toml"), &packages(&root)).unwrap_err();
+    assert!(matches!(&error, IncludeError::Conflict { kind: "struct", name, .. } if name == "vec3"), "{error}");
+}
+
+#[test]
+fn a_file_importing_itself_is_a_cycle() {
+    let envelope = format!("imports = [{{ path = \"../openpid.toml\" }}]\n{ENVELOPE}");
+    let root = tree("cycle", &[("device/openpid.toml", DEVICE), ("device/common/envelope.toml", &envelope), ("packages/imu/openpid.toml", STRUCTS)]);
+    let error = include::load_with(&root.join("device/openpid.toml"), &packages(&root)).unwrap_err();
+    assert!(matches!(error, IncludeError::Cycle { .. }), "{error}");
+}
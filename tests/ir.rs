@@ -0,0 +1,220 @@
+//! Lowering documents into the IR backends generate from, and the sample values their tests use
+
+use std::str::FromStr;
+
+use openpid::codegen::{CodegenError, Layout};
+use openpid::ir::{Action, FlatElement, FrameElement, Length, Name, Type};
+use openpid::prelude::*;
+
+const DOC: &str = r#"[device_info]
+name = "Bench IMU"
+description = "An IMU"
+
+[uart]
+tx_format = [
+    { type = "Const", data = [0xAA] },
+    { type = "SizeOfElements", size_bits = 8, express_as = "Bytes", elements = [
+        { type = "Metadata", segment = { name = "frame_id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+        { type = "Payload" },
+    ] },
+    { type = "Crc", algorithm = "Crc16XModem" },
+]
+rx_format = [
+    { type = "Metadata", segment = { name = "frame_id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+    { type = "Payload" },
+]
+
+[structs.sample]
+name = "sample"
+fields = [
+    { name = "at", bits = 32, type = { type = "Integer", signing = "Unsigned", endianness = "LittleEndian" }, units = "ms" },
+    { name = "accel", struct_name = "vec3" },
+]
+
+[structs.vec3]
+name = "vec3"
+fields = [
+    { name = "x", bits = 12, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "y", bits = 4, type = { type = "Integer", signing = "OnesComplement", endianness = "BigEndian" } },
+    { name = "z", bits = 1, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "pad", bits = 7, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+]
+
+[payloads.tx.set_name]
+description = "Names the device"
+frame_id = 1
+segments = [
+    { name = "magic", bits = 16, type = { type = "Const", data = [0xCA, 0xFE] } },
+    { name = "name", bits = 64, type = { type = "StringUTF8" }, description = "Up to 8 bytes" },
+    { name = "key", bits = 24, type = { type = "Raw" } },
+    { name = "gain", bits = 64, type = { type = "FloatIEEE", endianness = "BigEndian" } },
+    { name = "note", type = { type = "StringUTF8" }, termination = { sequence = [0x6F] } },
+    { name = "rest", type = { type = "Raw" } },
+]
+
+[payloads.rx.samples]
+description = "Samples"
+frame_id = [2, 3]
+segments = [
+    { name = "count", bits = 2, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "level", bits = 6, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "samples", type = { type = "Array", item_struct = "sample" }, termination = { field_name = "count" } },
+    { name = "latest", struct_name = "sample" },
+    { name = "pair", type = { type = "Array", item_struct = "vec3" }, termination = { count = 2 } },
+    { name = "temperature", bits = 32, type = { type = "FloatIEEE", endianness = "LittleEndian" } },
+]
+
+[transactions.read]
+description = "Reads"
+actions = [{ type = "Flush" }, { type = "Tx", payload = "set_name" }, { type = "Sleep", milliseconds = 3 }, { type = "Rx", payload = "samples" }]
+returns = ["samples.level", "samples.latest.accel", "samples.samples"]
+"#;
+
+#[test]
+fn names_split_into_words() {
+    for raw in ["GetModInfo", "get_mod_info", "get-mod-info", "getModInfo"] {
+        let name = Name::new(raw);
+        assert_eq!(name.words(), ["get", "mod", "info"], "{raw}");
+        assert_eq!(name.raw(), raw);
+        assert_eq!((name.snake(), name.screaming(), name.kebab(), name.pascal(), name.camel()), (
+            "get_mod_info".to_owned(),
+            "GET_MOD_INFO".to_owned(),
+            "get-mod-info".to_owned(),
+            "GetModInfo".to_owned(),
+            "getModInfo".to_owned()
+        ));
+    }
+    assert_eq!(Name::new("vec3").pascal(), "Vec3");
+    assert_eq!(Name::new("Bench IMU").snake(), "bench_imu");
+}
+
+#[test]
+fn documents_lower_to_resolved_types() {
+    let ir = OpenPID::from_str(DOC).unwrap().to_ir().unwrap();
+    assert_eq!(ir.device.name.snake(), "bench_imu");
+
+    // structs come after the structs they use
+    assert_eq!(ir.structs.iter().map(|s| s.name.raw()).collect::<Vec<_>>(), ["vec3", "sample"]);
+    assert_eq!(ir.structs[1].size, Size::Fixed(56));
+    assert_eq!(ir.structs[1].fields[0].units.as_deref(), Some("ms"));
+
+    let set_name = &ir.tx[0];
+    let types = set_name.fields.iter().map(|f| f.ty.clone()).collect::<Vec<_>>();
+    assert_eq!(types[..4], [
+        Type::Const(vec![0xCA, 0xFE]),
+        Type::String(Length::Capacity(8)),
+        Type::Bytes(Length::Fixed(3)),
+        Type::Float { bits: 64, endianness: Endianness::BigEndian }
+    ]);
+    assert_eq!(types[4..], [Type::String(Length::Sequence(vec![0x6F])), Type::Bytes(Length::Remainder)]);
+    assert_eq!(set_name.fields.iter().map(|f| f.offset_bits).collect::<Vec<_>>(), [Some(0), Some(16), Some(80), Some(104), Some(168), None]);
+    assert_eq!(set_name.field("name").unwrap().description.as_deref(), Some("Up to 8 bytes"));
+    assert!(!set_name.field("magic").unwrap().is_user_facing());
+    assert_eq!(set_name.size, Size::Variable { min_bits: 176, max_bits: None });
+
+    // the count field knows what it counts, and the array is bounded by its width
+    let samples = &ir.rx[0];
+    let count = samples.field("count").unwrap();
+    assert_eq!(count.count_of, Some(Name::new("samples")));
+    assert!(!count.is_user_facing());
+    assert_eq!(samples.field("samples").unwrap().ty, Type::Array { item: Name::new("sample"), len: Length::CountField(Name::new("count")) });
+    assert_eq!(samples.field("samples").unwrap().size, Size::Variable { min_bits: 0, max_bits: Some(3 * 56) });
+    assert_eq!(samples.field("pair").unwrap().size, Size::Fixed(48));
+    assert_eq!(samples.metadata("frame_id").unwrap().packed(), Some(vec![2, 3]));
+}
+
+#[test]
+fn frame_formats_and_transactions_lower() {
+    let ir = OpenPID::from_str(DOC).unwrap().to_ir().unwrap();
+    let framing = ir.framing.as_ref().unwrap();
+    assert!(matches!(&framing.tx[1], FrameElement::SizeOfElements { bits: 8, elements, .. } if elements.len() == 2));
+    assert_eq!(framing.tx.iter().map(FrameElement::envelope_bits).sum::<u64>(), 8 + 8 + 8 + 16);
+    assert_eq!(framing.metadata(Direction::Tx).iter().map(|(name, _)| name.raw()).collect::<Vec<_>>(), ["frame_id"]);
+
+    let flat = framing.flatten(Direction::Tx).unwrap();
+    assert_eq!(flat[1], FlatElement::SizeOfElements { bits: 8, unit: BitsOrBytes::Bytes, covers: 2, static_bits: 8, has_payload: true });
+    assert_eq!(flat[2], FlatElement::Metadata { name: Name::new("frame_id"), bits: 8, endianness: Endianness::BigEndian });
+    assert_eq!(flat.iter().map(FlatElement::bits).collect::<Vec<_>>(), [8, 8, 8, 0, 16]);
+
+    let read = &ir.transactions[0];
+    assert_eq!(read.actions, [Action::Flush, Action::Tx(Name::new("set_name")), Action::Sleep { milliseconds: 3 }, Action::Rx(Name::new("samples"))]);
+    assert_eq!(read.returns.iter().map(|r| r.name.raw()).collect::<Vec<_>>(), ["level", "accel", "samples"]);
+    assert_eq!(read.returns[1].path, [Name::new("latest"), Name::new("accel")]);
+    assert_eq!(read.returns[1].ty, Type::Struct(Name::new("vec3")));
+
+    let layout = Layout::new(&ir, 16);
+    assert_eq!(layout.return_field(&read.returns[1]).unwrap().name.raw(), "accel");
+    assert_eq!(layout.return_siblings(&read.returns[1]).len(), 2);
+    assert_eq!(layout.max_frame_len(Direction::Rx), 1 + (8 + 3 * 56 + 56 + 48 + 32) / 8);
+}
+
+#[test]
+fn every_problem_is_reported_at_once() {
+    let broken = DOC
+        .replace("struct_name = \"vec3\" }", "struct_name = \"vec4\" }")
+        .replace("field_name = \"count\"", "field_name = \"level_count\"")
+        .replace("{ name = \"temperature\"", "{ name = \"tail\", type = { type = \"Raw\" } },\n    { name = \"Level\", bits = 8, type = { type = \"Raw\" } },\n    { name = \"temperature\"")
+        .replace("frame_id = 1\n", "")
+        .replace("\"samples.level\"", "\"samples.missing\"")
+        .replace("payload = \"set_name\"", "payload = \"rename\"");
+    let Err(CodegenError::Invalid(errors)) = OpenPID::from_str(&broken).unwrap().to_ir() else { panic!("the broken document lowered") };
+    let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+    for expected in [
+        "Undefined struct \"vec4\" referenced by [Struct sample]->accel",
+        "samples->samples is counted by \"level_count\", which must be an integer field before it",
+        "Payload \"set_name\" has no value for metadata \"frame_id\"",
+        "Unterminated unsized reads not possible. One was found in samples->tail",
+        "Undefined TX payload \"rename\" referenced by transaction \"read\"",
+        "Transaction \"read\" returns \"samples.missing\", which isn't a field of a payload it receives",
+        "Field names \"level\" and \"Level\" would clash in generated code",
+    ] {
+        assert!(errors.iter().any(|e| e == expected), "no \"{expected}\" in {errors:#?}");
+    }
+}
+
+#[test]
+fn clashing_returns_are_prefixed_with_their_payload() {
+    let doc = DOC.replace("[transactions.read]", r#"[payloads.rx.status]
+description = "Status"
+frame_id = 4
+segments = [{ name = "level", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } }]
+
+[transactions.both]
+description = "Reads both"
+actions = [{ type = "Rx", payload = "samples" }, { type = "Rx", payload = "status" }]
+returns = ["samples.level", "status.level", "samples.temperature"]
+
+[transactions.read]"#);
+    let ir = OpenPID::from_str(&doc).unwrap().to_ir().unwrap();
+    assert_eq!(ir.transactions[0].returns.iter().map(|r| r.name.raw()).collect::<Vec<_>>(), ["samples_level", "status_level", "temperature"]);
+}
+
+#[test]
+fn samples_fit_their_fields_and_are_not_zero() {
+    let doc = OpenPID::from_str(DOC).unwrap();
+    let ir = doc.to_ir().unwrap();
+    let layout = Layout::new(&ir, 16);
+    for payload in ir.all_payloads() {
+        let sample = layout.sample_fields(&payload.fields, 0);
+        let bytes = doc.encode_payload(payload.direction, payload.name.raw(), &sample).unwrap();
+        assert_eq!(doc.decode_payload(payload.direction, payload.name.raw(), &bytes).unwrap().fields, sample, "{}", payload.name);
+        assert_eq!(layout.sample_fields(&payload.fields, 0), sample, "samples must be the same every time");
+    }
+
+    let samples = layout.sample_fields(&ir.rx[0].fields, 0);
+    assert_eq!(samples.get("count"), Some(&Value::UInt(3)));
+    assert!(matches!(samples.get("level"), Some(Value::Int(i)) if *i < 0));
+    let Some(Value::Array(pair)) = samples.get("pair") else { panic!("pair isn't an array") };
+    assert_ne!(pair[0], pair[1]);
+    let Some(Value::Struct(vec3)) = &pair.first() else { panic!("pair holds structs") };
+    assert!(vec3.iter().all(|(_, v)| *v != Value::Int(0) && *v != Value::UInt(0)), "{vec3:?}");
+
+    let set_name = layout.sample_fields(&ir.tx[0].fields, 0);
+    assert_eq!(set_name.get("magic"), Some(&Value::Bytes(vec![0xCA, 0xFE])));
+    assert!(matches!(set_name.get("note"), Some(Value::String(s)) if !s.is_empty() && !s.contains('o')));
+    assert!(matches!(set_name.get("key"), Some(Value::Bytes(b)) if b.len() == 3 && !b.contains(&0)));
+
+    // a capacity of one can only hold one
+    let tight = Layout::new(&ir, 1);
+    assert_eq!(tight.sample_fields(&ir.rx[0].fields, 0).get("count"), Some(&Value::UInt(1)));
+}
@@ -0,0 +1,114 @@
+//! The Kaitai Struct backend and importer, checked against each other: a document exported and
+//! imported again must encode the same frames
+
+use std::str::FromStr;
+
+use openpid::codegen::{BackendOptions, Layout, Registry, VirtualTree};
+use openpid::import::{kaitai, ImportError};
+use openpid::prelude::*;
+
+const DOC: &str = r#"[device_info]
+name = "sensor"
+description = "A sensor"
+
+[uart]
+tx_format = [
+    { type = "SizeTotal", size_bits = 8, express_as = "Bytes" },
+    { type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+    { type = "Payload" },
+    { type = "Crc", algorithm = "Crc16XModem" },
+]
+rx_format = [
+    { type = "Const", data = [0xAA] },
+    { type = "SizeOfPayload", size_bits = 8, express_as = "Bytes" },
+    { type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+    { type = "Payload" },
+    { type = "Crc", algorithm = "Crc32" },
+]
+
+[structs.point]
+name = "point"
+description = "A point"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "LittleEndian" } },
+    { name = "y", bits = 32, type = { type = "FloatIEEE", endianness = "BigEndian" } },
+]
+
+[payloads.tx.ping]
+description = "Pings"
+id = 0x01
+segments = []
+
+[payloads.rx.track]
+description = "A track"
+id = 0x81
+segments = [
+    { name = "serial", bits = 24, type = { type = "Raw" } },
+    { name = "count", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "points", type = { type = "Array", item_struct = "point" }, termination = { field_name = "count" } },
+    { name = "label", type = { type = "StringUTF8" }, termination = { sequence = [0] } },
+]
+
+[payloads.rx.status]
+description = "Status"
+id = 0x82
+segments = [{ name = "level", bits = 16, type = { type = "Integer", signing = "Unsigned", endianness = "LittleEndian" } }]
+
+[transactions]
+"#;
+
+fn export(doc: &str, direction: &str) -> String {
+    let ir = OpenPID::from_str(doc).unwrap().to_ir().unwrap();
+    let mut options = BackendOptions::new();
+    options.parse(&format!("direction={direction}"));
+    let mut tree = VirtualTree::new();
+    Registry::builtin().create("kaitai", &options).unwrap().generate(&ir, &mut tree).unwrap();
+    let path = format!("sensor_{direction}.ksy");
+    assert_eq!(tree.paths().collect::<Vec<_>>(), [path.as_str()]);
+    tree.get_text(&path).unwrap().to_owned()
+}
+
+#[test]
+fn frames_become_a_switch_on_their_metadata() {
+    let ksy = export(DOC, "rx");
+    assert!(ksy.starts_with("meta:\n  id: sensor\n  title: \"sensor\"\n  bit-endian: be\n"), "{ksy}");
+    assert!(ksy.contains("  - id: payload\n    size: payload_size\n    type:\n      switch-on: id\n      cases:\n        0x82: rx_status\n        0x81: rx_track\n"));
+    assert!(ksy.contains("      - id: points\n        type: point\n        repeat: expr\n        repeat-expr: count\n"));
+    assert!(ksy.contains("      - id: x\n        type: s2le\n"));
+
+    // one payload is still switched on, so that importing it finds the envelope again
+    let ksy = export(DOC, "tx");
+    assert!(ksy.contains("      switch-on: id\n      cases:\n        0x1: tx_ping\n"), "{ksy}");
+}
+
+#[test]
+fn exported_documents_import_to_the_same_frames() {
+    let original = OpenPID::from_str(DOC).unwrap();
+    let ir = original.to_ir().unwrap();
+    let imported = kaitai::import(&export(DOC, "rx"), Direction::Tx).unwrap();
+    assert_eq!(imported.unmapped, ["seq: the frame format is used for both directions, since a .ksy file only describes one"]);
+    let document = imported.document;
+    assert_eq!(document.payloads.rx.keys().collect::<Vec<_>>(), ["status", "track"]);
+    assert!(document.payloads.tx.is_empty() && document.structs.contains_key("point"));
+
+    for payload in &ir.rx {
+        let fields = Layout::new(&ir, 64).sample_fields(&payload.fields, 0);
+        let frame = original.encode_frame(Direction::Rx, payload.name.raw(), &fields).unwrap();
+        assert_eq!(document.encode_frame(Direction::Rx, payload.name.raw(), &fields).unwrap(), frame, "{}", payload.name);
+        assert_eq!(document.decode_frame(Direction::Rx, &frame).unwrap().payload.fields, fields);
+    }
+}
+
+#[test]
+fn what_doesnt_map_is_listed() {
+    let ksy = "meta:\n  id: thing\n  bit-endian: le\n  imports: [common]\nseq:\n  - id: a\n    type: u2\n  - id: b\n    type: f4le\n";
+    let imported = kaitai::import(ksy, Direction::Rx).unwrap();
+    assert!(imported.unmapped.iter().any(|note| note.starts_with("meta/bit-endian: ")), "{:?}", imported.unmapped);
+    assert!(imported.unmapped.iter().any(|note| note.starts_with("meta/imports: ")));
+    let thing = &imported.document.payloads.rx["thing"];
+    assert_eq!(thing.segments.len(), 2);
+    assert!(imported.to_toml().unwrap().starts_with("# Imported with these differences from the original:\n#   meta/"));
+
+    assert!(matches!(kaitai::import("meta:\n  title: x\n", Direction::Rx), Err(ImportError::Missing { what: "meta/id" })));
+    assert!(matches!(kaitai::import("meta: [", Direction::Rx), Err(ImportError::Syntax(_))));
+}
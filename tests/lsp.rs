@@ -0,0 +1,111 @@
+//! The language server's position conversions, and requests through an in-memory connection
+#![cfg(feature = "lsp")]
+
+use std::str::FromStr;
+use std::thread;
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{DidOpenTextDocument, Exit, Initialized, Notification as _, PublishDiagnostics};
+use lsp_types::request::{GotoDefinition, HoverRequest, Initialize, Shutdown};
+use lsp_types::{
+    DidOpenTextDocumentParams, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams, InitializeParams, InitializedParams, Position,
+    PublishDiagnosticsParams, TextDocumentIdentifier, TextDocumentItem, TextDocumentPositionParams, Uri,
+};
+use openpid::lsp::{offset, position, serve_connection};
+use serde_json::Value;
+
+const SOURCE: &str = r#"[device_info]
+name = "sensor"
+description = "A sensor"
+
+[uart]
+tx_format = [{ type = "Payload" }]
+rx_format = [{ type = "Payload" }]
+
+[structs.vec3]
+name = "vec3"
+description = "A vector"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "y", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+]
+
+[payloads.rx.reading]
+description = "A reading"
+segments = [{ name = "accel", description = "±2 g 🚀", struct_name = "vec3" }]
+
+[transactions]
+"#;
+
+/// Where `skip` bytes into the first `needle` is in `SOURCE`, counted out independently of [position]
+fn at(needle: &str, skip: usize) -> Position {
+    let at = SOURCE.find(needle).unwrap() + skip;
+    let line_start = SOURCE[..at].rfind('\n').unwrap() + 1;
+    Position { line: SOURCE[..at].matches('\n').count() as u32, character: SOURCE[line_start..at].encode_utf16().count() as u32 }
+}
+
+#[test]
+fn characters_count_utf16_units() {
+    let text = "a = 1\nb = \"±🚀\" # x\n";
+    // `±` is one UTF-16 unit and two bytes, `🚀` two units and four bytes
+    for (character, byte) in [(0, 6), (5, 11), (6, 13), (8, 17), (9, 18), (11, 20)] {
+        let at = Position { line: 1, character };
+        assert_eq!(offset(text, at), byte, "{at:?}");
+        assert_eq!(position(text, byte), at, "{byte}");
+    }
+
+    // past the end of a line stops at its newline, and past the last line at the end of the text
+    assert_eq!(offset(text, Position { line: 1, character: 40 }), text.find(" x\n").unwrap() + 2);
+    assert_eq!(offset(text, Position { line: 7, character: 0 }), text.len());
+    assert_eq!(position(text, text.len()), Position { line: 2, character: 0 });
+}
+
+fn request<R: lsp_types::request::Request>(client: &Connection, id: i32, params: R::Params) -> Value {
+    client.sender.send(Message::Request(Request::new(RequestId::from(id), R::METHOD.to_owned(), params))).unwrap();
+    loop {
+        match client.receiver.recv().unwrap() {
+            Message::Response(Response { id: got, result, error, .. }) if got == RequestId::from(id) => {
+                assert!(error.is_none(), "{error:?}");
+                return result.unwrap_or(Value::Null);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn notify<N: lsp_types::notification::Notification>(client: &Connection, params: N::Params) {
+    client.sender.send(Message::Notification(Notification::new(N::METHOD.to_owned(), params))).unwrap();
+}
+
+#[test]
+fn hover_and_definition_round_trip() {
+    let (server, client) = Connection::memory();
+    let server = thread::spawn(move || serve_connection(server).unwrap());
+
+    request::<Initialize>(&client, 1, InitializeParams::default());
+    notify::<Initialized>(&client, InitializedParams {});
+    let uri = Uri::from_str("untitled:openpid.toml").unwrap();
+    notify::<DidOpenTextDocument>(&client, DidOpenTextDocumentParams { text_document: TextDocumentItem::new(uri.clone(), "toml".to_owned(), 1, SOURCE.to_owned()) });
+    let Message::Notification(published) = client.receiver.recv().unwrap() else { panic!("expected diagnostics") };
+    assert_eq!(published.method, PublishDiagnostics::METHOD);
+    let published: PublishDiagnosticsParams = serde_json::from_value(published.params).unwrap();
+    assert_eq!(published.diagnostics, []);
+
+    // `vec3` comes after multi-byte characters on its line, so a byte column would land past it
+    let params = TextDocumentPositionParams { text_document: TextDocumentIdentifier { uri: uri.clone() }, position: at(r#""vec3" }]"#, 2) };
+    let definition =
+        GotoDefinitionParams { text_document_position_params: params.clone(), work_done_progress_params: Default::default(), partial_result_params: Default::default() };
+    let definition: GotoDefinitionResponse = serde_json::from_value(request::<GotoDefinition>(&client, 2, definition)).unwrap();
+    let GotoDefinitionResponse::Scalar(location) = definition else { panic!("expected one location") };
+    assert_eq!(location.uri, uri);
+    assert_eq!(location.range.start, at("[structs.vec3]", 9));
+
+    let hover = HoverParams { text_document_position_params: params, work_done_progress_params: Default::default() };
+    let hover: Hover = serde_json::from_value(request::<HoverRequest>(&client, 3, hover)).unwrap();
+    let HoverContents::Markup(contents) = hover.contents else { panic!("expected markdown") };
+    assert!(contents.value.contains("A vector"), "{}", contents.value);
+
+    request::<Shutdown>(&client, 4, ());
+    notify::<Exit>(&client, ());
+    server.join().unwrap();
+}
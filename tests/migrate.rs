@@ -0,0 +1,85 @@
+//! Reading documents written for older versions of the spec, refusing newer ones, and rewriting
+//! them with `migrate`
+
+use openpid::analysis::Analysis;
+use openpid::migrate::{migrate, MigrateError, SpecVersion, CURRENT_VERSION};
+use openpid::OpenPID;
+
+/// Written for 0.1, which had no `openpid_version`, in the syntax of the bundled document
+const OLD: &str = r#"#:schema ./openpid.schema.json
+# A sensor
+
+[device_info]
+name = "sensor"
+description = "A sensor"
+
+[uart]
+tx_format = [
+    { type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian"} } },
+    { type = "Payload" },
+]
+rx_format = [
+    { type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian"} } },
+    { type = "Payload" },
+]
+
+[payloads.tx.read]
+description = "Reads"
+id = 0x01
+segments = [{ name = "data", type = { type = "Raw" } }]
+
+[payloads.rx.reading]
+description = "A reading"
+id = 0x02
+segments = [
+    { name = "serial", bits = 32, type = { type = "StringUTF8" } }, # as printed on the case
+]
+
+[transactions.read]
+description = "Reads"
+actions = [
+    {type = "Tx", payload = "read"},
+    {type = "Flush"},
+    {type = "Rx", payload = "reading"},
+]
+returns = ["reading.serial"]
+"#;
+
+#[test]
+fn older_documents_load_as_the_current_model() {
+    let doc: OpenPID = OLD.parse().unwrap();
+    let ir = doc.to_ir().unwrap();
+    assert_eq!(ir.tx[0].fields.len(), 1);
+    let diagnostics = Analysis::new(OLD).diagnostics;
+    assert!(diagnostics.is_empty(), "{diagnostics:?}");
+}
+
+#[test]
+fn migrating_only_records_the_version() {
+    let (migrated, from) = migrate(OLD).unwrap();
+    assert_eq!(from, SpecVersion { major: 0, minor: 1 });
+    let stamped = OLD.replace("# A sensor\n\n", "# A sensor\nopenpid_version = \"0.2\"\n\n");
+    assert_eq!(migrated, stamped);
+
+    assert_eq!(migrate(&migrated).unwrap(), (migrated, CURRENT_VERSION));
+
+    // the bundled document is the 0.1 one with its version recorded
+    let bundled = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/openpid.toml")).unwrap();
+    let (migrated, _) = migrate(&bundled.replace("openpid_version = \"0.2\"\n", "")).unwrap();
+    assert_eq!(migrated, bundled);
+}
+
+#[test]
+fn newer_and_malformed_versions_are_refused() {
+    let future = format!("openpid_version = \"{}.{}\"\n{OLD}", CURRENT_VERSION.major, CURRENT_VERSION.minor + 1);
+    let error = future.parse::<OpenPID>().unwrap_err();
+    assert!(matches!(error, MigrateError::Future { .. }), "{error}");
+    assert!(error.to_string().contains("Update openpid"));
+
+    let analysis = Analysis::new(&future);
+    assert_eq!(analysis.diagnostics[0].span.clone().unwrap().start, future.find('"').unwrap());
+
+    assert!(matches!("openpid_version = 2\n".parse::<OpenPID>(), Err(MigrateError::BadVersion { .. })));
+    assert_eq!("v0.2.1".parse(), Ok(SpecVersion { major: 0, minor: 2 }));
+    assert!("0".parse::<SpecVersion>().is_err());
+}
@@ -0,0 +1,117 @@
+//! Publishing to and installing from a local registry, resolution with the lockfile, and checksums
+
+use std::path::{Path, PathBuf};
+
+use openpid::package::{self, LocalRegistry, Lockfile, Manifest, PackageError};
+
+const VEC3: &str = r#"[structs.vec3]
+name = "vec3"
+description = "A vector"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "y", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+]
+"#;
+
+const DEVICE: &str = r#"imports = [{ package = "imu", namespace = "imu" }]
+
+[device_info]
+name = "sensor"
+description = "A sensor"
+
+[payloads.tx.read]
+description = "Reads"
+segments = []
+
+[payloads.rx.reading]
+description = "A reading"
+segments = [{ name = "accel", struct_name = "imu_vec3" }]
+
+[transactions.read]
+description = "Reads the acceleration"
+actions = [{ type = "Tx", payload = "read" }, { type = "Rx", payload = "reading" }]
+returns = ["reading.accel"]
+"#;
+
+/// A fresh directory for one test
+fn scratch(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("openpid-package-{name}"));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    root
+}
+
+/// Writes a package with `dependencies` lines and `document` into `dir`
+fn package(dir: &Path, name: &str, version: &str, dependencies: &str, document: &str) {
+    std::fs::create_dir_all(dir).unwrap();
+    let manifest = format!("[package]\nname = \"{name}\"\nversion = \"{version}\"\nlicense = \"MIT\"\n\n[dependencies]\n{dependencies}");
+    std::fs::write(dir.join(package::MANIFEST_FILE), manifest).unwrap();
+    std::fs::write(dir.join("openpid.toml"), document).unwrap();
+}
+
+/// A registry with `common` 1.0.0, 1.1.0 and 2.0.0, and `imu` 0.1.0 needing `common` 1 and
+/// 0.2.0 needing `common` 2
+fn registry(root: &Path) -> LocalRegistry {
+    let registry = LocalRegistry::new(root.join("registry"));
+    for (version, deps) in [("0.1.0", "common = \"^1\"\n"), ("0.2.0", "common = \"^2\"\n")] {
+        package(&root.join("src/imu"), "imu", version, deps, VEC3);
+        registry.publish(&root.join("src/imu")).unwrap();
+    }
+    for version in ["1.0.0", "1.1.0", "2.0.0"] {
+        package(&root.join("src/common"), "common", version, "", "");
+        registry.publish(&root.join("src/common")).unwrap();
+    }
+    registry
+}
+
+fn versions(lock: &Lockfile) -> Vec<String> {
+    lock.packages.iter().map(|p| format!("{} {}", p.name, p.version)).collect()
+}
+
+#[test]
+fn install_resolves_locks_and_imports() {
+    let root = scratch("install");
+    let registry = registry(&root);
+    assert!(matches!(registry.publish(&root.join("src/common")), Err(PackageError::Exists { .. })));
+
+    // the newest imu needs common 2, which the device rules out, so the resolver backs off to 0.1
+    let device = root.join("device");
+    package(&device, "sensor", "1.0.0", "imu = \"*\"\ncommon = \"~1.0\"\n", DEVICE);
+    let lock = package::install(&device, &registry, &root.join("cache")).unwrap();
+    assert_eq!(versions(&lock), ["common 1.0.0", "imu 0.1.0"]);
+    assert_eq!(Lockfile::load(&device).unwrap(), lock);
+    assert!(device.join("openpid_packages/imu/openpid.toml").is_file());
+    openpid::include::load(&device.join("openpid.toml")).unwrap().to_ir().unwrap();
+
+    // loosening the requirement keeps the locked version rather than upgrading
+    package(&device, "sensor", "1.0.0", "imu = \"*\"\ncommon = \"^1\"\n", DEVICE);
+    assert_eq!(versions(&package::install(&device, &registry, &root.join("cache")).unwrap()), ["common 1.0.0", "imu 0.1.0"]);
+    std::fs::remove_file(device.join(package::LOCK_FILE)).unwrap();
+    assert_eq!(versions(&package::install(&device, &registry, &root.join("cache")).unwrap()), ["common 1.1.0", "imu 0.1.0"]);
+}
+
+#[test]
+fn unsatisfiable_requirements_name_the_package() {
+    let root = scratch("unsatisfiable");
+    let registry = registry(&root);
+    let manifest: Manifest = toml::from_str("[package]\nname = \"sensor\"\nversion = \"1.0.0\"\n\n[dependencies]\nimu = \"^0.2\"\ncommon = \"^1\"\n").unwrap();
+    let error = package::resolve(&manifest, &registry, &Lockfile::default()).unwrap_err();
+    assert!(matches!(&error, PackageError::NoMatch { name, .. } if name == "common"), "{error}");
+
+    let manifest: Manifest = toml::from_str("[package]\nname = \"sensor\"\nversion = \"1.0.0\"\n\n[dependencies]\nmissing = \"1\"\n").unwrap();
+    assert!(matches!(package::resolve(&manifest, &registry, &Lockfile::default()), Err(PackageError::NotFound { .. })));
+}
+
+#[test]
+fn changed_packages_fail_their_checksum() {
+    let root = scratch("checksum");
+    let registry = registry(&root);
+    let device = root.join("device");
+    package(&device, "sensor", "1.0.0", "imu = \"0.1\"\n", DEVICE);
+
+    // a package edited in the registry after it was published
+    std::fs::write(root.join("registry/packages/imu/0.1.0/openpid.toml"), VEC3.replace("A vector", "Changed")).unwrap();
+    let error = package::install(&device, &registry, &root.join("cache")).unwrap_err();
+    assert!(matches!(&error, PackageError::Checksum { name, .. } if name == "imu"), "{error}");
+    assert!(!device.join(package::LOCK_FILE).exists());
+}
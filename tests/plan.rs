@@ -0,0 +1,185 @@
+//! Compiled decoding plans, checked against the dynamic codec
+
+use std::str::FromStr;
+
+use openpid::plan::FieldView;
+use openpid::prelude::*;
+
+const CONST: &str = r#"[device_info]
+name = "sensor"
+description = "A sensor"
+
+[payloads.tx.hello]
+description = "Says hello"
+segments = [{ name = "magic", bits = 16, type = { type = "Const", data = [0xCA, 0xFE] } }, { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } }]
+
+[transactions]
+"#;
+
+#[test]
+fn constants_must_fill_their_width() {
+    let doc = OpenPID::from_str(CONST).unwrap();
+    let plan = doc.compile().unwrap();
+    assert_eq!(plan.payload(Direction::Tx, "hello").unwrap().field_offset_bits("id"), Some(16));
+
+    // otherwise the plan would put `id` at 24 bits while the codec reads it at 16
+    let wide = OpenPID::from_str(&CONST.replace("bits = 16", "bits = 24")).unwrap();
+    assert!(matches!(wide.compile(), Err(CodecError::LengthMismatch { expected: 3, found: 2, .. })));
+}
+
+const DOC: &str = r#"[device_info]
+name = "sensor"
+description = "A sensor"
+
+[structs.point]
+name = "point"
+fields = [
+    { name = "x", bits = 12, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "y", bits = 4, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "at", bits = 16, type = { type = "Integer", signing = "Unsigned", endianness = "LittleEndian" } },
+]
+
+[structs.track]
+name = "track"
+fields = [
+    { name = "count", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "points", type = { type = "Array", item_struct = "point" }, termination = { field_name = "count" } },
+]
+
+[payloads.rx.fixed]
+description = "Known size"
+segments = [
+    { name = "magic", bits = 8, type = { type = "Const", data = [0x5A] } },
+    { name = "level", bits = 8, type = { type = "Integer", signing = "OnesComplement", endianness = "BigEndian" } },
+    { name = "gain", bits = 32, type = { type = "FloatIEEE", endianness = "LittleEndian" } },
+    { name = "serial", bits = 24, type = { type = "Raw" } },
+    { name = "name", bits = 32, type = { type = "StringUTF8" } },
+    { name = "origin", struct_name = "point" },
+    { name = "corners", type = { type = "Array", item_struct = "point" }, termination = { count = 2 } },
+]
+
+[payloads.rx.variable]
+description = "Size depends on the data"
+segments = [
+    { name = "count", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "points", type = { type = "Array", item_struct = "point" }, termination = { field_name = "count" } },
+    { name = "tracks", type = { type = "Array", item_struct = "track" }, termination = { sequence = [0xFF] } },
+    { name = "label", type = { type = "StringUTF8" }, termination = { sequence = [0] } },
+    { name = "after", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "rest", type = { type = "Raw" } },
+]
+
+[transactions]
+"#;
+
+fn point(x: i64, y: u64, at: u64) -> Value {
+    Value::Struct(Fields::from_iter([("x", Value::Int(x)), ("y", Value::UInt(y)), ("at", Value::UInt(at))]))
+}
+
+fn track(points: Vec<Value>) -> Value {
+    Value::Struct(Fields::from_iter([("count", Value::UInt(points.len() as u64)), ("points", Value::Array(points))]))
+}
+
+fn fixed() -> Fields {
+    Fields::from_iter([
+        ("magic", Value::Bytes(vec![0x5A])),
+        ("level", Value::Int(-100)),
+        ("gain", Value::Float(1.5)),
+        ("serial", Value::Bytes(vec![1, 2, 3])),
+        ("name", Value::String("gyro".to_owned())),
+        ("origin", point(-2048, 15, 0xBEEF)),
+        ("corners", Value::Array(vec![point(1, 2, 3), point(2047, 0, 0xFFFF)])),
+    ])
+}
+
+fn variable() -> Fields {
+    Fields::from_iter([
+        ("count", Value::UInt(3)),
+        ("points", Value::Array(vec![point(-1, 1, 1), point(0, 2, 2), point(5, 3, 0x0100)])),
+        ("tracks", Value::Array(vec![track(vec![point(7, 7, 7)]), track(vec![])])),
+        ("label", Value::String("ok".to_owned())),
+        ("after", Value::UInt(0x42)),
+        ("rest", Value::Bytes(vec![0xDE, 0xAD])),
+    ])
+}
+
+#[test]
+fn plans_decode_what_the_codec_encodes() {
+    let doc = OpenPID::from_str(DOC).unwrap();
+    let plan = doc.compile().unwrap();
+    for (name, fields) in [("fixed", fixed()), ("variable", variable())] {
+        let bytes = doc.encode_payload(Direction::Rx, name, &fields).unwrap();
+        let view = plan.payload(Direction::Rx, name).unwrap().decode(&bytes).unwrap();
+        assert_eq!(view.to_fields().unwrap(), fields, "{name}");
+        assert_eq!(view.to_fields().unwrap(), doc.decode_payload(Direction::Rx, name, &bytes).unwrap().fields, "{name}");
+        assert_eq!(view.len_bits().unwrap(), bytes.len() * 8, "{name}");
+    }
+    assert_eq!(plan.payloads(Direction::Rx).map(|p| p.name()).collect::<Vec<_>>(), ["fixed", "variable"]);
+    assert!(plan.payload(Direction::Tx, "fixed").is_none());
+}
+
+#[test]
+fn fields_are_found_at_their_offsets() {
+    let doc = OpenPID::from_str(DOC).unwrap();
+    let plan = doc.compile().unwrap();
+    let fixed_plan = plan.payload(Direction::Rx, "fixed").unwrap();
+    assert_eq!(fixed_plan.size(), Size::Fixed(8 + 8 + 32 + 24 + 32 + 3 * 32));
+    assert_eq!(fixed_plan.field_offset_bits("origin"), Some(104));
+    assert_eq!(fixed_plan.field_offset_bits("missing"), None);
+
+    let bytes = doc.encode_payload(Direction::Rx, "fixed", &fixed()).unwrap();
+    let view = fixed_plan.decode(&bytes).unwrap();
+    assert!(matches!(view.get("level").unwrap(), Some(FieldView::Int(-100))));
+    assert!(matches!(view.get("name").unwrap(), Some(FieldView::Str("gyro"))));
+    let Some(FieldView::Struct(origin)) = view.get("origin").unwrap() else { panic!("origin isn't a struct") };
+    assert!(matches!(origin.get("at").unwrap(), Some(FieldView::UInt(0xBEEF))));
+    let Some(FieldView::Array(corners)) = view.get("corners").unwrap() else { panic!("corners isn't an array") };
+    assert_eq!(corners.count(), Some(2));
+    assert_eq!(Value::Struct(corners.items().last().unwrap().unwrap().to_fields().unwrap()), point(2047, 0, 0xFFFF));
+    assert!(view.get("missing").unwrap().is_none());
+
+    // fields after something variable are found by walking up to them
+    let variable_plan = plan.payload(Direction::Rx, "variable").unwrap();
+    assert!(matches!(variable_plan.size(), Size::Variable { max_bits: None, .. }));
+    assert_eq!(variable_plan.field_offset_bits("points"), Some(8));
+    assert_eq!(variable_plan.field_offset_bits("after"), None);
+    let bytes = doc.encode_payload(Direction::Rx, "variable", &variable()).unwrap();
+    let view = variable_plan.decode(&bytes).unwrap();
+    assert!(matches!(view.get("after").unwrap(), Some(FieldView::UInt(0x42))));
+    let Some(FieldView::Array(tracks)) = view.get("tracks").unwrap() else { panic!("tracks isn't an array") };
+    assert_eq!((tracks.count(), tracks.items().count()), (None, 2));
+    assert_eq!(tracks.len_bits().unwrap(), (1 + 4 + 1 + 1) * 8);
+}
+
+#[test]
+fn plans_refuse_what_the_codec_refuses() {
+    let doc = OpenPID::from_str(DOC).unwrap();
+    let plan = doc.compile().unwrap();
+    let fixed_plan = plan.payload(Direction::Rx, "fixed").unwrap();
+    let bytes = doc.encode_payload(Direction::Rx, "fixed", &fixed()).unwrap();
+
+    let mut long = bytes.clone();
+    long.push(0);
+    let mut wrong_magic = bytes.clone();
+    wrong_magic[0] = 0;
+    let mut bad_name = bytes.clone();
+    bad_name[9] = 0xFF;
+    // refused by `decode` itself, not later while reading fields, even though the length is right
+    for data in [&bytes[..bytes.len() - 1], &long, &wrong_magic, &bad_name] {
+        let dynamic = doc.decode_payload(Direction::Rx, "fixed", data).unwrap_err();
+        let planned = fixed_plan.decode(data).unwrap_err();
+        assert_eq!(std::mem::discriminant(&planned), std::mem::discriminant(&dynamic), "{planned} vs {dynamic}");
+    }
+
+    // a count running past the end of the data
+    let variable_plan = plan.payload(Direction::Rx, "variable").unwrap();
+    let mut bytes = doc.encode_payload(Direction::Rx, "variable", &variable()).unwrap();
+    bytes[0] = 200;
+    assert!(matches!(variable_plan.decode(&bytes), Err(CodecError::UnexpectedEnd { .. })));
+    assert!(matches!(doc.decode_payload(Direction::Rx, "variable", &bytes), Err(CodecError::UnexpectedEnd { .. })));
+
+    // errors inside structs name the whole path to the field
+    let mut bytes = doc.encode_payload(Direction::Rx, "variable", &variable()).unwrap();
+    bytes[13] = 200;
+    assert!(matches!(variable_plan.decode(&bytes), Err(CodecError::UnexpectedEnd { field, .. }) if field == "variable.tracks.points"));
+}
@@ -0,0 +1,211 @@
+//! The no_std runtime against the dynamic codec: the same frames built and parsed both ways must
+//! come out byte for byte the same
+
+use std::str::FromStr;
+
+use openpid::prelude::*;
+use openpid_runtime::{self as rt, FrameParser};
+
+/// A size total and CRC-16 around one frame ID, like the TargetPoint3, and an unsized RX format
+/// that starts with a half-byte constant
+const XMODEM: &str = r#"[device_info]
+name = "sensor"
+description = "A sensor"
+
+[uart]
+tx_format = [
+    { type = "SizeTotal", size_bits = 8, express_as = "Bytes" },
+    { type = "Metadata", segment = { name = "id", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+    { type = "Payload" },
+    { type = "Crc", algorithm = "Crc16XModem" },
+]
+rx_format = [
+    { type = "Const", data = [0x0A], bits = 4 },
+    { type = "Metadata", segment = { name = "id", bits = 4, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+    { type = "Payload" },
+    { type = "Crc", algorithm = "Crc32" },
+]
+
+[structs.vec3]
+name = "vec3"
+fields = [
+    { name = "x", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "LittleEndian" } },
+    { name = "y", bits = 16, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "z", bits = 12, type = { type = "Integer", signing = "TwosComplement", endianness = "BigEndian" } },
+    { name = "flags", bits = 4, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+]
+
+[payloads.tx.configure]
+description = "Configures"
+id = 0x21
+segments = [
+    { name = "rate", bits = 16, type = { type = "Integer", signing = "Unsigned", endianness = "LittleEndian" } },
+    { name = "name", bits = 64, type = { type = "StringUTF8" } },
+    { name = "origin", struct_name = "vec3" },
+    { name = "count", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "points", type = { type = "Array", item_struct = "vec3" }, termination = { field_name = "count" } },
+    { name = "label", type = { type = "StringUTF8" }, termination = { sequence = [0] } },
+]
+
+[payloads.rx.reading]
+description = "A reading"
+id = 0x3
+segments = [
+    { name = "temperature", bits = 32, type = { type = "FloatIEEE", endianness = "LittleEndian" } },
+    { name = "humidity", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+]
+
+[transactions]
+"#;
+
+/// A sync word and a length covering the frame ID and payload, with a CRC-32 over it all, and a
+/// payload length with a little endian frame ID the other way
+const SYNC: &str = r#"[device_info]
+name = "sensor"
+description = "A sensor"
+
+[uart]
+tx_format = [
+    { type = "SizeOfPayload", size_bits = 16, express_as = "Bits" },
+    { type = "Metadata", segment = { name = "kind", bits = 16, type = { type = "Integer", signing = "Unsigned", endianness = "LittleEndian" } } },
+    { type = "Payload" },
+]
+rx_format = [
+    { type = "Const", data = [0xAA, 0x55] },
+    { type = "SizeOfElements", size_bits = 16, express_as = "Bytes", elements = [
+        { type = "Metadata", segment = { name = "kind", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } } },
+        { type = "Payload" },
+    ] },
+    { type = "Crc", algorithm = "Crc32" },
+]
+
+[structs.sample]
+name = "sample"
+fields = [
+    { name = "at", bits = 32, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "value", bits = 64, type = { type = "FloatIEEE", endianness = "BigEndian" } },
+]
+
+[payloads.tx.rename]
+description = "Renames"
+kind = 0x0102
+segments = [
+    { name = "length", bits = 8, type = { type = "Integer", signing = "Unsigned", endianness = "BigEndian" } },
+    { name = "name", type = { type = "StringUTF8" }, termination = { field_name = "length" } },
+    { name = "key", bits = 32, type = { type = "Raw" } },
+]
+
+[payloads.rx.samples]
+description = "Samples"
+kind = 7
+segments = [
+    { name = "serial", bits = 24, type = { type = "Raw" } },
+    { name = "samples", type = { type = "Array", item_struct = "sample" }, termination = { count = 2 } },
+    { name = "note", type = { type = "StringUTF8" } },
+]
+
+[transactions]
+"#;
+
+fn int(v: i64) -> Value {
+    Value::Int(v)
+}
+
+fn uint(v: u64) -> Value {
+    Value::UInt(v)
+}
+
+fn vec3(x: i64, y: i64, z: i64, flags: u64) -> Value {
+    Value::Struct(Fields::from_iter([("x", int(x)), ("y", int(y)), ("z", int(z)), ("flags", uint(flags))]))
+}
+
+fn sample(at: u64, value: f64) -> Value {
+    Value::Struct(Fields::from_iter([("at", uint(at)), ("value", Value::Float(value))]))
+}
+
+/// Builds the frame with the codec and with the runtime, then parses it with both, the runtime
+/// once whole and once a byte at a time, twice over so the parser has to find the second frame
+fn round_trip(doc: &OpenPID, direction: Direction, name: &str, fields: Fields, payload_len: Option<rt::frame::PayloadLen>) -> Vec<u8> {
+    let frame = doc.encode_frame(direction, name, &fields).unwrap();
+    let format = doc.runtime_format(direction).unwrap();
+    let metadata = doc.runtime_metadata(direction, name).unwrap();
+    let payload = doc.encode_payload(direction, name, &fields).unwrap();
+
+    let mut out = [0u8; 128];
+    let len = rt::frame::encode(&format, &metadata, &payload, &mut out).unwrap();
+    assert_eq!(out[..len], frame, "{name} encodes differently");
+
+    let decoded = rt::frame::decode(&format, &frame).unwrap();
+    assert_eq!((decoded.payload, decoded.metadata(), decoded.len), (payload.as_slice(), metadata.as_slice(), frame.len()));
+
+    let mut parser = FrameParser::<64>::new(&format);
+    if let Some(payload_len) = payload_len {
+        parser = parser.with_payload_len(payload_len);
+    }
+    let mut found = Vec::new();
+    for byte in frame.iter().chain(&frame) {
+        if let Some(parsed) = parser.push(*byte) {
+            let parsed = parsed.unwrap();
+            found.push((parsed.payload.to_vec(), parsed.metadata().to_vec()));
+        }
+    }
+    assert_eq!(found, [(payload.clone(), metadata.clone()), (payload, metadata)]);
+
+    let dynamic = doc.decode_frame(direction, &frame).unwrap();
+    assert_eq!(dynamic.payload.name, name);
+    assert_eq!(dynamic.payload.fields, fields);
+    frame
+}
+
+#[test]
+fn crc_and_size_total_frames_match() {
+    let doc = OpenPID::from_str(XMODEM).unwrap();
+    let fields = Fields::from_iter([
+        ("rate", uint(0x1234)),
+        ("name", Value::String("imu".to_owned())),
+        ("origin", vec3(-2, 300, -2048, 0xA)),
+        ("count", uint(2)),
+        ("points", Value::Array(vec![vec3(1, -1, 2047, 1), vec3(-32768, 32767, 5, 0xF)])),
+        ("label", Value::String("front".to_owned())),
+    ]);
+    let frame = round_trip(&doc, Direction::Tx, "configure", fields, None);
+    assert_eq!(frame[..4], [frame.len() as u8, 0x21, 0x34, 0x12]);
+
+    // nothing in the frame says how long the payload is, so the parser looks it up from the ID
+    let fields = Fields::from_iter([("temperature", Value::Float(-21.5)), ("humidity", uint(87))]);
+    let frame = round_trip(&doc, Direction::Rx, "reading", fields, Some(&|metadata| (metadata == [3]).then_some(5)));
+    assert_eq!(frame[0], 0xA3);
+}
+
+#[test]
+fn sync_words_and_length_fields_match() {
+    let doc = OpenPID::from_str(SYNC).unwrap();
+    let fields = Fields::from_iter([("length", uint(6)), ("name", Value::String("gyro-2".to_owned())), ("key", Value::Bytes(vec![0xDE, 0xAD, 0xBE, 0xEF]))]);
+    let frame = round_trip(&doc, Direction::Tx, "rename", fields, None);
+    assert_eq!(frame[..4], [0x00, 11 * 8, 0x02, 0x01]);
+
+    let fields = Fields::from_iter([
+        ("serial", Value::Bytes(vec![1, 2, 3])),
+        ("samples", Value::Array(vec![sample(1_000, 0.25), sample(0xFFFF_FFFF, -1e300)])),
+        ("note", Value::String("ok".to_owned())),
+    ]);
+    let frame = round_trip(&doc, Direction::Rx, "samples", fields, None);
+    assert_eq!(frame[..5], [0xAA, 0x55, 0x00, 1 + 3 + 2 * 12 + 2, 7]);
+}
+
+#[test]
+fn corrupted_frames_are_refused_both_ways() {
+    let doc = OpenPID::from_str(XMODEM).unwrap();
+    let fields = Fields::from_iter([("temperature", Value::Float(1.0)), ("humidity", uint(1))]);
+    let mut frame = doc.encode_frame(Direction::Rx, "reading", &fields).unwrap();
+    *frame.last_mut().unwrap() ^= 1;
+
+    let format = doc.runtime_format(Direction::Rx).unwrap();
+    assert!(matches!(rt::frame::decode(&format, &frame), Err(rt::Error::CrcMismatch { .. })));
+    assert!(matches!(doc.decode_frame(Direction::Rx, &frame), Err(CodecError::CrcMismatch { .. })));
+
+    let payload_len: rt::frame::PayloadLen = &|_| Some(5);
+    let mut parser = FrameParser::<16>::new(&format).with_payload_len(payload_len);
+    let results = frame.iter().filter_map(|b| parser.push(*b).map(|r| r.map(|f| f.len))).collect::<Vec<_>>();
+    assert_eq!(results, [Err(rt::Error::CrcMismatch { expected: rt::Crc::Crc32.compute(&frame[..6]), found: u32::from_be_bytes(frame[6..].try_into().unwrap()) })]);
+}
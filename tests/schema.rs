@@ -0,0 +1,57 @@
+//! The JSON Schema checked in for editors matches the config types, and accepts the bundled document
+
+use std::path::Path;
+
+fn validate(document: &str) -> Vec<String> {
+    let document: serde_json::Value = toml::from_str(document).expect("the document should be valid TOML");
+    let schema = serde_json::to_value(openpid::config::json_schema()).unwrap();
+    let validator = jsonschema::validator_for(&schema).expect("the schema should be valid");
+    validator.iter_errors(&document).map(|e| format!("{} at {}", e, e.instance_path)).collect()
+}
+
+#[test]
+fn checked_in_schema_is_up_to_date() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("openpid.schema.json");
+    let expected = serde_json::to_string_pretty(&openpid::config::json_schema()).unwrap() + "\n";
+    let found = std::fs::read_to_string(&path).unwrap_or_default();
+    let (found, expected) = (found.lines().collect::<Vec<_>>(), expected.lines().collect::<Vec<_>>());
+    let Some(first) = (0..found.len().max(expected.len())).find(|&i| found.get(i) != expected.get(i)) else {
+        return;
+    };
+    // the lines around the first difference, which is usually enough to see what changed
+    let mut diff = Vec::new();
+    for i in first.saturating_sub(3)..first + 4 {
+        match (found.get(i), expected.get(i)) {
+            (Some(f), Some(e)) if f == e => diff.push(format!(" {f}")),
+            (f, e) => diff.extend(f.map(|f| format!("-{f}")).into_iter().chain(e.map(|e| format!("+{e}")))),
+        }
+    }
+    panic!(
+        "openpid.schema.json is out of date from line {}; regenerate it with `openpid schema > openpid.schema.json`\n{}",
+        first + 1,
+        diff.join("\n")
+    );
+}
+
+#[test]
+fn bundled_document_validates() {
+    let document = std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("openpid.toml")).unwrap();
+    let errors = validate(&document);
+    assert!(errors.is_empty(), "openpid.toml doesn't match the schema:\n{}", errors.join("\n"));
+}
+
+#[test]
+fn misspelled_segment_key_is_an_error() {
+    let document = r#"
+        structs = {}
+        transactions = {}
+        [device_info]
+        name = "Test"
+        description = "A test device"
+        [payloads.rx]
+        [payloads.tx.ping]
+        description = "Checks the device is there"
+        segments = [{ name = "value", bitz = 8, type = { type = "Integer", endianness = "BigEndian", signing = "Unsigned" } }]
+    "#;
+    assert!(!validate(document).is_empty());
+}